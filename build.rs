@@ -0,0 +1,197 @@
+//! Build details for `zap about`: the commit, the target, the features and
+//! the licenses of the third-party crates compiled in
+//!
+//! The crates come from `cargo metadata` for the features being built, and
+//! their license texts from the `LICENSE*`, `COPYING*` and `NOTICE*` files
+//! they ship. Without git or cargo metadata the build still works, with
+//! the details left out.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::Value;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    // A path that doesn't exist would rerun this on every build
+    for git in [".git/HEAD", ".git/refs"] {
+        if Path::new(git).exists() {
+            println!("cargo:rerun-if-changed={}", git);
+        }
+    }
+    println!("cargo:rerun-if-env-changed=ZAP_GIT_SHA");
+
+    println!("cargo:rustc-env=ZAP_GIT_SHA={}", git_sha());
+    println!("cargo:rustc-env=ZAP_TARGET={}", env::var("TARGET").unwrap());
+    println!("cargo:rustc-env=ZAP_FEATURES={}", features().join(","));
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let (crates, notices) = match third_party() {
+        Ok(found) => found,
+        Err(e) => {
+            println!("cargo:warning=leaving out third-party licenses: {}", e);
+            Default::default()
+        }
+    };
+    fs::write(out.join("crates.txt"), crates).unwrap();
+    fs::write(out.join("notices.txt"), notices).unwrap();
+}
+
+/// The commit being built, from `ZAP_GIT_SHA` for builds outside a checkout
+fn git_sha() -> String {
+    if let Ok(sha) = env::var("ZAP_GIT_SHA") {
+        return sha;
+    }
+    Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .filter(|sha| !sha.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// The package's enabled features, other than `default`
+fn features() -> Vec<String> {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            let name = key.strip_prefix("CARGO_FEATURE_")?.to_lowercase();
+            (name != "default").then(|| name.replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    features
+}
+
+/// One `name\tversion\tlicense` line per crate compiled in, and their
+/// license texts, each given once with the crates it covers
+fn third_party() -> Result<(String, String), String> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let manifest = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.toml");
+    let mut command = Command::new(cargo);
+    command
+        .args(["metadata", "--format-version", "1", "--offline"])
+        .args(["--filter-platform", &env::var("TARGET").unwrap()])
+        .arg("--manifest-path")
+        .arg(&manifest)
+        .arg("--no-default-features");
+    let features = features();
+    if !features.is_empty() {
+        command.args(["--features", &features.join(",")]);
+    }
+    let out = command.output().map_err(|e| e.to_string())?;
+    if !out.status.success() {
+        return Err(String::from_utf8_lossy(&out.stderr).trim().to_string());
+    }
+    let metadata: Value = serde_json::from_slice(&out.stdout).map_err(|e| e.to_string())?;
+
+    let packages: HashMap<&str, &Value> = metadata["packages"]
+        .as_array()
+        .ok_or("no packages")?
+        .iter()
+        .filter_map(|p| Some((p["id"].as_str()?, p)))
+        .collect();
+    let nodes: HashMap<&str, &Value> = metadata["resolve"]["nodes"]
+        .as_array()
+        .ok_or("no resolve graph")?
+        .iter()
+        .filter_map(|n| Some((n["id"].as_str()?, n)))
+        .collect();
+
+    // Only what the binary links: normal dependencies, from the root down
+    let root = metadata["resolve"]["root"].as_str().ok_or("no root")?;
+    let mut seen = HashSet::from([root]);
+    let mut queue = vec![root];
+    while let Some(id) = queue.pop() {
+        let deps = nodes.get(id).and_then(|n| n["deps"].as_array());
+        for dep in deps.into_iter().flatten() {
+            let normal = dep["dep_kinds"]
+                .as_array()
+                .is_some_and(|kinds| kinds.iter().any(|k| k["kind"].is_null()));
+            if let Some(pkg) = dep["pkg"].as_str()
+                && normal
+                && seen.insert(pkg)
+            {
+                queue.push(pkg);
+            }
+        }
+    }
+
+    let mut crates = Vec::new();
+    // License texts, with the crates that ship each
+    let mut texts: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for id in seen {
+        let Some(package) = packages.get(id) else {
+            continue;
+        };
+        // Workspace members have no source
+        if package["source"].is_null() {
+            continue;
+        }
+        let name = package["name"].as_str().unwrap_or_default();
+        let version = package["version"].as_str().unwrap_or_default();
+        let license = package["license"].as_str().unwrap_or("unknown");
+        crates.push(format!("{}\t{}\t{}", name, version, license));
+
+        let dir = package["manifest_path"]
+            .as_str()
+            .and_then(|m| Path::new(m).parent())
+            .ok_or("no manifest path")?;
+        for path in license_files(dir, package["license_file"].as_str()) {
+            if let Ok(text) = fs::read_to_string(&path) {
+                texts
+                    .entry(text.trim().to_string())
+                    .or_default()
+                    .push(format!("{} {}", name, version));
+            }
+        }
+    }
+    crates.sort();
+
+    let mut groups: Vec<(Vec<String>, String)> = texts
+        .into_iter()
+        .map(|(text, mut owners)| {
+            owners.sort();
+            owners.dedup();
+            (owners, text)
+        })
+        .collect();
+    groups.sort();
+    let mut notices = String::new();
+    for (owners, text) in groups {
+        notices.push_str(&format!("=== {} ===\n\n{}\n\n", owners.join(", "), text));
+    }
+    Ok((crates.join("\n"), notices))
+}
+
+fn license_files(dir: &Path, license_file: Option<&str>) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default()
+                .to_uppercase();
+            path.is_file()
+                && ["LICENSE", "LICENCE", "COPYING", "NOTICE"]
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+        })
+        .collect();
+    if let Some(file) = license_file {
+        files.push(dir.join(file));
+    }
+    files.sort();
+    files.dedup();
+    files
+}
@@ -0,0 +1,362 @@
+//! Release tooling, run with `cargo xtask <command>`
+//!
+//! `cargo xtask dist` builds zap for every release platform and writes the
+//! binaries, their checksums and the release manifest to `dist/`. The
+//! manifest is what the relay serves at `/api/releases/latest` and what
+//! `zap update` and the install script read. The release workflow runs
+//! `build` on each runner and `manifest` once over everything they built.
+//!
+//! Linux builds are static musl binaries built with `cross`, so they run on
+//! any distribution. macOS builds need a macOS host.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result, bail};
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Where release artifacts are downloaded from, before the tag
+const RELEASES: &str = "https://github.com/voidash/zapper.cloud/releases/download";
+
+/// A platform zap is released for
+struct Target {
+    /// Rust target triple
+    triple: &'static str,
+    /// Name in artifact names and the manifest, as `zap update` works it
+    /// out for the machine it runs on
+    platform: &'static str,
+}
+
+const TARGETS: &[Target] = &[
+    Target {
+        triple: "x86_64-unknown-linux-musl",
+        platform: "linux-x86_64",
+    },
+    Target {
+        triple: "aarch64-unknown-linux-musl",
+        platform: "linux-arm64",
+    },
+    Target {
+        triple: "armv7-unknown-linux-musleabihf",
+        platform: "linux-armv7",
+    },
+    Target {
+        triple: "x86_64-apple-darwin",
+        platform: "darwin-x86_64",
+    },
+    Target {
+        triple: "aarch64-apple-darwin",
+        platform: "darwin-arm64",
+    },
+];
+
+impl Target {
+    fn is_macos(&self) -> bool {
+        self.triple.ends_with("-apple-darwin")
+    }
+
+    fn artifact(&self) -> String {
+        format!("zap-{}", self.platform)
+    }
+}
+
+#[derive(Parser)]
+#[command(name = "xtask")]
+struct Cli {
+    #[command(subcommand)]
+    command: Task,
+
+    /// Where artifacts go, relative to the workspace
+    #[arg(long, global = true, default_value = "dist")]
+    out: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum Task {
+    /// Build every platform, then write checksums and the manifest
+    Dist {
+        #[command(flatten)]
+        build: BuildArgs,
+        #[command(flatten)]
+        manifest: ManifestArgs,
+    },
+    /// Build release binaries into the output directory
+    Build(BuildArgs),
+    /// Write checksums and the manifest for the binaries in the output
+    /// directory
+    Manifest(ManifestArgs),
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    /// Platforms or target triples to build, e.g. linux-armv7; defaults to
+    /// every Linux platform, and macOS ones on a Mac
+    #[arg(long = "target", value_name = "PLATFORM")]
+    targets: Vec<String>,
+}
+
+#[derive(Args)]
+struct ManifestArgs {
+    /// Release version, without the `v`
+    #[arg(long, default_value = env!("CARGO_PKG_VERSION"))]
+    version: String,
+
+    /// URL artifacts are downloaded from, followed by their name; defaults
+    /// to the GitHub release for the version
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// Sign every artifact with this minisign secret key, reading its
+    /// password from MINISIGN_PASSWORD
+    #[arg(long, value_name = "PATH")]
+    minisign_key: Option<PathBuf>,
+}
+
+/// Release manifest, as served at `/api/releases/latest`
+#[derive(Debug, Serialize)]
+struct ReleaseManifest {
+    version: String,
+    published_at: String,
+    artifacts: Vec<ReleaseArtifact>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReleaseArtifact {
+    platform: String,
+    name: String,
+    url: String,
+    sha256: String,
+    /// Contents of the `.minisig` file for this artifact
+    #[serde(skip_serializing_if = "Option::is_none")]
+    minisig: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let root = workspace_root();
+    let out = root.join(&cli.out);
+    match cli.command {
+        Task::Dist { build, manifest } => {
+            self::build(&root, &build.targets, &out)?;
+            write_manifest(&out, &manifest)?;
+        }
+        Task::Build(build) => self::build(&root, &build.targets, &out)?,
+        Task::Manifest(manifest) => write_manifest(&out, &manifest)?,
+    }
+    Ok(())
+}
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .ancestors()
+        .nth(2)
+        .expect("xtask is in crates/xtask")
+        .to_path_buf()
+}
+
+/// The targets `names` pick, by platform or triple, or the ones this host
+/// can build
+fn pick_targets(names: &[String]) -> Result<Vec<&'static Target>> {
+    if names.is_empty() {
+        let macos = cfg!(target_os = "macos");
+        return Ok(TARGETS.iter().filter(|t| macos || !t.is_macos()).collect());
+    }
+    names
+        .iter()
+        .map(|name| {
+            TARGETS
+                .iter()
+                .find(|t| t.platform == name || t.triple == name)
+                .with_context(|| {
+                    let known: Vec<_> = TARGETS.iter().map(|t| t.platform).collect();
+                    format!("unknown platform {} (known: {})", name, known.join(", "))
+                })
+        })
+        .collect()
+}
+
+/// Build each target in release mode and copy the binary to `out` under
+/// its artifact name
+fn build(root: &Path, names: &[String], out: &Path) -> Result<()> {
+    let targets = pick_targets(names)?;
+    fs::create_dir_all(out)?;
+    for target in targets {
+        if target.is_macos() && !cfg!(target_os = "macos") {
+            bail!("{} can only be built on macOS", target.platform);
+        }
+        println!("building {} ({})", target.platform, target.triple);
+
+        let mut command = if target.is_macos() {
+            Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+        } else {
+            // cross builds in a container with the musl toolchain, see Cross.toml
+            let mut cross = Command::new("cross");
+            cross.env("RUSTFLAGS", "-C target-feature=+crt-static");
+            cross
+        };
+        let status = command
+            .current_dir(root)
+            .args(["build", "--release", "--bin", "zap"])
+            .args(["--target", target.triple])
+            .status()
+            .with_context(|| format!("could not run the build for {}", target.platform))?;
+        if !status.success() {
+            bail!("building {} failed", target.platform);
+        }
+
+        let binary = root.join("target").join(target.triple).join("release/zap");
+        let artifact = out.join(target.artifact());
+        fs::copy(&binary, &artifact)
+            .with_context(|| format!("could not copy {}", binary.display()))?;
+        make_executable(&artifact)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Hash and sign every artifact in `dir`, writing `<name>.sha256` next to
+/// each and `manifest.json` for all of them
+fn write_manifest(dir: &Path, args: &ManifestArgs) -> Result<()> {
+    let version = args.version.trim_start_matches('v');
+    let base_url = match &args.base_url {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => format!("{}/v{}", RELEASES, version),
+    };
+
+    let mut artifacts = Vec::new();
+    for target in TARGETS {
+        let name = target.artifact();
+        let path = dir.join(&name);
+        if !path.exists() {
+            continue;
+        }
+        let data = fs::read(&path).with_context(|| format!("could not read {}", path.display()))?;
+        let sha256 = hex(&Sha256::digest(&data));
+        fs::write(
+            dir.join(format!("{}.sha256", name)),
+            format!("{}  {}\n", sha256, name),
+        )?;
+
+        let minisig = match &args.minisign_key {
+            Some(key) => Some(sign(key, &path, &format!("zap v{} {}", version, name))?),
+            None => None,
+        };
+        println!("{}  {}", sha256, name);
+        artifacts.push(ReleaseArtifact {
+            platform: target.platform.to_string(),
+            url: format!("{}/{}", base_url, name),
+            name,
+            sha256,
+            minisig,
+        });
+    }
+    if artifacts.is_empty() {
+        bail!("no artifacts in {}", dir.display());
+    }
+    if args.minisign_key.is_none() {
+        eprintln!("warning: artifacts aren't signed, so zap update will refuse them");
+    }
+
+    let manifest = ReleaseManifest {
+        version: version.to_string(),
+        published_at: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        artifacts,
+    };
+    let path = dir.join("manifest.json");
+    fs::write(&path, serde_json::to_string_pretty(&manifest)? + "\n")?;
+    println!("wrote {}", path.display());
+    Ok(())
+}
+
+/// Sign `path` with minisign, returning the signature file's contents
+fn sign(key: &Path, path: &Path, comment: &str) -> Result<String> {
+    let mut child = Command::new("minisign")
+        .arg("-S")
+        .arg("-s")
+        .arg(key)
+        .arg("-m")
+        .arg(path)
+        .args(["-t", comment])
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("could not run minisign")?;
+    if let Ok(password) = std::env::var("MINISIGN_PASSWORD") {
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        writeln!(stdin, "{}", password)?;
+    }
+    if !child.wait()?.success() {
+        bail!("signing {} failed", path.display());
+    }
+
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(".minisig");
+    Ok(fs::read_to_string(&signature)?)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_targets() {
+        let picked = pick_targets(&["linux-armv7".into(), "aarch64-apple-darwin".into()]).unwrap();
+        let platforms: Vec<_> = picked.iter().map(|t| t.platform).collect();
+        assert_eq!(platforms, ["linux-armv7", "darwin-arm64"]);
+        assert!(pick_targets(&["windows-x86_64".into()]).is_err());
+
+        let host = pick_targets(&[]).unwrap();
+        assert!(host.iter().any(|t| t.platform == "linux-x86_64"));
+        assert_eq!(host.iter().any(|t| t.is_macos()), cfg!(target_os = "macos"));
+    }
+
+    #[test]
+    fn test_write_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("zap-linux-armv7"), b"binary").unwrap();
+        fs::write(dir.path().join("notes.txt"), b"not an artifact").unwrap();
+        let args = ManifestArgs {
+            version: "v1.2.3".into(),
+            base_url: None,
+            minisign_key: None,
+        };
+        write_manifest(dir.path(), &args).unwrap();
+
+        let sha256 = hex(&Sha256::digest(b"binary"));
+        let checksum = fs::read_to_string(dir.path().join("zap-linux-armv7.sha256")).unwrap();
+        assert_eq!(checksum, format!("{}  zap-linux-armv7\n", sha256));
+
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.path().join("manifest.json")).unwrap()).unwrap();
+        assert_eq!(manifest["version"], "1.2.3");
+        let artifacts = manifest["artifacts"].as_array().unwrap();
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0]["platform"], "linux-armv7");
+        assert_eq!(
+            artifacts[0]["url"],
+            format!("{}/v1.2.3/zap-linux-armv7", RELEASES)
+        );
+        assert_eq!(artifacts[0]["sha256"], sha256.as_str());
+        assert!(artifacts[0].get("minisig").is_none());
+
+        assert!(write_manifest(&dir.path().join("empty"), &args).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! `zap about`: what this binary is, for bug reports and for the license
+//! notices its distribution has to carry
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use console::style;
+
+use crate::output::accent;
+use crate::relay_url;
+
+/// What the `zap` binary's build script found out about the build
+#[derive(Debug, Clone, Copy)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Short hash of the commit built, or `unknown`
+    pub commit: &'static str,
+    /// Rust target triple
+    pub target: &'static str,
+    /// Enabled cargo features, comma-separated
+    pub features: &'static str,
+    /// The third-party crates compiled in, one `name\tversion\tlicense`
+    /// per line
+    pub crates: &'static str,
+    /// Their license texts and notices
+    pub notices: &'static str,
+}
+
+/// Print the build details, or with `licenses` the third-party notices
+pub fn run_about(info: BuildInfo, licenses: bool) -> Result<()> {
+    if licenses {
+        if info.notices.is_empty() {
+            println!("{}", style("No license notices in this build").dim());
+        } else {
+            print!("{}", info.notices);
+        }
+        return Ok(());
+    }
+
+    println!("{} {}", accent("zap"), info.version);
+    let features = match info.features {
+        "" => "none".to_string(),
+        features => features.replace(',', ", "),
+    };
+    for (key, value) in [
+        ("commit", info.commit),
+        ("target", info.target),
+        ("features", &features),
+        ("relay", &relay_url(None)),
+        ("license", env!("CARGO_PKG_LICENSE")),
+    ] {
+        println!("{:<10}{}", format!("{}:", key), value);
+    }
+
+    let counts = license_counts(info.crates);
+    if counts.is_empty() {
+        return Ok(());
+    }
+    let total: usize = counts.iter().map(|(_, n)| n).sum();
+    println!("\nBuilt with {} third-party crates:", total);
+    for (license, n) in counts {
+        println!("  {:>4}  {}", n, license);
+    }
+    println!(
+        "{}",
+        style("Run `zap about --licenses` for their license texts").dim()
+    );
+    Ok(())
+}
+
+/// How many crates use each license, most used first
+fn license_counts(crates: &str) -> Vec<(&str, usize)> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for line in crates.lines().filter(|l| !l.is_empty()) {
+        let license = line.splitn(3, '\t').nth(2).unwrap_or("unknown");
+        *counts.entry(license).or_default() += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_license_counts() {
+        let crates = "anyhow\t1.0.0\tMIT OR Apache-2.0\n\
+                      ring\t0.17.0\tApache-2.0 AND ISC\n\
+                      serde\t1.0.0\tMIT OR Apache-2.0\n\
+                      weird\t0.1.0";
+        assert_eq!(
+            license_counts(crates),
+            vec![
+                ("MIT OR Apache-2.0", 2),
+                ("Apache-2.0 AND ISC", 1),
+                ("unknown", 1)
+            ]
+        );
+        assert!(license_counts("").is_empty());
+    }
+}
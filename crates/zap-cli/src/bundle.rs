@@ -0,0 +1,83 @@
+//! `--debug-bundle`: everything needed to report a failed transfer
+//!
+//! The command runs with its log kept, debug messages included, whatever
+//! `RUST_LOG` prints. Once it's done, whether it worked or not, the bundle
+//! is written: the version and platform, the command, how it ended and
+//! the log. Codes and paths given on the command line are left out, since
+//! bundles get attached to public bug reports.
+
+use std::future::Future;
+use std::path::Path;
+
+use anyhow::Result;
+use tracing::Instrument;
+
+use crate::update::current_platform;
+
+/// The name the CLI's log is kept under
+const TRANSFER: &str = "cli";
+
+/// Run `command`, the `zap` subcommand `name`, then write its debug bundle
+/// to `path`
+pub async fn with_debug_bundle(
+    path: &Path,
+    name: &str,
+    command: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    let result = command.instrument(zap_core::logs::span(TRANSFER)).await;
+    let log = zap_core::logs::get(TRANSFER).unwrap_or_default();
+    match std::fs::write(path, bundle(name, &result, &log)) {
+        Ok(()) => eprintln!("Debug bundle written to {}", path.display()),
+        Err(e) => eprintln!(
+            "Could not write the debug bundle to {}: {}",
+            path.display(),
+            e
+        ),
+    }
+    result
+}
+
+fn bundle(name: &str, result: &Result<()>, log: &str) -> String {
+    let outcome = match result {
+        Ok(()) => "succeeded".to_string(),
+        Err(e) => format!("failed: {:#}", e),
+    };
+    format!(
+        "zap debug bundle\n\
+         version: {}\n\
+         platform: {}\n\
+         command: zap {}\n\
+         outcome: {}\n\
+         written: {}\n\
+         \n\
+         {}",
+        env!("CARGO_PKG_VERSION"),
+        current_platform(),
+        name,
+        outcome,
+        chrono::Local::now().to_rfc3339(),
+        log
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_debug_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.txt");
+        let failing = async {
+            tracing::debug!("dialing the sender");
+            anyhow::bail!("connection lost")
+        };
+        let result = with_debug_bundle(&path, "receive", failing).await;
+        assert!(result.is_err());
+
+        let bundle = std::fs::read_to_string(&path).unwrap();
+        assert!(bundle.starts_with("zap debug bundle\n"));
+        assert!(bundle.contains("\ncommand: zap receive\n"));
+        assert!(bundle.contains("\noutcome: failed: connection lost\n"));
+    }
+}
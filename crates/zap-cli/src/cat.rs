@@ -0,0 +1,126 @@
+//! `zap cat`: print a small text file instead of saving it
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::SyntaxSet;
+use syntect::util::{LinesWithEndings, as_24_bit_terminal_escaped};
+use zap_core::directory::safe_join;
+use zap_core::{ReceiveOptions, ReceiveProgress};
+
+use crate::code::resolve_ticket;
+use crate::format_bytes;
+use crate::net::start_node;
+use crate::output::{accent, success, symbol};
+
+/// Receive a file into memory and print it to stdout
+///
+/// Status goes to stderr so the output can be piped.
+pub async fn run_cat(
+    code: String,
+    max_size: u64,
+    save: bool,
+    plain: bool,
+    relay: String,
+) -> Result<()> {
+    let ticket = resolve_ticket(&code, &relay).await?;
+    let node = start_node(None).await?;
+
+    let options = ReceiveOptions {
+        in_memory: Some(max_size),
+        ..Default::default()
+    };
+    let mut progress_rx = node.receive_with_options(ticket, None, options).await?;
+
+    let mut loaded = None;
+    while let Some(progress) = progress_rx.recv().await {
+        match progress {
+            ReceiveProgress::Offer { name, size, .. } => {
+                let size = size.map(format_bytes).unwrap_or("streaming".into());
+                eprintln!("{} {} ({})", accent(symbol("⚡")), accent(&name), size);
+            }
+            ReceiveProgress::Loaded { name, data, .. } => {
+                loaded = Some((name, data));
+                break;
+            }
+            ReceiveProgress::Error(e) => anyhow::bail!("Transfer failed: {}", e),
+            _ => {}
+        }
+    }
+    node.shutdown().await?;
+
+    let Some((name, data)) = loaded else {
+        anyhow::bail!("Transfer ended without any data");
+    };
+
+    if save {
+        let path = safe_join(&std::env::current_dir()?, &name)?;
+        std::fs::write(&path, &data).with_context(|| format!("writing {}", path.display()))?;
+        eprintln!(
+            "{} Saved to {}",
+            success(symbol("✓")).bold(),
+            accent(path.display())
+        );
+    }
+
+    let text = match std::str::from_utf8(&data) {
+        Ok(text) if !text.contains('\0') => text,
+        _ if save => return Ok(()),
+        _ => anyhow::bail!(
+            "{} looks binary, not printing it (use --save or zap receive)",
+            name
+        ),
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    let highlighted = if !plain && console::colors_enabled() {
+        highlight(&name, text)
+    } else {
+        None
+    };
+    stdout.write_all(highlighted.as_deref().unwrap_or(text).as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Color `text` for the terminal by its file name or first line, if the
+/// syntax is known
+fn highlight(name: &str, text: &str) -> Option<String> {
+    let syntaxes = SyntaxSet::load_defaults_newlines();
+    let themes = ThemeSet::load_defaults();
+
+    let syntax = Path::new(name)
+        .extension()
+        .and_then(|ext| syntaxes.find_syntax_by_extension(ext.to_str()?))
+        .or_else(|| syntaxes.find_syntax_by_first_line(text))?;
+    let mut highlighter = HighlightLines::new(syntax, &themes.themes["base16-ocean.dark"]);
+
+    let mut out = String::with_capacity(text.len() * 2);
+    for line in LinesWithEndings::from(text) {
+        let ranges = highlighter.highlight_line(line, &syntaxes).ok()?;
+        out.push_str(&as_24_bit_terminal_escaped(&ranges, false));
+    }
+    // Reset the colors so they don't leak into the prompt
+    out.push_str("\x1b[0m");
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        let rust = highlight("main.rs", "fn main() {}\n").unwrap();
+        assert!(rust.contains("\x1b[38;2;"));
+        assert!(rust.contains("main"));
+
+        // Detected from a shebang when there's no extension
+        assert!(highlight("run", "#!/bin/sh\necho hi\n").is_some());
+
+        assert!(highlight("notes", "just some words\n").is_none());
+    }
+}
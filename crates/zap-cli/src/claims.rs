@@ -0,0 +1,192 @@
+//! `zap send` hearing from the relay when its code is used
+//!
+//! A relay hands back a token with the code that follows it at
+//! `/api/codes/{code}/events`: each lookup of the code, and each download
+//! of it through the relay's web page. A receiver that claims the code
+//! shows up before its connection does, and a browser that downloads
+//! through the relay shows up at all. Relays without it, and failed polls,
+//! just leave the sender without these lines.
+
+use std::time::Duration;
+
+use console::style;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::output::{accent, symbol};
+
+/// How long to wait for a poll, a bit longer than the relay holds one
+const POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait after a poll fails before the next
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct Events {
+    events: Vec<CodeEvent>,
+}
+
+/// Something that happened to a code, as the relay tells its sender
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CodeEvent {
+    seq: u64,
+    #[serde(flatten)]
+    pub kind: CodeEventKind,
+    /// Lookups of the code so far, this one included
+    pub claims: u64,
+    /// Downloads through the relay so far
+    pub downloads: u64,
+}
+
+/// What happened to the code
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CodeEventKind {
+    /// A receiver looked the code up, with zap or on the relay's web page
+    Claimed { web: bool },
+    /// A browser finished downloading it through the relay
+    Downloaded { file_name: Option<String> },
+    /// Something a newer relay reports
+    #[serde(other)]
+    Other,
+}
+
+/// The events of a code, as the relay reports them
+pub(crate) struct Claims {
+    events: Option<mpsc::Receiver<CodeEvent>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Claims {
+    /// Nothing to follow, e.g. without a relay
+    pub(crate) fn none() -> Self {
+        Self {
+            events: None,
+            task: None,
+        }
+    }
+
+    /// Follow `code` on `relay` with the token it was registered with
+    pub(crate) fn follow(relay: &str, code: &str, token: &str) -> Self {
+        let (tx, rx) = mpsc::channel(16);
+        let url = format!("{}/api/codes/{}/events", relay, code);
+        let task = tokio::spawn(poll(url, token.to_string(), tx));
+        Self {
+            events: Some(rx),
+            task: Some(task),
+        }
+    }
+
+    /// The next event, waiting forever once there are no more
+    pub(crate) async fn next(&mut self) -> CodeEvent {
+        if let Some(events) = &mut self.events {
+            if let Some(event) = events.recv().await {
+                return event;
+            }
+            self.events = None;
+        }
+        std::future::pending().await
+    }
+}
+
+impl Drop for Claims {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+async fn poll(url: String, token: String, tx: mpsc::Sender<CodeEvent>) {
+    let Ok(client) = crate::tls::relay_client() else {
+        return;
+    };
+    let mut after = 0;
+    loop {
+        let sent = client
+            .get(&url)
+            .query(&[("token", token.as_str()), ("after", &after.to_string())])
+            .timeout(POLL_TIMEOUT)
+            .send()
+            .await;
+        let events = match sent {
+            Ok(resp) if resp.status().is_success() => resp.json::<Events>().await,
+            // The code expired, or the relay restarted or doesn't know the
+            // endpoint; none of that gets better by asking again
+            Ok(_) => return,
+            Err(_) => {
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let Ok(events) = events else {
+            return;
+        };
+        for event in events.events {
+            after = event.seq;
+            if tx.send(event).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// What to tell the sender about `event`
+pub(crate) fn line(event: &CodeEvent) -> Option<String> {
+    let (what, count) = match &event.kind {
+        CodeEventKind::Claimed { web: false } => {
+            ("A receiver claimed your code".to_string(), event.claims)
+        }
+        CodeEventKind::Claimed { web: true } => (
+            "A receiver claimed your code on the web page".to_string(),
+            event.claims,
+        ),
+        CodeEventKind::Downloaded { file_name } => (
+            format!(
+                "{} was downloaded through the relay",
+                file_name.as_deref().unwrap_or("The file")
+            ),
+            event.downloads,
+        ),
+        CodeEventKind::Other => return None,
+    };
+    let count = match count {
+        1 => String::new(),
+        n => format!("{}", style(format!(" ({} so far)", n)).dim()),
+    };
+    Some(format!("{} {}{}", accent(symbol("→")), what, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let events: Events = serde_json::from_str(
+            r#"{"events": [
+                {"seq": 1, "type": "claimed", "web": false, "claims": 1, "downloads": 0},
+                {"seq": 2, "type": "downloaded", "file_name": "slides.pdf", "claims": 1, "downloads": 1},
+                {"seq": 3, "type": "claimed", "web": true, "claims": 2, "downloads": 1},
+                {"seq": 4, "type": "something_new", "claims": 2, "downloads": 1}
+            ]}"#,
+        )
+        .unwrap();
+        let lines: Vec<_> = events.events.iter().map(line).collect();
+        assert!(
+            lines[0]
+                .as_ref()
+                .unwrap()
+                .ends_with("A receiver claimed your code")
+        );
+        assert!(
+            lines[1]
+                .as_ref()
+                .unwrap()
+                .contains("slides.pdf was downloaded")
+        );
+        assert!(lines[2].as_ref().unwrap().contains("(2 so far)"));
+        assert!(lines[3].is_none());
+    }
+}
@@ -0,0 +1,382 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use zap_core::relay_hint::TOKEN_PREFIX;
+use zap_core::ticket::split_key;
+use zap_core::{RelayHint, Ticket, TransferKey};
+
+use crate::output::{accent, success, symbol};
+use crate::{Lookup, lookup_ticket};
+
+/// What a pasted code, ticket or link points at
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum CodeInput {
+    /// A short or word code to look up, on the relay from the link if any
+    Code { code: String, relay: Option<String> },
+
+    /// A full ticket
+    Ticket(String),
+
+    /// A relay token that doesn't check out, and why
+    Invalid(String),
+}
+
+/// What a relay makes the codes for this machine's sends of, for the
+/// profile's `code_alphabet`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CodeAlphabet {
+    /// Letters and digits
+    Standard,
+    /// Digits only, easy to read out over a radio or phone
+    Digits,
+    /// NATO alphabet words, as in `bravo-kilo-zulu-echo-mike`
+    Words,
+}
+
+/// Normalize user input, which is often pasted from chat
+///
+/// Accepts bare codes and tickets as well as relay links such as
+/// `https://zapper.cloud/r/abc123`, links carrying a ticket in the
+/// fragment, and codes naming their relay, as `zapper.cloud:abc123` or a
+/// `zap1...` token, ignoring surrounding whitespace, quotes, brackets and
+/// trailing punctuation.
+pub(crate) fn parse_code(input: &str) -> CodeInput {
+    let trimmed = input
+        .trim()
+        .trim_start_matches(['<', '(', '[', '"', '\''])
+        .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '>', '"', '\''])
+        .trim();
+
+    if let Ok(url) = Url::parse(trimmed)
+        && matches!(url.scheme(), "http" | "https")
+        && let Some(host) = url.host_str()
+    {
+        let relay = match url.port() {
+            Some(port) => format!("{}://{}:{}", url.scheme(), host, port),
+            None => format!("{}://{}", url.scheme(), host),
+        };
+
+        // A ticket in the fragment needs no relay at all
+        if let Some(fragment) = url.fragment().filter(|f| !f.is_empty()) {
+            return parse_bare(fragment, Some(relay));
+        }
+
+        let code = url
+            .path_segments()
+            .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+            .unwrap_or_default();
+        return parse_bare(code, Some(relay));
+    }
+
+    parse_bare(trimmed, None)
+}
+
+fn parse_bare(input: &str, relay: Option<String>) -> CodeInput {
+    let input = join_spoken(&input.trim().to_lowercase());
+
+    // Tickets never have a 1 in them, but a custom code might start with
+    // zap1, so only a short one that isn't a token is taken as a code
+    if input.starts_with(TOKEN_PREFIX) {
+        match RelayHint::from_token(&input) {
+            Ok(hint) => return hint_code(hint),
+            Err(e) if !is_short_code(&input) => return CodeInput::Invalid(e.to_string()),
+            Err(_) => {}
+        }
+    }
+    if relay.is_none()
+        && let Some((host, code)) = input.rsplit_once(':')
+        && is_relay_host(host)
+        && is_short_code(code)
+    {
+        return hint_code(RelayHint::new(host, code));
+    }
+
+    if is_short_code(&input) {
+        CodeInput::Code { code: input, relay }
+    } else {
+        CodeInput::Ticket(input)
+    }
+}
+
+/// Put back together a code typed the way it was read out, with spaces, as
+/// in `4829 1375` or `bravo kilo zulu`
+fn join_spoken(input: &str) -> String {
+    let parts: Vec<&str> = input.split_whitespace().collect();
+    if parts.len() > 1 {
+        if parts.iter().all(|p| p.chars().all(|c| c.is_ascii_digit())) {
+            return parts.concat();
+        }
+        if parts.iter().all(|p| p.chars().all(|c| c.is_alphabetic())) {
+            return parts.join("-");
+        }
+    }
+    input.to_string()
+}
+
+fn hint_code(hint: RelayHint) -> CodeInput {
+    CodeInput::Code {
+        relay: Some(hint.url()),
+        code: hint.code,
+    }
+}
+
+/// Whether `host` could be a relay's host, with its port if any; relays
+/// other than `localhost` have a dot in their name
+fn is_relay_host(host: &str) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    (name.contains('.') || name == "localhost")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+}
+
+/// Check if the input looks like a short code or word-based code
+pub(crate) fn is_short_code(input: &str) -> bool {
+    // Word-based code (contains hyphens, like "alpha-bravo-charlie")
+    if input.contains('-')
+        && input
+            .split('-')
+            .all(|w| w.chars().all(|c| c.is_alphabetic()))
+    {
+        return true;
+    }
+
+    // Short alphanumeric code (6 chars or less), or a custom code such as
+    // "standup-recording" of up to 32; tickets are far longer
+    if input.len() <= 32 && input.chars().all(|c| c.is_alphanumeric() || c == '-') {
+        return true;
+    }
+
+    false
+}
+
+/// What pasted input turned out to be
+pub enum Resolved {
+    /// A sender to connect to
+    Ticket(Ticket),
+    /// A file left on `relay` under `code`, sealed under `key`
+    Mirror {
+        relay: String,
+        code: String,
+        key: Option<TransferKey>,
+    },
+}
+
+/// Turn pasted input into a ticket without asking a relay, for `zap
+/// receive --direct`; codes have to be looked up, so only tickets, also
+/// read out as words, are taken
+pub(crate) fn resolve_direct(input: &str) -> Result<Ticket> {
+    if zap_core::mnemonic::is_words(input) {
+        return Ok(zap_core::mnemonic::from_words(input)?);
+    }
+
+    let (input, key) = split_key(input);
+    let ticket = match parse_code(input) {
+        CodeInput::Ticket(ticket) => Ticket::deserialize(&ticket)?,
+        CodeInput::Code { .. } => anyhow::bail!(
+            "--direct needs the sender's full ticket, since a code is looked up on a relay; \
+             `zap send --ticket-only` prints one"
+        ),
+        CodeInput::Invalid(reason) => anyhow::bail!(reason),
+    };
+    match key {
+        Some(key) => Ok(ticket.with_key(key)?),
+        None => Ok(ticket),
+    }
+}
+
+/// Turn pasted input into a ticket, looking codes up on the relay
+///
+/// A relay named in a pasted link takes precedence over `relay`.
+pub(crate) async fn resolve_ticket(input: &str, relay: &str) -> Result<Ticket> {
+    match resolve(input, relay).await? {
+        Resolved::Ticket(ticket) => Ok(ticket),
+        Resolved::Mirror { .. } => {
+            anyhow::bail!("This code is for a file left on the relay; get it with `zap receive`")
+        }
+    }
+}
+
+/// [`resolve_ticket`], for callers that can also fetch files left on the
+/// relay
+pub(crate) async fn resolve(input: &str, relay: &str) -> Result<Resolved> {
+    resolve_with(input, relay, |code| {
+        println!(
+            "{} Looking up code: {}",
+            accent(symbol("⚡")),
+            success(code)
+        );
+    })
+    .await
+}
+
+/// [`resolve`], calling `looking_up` with the code before asking the relay
+/// for it
+pub(crate) async fn resolve_with(
+    input: &str,
+    relay: &str,
+    looking_up: impl FnOnce(&str),
+) -> Result<Resolved> {
+    // A ticket read out as words needs no relay
+    if zap_core::mnemonic::is_words(input) {
+        return Ok(Resolved::Ticket(zap_core::mnemonic::from_words(input)?));
+    }
+
+    let (input, key) = split_key(input);
+    let ticket = match parse_code(input) {
+        CodeInput::Code {
+            code,
+            relay: link_relay,
+        } => {
+            looking_up(&code);
+            let relay = link_relay.as_deref().unwrap_or(relay);
+            match lookup_ticket(relay, &code).await? {
+                Lookup::Ticket(ticket) => ticket,
+                Lookup::Mirror => {
+                    return Ok(Resolved::Mirror {
+                        relay: relay.to_string(),
+                        code,
+                        key,
+                    });
+                }
+            }
+        }
+        CodeInput::Ticket(ticket) => ticket,
+        CodeInput::Invalid(reason) => anyhow::bail!(reason),
+    };
+
+    let ticket = Ticket::deserialize(&ticket)?;
+    match key {
+        Some(key) => Ok(Resolved::Ticket(ticket.with_key(key)?)),
+        None => Ok(Resolved::Ticket(ticket)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(code: &str, relay: Option<&str>) -> CodeInput {
+        CodeInput::Code {
+            code: code.to_string(),
+            relay: relay.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_bare_codes() {
+        assert_eq!(parse_code("abc123"), code("abc123", None));
+        assert_eq!(parse_code("  ABC123\n"), code("abc123", None));
+        assert_eq!(parse_code("abc123."), code("abc123", None));
+        assert_eq!(parse_code("\"abc123\","), code("abc123", None));
+        assert_eq!(
+            parse_code("Apple-Banana-Cherry!"),
+            code("apple-banana-cherry", None)
+        );
+        assert_eq!(
+            parse_code("standup-recording2"),
+            code("standup-recording2", None)
+        );
+
+        // Digit and word codes as they were read out
+        assert_eq!(parse_code("4829 1375"), code("48291375", None));
+        assert_eq!(
+            parse_code("Bravo Kilo  Zulu"),
+            code("bravo-kilo-zulu", None)
+        );
+    }
+
+    #[test]
+    fn test_links() {
+        let relay = Some("https://zapper.cloud");
+        assert_eq!(
+            parse_code("https://zapper.cloud/r/abc123"),
+            code("abc123", relay)
+        );
+        assert_eq!(
+            parse_code("https://zapper.cloud/r/ABC123/"),
+            code("abc123", relay)
+        );
+        assert_eq!(
+            parse_code(" <https://zapper.cloud/r/abc123>. "),
+            code("abc123", relay)
+        );
+        assert_eq!(
+            parse_code("(https://zapper.cloud/r/abc123)"),
+            code("abc123", relay)
+        );
+        assert_eq!(
+            parse_code("http://localhost:8080/r/abc123?utm=chat"),
+            code("abc123", Some("http://localhost:8080"))
+        );
+    }
+
+    #[test]
+    fn test_tickets() {
+        let ticket = "a".repeat(60);
+        assert_eq!(parse_code(&ticket), CodeInput::Ticket(ticket.clone()));
+        assert_eq!(
+            parse_code(&format!("{}\n", ticket.to_uppercase())),
+            CodeInput::Ticket(ticket.clone())
+        );
+        assert_eq!(
+            parse_code(&format!("https://zapper.cloud/r#{}", ticket)),
+            CodeInput::Ticket(ticket)
+        );
+    }
+
+    #[test]
+    fn test_relay_hints() {
+        let relay = Some("https://relay.example.com");
+        assert_eq!(
+            parse_code("relay.example.com:abc123"),
+            code("abc123", relay)
+        );
+        assert_eq!(
+            parse_code(" Relay.Example.com:ABC123. "),
+            code("abc123", relay)
+        );
+        assert_eq!(
+            parse_code("relay.example.com:8443:apple-banana-cherry"),
+            code(
+                "apple-banana-cherry",
+                Some("https://relay.example.com:8443")
+            )
+        );
+
+        let token = RelayHint::new("https://relay.example.com/", "abc123").token();
+        assert_eq!(parse_code(&token), code("abc123", relay));
+        assert_eq!(parse_code(&token.to_uppercase()), code("abc123", relay));
+        let token = RelayHint::new("http://localhost:8080", "abc123").token();
+        assert_eq!(
+            parse_code(&token),
+            code("abc123", Some("http://localhost:8080"))
+        );
+
+        // A mistyped token is caught rather than looked up
+        let mut token = RelayHint::new("https://relay.example.com", "apple-banana-cherry").token();
+        let last = token.pop().unwrap();
+        token.push(if last == 'a' { 'b' } else { 'a' });
+        assert!(matches!(parse_code(&token), CodeInput::Invalid(_)));
+        // but a custom code that happens to start like one is still a code
+        assert_eq!(parse_code("zap1team"), code("zap1team", None));
+    }
+
+    #[test]
+    fn test_resolve_direct() {
+        let key = zap_core::SecretKey::generate(&mut rand::rng());
+        let ticket = Ticket::new(zap_core::EndpointAddr::new(key.public()));
+        let resolved = resolve_direct(&format!(" {} ", ticket.serialize())).unwrap();
+        assert_eq!(resolved.addr.id, key.public());
+
+        // Codes would need a relay
+        for input in ["abc123", "relay.example.com:abc123"] {
+            let error = resolve_direct(input).unwrap_err().to_string();
+            assert!(error.contains("--direct"), "{}", error);
+        }
+    }
+}
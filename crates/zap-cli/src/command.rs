@@ -0,0 +1,90 @@
+//! `zap send --command`: sending what a command writes
+//!
+//! The command's output is sent as it's written, like stdin, so a database
+//! dump or a tar pipeline never has to be staged on disk. A command that
+//! fails fails the transfer, rather than the receiver getting the part of
+//! a dump written before the error as if it were all of it.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::{Context, Result};
+use futures::{StreamExt, TryStreamExt};
+use tokio::process::Command;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::flows::Source;
+use crate::hooks::shell;
+
+/// Start `line` in the shell, to send its output under the name of the
+/// program it runs
+pub(crate) fn spawn(line: &str) -> Result<Source> {
+    let mut child = Command::from(shell(line))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        // Stopped along with the transfer, rather than left writing to
+        // nobody
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Could not run `{}`", line))?;
+    let stdout = child.stdout.take().context("The command has no output")?;
+
+    let name = program_name(line);
+    let line = line.to_string();
+    let exit = futures::stream::once(async move {
+        let status = child.wait().await?;
+        if status.success() {
+            Ok(None)
+        } else {
+            let message = format!("`{}` failed, {}", line, status);
+            Err(std::io::Error::other(message))
+        }
+    });
+    let output = ReaderStream::new(stdout)
+        .map_ok(Some)
+        .chain(exit)
+        .try_filter_map(|chunk| async move { Ok(chunk) });
+    Ok(Source::Stream {
+        name,
+        reader: Box::new(StreamReader::new(Box::pin(output))),
+    })
+}
+
+/// The name of the program `line` starts with, as in `pg_dump` for
+/// `/usr/bin/pg_dump db`
+fn program_name(line: &str) -> String {
+    line.split_whitespace()
+        .next()
+        .and_then(|program| Path::new(program).file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "output".to_string())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    async fn output(line: &str) -> std::io::Result<Vec<u8>> {
+        let Source::Stream { mut reader, .. } = spawn(line).unwrap() else {
+            unreachable!()
+        };
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).await?;
+        Ok(output)
+    }
+
+    #[tokio::test]
+    async fn test_command_output() {
+        assert_eq!(output("printf 'a b'; printf c").await.unwrap(), b"a bc");
+
+        // Whatever was written before the command failed isn't passed off
+        // as all of it
+        let error = output("printf partial; exit 3").await.unwrap_err();
+        assert!(error.to_string().contains("failed"), "{}", error);
+
+        assert_eq!(program_name("/usr/bin/pg_dump -Fc db"), "pg_dump");
+        assert_eq!(program_name("  tar cf - ."), "tar");
+    }
+}
@@ -0,0 +1,210 @@
+//! Settings remembered between runs, stored as TOML in the user's config
+//! directory (e.g. `~/.config/zap/config.toml`)
+//!
+//! Settings at the top level are the default profile. Named profiles, in
+//! `[profiles.<name>]` tables, replace them as a whole when selected with
+//! `--profile` or `zap profile use`.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::code::CodeAlphabet;
+use crate::hooks::Hooks;
+use crate::output::Theme;
+
+/// Name that stands for the top-level settings
+pub(crate) const DEFAULT_PROFILE: &str = "default";
+
+/// The profile given with `--profile`, which wins over the config's
+static SELECTED: OnceLock<String> = OnceLock::new();
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    /// Directory the interactive picker last sent from
+    pub(crate) last_dir: Option<PathBuf>,
+
+    /// Profile used when `--profile` isn't given, set by `zap profile use`
+    pub(crate) profile: Option<String>,
+
+    /// Bytes a month after which transfers warn, set by `zap stats --limit`
+    pub(crate) usage_limit: Option<u64>,
+
+    /// Colors to draw output in
+    pub(crate) theme: Theme,
+
+    /// Settings used without a profile
+    #[serde(flatten)]
+    pub(crate) default: Profile,
+
+    /// Named profiles
+    pub(crate) profiles: BTreeMap<String, Profile>,
+}
+
+/// Settings that can differ between profiles
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Profile {
+    /// Relay server for short codes, when `--relay` isn't given
+    pub(crate) relay: Option<String>,
+
+    /// Where `zap receive` saves when `--output` isn't given, set by
+    /// `zap init`
+    pub(crate) download_dir: Option<PathBuf>,
+
+    /// Private protocol namespace, e.g. `zap/acme/1`; only peers using the
+    /// same one can connect
+    pub(crate) alpn: Option<String>,
+
+    /// PEM certificate and key shown to relays that require client
+    /// certificates, when `--client-cert` isn't given
+    pub(crate) client_cert: Option<PathBuf>,
+    pub(crate) client_key: Option<PathBuf>,
+
+    /// What relays make codes of, and how many characters or words long,
+    /// for codes read out over a radio or phone; the relay decides
+    /// otherwise
+    pub(crate) code_alphabet: Option<CodeAlphabet>,
+    pub(crate) code_length: Option<usize>,
+
+    /// Commands to run after transfers when no flag is given
+    pub(crate) hooks: Hooks,
+
+    /// Paired peers' node ids by name, for `zap cp` and `zap listen`
+    pub(crate) peers: BTreeMap<String, String>,
+}
+
+/// Use the profile `name` for the rest of this run, whatever the config
+/// says
+pub fn select_profile(name: &str) -> Result<()> {
+    if name != DEFAULT_PROFILE && !Config::load().profiles.contains_key(name) {
+        bail!("no profile named {}; see zap profile list", name);
+    }
+    let _ = SELECTED.set(name.to_string());
+    Ok(())
+}
+
+impl Config {
+    /// The name of the profile in use, or `None` for the default settings
+    pub(crate) fn profile_name(&self) -> Option<&str> {
+        SELECTED
+            .get()
+            .map(String::as_str)
+            .or(self.profile.as_deref())
+            .filter(|name| self.profiles.contains_key(*name))
+    }
+
+    /// The settings of the profile in use
+    pub(crate) fn active(&self) -> &Profile {
+        match self.profile_name() {
+            Some(name) => &self.profiles[name],
+            None => &self.default,
+        }
+    }
+
+    /// The settings of the profile in use, to change and save
+    pub(crate) fn active_mut(&mut self) -> &mut Profile {
+        match self.profile_name().map(str::to_string) {
+            Some(name) => self.profiles.entry(name).or_default(),
+            None => &mut self.default,
+        }
+    }
+
+    pub(crate) fn path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("zap").join("config.toml"))
+    }
+
+    /// Load the config, falling back to defaults if it's missing or unreadable
+    pub(crate) fn load() -> Self {
+        Self::path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|text| toml::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        let path = Self::path().context("no config directory on this system")?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, toml::to_string(self)?)
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::Conversion;
+
+    #[test]
+    fn test_round_trip() {
+        let work = Profile {
+            relay: Some("https://relay.acme.example".to_string()),
+            alpn: Some("zap/acme/1".to_string()),
+            download_dir: Some(PathBuf::from("/home/alice/Work")),
+            client_cert: Some(PathBuf::from("/home/alice/.config/zap/acme.crt")),
+            client_key: Some(PathBuf::from("/home/alice/.config/zap/acme.key")),
+            code_alphabet: Some(CodeAlphabet::Digits),
+            code_length: Some(10),
+            ..Default::default()
+        };
+        let config = Config {
+            last_dir: Some(PathBuf::from("/home/alice/Pictures")),
+            profile: Some("work".to_string()),
+            usage_limit: Some(50 << 30),
+            theme: Theme::Colorblind,
+            default: Profile {
+                hooks: Hooks {
+                    on_complete: Some("photos-import {path}".to_string()),
+                    on_sent: None,
+                    convert: BTreeMap::from([(
+                        "heic".to_string(),
+                        Conversion {
+                            to: "jpg".to_string(),
+                            command: "heif-convert {path} {output}".to_string(),
+                        },
+                    )]),
+                },
+                peers: BTreeMap::from([("laptop".to_string(), "ae58ff88".to_string())]),
+                ..Default::default()
+            },
+            profiles: BTreeMap::from([("work".to_string(), work.clone())]),
+        };
+        let text = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<Config>(&text).unwrap(), config);
+        assert_eq!(config.active(), &work);
+
+        // Missing keys fall back to defaults
+        assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_default_profile_at_top_level() {
+        // Configs from before profiles keep working as the default profile
+        let config: Config = toml::from_str(
+            r#"
+            alpn = "zap/acme/1"
+
+            [peers]
+            laptop = "ae58ff88"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.profile_name(), None);
+        assert_eq!(config.active().alpn.as_deref(), Some("zap/acme/1"));
+        assert_eq!(config.active().peers.len(), 1);
+
+        // A profile that no longer exists falls back to the default
+        let config = Config {
+            profile: Some("gone".to_string()),
+            ..config
+        };
+        assert_eq!(config.profile_name(), None);
+    }
+}
@@ -0,0 +1,298 @@
+//! `zap cp` and `zap listen`: copying to and from a paired peer, like scp
+//!
+//! One side of a copy is `name:path`, where `name` is a peer paired with
+//! `zap pair` and `path` is relative to the directory its `zap listen`
+//! serves. Copies into a peer go into an existing directory there.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+use console::style;
+use zap_core::{ListenEvent, ListenOptions, ReceiveProgress, SendProgress};
+
+use crate::config::Config;
+use crate::format_bytes;
+use crate::inbox::{self, InboxOptions};
+use crate::net::start_paired_node;
+use crate::output::{self, accent, failure, success, symbol, warning};
+use crate::peers::{paired, peer_id, peer_name};
+
+/// One side of a copy
+#[derive(Debug, PartialEq, Eq)]
+enum Location {
+    Local(PathBuf),
+    Remote { peer: String, path: String },
+}
+
+impl Location {
+    /// `name:path` for a paired peer's name, otherwise a local path, so
+    /// local paths with a colon still work
+    fn parse(arg: &str, peers: &BTreeMap<String, String>) -> Self {
+        match arg.split_once(':') {
+            Some((peer, path)) if peers.contains_key(peer) => Location::Remote {
+                peer: peer.to_string(),
+                path: path.to_string(),
+            },
+            _ => Location::Local(PathBuf::from(arg)),
+        }
+    }
+}
+
+/// Copy between this machine and a paired peer running `zap listen`
+pub async fn run_cp(source: String, dest: String) -> Result<()> {
+    let peers = Config::load().active().peers.clone();
+    match (
+        Location::parse(&source, &peers),
+        Location::parse(&dest, &peers),
+    ) {
+        (Location::Local(source), Location::Remote { peer, path }) => {
+            copy_to(&peers, &source, &peer, &path).await
+        }
+        (Location::Remote { peer, path }, Location::Local(dest)) => {
+            copy_from(&peers, &peer, &path, &dest).await
+        }
+        (Location::Remote { .. }, Location::Remote { .. }) => {
+            bail!("can't copy between two peers; copy to this machine first")
+        }
+        (Location::Local(_), Location::Local(_)) => {
+            let names: Vec<_> = peers.keys().map(String::as_str).collect();
+            if names.is_empty() {
+                bail!("no paired peers yet; pair with: zap pair <name> <node id>");
+            }
+            bail!(
+                "neither side is on a paired peer, e.g. {}:incoming/ (paired: {})",
+                names[0],
+                names.join(", ")
+            );
+        }
+    }
+}
+
+async fn copy_to(
+    peers: &BTreeMap<String, String>,
+    source: &Path,
+    peer: &str,
+    path: &str,
+) -> Result<()> {
+    let id = peer_id(peers, peer)?;
+    let node = start_paired_node().await?;
+    let mut progress_rx = node.copy_to(id, source, path).await?;
+    println!(
+        "\n{} Copying {} to {}",
+        accent(symbol("⚡")),
+        accent(source.display()),
+        accent(format!("{}:{}", peer, path))
+    );
+
+    let pb = output::bar(0);
+    pb.set_style(output::bar_style(false));
+    while let Some(progress) = progress_rx.recv().await {
+        match progress {
+            SendProgress::Sending {
+                bytes_sent,
+                total_bytes,
+            } => {
+                if let Some(total) = total_bytes {
+                    pb.set_length(total);
+                }
+                pb.set_position(bytes_sent);
+            }
+            SendProgress::Complete { size, .. } => {
+                pb.finish_and_clear();
+                println!(
+                    "{} Copied {} to {}",
+                    success(symbol("✓")).bold(),
+                    format_bytes(size),
+                    accent(peer)
+                );
+                break;
+            }
+            SendProgress::Error(e) => {
+                pb.abandon();
+                bail!("Copy failed: {}", e);
+            }
+            _ => {}
+        }
+    }
+
+    node.shutdown().await?;
+    Ok(())
+}
+
+async fn copy_from(
+    peers: &BTreeMap<String, String>,
+    peer: &str,
+    path: &str,
+    dest: &Path,
+) -> Result<()> {
+    if !dest.is_dir() {
+        bail!("{} is not a directory", dest.display());
+    }
+    let id = peer_id(peers, peer)?;
+    let node = start_paired_node().await?;
+    let mut progress_rx = node.copy_from(id, path, Some(dest)).await?;
+    println!(
+        "\n{} Copying {} to {}",
+        accent(symbol("⚡")),
+        accent(format!("{}:{}", peer, path)),
+        accent(dest.display())
+    );
+
+    let pb = output::bar(0);
+    pb.set_style(output::bar_style(false));
+    while let Some(progress) = progress_rx.recv().await {
+        match progress {
+            ReceiveProgress::Receiving {
+                bytes_received,
+                total_bytes,
+            } => {
+                if let Some(total) = total_bytes {
+                    pb.set_length(total);
+                }
+                pb.set_position(bytes_received);
+            }
+            ReceiveProgress::Complete { path, .. } => {
+                pb.finish_and_clear();
+                println!(
+                    "{} Saved to {}",
+                    success(symbol("✓")).bold(),
+                    accent(path.display())
+                );
+                break;
+            }
+            ReceiveProgress::Error(e) => {
+                pb.abandon();
+                bail!("Copy failed: {}", e);
+            }
+            _ => {}
+        }
+    }
+
+    node.shutdown().await?;
+    Ok(())
+}
+
+/// Serve a directory to paired peers until Ctrl-C
+pub async fn run_listen(dir: PathBuf, read_only: bool, inbox: Option<InboxOptions>) -> Result<()> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    let peers = Config::load().active().peers.clone();
+    if peers.is_empty() {
+        bail!("no paired peers yet; pair with: zap pair <name> <node id>");
+    }
+
+    let node = start_paired_node().await?;
+    let sorting = inbox.is_some();
+    let options = ListenOptions {
+        root: dir.clone(),
+        peers: paired(&peers)?,
+        read_only,
+        inbox: inbox
+            .map(|options| inbox::inbox(&dir, peers.clone(), options))
+            .transpose()?,
+    };
+    let mut events = node.listen(options).await?;
+
+    println!(
+        "\n{} Listening as {}",
+        accent(symbol("⚡")),
+        success(node.id())
+    );
+    println!(
+        "  Serving {} to {} paired peer{}{}",
+        accent(dir.display()),
+        peers.len(),
+        if peers.len() == 1 { "" } else { "s" },
+        if read_only { ", read-only" } else { "" }
+    );
+    if sorting {
+        println!(
+            "  Copies in go to {}",
+            accent(dir.join("inbox").join("<peer>").display())
+        );
+    }
+    println!("  {}", style("Press Ctrl-C to stop").dim());
+
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        match event {
+            Some(ListenEvent::Listening) => {}
+            Some(ListenEvent::Refused { peer }) => {
+                println!(
+                    "{} Refused {}, which isn't paired",
+                    warning(symbol("⚠")),
+                    style(peer_name(&peers, &peer)).dim()
+                );
+            }
+            Some(ListenEvent::Sent { peer, path }) => {
+                println!(
+                    "{} {} copied {}",
+                    success(symbol("✓")).bold(),
+                    accent(peer_name(&peers, &peer)),
+                    path.display()
+                );
+            }
+            Some(ListenEvent::Received { peer, path }) => {
+                println!(
+                    "{} Received {} from {}",
+                    success(symbol("✓")).bold(),
+                    path.display(),
+                    accent(peer_name(&peers, &peer))
+                );
+            }
+            Some(ListenEvent::Failed { peer, message }) => {
+                println!(
+                    "{} {}: {}",
+                    failure(symbol("✗")),
+                    accent(peer_name(&peers, &peer)),
+                    message
+                );
+            }
+            Some(ListenEvent::Error(e)) => bail!("Listening failed: {}", e),
+            None => break,
+        }
+    }
+
+    node.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_location() {
+        let peers = BTreeMap::from([("laptop".to_string(), "ae58ff88".to_string())]);
+
+        assert_eq!(
+            Location::parse("laptop:incoming/", &peers),
+            Location::Remote {
+                peer: "laptop".to_string(),
+                path: "incoming/".to_string()
+            }
+        );
+        assert_eq!(
+            Location::parse("laptop:", &peers),
+            Location::Remote {
+                peer: "laptop".to_string(),
+                path: String::new()
+            }
+        );
+
+        // Unknown names and plain paths stay local
+        assert_eq!(
+            Location::parse("notes:draft.txt", &peers),
+            Location::Local(PathBuf::from("notes:draft.txt"))
+        );
+        assert_eq!(
+            Location::parse("./app.log", &peers),
+            Location::Local(PathBuf::from("./app.log"))
+        );
+    }
+}
@@ -0,0 +1,696 @@
+//! `zap ctl`: manage running transfers from another terminal or a script
+//!
+//! A zap process that's sending registers its transfers here and, with the
+//! first one, listens on a unix socket named after its pid in
+//! `$XDG_RUNTIME_DIR/zap` (or `zap` in the temp directory). The directory
+//! is only accessible to its owner, and a connection is refused unless the
+//! peer's credentials show it runs as the same user, so there's no port or
+//! token to manage. `zap ctl` talks to every socket it finds there.
+//!
+//! Requests and responses are single lines of JSON, such as
+//! `{"cmd":"pause","id":1}`, so other local tools can use the socket too.
+//! `zap ctl` names a transfer `<pid>.<id>`, after the process that owns it.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use anyhow::{Result, bail};
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use zap_core::{PublicKey, TransferHandle};
+
+#[derive(Subcommand, Debug)]
+pub enum CtlAction {
+    /// List the transfers of every running zap process
+    List,
+
+    /// Cancel a transfer
+    Cancel {
+        /// Transfer id from `zap ctl list`, e.g. 4312.1
+        id: String,
+    },
+
+    /// Pause a transfer, keeping the connection
+    Pause {
+        /// Transfer id from `zap ctl list`, e.g. 4312.1
+        id: String,
+    },
+
+    /// Resume a paused transfer
+    Resume {
+        /// Transfer id from `zap ctl list`, e.g. 4312.1
+        id: String,
+    },
+
+    /// Show totals for every running zap process
+    Stats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+pub(crate) enum Request {
+    List,
+    Cancel { id: u64 },
+    Pause { id: u64 },
+    Resume { id: u64 },
+    Stats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Response {
+    Transfers(Vec<TransferInfo>),
+    Stats(Stats),
+    Ok,
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TransferInfo {
+    pub(crate) id: u64,
+    pub(crate) name: String,
+    pub(crate) code: Option<String>,
+    pub(crate) state: State,
+    pub(crate) bytes: u64,
+    pub(crate) total: Option<u64>,
+    /// The receiver's node id, once one has connected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) peer: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum State {
+    Waiting,
+    Sending,
+    Paused,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Stats {
+    pid: u32,
+    uptime_secs: u64,
+    active: usize,
+    completed: u64,
+    bytes_sent: u64,
+}
+
+/// What `zap ctl` last asked of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Requested {
+    Nothing,
+    Pause,
+    Cancel,
+}
+
+/// One registered transfer
+struct Entry {
+    name: String,
+    code: Option<String>,
+    handle: TransferHandle,
+    requested: watch::Sender<Requested>,
+    /// Whether the owner applies pauses itself, because it also pauses
+    /// the transfer for its own reasons
+    scheduled: bool,
+    peer: Option<String>,
+    bytes: u64,
+    total: Option<u64>,
+}
+
+#[derive(Default)]
+struct Registry {
+    entries: BTreeMap<u64, Entry>,
+    next_id: u64,
+    completed: u64,
+    /// Bytes sent by transfers that are no longer registered
+    bytes_sent: u64,
+}
+
+/// The transfers of this process
+struct Process {
+    registry: Mutex<Registry>,
+    started: Instant,
+}
+
+impl Process {
+    fn new() -> Self {
+        Self {
+            registry: Mutex::default(),
+            started: Instant::now(),
+        }
+    }
+
+    fn register(
+        &'static self,
+        name: &str,
+        code: Option<&str>,
+        handle: &TransferHandle,
+        scheduled: bool,
+    ) -> Controlled {
+        let (requested, requests) = watch::channel(Requested::Nothing);
+        let mut registry = self.registry.lock().unwrap();
+        registry.next_id += 1;
+        let id = registry.next_id;
+        registry.entries.insert(
+            id,
+            Entry {
+                name: name.to_string(),
+                code: code.map(str::to_string),
+                handle: handle.clone(),
+                requested,
+                scheduled,
+                peer: None,
+                bytes: 0,
+                total: None,
+            },
+        );
+        Controlled {
+            process: self,
+            id,
+            requests,
+        }
+    }
+
+    async fn answer(&self, request: Request) -> Response {
+        let (id, action) = match request {
+            Request::List => return Response::Transfers(self.list()),
+            Request::Stats => return Response::Stats(self.stats()),
+            Request::Cancel { id } => (id, Requested::Cancel),
+            Request::Pause { id } => (id, Requested::Pause),
+            Request::Resume { id } => (id, Requested::Nothing),
+        };
+
+        let (handle, scheduled) = {
+            let registry = self.registry.lock().unwrap();
+            let Some(entry) = registry.entries.get(&id) else {
+                return Response::Error(format!("no transfer {}", id));
+            };
+            let changed = entry.requested.send_if_modified(|requested| {
+                // A cancelled transfer stays cancelled
+                let change = *requested != Requested::Cancel && *requested != action;
+                if change {
+                    *requested = action;
+                }
+                change
+            });
+            if !changed && action != Requested::Cancel {
+                return Response::Ok;
+            }
+            (entry.handle.clone(), entry.scheduled)
+        };
+        match action {
+            Requested::Cancel => handle.cancel().await,
+            Requested::Pause if !scheduled => handle.pause(),
+            Requested::Nothing if !scheduled => handle.resume(),
+            _ => {}
+        }
+        Response::Ok
+    }
+
+    fn list(&self) -> Vec<TransferInfo> {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .entries
+            .iter()
+            .map(|(&id, entry)| {
+                let state =
+                    if entry.handle.is_paused() || *entry.requested.borrow() == Requested::Pause {
+                        State::Paused
+                    } else if entry.peer.is_some() {
+                        State::Sending
+                    } else {
+                        State::Waiting
+                    };
+                TransferInfo {
+                    id,
+                    name: entry.name.clone(),
+                    code: entry.code.clone(),
+                    state,
+                    bytes: entry.bytes,
+                    total: entry.total,
+                    peer: entry.peer.clone(),
+                }
+            })
+            .collect()
+    }
+
+    fn stats(&self) -> Stats {
+        let registry = self.registry.lock().unwrap();
+        let active_bytes: u64 = registry.entries.values().map(|e| e.bytes).sum();
+        Stats {
+            pid: std::process::id(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            active: registry.entries.len(),
+            completed: registry.completed,
+            bytes_sent: registry.bytes_sent + active_bytes,
+        }
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Entry)) {
+        if let Some(entry) = self.registry.lock().unwrap().entries.get_mut(&id) {
+            f(entry);
+        }
+    }
+}
+
+/// A transfer `zap ctl` can see and control, until dropped
+pub(crate) struct Controlled {
+    process: &'static Process,
+    id: u64,
+    requests: watch::Receiver<Requested>,
+}
+
+impl Controlled {
+    pub(crate) fn connected(&self, peer: PublicKey) {
+        self.process
+            .update(self.id, |entry| entry.peer = Some(peer.to_string()));
+    }
+
+    pub(crate) fn progress(&self, bytes: u64, total: Option<u64>) {
+        self.process.update(self.id, |entry| {
+            entry.bytes = bytes;
+            entry.total = total.or(entry.total);
+        });
+    }
+
+    pub(crate) fn complete(&self) {
+        self.process.registry.lock().unwrap().completed += 1;
+    }
+
+    pub(crate) fn requested(&self) -> Requested {
+        *self.requests.borrow()
+    }
+
+    /// Wait until `zap ctl` asks for something else
+    pub(crate) async fn changed(&mut self) -> Requested {
+        // The sender lives as long as this does
+        let _ = self.requests.changed().await;
+        *self.requests.borrow_and_update()
+    }
+}
+
+impl Drop for Controlled {
+    fn drop(&mut self) {
+        let mut registry = self.process.registry.lock().unwrap();
+        if let Some(entry) = registry.entries.remove(&self.id) {
+            registry.bytes_sent += entry.bytes;
+        }
+    }
+}
+
+/// Register a transfer; pausing from `zap ctl` pauses `handle` directly
+pub(crate) fn register(name: &str, code: Option<&str>, handle: &TransferHandle) -> Controlled {
+    process().register(name, code, handle, false)
+}
+
+/// Register a transfer whose owner pauses and resumes it, treating a
+/// pause from `zap ctl` as one more reason to hold it
+pub(crate) fn register_scheduled(
+    name: &str,
+    code: Option<&str>,
+    handle: &TransferHandle,
+) -> Controlled {
+    process().register(name, code, handle, true)
+}
+
+/// This process's registry, listening on its socket from first use
+fn process() -> &'static Process {
+    static PROCESS: OnceLock<Process> = OnceLock::new();
+    let mut created = false;
+    let process = PROCESS.get_or_init(|| {
+        created = true;
+        Process::new()
+    });
+    #[cfg(unix)]
+    if created && let Err(e) = unix::listen(process, &socket_dir()) {
+        tracing::debug!("zap ctl is unavailable: {}", e);
+    }
+    process
+}
+
+/// Where every zap process of this user puts its socket
+pub(crate) fn socket_dir() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("zap")
+}
+
+/// Split a `<pid>.<id>` transfer id
+fn parse_id(id: &str) -> Result<(u32, u64)> {
+    let parsed = id
+        .split_once('.')
+        .and_then(|(pid, id)| Some((pid.parse().ok()?, id.parse().ok()?)));
+    match parsed {
+        Some(parsed) => Ok(parsed),
+        None => bail!("{} isn't a transfer id (see zap ctl list)", id),
+    }
+}
+
+/// List, pause, resume or cancel the transfers of running zap processes
+pub async fn run_ctl(action: CtlAction) -> Result<()> {
+    #[cfg(unix)]
+    return unix::run(action).await;
+
+    #[cfg(not(unix))]
+    {
+        let _ = action;
+        bail!("zap ctl needs unix domain sockets, which this platform doesn't have")
+    }
+}
+
+#[cfg(unix)]
+pub(crate) use unix::{act, bind, transfers};
+
+#[cfg(unix)]
+mod unix {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    use std::path::Path;
+    use std::time::Duration;
+
+    use anyhow::{Context, anyhow};
+    use console::style;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+
+    use super::*;
+    use crate::format_bytes;
+    use crate::net::format_duration;
+    use crate::output::{accent, success, symbol, warning};
+
+    /// Serve `process` on a socket in `dir`, returning its path
+    pub(super) fn listen(process: &'static Process, dir: &Path) -> Result<PathBuf> {
+        let path = dir.join(format!("{}.sock", std::process::id()));
+        let (listener, uid) = bind(&path)?;
+
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::debug!("zap ctl accept failed: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                if !stream.peer_cred().is_ok_and(|cred| cred.uid() == uid) {
+                    tracing::debug!("refused zap ctl connection from another user");
+                    continue;
+                }
+                tokio::spawn(serve(process, stream));
+            }
+        });
+        Ok(path)
+    }
+
+    /// Listen on a socket at `path`, replacing whatever was left there,
+    /// in a directory only we can get into. Returns our uid, which peers
+    /// have to share.
+    pub(crate) fn bind(path: &Path) -> Result<(UnixListener, u32)> {
+        let dir = path.parent().context("socket path has no directory")?;
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        // Left behind by an earlier process, e.g. one with the same pid
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+
+        // The socket was just created by us, so it says who we are
+        let uid = std::fs::metadata(path)?.uid();
+        let dir_metadata = std::fs::metadata(dir)?;
+        if dir_metadata.uid() != uid || dir_metadata.permissions().mode() & 0o077 != 0 {
+            let _ = std::fs::remove_file(path);
+            bail!("{} is accessible to other users", dir.display());
+        }
+        Ok((listener, uid))
+    }
+
+    /// Answer one connection's requests, a line each
+    async fn serve(process: &'static Process, stream: UnixStream) {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response = match serde_json::from_str(&line) {
+                Ok(request) => process.answer(request).await,
+                Err(e) => Response::Error(format!("bad request: {}", e)),
+            };
+            let Ok(mut line) = serde_json::to_string(&response) else {
+                break;
+            };
+            line.push('\n');
+            if write.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Send one request, or `None` if the process behind the socket is gone
+    pub(super) async fn request(path: &Path, request: &Request) -> Result<Option<Response>> {
+        let stream = match UnixStream::connect(path).await {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                // Its process exited without removing it
+                let _ = std::fs::remove_file(path);
+                return Ok(None);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("connecting to {}", path.display())),
+        };
+        let (read, mut write) = stream.into_split();
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        write.write_all(line.as_bytes()).await?;
+
+        let response = BufReader::new(read)
+            .lines()
+            .next_line()
+            .await?
+            .ok_or_else(|| anyhow!("{} closed the connection", path.display()))?;
+        Ok(Some(serde_json::from_str(&response)?))
+    }
+
+    /// The socket of every zap process, by pid
+    fn sockets() -> Result<Vec<(u32, PathBuf)>> {
+        let entries = match std::fs::read_dir(socket_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut sockets: Vec<_> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let pid = path.file_stem()?.to_str()?.parse().ok()?;
+                (path.extension()? == "sock").then_some((pid, path))
+            })
+            .collect();
+        sockets.sort();
+        Ok(sockets)
+    }
+
+    /// The transfers of every running zap process, by pid
+    pub(crate) async fn transfers() -> Result<Vec<(u32, Vec<TransferInfo>)>> {
+        let mut all = Vec::new();
+        for (pid, path) in sockets()? {
+            if let Some(Response::Transfers(transfers)) = request(&path, &Request::List).await? {
+                all.push((pid, transfers));
+            }
+        }
+        Ok(all)
+    }
+
+    /// Ask process `pid` to cancel, pause or resume one of its transfers
+    pub(crate) async fn act(pid: u32, req: &Request) -> Result<()> {
+        let path = socket_dir().join(format!("{}.sock", pid));
+        match request(&path, req).await? {
+            Some(Response::Ok) => Ok(()),
+            Some(Response::Error(e)) => bail!("{}", e),
+            Some(_) => bail!("unexpected response from zap process {}", pid),
+            None => bail!("no zap process {} is running", pid),
+        }
+    }
+
+    pub(super) async fn run(action: CtlAction) -> Result<()> {
+        let (id, make, done): (_, fn(u64) -> Request, _) = match action {
+            CtlAction::List => return list().await,
+            CtlAction::Stats => return stats().await,
+            CtlAction::Cancel { id } => (id, |id| Request::Cancel { id }, "Cancelled"),
+            CtlAction::Pause { id } => (id, |id| Request::Pause { id }, "Paused"),
+            CtlAction::Resume { id } => (id, |id| Request::Resume { id }, "Resumed"),
+        };
+        let (pid, n) = parse_id(&id)?;
+
+        act(pid, &make(n)).await?;
+        println!("{} {} {}", success(symbol("✓")).bold(), done, id);
+        Ok(())
+    }
+
+    async fn list() -> Result<()> {
+        let mut any = false;
+        for (pid, transfers) in transfers().await? {
+            for transfer in transfers {
+                any = true;
+                let state = match transfer.state {
+                    State::Waiting => style("waiting").dim(),
+                    State::Sending => success("sending"),
+                    State::Paused => warning("paused"),
+                };
+                let progress = match transfer.total {
+                    Some(total) => {
+                        format!("{} / {}", format_bytes(transfer.bytes), format_bytes(total))
+                    }
+                    None => format_bytes(transfer.bytes),
+                };
+                println!(
+                    "{:<12} {:<8} {}  {}  {}",
+                    accent(format!("{}.{}", pid, transfer.id)),
+                    state,
+                    transfer.name,
+                    style(progress).dim(),
+                    success(transfer.code.unwrap_or_default())
+                );
+            }
+        }
+        if !any {
+            println!("{}", style("No transfers running").dim());
+        }
+        Ok(())
+    }
+
+    async fn stats() -> Result<()> {
+        let mut any = false;
+        for (_, path) in sockets()? {
+            let Some(Response::Stats(stats)) = request(&path, &Request::Stats).await? else {
+                continue;
+            };
+            any = true;
+            println!(
+                "{}  {} active, {} completed, {} sent, up {}",
+                accent(format!("pid {}", stats.pid)),
+                stats.active,
+                stats.completed,
+                format_bytes(stats.bytes_sent),
+                format_duration(Duration::from_secs(stats.uptime_secs))
+            );
+        }
+        if !any {
+            println!("{}", style("No zap processes running").dim());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaked() -> &'static Process {
+        Box::leak(Box::new(Process::new()))
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("4312.1").unwrap(), (4312, 1));
+        assert!(parse_id("4312").is_err());
+        assert!(parse_id("a.1").is_err());
+    }
+
+    #[test]
+    fn test_protocol() {
+        let request: Request = serde_json::from_str(r#"{"cmd":"pause","id":3}"#).unwrap();
+        assert_eq!(request, Request::Pause { id: 3 });
+        let request: Request = serde_json::from_str(r#"{"cmd":"list"}"#).unwrap();
+        assert_eq!(request, Request::List);
+        assert_eq!(serde_json::to_string(&Response::Ok).unwrap(), r#""ok""#);
+    }
+
+    #[tokio::test]
+    async fn test_answer() {
+        let process = leaked();
+        let direct = TransferHandle::new();
+        let scheduled = TransferHandle::new();
+        let a = process.register("a.txt", Some("123456"), &direct, false);
+        let mut b = process.register("b.txt", None, &scheduled, true);
+        let peer = zap_core::SecretKey::generate(&mut rand::rng()).public();
+        a.connected(peer);
+        a.progress(10, Some(100));
+
+        // Direct transfers are paused here, scheduled ones by their owner
+        assert_eq!(
+            process.answer(Request::Pause { id: a.id }).await,
+            Response::Ok
+        );
+        assert!(direct.is_paused());
+        assert_eq!(
+            process.answer(Request::Pause { id: b.id }).await,
+            Response::Ok
+        );
+        assert!(!scheduled.is_paused());
+        assert_eq!(b.changed().await, Requested::Pause);
+
+        let Response::Transfers(list) = process.answer(Request::List).await else {
+            panic!("expected a list");
+        };
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].state, State::Paused);
+        assert_eq!((list[0].bytes, list[0].total), (10, Some(100)));
+        assert_eq!(list[0].code.as_deref(), Some("123456"));
+        assert_eq!(list[0].peer, Some(peer.to_string()));
+        assert_eq!(list[1].peer, None);
+
+        assert_eq!(
+            process.answer(Request::Resume { id: a.id }).await,
+            Response::Ok
+        );
+        assert!(!direct.is_paused());
+
+        // A cancel can't be undone by a resume
+        assert_eq!(
+            process.answer(Request::Cancel { id: b.id }).await,
+            Response::Ok
+        );
+        assert_eq!(
+            process.answer(Request::Resume { id: b.id }).await,
+            Response::Ok
+        );
+        assert_eq!(b.requested(), Requested::Cancel);
+
+        a.complete();
+        drop(a);
+        let Response::Stats(stats) = process.answer(Request::Stats).await else {
+            panic!("expected stats");
+        };
+        assert_eq!(
+            (stats.active, stats.completed, stats.bytes_sent),
+            (1, 1, 10)
+        );
+        assert!(matches!(
+            process.answer(Request::Cancel { id: 99 }).await,
+            Response::Error(_)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let process = leaked();
+        let handle = TransferHandle::new();
+        let _transfer = process.register("c.txt", None, &handle, false);
+
+        let path = unix::listen(process, &dir.path().join("zap")).unwrap();
+        let response = unix::request(&path, &Request::List).await.unwrap();
+        let Some(Response::Transfers(list)) = response else {
+            panic!("expected a list");
+        };
+        assert_eq!(list[0].name, "c.txt");
+
+        let response = unix::request(&path, &Request::Pause { id: list[0].id })
+            .await
+            .unwrap();
+        assert_eq!(response, Some(Response::Ok));
+        assert!(handle.is_paused());
+    }
+}
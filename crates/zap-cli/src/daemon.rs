@@ -0,0 +1,845 @@
+//! `zap daemon`: a long-lived node that `zap send` and `zap receive` hand
+//! their transfers to
+//!
+//! Most of the wait before a small transfer is the node starting, binding
+//! an endpoint and reaching a relay. While `zap daemon` runs, send and
+//! receive find its socket, `daemon.sock` next to the `zap ctl` ones, and
+//! ask it to do the transfer on its node instead, which is already online
+//! and keeps this machine's paired key, so receivers see the same node id
+//! every time. They print what it reports as if they'd done it themselves.
+//!
+//! They start their own node as before when there's no daemon, when it's
+//! already sending (a sending node takes whichever receiver connects first,
+//! so it sends one thing at a time), or when they need something only a
+//! node of their own can do, like `--port`, streaming or `--no-daemon`.
+//!
+//! A send's connection also takes `pause`, `resume` and `cancel`, and
+//! closing it cancels the send. A receive carries on in the daemon if the
+//! terminal that asked for it goes away. Like `zap ctl`, everything is a
+//! line of JSON.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use zap_core::protocol::OfferKind;
+use zap_core::{ReceiveProgress, SendProgress, Ticket};
+
+use crate::ctl::Requested;
+use crate::hooks::Conversion;
+
+/// What a client asks of the daemon, a send or a receive first and then,
+/// for a send, what `zap ctl` asks of it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum Request {
+    Send(SendRequest),
+    Receive(ReceiveRequest),
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// A send for the daemon, with the options `zap send` would have used
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SendRequest {
+    /// Absolute paths, all in one folder if there are several
+    pub(crate) paths: Vec<PathBuf>,
+    /// The protocol namespace the client's profile speaks, which the
+    /// daemon's node has to speak too
+    pub(crate) alpn: Option<String>,
+    pub(crate) auto_archive: Option<usize>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_rate: Option<u64>,
+    pub(crate) max_rate_per_receiver: Option<u64>,
+    pub(crate) require_key: bool,
+    pub(crate) retry_rejected: bool,
+    /// Clients from before it was added don't send it
+    #[serde(default)]
+    pub(crate) retry_on_change: bool,
+    pub(crate) label: Option<String>,
+    /// The client profile's conversions, run by the daemon
+    pub(crate) convert: BTreeMap<String, Conversion>,
+    pub(crate) ticket_ttl: Option<Duration>,
+}
+
+/// A receive for the daemon
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ReceiveRequest {
+    /// The ticket, with its key if it has one
+    pub(crate) ticket: String,
+    /// Absolute, as the daemon has a working directory of its own
+    pub(crate) output: PathBuf,
+    pub(crate) alpn: Option<String>,
+    pub(crate) detect_extension: bool,
+    pub(crate) dedup: bool,
+    pub(crate) verify_pieces: bool,
+}
+
+/// What the daemon reports back, mirroring [`SendProgress`] and
+/// [`ReceiveProgress`] without what only the node's owner can use
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event {
+    /// The daemon won't take the transfer, so the client does it itself
+    Declined {
+        reason: String,
+    },
+    /// The ticket for a send, with its key if it has one
+    Ticket {
+        ticket: String,
+    },
+    Converting {
+        files_done: usize,
+        total_files: usize,
+    },
+    Archiving {
+        files_done: usize,
+        total_files: usize,
+    },
+    Waiting,
+    Connected {
+        peer: Option<String>,
+    },
+    Offer {
+        name: String,
+        size: Option<u64>,
+        kind: OfferKind,
+        label: Option<String>,
+    },
+    Progress {
+        bytes: u64,
+        total: Option<u64>,
+    },
+    Paused,
+    Resumed,
+    Rejected {
+        reason: String,
+    },
+    Changed {
+        path: PathBuf,
+    },
+    Unpacking,
+    AlreadyHave {
+        source: PathBuf,
+    },
+    Sent {
+        peer: String,
+        size: u64,
+        checksum: [u8; 32],
+    },
+    Saved {
+        path: PathBuf,
+        checksum: [u8; 32],
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl Event {
+    fn from_send(progress: SendProgress) -> Option<Self> {
+        Some(match progress {
+            SendProgress::Transforming {
+                files_done,
+                total_files,
+            } => Event::Converting {
+                files_done,
+                total_files,
+            },
+            SendProgress::Archiving {
+                files_done,
+                total_files,
+            } => Event::Archiving {
+                files_done,
+                total_files,
+            },
+            SendProgress::Waiting => Event::Waiting,
+            SendProgress::Connected { peer } => Event::Connected {
+                peer: Some(peer.to_string()),
+            },
+            SendProgress::Sending {
+                bytes_sent,
+                total_bytes,
+            } => Event::Progress {
+                bytes: bytes_sent,
+                total: total_bytes,
+            },
+            SendProgress::Paused => Event::Paused,
+            SendProgress::Resumed => Event::Resumed,
+            SendProgress::Rejected { reason } => Event::Rejected { reason },
+            SendProgress::Changed { path } => Event::Changed { path },
+            SendProgress::Complete {
+                peer,
+                size,
+                checksum,
+                ..
+            } => Event::Sent {
+                peer: peer.to_string(),
+                size,
+                checksum,
+            },
+            SendProgress::Error(message) => Event::Error { message },
+            SendProgress::Path(_)
+            | SendProgress::Negotiated(_)
+            | SendProgress::Interrupted { .. }
+            | SendProgress::Throughput { .. }
+            | SendProgress::FileSent { .. }
+            | SendProgress::Receipt(_) => return None,
+        })
+    }
+
+    fn into_send(self) -> Option<SendProgress> {
+        Some(match self {
+            Event::Converting {
+                files_done,
+                total_files,
+            } => SendProgress::Transforming {
+                files_done,
+                total_files,
+            },
+            Event::Archiving {
+                files_done,
+                total_files,
+            } => SendProgress::Archiving {
+                files_done,
+                total_files,
+            },
+            Event::Waiting => SendProgress::Waiting,
+            Event::Connected { peer } => SendProgress::Connected {
+                peer: peer?.parse().ok()?,
+            },
+            Event::Progress { bytes, total } => SendProgress::Sending {
+                bytes_sent: bytes,
+                total_bytes: total,
+            },
+            Event::Paused => SendProgress::Paused,
+            Event::Resumed => SendProgress::Resumed,
+            Event::Rejected { reason } => SendProgress::Rejected { reason },
+            Event::Changed { path } => SendProgress::Changed { path },
+            Event::Sent {
+                peer,
+                size,
+                checksum,
+            } => SendProgress::Complete {
+                peer: peer.parse().ok()?,
+                size,
+                checksum,
+                timings: Default::default(),
+            },
+            Event::Error { message } => SendProgress::Error(message),
+            _ => return None,
+        })
+    }
+
+    fn from_receive(progress: ReceiveProgress) -> Option<Self> {
+        Some(match progress {
+            ReceiveProgress::Connected => Event::Connected { peer: None },
+            ReceiveProgress::Offer {
+                name,
+                size,
+                kind,
+                label,
+            } => Event::Offer {
+                name,
+                size,
+                kind,
+                label,
+            },
+            ReceiveProgress::Receiving {
+                bytes_received,
+                total_bytes,
+            } => Event::Progress {
+                bytes: bytes_received,
+                total: total_bytes,
+            },
+            ReceiveProgress::Paused => Event::Paused,
+            ReceiveProgress::Resumed => Event::Resumed,
+            ReceiveProgress::Unpacking => Event::Unpacking,
+            ReceiveProgress::AlreadyHave { source } => Event::AlreadyHave { source },
+            ReceiveProgress::Complete { path, checksum, .. } => Event::Saved { path, checksum },
+            ReceiveProgress::Error(message) => Event::Error { message },
+            ReceiveProgress::Connecting
+            | ReceiveProgress::Path(_)
+            | ReceiveProgress::Throughput { .. }
+            | ReceiveProgress::Manifest { .. }
+            | ReceiveProgress::FileReceived { .. }
+            | ReceiveProgress::Loaded { .. } => return None,
+        })
+    }
+
+    fn into_receive(self) -> Option<ReceiveProgress> {
+        Some(match self {
+            Event::Connected { .. } => ReceiveProgress::Connected,
+            Event::Offer {
+                name,
+                size,
+                kind,
+                label,
+            } => ReceiveProgress::Offer {
+                name,
+                size,
+                kind,
+                label,
+            },
+            Event::Progress { bytes, total } => ReceiveProgress::Receiving {
+                bytes_received: bytes,
+                total_bytes: total,
+            },
+            Event::Paused => ReceiveProgress::Paused,
+            Event::Resumed => ReceiveProgress::Resumed,
+            Event::Unpacking => ReceiveProgress::Unpacking,
+            Event::AlreadyHave { source } => ReceiveProgress::AlreadyHave { source },
+            Event::Saved { path, checksum } => ReceiveProgress::Complete {
+                path,
+                checksum,
+                timings: Default::default(),
+            },
+            Event::Error { message } => ReceiveProgress::Error(message),
+            _ => return None,
+        })
+    }
+
+    /// Whether nothing follows this
+    fn is_last(&self) -> bool {
+        matches!(
+            self,
+            Event::Declined { .. } | Event::Sent { .. } | Event::Saved { .. } | Event::Error { .. }
+        )
+    }
+}
+
+/// A send the daemon is doing for us, which goes on as long as this lives
+pub(crate) struct Handoff {
+    #[cfg(unix)]
+    write: tokio::net::unix::OwnedWriteHalf,
+}
+
+impl Handoff {
+    /// Pass on what `zap ctl` asked of the send
+    pub(crate) async fn request(&mut self, requested: Requested) {
+        let request = match requested {
+            Requested::Nothing => Request::Resume,
+            Requested::Pause => Request::Pause,
+            Requested::Cancel => Request::Cancel,
+        };
+        #[cfg(unix)]
+        if let Err(e) = unix::write_line(&mut self.write, &request).await {
+            tracing::debug!("lost zap daemon: {}", e);
+        }
+    }
+}
+
+/// Hand a send to the daemon, or `None` if there isn't one or it won't
+/// take it
+pub(crate) async fn send(
+    request: SendRequest,
+) -> Result<Option<(Handoff, Ticket, mpsc::Receiver<SendProgress>)>> {
+    #[cfg(unix)]
+    return unix::send(request).await;
+
+    #[cfg(not(unix))]
+    {
+        let _ = request;
+        Ok(None)
+    }
+}
+
+/// Hand a receive to the daemon, or `None` if there isn't one or it won't
+/// take it
+pub(crate) async fn receive(
+    request: ReceiveRequest,
+) -> Result<Option<mpsc::Receiver<ReceiveProgress>>> {
+    #[cfg(unix)]
+    return unix::receive(request).await;
+
+    #[cfg(not(unix))]
+    {
+        let _ = request;
+        Ok(None)
+    }
+}
+
+/// Keep a node online for other zap commands to send and receive with,
+/// until Ctrl-C
+pub async fn run_daemon() -> Result<()> {
+    #[cfg(unix)]
+    return unix::run().await;
+
+    #[cfg(not(unix))]
+    anyhow::bail!("zap daemon needs unix domain sockets, which this platform doesn't have")
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use anyhow::bail;
+    use console::style;
+    use serde::de::DeserializeOwned;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+    use tokio::net::UnixStream;
+    use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+    use zap_core::{
+        ArchivePolicy, Bandwidth, ReceiveOptions, SendOptions, TransferHandle, TransferKey, ZapNode,
+    };
+
+    use super::*;
+    use crate::config::Config;
+    use crate::ctl::{bind, socket_dir};
+    use crate::hooks;
+    use crate::net::start_paired_node;
+    use crate::output::{accent, failure, success, symbol, warning};
+
+    type Reader = Lines<BufReader<OwnedReadHalf>>;
+
+    fn socket_path() -> PathBuf {
+        socket_dir().join("daemon.sock")
+    }
+
+    pub(super) async fn write_line(
+        write: &mut OwnedWriteHalf,
+        value: &impl Serialize,
+    ) -> Result<()> {
+        let mut line = serde_json::to_string(value)?;
+        line.push('\n');
+        write.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn read_line<T: DeserializeOwned>(lines: &mut Reader) -> Result<Option<T>> {
+        match lines.next_line().await? {
+            Some(line) => Ok(Some(serde_json::from_str(&line)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Connect and make a request, returning the daemon's first answer if
+    /// it takes it
+    async fn hand_off(request: &Request) -> Option<(OwnedWriteHalf, Reader, Event)> {
+        let stream = UnixStream::connect(socket_path()).await.ok()?;
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let answered = async {
+            write_line(&mut write, request).await?;
+            read_line(&mut lines).await
+        };
+        match answered.await {
+            Ok(Some(Event::Declined { reason })) => {
+                tracing::debug!("zap daemon declined: {}", reason);
+                None
+            }
+            Ok(Some(event)) => Some((write, lines, event)),
+            Ok(None) => None,
+            Err(e) => {
+                tracing::debug!("zap daemon is unavailable: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Turn the daemon's events, starting with `first` if there is one,
+    /// into progress, ending with an error if it goes away first
+    fn forward<T: Send + 'static>(
+        first: Option<Event>,
+        mut lines: Reader,
+        write: Option<OwnedWriteHalf>,
+        convert: fn(Event) -> Option<T>,
+        error: fn(String) -> T,
+    ) -> mpsc::Receiver<T> {
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            // Closing our end would tell the daemon we're gone
+            let _write = write;
+            let mut first = first;
+            loop {
+                let read = match first.take() {
+                    Some(event) => Ok(Some(event)),
+                    None => read_line::<Event>(&mut lines).await,
+                };
+                let event = match read {
+                    Ok(Some(event)) => event,
+                    Ok(None) => {
+                        let _ = tx.send(error("zap daemon stopped".into())).await;
+                        break;
+                    }
+                    Err(e) => {
+                        let _ = tx.send(error(format!("lost zap daemon: {}", e))).await;
+                        break;
+                    }
+                };
+                let last = event.is_last();
+                if let Some(progress) = convert(event)
+                    && tx.send(progress).await.is_err()
+                {
+                    break;
+                }
+                if last {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+
+    pub(super) async fn send(
+        request: SendRequest,
+    ) -> Result<Option<(Handoff, Ticket, mpsc::Receiver<SendProgress>)>> {
+        let Some((write, lines, first)) = hand_off(&Request::Send(request)).await else {
+            return Ok(None);
+        };
+        let ticket = match first {
+            Event::Ticket { ticket } => Ticket::deserialize(&ticket)?,
+            Event::Error { message } => bail!("{}", message),
+            event => bail!("zap daemon answered with {:?}", event),
+        };
+        let progress = forward(None, lines, None, Event::into_send, SendProgress::Error);
+        Ok(Some((Handoff { write }, ticket, progress)))
+    }
+
+    pub(super) async fn receive(
+        request: ReceiveRequest,
+    ) -> Result<Option<mpsc::Receiver<ReceiveProgress>>> {
+        let Some((write, lines, first)) = hand_off(&Request::Receive(request)).await else {
+            return Ok(None);
+        };
+        // The first event is progress like any other
+        Ok(Some(forward(
+            Some(first),
+            lines,
+            Some(write),
+            Event::into_receive,
+            ReceiveProgress::Error,
+        )))
+    }
+
+    /// The daemon's node, and what it's doing
+    struct Daemon {
+        node: ZapNode,
+        /// The protocol namespace of the profile it started with
+        alpn: Option<String>,
+        /// Held while a send waits or runs
+        sending: tokio::sync::Mutex<()>,
+    }
+
+    pub(super) async fn run() -> Result<()> {
+        let path = socket_path();
+        if UnixStream::connect(&path).await.is_ok() {
+            bail!("zap daemon is already running");
+        }
+
+        let node = start_paired_node().await?;
+        let daemon = Arc::new(Daemon {
+            node,
+            alpn: Config::load().active().alpn.clone(),
+            sending: tokio::sync::Mutex::new(()),
+        });
+        let (listener, uid) = bind(&path)?;
+
+        println!(
+            "\n{} Daemon running as {}",
+            accent(symbol("⚡")),
+            success(daemon.node.id())
+        );
+        println!(
+            "  {}",
+            style("zap send and zap receive in other terminals now use it").dim()
+        );
+        println!("  {}", style("Press Ctrl-C to stop").dim());
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = tokio::signal::ctrl_c() => break,
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::debug!("zap daemon accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            if !stream.peer_cred().is_ok_and(|cred| cred.uid() == uid) {
+                tracing::debug!("refused zap daemon connection from another user");
+                continue;
+            }
+            tokio::spawn(serve(daemon.clone(), stream));
+        }
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    /// Do what one connection asks for
+    async fn serve(daemon: Arc<Daemon>, stream: UnixStream) {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let request = match read_line::<Request>(&mut lines).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => {
+                let message = format!("bad request: {}", e);
+                let _ = write_line(&mut write, &Event::Error { message }).await;
+                return;
+            }
+        };
+        let result = match request {
+            Request::Send(request) => daemon.send(request, lines, &mut write).await,
+            Request::Receive(request) => daemon.receive(request, &mut write).await,
+            _ => {
+                let message = "nothing to control yet".to_string();
+                let _ = write_line(&mut write, &Event::Error { message }).await;
+                return;
+            }
+        };
+        if let Err(e) = result {
+            let message = e.to_string();
+            let _ = write_line(&mut write, &Event::Error { message }).await;
+        }
+    }
+
+    impl Daemon {
+        /// Why the daemon can't do a transfer for a client speaking `alpn`
+        fn mismatch(&self, alpn: &Option<String>) -> Option<Event> {
+            (*alpn != self.alpn).then(|| Event::Declined {
+                reason: "it speaks another protocol namespace".into(),
+            })
+        }
+
+        async fn send(
+            &self,
+            request: SendRequest,
+            mut lines: Reader,
+            write: &mut OwnedWriteHalf,
+        ) -> Result<()> {
+            if let Some(declined) = self.mismatch(&request.alpn) {
+                return write_line(write, &declined).await;
+            }
+            let Ok(_sending) = self.sending.try_lock() else {
+                let declined = Event::Declined {
+                    reason: "it's already sending".into(),
+                };
+                return write_line(write, &declined).await;
+            };
+
+            let handle = TransferHandle::new();
+            let bandwidth = (request.max_rate.is_some() || request.max_rate_per_receiver.is_some())
+                .then(|| Bandwidth::new(request.max_rate, request.max_rate_per_receiver));
+            let options = SendOptions {
+                auto_archive: request.auto_archive.map(|max_files| ArchivePolicy {
+                    max_files,
+                    ..Default::default()
+                }),
+                handle: Some(handle.clone()),
+                idle_timeout: request.idle_timeout,
+                key: request.require_key.then(TransferKey::generate),
+                bandwidth,
+                retry_rejected: request.retry_rejected,
+                retry_on_change: request.retry_on_change,
+                label: request.label,
+                transform: hooks::transform(request.convert),
+                ticket_ttl: request.ticket_ttl,
+                ..Default::default()
+            };
+            let (ticket, mut progress) = match request.paths.as_slice() {
+                [path] => self.node.send_with_options(path, options).await?,
+                paths => self.node.send_many(paths, options).await?,
+            };
+            write_line(
+                write,
+                &Event::Ticket {
+                    ticket: ticket.share(),
+                },
+            )
+            .await?;
+            let name = describe(&request.paths);
+            println!("{} Sending {}", accent(symbol("⚡")), accent(&name));
+
+            // Once the client's gone, the send only has to wind down
+            let mut client = true;
+            loop {
+                let progress = tokio::select! {
+                    progress = progress.recv() => progress,
+                    line = read_line::<Request>(&mut lines), if client => {
+                        match line {
+                            Ok(Some(Request::Pause)) => handle.pause(),
+                            Ok(Some(Request::Resume)) => handle.resume(),
+                            Ok(Some(Request::Cancel)) => handle.cancel().await,
+                            Ok(Some(_)) => {}
+                            Ok(None) | Err(_) => {
+                                client = false;
+                                handle.cancel().await;
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let Some(progress) = progress else {
+                    break;
+                };
+                let Some(event) = Event::from_send(progress) else {
+                    continue;
+                };
+                log(&name, &event);
+                let last = event.is_last();
+                if client && write_line(write, &event).await.is_err() {
+                    client = false;
+                    handle.cancel().await;
+                }
+                if last {
+                    break;
+                }
+            }
+            Ok(())
+        }
+
+        async fn receive(&self, request: ReceiveRequest, write: &mut OwnedWriteHalf) -> Result<()> {
+            if let Some(declined) = self.mismatch(&request.alpn) {
+                return write_line(write, &declined).await;
+            }
+
+            let ticket = Ticket::deserialize(&request.ticket)?;
+            let options = ReceiveOptions {
+                detect_extension: request.detect_extension,
+                keep_state: true,
+                dedup: request.dedup,
+                verify_pieces: request.verify_pieces,
+                ..Default::default()
+            };
+            let mut progress = self
+                .node
+                .receive_with_options(ticket, Some(&request.output), options)
+                .await?;
+            let name = request.output.display().to_string();
+            println!("{} Receiving into {}", accent(symbol("⚡")), accent(&name));
+
+            // Nothing can stop a receive, so it's seen through without
+            // the client if need be
+            let mut client = true;
+            while let Some(progress) = progress.recv().await {
+                let Some(event) = Event::from_receive(progress) else {
+                    continue;
+                };
+                log(&name, &event);
+                let last = event.is_last();
+                if client && write_line(write, &event).await.is_err() {
+                    client = false;
+                }
+                if last {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// What's being sent, for the daemon's own output
+    fn describe(paths: &[PathBuf]) -> String {
+        let name = |path: &Path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.display().to_string())
+        };
+        match paths {
+            [path] => name(path),
+            paths => format!("{} items", paths.len()),
+        }
+    }
+
+    /// Say how a transfer ended, in the daemon's terminal
+    fn log(name: &str, event: &Event) {
+        match event {
+            Event::Sent { .. } => println!("{} Sent {}", success(symbol("✓")).bold(), name),
+            Event::Saved { path, .. } => {
+                println!("{} Saved {}", success(symbol("✓")).bold(), path.display())
+            }
+            Event::Rejected { reason } => {
+                println!("{} {} was rejected: {}", warning(symbol("⚠")), name, reason)
+            }
+            Event::Error { message } => {
+                println!(
+                    "{} {} failed: {}",
+                    failure(symbol("✗")).bold(),
+                    name,
+                    message
+                )
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_lines() {
+        let request: Request = serde_json::from_str(
+            r#"{"cmd":"receive","ticket":"abc","output":"/tmp","alpn":null,"detect_extension":true,"dedup":false,"verify_pieces":false}"#,
+        )
+        .unwrap();
+        assert!(
+            matches!(request, Request::Receive(ReceiveRequest { ref ticket, .. }) if ticket == "abc")
+        );
+        assert_eq!(
+            serde_json::to_string(&Request::Pause).unwrap(),
+            r#"{"cmd":"pause"}"#
+        );
+
+        let event = Event::Progress {
+            bytes: 10,
+            total: Some(20),
+        };
+        let line = serde_json::to_string(&event).unwrap();
+        assert_eq!(line, r#"{"event":"progress","bytes":10,"total":20}"#);
+        assert_eq!(serde_json::from_str::<Event>(&line).unwrap(), event);
+    }
+
+    #[test]
+    fn test_progress_round_trip() {
+        let progress = Event::Progress {
+            bytes: 5,
+            total: None,
+        };
+        assert!(matches!(
+            progress.clone().into_send(),
+            Some(SendProgress::Sending {
+                bytes_sent: 5,
+                total_bytes: None
+            })
+        ));
+        assert!(matches!(
+            progress.into_receive(),
+            Some(ReceiveProgress::Receiving {
+                bytes_received: 5,
+                total_bytes: None
+            })
+        ));
+
+        // The end of a receive comes through whole
+        let saved = Event::from_receive(ReceiveProgress::Complete {
+            path: "/tmp/a".into(),
+            checksum: [7; 32],
+            timings: Default::default(),
+        })
+        .unwrap();
+        assert!(saved.is_last());
+        assert!(matches!(
+            saved.into_receive(),
+            Some(ReceiveProgress::Complete { checksum, .. }) if checksum == [7; 32]
+        ));
+        assert!(Event::from_receive(ReceiveProgress::Connecting).is_none());
+        assert!(
+            Event::Declined {
+                reason: String::new()
+            }
+            .is_last()
+        );
+    }
+}
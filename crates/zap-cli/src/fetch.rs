@@ -0,0 +1,108 @@
+//! `zap send --from-url`: passing on a file that's somewhere on the web
+//!
+//! The file is sent as it downloads, like stdin, so none of it is kept on
+//! disk and the receiver gets the first bytes as soon as they're here. A
+//! download that breaks off fails the transfer rather than sending part of
+//! the file as if it were all of it.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use reqwest::Url;
+use reqwest::header::{CONTENT_DISPOSITION, HeaderMap};
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+
+use crate::webdav::decode_path;
+
+/// A download that has started
+pub(crate) struct Download {
+    /// The file's name, from the server or the URL
+    pub(crate) name: String,
+    pub(crate) reader: Box<dyn AsyncRead + Send + Unpin>,
+}
+
+/// Start downloading `url`
+pub(crate) async fn open(url: &str) -> Result<Download> {
+    let url = Url::parse(url).with_context(|| format!("Invalid URL: {}", url))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("Only http and https URLs can be sent");
+    }
+    let response = reqwest::get(url.clone())
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Could not download {}", url))?;
+    let name = file_name(&url, response.headers());
+
+    let body = futures::stream::try_unfold(response, |mut response| async move {
+        let chunk = response.chunk().await.map_err(std::io::Error::other)?;
+        Ok::<_, std::io::Error>(chunk.map(|chunk| (chunk, response)))
+    });
+    Ok(Download {
+        name,
+        reader: Box::new(StreamReader::new(Box::pin(body))),
+    })
+}
+
+/// The name the server gives the file, or else the last part of the URL's
+/// path, or else its host
+fn file_name(url: &Url, headers: &HeaderMap) -> String {
+    let from_header = headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(disposition_name);
+    let from_path = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .and_then(decode_path);
+    [from_header, from_path]
+        .into_iter()
+        .flatten()
+        // Only ever a name, never a path
+        .filter_map(|name| {
+            let name = Path::new(&name).file_name()?.to_str()?.to_string();
+            (!name.is_empty()).then_some(name)
+        })
+        .next()
+        .or_else(|| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| "download".to_string())
+}
+
+/// The `filename` in a Content-Disposition header
+fn disposition_name(value: &str) -> Option<String> {
+    value.split(';').find_map(|param| {
+        let (key, name) = param.trim().split_once('=')?;
+        let name = name.trim().trim_matches('"');
+        (key.trim().eq_ignore_ascii_case("filename") && !name.is_empty()).then(|| name.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_file_name() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let none = HeaderMap::new();
+        assert_eq!(
+            file_name(&url("https://example.com/isos/big%20file.iso"), &none),
+            "big file.iso"
+        );
+        assert_eq!(
+            file_name(&url("https://example.com/"), &none),
+            "example.com"
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"../report.pdf\""),
+        );
+        assert_eq!(
+            file_name(&url("https://example.com/download?id=3"), &headers),
+            "report.pdf"
+        );
+    }
+}
@@ -0,0 +1,564 @@
+//! `zap send` and `zap receive` without the terminal
+//!
+//! The commands set their transfers up here and show what comes back.
+//! Sending works out what's sent, hands it to a running daemon or starts a
+//! node, and gets a code for it from the relay; receiving looks the code up
+//! and connects. What happens next comes out as typed events, which the
+//! commands print and a GUI can draw. Nothing here prints or asks, so what
+//! needs asking, like which files of a folder to take, is passed in.
+//!
+//! Queues, `--recipients` and files left on the relay have flows of their
+//! own, which still print as they go.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc;
+use zap_core::resume::ResumeState;
+use zap_core::{
+    ArchivePolicy, Bandwidth, Checksum, Durability, EntryChooser, ReceiveOptions, ReceiveProgress,
+    Renamer, SendOptions, SendProgress, Ticket, TransferHandle, TransferKey, ZapNode,
+};
+
+use crate::claims::Claims;
+use crate::ctl::Requested;
+use crate::daemon::{self, Handoff, ReceiveRequest, SendRequest};
+use crate::template::{Fields, is_folder, unique_name};
+use crate::{Listing, NetArgs, code, config, hooks, register_ticket};
+
+pub use crate::claims::{CodeEvent, CodeEventKind};
+pub use crate::code::Resolved;
+pub use crate::template::OutputTemplate;
+
+/// What a send sends
+pub enum Source {
+    /// A file or folder, or several paths from one folder, which are sent
+    /// inside a folder named after it
+    Paths(Vec<PathBuf>),
+    /// Data of unknown length, such as stdin, offered as `name`
+    Stream {
+        name: String,
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+    },
+}
+
+/// A send to one receiver, or with `share` to everyone with the code
+pub struct SendFlow {
+    pub source: Source,
+    /// The name to offer instead of the file's or the stream's
+    pub name: Option<String>,
+    pub label: Option<String>,
+    pub auto_archive: Option<usize>,
+    /// Keep serving the code until `downloads` receivers have everything,
+    /// or for good
+    pub share: bool,
+    pub downloads: Option<u32>,
+    pub idle_timeout: Option<Duration>,
+    pub expires: Option<Duration>,
+    pub max_rate: Option<u64>,
+    pub max_rate_per_receiver: Option<u64>,
+    pub require_key: bool,
+    /// Wait for another try when the receiver rejects the offer
+    pub retry_rejected: bool,
+    /// Offer a file again once it stops changing, when it changed while
+    /// it was being sent
+    pub retry_on_change: bool,
+    /// Wait for the receiver to carry on from what it has when the
+    /// connection breaks partway
+    pub resume: bool,
+    /// The relay to get a code from; without one, the ticket is shared
+    pub relay: Option<String>,
+    /// The code to ask the relay for instead of a generated one
+    pub code: Option<String>,
+    /// Put the file in the relay's catalog, with `share`
+    pub list: Option<Listing>,
+    pub net: NetArgs,
+}
+
+impl SendFlow {
+    /// Send `source` once, through the default relay, and otherwise as
+    /// `zap send` does without options
+    pub fn new(source: Source) -> Self {
+        Self {
+            source,
+            name: None,
+            label: None,
+            auto_archive: None,
+            share: false,
+            downloads: None,
+            idle_timeout: None,
+            expires: None,
+            max_rate: None,
+            max_rate_per_receiver: None,
+            require_key: false,
+            retry_rejected: true,
+            retry_on_change: false,
+            resume: false,
+            relay: Some(crate::relay_url(None)),
+            code: None,
+            list: None,
+            net: NetArgs::default(),
+        }
+    }
+
+    /// Check what's to be sent and name it, before anything goes online
+    pub fn prepare(self) -> Result<Prepared> {
+        if self.list.is_some() && !self.share {
+            anyhow::bail!("Only shared files can be listed in the relay's catalog");
+        }
+        let mut batch = false;
+        let (path, selection, stream) = match self.source {
+            Source::Paths(paths) if paths.len() > 1 => {
+                let paths = paths
+                    .iter()
+                    .map(std::path::absolute)
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                let parent = paths[0].parent().map(Path::to_path_buf).unwrap_or_default();
+                // Paths from different folders are saved side by side
+                batch = paths.iter().any(|p| p.parent() != Some(parent.as_path()));
+                (parent, paths, None)
+            }
+            Source::Paths(mut paths) => {
+                let path = paths.pop().unwrap_or_default();
+                if !path.exists() {
+                    anyhow::bail!("Path does not exist: {}", path.display());
+                }
+                (path, Vec::new(), None)
+            }
+            Source::Stream { name, reader } => (PathBuf::new(), Vec::new(), Some((name, reader))),
+        };
+        let name = match (self.name, &stream) {
+            (Some(name), _) => name,
+            (None, Some((name, _))) => name.clone(),
+            (None, None) if batch => format!(
+                "{} and {} more",
+                selection[0]
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+                selection.len() - 1
+            ),
+            (None, None) => path
+                .file_name()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| "file".to_string()),
+        };
+        let flow = SendFlow {
+            source: Source::Paths(Vec::new()),
+            name: None,
+            ..self
+        };
+        Ok(Prepared {
+            name,
+            path,
+            selection,
+            batch,
+            stream: stream.map(|(_, reader)| reader),
+            flow,
+        })
+    }
+}
+
+/// A send that's been checked and named, ready to start
+pub struct Prepared {
+    /// What the receiver is offered
+    pub name: String,
+    /// What's sent, or the folder several paths are sent from; empty for
+    /// a stream
+    pub path: PathBuf,
+    /// The paths sent from `path`, if not all of it
+    pub selection: Vec<PathBuf>,
+    /// Whether `selection` spans folders, so it's sent as a batch the
+    /// receiver saves side by side rather than as `path`
+    pub batch: bool,
+    stream: Option<Box<dyn AsyncRead + Send + Unpin>>,
+    flow: SendFlow,
+}
+
+impl Prepared {
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// One limit for every receiver, however they're served
+    pub(crate) fn bandwidth(&self) -> Option<Bandwidth> {
+        let (max_rate, per_receiver) = (self.flow.max_rate, self.flow.max_rate_per_receiver);
+        (max_rate.is_some() || per_receiver.is_some())
+            .then(|| Bandwidth::new(max_rate, per_receiver))
+    }
+
+    /// Start serving, through a running daemon if it can take the send,
+    /// and get a code for it
+    ///
+    /// A relay that can't be reached leaves the send with its ticket, and
+    /// the reason in [`Sending::relay_error`], unless a code or a listing
+    /// was asked for, which fails it.
+    pub async fn start(self) -> Result<Sending> {
+        let bandwidth = self.bandwidth();
+        let Prepared {
+            name,
+            path,
+            selection,
+            batch,
+            stream,
+            flow,
+        } = self;
+        let conversions = hooks::conversions();
+
+        // A running daemon's node is already online, so it can send right
+        // away; it sends one file or folder once, without waiting on a
+        // receiver to resume
+        let once = !flow.share && stream.is_none() && !batch && !flow.resume;
+        let handoff = if once && flow.net.daemon() {
+            let paths = if selection.is_empty() {
+                vec![std::path::absolute(&path)?]
+            } else {
+                selection.clone()
+            };
+            daemon::send(SendRequest {
+                paths,
+                alpn: config::Config::load().active().alpn.clone(),
+                auto_archive: flow.auto_archive,
+                idle_timeout: flow.idle_timeout,
+                max_rate: flow.max_rate,
+                max_rate_per_receiver: flow.max_rate_per_receiver,
+                require_key: flow.require_key,
+                retry_rejected: flow.retry_rejected,
+                retry_on_change: flow.retry_on_change,
+                label: flow.label.clone(),
+                convert: conversions.clone(),
+                ticket_ttl: flow.expires,
+            })
+            .await?
+        } else {
+            None
+        };
+
+        let handle = TransferHandle::new();
+        let (node, ticket, progress, handoff) = match handoff {
+            Some((handoff, ticket, progress)) => (None, ticket, progress, Some(handoff)),
+            None => {
+                let node = flow.net.node().await?;
+                let options = SendOptions {
+                    auto_archive: flow.auto_archive.map(|max_files| ArchivePolicy {
+                        max_files,
+                        ..Default::default()
+                    }),
+                    handle: Some(handle.clone()),
+                    keep_serving: flow.share,
+                    max_downloads: flow.downloads.filter(|_| flow.share),
+                    idle_timeout: flow.idle_timeout,
+                    key: flow.require_key.then(TransferKey::generate),
+                    bandwidth,
+                    retry_rejected: flow.retry_rejected,
+                    retry_on_change: flow.retry_on_change,
+                    resume: flow.resume,
+                    label: flow.label.clone(),
+                    transform: hooks::transform(conversions),
+                    ticket_ttl: flow.expires,
+                    ack_window: None,
+                };
+                let (ticket, progress) = if let Some(reader) = stream {
+                    node.send_reader_with_options(name.clone(), reader, options)
+                        .await?
+                } else if selection.is_empty() {
+                    node.send_with_options(&path, options).await?
+                } else if batch {
+                    node.send_batch(&selection, options).await?
+                } else {
+                    node.send_many(&selection, options).await?
+                };
+                (Some(node), ticket, progress, None)
+            }
+        };
+
+        let mut code = None;
+        let mut relay_error = None;
+        if let Some(relay) = &flow.relay {
+            let listing = flow.list.map(|list| {
+                let size = std::fs::metadata(&path)
+                    .ok()
+                    .filter(|metadata| metadata.is_file())
+                    .map(|metadata| metadata.len());
+                (list, size)
+            });
+            let registered = register_ticket(
+                relay,
+                &ticket.to_string(),
+                Some(&name),
+                flow.code.as_deref(),
+                listing,
+            )
+            .await;
+            match registered {
+                Ok(info) => code = Some(info),
+                // Falling back to another code would hand out one nobody
+                // asked for, or a ticket nobody can find
+                Err(e) if flow.code.is_some() || flow.list.is_some() => {
+                    anyhow::bail!("Could not register code: {}", e)
+                }
+                Err(e) => relay_error = Some(e.to_string()),
+            }
+        }
+        let claims = match (&flow.relay, &code) {
+            (
+                Some(relay),
+                Some(crate::RegisterResponse {
+                    code,
+                    events_token: Some(token),
+                    ..
+                }),
+            ) => Claims::follow(relay, code, token),
+            _ => Claims::none(),
+        };
+
+        Ok(Sending {
+            name,
+            path,
+            ticket,
+            code: code.map(|info| Registered {
+                code: info.code,
+                words: info.words,
+                token: info.token,
+            }),
+            relay_error,
+            handle,
+            node,
+            handoff,
+            progress,
+            claims,
+        })
+    }
+}
+
+/// A code the relay gave a send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registered {
+    pub code: String,
+    /// The code as words, easier to read out
+    pub words: String,
+    /// The code with the relay's address, from relays that send it
+    pub token: Option<String>,
+}
+
+/// What a send reports
+#[derive(Debug)]
+pub enum SendEvent {
+    /// From the transfer
+    Progress(SendProgress),
+    /// From the relay, about the code
+    Code(CodeEvent),
+}
+
+/// A send that's being served
+pub struct Sending {
+    pub name: String,
+    pub path: PathBuf,
+    /// The ticket, with its key if it has one
+    pub ticket: Ticket,
+    pub code: Option<Registered>,
+    /// Why there's no code, when the relay couldn't give one
+    pub relay_error: Option<String>,
+    /// Pauses, resumes and cancels the send, unless the daemon has it
+    pub handle: TransferHandle,
+    node: Option<ZapNode>,
+    handoff: Option<Handoff>,
+    progress: mpsc::Receiver<SendProgress>,
+    claims: Claims,
+}
+
+impl Sending {
+    /// The node serving the send, unless the daemon is
+    pub fn node(&self) -> Option<&ZapNode> {
+        self.node.as_ref()
+    }
+
+    /// Whether the daemon is serving the send
+    pub fn through_daemon(&self) -> bool {
+        self.handoff.is_some()
+    }
+
+    /// What happens next, or `None` once the send is over
+    pub async fn next(&mut self) -> Option<SendEvent> {
+        tokio::select! {
+            progress = self.progress.recv() => progress.map(SendEvent::Progress),
+            event = self.claims.next() => Some(SendEvent::Code(event)),
+        }
+    }
+
+    /// Pass on what `zap ctl` asked to the daemon's send
+    pub(crate) async fn request(&mut self, requested: Requested) {
+        if let Some(handoff) = &mut self.handoff {
+            handoff.request(requested).await;
+        }
+    }
+
+    /// Take the node offline, once the send is over
+    pub async fn shutdown(self) -> Result<()> {
+        if let Some(node) = self.node {
+            node.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Turn pasted input into a ticket, or a file left on the relay, looking
+/// codes up on `relay` unless `offline`
+pub async fn resolve(input: &str, relay: &str, offline: bool) -> Result<Resolved> {
+    if offline {
+        return Ok(Resolved::Ticket(code::resolve_direct(input)?));
+    }
+    code::resolve_with(input, relay, |_| {}).await
+}
+
+/// A receive, with the options `zap receive` takes
+#[derive(Default)]
+pub struct ReceiveFlow {
+    /// Where to save, instead of the current directory
+    pub output: Option<PathBuf>,
+    pub keep_name: bool,
+    pub durability: Durability,
+    pub dedup: bool,
+    pub cache_dir: Option<PathBuf>,
+    pub verify_pieces: bool,
+    /// Name the received file from a pattern like `{date}-{name}`
+    pub template: Option<OutputTemplate>,
+    /// Pick the files to take from a folder
+    pub choose_entries: Option<EntryChooser>,
+    pub net: NetArgs,
+}
+
+/// A receive under way
+pub struct Receiving {
+    /// The sender's node id
+    pub peer: String,
+    node: Option<ZapNode>,
+    progress: mpsc::Receiver<ReceiveProgress>,
+}
+
+impl Receiving {
+    /// The node receiving, unless the daemon is
+    pub fn node(&self) -> Option<&ZapNode> {
+        self.node.as_ref()
+    }
+
+    /// Whether the daemon is receiving
+    pub fn through_daemon(&self) -> bool {
+        self.node.is_none()
+    }
+
+    /// What happens next, or `None` once the receive is over
+    pub async fn next(&mut self) -> Option<ReceiveProgress> {
+        self.progress.recv().await
+    }
+
+    /// Take the node offline, once the receive is over
+    pub async fn shutdown(self) -> Result<()> {
+        if let Some(node) = self.node {
+            node.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Connect to the sender of `ticket` and receive what it offers, through a
+/// running daemon if it can
+pub async fn receive(ticket: Ticket, flow: ReceiveFlow) -> Result<Receiving> {
+    let sender = ticket.addr.id.to_string();
+    let peer = sender.clone();
+    let dir = match &flow.output {
+        Some(dir) => dir.clone(),
+        None => std::env::current_dir()?,
+    };
+
+    // A running daemon's node is already online, so it can connect right
+    // away
+    let plain = flow.choose_entries.is_none()
+        && flow.template.is_none()
+        && flow.cache_dir.is_none()
+        && flow.durability == Durability::default();
+    let handoff = if plain && flow.net.daemon() {
+        daemon::receive(ReceiveRequest {
+            ticket: ticket.share(),
+            output: std::path::absolute(&dir)?,
+            alpn: config::Config::load().active().alpn.clone(),
+            detect_extension: !flow.keep_name,
+            dedup: flow.dedup,
+            verify_pieces: flow.verify_pieces,
+        })
+        .await?
+    } else {
+        None
+    };
+    if let Some(progress) = handoff {
+        return Ok(Receiving {
+            peer,
+            node: None,
+            progress,
+        });
+    }
+
+    let node = flow.net.node().await?;
+    let options = ReceiveOptions {
+        detect_extension: !flow.keep_name,
+        durability: flow.durability,
+        keep_state: true,
+        dedup: flow.dedup || flow.cache_dir.is_some(),
+        cache_dir: flow.cache_dir,
+        verify_pieces: flow.verify_pieces,
+        choose_entries: flow.choose_entries,
+        rename: flow.template.map(|template| {
+            Renamer::new(move |offer| {
+                let name = template.render(&Fields {
+                    name: &offer.name,
+                    sender: &sender,
+                    size: offer.size,
+                    now: chrono::Local::now(),
+                });
+                unique_name(&dir, &name, is_folder(offer))
+            })
+        }),
+        ..Default::default()
+    };
+    let progress = node
+        .receive_with_options(ticket, flow.output.as_deref(), options)
+        .await?;
+    Ok(Receiving {
+        peer,
+        node: Some(node),
+        progress,
+    })
+}
+
+/// Carry on with the receive saved in `saved`, read from `state`
+pub async fn resume(
+    state: &Path,
+    saved: &ResumeState,
+    durability: Durability,
+    net: &NetArgs,
+) -> Result<Receiving> {
+    let peer = Ticket::deserialize(&saved.ticket)?.addr.id.to_string();
+    let node = net.node().await?;
+    let progress = node.resume(state, durability).await?;
+    Ok(Receiving {
+        peer,
+        node: Some(node),
+        progress,
+    })
+}
+
+/// Check a received file against the hash it was expected to have,
+/// deleting it if it doesn't match
+pub async fn check_received(expected: &Checksum, path: &Path, checksum: &[u8; 32]) -> Result<()> {
+    if let Err(e) = expected.verify(path, checksum).await {
+        // Whatever arrived isn't what was meant to
+        if path.is_file() {
+            std::fs::remove_file(path)?;
+            anyhow::bail!("{}; deleted {}", e, path.display());
+        }
+        anyhow::bail!(e);
+    }
+    Ok(())
+}
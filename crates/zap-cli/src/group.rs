@@ -0,0 +1,232 @@
+//! `zap send --recipients N`: the same files under a code per recipient
+//!
+//! Each code is served by a node of its own, like a `--queue` item, and
+//! stops working once its receiver has everything. The overview has a line
+//! per code, so the sender can tell whose code was claimed and whose wasn't.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use console::style;
+use indicatif::ProgressBar;
+use tokio::task::JoinSet;
+use zap_core::{
+    ArchivePolicy, Bandwidth, PublicKey, SendOptions, SendProgress, TransferHandle, TransferKey,
+};
+
+use crate::ctl::{self, Requested};
+use crate::hooks::{self, HookFields, run_hook};
+use crate::net::start_node;
+use crate::output::{self, accent, failure, success, symbol, warning};
+use crate::register_ticket;
+
+/// What every recipient gets, and how
+#[derive(Debug, Clone)]
+pub(crate) struct Group {
+    pub(crate) path: PathBuf,
+    /// Paths picked from `path`, if not all of it
+    pub(crate) selection: Vec<PathBuf>,
+    /// Whether `selection` is a batch from several folders
+    pub(crate) batch: bool,
+    pub(crate) name: String,
+    pub(crate) label: Option<String>,
+    pub(crate) auto_archive: Option<usize>,
+    /// How long a code waits for its recipient
+    pub(crate) idle_timeout: Option<Duration>,
+    /// Shared by all the codes, so the cap holds for the whole group
+    pub(crate) bandwidth: Option<Bandwidth>,
+    pub(crate) require_key: bool,
+    pub(crate) no_relay: bool,
+    pub(crate) relay: String,
+    pub(crate) on_sent: Option<String>,
+}
+
+/// Serve the group's files to `recipients` receivers, each under a code
+/// of its own, until every code is claimed or the sender gives up
+pub(crate) async fn run_group(group: Group, recipients: usize) -> Result<()> {
+    println!(
+        "\n{} Sending {} to {} recipients, one code each",
+        accent(symbol("⚡")),
+        success(&group.name),
+        recipients
+    );
+    println!(
+        "{}",
+        style("Each code works once; give every recipient their own").dim()
+    );
+    println!();
+
+    let multi = output::multi();
+    let mut lines = Vec::new();
+    let mut tasks = JoinSet::new();
+    for i in 0..recipients {
+        let pb = multi.add(output::bar(0));
+        pb.set_style(output::status_style());
+        pb.set_prefix(format!("[{}/{}]", i + 1, recipients));
+        pb.set_message(format!("{}", style("registering").dim()));
+        lines.push(pb.clone());
+
+        let group = group.clone();
+        tasks.spawn(async move {
+            let result = serve(&group, &pb).await;
+            match &result {
+                Ok(peer) => pb.finish_with_message(format!(
+                    "{} {}",
+                    success("claimed by"),
+                    accent(short_peer(peer))
+                )),
+                Err(e) => {
+                    pb.set_style(output::status_style());
+                    pb.abandon_with_message(format!("{}", failure(format!("not claimed: {}", e))));
+                }
+            }
+            result
+        });
+    }
+
+    let mut claimed = 0;
+    loop {
+        tokio::select! {
+            joined = tasks.join_next() => match joined {
+                Some(Ok(Ok(_))) => claimed += 1,
+                Some(_) => {}
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => {
+                tasks.shutdown().await;
+                for pb in lines.iter().filter(|pb| !pb.is_finished()) {
+                    pb.set_style(output::status_style());
+                    pb.abandon_with_message(format!("{}", warning("not claimed")));
+                }
+                break;
+            }
+        }
+    }
+
+    println!();
+    if claimed < recipients {
+        anyhow::bail!("{} of {} codes were claimed", claimed, recipients);
+    }
+    println!(
+        "{} All {} codes claimed!",
+        success(symbol("✓")).bold(),
+        recipients
+    );
+    Ok(())
+}
+
+/// Offer the files under a fresh code and wait until its receiver has
+/// everything, returning who that was
+async fn serve(group: &Group, pb: &ProgressBar) -> Result<PublicKey> {
+    // Every node has its own address, so each recipient gets its own ticket
+    let node = start_node(None).await?;
+    let handle = TransferHandle::new();
+    let options = SendOptions {
+        auto_archive: group.auto_archive.map(|max_files| ArchivePolicy {
+            max_files,
+            ..Default::default()
+        }),
+        handle: Some(handle.clone()),
+        idle_timeout: group.idle_timeout,
+        bandwidth: group.bandwidth.clone(),
+        key: group.require_key.then(TransferKey::generate),
+        label: group.label.clone(),
+        transform: hooks::transform(hooks::conversions()),
+        ..Default::default()
+    };
+    let (ticket, mut progress_rx) = if group.selection.is_empty() {
+        node.send_with_options(&group.path, options).await?
+    } else if group.batch {
+        node.send_batch(&group.selection, options).await?
+    } else {
+        node.send_many(&group.selection, options).await?
+    };
+
+    let code = if group.no_relay {
+        ticket.share()
+    } else {
+        let registered = register_ticket(
+            &group.relay,
+            &ticket.to_string(),
+            Some(&group.name),
+            None,
+            None,
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("could not register with relay: {}", e))?;
+        // The relay only ever sees the ticket; the key goes with the code
+        match ticket.key {
+            Some(key) => format!("{}.{}", registered.code, key),
+            None => registered.code,
+        }
+    };
+    pb.set_prefix(format!("{} {}", pb.prefix(), code));
+    pb.set_message(format!("{}", style("waiting").dim()));
+    let mut ctl = ctl::register(&group.name, Some(&code), &handle);
+
+    let mut connected = false;
+    let peer = loop {
+        let progress = tokio::select! {
+            progress = progress_rx.recv() => progress,
+            requested = ctl.changed() => {
+                // Once connected, the transfer itself reports the cancel
+                if requested == Requested::Cancel && !connected {
+                    anyhow::bail!("cancelled");
+                }
+                continue;
+            }
+        };
+        let Some(progress) = progress else {
+            anyhow::bail!("the sender stopped");
+        };
+        match progress {
+            SendProgress::Connected { peer } => {
+                pb.set_style(output::bar_style(true));
+                pb.set_message(short_peer(&peer));
+                connected = true;
+                ctl.connected(peer);
+            }
+            SendProgress::Sending {
+                bytes_sent,
+                total_bytes,
+            } => {
+                if let Some(total) = total_bytes {
+                    pb.set_length(total);
+                }
+                pb.set_position(bytes_sent);
+                ctl.progress(bytes_sent, total_bytes);
+            }
+            SendProgress::Complete {
+                peer,
+                size,
+                checksum,
+                ..
+            } => {
+                ctl.complete();
+                pb.set_style(output::status_style());
+                if let Some(command) = &group.on_sent {
+                    let fields = HookFields {
+                        path: &group.path,
+                        name: &group.name,
+                        size,
+                        checksum: &checksum,
+                        peer: peer.to_string(),
+                    };
+                    run_hook(command, &fields).await?;
+                }
+                break peer;
+            }
+            SendProgress::Error(e) => anyhow::bail!("{}", e),
+            _ => {}
+        }
+    };
+
+    node.shutdown().await?;
+    Ok(peer)
+}
+
+/// The start of a node id, which is enough to tell receivers apart
+fn short_peer(peer: &PublicKey) -> String {
+    peer.to_string().chars().take(8).collect()
+}
@@ -0,0 +1,158 @@
+//! `zap history`: what `zap send` delivered, with the receivers' receipts
+//!
+//! Each finished send is added to `history.jsonl` next to the config: what
+//! was sent, to which node, its size and BLAKE3 hash, and the receipt the
+//! receiver signed for it. A receipt proves that node got exactly this
+//! data, so it can stand as proof of delivery; `zap history --json` prints
+//! the entries with their receipts to keep elsewhere. Receivers from before
+//! receipts were added don't send one.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use console::style;
+use serde::{Deserialize, Serialize};
+use zap_core::Receipt;
+
+use crate::format_bytes;
+use crate::output::{accent, success, symbol, warning};
+
+/// One finished send
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    /// When it finished, in RFC 3339
+    pub(crate) time: String,
+    pub(crate) name: String,
+    /// The receiver's node id
+    pub(crate) peer: String,
+    pub(crate) size: u64,
+    /// BLAKE3 hash of the data, in hex
+    pub(crate) checksum: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) receipt: Option<Receipt>,
+}
+
+fn path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("zap").join("history.jsonl"))
+}
+
+/// Every entry, oldest first, skipping lines that can't be read
+fn load() -> Vec<Entry> {
+    path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .map(|text| {
+            text.lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn append(entry: &Entry) -> Result<()> {
+    let path = path().context("no config directory on this system")?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+/// Add a finished send. Failing to keep it never fails the send.
+pub(crate) fn record(
+    name: &str,
+    peer: String,
+    size: u64,
+    checksum: &[u8; 32],
+    receipt: Option<Receipt>,
+) {
+    let entry = Entry {
+        time: chrono::Local::now().to_rfc3339(),
+        name: name.to_string(),
+        peer,
+        size,
+        checksum: blake3::Hash::from(*checksum).to_hex().to_string(),
+        receipt,
+    };
+    if let Err(e) = append(&entry) {
+        tracing::warn!("could not add the send to the history: {}", e);
+    }
+}
+
+/// `zap history`: show the latest sends and whether each has a good
+/// receipt, or print them as JSON
+pub async fn run_history(last: usize, json: bool) -> Result<()> {
+    let entries = load();
+    let latest = &entries[entries.len().saturating_sub(last)..];
+    if json {
+        for entry in latest {
+            println!("{}", serde_json::to_string(entry)?);
+        }
+        return Ok(());
+    }
+
+    if latest.is_empty() {
+        println!("{}", style("Nothing sent yet").dim());
+    }
+    for entry in latest.iter().rev() {
+        let time = chrono::DateTime::parse_from_rfc3339(&entry.time)
+            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|_| entry.time.clone());
+        let receipt = match &entry.receipt {
+            Some(receipt) if receipt.verify().is_ok() => {
+                format!("{} receipt", success(symbol("✓")))
+            }
+            Some(_) => format!("{}", warning("receipt doesn't verify")),
+            None => format!("{}", style("no receipt").dim()),
+        };
+        let peer: String = entry.peer.chars().take(10).collect();
+        println!(
+            "{}  {}  {}  to {}  {}",
+            style(time).dim(),
+            accent(&entry.name),
+            format_bytes(entry.size),
+            peer,
+            receipt
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_keeps_receipt() {
+        let sender = zap_core::SecretKey::generate(&mut rand::rng()).public();
+        let key = zap_core::SecretKey::generate(&mut rand::rng());
+        let entry = Entry {
+            time: "2026-10-16T09:30:00+02:00".to_string(),
+            name: "report.pdf".to_string(),
+            peer: key.public().to_string(),
+            size: 1024,
+            checksum: "ab".repeat(32),
+            receipt: Some(zap_core::Receipt::sign(&key, sender, [7; 32], 1024)),
+        };
+
+        // Still good as proof once it's been through the file
+        let line = serde_json::to_string(&entry).unwrap();
+        let read: Entry = serde_json::from_str(&line).unwrap();
+        assert_eq!(read, entry);
+        read.receipt.unwrap().verify().unwrap();
+
+        let bare = Entry {
+            receipt: None,
+            ..entry
+        };
+        let line = serde_json::to_string(&bare).unwrap();
+        assert!(!line.contains("receipt"));
+        assert_eq!(serde_json::from_str::<Entry>(&line).unwrap(), bare);
+    }
+}
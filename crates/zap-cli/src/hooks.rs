@@ -0,0 +1,269 @@
+//! Commands run after a transfer, from `--on-complete`, `--on-sent` or the
+//! `[hooks]` table of the config file, and conversions run on files before
+//! they're sent, from its `[hooks.convert]` table
+//!
+//! Placeholders in the command are replaced with shell-quoted values, so
+//! names from the sender can't inject commands. The same values are also
+//! set as `ZAP_*` environment variables.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use zap_core::Transform;
+
+use crate::config::Config;
+
+/// Commands from the config file, used when no flag is given
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Hooks {
+    /// Run after receiving, like `--on-complete`
+    pub(crate) on_complete: Option<String>,
+
+    /// Run after a transfer was received, like `--on-sent`
+    pub(crate) on_sent: Option<String>,
+
+    /// Conversions for files about to be sent, by the extension they
+    /// convert from
+    pub(crate) convert: BTreeMap<String, Conversion>,
+}
+
+/// A command that turns a file into another format before it's sent, e.g.
+/// HEIC photos into JPEG
+///
+/// `{path}` in the command is the file and `{output}` is where to write the
+/// converted one, which ends in `.to`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Conversion {
+    /// The extension converted files get, e.g. `jpg`
+    pub(crate) to: String,
+
+    pub(crate) command: String,
+}
+
+/// What a finished transfer fills a hook's placeholders with
+pub(crate) struct HookFields<'a> {
+    pub(crate) path: &'a Path,
+    pub(crate) name: &'a str,
+    pub(crate) size: u64,
+    pub(crate) checksum: &'a [u8; 32],
+    pub(crate) peer: String,
+}
+
+impl HookFields<'_> {
+    fn values(&self) -> [(&'static str, String); 5] {
+        let checksum = self.checksum.iter().map(|b| format!("{:02x}", b)).collect();
+        [
+            ("path", self.path.display().to_string()),
+            ("name", self.name.to_string()),
+            ("size", self.size.to_string()),
+            ("checksum", checksum),
+            ("peer", self.peer.clone()),
+        ]
+    }
+}
+
+/// The `--on-complete` command, or the config's
+pub(crate) fn on_complete(flag: Option<String>) -> Option<String> {
+    flag.or_else(|| Config::load().active().hooks.on_complete.clone())
+}
+
+/// The `--on-sent` command, or the config's
+pub(crate) fn on_sent(flag: Option<String>) -> Option<String> {
+    flag.or_else(|| Config::load().active().hooks.on_sent.clone())
+}
+
+/// The config's conversions
+pub(crate) fn conversions() -> BTreeMap<String, Conversion> {
+    Config::load().active().hooks.convert.clone()
+}
+
+/// Run a hook through the shell and wait for it, failing if it does
+pub(crate) async fn run_hook(command: &str, fields: &HookFields<'_>) -> Result<()> {
+    let values = fields.values();
+    let line = expand(command, &values);
+
+    let mut cmd = tokio::process::Command::from(shell(&line));
+    for (key, value) in &values {
+        cmd.env(format!("ZAP_{}", key.to_uppercase()), value);
+    }
+
+    let status = cmd.status().await?;
+    if !status.success() {
+        bail!("hook `{}` failed with {}", line, status);
+    }
+    Ok(())
+}
+
+/// A transform that runs `conversions` on the files they're for, matching
+/// extensions in any case, or `None` if there aren't any
+pub(crate) fn transform(conversions: BTreeMap<String, Conversion>) -> Option<Transform> {
+    if conversions.is_empty() {
+        return None;
+    }
+    let conversions: Arc<BTreeMap<_, _>> = Arc::new(
+        conversions
+            .into_iter()
+            .map(|(from, conversion)| (from.to_lowercase(), conversion))
+            .collect(),
+    );
+    let rename = {
+        let conversions = conversions.clone();
+        move |name: &str| find_conversion(&conversions, name).map(|(renamed, _)| renamed)
+    };
+    let apply = move |source: &Path, output: &Path| {
+        let name = source.file_name().unwrap_or_default().to_string_lossy();
+        let Some((_, conversion)) = find_conversion(&conversions, &name) else {
+            return Ok(());
+        };
+        let values = [
+            ("path", source.display().to_string()),
+            ("output", output.display().to_string()),
+        ];
+        let line = expand(&conversion.command, &values);
+        let mut cmd = shell(&line);
+        for (key, value) in &values {
+            cmd.env(format!("ZAP_{}", key.to_uppercase()), value);
+        }
+        let status = cmd.status()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "`{}` failed with {}",
+                line, status
+            )));
+        }
+        if !output.exists() {
+            return Err(std::io::Error::other(format!(
+                "`{}` didn't write the converted file",
+                line
+            )));
+        }
+        Ok(())
+    };
+    Some(Transform::new(rename, apply))
+}
+
+/// The conversion for a file named `name`, from lowercase extensions, and
+/// the name it gets
+fn find_conversion<'a>(
+    conversions: &'a BTreeMap<String, Conversion>,
+    name: &str,
+) -> Option<(String, &'a Conversion)> {
+    let (stem, extension) = name.rsplit_once('.')?;
+    let conversion = conversions.get(&extension.to_lowercase())?;
+    let to = conversion.to.trim_start_matches('.');
+    Some((format!("{}.{}", stem, to), conversion))
+}
+
+/// Replace each `{placeholder}` with its quoted value, in one pass so
+/// values that look like placeholders are left alone
+fn expand(command: &str, values: &[(&str, String)]) -> String {
+    let mut line = String::with_capacity(command.len());
+    let mut rest = command;
+    while let Some(start) = rest.find('{') {
+        line.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('}').and_then(|end| {
+            let key = &after[..end];
+            let (_, value) = values.iter().find(|(k, _)| *k == key)?;
+            Some((end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                line.push_str(&quote(value));
+                rest = &after[end + 1..];
+            }
+            None => {
+                line.push('{');
+                rest = after;
+            }
+        }
+    }
+    line.push_str(rest);
+    line
+}
+
+#[cfg(unix)]
+pub(crate) fn shell(line: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(line);
+    cmd
+}
+
+#[cfg(windows)]
+pub(crate) fn shell(line: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(line);
+    cmd
+}
+
+/// Quote a value as a single shell word
+#[cfg(unix)]
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quote a value as a single shell word
+///
+/// cmd.exe has no way to escape a double quote inside quotes, so they're
+/// dropped; file names can't contain them on Windows anyway. It expands
+/// `%VAR%` even inside quotes, so each `%` is closed out of them and
+/// escaped with `^` instead.
+#[cfg(windows)]
+fn quote(value: &str) -> String {
+    let value = value.replace('"', "").replace('%', "\"^%\"");
+    format!("\"{}\"", value)
+}
+
+#[cfg(all(test, windows))]
+mod windows_tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("C:\\in\\a b.jpg"), "\"C:\\in\\a b.jpg\"");
+        assert_eq!(quote("%PATH%.jpg"), "\"\"^%\"PATH\"^%\".jpg\"");
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let values = [
+            ("path", "/tmp/in/it's here.jpg".to_string()),
+            ("name", "$(rm -rf ~).jpg".to_string()),
+        ];
+        assert_eq!(
+            expand("cp {path} /photos/{name}", &values),
+            r"cp '/tmp/in/it'\''s here.jpg' /photos/'$(rm -rf ~).jpg'"
+        );
+        assert_eq!(expand("echo {size}", &values), "echo {size}");
+
+        // A value that looks like a placeholder isn't expanded again
+        let values = [("name", "{path}".to_string()), ("path", "x".to_string())];
+        assert_eq!(expand("echo {name} {path}", &values), "echo '{path}' 'x'");
+    }
+
+    #[test]
+    fn test_find_conversion() {
+        let conversions = BTreeMap::from([(
+            "heic".to_string(),
+            Conversion {
+                to: "jpg".to_string(),
+                command: "heif-convert {path} {output}".to_string(),
+            },
+        )]);
+        let renamed = |name| find_conversion(&conversions, name).map(|(renamed, _)| renamed);
+        assert_eq!(renamed("IMG_0001.HEIC").as_deref(), Some("IMG_0001.jpg"));
+        assert_eq!(renamed("a.b.heic").as_deref(), Some("a.b.jpg"));
+        assert_eq!(renamed("notes.txt"), None);
+        assert_eq!(renamed("heic"), None);
+    }
+}
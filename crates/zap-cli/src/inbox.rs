@@ -0,0 +1,186 @@
+//! `zap listen --inbox`: sorting what paired peers copy in
+//!
+//! Copies in go to `inbox/<peer>/<folder>` in the served directory,
+//! whatever directory the peer named. `<peer>` is the name the peer was
+//! paired under, and `<folder>` comes from a template, `{date}` by default,
+//! so each day gets a folder of its own. A name that's already taken is
+//! renamed, overwritten or refused, and each peer's folder can be capped.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use zap_core::{Inbox, OfferCheck, ReceiveOptions, Renamer};
+
+use crate::format_bytes;
+use crate::peers::peer_name;
+use crate::template::{Fields, OutputTemplate, is_folder, unique_name};
+
+/// The folder in the served directory that inboxes are kept in
+const INBOX: &str = "inbox";
+
+/// The folders inside a peer's when no template is given
+const DEFAULT_TEMPLATE: &str = "{date}";
+
+/// What `--inbox` does with a name that's already taken, for
+/// `--on-conflict`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Conflict {
+    /// Save as `name-1.ext`, `name-2.ext`, ...
+    #[default]
+    Rename,
+    /// Replace what's there
+    Overwrite,
+    /// Turn the copy down
+    Refuse,
+}
+
+/// How `zap listen --inbox` sorts copies in
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InboxOptions {
+    /// Folders inside each peer's, like `{date}/{time}`
+    pub template: Option<String>,
+    pub conflict: Conflict,
+    /// Most bytes each peer's folder may hold
+    pub quota: Option<u64>,
+}
+
+/// The [`Inbox`] for a listener serving `root`
+pub(crate) fn inbox(
+    root: &Path,
+    peers: BTreeMap<String, String>,
+    options: InboxOptions,
+) -> Result<Inbox> {
+    let template = parse_template(options.template.as_deref().unwrap_or(DEFAULT_TEMPLATE))?;
+    let root = root.to_path_buf();
+
+    Ok(Inbox::new(move |peer| {
+        let sender = peer_name(&peers, peer);
+        let fields = Fields {
+            name: "",
+            sender: &sender,
+            size: None,
+            now: chrono::Local::now(),
+        };
+        let own = Path::new(INBOX).join(&sender);
+        let dir = folder(&own, &template, &fields);
+
+        let full = root.join(&dir);
+        let rename = (options.conflict == Conflict::Rename).then(|| {
+            let full = full.clone();
+            Renamer::new(move |offer| unique_name(&full, &offer.name, is_folder(offer)))
+        });
+        let own = root.join(own);
+        let (conflict, quota) = (options.conflict, options.quota);
+        let check = OfferCheck::new(move |offer| {
+            if conflict == Conflict::Refuse && full.join(&offer.name).exists() {
+                return Err(format!("{} is already here", offer.name));
+            }
+            if let Some(quota) = quota {
+                let Some(size) = offer.size else {
+                    return Err("streams can't be checked against the inbox quota".to_string());
+                };
+                let used = disk_usage(&own);
+                if used + size > quota {
+                    return Err(format!(
+                        "that would go over your inbox quota of {} ({} used)",
+                        format_bytes(quota),
+                        format_bytes(used)
+                    ));
+                }
+            }
+            Ok(())
+        });
+
+        let options = ReceiveOptions {
+            rename,
+            check: Some(check),
+            ..Default::default()
+        };
+        (dir, options)
+    }))
+}
+
+/// Parse a template of folders, one part per folder
+fn parse_template(template: &str) -> Result<Vec<OutputTemplate>> {
+    template
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .map(OutputTemplate::parse)
+        .collect()
+}
+
+/// The folder inside `own` that a copy goes to, skipping parts that come
+/// out empty or would leave it
+fn folder(own: &Path, template: &[OutputTemplate], fields: &Fields) -> PathBuf {
+    let mut dir = own.to_path_buf();
+    for part in template {
+        let name = part.render(fields);
+        if !name.is_empty() && name != "." && name != ".." {
+            dir.push(name);
+        }
+    }
+    dir
+}
+
+/// Bytes taken by the files under `dir`
+fn disk_usage(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => disk_usage(&entry.path()),
+            Ok(_) => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_folder() {
+        let fields = Fields {
+            name: "",
+            sender: "laptop",
+            size: None,
+            now: chrono::Local
+                .with_ymd_and_hms(2024, 5, 31, 14, 25, 1)
+                .unwrap(),
+        };
+        let own = Path::new("inbox/laptop");
+        let template = parse_template("{date}").unwrap();
+        assert_eq!(
+            folder(own, &template, &fields),
+            Path::new("inbox/laptop/2024-05-31")
+        );
+        let template = parse_template("{date}/{time}/").unwrap();
+        assert_eq!(
+            folder(own, &template, &fields),
+            Path::new("inbox/laptop/2024-05-31/142501")
+        );
+        // Parts that come out empty are left out
+        let template = parse_template("{size}/{date}").unwrap();
+        assert_eq!(
+            folder(own, &template, &fields),
+            Path::new("inbox/laptop/2024-05-31")
+        );
+        assert!(parse_template("{day}").is_err());
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"12345").unwrap();
+        std::fs::write(dir.path().join("sub/b.txt"), b"678").unwrap();
+        assert_eq!(disk_usage(dir.path()), 8);
+        assert_eq!(disk_usage(&dir.path().join("missing")), 0);
+    }
+}
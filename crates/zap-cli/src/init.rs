@@ -0,0 +1,109 @@
+//! `zap init`: setting zap up on a new machine
+//!
+//! Nothing here is needed before using zap: the key is made the first time
+//! `zap pair` or `zap cp` needs it, and settings fall back to zapper.cloud
+//! and the current directory. Running it once gets the questions out of the
+//! way, and shows the node id other machines pair with before it's needed.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use console::style;
+use dialoguer::{Input, theme::ColorfulTheme};
+
+use crate::config::Config;
+use crate::output::{accent, success, symbol};
+use crate::{DEFAULT_RELAY, peers};
+
+/// Ask for a relay and a download folder, save them in the profile in use,
+/// and make this machine's key
+pub async fn run_init() -> Result<()> {
+    let mut config = Config::load();
+    let theme = ColorfulTheme::default();
+    match config.profile_name() {
+        Some(name) => println!(
+            "{} Setting up zap, profile {}\n",
+            accent(symbol("⚡")),
+            accent(name)
+        ),
+        None => println!("{} Setting up zap\n", accent(symbol("⚡"))),
+    }
+
+    let relay = Input::<String>::with_theme(&theme)
+        .with_prompt("Relay for short codes")
+        .default(crate::relay_url(None))
+        .validate_with(|input: &String| check_relay(input))
+        .interact_text()?;
+    let relay = relay.trim().trim_end_matches('/').to_string();
+
+    let download_dir = config
+        .active()
+        .download_dir
+        .clone()
+        .or_else(dirs::download_dir)
+        .or_else(|| std::env::current_dir().ok())
+        .unwrap_or_default();
+    let download_dir = Input::<String>::with_theme(&theme)
+        .with_prompt("Save received files in")
+        .default(download_dir.display().to_string())
+        .interact_text()?;
+    let download_dir = std::path::absolute(PathBuf::from(
+        shellexpand::tilde(download_dir.trim()).as_ref(),
+    ))?;
+    std::fs::create_dir_all(&download_dir)?;
+
+    let profile = config.active_mut();
+    // Left out, the default follows zap if it ever moves
+    profile.relay = (relay != DEFAULT_RELAY).then_some(relay);
+    profile.download_dir = Some(download_dir);
+    config.save()?;
+    let id = peers::secret_key()?.public().to_string();
+
+    println!("\n{} zap is set up", success(symbol("✓")).bold());
+    if let Some(path) = Config::path() {
+        println!("  {}", style(format!("Settings: {}", path.display())).dim());
+    }
+    println!("\nThis machine: {}", success(&id));
+    println!("  Fingerprint: {}", accent(fingerprint(&id)));
+    println!(
+        "  {}",
+        style(format!("Other machines pair with: zap pair <name> {}", id)).dim()
+    );
+    println!(
+        "  {}",
+        style("They see the fingerprint once paired, to check it's this machine").dim()
+    );
+    Ok(())
+}
+
+fn check_relay(input: &str) -> Result<(), String> {
+    match url::Url::parse(input.trim()) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        _ => Err("expected a URL like https://zapper.cloud".to_string()),
+    }
+}
+
+/// The start of a node id in groups of four, short enough to read out
+/// when checking that a pasted id is the right one
+pub(crate) fn fingerprint(id: &str) -> String {
+    let chars: Vec<char> = id.chars().take(16).collect();
+    chars
+        .chunks(4)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        let id = "ae58ff8833e7a3f5b4c1d2e3f4a5b6c7d8e9f0a1b2c3d4e5f6a7b8c9d0e1f2a3";
+        assert_eq!(fingerprint(id), "ae58 ff88 33e7 a3f5");
+        assert_eq!(fingerprint("ae58ff"), "ae58 ff");
+        assert!(check_relay("https://relay.acme.example/").is_ok());
+        assert!(check_relay("relay.acme.example").is_err());
+    }
+}
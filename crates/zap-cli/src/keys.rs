@@ -0,0 +1,76 @@
+use std::io::IsTerminal;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+use zap_core::TransferHandle;
+
+/// Pauses and resumes a send from the keyboard: `p` pauses, `r` resumes,
+/// and Ctrl-C cancels it
+///
+/// The terminal is in raw mode while this is alive, so print with
+/// [`output::println`](crate::output::println) or
+/// [`output::print_line`](crate::output::print_line) until it's dropped.
+pub(crate) struct KeyControls {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KeyControls {
+    /// Start listening, unless stdin isn't an interactive terminal
+    pub(crate) fn start(handle: TransferHandle) -> Option<Self> {
+        if !std::io::stdin().is_terminal() || terminal::enable_raw_mode().is_err() {
+            return None;
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || listen(&handle, &stop)
+        });
+
+        Some(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for KeyControls {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn listen(handle: &TransferHandle, stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        // Poll with a timeout so the thread notices when it should stop
+        if !event::poll(Duration::from_millis(100)).unwrap_or(false) {
+            continue;
+        }
+        let Ok(Event::Key(key)) = event::read() else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        match key.code {
+            KeyCode::Char('p') => handle.pause(),
+            KeyCode::Char('r') => handle.resume(),
+            // Raw mode swallows Ctrl-C, so cancel here, which the send
+            // reports like any other cancel once it has stopped
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                futures::executor::block_on(handle.cancel());
+            }
+            _ => {}
+        }
+    }
+}
@@ -3,18 +3,211 @@ use std::path::PathBuf;
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use console::style;
-use dialoguer::{theme::ColorfulTheme, Input, Select};
+use dialoguer::{Input, theme::ColorfulTheme};
 use indicatif::{ProgressBar, ProgressStyle};
 use serde::{Deserialize, Serialize};
-use zap_core::{ReceiveProgress, SendProgress, Ticket, ZapNode};
+use zap_core::protocol::OfferKind;
+use zap_core::resume::ResumeState;
+use zap_core::{EntryChooser, ReceiveProgress, SendProgress};
+
+mod about;
+mod bundle;
+mod cat;
+mod claims;
+mod code;
+mod command;
+mod config;
+mod cp;
+mod ctl;
+mod daemon;
+mod fetch;
+pub mod flows;
+mod group;
+mod history;
+mod hooks;
+mod inbox;
+mod init;
+mod keys;
+mod mirror;
+#[cfg(all(unix, feature = "fuse"))]
+mod mount;
+mod net;
+mod output;
+mod peers;
+mod picker;
+mod profile;
+mod qr;
+mod queue;
+mod replay;
+mod share;
+mod sync;
+mod template;
+mod tls;
+mod tui;
+mod tunnel;
+mod update;
+mod usage;
+mod webdav;
+
+use code::{CodeAlphabet, Resolved, resolve};
+use ctl::Requested;
+use flows::{ReceiveFlow, SendEvent, SendFlow, Source};
+use group::{Group, run_group};
+use hooks::{HookFields, run_hook};
+use keys::KeyControls;
+use mirror::receive_mirror;
+use net::PathWatch;
+use output::{accent, success, symbol, warning};
+use picker::{choose_entries, select_file_interactive};
+use queue::run_queue;
+use share::watch_share;
+use template::OutputTemplate;
+use usage::Direction;
+
+pub use about::{BuildInfo, run_about};
+pub use bundle::with_debug_bundle;
+pub use cat::run_cat;
+pub use config::select_profile;
+pub use cp::{run_cp, run_listen};
+pub use ctl::{CtlAction, run_ctl};
+pub use daemon::run_daemon;
+pub use history::run_history;
+pub use inbox::{Conflict, InboxOptions};
+pub use init::run_init;
+pub use mirror::run_send_async;
+#[cfg(all(unix, feature = "fuse"))]
+pub use mount::run_mount;
+pub use net::{NetArgs, NetProfile};
+pub use output::{ColorChoice, set_color, set_plain};
+pub use peers::run_pair;
+pub use picker::with_path_list;
+pub use profile::{ProfileAction, run_profile};
+pub use queue::QueueOptions;
+pub use replay::run_replay;
+pub use share::{ExitAfter, Listing, parse_duration, parse_label, parse_rate, parse_size};
+pub use sync::run_sync;
+pub use tls::set_client_cert;
+pub use tui::run_tui;
+pub use tunnel::run_tunnel;
+pub use update::run_update;
+pub use usage::run_stats;
+pub use webdav::run_webdav;
+pub use zap_core::{Checksum, Durability};
 
 /// Default relay server for short codes
 const DEFAULT_RELAY: &str = "https://zapper.cloud";
 
+/// The relay to use: `--relay` if given, then the profile's, then
+/// zapper.cloud
+pub fn relay_url(flag: Option<String>) -> String {
+    flag.or_else(|| config::Config::load().active().relay.clone())
+        .unwrap_or_else(|| DEFAULT_RELAY.to_string())
+}
+
+/// How `zap send` serves what it sends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendMode {
+    /// To the first receiver
+    Once,
+    /// Each path under its own code, a few at a time
+    Queue(QueueOptions),
+    /// To everyone with the code, until interrupted, idle, or `downloads`
+    /// receivers have everything
+    Share { downloads: Option<u32> },
+    /// To this many receivers, each with a single-use code of its own
+    Recipients(usize),
+}
+
+/// What `zap send` was asked for, besides the paths
+pub struct SendArgs {
+    /// Share the ticket instead of getting a code from `relay`
+    pub no_relay: bool,
+    pub relay: String,
+    /// The code to ask the relay for instead of a generated one
+    pub code: Option<String>,
+    /// The name to offer instead of the file's
+    pub name: Option<String>,
+    pub label: Option<String>,
+    /// Send what this URL downloads, instead of the paths
+    pub from_url: Option<String>,
+    /// Send what this command writes, instead of the paths
+    pub command: Option<String>,
+    pub auto_archive: Option<usize>,
+    pub mode: SendMode,
+    /// Put the file in the relay's catalog, when sharing
+    pub list: Option<Listing>,
+    pub idle_timeout: Option<std::time::Duration>,
+    pub expires: Option<std::time::Duration>,
+    pub max_rate: Option<u64>,
+    pub max_rate_per_receiver: Option<u64>,
+    pub require_key: bool,
+    /// Give up when the receiver rejects the offer, rather than wait for
+    /// another try
+    pub exit_on_reject: bool,
+    pub retry_on_change: bool,
+    pub resume: bool,
+    /// A command to run once the file is sent
+    pub on_sent: Option<String>,
+    pub net: NetArgs,
+}
+
+/// What `zap receive` was asked for
+pub struct ReceiveArgs {
+    /// The code or ticket, asked for if neither this nor `qr_image` is given
+    pub code: Option<String>,
+    /// An image of a QR code to read the code from
+    pub qr_image: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub relay: String,
+    pub keep_name: bool,
+    /// Choose which of a folder's files to receive
+    pub select: bool,
+    pub output_template: Option<String>,
+    /// A command to run on the received file
+    pub on_complete: Option<String>,
+    pub durability: Durability,
+    pub dedup: bool,
+    pub cache_dir: Option<PathBuf>,
+    pub verify_pieces: bool,
+    /// The checksum the file has to have
+    pub expected: Option<Checksum>,
+    /// A state file to carry on from instead of receiving anew
+    pub resume: Option<PathBuf>,
+    pub net: NetArgs,
+}
+
 #[derive(Parser)]
 #[command(name = "zap")]
 #[command(about = "Fast, secure file transfers", long_about = None)]
 pub struct Cli {
+    /// Settings profile to use, see `zap profile`
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Plain output for screen readers and logs: no colors, symbols or
+    /// progress bars; on by default when TERM=dumb is set
+    #[arg(long, global = true)]
+    pub plain: bool,
+
+    /// When to color output; `auto` leaves it off in pipes and when
+    /// NO_COLOR is set
+    #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Write the version, the outcome and a debug log of the command to
+    /// this file when it's done, to attach to a bug report
+    #[arg(long, global = true, value_name = "PATH")]
+    pub debug_bundle: Option<PathBuf>,
+
+    /// PEM certificate to show relays that need one, instead of the
+    /// profile's; goes with --client-key
+    #[arg(long, global = true, value_name = "PATH", requires = "client_key")]
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM private key for --client-cert
+    #[arg(long, global = true, value_name = "PATH", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -23,16 +216,156 @@ pub struct Cli {
 pub enum Commands {
     /// Send a file or folder
     Send {
-        /// Path to the file or folder to send (interactive if not provided)
-        path: Option<PathBuf>,
+        /// Files or folders to send, or `-` for stdin (interactive if not
+        /// provided). Several paths from one folder are sent together
+        paths: Vec<PathBuf>,
+
+        /// File name to offer when sending from stdin, a URL or a command
+        #[arg(long)]
+        name: Option<String>,
+
+        /// A short description shown to the receiver with the offer, e.g.
+        /// "Q3 report v2", to tell several transfers apart
+        #[arg(long, value_parser = parse_label, conflicts_with = "leave_on_relay")]
+        label: Option<String>,
+
+        /// Send the file at this URL, downloading it as it's sent instead
+        /// of saving it here first
+        #[arg(long, value_name = "URL", conflicts_with_all = ["paths", "queue", "share", "recipients", "exit_after", "leave_on_relay"])]
+        from_url: Option<String>,
+
+        /// Send what this shell command writes, as it's written, e.g.
+        /// 'pg_dump db'. The transfer fails if the command does
+        #[arg(long, value_name = "COMMAND", conflicts_with_all = ["paths", "from_url", "queue", "share", "recipients", "exit_after", "leave_on_relay"])]
+        command: Option<String>,
+
+        /// Also send the paths listed in this file, one per line, or `-` to
+        /// read the list from stdin
+        #[arg(long, value_name = "LIST", conflicts_with_all = ["from_url", "command"])]
+        from_file: Option<PathBuf>,
+
+        /// Send folders with more than this many files (default 100) or deep
+        /// nesting as a single archive
+        #[arg(long, value_name = "FILES", num_args = 0..=1, default_missing_value = "100")]
+        auto_archive: Option<usize>,
+
+        /// Send each path under its own code, one after another
+        #[arg(long, conflicts_with_all = ["port", "debug_connection"])]
+        queue: bool,
+
+        /// With --queue, how many files to serve at once
+        #[arg(long, default_value_t = 1, requires = "queue")]
+        concurrency: usize,
+
+        /// With --queue, how many files one receiver can get at once
+        #[arg(long, value_name = "N", requires = "queue")]
+        per_peer: Option<usize>,
+
+        /// With --queue, a file to send before the others, which pause
+        /// while it's being sent; can be repeated
+        #[arg(long, value_name = "PATH", requires = "queue")]
+        urgent: Vec<PathBuf>,
+
+        /// Keep serving the same code to everyone who uses it, until stopped
+        #[arg(long, conflicts_with = "queue")]
+        share: bool,
+
+        /// Give each of this many recipients a code of their own, which
+        /// works once, and show which codes were claimed
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..),
+            conflicts_with_all = ["queue", "share", "exit_after", "code", "port", "debug_connection"])]
+        recipients: Option<u16>,
+
+        /// Stop after the first receiver has everything (the default), after
+        /// --downloads receivers have, or never, like --share
+        #[arg(long, value_enum, conflicts_with_all = ["queue", "share"])]
+        exit_after: Option<ExitAfter>,
+
+        /// With --exit-after all, how many receivers get the files
+        #[arg(long, value_name = "N", required_if_eq("exit_after", "all"))]
+        downloads: Option<u32>,
+
+        /// With --share or --exit-after, list the file in the relay's
+        /// catalog at /catalog for receivers who don't have the code;
+        /// `key` lists it only for those with the same ZAP_API_KEY
+        #[arg(long, value_enum, value_name = "WHO", num_args = 0..=1, default_missing_value = "public",
+            conflicts_with_all = ["queue", "recipients", "leave_on_relay", "require_key", "no_relay", "ticket_only"])]
+        list: Option<Listing>,
+
+        /// Stop once nobody has been connected for this long, e.g. "10m";
+        /// a send nobody came for fails
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with = "queue")]
+        idle_timeout: Option<std::time::Duration>,
+
+        /// Leave the file on the relay, sealed so only the code opens it,
+        /// for the receiver to pick up after this exits; for files under
+        /// the relay's limit, and deleted once received unless --share
+        #[arg(long = "async", conflicts_with_all = ["queue", "recipients", "exit_after", "idle_timeout", "code", "require_key", "no_relay"])]
+        leave_on_relay: bool,
+
+        /// Stop the code and ticket from working after this long, e.g.
+        /// "2h"; with --async, how long the relay keeps the file, up to the
+        /// relay's own limit
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with_all = ["queue", "recipients"])]
+        expires: Option<std::time::Duration>,
+
+        /// Send at most this fast to all receivers together, e.g. "10MB",
+        /// shared evenly so a fast receiver can't starve the others
+        #[arg(long, value_name = "RATE", value_parser = parse_rate, conflicts_with_all = ["queue", "leave_on_relay"])]
+        max_rate: Option<u64>,
+
+        /// Send at most this fast to any one receiver, e.g. "2MB"
+        #[arg(long, value_name = "RATE", value_parser = parse_rate, conflicts_with_all = ["queue", "leave_on_relay"])]
+        max_rate_per_receiver: Option<u64>,
+
+        /// Register under this code instead of a generated one, e.g.
+        /// "standup-recording"; the relay needs an API key for it
+        #[arg(long, value_name = "CODE", conflicts_with_all = ["queue", "no_relay"])]
+        code: Option<String>,
+
+        /// Only let receivers in who also have a key, which is added to the
+        /// code and kept from the relay, so the relay can't fetch the files
+        #[arg(long, conflicts_with = "queue")]
+        require_key: bool,
+
+        /// Give up when the receiver rejects the file, instead of waiting
+        /// for it or someone else to try again
+        #[arg(long)]
+        exit_on_reject: bool,
+
+        /// When the file changes while it's being sent, wait for it to
+        /// stop changing and offer the new version, instead of failing
+        #[arg(long, conflicts_with_all = ["queue", "share", "exit_after", "recipients"])]
+        retry_on_change: bool,
+
+        /// When the connection breaks partway, wait for the receiver to
+        /// carry on from what it has with `zap receive --resume`, instead
+        /// of failing
+        #[arg(long, conflicts_with_all = ["queue", "share", "exit_after", "recipients"])]
+        resume: bool,
+
+        /// Run a command once the receiver has everything, e.g.
+        /// "notify-send {name}"; placeholders are {path}, {name}, {size},
+        /// {checksum} and {peer}
+        #[arg(long, value_name = "CMD")]
+        on_sent: Option<String>,
 
         /// Don't use relay for short codes (share full ticket instead)
         #[arg(long)]
         no_relay: bool,
 
-        /// Custom relay server URL
-        #[arg(long, default_value = DEFAULT_RELAY)]
-        relay: String,
+        /// Work without internet: no relays and no code, only a ticket
+        /// with this machine's addresses, for a receiver on the same
+        /// network using --direct. Pair with --port to fix the UDP port
+        #[arg(long, conflicts_with_all = ["code", "relay", "from_url", "queue", "recipients", "leave_on_relay"])]
+        ticket_only: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+
+        #[command(flatten)]
+        net: NetArgs,
     },
 
     /// Receive a file
@@ -40,13 +373,309 @@ pub enum Commands {
         /// The code or ticket from the sender (interactive if not provided)
         code: Option<String>,
 
+        /// Read the code from a QR code in this image, e.g. a screenshot
+        /// or a photo of the sender's screen
+        #[arg(long, value_name = "IMAGE", conflicts_with = "code")]
+        qr_image: Option<PathBuf>,
+
+        /// Output directory (defaults to the one set with zap init, or the
+        /// current directory)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Save under the sender's exact file name, without adding an
+        /// extension detected from the content
+        #[arg(long)]
+        keep_name: bool,
+
+        /// When receiving a folder, pick which of its files to download
+        #[arg(long)]
+        select: bool,
+
+        /// Name the received file from a pattern, e.g.
+        /// "{date}-{sender_short}-{name}"; also {stem}, {ext}, {time},
+        /// {sender} and {size}. Existing files are never overwritten
+        #[arg(long)]
+        output_template: Option<String>,
+
+        /// Run a command on the received file, e.g. "photos-import {path}";
+        /// placeholders are {path}, {name}, {size}, {checksum} and {peer}
+        #[arg(long, value_name = "CMD")]
+        on_complete: Option<String>,
+
+        /// When to fsync what's received: "none", "done" once each file has
+        /// arrived, or a number of megabytes like "64" to also sync along
+        /// the way. Worth it on network filesystems and SD cards
+        #[arg(long, default_value = "none")]
+        durability: Durability,
+
+        /// Don't download a file that's already in the output directory
+        /// with the same contents
+        #[arg(long)]
+        dedup: bool,
+
+        /// Also look for the file anywhere in this folder, copying it from
+        /// there if it's found; implies --dedup
+        #[arg(long, value_name = "DIR")]
+        cache_dir: Option<PathBuf>,
+
+        /// Get the file's hash tree from the sender first and check every
+        /// 256 KB as it arrives, stopping at the first bad piece. For a
+        /// folder, get every file's hash and check each file instead
+        #[arg(long)]
+        verify_pieces: bool,
+
+        /// Fail unless the file has this hash, passed along by the sender
+        /// some other way: BLAKE3 as zap and b3sum print it, or
+        /// sha256:<hex>. The file is deleted if it doesn't match
+        #[arg(long, value_name = "HASH")]
+        checksum: Option<Checksum>,
+
+        /// Carry on with a receive that broke off, from the .zap-state
+        /// file left next to the partial file, here or on another machine.
+        /// The sender has to be sharing with --share or sending with --resume
+        #[arg(long, value_name = "STATE", conflicts_with_all = ["code", "qr_image"])]
+        resume: Option<PathBuf>,
+
+        /// Connect straight to the addresses in the sender's ticket, without
+        /// relays or code lookups, e.g. on a LAN without internet
+        #[arg(long, conflicts_with = "relay")]
+        direct: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+
+        #[command(flatten)]
+        net: NetArgs,
+    },
+
+    /// Print a small text file from a sender instead of saving it
+    Cat {
+        /// The code or ticket from the sender
+        code: String,
+
+        /// Refuse files larger than this many bytes
+        #[arg(long, default_value_t = 1024 * 1024)]
+        max_size: u64,
+
+        /// Also save the file to the current directory
+        #[arg(long)]
+        save: bool,
+
+        /// Don't syntax highlight the output
+        #[arg(long)]
+        plain: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Receive a transfer captured with ZAP_CAPTURE again, without the
+    /// network, to reproduce a failure
+    Replay {
+        /// The capture file
+        capture: PathBuf,
+
         /// Output directory (defaults to current directory)
         #[arg(short, long)]
         output: Option<PathBuf>,
+    },
+
+    /// Browse a shared folder from your file manager over WebDAV
+    Webdav {
+        /// The code or ticket from a sender running `zap send --share`
+        code: String,
+
+        /// Local port to serve WebDAV on
+        #[arg(long, default_value_t = 4918)]
+        port: u16,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Receive only the new and changed files of a shared folder
+    Sync {
+        /// Local directory to bring up to date
+        dir: PathBuf,
+
+        /// The code or ticket from a sender running `zap send --share`
+        code: String,
+
+        /// Only show what would be received
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Mount a shared folder as a read-only filesystem, fetching files as
+    /// they're read
+    #[cfg(all(unix, feature = "fuse"))]
+    Mount {
+        /// The code or ticket from a sender running `zap send --share`
+        code: String,
+
+        /// Empty directory to mount on
+        mountpoint: PathBuf,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Copy files to or from a paired peer running `zap listen`
+    Cp {
+        /// What to copy: a local path, or name:path on a paired peer
+        source: String,
+
+        /// Where to: a local directory, or name:dir on a paired peer
+        dest: String,
+    },
+
+    /// Serve a directory to paired peers for `zap cp`
+    Listen {
+        /// Directory to serve; peers can't reach anything outside it
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+
+        /// Only let peers copy from the directory, not into it
+        #[arg(long)]
+        read_only: bool,
+
+        /// Sort copies in into inbox/<peer>/<date>/ here, whatever
+        /// directory the peer names
+        #[arg(long, conflicts_with = "read_only")]
+        inbox: bool,
+
+        /// With --inbox, the folders inside each peer's, e.g.
+        /// "{date}/{time}"; placeholders are {date}, {time}, {sender} and
+        /// {sender_short}
+        #[arg(long, value_name = "TEMPLATE", requires = "inbox")]
+        inbox_template: Option<String>,
+
+        /// With --inbox, what to do when a name is already taken
+        #[arg(long, value_enum, default_value_t, requires = "inbox")]
+        on_conflict: Conflict,
+
+        /// With --inbox, the most each peer's folder may hold, e.g. "5GB"
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "inbox")]
+        quota: Option<u64>,
+    },
+
+    /// Set up this machine: pick a relay and a download folder, and make
+    /// the key other machines pair with
+    Init,
+
+    /// Show this machine's node id and paired peers, or pair with a peer
+    Pair {
+        /// Name to use for the peer, as in name:path
+        #[arg(requires = "id")]
+        name: Option<String>,
+
+        /// The peer's node id, shown by `zap pair` on that machine
+        id: Option<String>,
+
+        /// Forget a paired peer
+        #[arg(long, value_name = "NAME", conflicts_with = "name")]
+        remove: Option<String>,
+    },
+
+    /// Show how much was sent and received each month, or set a monthly
+    /// limit to be warned about
+    Stats {
+        /// How many months to show, latest first
+        #[arg(long, default_value_t = 12)]
+        months: usize,
+
+        /// Warn when a month's transfers go over this, e.g. 50GB
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "no_limit")]
+        limit: Option<u64>,
+
+        /// Stop warning about a monthly limit
+        #[arg(long)]
+        no_limit: bool,
+    },
+
+    /// List what was sent, with the receipts receivers signed for it
+    History {
+        /// How many sends to show, latest first
+        #[arg(long, default_value_t = 20)]
+        last: usize,
 
-        /// Custom relay server URL
-        #[arg(long, default_value = DEFAULT_RELAY)]
-        relay: String,
+        /// Print the entries and their receipts as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List, create or switch settings profiles
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
+    },
+
+    /// List, pause, resume or cancel transfers running in other terminals
+    Ctl {
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+
+    /// Watch and control every running transfer in a live dashboard
+    Tui,
+
+    /// Keep a node online for `zap send` and `zap receive` to hand their
+    /// transfers to, so they start faster and keep one node id
+    Daemon,
+
+    /// Forward a TCP port over a zap connection
+    Tunnel {
+        /// The code or ticket of the exposing peer
+        #[arg(conflicts_with = "expose", required_unless_present = "expose")]
+        code: Option<String>,
+
+        /// Local port to expose to the peer
+        #[arg(long)]
+        expose: Option<u16>,
+
+        /// Local port to listen on when connecting to a peer
+        #[arg(long, default_value_t = 8080)]
+        local: u16,
+
+        /// Don't use relay for short codes (share full ticket instead)
+        #[arg(long)]
+        no_relay: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Update zap to the latest release
+    Update {
+        /// Only check whether a newer version is available
+        #[arg(long)]
+        check: bool,
+
+        /// Install without verifying the release signature
+        #[arg(long)]
+        skip_verify: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Show the version and build details, and the licenses of the
+    /// third-party crates zap is built from
+    About {
+        /// Print the license texts and notices of the third-party crates
+        #[arg(long)]
+        licenses: bool,
     },
 }
 
@@ -54,279 +683,730 @@ pub enum Commands {
 struct RegisterRequest {
     ticket: String,
     file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    list: Option<Listing>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alphabet: Option<CodeAlphabet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<usize>,
 }
 
 #[derive(Deserialize)]
 struct RegisterResponse {
     code: String,
     words: String,
+    /// The code with the relay's address; older relays don't send it
+    #[serde(default)]
+    token: Option<String>,
+    /// Follows the code's lookups and downloads, on relays that can
+    #[serde(default)]
+    events_token: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct LookupResponse {
-    ticket: String,
+    #[serde(default)]
+    ticket: Option<String>,
+    /// The code is for a file left on the relay, not a running sender
+    #[serde(default)]
+    mirror: bool,
+    /// The iroh relay in our region, for relays that know of several
+    #[serde(default)]
+    iroh_relay: Option<String>,
 }
 
-pub async fn run_send(path: Option<PathBuf>, no_relay: bool, relay: String) -> Result<()> {
+/// What the relay has under a code
+pub(crate) enum Lookup {
+    /// A sender's ticket
+    Ticket(String),
+    /// A sealed file, see [`zap_core::mirror`]
+    Mirror,
+}
+
+/// Send files, all under one code, or each under its own with
+/// [`SendMode::Queue`]
+pub async fn run_send(paths: Vec<PathBuf>, args: SendArgs) -> Result<()> {
+    let SendArgs {
+        no_relay,
+        relay,
+        code,
+        name,
+        label,
+        from_url,
+        command,
+        auto_archive,
+        mode,
+        list,
+        idle_timeout,
+        expires,
+        max_rate,
+        max_rate_per_receiver,
+        require_key,
+        exit_on_reject,
+        retry_on_change,
+        resume,
+        on_sent,
+        net,
+    } = args;
+    let on_sent = hooks::on_sent(on_sent);
+
+    // A URL is sent as it downloads, and a command's output as it's
+    // written, like stdin
+    let stream = match (from_url, command) {
+        (Some(url), _) => {
+            let download = fetch::open(&url).await?;
+            Some(Source::Stream {
+                name: download.name,
+                reader: download.reader,
+            })
+        }
+        (None, Some(line)) => Some(command::spawn(&line)?),
+        (None, None) => None,
+    };
+
     // Interactive file selection if no path provided
-    let path = match path {
-        Some(p) => p,
-        None => select_file_interactive()?,
+    let urgent_only = matches!(&mode, SendMode::Queue(options) if !options.urgent.is_empty());
+    let paths = if paths.is_empty() && !urgent_only && stream.is_none() {
+        select_file_interactive()?
+    } else {
+        paths
     };
 
-    // Validate path exists
-    if !path.exists() {
-        anyhow::bail!("Path does not exist: {}", path.display());
+    if list.is_some() && !matches!(mode, SendMode::Share { .. }) {
+        anyhow::bail!("--list needs --share or --exit-after all|never");
     }
 
-    let file_name = path
-        .file_name()
-        .map(|s| s.to_string_lossy().to_string())
-        .unwrap_or_else(|| "file".to_string());
+    if let SendMode::Queue(options) = mode {
+        return run_queue(
+            paths,
+            options,
+            no_relay,
+            relay,
+            auto_archive,
+            label,
+            on_sent,
+        )
+        .await;
+    }
+
+    // `-` streams stdin, whose length isn't known up front
+    let from_stdin = paths.len() == 1 && paths[0].as_os_str() == "-";
+    let source = match stream {
+        Some(stream) => stream,
+        None if from_stdin => Source::Stream {
+            name: "stdin".to_string(),
+            reader: Box::new(tokio::io::stdin()),
+        },
+        None => Source::Paths(paths),
+    };
+    let prepared = SendFlow {
+        name,
+        label: label.clone(),
+        auto_archive,
+        share: matches!(mode, SendMode::Share { .. }),
+        downloads: match mode {
+            SendMode::Share { downloads } => downloads,
+            _ => None,
+        },
+        idle_timeout,
+        expires,
+        max_rate,
+        max_rate_per_receiver,
+        require_key,
+        retry_rejected: !exit_on_reject,
+        retry_on_change,
+        resume,
+        relay: (!no_relay).then(|| relay.clone()),
+        code,
+        list,
+        net: net.clone(),
+        ..SendFlow::new(source)
+    }
+    .prepare()?;
+    let streamed = prepared.is_stream();
+    let batch = prepared.batch;
+
+    if let SendMode::Recipients(recipients) = mode {
+        if streamed {
+            anyhow::bail!("stdin can only be sent to one recipient");
+        }
+        let group = Group {
+            bandwidth: prepared.bandwidth(),
+            path: prepared.path,
+            selection: prepared.selection,
+            batch: prepared.batch,
+            name: prepared.name,
+            label,
+            auto_archive,
+            idle_timeout,
+            require_key,
+            no_relay,
+            relay,
+            on_sent,
+        };
+        return run_group(group, recipients).await;
+    }
 
     println!(
         "\n{} Preparing to send: {}",
-        style("⚡").cyan(),
-        style(&file_name).green()
+        accent(symbol("⚡")),
+        success(&prepared.name)
     );
+    if let Some(label) = &label {
+        println!("  Label: {}", accent(label));
+    }
 
-    let node = ZapNode::new().await?;
-    let (ticket, mut progress_rx) = node.send(&path).await?;
+    let mut sending = prepared.start().await?;
+    if sending.through_daemon() {
+        println!("{}", style("Sending through zap daemon").dim());
+    }
+    if let Some(e) = &sending.relay_error {
+        eprintln!(
+            "{} Could not register with relay: {}",
+            warning(symbol("⚠")),
+            e
+        );
+    }
+    let path = sending.path.clone();
+    let file_name = sending.name.clone();
+    let handle = sending.handle.clone();
 
-    // Register with relay to get short code
-    let code_info = if no_relay {
-        None
-    } else {
-        match register_ticket(&relay, &ticket.to_string(), Some(&file_name)).await {
-            Ok(info) => Some(info),
-            Err(e) => {
-                eprintln!(
-                    "{} Could not register with relay: {}",
-                    style("⚠").yellow(),
-                    e
-                );
-                None
-            }
-        }
+    // The relay only ever sees the ticket; the key goes with the code
+    let key = sending.ticket.key;
+    let with_key = |code: &str| match key {
+        Some(key) => format!("{}.{}", code, key),
+        None => code.to_string(),
     };
-
     println!();
-    if let Some(ref info) = code_info {
+    if let Some(ref info) = sending.code {
         println!(
             "{} Share this code with the receiver:\n",
-            style("⚡").cyan()
+            accent(symbol("⚡"))
         );
-        println!("  Code:  {}", style(&info.code).green().bold());
-        println!("  Words: {}", style(&info.words).cyan().bold());
+        println!("  Code:  {}", success(with_key(&info.code)).bold());
+        println!("  Words: {}", accent(with_key(&info.words)).bold());
+        if let Some(ref token) = info.token {
+            println!("  Token: {}", accent(with_key(token)));
+        }
         println!();
         println!(
             "  {}",
             style("Receiver runs: zap receive <code>").dim()
         );
+        if info.token.is_some() {
+            println!(
+                "  {}",
+                style("The token works without --relay, whichever relay they use").dim()
+            );
+        }
+        if list.is_some() {
+            let line = format!("Listed for anyone to receive at {}/catalog", relay);
+            println!("  {}", style(line).dim());
+        }
     } else {
         println!(
             "{} Share this ticket with the receiver:\n",
-            style("⚡").cyan()
+            accent(symbol("⚡"))
         );
-        println!("  {}", style(ticket.to_string()).green());
+        println!("  {}", success(sending.ticket.share()));
+        // Longer than a code, but it can be read out loud
+        if let Ok(words) = zap_core::mnemonic::to_words(&sending.ticket) {
+            println!("\n  Or as words: {}", accent(words));
+        }
+    }
+    if let Some(expires) = expires {
+        let line = format!("It stops working in {}", share::short_duration(expires));
+        println!("\n  {}", style(line).dim());
     }
 
     println!();
+    let code = sending.code.as_ref().map(|info| info.code.as_str());
+    // The daemon pauses its send itself when asked
+    let mut ctl = if sending.through_daemon() {
+        ctl::register_scheduled(&file_name, code, &handle)
+    } else {
+        ctl::register(&file_name, code, &handle)
+    };
+    if let SendMode::Share { downloads } = mode {
+        return watch_share(sending, on_sent, downloads, idle_timeout).await;
+    }
     println!("{}", style("Waiting for receiver to connect...").dim());
 
-    let pb = ProgressBar::new(0);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=>-"),
-    );
+    let pb = output::bar(0);
+    pb.set_style(if streamed {
+        spinner_style()
+    } else {
+        output::bar_style(false)
+    });
+    let mut archive_pb: Option<ProgressBar> = None;
+    let mut convert_pb: Option<ProgressBar> = None;
+    let mut controls: Option<KeyControls> = None;
+    let mut paths = PathWatch::new(&net);
+    let mut connected = false;
+    let mut receipt = None;
 
-    while let Some(progress) = progress_rx.recv().await {
+    loop {
+        let event = tokio::select! {
+            event = sending.next() => event,
+            requested = ctl.changed() => {
+                // Once connected, the transfer itself reports the cancel
+                if requested == Requested::Cancel && !connected {
+                    pb.abandon();
+                    anyhow::bail!("Transfer cancelled");
+                }
+                sending.request(requested).await;
+                continue;
+            }
+        };
+        let progress = match event {
+            Some(SendEvent::Progress(progress)) => progress,
+            Some(SendEvent::Code(event)) => {
+                if let Some(line) = claims::line(&event) {
+                    output::println(&pb, line);
+                }
+                continue;
+            }
+            None => break,
+        };
         match progress {
-            SendProgress::Waiting => {}
-            SendProgress::Connected => {
-                println!("{}", style("Receiver connected!").green());
+            SendProgress::Transforming {
+                files_done,
+                total_files,
+            } => {
+                let bar = convert_pb.get_or_insert_with(|| {
+                    let bar = output::bar(total_files as u64);
+                    bar.set_style(convert_style());
+                    bar
+                });
+                bar.set_position(files_done as u64);
+                if files_done == total_files {
+                    bar.finish_and_clear();
+                }
+            }
+            SendProgress::Archiving {
+                files_done,
+                total_files,
+            } => {
+                let bar = archive_pb.get_or_insert_with(|| {
+                    let bar = output::bar(total_files as u64);
+                    bar.set_style(archive_style());
+                    bar
+                });
+                bar.set_position(files_done as u64);
+                if files_done == total_files {
+                    bar.finish_and_clear();
+                }
+            }
+            // The bar works out its own rate
+            SendProgress::Waiting | SendProgress::Throughput { .. } => {}
+            SendProgress::Path(path) => {
+                if let Some(line) = paths.update(path) {
+                    output::println(&pb, line);
+                }
+            }
+            SendProgress::Negotiated(negotiated) => {
+                if let Some(line) = paths.negotiate(negotiated) {
+                    output::println(&pb, line);
+                }
+            }
+            SendProgress::Connected { peer } => {
+                println!("{}", success("Receiver connected!"));
+                connected = true;
+                ctl.connected(peer);
+                // Stdin is the data when streaming, so it can't take keys,
+                // and the daemon's send is paused with zap ctl
+                if !from_stdin && !sending.through_daemon() {
+                    controls = KeyControls::start(handle.clone());
+                    if controls.is_some() {
+                        let hint = style("Press p to pause, r to resume").dim();
+                        output::print_line(&hint.to_string());
+                    }
+                }
             }
             SendProgress::Sending {
                 bytes_sent,
                 total_bytes,
             } => {
-                pb.set_length(total_bytes);
+                if let Some(total) = total_bytes {
+                    pb.set_length(total);
+                }
                 pb.set_position(bytes_sent);
+                ctl.progress(bytes_sent, total_bytes);
+            }
+            SendProgress::Rejected { reason } => {
+                drop(controls.take());
+                connected = false;
+                pb.reset();
+                let message = format!(
+                    "{} The receiver rejected it: {}",
+                    warning(symbol("⚠")).bold(),
+                    reason
+                );
+                output::println(&pb, message);
+                // A stream can't be offered again, so it fails instead
+                if !exit_on_reject && !streamed {
+                    let waiting = "Waiting for another try, the code still works...";
+                    output::println(&pb, format!("{}", style(waiting).dim()));
+                }
+            }
+            SendProgress::Changed { path } => {
+                drop(controls.take());
+                connected = false;
+                pb.reset();
+                let message = format!(
+                    "{} {} changed while it was being sent",
+                    warning(symbol("⚠")).bold(),
+                    path.display()
+                );
+                output::println(&pb, message);
+                if retry_on_change {
+                    let waiting = "Offering the new version once it stops changing; the receiver has to try again";
+                    output::println(&pb, format!("{}", style(waiting).dim()));
+                }
+            }
+            SendProgress::Interrupted { reason } => {
+                drop(controls.take());
+                connected = false;
+                let message = format!(
+                    "{} Lost the receiver partway: {}",
+                    warning(symbol("⚠")).bold(),
+                    reason
+                );
+                output::println(&pb, message);
+                let waiting = format!(
+                    "Waiting for it to carry on with zap receive --resume {}.zap-state",
+                    file_name
+                );
+                output::println(&pb, format!("{}", style(waiting).dim()));
+            }
+            SendProgress::Receipt(signed) => receipt = Some(signed),
+            // A folder's files would flood the screen
+            SendProgress::FileSent { path } if batch => {
+                output::println(&pb, format!("  {} {}", success(symbol("✓")), path));
+            }
+            SendProgress::FileSent { .. } => {}
+            SendProgress::Paused => {
+                pb.set_message(format!("{}", warning("paused")));
             }
-            SendProgress::Complete => {
+            SendProgress::Resumed => {
+                pb.set_message("");
+            }
+            SendProgress::Complete {
+                peer,
+                size,
+                checksum,
+                timings,
+            } => {
+                drop(controls.take());
+                ctl.complete();
                 pb.finish_with_message("done");
-                println!("\n{} Transfer complete!", style("✓").green().bold());
+                usage::record(Direction::Sent, pb.position());
+                println!("\n{} Transfer complete!", success(symbol("✓")).bold());
+                if receipt.is_some() {
+                    println!(
+                        "  {}",
+                        style("The receiver signed a receipt, see zap history").dim()
+                    );
+                }
+                history::record(
+                    &file_name,
+                    peer.to_string(),
+                    size,
+                    &checksum,
+                    receipt.take(),
+                );
+                if let Some(node) = sending.node() {
+                    paths.report(node, false);
+                }
+                paths.timings(&timings);
+                if let Some(command) = &on_sent {
+                    let fields = HookFields {
+                        path: &path,
+                        name: &file_name,
+                        size,
+                        checksum: &checksum,
+                        peer: peer.to_string(),
+                    };
+                    run_hook(command, &fields).await?;
+                }
                 break;
             }
             SendProgress::Error(e) => {
+                drop(controls.take());
                 pb.abandon();
+                usage::record(Direction::Sent, pb.position());
+                if let Some(node) = sending.node() {
+                    paths.report(node, true);
+                }
                 anyhow::bail!("Transfer failed: {}", e);
             }
         }
     }
 
-    node.shutdown().await?;
-    Ok(())
+    sending.shutdown().await
 }
 
-pub async fn run_receive(
-    code: Option<String>,
-    output: Option<PathBuf>,
-    relay: String,
-) -> Result<()> {
-    // Interactive code input if not provided
-    let code = match code {
-        Some(c) => c,
-        None => Input::<String>::with_theme(&ColorfulTheme::default())
-            .with_prompt("Enter code or ticket")
-            .interact_text()?,
-    };
+pub async fn run_receive(args: ReceiveArgs) -> Result<()> {
+    let ReceiveArgs {
+        code,
+        qr_image,
+        output,
+        relay,
+        keep_name,
+        select,
+        output_template,
+        on_complete,
+        durability,
+        dedup,
+        cache_dir,
+        verify_pieces,
+        expected,
+        resume,
+        net,
+    } = args;
+    let on_complete = hooks::on_complete(on_complete);
+    let output = output.or_else(|| config::Config::load().active().download_dir.clone());
+    let template = output_template
+        .as_deref()
+        .map(OutputTemplate::parse)
+        .transpose()?;
 
-    let code = code.trim();
+    let mut receiving = match resume {
+        Some(state) => {
+            let saved = ResumeState::load(&state).await?;
+            println!(
+                "{} Resuming {} from {}",
+                accent(symbol("⚡")),
+                accent(&saved.name),
+                state.display()
+            );
+            flows::resume(&state, &saved, durability, &net).await?
+        }
+        None => {
+            let code = match (code, qr_image) {
+                (Some(c), _) => c,
+                (None, Some(image)) => qr::read_code(&image)?,
+                (None, None) => Input::<String>::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Enter code or ticket")
+                    .interact_text()?,
+            };
 
-    // Determine if it's a short code/words or full ticket
-    let ticket_str = if is_short_code(code) {
-        println!(
-            "{} Looking up code: {}",
-            style("⚡").cyan(),
-            style(code).green()
-        );
-        lookup_ticket(&relay, code).await?
-    } else {
-        code.to_string()
+            // Offline, a code can't be looked up
+            let resolved = if net.offline {
+                Resolved::Ticket(code::resolve_direct(&code)?)
+            } else {
+                resolve(&code, &relay).await?
+            };
+            let ticket = match resolved {
+                Resolved::Ticket(ticket) => ticket,
+                Resolved::Mirror { relay, code, key } => {
+                    return receive_mirror(&relay, &code, key, output, on_complete).await;
+                }
+            };
+            let flow = ReceiveFlow {
+                output,
+                keep_name,
+                durability,
+                dedup,
+                cache_dir,
+                verify_pieces,
+                template,
+                choose_entries: select.then(|| {
+                    EntryChooser::new(|entries| async move {
+                        tokio::task::spawn_blocking(move || choose_entries(&entries))
+                            .await
+                            .ok()?
+                    })
+                }),
+                net: net.clone(),
+            };
+            let receiving = flows::receive(ticket, flow).await?;
+            if receiving.through_daemon() {
+                println!("{}", style("Receiving through zap daemon").dim());
+            }
+            receiving
+        }
     };
 
-    let ticket = Ticket::deserialize(&ticket_str)?;
-    let node = ZapNode::new().await?;
-
-    let mut progress_rx = node.receive(ticket, output.as_deref()).await?;
-
-    println!("\n{} Connecting to sender...", style("⚡").cyan());
+    println!("\n{} Connecting to sender...", accent(symbol("⚡")));
 
-    let pb = ProgressBar::new(0);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=>-"),
-    );
+    let pb = output::bar(0);
+    pb.set_style(output::bar_style(false));
+    let mut paths = PathWatch::new(&net);
+    // A copy found locally isn't downloaded, so it doesn't count as usage
+    let mut already_here = false;
+    // With a folder's manifest, the files in order and how many arrived
+    // whole
+    let mut manifest: Option<(Vec<String>, usize)> = None;
 
-    while let Some(progress) = progress_rx.recv().await {
+    while let Some(progress) = receiving.next().await {
         match progress {
-            ReceiveProgress::Connecting => {}
+            // The bar works out its own rate
+            ReceiveProgress::Connecting | ReceiveProgress::Throughput { .. } => {}
+            ReceiveProgress::Manifest { files } => {
+                let files: Vec<_> = files.into_iter().map(|file| file.path).collect();
+                pb.set_message(file_progress(&files, 0));
+                manifest = Some((files, 0));
+            }
+            // The offer listed the files, so only the one being received
+            // is shown
+            ReceiveProgress::FileReceived { .. } => {
+                if let Some((files, whole)) = &mut manifest {
+                    *whole += 1;
+                    pb.set_message(file_progress(files, *whole));
+                }
+            }
+            ReceiveProgress::Path(path) => {
+                if let Some(line) = paths.update(path) {
+                    output::println(&pb, line);
+                }
+            }
             ReceiveProgress::Connected => {
-                println!("{}", style("Connected!").green());
+                println!("{}", success("Connected!"));
             }
-            ReceiveProgress::Offer { name, size } => {
-                println!(
-                    "Receiving {} ({})",
-                    style(&name).cyan(),
-                    format_bytes(size)
-                );
+            ReceiveProgress::Offer {
+                name,
+                size,
+                kind,
+                label,
+            } => {
+                match (size, kind) {
+                    (Some(size), OfferKind::Directory { entries }) => {
+                        println!(
+                            "Receiving {}/ ({} files, {})",
+                            accent(&name),
+                            entries.len(),
+                            format_bytes(size)
+                        );
+                    }
+                    (Some(size), OfferKind::Archive) => {
+                        println!(
+                            "Receiving {}/ (archive, {})",
+                            accent(&name),
+                            format_bytes(size)
+                        );
+                    }
+                    (Some(size), OfferKind::File) => {
+                        println!("Receiving {} ({})", accent(&name), format_bytes(size));
+                    }
+                    (None, _) => {
+                        println!("Receiving {} (streaming)", accent(&name));
+                        pb.set_style(spinner_style());
+                    }
+                }
+                if let Some(label) = label {
+                    println!("  Label: {}", accent(label));
+                }
             }
             ReceiveProgress::Receiving {
                 bytes_received,
                 total_bytes,
             } => {
-                pb.set_length(total_bytes);
+                if let Some(total) = total_bytes {
+                    pb.set_length(total);
+                }
                 pb.set_position(bytes_received);
             }
-            ReceiveProgress::Complete { path } => {
+            ReceiveProgress::Paused => {
+                pb.set_message(format!("{}", warning("paused by sender")));
+            }
+            ReceiveProgress::Resumed => {
+                pb.set_message("");
+            }
+            ReceiveProgress::Unpacking => {
+                pb.finish_and_clear();
+                println!("{}", style("Unpacking archive...").dim());
+            }
+            ReceiveProgress::AlreadyHave { source } => {
+                // Counts as received, for the hook's size
+                pb.set_position(pb.length().unwrap_or(0));
+                already_here = true;
+                let message = format!("Already here as {}, nothing to download", source.display());
+                output::println(&pb, format!("{}", style(message).dim()));
+            }
+            ReceiveProgress::Complete {
+                path,
+                checksum,
+                timings,
+            } => {
                 pb.finish_with_message("done");
+                if !already_here {
+                    usage::record(Direction::Received, pb.position());
+                }
+                if let Some(expected) = &expected {
+                    flows::check_received(expected, &path, &checksum).await?;
+                }
                 println!(
                     "\n{} Saved to {}",
-                    style("✓").green().bold(),
-                    style(path.display()).cyan()
+                    success(symbol("✓")).bold(),
+                    accent(path.display())
                 );
+                let hex: String = checksum.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("  {}", style(format!("BLAKE3: {}", hex)).dim());
+                if expected.is_some() {
+                    println!("  {}", success("Matches the expected checksum"));
+                }
+                if let Some(node) = receiving.node() {
+                    paths.report(node, false);
+                }
+                paths.timings(&timings);
+                if let Some(command) = &on_complete {
+                    let name = path
+                        .file_name()
+                        .map(|n| n.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    let fields = HookFields {
+                        path: &path,
+                        name: &name,
+                        size: pb.position(),
+                        checksum: &checksum,
+                        peer: receiving.peer.clone(),
+                    };
+                    run_hook(command, &fields).await?;
+                }
                 break;
             }
+            // Only `zap cat` receives into memory; anything else loaded
+            // there has nowhere to go
+            ReceiveProgress::Loaded { name, .. } => {
+                pb.abandon();
+                anyhow::bail!("{} was received into memory instead of saved", name);
+            }
             ReceiveProgress::Error(e) => {
                 pb.abandon();
+                usage::record(Direction::Received, pb.position());
+                if let Some((files, whole)) = &manifest
+                    && let Some(broken) = files.get(*whole)
+                {
+                    println!(
+                        "{} of {} files arrived whole; {} and any after it didn't",
+                        whole,
+                        files.len(),
+                        accent(broken)
+                    );
+                }
+                if let Some(node) = receiving.node() {
+                    paths.report(node, true);
+                }
                 anyhow::bail!("Transfer failed: {}", e);
             }
         }
     }
 
-    node.shutdown().await?;
-    Ok(())
-}
-
-/// Interactive file/folder selection
-fn select_file_interactive() -> Result<PathBuf> {
-    println!(
-        "\n{} What would you like to send?",
-        style("⚡").cyan()
-    );
-
-    let options = vec!["Select a file", "Enter path manually"];
-    let selection = Select::with_theme(&ColorfulTheme::default())
-        .items(&options)
-        .default(0)
-        .interact()?;
-
-    match selection {
-        0 => {
-            // List current directory files
-            let cwd = std::env::current_dir()?;
-            let mut entries: Vec<_> = std::fs::read_dir(&cwd)?
-                .filter_map(|e| e.ok())
-                .map(|e| e.path())
-                .collect();
-            entries.sort();
-
-            if entries.is_empty() {
-                anyhow::bail!("No files in current directory");
-            }
-
-            let display_names: Vec<String> = entries
-                .iter()
-                .map(|p| {
-                    let name = p
-                        .file_name()
-                        .map(|s| s.to_string_lossy().to_string())
-                        .unwrap_or_default();
-                    if p.is_dir() {
-                        format!("{}/", name)
-                    } else {
-                        name
-                    }
-                })
-                .collect();
-
-            let selection = Select::with_theme(&ColorfulTheme::default())
-                .with_prompt("Select file or folder")
-                .items(&display_names)
-                .default(0)
-                .interact()?;
-
-            Ok(entries[selection].clone())
-        }
-        _ => {
-            let input: String = Input::with_theme(&ColorfulTheme::default())
-                .with_prompt("Enter path")
-                .interact_text()?;
-
-            let path = PathBuf::from(shellexpand::tilde(&input).to_string());
-            Ok(path)
-        }
-    }
+    receiving.shutdown().await
 }
 
-/// Check if the input looks like a short code or word-based code
-fn is_short_code(input: &str) -> bool {
-    // Word-based code (contains hyphens, like "alpha-bravo-charlie")
-    if input.contains('-') && input.split('-').all(|w| w.chars().all(|c| c.is_alphabetic())) {
-        return true;
-    }
-
-    // Short alphanumeric code (6 chars or less)
-    if input.len() <= 8 && input.chars().all(|c| c.is_alphanumeric()) {
-        return true;
+/// The file of `files` being received once `whole` of them are in, and how
+/// far along the folder that is
+fn file_progress(files: &[String], whole: usize) -> String {
+    match files.get(whole) {
+        Some(file) => format!("{} ({}/{})", file, whole + 1, files.len()),
+        None => String::new(),
     }
-
-    false
 }
 
 /// Register a ticket with the relay server
@@ -334,27 +1414,48 @@ async fn register_ticket(
     relay: &str,
     ticket: &str,
     file_name: Option<&str>,
+    code: Option<&str>,
+    list: Option<(Listing, Option<u64>)>,
 ) -> Result<RegisterResponse> {
-    let client = reqwest::Client::new();
-    let resp = client
+    let client = tls::relay_client()?;
+    let config = config::Config::load();
+    let profile = config.active();
+    let mut req = client
         .post(format!("{}/api/register", relay))
         .json(&RegisterRequest {
             ticket: ticket.to_string(),
             file_name: file_name.map(String::from),
-        })
-        .send()
-        .await?;
+            code: code.map(String::from),
+            size: list.and_then(|(_, size)| size),
+            list: list.map(|(list, _)| list),
+            alphabet: profile.code_alphabet,
+            length: profile.code_length,
+        });
+
+    // Relays with API keys account registrations to the key
+    if let Ok(key) = std::env::var("ZAP_API_KEY") {
+        req = req.header("X-Api-Key", key);
+    }
+
+    let resp = req.send().await?;
 
     if !resp.status().is_success() {
-        anyhow::bail!("Relay returned error: {}", resp.status());
+        let status = resp.status();
+        // Say why a custom code was refused, e.g. that it's taken
+        if let Ok(body) = resp.json::<serde_json::Value>().await
+            && let Some(error) = body["error"].as_str()
+        {
+            anyhow::bail!("{} ({})", error, status);
+        }
+        anyhow::bail!("Relay returned error: {}", status);
     }
 
     Ok(resp.json().await?)
 }
 
 /// Look up a ticket from the relay server
-async fn lookup_ticket(relay: &str, code: &str) -> Result<String> {
-    let client = reqwest::Client::new();
+async fn lookup_ticket(relay: &str, code: &str) -> Result<Lookup> {
+    let client = tls::relay_client()?;
     let resp = client
         .get(format!("{}/api/lookup/{}", relay, code))
         .send()
@@ -368,7 +1469,42 @@ async fn lookup_ticket(relay: &str, code: &str) -> Result<String> {
     }
 
     let data: LookupResponse = resp.json().await?;
-    Ok(data.ticket)
+    if let Some(url) = &data.iroh_relay {
+        net::steer(url);
+    }
+    match data.ticket {
+        _ if data.mirror => Ok(Lookup::Mirror),
+        Some(ticket) => Ok(Lookup::Ticket(ticket)),
+        None => anyhow::bail!("Relay returned no ticket for the code"),
+    }
+}
+
+/// Spinner for streams whose total size isn't known until they finish
+fn spinner_style() -> ProgressStyle {
+    ProgressStyle::default_spinner()
+        .template(&output::template(
+            "{spinner:.success} {bytes} ({bytes_per_sec})",
+        ))
+        .unwrap()
+}
+
+/// Progress bar for packing a folder into an archive
+fn archive_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template(&output::template(
+            "{spinner:.success} Archiving [{bar:40.accent/blue}] {pos}/{len} files",
+        ))
+        .unwrap()
+        .progress_chars("=>-")
+}
+
+fn convert_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template(&output::template(
+            "{spinner:.success} Converting [{bar:40.accent/blue}] {pos}/{len} files",
+        ))
+        .unwrap()
+        .progress_chars("=>-")
 }
 
 fn format_bytes(bytes: u64) -> String {
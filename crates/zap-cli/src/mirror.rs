@@ -0,0 +1,275 @@
+//! `zap send --async`: a small file left on the relay for later pickup
+//!
+//! The file is sealed under a fresh key as it's uploaded, see
+//! [`zap_core::mirror`]. The key is added to the code the sender gets, as
+//! with `--require-key`, so the relay only ever holds what it can't read,
+//! and the sender doesn't have to stay around until the receiver shows up.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use console::style;
+use futures::Stream;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use zap_core::TransferKey;
+use zap_core::mirror::{Opener, SEGMENT, Sealer};
+
+use crate::format_bytes;
+use crate::hooks::{HookFields, run_hook};
+use crate::output::{accent, success, symbol};
+use crate::picker::select_file_interactive;
+use crate::share::short_duration;
+use crate::template::unique_name;
+use crate::tls;
+
+#[derive(Deserialize)]
+struct UploadResponse {
+    code: String,
+    words: String,
+    /// Seconds the relay keeps the file
+    expires_in: u64,
+}
+
+/// Seal a file and leave it on the relay, printing the code to fetch it
+/// with; unless `share` is set, it's deleted after its first download
+pub async fn run_send_async(
+    paths: Vec<PathBuf>,
+    name: Option<String>,
+    share: bool,
+    expires: Option<Duration>,
+    relay: String,
+) -> Result<()> {
+    let mut paths = if paths.is_empty() {
+        select_file_interactive()?
+    } else {
+        paths
+    };
+    if paths.len() > 1 {
+        anyhow::bail!("--async leaves a single file on the relay");
+    }
+    let path = paths.remove(0);
+
+    let (file_name, size, reader): (_, _, Box<dyn AsyncRead + Send + Unpin>) =
+        if path.as_os_str() == "-" {
+            let name = name.unwrap_or_else(|| "stdin".to_string());
+            (name, None, Box::new(tokio::io::stdin()))
+        } else {
+            if path.is_dir() {
+                anyhow::bail!("--async leaves a single file on the relay, not a folder");
+            }
+            let file = tokio::fs::File::open(&path)
+                .await
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let size = file.metadata().await?.len();
+            let file_name = name.unwrap_or_else(|| {
+                path.file_name()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_else(|| "file".to_string())
+            });
+            (file_name, Some(size), Box::new(file))
+        };
+
+    let size = size.map(|size| format!(" ({})", format_bytes(size)));
+    println!(
+        "\n{} Sealing {}{} for the relay",
+        accent(symbol("⚡")),
+        success(&file_name),
+        size.unwrap_or_default()
+    );
+    let key = TransferKey::generate();
+    let (sealer, header) = Sealer::new(&key, &file_name)?;
+    let sealed = reqwest::Body::wrap_stream(sealed(reader, sealer, header));
+
+    let mut query = vec![("delete_after_download", (!share).to_string())];
+    if let Some(expires) = expires {
+        query.push(("expires_in", expires.as_secs().to_string()));
+    }
+    let mut req = tls::relay_client()?
+        .post(format!("{}/api/mirror", relay))
+        .query(&query)
+        .body(sealed);
+    if let Ok(api_key) = std::env::var("ZAP_API_KEY") {
+        req = req.header("X-Api-Key", api_key);
+    }
+    let resp = req.send().await?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        // Say why, e.g. that the file is over the relay's limit
+        if let Ok(body) = resp.json::<serde_json::Value>().await
+            && let Some(error) = body["error"].as_str()
+        {
+            anyhow::bail!(
+                "Could not leave the file on the relay: {} ({})",
+                error,
+                status
+            );
+        }
+        anyhow::bail!("Could not leave the file on the relay: {}", status);
+    }
+    let uploaded: UploadResponse = resp.json().await?;
+
+    // The relay never sees the key; it goes with the code
+    println!(
+        "\n{} The file is on the relay. Share this code with the receiver:\n",
+        success(symbol("✓")).bold()
+    );
+    println!(
+        "  Code:  {}",
+        success(format!("{}.{}", uploaded.code, key)).bold()
+    );
+    println!(
+        "  Words: {}",
+        accent(format!("{}.{}", uploaded.words, key)).bold()
+    );
+    println!();
+    let kept = short_duration(Duration::from_secs(uploaded.expires_in));
+    let until = if share {
+        format!("Anyone with the code can get it for the next {}", kept)
+    } else {
+        format!("It's deleted once received, or in {} if nobody does", kept)
+    };
+    println!("  {}", style(until).dim());
+    println!("  {}", style("Receiver runs: zap receive <code>").dim());
+    Ok(())
+}
+
+/// What `reader` holds, sealed a segment at a time as the upload asks for
+/// more, after the sealer's `header`
+fn sealed(
+    reader: impl AsyncRead + Send + Unpin + 'static,
+    sealer: Sealer,
+    header: Vec<u8>,
+) -> impl Stream<Item = std::io::Result<Vec<u8>>> {
+    let header = futures::stream::once(async { Ok(header) });
+    let segments =
+        futures::stream::try_unfold((reader, Some(sealer)), |(mut reader, sealer)| async move {
+            let Some(mut sealer) = sealer else {
+                return Ok(None);
+            };
+            let mut buf = vec![0; SEGMENT];
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                let last = sealer.finish().map_err(std::io::Error::other)?;
+                return Ok(Some((last, (reader, None))));
+            }
+            let sealed = sealer.update(&buf[..n]).map_err(std::io::Error::other)?;
+            Ok(Some((sealed, (reader, Some(sealer)))))
+        });
+    futures::StreamExt::chain(header, segments)
+}
+
+/// Fetch a file left on the relay under `code`, open it with `key` and
+/// save it into `output`, or the current directory
+pub(crate) async fn receive_mirror(
+    relay: &str,
+    code: &str,
+    key: Option<TransferKey>,
+    output: Option<PathBuf>,
+    on_complete: Option<String>,
+) -> Result<()> {
+    let Some(key) = key else {
+        anyhow::bail!("This file is sealed; use the whole code, including the key after the '.'");
+    };
+
+    println!(
+        "{} Fetching the file left on the relay...",
+        accent(symbol("⚡"))
+    );
+    let resp = tls::relay_client()?
+        .get(format!("{}/api/mirror/{}", relay, code))
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        if resp.status() == reqwest::StatusCode::GONE {
+            anyhow::bail!("The file was already received or has expired");
+        }
+        anyhow::bail!("Relay returned error: {}", resp.status());
+    }
+
+    let dir = match output {
+        Some(dir) => dir,
+        None => std::env::current_dir()?,
+    };
+    tokio::fs::create_dir_all(&dir).await?;
+    let mut saved = None;
+    let result = save(resp, Opener::new(key), &dir, &mut saved).await;
+    let (path, name, size, checksum) = match result {
+        Ok(done) => done,
+        Err(e) => {
+            // Don't leave a file that was cut short or didn't open
+            if let Some(path) = saved {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+            return Err(e);
+        }
+    };
+
+    println!(
+        "\n{} Saved to {} ({})",
+        success(symbol("✓")).bold(),
+        accent(path.display()),
+        format_bytes(size)
+    );
+    let hex: String = checksum.iter().map(|b| format!("{:02x}", b)).collect();
+    println!("  {}", style(format!("BLAKE3: {}", hex)).dim());
+
+    if let Some(command) = &on_complete {
+        let fields = HookFields {
+            path: &path,
+            name: &name,
+            size,
+            checksum: &checksum,
+            peer: "relay".to_string(),
+        };
+        run_hook(command, &fields).await?;
+    }
+    Ok(())
+}
+
+/// Open what the relay sends a segment at a time into a new file in `dir`,
+/// returning its path, name, size and checksum
+///
+/// `saved` is set to the file as soon as it's created, so the caller can
+/// remove it if this fails partway.
+async fn save(
+    mut resp: reqwest::Response,
+    mut opener: Opener,
+    dir: &Path,
+    saved: &mut Option<PathBuf>,
+) -> Result<(PathBuf, String, u64, [u8; 32])> {
+    let mut file = None;
+    let mut hasher = blake3::Hasher::new();
+    let mut size = 0;
+    let mut write = async |name: Option<&str>, data: Vec<u8>| -> Result<()> {
+        if file.is_none()
+            && let Some(name) = name
+        {
+            let path = dir.join(unique_name(dir, name, false));
+            let created = tokio::fs::File::create(&path)
+                .await
+                .with_context(|| format!("Could not write {}", path.display()))?;
+            *saved = Some(path);
+            file = Some(created);
+        }
+        if let Some(file) = file.as_mut() {
+            file.write_all(&data).await?;
+        }
+        hasher.update(&data);
+        size += data.len() as u64;
+        Ok(())
+    };
+
+    while let Some(chunk) = resp.chunk().await? {
+        let data = opener.update(&chunk)?;
+        write(opener.name(), data).await?;
+    }
+    let (name, data) = opener.finish()?;
+    write(Some(&name), data).await?;
+    if let Some(file) = file.as_mut() {
+        file.flush().await?;
+    }
+    let path = saved.clone().expect("the name was opened");
+    Ok((path, name, size, *hasher.finalize().as_bytes()))
+}
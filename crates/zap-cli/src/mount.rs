@@ -0,0 +1,455 @@
+//! `zap mount`: a shared folder as a read-only filesystem
+//!
+//! The sender has to be sharing (`zap send --share`). The tree comes from
+//! the offer alone, and a read only fetches the blocks it touches, as byte
+//! ranges of that one file, so looking into a huge dataset doesn't
+//! download all of it. Blocks are fetched over one connection to the
+//! share, and recently read ones are kept in memory.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, bail};
+use console::style;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+use tokio::runtime::Handle;
+use tracing::warn;
+use zap_core::RangeReader;
+use zap_core::protocol::{FileOffer, OfferKind};
+
+use crate::code::resolve_ticket;
+use crate::format_bytes;
+use crate::net::start_node;
+use crate::output::{accent, success, symbol};
+
+/// Size of the ranges reads are fetched in
+const BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// How many blocks are kept in memory
+const CACHED_BLOCKS: usize = 64;
+
+/// How long the kernel may cache names and attributes; a share never changes
+const TTL: Duration = Duration::from_secs(3600);
+
+const ROOT: u64 = 1;
+
+/// Mount a sharing sender's files at `mountpoint` until Ctrl-C
+pub async fn run_mount(code: String, mountpoint: PathBuf, relay: String) -> Result<()> {
+    let metadata = std::fs::metadata(&mountpoint)
+        .with_context(|| format!("mount point {}", mountpoint.display()))?;
+    if !metadata.is_dir() {
+        bail!("{} is not a directory", mountpoint.display());
+    }
+
+    let ticket = resolve_ticket(&code, &relay).await?;
+    let node = start_node(None).await?;
+    let offer = node.peek(&ticket).await?;
+    let tree = Tree::new(&offer)?;
+
+    let fs = ZapFs {
+        tree,
+        reader: node.range_reader(&ticket),
+        runtime: Handle::current(),
+        blocks: BlockCache::default(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        time: SystemTime::now(),
+    };
+    let (files, size) = fs.tree.totals();
+
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("zap".into()),
+        MountOption::Subtype("zap".into()),
+    ];
+    let session = fuser::spawn_mount2(fs, &mountpoint, &options)
+        .with_context(|| format!("mounting on {}", mountpoint.display()))?;
+
+    println!(
+        "\n{} Mounted {} ({} files, {}) on {}",
+        accent(symbol("⚡")),
+        accent(&offer.name),
+        files,
+        format_bytes(size),
+        success(mountpoint.display()).bold()
+    );
+    println!(
+        "  {}",
+        style("Read-only; data is fetched as it's read. Press Ctrl-C to unmount").dim()
+    );
+
+    let _ = tokio::signal::ctrl_c().await;
+
+    // Unmounting waits for reads in flight, which need the runtime
+    tokio::task::spawn_blocking(move || session.umount_and_join()).await?;
+    node.shutdown().await?;
+    println!("{} Unmounted", success(symbol("✓")).bold());
+    Ok(())
+}
+
+/// A file or directory, numbered by its position in [`Tree::inodes`] plus one
+#[derive(Debug, PartialEq, Eq)]
+enum Inode {
+    Dir {
+        parent: u64,
+        children: BTreeMap<String, u64>,
+    },
+    File {
+        /// The offer's entry, as asked for in a range request
+        entry: u32,
+        size: u64,
+    },
+}
+
+/// The shared files, laid out as directories
+#[derive(Debug)]
+struct Tree {
+    inodes: Vec<Inode>,
+}
+
+impl Tree {
+    /// Lay out an offer's files under the root; a single file sits in it
+    /// on its own
+    fn new(offer: &FileOffer) -> Result<Self> {
+        let mut tree = Tree {
+            inodes: vec![Inode::Dir {
+                parent: ROOT,
+                children: BTreeMap::new(),
+            }],
+        };
+
+        match &offer.kind {
+            OfferKind::File => {
+                let Some(size) = offer.size else {
+                    bail!("{} is a stream, which can't be mounted", offer.name);
+                };
+                tree.add(ROOT, &offer.name, Inode::File { entry: 0, size });
+            }
+            OfferKind::Directory { entries } => {
+                for (index, entry) in entries.iter().enumerate() {
+                    let (dirs, name) = match entry.path.rsplit_once('/') {
+                        Some((dirs, name)) => (Some(dirs), name),
+                        None => (None, entry.path.as_str()),
+                    };
+                    let parent = dirs
+                        .into_iter()
+                        .flat_map(|dirs| dirs.split('/'))
+                        .fold(ROOT, |parent, dir| tree.dir(parent, dir));
+                    let file = Inode::File {
+                        entry: index as u32,
+                        size: entry.size,
+                    };
+                    tree.add(parent, name, file);
+                }
+            }
+            OfferKind::Archive => bail!(
+                "{} is sent as an archive, which can't be mounted; ask the sender to share it \
+                 without --auto-archive",
+                offer.name
+            ),
+        }
+        Ok(tree)
+    }
+
+    fn get(&self, ino: u64) -> Option<&Inode> {
+        self.inodes.get(ino.checked_sub(1)? as usize)
+    }
+
+    fn lookup(&self, parent: u64, name: &str) -> Option<u64> {
+        match self.get(parent)? {
+            Inode::Dir { children, .. } => children.get(name).copied(),
+            Inode::File { .. } => None,
+        }
+    }
+
+    /// The directory `name` in `parent`, created if needed
+    fn dir(&mut self, parent: u64, name: &str) -> u64 {
+        match self.lookup(parent, name) {
+            Some(ino) => ino,
+            None => {
+                let dir = Inode::Dir {
+                    parent,
+                    children: BTreeMap::new(),
+                };
+                self.add(parent, name, dir)
+            }
+        }
+    }
+
+    fn add(&mut self, parent: u64, name: &str, inode: Inode) -> u64 {
+        self.inodes.push(inode);
+        let ino = self.inodes.len() as u64;
+        if let Some(Inode::Dir { children, .. }) = self.inodes.get_mut(parent as usize - 1) {
+            children.insert(name.to_string(), ino);
+        }
+        ino
+    }
+
+    /// How many files there are and their total size
+    fn totals(&self) -> (usize, u64) {
+        self.inodes
+            .iter()
+            .filter_map(|inode| match inode {
+                Inode::File { size, .. } => Some(*size),
+                Inode::Dir { .. } => None,
+            })
+            .fold((0, 0), |(files, total), size| (files + 1, total + size))
+    }
+}
+
+/// Recently read blocks, by entry and block number
+#[derive(Default)]
+struct BlockCache {
+    blocks: HashMap<(u32, u64), Arc<Vec<u8>>>,
+    /// Oldest first
+    order: VecDeque<(u32, u64)>,
+}
+
+impl BlockCache {
+    fn get(&self, key: (u32, u64)) -> Option<Arc<Vec<u8>>> {
+        self.blocks.get(&key).cloned()
+    }
+
+    fn insert(&mut self, key: (u32, u64), block: Arc<Vec<u8>>) {
+        if self.blocks.insert(key, block).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > CACHED_BLOCKS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.blocks.remove(&oldest);
+            }
+        }
+    }
+}
+
+struct ZapFs {
+    tree: Tree,
+    /// Fetches blocks over one connection to the share
+    reader: RangeReader,
+    /// Reads come in on the mount's own thread and block on fetches here
+    runtime: Handle,
+    blocks: BlockCache,
+    /// Owner of the mount point, who the files appear to belong to
+    uid: u32,
+    gid: u32,
+    /// When the share was mounted, used for every timestamp
+    time: SystemTime,
+}
+
+impl ZapFs {
+    fn attr(&self, ino: u64, inode: &Inode) -> FileAttr {
+        let (kind, size, perm, nlink) = match inode {
+            Inode::Dir { .. } => (FileType::Directory, 0, 0o555, 2),
+            Inode::File { size, .. } => (FileType::RegularFile, *size, 0o444, 1),
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.time,
+            mtime: self.time,
+            ctime: self.time,
+            crtime: self.time,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+            flags: 0,
+        }
+    }
+
+    /// Block `block` of an entry, fetching it unless it's cached
+    fn block(&mut self, entry: u32, size: u64, block: u64) -> Result<Arc<Vec<u8>>> {
+        if let Some(data) = self.blocks.get((entry, block)) {
+            return Ok(data);
+        }
+
+        let start = block * BLOCK_SIZE;
+        let len = BLOCK_SIZE.min(size - start);
+        let fetch = self.reader.read(entry, start, len);
+        let data = Arc::new(self.runtime.block_on(fetch)?);
+        self.blocks.insert((entry, block), data.clone());
+        Ok(data)
+    }
+}
+
+impl Filesystem for ZapFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let found = name
+            .to_str()
+            .and_then(|name| self.tree.lookup(parent, name))
+            .and_then(|ino| Some((ino, self.tree.get(ino)?)));
+        match found {
+            Some((ino, inode)) => reply.entry(&TTL, &self.attr(ino, inode), 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.tree.get(ino) {
+            Some(inode) => reply.attr(&TTL, &self.attr(ino, inode)),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let (entry, file_size) = match self.tree.get(ino) {
+            Some(&Inode::File { entry, size }) => (entry, size),
+            Some(Inode::Dir { .. }) => return reply.error(libc::EISDIR),
+            None => return reply.error(libc::ENOENT),
+        };
+
+        let start = (offset.max(0) as u64).min(file_size);
+        let end = start.saturating_add(size as u64).min(file_size);
+        let mut data = Vec::with_capacity((end - start) as usize);
+        let mut pos = start;
+        while pos < end {
+            let block = pos / BLOCK_SIZE;
+            let bytes = match self.block(entry, file_size, block) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!("reading from the share failed: {}", e);
+                    return reply.error(libc::EIO);
+                }
+            };
+            let from = (pos - block * BLOCK_SIZE) as usize;
+            let to = ((end - block * BLOCK_SIZE) as usize).min(bytes.len());
+            if from >= to {
+                return reply.error(libc::EIO);
+            }
+            data.extend_from_slice(&bytes[from..to]);
+            pos += (to - from) as u64;
+        }
+        reply.data(&data);
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(Inode::Dir { parent, children }) = self.tree.get(ino) else {
+            return reply.error(libc::ENOTDIR);
+        };
+
+        let dots = [(ino, ".".to_string()), (*parent, "..".to_string())];
+        let listing = dots
+            .into_iter()
+            .chain(children.iter().map(|(name, &ino)| (ino, name.clone())));
+        for (i, (ino, name)) in listing.enumerate().skip(offset.max(0) as usize) {
+            let kind = match self.tree.get(ino) {
+                Some(Inode::File { .. }) => FileType::RegularFile,
+                _ => FileType::Directory,
+            };
+            // The offset given back is where the next call should start
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zap_core::protocol::DirEntry;
+
+    fn offer(paths: &[(&str, u64)]) -> FileOffer {
+        let entries: Vec<_> = paths
+            .iter()
+            .map(|&(path, size)| DirEntry {
+                path: path.to_string(),
+                size,
+            })
+            .collect();
+        FileOffer {
+            name: "data".to_string(),
+            size: Some(entries.iter().map(|e| e.size).sum()),
+            checksum: None,
+            kind: OfferKind::Directory { entries },
+            label: None,
+            batch: false,
+        }
+    }
+
+    #[test]
+    fn test_tree() {
+        let tree = Tree::new(&offer(&[("a.txt", 3), ("sub/b.txt", 5), ("sub/deep/c", 7)])).unwrap();
+        assert_eq!(tree.totals(), (3, 15));
+
+        let a = tree.lookup(ROOT, "a.txt").unwrap();
+        assert_eq!(tree.get(a), Some(&Inode::File { entry: 0, size: 3 }));
+
+        let sub = tree.lookup(ROOT, "sub").unwrap();
+        let deep = tree.lookup(sub, "deep").unwrap();
+        let c = tree.lookup(deep, "c").unwrap();
+        assert_eq!(tree.get(c), Some(&Inode::File { entry: 2, size: 7 }));
+        assert!(matches!(tree.get(deep), Some(Inode::Dir { parent, .. }) if *parent == sub));
+
+        assert_eq!(tree.lookup(ROOT, "missing"), None);
+        assert_eq!(tree.lookup(a, "anything"), None);
+        assert!(tree.get(0).is_none());
+    }
+
+    #[test]
+    fn test_tree_single_file() {
+        let offer = FileOffer {
+            name: "big.bin".to_string(),
+            size: Some(1 << 40),
+            checksum: None,
+            kind: OfferKind::File,
+            label: None,
+            batch: false,
+        };
+        let tree = Tree::new(&offer).unwrap();
+        let file = tree.lookup(ROOT, "big.bin").unwrap();
+        assert_eq!(
+            tree.get(file),
+            Some(&Inode::File {
+                entry: 0,
+                size: 1 << 40
+            })
+        );
+
+        let stream = FileOffer {
+            size: None,
+            ..offer
+        };
+        assert!(Tree::new(&stream).is_err());
+    }
+
+    #[test]
+    fn test_block_cache_evicts_oldest() {
+        let mut cache = BlockCache::default();
+        for block in 0..=CACHED_BLOCKS as u64 {
+            cache.insert((0, block), Arc::new(vec![block as u8]));
+        }
+        assert!(cache.get((0, 0)).is_none());
+        assert!(cache.get((0, 1)).is_some());
+        assert!(cache.get((0, CACHED_BLOCKS as u64)).is_some());
+    }
+}
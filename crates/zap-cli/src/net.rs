@@ -0,0 +1,279 @@
+//! Connection options shared by send and receive, and reporting on
+//! connections that end up relayed or fail
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use console::style;
+use zap_core::{
+    ConnectionPath, Negotiated, PhaseTimings, RelayUrl, Tuning, ZapNode, ZapNodeBuilder,
+};
+
+use crate::config::Config;
+use crate::format_bytes;
+use crate::output::{symbol, warning};
+use crate::peers;
+
+/// The iroh relay the zap relay pointed us at when looking up a code
+static STEERED: OnceLock<RelayUrl> = OnceLock::new();
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct NetArgs {
+    /// Listen on this UDP port, e.g. one forwarded through your router
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Report how the peer was reached, with suggestions when the
+    /// connection is relayed or fails
+    #[arg(long)]
+    pub debug_connection: bool,
+
+    /// Show how long connecting, waiting, transferring and verifying took,
+    /// and the chunk size and window a sender settled on
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Use no relays or lookups, set by `zap send --ticket-only` and `zap
+    /// receive --direct`
+    #[arg(skip)]
+    pub offline: bool,
+
+    /// Start a node for this transfer even if `zap daemon` is running
+    #[arg(long)]
+    pub no_daemon: bool,
+
+    /// The kind of link to size the connection for
+    #[arg(long, value_enum, value_name = "LINK", default_value_t)]
+    pub net_profile: NetProfile,
+}
+
+/// The link a transfer goes over, for `--net-profile`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum NetProfile {
+    /// A fast local network, such as 10GbE
+    Lan,
+    /// An ordinary internet connection
+    #[default]
+    Wan,
+    /// Round trips of half a second or more, as over a satellite
+    Satellite,
+}
+
+impl NetProfile {
+    fn tuning(self) -> Option<Tuning> {
+        match self {
+            Self::Lan => Some(Tuning::lan()),
+            Self::Wan => None,
+            Self::Satellite => Some(Tuning::satellite()),
+        }
+    }
+}
+
+impl NetArgs {
+    /// Whether a running `zap daemon` can do the transfer, as nothing asked
+    /// for needs a node of our own
+    pub(crate) fn daemon(&self) -> bool {
+        !self.no_daemon
+            && self.port.is_none()
+            && !self.debug_connection
+            && !self.stats
+            && !self.offline
+            && self.net_profile == NetProfile::Wan
+    }
+
+    pub(crate) async fn node(&self) -> Result<ZapNode> {
+        let mut builder = configured();
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
+        if self.offline {
+            builder = builder.offline();
+        }
+        if let Some(tuning) = self.net_profile.tuning() {
+            builder = builder.tuning(tuning);
+        }
+        Ok(builder.build().await?)
+    }
+}
+
+/// Start a node, speaking the private protocol namespace from the config
+/// if one is set
+pub(crate) async fn start_node(port: Option<u16>) -> Result<ZapNode> {
+    let mut builder = configured();
+    if let Some(port) = port {
+        builder = builder.port(port);
+    }
+    Ok(builder.build().await?)
+}
+
+/// Start a node with this machine's saved key, so paired peers recognize it
+pub(crate) async fn start_paired_node() -> Result<ZapNode> {
+    let key = peers::secret_key()?;
+    Ok(configured().secret_key(key).build().await?)
+}
+
+/// Have nodes started from now on use the iroh relay at `url`, as
+/// suggested by a zap relay with relays in several regions
+pub(crate) fn steer(url: &str) {
+    match url.parse::<RelayUrl>() {
+        Ok(url) => {
+            let _ = STEERED.set(url);
+        }
+        Err(e) => tracing::debug!("ignoring relay hint {}: {}", url, e),
+    }
+}
+
+fn configured() -> ZapNodeBuilder {
+    let mut builder = ZapNode::builder();
+    if let Some(alpn) = Config::load().active().alpn.clone() {
+        builder = builder.alpn(alpn);
+    }
+    if let Some(url) = STEERED.get() {
+        builder = builder.relay(url.clone());
+    }
+    builder
+}
+
+/// Follows the path a transfer takes, to explain it at the end
+pub(crate) struct PathWatch {
+    debug: bool,
+    stats: bool,
+    path: Option<ConnectionPath>,
+    /// What the sender settled on after probing the link
+    negotiated: Option<Negotiated>,
+}
+
+impl PathWatch {
+    pub(crate) fn new(args: &NetArgs) -> Self {
+        Self {
+            debug: args.debug_connection,
+            stats: args.stats,
+            path: None,
+            negotiated: None,
+        }
+    }
+
+    /// Break down where the time went, with --stats
+    pub(crate) fn timings(&self, timings: &PhaseTimings) {
+        if !self.stats {
+            return;
+        }
+        eprintln!("\n{}", style("Phases").bold());
+        let phases = [
+            ("handshake", timings.handshake),
+            ("waiting", timings.waiting),
+            ("transfer", timings.transfer),
+            ("verification", timings.verification),
+        ];
+        for (phase, took) in phases {
+            eprintln!("  {:<14}{}", phase, format_duration(took));
+        }
+
+        let Some(negotiated) = self.negotiated else {
+            return;
+        };
+        eprintln!("\n{}", style("Negotiated").bold());
+        let settings = [
+            ("chunk size", format_bytes(negotiated.chunk_size as u64)),
+            ("ack window", format!("{} chunks", negotiated.ack_window)),
+            ("round trip", format_duration(negotiated.rtt)),
+            (
+                "link",
+                format!("{}/s", format_bytes(negotiated.bytes_per_sec)),
+            ),
+        ];
+        for (setting, value) in settings {
+            eprintln!("  {:<14}{}", setting, value);
+        }
+    }
+
+    /// Record the chunk size and window the sender settled on, describing
+    /// them if debugging
+    pub(crate) fn negotiate(&mut self, negotiated: Negotiated) -> Option<String> {
+        let line = self.debug.then(|| {
+            format!(
+                "{}",
+                style(format!(
+                    "Probe: {} chunks, {} ahead of acks ({} ms)",
+                    format_bytes(negotiated.chunk_size as u64),
+                    negotiated.ack_window,
+                    negotiated.rtt.as_millis()
+                ))
+                .dim()
+            )
+        });
+        self.negotiated = Some(negotiated);
+        line
+    }
+
+    /// Record a new path, describing it if debugging
+    pub(crate) fn update(&mut self, path: ConnectionPath) -> Option<String> {
+        let line = self.debug.then(|| {
+            let kind = if path.direct { "direct" } else { "relayed" };
+            format!(
+                "{}",
+                style(format!(
+                    "Path: {} via {} ({} ms)",
+                    kind,
+                    path.remote,
+                    path.rtt.as_millis()
+                ))
+                .dim()
+            )
+        });
+        self.path = Some(path);
+        line
+    }
+
+    /// Explain a relayed or failed connection: in full when debugging,
+    /// otherwise as a hint
+    pub(crate) fn report(&self, node: &ZapNode, failed: bool) {
+        let report = node.connection_report(self.path.clone());
+
+        if self.debug {
+            eprintln!("\n{}", style("Connection report").bold());
+            for line in report.to_string().lines() {
+                eprintln!("  {}", line);
+            }
+            for advice in report.advice() {
+                eprintln!("  {} {}", warning(symbol("→")), advice);
+            }
+        } else if report.is_degraded() {
+            let what = if failed {
+                "The connection failed"
+            } else {
+                "The transfer went through a relay, which can be slower"
+            };
+            eprintln!(
+                "{}",
+                style(format!(
+                    "{}; run again with --debug-connection to see why",
+                    what
+                ))
+                .dim()
+            );
+        }
+    }
+}
+
+pub(crate) fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{} ms", duration.as_millis())
+    } else {
+        format!("{:.1} s", duration.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_micros(1500)), "1 ms");
+        assert_eq!(format_duration(Duration::from_millis(999)), "999 ms");
+        assert_eq!(format_duration(Duration::from_millis(12_340)), "12.3 s");
+    }
+}
@@ -0,0 +1,325 @@
+//! How output looks: plain for screen readers, dumb terminals and CI logs,
+//! and colored by what it means rather than with fixed colors
+//!
+//! With `--plain`, or when `TERM=dumb` is set, colors are off, symbols are
+//! spelled out as words, and progress bars are replaced by a line of text
+//! every few seconds, printed only when something changed. Colors alone
+//! follow `--color` and `NO_COLOR`, and the theme picks them.
+
+use std::io::Write;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use console::{Color, StyledObject};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, WeakProgressBar};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::format_bytes;
+
+/// How often a plain progress line may be printed
+const REPORT_EVERY: Duration = Duration::from_secs(5);
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// The theme in the config, read when it's first needed
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// Use plain output if `flag` asks for it or the environment calls for it
+pub fn set_plain(flag: bool) {
+    let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+    let plain = flag || dumb;
+    PLAIN.store(plain, Ordering::Relaxed);
+    if plain {
+        set_colors(false);
+    }
+}
+
+/// When to color output, set with `--color`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// On a terminal, unless `NO_COLOR` is set or output is plain
+    #[default]
+    Auto,
+    /// Even into a pipe or a file
+    Always,
+    Never,
+}
+
+/// Color output as `choice` says; call after [`set_plain`]
+pub fn set_color(choice: ColorChoice) {
+    match choice {
+        ColorChoice::Always => set_colors(true),
+        ColorChoice::Never => set_colors(false),
+        ColorChoice::Auto => {
+            if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+                set_colors(false);
+            }
+        }
+    }
+}
+
+fn set_colors(enabled: bool) {
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+/// The colors output is drawn in, set with `theme` in the config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Theme {
+    #[default]
+    Default,
+    /// Blue for success and magenta for failure, which unlike green and
+    /// red can be told apart with the common kinds of color blindness
+    Colorblind,
+}
+
+/// What a piece of output means, which the theme gives a color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Success,
+    Warning,
+    Failure,
+    /// Names, codes and paths to pick out of a line
+    Accent,
+}
+
+impl Theme {
+    fn color(self, role: Role) -> Color {
+        match (self, role) {
+            (Theme::Default, Role::Success) => Color::Green,
+            (Theme::Colorblind, Role::Success) => Color::Blue,
+            (_, Role::Warning) => Color::Yellow,
+            (Theme::Default, Role::Failure) => Color::Red,
+            (Theme::Colorblind, Role::Failure) => Color::Magenta,
+            (_, Role::Accent) => Color::Cyan,
+        }
+    }
+
+    /// The color's name in an indicatif template
+    fn color_name(self, role: Role) -> &'static str {
+        match self.color(role) {
+            Color::Green => "green",
+            Color::Blue => "blue",
+            Color::Yellow => "yellow",
+            Color::Red => "red",
+            Color::Magenta => "magenta",
+            _ => "cyan",
+        }
+    }
+}
+
+fn theme() -> Theme {
+    *THEME.get_or_init(|| Config::load().theme)
+}
+
+fn paint<D>(role: Role, value: D) -> StyledObject<D> {
+    console::style(value).fg(theme().color(role))
+}
+
+/// Something that worked, like a finished transfer
+pub(crate) fn success<D>(value: D) -> StyledObject<D> {
+    paint(Role::Success, value)
+}
+
+/// Something to look out for that didn't stop anything
+pub(crate) fn warning<D>(value: D) -> StyledObject<D> {
+    paint(Role::Warning, value)
+}
+
+/// Something that failed
+pub(crate) fn failure<D>(value: D) -> StyledObject<D> {
+    paint(Role::Failure, value)
+}
+
+/// A name, code or path to pick out of a line
+pub(crate) fn accent<D>(value: D) -> StyledObject<D> {
+    paint(Role::Accent, value)
+}
+
+/// A progress bar template with `success` and `accent` as styles, such as
+/// `{spinner:.success}`, swapped for the theme's colors
+pub(crate) fn template(template: &str) -> String {
+    themed_template(theme(), template)
+}
+
+/// Progress bar for transfers of known size, led by a spinner, or when
+/// `prefixed` by the line's prefix, with a narrower bar to make room for it
+/// among other transfers
+pub(crate) fn bar_style(prefixed: bool) -> ProgressStyle {
+    let layout = if prefixed {
+        "{prefix:.bold} [{bar:30.accent/blue}] {bytes}/{total_bytes} ({eta}) {msg}"
+    } else {
+        "{spinner:.success} [{bar:40.accent/blue}] {bytes}/{total_bytes} ({eta}) {msg}"
+    };
+    ProgressStyle::default_bar()
+        .template(&template(layout))
+        .unwrap()
+        .progress_chars("=>-")
+}
+
+/// A line of several that isn't transferring yet, or is finished
+pub(crate) fn status_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{prefix:.bold} {msg}")
+        .unwrap()
+}
+
+fn themed_template(theme: Theme, template: &str) -> String {
+    template
+        .replace(".success", &format!(".{}", theme.color_name(Role::Success)))
+        .replace(".accent", &format!(".{}", theme.color_name(Role::Accent)))
+}
+
+pub(crate) fn is_plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+/// A symbol, or in plain output a word a screen reader can say instead
+pub(crate) fn symbol(symbol: &'static str) -> &'static str {
+    if !is_plain() {
+        return symbol;
+    }
+    match symbol {
+        "✓" => "ok:",
+        "⚠" => "warning:",
+        "✗" => "error:",
+        "→" => "-",
+        _ => "zap:",
+    }
+}
+
+/// A progress bar, which in plain output is never drawn; its progress is
+/// printed as lines instead
+pub(crate) fn bar(len: u64) -> ProgressBar {
+    if !is_plain() {
+        return ProgressBar::new(len);
+    }
+    let pb = ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden());
+    report(pb.downgrade());
+    pb
+}
+
+/// Bars drawn together, which in plain output aren't drawn at all
+pub(crate) fn multi() -> MultiProgress {
+    if is_plain() {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    }
+}
+
+/// Print a line above a bar, or on its own in plain output, where the bar
+/// isn't drawn
+pub(crate) fn println(pb: &ProgressBar, line: String) {
+    if is_plain() {
+        print_line(&line);
+    } else if is_raw() {
+        pb.suspend(|| print_line(&line));
+    } else {
+        pb.println(line);
+    }
+}
+
+/// Print a line on its own, ending it with `\r\n` while
+/// [`KeyControls`](crate::keys::KeyControls) has the terminal in raw mode,
+/// where `\n` alone doesn't go back to the start of the line
+pub(crate) fn print_line(line: &str) {
+    if is_raw() {
+        print!("{}\r\n", line);
+        let _ = std::io::stdout().flush();
+    } else {
+        println!("{}", line);
+    }
+}
+
+fn is_raw() -> bool {
+    crossterm::terminal::is_raw_mode_enabled().unwrap_or(false)
+}
+
+/// [`println`] for bars drawn together
+pub(crate) fn println_multi(multi: &MultiProgress, line: String) -> std::io::Result<()> {
+    if is_plain() {
+        println!("{}", line);
+        Ok(())
+    } else {
+        multi.println(line)
+    }
+}
+
+/// Print a bar's progress now and then, until it's finished or dropped
+fn report(pb: WeakProgressBar) {
+    std::thread::spawn(move || {
+        let mut last = String::new();
+        loop {
+            std::thread::sleep(REPORT_EVERY);
+            let Some(pb) = pb.upgrade() else {
+                return;
+            };
+            let line = progress_line(&pb.prefix(), pb.position(), pb.length(), &pb.message());
+            if !line.is_empty() && line != last {
+                if is_raw() {
+                    eprint!("{}\r\n", line);
+                } else {
+                    eprintln!("{}", line);
+                }
+                last = line;
+            }
+            if pb.is_finished() {
+                return;
+            }
+        }
+    });
+}
+
+/// A bar as a line of text: its prefix, how far along it is, and its
+/// message
+fn progress_line(prefix: &str, position: u64, length: Option<u64>, message: &str) -> String {
+    let progress = match length {
+        Some(length) if length > 0 => format!("{}%", position.min(length) * 100 / length),
+        _ if position > 0 => format_bytes(position),
+        _ => String::new(),
+    };
+    [prefix, &progress, message]
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_themed_template() {
+        let template = "{spinner:.success} [{bar:40.accent/blue}] {bytes}";
+        assert_eq!(
+            themed_template(Theme::Default, template),
+            "{spinner:.green} [{bar:40.cyan/blue}] {bytes}"
+        );
+        assert_eq!(
+            themed_template(Theme::Colorblind, template),
+            "{spinner:.blue} [{bar:40.cyan/blue}] {bytes}"
+        );
+        assert_ne!(
+            Theme::Colorblind.color(Role::Success),
+            Theme::Colorblind.color(Role::Failure)
+        );
+    }
+
+    #[test]
+    fn test_progress_line() {
+        assert_eq!(progress_line("", 250, Some(1000), ""), "25%");
+        assert_eq!(
+            progress_line("[1/2] a.txt", 1000, Some(1000), "done"),
+            "[1/2] a.txt 100% done"
+        );
+        // Streams don't have a length
+        assert_eq!(progress_line("", 2048, Some(0), ""), "2.00 KB");
+        assert_eq!(progress_line("", 0, None, "waiting"), "waiting");
+        assert_eq!(progress_line("", 0, None, ""), "");
+    }
+}
@@ -0,0 +1,173 @@
+//! Pairing for `zap cp` and `zap listen`: this machine's lasting key, and
+//! the peers it trusts, kept by name in the `[peers]` table of the config
+//!
+//! Other commands use a fresh key every time, so they can't be told apart
+//! from run to run; a listener needs to know who's asking.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, anyhow, bail};
+use console::style;
+use zap_core::{PublicKey, SecretKey};
+
+use crate::config::Config;
+use crate::init::fingerprint;
+use crate::output::{accent, success, symbol};
+
+/// Where a profile's key is kept: `secret.key` for the default settings,
+/// `profiles/<name>.key` for a named profile
+fn key_path(profile: Option<&str>) -> Option<PathBuf> {
+    let dir = dirs::config_dir()?.join("zap");
+    Some(match profile {
+        Some(name) => dir.join("profiles").join(format!("{}.key", name)),
+        None => dir.join("secret.key"),
+    })
+}
+
+/// This machine's secret key in the profile in use, created on first use
+pub(crate) fn secret_key() -> Result<SecretKey> {
+    let path =
+        key_path(Config::load().profile_name()).context("no config directory on this system")?;
+    match std::fs::read(&path) {
+        Ok(bytes) => {
+            let bytes: [u8; 32] = bytes
+                .try_into()
+                .map_err(|_| anyhow!("{} is not a zap key", path.display()))?;
+            Ok(SecretKey::from_bytes(&bytes))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = SecretKey::generate(&mut rand::rng());
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options
+                .open(&path)
+                .and_then(|mut file| file.write_all(&key.to_bytes()))
+                .with_context(|| format!("writing {}", path.display()))?;
+            Ok(key)
+        }
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// The node ids of every paired peer
+pub(crate) fn paired(peers: &BTreeMap<String, String>) -> Result<Vec<PublicKey>> {
+    peers.keys().map(|name| peer_id(peers, name)).collect()
+}
+
+/// A paired peer's node id, by name
+pub(crate) fn peer_id(peers: &BTreeMap<String, String>, name: &str) -> Result<PublicKey> {
+    let id = peers
+        .get(name)
+        .with_context(|| format!("no peer named {} is paired", name))?;
+    id.parse()
+        .map_err(|e| anyhow!("peer {} has an invalid node id in the config: {}", name, e))
+}
+
+/// The name a peer was paired under, or its short id
+pub(crate) fn peer_name(peers: &BTreeMap<String, String>, id: &PublicKey) -> String {
+    let id = id.to_string();
+    peers
+        .iter()
+        .find(|(_, paired)| **paired == id)
+        .map(|(name, _)| name.clone())
+        .unwrap_or_else(|| id[..8.min(id.len())].to_string())
+}
+
+/// `zap pair`: show this machine's id and its peers, pair with a peer, or
+/// forget one
+pub async fn run_pair(
+    name: Option<String>,
+    id: Option<String>,
+    remove: Option<String>,
+) -> Result<()> {
+    let mut config = Config::load();
+
+    if let Some(name) = remove {
+        if config.active_mut().peers.remove(&name).is_none() {
+            bail!("no peer named {} is paired", name);
+        }
+        config.save()?;
+        println!("{} Forgot {}", success(symbol("✓")).bold(), accent(&name));
+        return Ok(());
+    }
+
+    if let (Some(name), Some(id)) = (name, id) {
+        validate_name(&name)?;
+        let id: PublicKey = id
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("invalid node id: {}", e))?;
+        config
+            .active_mut()
+            .peers
+            .insert(name.clone(), id.to_string());
+        config.save()?;
+        println!(
+            "{} Paired with {} ({})",
+            success(symbol("✓")).bold(),
+            accent(&name),
+            fingerprint(&id.to_string())
+        );
+        println!(
+            "  {}",
+            style(format!(
+                "They pair back with: zap pair <name> {}",
+                secret_key()?.public()
+            ))
+            .dim()
+        );
+        return Ok(());
+    }
+
+    let id = secret_key()?.public().to_string();
+    println!("This machine: {}", success(&id));
+    println!(
+        "  {}",
+        style(format!("Fingerprint: {}", fingerprint(&id))).dim()
+    );
+    let peers = &config.active().peers;
+    if peers.is_empty() {
+        println!(
+            "{}",
+            style("No paired peers; add one with: zap pair <name> <node id>").dim()
+        );
+    }
+    for (name, id) in peers {
+        println!("  {} {}", accent(name), style(id).dim());
+    }
+    Ok(())
+}
+
+/// Names are used as `name:path` in `zap cp`, so they can't contain a
+/// colon or a path separator
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains([':', '/', '\\']) || name.chars().any(char::is_whitespace) {
+        bail!(
+            "invalid peer name {:?}: use letters, digits and dashes, e.g. laptop",
+            name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("laptop").is_ok());
+        assert!(validate_name("build-box-2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("a:b").is_err());
+        assert!(validate_name("a/b").is_err());
+        assert!(validate_name("my laptop").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::io::{IsTerminal, Read};
+use std::path::{MAIN_SEPARATOR, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use dialoguer::{
+    Completion, Confirm, FuzzySelect, Input, MultiSelect, Select, theme::ColorfulTheme,
+};
+use zap_core::directory::walk;
+use zap_core::protocol::DirEntry;
+
+use crate::config::Config;
+use crate::format_bytes;
+use crate::output::{accent, symbol};
+
+/// A row in the file browser
+///
+/// Labels are plain text since the fuzzy filter matches against them.
+enum Item {
+    Parent,
+    SendThisFolder,
+    SelectSeveral,
+    Dir(PathBuf),
+    File(PathBuf, u64),
+}
+
+impl Item {
+    fn label(&self) -> String {
+        match self {
+            Item::Parent => "../".to_string(),
+            Item::SendThisFolder => "[send this folder]".to_string(),
+            Item::SelectSeveral => "[select several...]".to_string(),
+            Item::Dir(path) => format!("{}/", file_name(path)),
+            Item::File(path, size) => format!("{}  ({})", file_name(path), format_bytes(*size)),
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Interactive file/folder selection, which may pick several from one folder
+pub(crate) fn select_file_interactive() -> Result<Vec<PathBuf>> {
+    println!("\n{} What would you like to send?", accent(symbol("⚡")));
+
+    let options = vec!["Browse files", "Enter path manually"];
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .items(&options)
+        .default(0)
+        .interact()?;
+
+    match selection {
+        0 => {
+            let mut config = Config::load();
+            let start = match config.last_dir.take() {
+                Some(dir) if dir.is_dir() => dir,
+                _ => std::env::current_dir()?,
+            };
+
+            let (paths, dir) = browse(start)?;
+
+            config.last_dir = Some(dir);
+            if let Err(e) = config.save() {
+                tracing::debug!("Failed to save config: {}", e);
+            }
+            Ok(paths)
+        }
+        _ => {
+            let input: String = Input::with_theme(&ColorfulTheme::default())
+                .with_prompt("Enter path (tab completes)")
+                .completion_with(&PathCompletion)
+                .interact_text()?;
+
+            let path = PathBuf::from(shellexpand::tilde(&input).to_string());
+            Ok(vec![path])
+        }
+    }
+}
+
+/// Tab completion of paths typed by hand, for when there's no room for the
+/// browser, e.g. over a restricted SSH session
+struct PathCompletion;
+
+impl Completion for PathCompletion {
+    /// The input with its last component completed as far as the names it
+    /// could be agree, and a separator added once it's a whole folder
+    fn get(&self, input: &str) -> Option<String> {
+        let split = input.rfind(['/', MAIN_SEPARATOR]).map_or(0, |i| i + 1);
+        let (dir, prefix) = input.split_at(split);
+        let search = match dir {
+            "" => PathBuf::from("."),
+            dir => PathBuf::from(shellexpand::tilde(dir).as_ref()),
+        };
+
+        let mut matches: Vec<(String, bool)> = std::fs::read_dir(search)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                // Hidden files only when asked for with a leading dot
+                if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.'))
+                {
+                    return None;
+                }
+                Some((name, entry.path().is_dir()))
+            })
+            .collect();
+        matches.sort();
+
+        let completed = match matches.as_slice() {
+            [] => return None,
+            [(name, true)] => format!("{}{}", name, MAIN_SEPARATOR),
+            [(name, false)] => name.clone(),
+            [(first, _), .., (last, _)] => common_prefix(first, last).to_string(),
+        };
+        Some(format!("{}{}", dir, completed))
+    }
+}
+
+/// The longest start `a` and `b` share, which for the first and last of
+/// sorted names is the start they all share
+fn common_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let len = a
+        .char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or(a.len().min(b.len()), |((i, _), _)| i);
+    &a[..len]
+}
+
+/// `paths` followed by those listed in `list`, one per line, for sending
+/// in batches without a terminal; `-` reads the list from stdin
+///
+/// Blank lines and lines starting with `#` are skipped, and `~` is
+/// expanded.
+pub fn with_path_list(mut paths: Vec<PathBuf>, list: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let Some(list) = list else {
+        return Ok(paths);
+    };
+    let text = if list == Path::new("-") {
+        let mut text = String::new();
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .context("reading paths from stdin")?;
+        text
+    } else {
+        std::fs::read_to_string(list).with_context(|| format!("reading {}", list.display()))?
+    };
+    paths.extend(parse_path_list(&text));
+    if paths.is_empty() {
+        anyhow::bail!("{} lists no paths", list.display());
+    }
+    Ok(paths)
+}
+
+fn parse_path_list(text: &str) -> impl Iterator<Item = PathBuf> + '_ {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| PathBuf::from(shellexpand::tilde(line).as_ref()))
+}
+
+/// Walk the file system from `dir` until files or folders are picked
+///
+/// Returns the picks and the directory they were picked from.
+fn browse(mut dir: PathBuf) -> Result<(Vec<PathBuf>, PathBuf)> {
+    loop {
+        let items = list_dir(&dir)?;
+        let labels: Vec<String> = items.iter().map(Item::label).collect();
+
+        let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("{} (type to filter)", dir.display()))
+            .items(&labels)
+            .default(0)
+            .interact()?;
+
+        match &items[selection] {
+            Item::Parent => {
+                if let Some(parent) = dir.parent() {
+                    dir = parent.to_path_buf();
+                }
+            }
+            Item::SendThisFolder => {
+                let parent = dir.parent().map(Path::to_path_buf).unwrap_or(dir.clone());
+                return Ok((vec![dir], parent));
+            }
+            Item::SelectSeveral => {
+                if let Some(paths) = select_several(&items)? {
+                    return Ok((paths, dir));
+                }
+            }
+            Item::Dir(path) => dir = path.clone(),
+            Item::File(path, _) => return Ok((vec![path.clone()], dir)),
+        }
+    }
+}
+
+/// Pick several entries of the current folder and confirm the total size
+///
+/// Returns `None` to go back to browsing.
+fn select_several(items: &[Item]) -> Result<Option<Vec<PathBuf>>> {
+    let entries: Vec<&Item> = items
+        .iter()
+        .filter(|item| matches!(item, Item::Dir(_) | Item::File(..)))
+        .collect();
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    let labels: Vec<String> = entries.iter().map(|item| item.label()).collect();
+    let chosen = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Space to select, enter to confirm")
+        .items(&labels)
+        .interact()?;
+    if chosen.is_empty() {
+        return Ok(None);
+    }
+
+    let mut paths = Vec::with_capacity(chosen.len());
+    let mut total = 0;
+    for i in chosen {
+        match entries[i] {
+            Item::Dir(path) => {
+                total += walk(path)?.iter().map(|e| e.size).sum::<u64>();
+                paths.push(path.clone());
+            }
+            Item::File(path, size) => {
+                total += size;
+                paths.push(path.clone());
+            }
+            _ => {}
+        }
+    }
+
+    let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!(
+            "Send {} items ({})?",
+            paths.len(),
+            format_bytes(total)
+        ))
+        .default(true)
+        .interact()?;
+
+    Ok(confirmed.then_some(paths))
+}
+
+/// Pick which files of an offered folder to receive, all checked at first
+///
+/// Returns `None` to receive everything when there's no terminal to ask on.
+pub(crate) fn choose_entries(entries: &[DirEntry]) -> Option<Vec<usize>> {
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+
+    let labels: Vec<String> = entries
+        .iter()
+        .map(|e| format!("{}  ({})", e.path, format_bytes(e.size)))
+        .collect();
+    let checked = vec![true; entries.len()];
+    let chosen = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Space to toggle, enter to receive")
+        .items(&labels)
+        .defaults(&checked)
+        .interact()
+        .ok()?;
+    Some(chosen)
+}
+
+/// Entries of `dir`, folders first, each group sorted by name
+fn list_dir(dir: &Path) -> Result<Vec<Item>> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+
+    for entry in std::fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        // Follow symlinks so linked folders can be browsed into
+        match std::fs::metadata(&path) {
+            Ok(meta) if meta.is_dir() => dirs.push(path),
+            Ok(meta) => files.push((path, meta.len())),
+            Err(_) => {}
+        }
+    }
+    dirs.sort();
+    files.sort();
+
+    let mut items = Vec::new();
+    if dir.parent().is_some() {
+        items.push(Item::Parent);
+    }
+    items.push(Item::SendThisFolder);
+    if !dirs.is_empty() || !files.is_empty() {
+        items.push(Item::SelectSeveral);
+    }
+    items.extend(dirs.into_iter().map(Item::Dir));
+    items.extend(files.into_iter().map(|(path, size)| Item::File(path, size)));
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_completion() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("photos")).unwrap();
+        std::fs::write(dir.path().join("report-2024.pdf"), b"").unwrap();
+        std::fs::write(dir.path().join("report-2025.pdf"), b"").unwrap();
+        std::fs::write(dir.path().join(".secret"), b"").unwrap();
+        let base = format!("{}{}", dir.path().display(), MAIN_SEPARATOR);
+        let complete = |input: &str| PathCompletion.get(&format!("{}{}", base, input));
+
+        assert_eq!(
+            complete("ph"),
+            Some(format!("{}photos{}", base, MAIN_SEPARATOR))
+        );
+        assert_eq!(complete("re"), Some(format!("{}report-202", base)));
+        assert_eq!(
+            complete("report-2025"),
+            Some(format!("{}report-2025.pdf", base))
+        );
+        assert_eq!(complete("x"), None);
+        assert_eq!(complete(".s"), Some(format!("{}.secret", base)));
+    }
+
+    #[test]
+    fn test_parse_path_list() {
+        let paths: Vec<PathBuf> =
+            parse_path_list("a.txt\n\n  # skipped\n  dir/b.txt  \n").collect();
+        assert_eq!(paths, [PathBuf::from("a.txt"), PathBuf::from("dir/b.txt")]);
+    }
+}
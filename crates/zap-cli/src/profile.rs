@@ -0,0 +1,153 @@
+//! `zap profile`: named sets of settings, e.g. a work relay, identity and
+//! peers kept apart from personal ones
+
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use clap::Subcommand;
+use console::style;
+
+use crate::config::{Config, DEFAULT_PROFILE, Profile};
+use crate::output::{accent, success, symbol};
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileAction {
+    /// List profiles, marking the one in use
+    List,
+
+    /// Create a profile
+    Create {
+        /// Name to select it by, e.g. work
+        name: String,
+
+        /// Relay server for short codes
+        #[arg(long)]
+        relay: Option<String>,
+
+        /// Private protocol namespace, e.g. zap/acme/1
+        #[arg(long)]
+        alpn: Option<String>,
+
+        /// PEM certificate for relays that require one
+        #[arg(long, value_name = "PATH", requires = "client_key")]
+        client_cert: Option<PathBuf>,
+
+        /// PEM private key for --client-cert
+        #[arg(long, value_name = "PATH", requires = "client_cert")]
+        client_key: Option<PathBuf>,
+    },
+
+    /// Use a profile when --profile isn't given; `default` goes back to the
+    /// top-level settings
+    Use {
+        /// Profile name
+        name: String,
+    },
+}
+
+/// List, create or select profiles
+pub async fn run_profile(action: ProfileAction) -> Result<()> {
+    let mut config = Config::load();
+    match action {
+        ProfileAction::List => {
+            let active = config.profile_name().unwrap_or(DEFAULT_PROFILE);
+            let rows = std::iter::once((DEFAULT_PROFILE, &config.default))
+                .chain(config.profiles.iter().map(|(n, p)| (n.as_str(), p)));
+            for (name, profile) in rows {
+                let marker = if name == active { "*" } else { " " };
+                println!(
+                    "{} {} {}",
+                    marker,
+                    accent(name),
+                    style(profile.relay.as_deref().unwrap_or(crate::DEFAULT_RELAY)).dim()
+                );
+            }
+        }
+        ProfileAction::Create {
+            name,
+            relay,
+            alpn,
+            client_cert,
+            client_key,
+        } => {
+            validate_name(&name)?;
+            if config.profiles.contains_key(&name) {
+                bail!("profile {} already exists", name);
+            }
+            // Kept working from whatever directory zap runs in later
+            let absolute = |path: Option<PathBuf>| path.map(std::path::absolute).transpose();
+            let profile = Profile {
+                relay,
+                alpn,
+                client_cert: absolute(client_cert)?,
+                client_key: absolute(client_key)?,
+                ..Profile::default()
+            };
+            config.profiles.insert(name.clone(), profile);
+            config.save()?;
+            println!(
+                "{} Created profile {}",
+                success(symbol("✓")).bold(),
+                accent(&name)
+            );
+            println!(
+                "  {}",
+                style(format!(
+                    "Use it with --profile {} or zap profile use {}",
+                    name, name
+                ))
+                .dim()
+            );
+        }
+        ProfileAction::Use { name } => {
+            if name == DEFAULT_PROFILE {
+                config.profile = None;
+            } else if config.profiles.contains_key(&name) {
+                config.profile = Some(name.clone());
+            } else {
+                bail!("no profile named {}; see zap profile list", name);
+            }
+            config.save()?;
+            println!(
+                "{} Using profile {}",
+                success(symbol("✓")).bold(),
+                accent(&name)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Names end up in key file names, so only letters, digits, `-` and `_`;
+/// `default` is taken by the top-level settings
+fn validate_name(name: &str) -> Result<()> {
+    if name == DEFAULT_PROFILE {
+        bail!("{} is the top-level settings; pick another name", name);
+    }
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "invalid profile name {:?}: use letters, digits, dashes and underscores",
+            name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("work").is_ok());
+        assert!(validate_name("client_2").is_ok());
+        assert!(validate_name("default").is_err());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("../etc").is_err());
+        assert!(validate_name("my work").is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! `zap receive --qr-image`: reading the code from a picture of it
+//!
+//! Codes often arrive as a screenshot or a photo of a screen rather than as
+//! text. Whatever the QR code holds, a code, a ticket or a link to the web
+//! page, is used as if it had been pasted.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// The text of the first QR code that can be read in the image at `path`
+pub(crate) fn read_code(path: &Path) -> Result<String> {
+    let image = image::open(path)
+        .with_context(|| format!("Could not read the image {}", path.display()))?
+        .to_luma8();
+    decode(image).with_context(|| format!("No readable QR code in {}", path.display()))
+}
+
+fn decode(image: image::GrayImage) -> Result<String> {
+    let mut prepared = rqrr::PreparedImage::prepare(image);
+    // A photo can catch other QR codes around the one that matters, so
+    // the first one that reads as something wins
+    let mut last_error = None;
+    for grid in prepared.detect_grids() {
+        match grid.decode() {
+            Ok((_, content)) if !content.trim().is_empty() => {
+                return Ok(content.trim().to_string());
+            }
+            Ok(_) => {}
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => anyhow::bail!("the QR code couldn't be decoded: {}", e),
+        None => anyhow::bail!("no QR code found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let link = "https://zapper.cloud/?code=abc234";
+        let code = qrcode::QrCode::new(link).unwrap();
+        let image = code.render::<image::Luma<u8>>().build();
+        assert_eq!(decode(image).unwrap(), link);
+
+        let blank = image::GrayImage::from_pixel(100, 100, image::Luma([255]));
+        assert!(decode(blank).is_err());
+    }
+}
@@ -0,0 +1,493 @@
+//! `zap send --queue`: one code per file, served a few at a time
+//!
+//! Urgent files are queued ahead of the rest, and while one is being sent
+//! every other transfer pauses, so a small file isn't stuck behind a bulk
+//! one. With a per-receiver limit, a receiver that connects while it
+//! already has that many transfers running is held paused until one ends.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use console::style;
+use indicatif::{MultiProgress, ProgressBar};
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore, watch};
+use tokio::task::JoinSet;
+use zap_core::{ArchivePolicy, PublicKey, SendOptions, SendProgress, TransferHandle};
+
+use crate::ctl::{self, Requested};
+use crate::hooks::{self, HookFields, run_hook};
+use crate::net::start_node;
+use crate::output::{self, accent, failure, success, symbol, warning};
+use crate::register_ticket;
+
+/// Limits and priorities for `zap send --queue`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueOptions {
+    /// How many files are served at once
+    pub concurrency: usize,
+    /// How many files one receiver can get at once
+    pub per_peer: Option<usize>,
+    /// Files sent before the others, which pause while these are sending
+    pub urgent: Vec<PathBuf>,
+}
+
+/// How soon a queued file is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    Urgent,
+    Normal,
+}
+
+/// Send each path under its own code, at most `options.concurrency` at a
+/// time, urgent paths first
+///
+/// A code is only handed out once its file is being served, so receivers
+/// never wait on a sender that isn't listening yet.
+pub(crate) async fn run_queue(
+    paths: Vec<PathBuf>,
+    options: QueueOptions,
+    no_relay: bool,
+    relay: String,
+    auto_archive: Option<usize>,
+    label: Option<String>,
+    on_sent: Option<String>,
+) -> Result<()> {
+    let queued = queue_order(paths, &options.urgent);
+    for (path, _) in &queued {
+        if path.as_os_str() == "-" {
+            anyhow::bail!("stdin can't be sent with --queue");
+        }
+        if !path.exists() {
+            anyhow::bail!("Path does not exist: {}", path.display());
+        }
+    }
+
+    let mut limits = format!("{} at a time", options.concurrency);
+    if let Some(per_peer) = options.per_peer {
+        limits.push_str(&format!(", {} per receiver", per_peer));
+    }
+    if !options.urgent.is_empty() {
+        limits.push_str(&format!(", {} urgent", options.urgent.len()));
+    }
+    println!(
+        "\n{} Queued {} transfers, {}",
+        accent(symbol("⚡")),
+        queued.len(),
+        limits
+    );
+
+    let multi = output::multi();
+    let slots = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    let scheduler = Arc::new(Scheduler::new(options.per_peer));
+    let total = queued.len();
+    let mut tasks = JoinSet::new();
+
+    for (i, (path, priority)) in queued.into_iter().enumerate() {
+        let name = path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "file".to_string());
+
+        let pb = multi.add(output::bar(0));
+        pb.set_style(output::status_style());
+        pb.set_prefix(format!("[{}/{}] {}", i + 1, total, name));
+        pb.set_message(format!("{}", style("queued").dim()));
+
+        let item = QueueItem {
+            path,
+            name,
+            priority,
+            scheduler: scheduler.clone(),
+            pb,
+            multi: multi.clone(),
+            no_relay,
+            relay: relay.clone(),
+            auto_archive,
+            label: label.clone(),
+            on_sent: on_sent.clone(),
+        };
+        let slots = slots.clone();
+        tasks.spawn(async move {
+            let _permit = slots.acquire_owned().await?;
+            let result = item.serve().await;
+            if let Err(e) = &result {
+                item.pb.set_style(output::status_style());
+                item.pb
+                    .abandon_with_message(format!("{}", failure(format!("failed: {}", e))));
+            }
+            result
+        });
+    }
+
+    let mut failed = 0;
+    while let Some(result) = tasks.join_next().await {
+        if !matches!(result, Ok(Ok(()))) {
+            failed += 1;
+        }
+    }
+
+    println!();
+    if failed > 0 {
+        anyhow::bail!("{} of {} transfers failed", failed, total);
+    }
+    println!(
+        "{} All {} transfers complete!",
+        success(symbol("✓")).bold(),
+        total
+    );
+    Ok(())
+}
+
+/// The files in the order slots are handed out: urgent ones first, then
+/// the rest, each in the order given
+fn queue_order(paths: Vec<PathBuf>, urgent: &[PathBuf]) -> Vec<(PathBuf, Priority)> {
+    (urgent.iter().map(|p| (p.clone(), Priority::Urgent)))
+        .chain(paths.into_iter().map(|p| (p, Priority::Normal)))
+        .collect()
+}
+
+/// Why a transfer should be paused, if it should: `zap ctl` asked,
+/// `peer_wait` it's waiting for a slot with its receiver, or `urgent`
+/// files are being sent and it isn't one of them
+fn hold_reason(
+    requested: Requested,
+    peer_wait: bool,
+    urgent: usize,
+    priority: Priority,
+) -> Option<&'static str> {
+    if requested == Requested::Pause {
+        Some("paused with zap ctl")
+    } else if peer_wait {
+        Some("waiting for another transfer to this receiver")
+    } else if urgent > 0 && priority == Priority::Normal {
+        Some("paused for urgent files")
+    } else {
+        None
+    }
+}
+
+/// What the queue's transfers share beyond the global slots
+struct Scheduler {
+    /// How many urgent files are being sent; other transfers pause while
+    /// there are any
+    urgent: watch::Sender<usize>,
+    /// Transfers each receiver may still start, when limited
+    per_peer: Option<usize>,
+    peers: Mutex<HashMap<PublicKey, Arc<Semaphore>>>,
+}
+
+impl Scheduler {
+    fn new(per_peer: Option<usize>) -> Self {
+        Self {
+            urgent: watch::channel(0).0,
+            per_peer,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The slots of one receiver, if they're limited
+    fn peer_slots(&self, peer: PublicKey) -> Option<Arc<Semaphore>> {
+        let limit = self.per_peer?;
+        let mut peers = self.peers.lock().unwrap();
+        Some(
+            peers
+                .entry(peer)
+                .or_insert_with(|| Arc::new(Semaphore::new(limit.max(1))))
+                .clone(),
+        )
+    }
+}
+
+/// Counts an urgent file as being sent until dropped
+struct UrgentSend(Arc<Scheduler>);
+
+impl UrgentSend {
+    fn start(scheduler: &Arc<Scheduler>) -> Self {
+        scheduler.urgent.send_modify(|n| *n += 1);
+        Self(scheduler.clone())
+    }
+}
+
+impl Drop for UrgentSend {
+    fn drop(&mut self) {
+        self.0.urgent.send_modify(|n| *n -= 1);
+    }
+}
+
+type PeerWait = Pin<Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send>>;
+
+/// A file waiting in the queue and its line in the overview
+struct QueueItem {
+    path: PathBuf,
+    name: String,
+    priority: Priority,
+    scheduler: Arc<Scheduler>,
+    pb: ProgressBar,
+    multi: MultiProgress,
+    no_relay: bool,
+    relay: String,
+    auto_archive: Option<usize>,
+    label: Option<String>,
+    on_sent: Option<String>,
+}
+
+impl QueueItem {
+    /// Offer the file under a fresh code and wait until it's received
+    async fn serve(&self) -> Result<()> {
+        // Every node has its own address, so each file gets its own ticket
+        let node = start_node(None).await?;
+        let handle = TransferHandle::new();
+        let options = SendOptions {
+            auto_archive: self.auto_archive.map(|max_files| ArchivePolicy {
+                max_files,
+                ..Default::default()
+            }),
+            handle: Some(handle.clone()),
+            label: self.label.clone(),
+            transform: hooks::transform(hooks::conversions()),
+            ..Default::default()
+        };
+        let (ticket, mut progress_rx) = node.send_with_options(&self.path, options).await?;
+
+        let code = if self.no_relay {
+            ticket.to_string()
+        } else {
+            let registered = register_ticket(
+                &self.relay,
+                &ticket.to_string(),
+                Some(&self.name),
+                None,
+                None,
+            )
+            .await;
+            match registered {
+                Ok(info) => info.code,
+                Err(e) => {
+                    output::println_multi(
+                        &self.multi,
+                        format!(
+                            "{} Could not register {} with relay: {}",
+                            warning(symbol("⚠")),
+                            self.name,
+                            e
+                        ),
+                    )?;
+                    ticket.to_string()
+                }
+            }
+        };
+        output::println_multi(
+            &self.multi,
+            format!("  {}  {}", accent(&self.name), success(&code).bold()),
+        )?;
+        self.pb
+            .set_message(format!("{}", style("waiting for receiver").dim()));
+        let mut ctl = ctl::register_scheduled(&self.name, Some(&code), &handle);
+
+        let mut urgent = self.scheduler.urgent.subscribe();
+        let mut peer_wait: Option<PeerWait> = None;
+        let mut _peer_slot = None;
+        let mut _urgent_send = None;
+        let mut connected = false;
+        loop {
+            let progress = tokio::select! {
+                progress = progress_rx.recv() => progress,
+                permit = async { peer_wait.as_mut().unwrap().await }, if peer_wait.is_some() => {
+                    peer_wait = None;
+                    _peer_slot = Some(permit?);
+                    if self.priority == Priority::Urgent {
+                        _urgent_send = Some(UrgentSend::start(&self.scheduler));
+                    }
+                    self.hold(&handle, &ctl, connected, false, *urgent.borrow());
+                    continue;
+                }
+                Ok(()) = urgent.changed() => {
+                    self.hold(&handle, &ctl, connected, peer_wait.is_some(), *urgent.borrow());
+                    continue;
+                }
+                requested = ctl.changed() => {
+                    // Once connected, the transfer itself reports the cancel
+                    if requested == Requested::Cancel && !connected {
+                        anyhow::bail!("cancelled");
+                    }
+                    self.hold(&handle, &ctl, connected, peer_wait.is_some(), *urgent.borrow());
+                    continue;
+                }
+            };
+            let Some(progress) = progress else {
+                break;
+            };
+            match progress {
+                SendProgress::Connected { peer } => {
+                    self.pb.set_style(output::bar_style(true));
+                    self.pb.set_message("");
+                    connected = true;
+                    ctl.connected(peer);
+                    if let Some(slots) = self.scheduler.peer_slots(peer) {
+                        match slots.clone().try_acquire_owned() {
+                            Ok(permit) => _peer_slot = Some(permit),
+                            Err(_) => peer_wait = Some(Box::pin(slots.acquire_owned())),
+                        }
+                    }
+                    if peer_wait.is_none() && self.priority == Priority::Urgent {
+                        _urgent_send = Some(UrgentSend::start(&self.scheduler));
+                    }
+                    self.hold(
+                        &handle,
+                        &ctl,
+                        connected,
+                        peer_wait.is_some(),
+                        *urgent.borrow(),
+                    );
+                }
+                SendProgress::Sending {
+                    bytes_sent,
+                    total_bytes,
+                } => {
+                    if let Some(total) = total_bytes {
+                        self.pb.set_length(total);
+                    }
+                    self.pb.set_position(bytes_sent);
+                    ctl.progress(bytes_sent, total_bytes);
+                }
+                SendProgress::Complete {
+                    peer,
+                    size,
+                    checksum,
+                    ..
+                } => {
+                    ctl.complete();
+                    self.pb.set_style(output::status_style());
+                    self.pb.finish_with_message(format!("{}", success("done")));
+                    if let Some(command) = &self.on_sent {
+                        let fields = HookFields {
+                            path: &self.path,
+                            name: &self.name,
+                            size,
+                            checksum: &checksum,
+                            peer: peer.to_string(),
+                        };
+                        run_hook(command, &fields).await?;
+                    }
+                    break;
+                }
+                SendProgress::Error(e) => anyhow::bail!("{}", e),
+                _ => {}
+            }
+        }
+
+        node.shutdown().await?;
+        Ok(())
+    }
+
+    /// Pause while waiting for a slot with the receiver, while urgent files
+    /// are being sent, unless this is one of them, or when `zap ctl` asks
+    fn hold(
+        &self,
+        handle: &TransferHandle,
+        ctl: &ctl::Controlled,
+        connected: bool,
+        peer_wait: bool,
+        urgent: usize,
+    ) {
+        let reason = hold_reason(ctl.requested(), peer_wait, urgent, self.priority);
+        match reason {
+            Some(_) => handle.pause(),
+            None => handle.resume(),
+        }
+        // Until then the line says it's waiting for a receiver
+        if connected {
+            let reason = reason.map(|r| format!("{}", style(r).dim()));
+            self.pb.set_message(reason.unwrap_or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scheduler() {
+        let scheduler = Arc::new(Scheduler::new(Some(1)));
+        let peer = zap_core::SecretKey::generate(&mut rand::rng()).public();
+        let other = zap_core::SecretKey::generate(&mut rand::rng()).public();
+
+        // Each receiver gets its own slots
+        let take = |peer| scheduler.peer_slots(peer).unwrap().try_acquire_owned();
+        let slot = take(peer).unwrap();
+        assert!(take(peer).is_err());
+        assert!(take(other).is_ok());
+        drop(slot);
+        assert!(take(peer).is_ok());
+
+        // Urgent sends are counted while they last
+        let urgent = scheduler.urgent.subscribe();
+        let first = UrgentSend::start(&scheduler);
+        let second = UrgentSend::start(&scheduler);
+        assert_eq!(*urgent.borrow(), 2);
+        drop(first);
+        drop(second);
+        assert_eq!(*urgent.borrow(), 0);
+
+        assert!(Scheduler::new(None).peer_slots(peer).is_none());
+    }
+
+    #[test]
+    fn test_queue_order() {
+        let paths = vec![PathBuf::from("a"), PathBuf::from("b")];
+        let urgent = [PathBuf::from("x"), PathBuf::from("y")];
+        assert_eq!(
+            queue_order(paths, &urgent),
+            [
+                (PathBuf::from("x"), Priority::Urgent),
+                (PathBuf::from("y"), Priority::Urgent),
+                (PathBuf::from("a"), Priority::Normal),
+                (PathBuf::from("b"), Priority::Normal),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hold() {
+        let scheduler = Arc::new(Scheduler::new(Some(1)));
+        let peer = zap_core::SecretKey::generate(&mut rand::rng()).public();
+        let urgent = scheduler.urgent.subscribe();
+        let reason = |peer_wait, priority| {
+            hold_reason(Requested::Nothing, peer_wait, *urgent.borrow(), priority)
+        };
+
+        // A second transfer to the same receiver waits for the first
+        let slots = scheduler.peer_slots(peer).unwrap();
+        let first = slots.clone().try_acquire_owned().unwrap();
+        let waiting = slots.clone().try_acquire_owned().is_err();
+        assert_eq!(reason(false, Priority::Normal), None);
+        assert_eq!(
+            reason(waiting, Priority::Normal),
+            Some("waiting for another transfer to this receiver")
+        );
+        drop(first);
+        assert!(slots.try_acquire_owned().is_ok());
+
+        // While an urgent file is sent, everything else pauses, and
+        // carries on once it's done
+        let sending = UrgentSend::start(&scheduler);
+        assert_eq!(reason(false, Priority::Urgent), None);
+        assert_eq!(
+            reason(false, Priority::Normal),
+            Some("paused for urgent files")
+        );
+        drop(sending);
+        assert_eq!(reason(false, Priority::Normal), None);
+
+        // zap ctl can pause any of them
+        assert_eq!(
+            hold_reason(Requested::Pause, false, 0, Priority::Urgent),
+            Some("paused with zap ctl")
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! `zap replay`: receive a captured transfer again, without the network
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+use zap_core::{ReceiveOptions, ReceiveProgress};
+
+use crate::format_bytes;
+use crate::output::{accent, success, symbol};
+
+/// Play the sender's side of a capture made with `ZAP_CAPTURE` back to a
+/// receiver, saving into `output`
+pub async fn run_replay(capture: PathBuf, output: Option<PathBuf>) -> Result<()> {
+    let (progress_tx, mut progress_rx) = mpsc::channel(32);
+    let options = ReceiveOptions {
+        detect_extension: true,
+        ..Default::default()
+    };
+    let replay = zap_core::debug::replay(&capture, output, options, progress_tx);
+
+    let report = async {
+        while let Some(progress) = progress_rx.recv().await {
+            match progress {
+                ReceiveProgress::Offer { name, size, .. } => {
+                    let size = size.map(format_bytes).unwrap_or("streaming".into());
+                    println!("{} {} ({})", accent(symbol("⚡")), accent(&name), size);
+                }
+                ReceiveProgress::Complete { path, .. } => {
+                    println!(
+                        "{} Saved: {}",
+                        success(symbol("✓")).bold(),
+                        accent(path.display())
+                    );
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let (replayed, ()) = tokio::join!(replay, report);
+    replayed.map_err(|e| anyhow::anyhow!("Replay failed: {}", e))
+}
@@ -0,0 +1,222 @@
+//! `zap send --share`: keep serving the same code to every receiver
+
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use console::style;
+use serde::Serialize;
+use zap_core::SendProgress;
+
+use crate::claims;
+use crate::flows::{SendEvent, Sending};
+use crate::hooks::{HookFields, run_hook};
+use crate::output::{accent, success, symbol, warning};
+
+/// When `zap send` stops serving, for `--exit-after`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExitAfter {
+    /// Once the first receiver has everything
+    First,
+    /// Once `--downloads` receivers have everything
+    All,
+    /// Not until interrupted or idle, like `--share`
+    Never,
+}
+
+/// Who the relay's catalog shows a shared file to, for `--list`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Listing {
+    /// Everyone who opens it
+    Public,
+    /// Only those using the same ZAP_API_KEY
+    Key,
+}
+
+/// Parse a duration like "90s", "10m", "2h" or "1d"; a bare number is
+/// seconds
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("expected a duration like 10m, not {:?}", input))?;
+    let seconds = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        unit => return Err(format!("unknown unit {:?}, use s, m, h or d", unit)),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+/// Check a transfer label, which receivers refuse if it's too long or has
+/// control characters in it
+pub fn parse_label(input: &str) -> Result<String, String> {
+    zap_core::validate_label(input).map_err(|e| e.to_string())?;
+    Ok(input.to_string())
+}
+
+/// Parse a size like "500KB", "10MB" or "2GB", or a bare number of bytes
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("expected a size like 10MB, not {:?}", input))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        unit => return Err(format!("unknown unit {:?}, use KB, MB or GB", unit)),
+    };
+    Ok(number * multiplier)
+}
+
+/// Parse a rate like "10MB", "500KB/s" or a bare number of bytes a second
+pub fn parse_rate(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    match parse_size(input.strip_suffix("/s").unwrap_or(input))? {
+        0 => Err("a rate has to be more than 0".to_string()),
+        rate => Ok(rate),
+    }
+}
+
+/// A duration the way [`parse_duration`] takes it
+pub(crate) fn short_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        s if s >= 3600 && s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s >= 60 && s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// Report finished transfers until interrupted, or until the sender stops
+/// on its own after `downloads` receivers or `idle_timeout` without any
+///
+/// Several receivers can download at once, so each one gets a line instead
+/// of a progress bar. Connections alone aren't shown, since `zap mount`
+/// opens one for every block it reads.
+pub(crate) async fn watch_share(
+    mut sending: Sending,
+    on_sent: Option<String>,
+    downloads: Option<u32>,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    let until = match (downloads, idle_timeout) {
+        (Some(1), _) => "until a receiver has it".to_string(),
+        (Some(n), _) => format!("until {} receivers have it", n),
+        (None, Some(idle)) => format!("until nobody has connected for {}", short_duration(idle)),
+        (None, None) => "until you press Ctrl-C".to_string(),
+    };
+    println!(
+        "{}",
+        style(format!("Sharing {}; the code works for everyone", until)).dim()
+    );
+
+    let mut served = 0;
+    loop {
+        let event = tokio::select! {
+            event = sending.next() => event,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let progress = match event {
+            Some(SendEvent::Progress(progress)) => Some(progress),
+            Some(SendEvent::Code(event)) => {
+                if let Some(line) = claims::line(&event) {
+                    println!("{}", line);
+                }
+                continue;
+            }
+            None => None,
+        };
+        match progress {
+            Some(SendProgress::Complete {
+                peer,
+                size,
+                checksum,
+                ..
+            }) => {
+                served += 1;
+                let peer = peer.to_string();
+                println!(
+                    "{} Sent to {}",
+                    success(symbol("✓")).bold(),
+                    accent(&peer[..8.min(peer.len())])
+                );
+                if let Some(command) = &on_sent {
+                    let fields = HookFields {
+                        path: &sending.path,
+                        name: &sending.name,
+                        size,
+                        checksum: &checksum,
+                        peer,
+                    };
+                    if let Err(e) = run_hook(command, &fields).await {
+                        eprintln!("{} {}", warning(symbol("⚠")), e);
+                    }
+                }
+            }
+            Some(SendProgress::Error(e)) => anyhow::bail!("Sharing failed: {}", e),
+            Some(_) => {}
+            None => break,
+        }
+    }
+
+    sending.shutdown().await?;
+    println!(
+        "\n{} Stopped sharing after {} transfer{}",
+        success(symbol("✓")).bold(),
+        served,
+        if served == 1 { "" } else { "s" }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration(" 2h "), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("10 weeks").is_err());
+        assert!(parse_duration("-5m").is_err());
+        assert_eq!(short_duration(Duration::from_secs(600)), "10m");
+        assert_eq!(short_duration(Duration::from_secs(90)), "90s");
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("500"), Ok(500));
+        assert_eq!(parse_rate("10MB"), Ok(10 << 20));
+        assert_eq!(parse_rate(" 500kb/s "), Ok(500 << 10));
+        assert_eq!(parse_rate("1G"), Ok(1 << 30));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("MB").is_err());
+        assert!(parse_rate("10 furlongs").is_err());
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("5 GB"), Ok(5 << 30));
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(parse_label("Q3 report v2"), Ok("Q3 report v2".to_string()));
+        assert!(parse_label("two\nlines").is_err());
+        assert!(parse_label(&"x".repeat(201)).is_err());
+    }
+}
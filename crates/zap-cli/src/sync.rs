@@ -0,0 +1,229 @@
+//! `zap sync`: bring a local directory up to date with a shared folder
+//!
+//! The sender has to be sharing (`zap send --share`). Its files are compared
+//! with the local ones by size and BLAKE3 hash before anything is
+//! downloaded, and only new and changed files are received. Local files the
+//! share doesn't have are left alone.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use console::style;
+use zap_core::directory::safe_join;
+use zap_core::protocol::{DirEntry, OfferKind};
+use zap_core::{EntryChooser, ReceiveOptions, ReceiveProgress, Renamer};
+
+use crate::code::resolve_ticket;
+use crate::format_bytes;
+use crate::net::start_node;
+use crate::output::{self, accent, success, symbol, warning};
+
+/// What happens to one file of the share
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    New,
+    Changed,
+    Unchanged,
+}
+
+/// Compare the share's files with those under `root`
+fn plan(root: &Path, entries: &[DirEntry], checksums: &[[u8; 32]]) -> Result<Vec<Change>> {
+    entries
+        .iter()
+        .zip(checksums)
+        .map(|(entry, checksum)| {
+            let path = safe_join(root, &entry.path)?;
+            let Ok(metadata) = std::fs::metadata(&path) else {
+                return Ok(Change::New);
+            };
+            if !metadata.is_file() || metadata.len() != entry.size {
+                return Ok(Change::Changed);
+            }
+            if hash_file(&path)? == *checksum {
+                Ok(Change::Unchanged)
+            } else {
+                Ok(Change::Changed)
+            }
+        })
+        .collect()
+}
+
+fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("reading {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 256 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Receive whatever is new or changed in a share into `dir`
+pub async fn run_sync(dir: PathBuf, code: String, dry_run: bool, relay: String) -> Result<()> {
+    std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    let dir = dir.canonicalize()?;
+
+    let ticket = resolve_ticket(&code, &relay).await?;
+    let node = start_node(None).await?;
+    let (offer, checksums) = node
+        .checksums(&ticket)
+        .await
+        .map_err(|e| anyhow::anyhow!("{} (is the sender running zap send --share?)", e))?;
+
+    // A shared folder's entries land directly in `dir`, a shared file inside it
+    let (entries, is_dir) = match offer.kind {
+        OfferKind::Directory { entries } => (entries, true),
+        OfferKind::File => {
+            let entry = DirEntry {
+                path: offer.name.clone(),
+                size: offer.size.unwrap_or(0),
+            };
+            (vec![entry], false)
+        }
+        OfferKind::Archive => bail!(
+            "{} is shared as an archive, which can't be synced",
+            offer.name
+        ),
+    };
+
+    let dir_for_plan = dir.clone();
+    let entries_for_plan = entries.clone();
+    let changes =
+        tokio::task::spawn_blocking(move || plan(&dir_for_plan, &entries_for_plan, &checksums))
+            .await??;
+
+    println!(
+        "\n{} Syncing {} into {}",
+        accent(symbol("⚡")),
+        accent(&offer.name),
+        accent(dir.display())
+    );
+    let mut wanted = Vec::new();
+    let mut size = 0;
+    for (i, (entry, change)) in entries.iter().zip(&changes).enumerate() {
+        let marker = match change {
+            Change::New => success("+"),
+            Change::Changed => warning("~"),
+            Change::Unchanged => continue,
+        };
+        println!(
+            "  {} {} {}",
+            marker,
+            entry.path,
+            style(format!("({})", format_bytes(entry.size))).dim()
+        );
+        wanted.push(i);
+        size += entry.size;
+    }
+    let unchanged = changes.len() - wanted.len();
+    println!(
+        "  {}",
+        style(format!(
+            "{} to receive ({}), {} unchanged",
+            wanted.len(),
+            format_bytes(size),
+            unchanged
+        ))
+        .dim()
+    );
+
+    if wanted.is_empty() || dry_run {
+        if wanted.is_empty() {
+            println!("{} Already up to date", success(symbol("✓")).bold());
+        }
+        node.shutdown().await?;
+        return Ok(());
+    }
+
+    let mut options = ReceiveOptions::default();
+    let output_dir = if is_dir {
+        let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) else {
+            bail!("can't sync into {}", dir.display());
+        };
+        let name = name.to_string_lossy().into_owned();
+        options.rename = Some(Renamer::new(move |_| name.clone()));
+        let chosen = wanted.clone();
+        options.choose_entries = Some(EntryChooser::new(move |_| {
+            let chosen = chosen.clone();
+            async move { Some(chosen) }
+        }));
+        parent.to_path_buf()
+    } else {
+        dir.clone()
+    };
+
+    let mut progress_rx = node
+        .receive_with_options(ticket, Some(&output_dir), options)
+        .await?;
+    let pb = output::bar(size);
+    pb.set_style(output::bar_style(false));
+    while let Some(progress) = progress_rx.recv().await {
+        match progress {
+            ReceiveProgress::Receiving { bytes_received, .. } => {
+                pb.set_position(bytes_received);
+            }
+            ReceiveProgress::Complete { .. } => {
+                pb.finish_and_clear();
+                println!(
+                    "{} Received {} file{} ({})",
+                    success(symbol("✓")).bold(),
+                    wanted.len(),
+                    if wanted.len() == 1 { "" } else { "s" },
+                    format_bytes(size)
+                );
+                break;
+            }
+            ReceiveProgress::Error(e) => {
+                pb.abandon();
+                bail!("Sync failed: {}", e);
+            }
+            _ => {}
+        }
+    }
+
+    node.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("same.txt"), b"hello").unwrap();
+        std::fs::write(dir.path().join("sub/edited.txt"), b"hellO").unwrap();
+        std::fs::write(dir.path().join("grown.txt"), b"hello!").unwrap();
+        std::fs::write(dir.path().join("only-here.txt"), b"x").unwrap();
+
+        let entry = |path: &str| DirEntry {
+            path: path.to_string(),
+            size: 5,
+        };
+        let entries = [
+            entry("same.txt"),
+            entry("sub/edited.txt"),
+            entry("grown.txt"),
+            entry("new.txt"),
+        ];
+        let checksums = [*blake3::hash(b"hello").as_bytes(); 4];
+
+        assert_eq!(
+            plan(dir.path(), &entries, &checksums).unwrap(),
+            vec![
+                Change::Unchanged,
+                Change::Changed,
+                Change::Changed,
+                Change::New
+            ]
+        );
+    }
+}
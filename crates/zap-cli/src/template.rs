@@ -0,0 +1,271 @@
+//! `zap receive --output-template`: naming received files from a pattern
+//!
+//! A template is text with placeholders in braces, e.g.
+//! `{date}-{sender_short}-{name}`. `{{` and `}}` stand for literal braces.
+
+use std::path::Path;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Local};
+use zap_core::protocol::{FileOffer, OfferKind};
+
+/// The placeholders a template can use
+///
+/// `{name}` is the offered name, `{stem}` and `{ext}` its parts (`{ext}`
+/// includes the dot, or is empty), `{date}` and `{time}` are local, like
+/// `2024-05-31` and `142501`, `{sender}` is the sender's node id and
+/// `{sender_short}` its first 8 characters, and `{size}` is in bytes.
+const PLACEHOLDERS: &[(&str, Field)] = &[
+    ("name", Field::Name),
+    ("stem", Field::Stem),
+    ("ext", Field::Ext),
+    ("date", Field::Date),
+    ("time", Field::Time),
+    ("sender", Field::Sender),
+    ("sender_short", Field::SenderShort),
+    ("size", Field::Size),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Name,
+    Stem,
+    Ext,
+    Date,
+    Time,
+    Sender,
+    SenderShort,
+    Size,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+/// A parsed output template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+}
+
+/// What a template is filled in with
+pub(crate) struct Fields<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) sender: &'a str,
+    pub(crate) size: Option<u64>,
+    pub(crate) now: DateTime<Local>,
+}
+
+impl OutputTemplate {
+    /// Parse a template, rejecting unknown placeholders and path separators
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("unclosed {{ in output template"),
+                        }
+                    }
+                    let Some(&(_, field)) = PLACEHOLDERS.iter().find(|(p, _)| *p == name) else {
+                        bail!(
+                            "unknown placeholder {{{}}} in output template, expected one of: {}",
+                            name,
+                            placeholder_list()
+                        );
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => bail!("unmatched }} in output template, use }}}} for a literal brace"),
+                '/' | '\\' => {
+                    bail!(
+                        "output template can't contain path separators, use --output for the directory"
+                    )
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        if parts.is_empty() {
+            bail!("output template is empty");
+        }
+        Ok(Self { parts })
+    }
+
+    /// Fill in the placeholders
+    pub(crate) fn render(&self, fields: &Fields) -> String {
+        let path = Path::new(fields.name);
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+        let ext = path
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_default();
+
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Field(Field::Name) => out.push_str(fields.name),
+                Part::Field(Field::Stem) => out.push_str(&stem),
+                Part::Field(Field::Ext) => out.push_str(&ext),
+                Part::Field(Field::Date) => {
+                    out.push_str(&fields.now.format("%Y-%m-%d").to_string())
+                }
+                Part::Field(Field::Time) => out.push_str(&fields.now.format("%H%M%S").to_string()),
+                Part::Field(Field::Sender) => out.push_str(fields.sender),
+                Part::Field(Field::SenderShort) => out.extend(fields.sender.chars().take(8)),
+                Part::Field(Field::Size) => {
+                    if let Some(size) = fields.size {
+                        out.push_str(&size.to_string());
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// A name based on `name` that wasn't taken in `dir`, adding `-1`, `-2`,
+/// ... before the extension
+///
+/// The name is taken by creating it, an empty file or a `folder`, so two
+/// receives can't both be given it; the file is then written over by the
+/// one it was given to. If `dir` can't be written to, `name` comes back
+/// as is for saving there to fail.
+pub(crate) fn unique_name(dir: &Path, name: &str, folder: bool) -> String {
+    let take = |candidate: &str| {
+        let path = dir.join(candidate);
+        if folder {
+            std::fs::create_dir(path)
+        } else {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map(drop)
+        }
+    };
+    if std::fs::create_dir_all(dir).is_err() {
+        return name.to_string();
+    }
+    match take(name) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        _ => return name.to_string(),
+    }
+
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    (1..)
+        .map(|n| format!("{}-{}{}", stem, n, ext))
+        .find(|candidate| {
+            !matches!(take(candidate), Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists)
+        })
+        .expect("some suffix is free")
+}
+
+/// Whether `offer` is saved as a folder rather than a file
+pub(crate) fn is_folder(offer: &FileOffer) -> bool {
+    !matches!(offer.kind, OfferKind::File)
+}
+
+fn placeholder_list() -> String {
+    PLACEHOLDERS
+        .iter()
+        .map(|(name, _)| format!("{{{}}}", name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn fields(name: &str) -> Fields<'_> {
+        Fields {
+            name,
+            sender: "k5nhtm4vqxa3b2e7",
+            size: Some(1234),
+            now: Local.with_ymd_and_hms(2024, 5, 31, 14, 25, 1).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = OutputTemplate::parse("{date}-{sender_short}-{name}").unwrap();
+        assert_eq!(
+            template.render(&fields("photo.jpg")),
+            "2024-05-31-k5nhtm4v-photo.jpg"
+        );
+
+        let template = OutputTemplate::parse("{stem}_{time}_{size}{ext}").unwrap();
+        assert_eq!(
+            template.render(&fields("photo.jpg")),
+            "photo_142501_1234.jpg"
+        );
+        assert_eq!(template.render(&fields("notes")), "notes_142501_1234");
+
+        let template = OutputTemplate::parse("{{{name}}}").unwrap();
+        assert_eq!(template.render(&fields("a.txt")), "{a.txt}");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(OutputTemplate::parse("").is_err());
+        assert!(OutputTemplate::parse("{nme}").is_err());
+        assert!(OutputTemplate::parse("{name").is_err());
+        assert!(OutputTemplate::parse("name}").is_err());
+        assert!(OutputTemplate::parse("{date}/{name}").is_err());
+    }
+
+    #[test]
+    fn test_unique_name() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(unique_name(dir.path(), "a.txt", false), "a.txt");
+        // The name is taken as soon as it's given out
+        assert!(dir.path().join("a.txt").is_file());
+        assert_eq!(unique_name(dir.path(), "a.txt", false), "a-1.txt");
+
+        std::fs::write(dir.path().join("a-2.txt"), b"mine").unwrap();
+        assert_eq!(unique_name(dir.path(), "a.txt", false), "a-3.txt");
+        assert_eq!(std::fs::read(dir.path().join("a-2.txt")).unwrap(), b"mine");
+
+        assert_eq!(unique_name(dir.path(), "photos", true), "photos");
+        assert!(dir.path().join("photos").is_dir());
+        assert_eq!(unique_name(dir.path(), "photos", true), "photos-1");
+    }
+}
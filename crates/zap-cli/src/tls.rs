@@ -0,0 +1,62 @@
+//! Client certificates for relays that only answer clients they know
+//!
+//! A relay set up with `ZAP_CLIENT_CA` turns away API calls, like
+//! registering and looking up codes, from clients without a certificate its
+//! CA signed. The certificate and key come from `--client-cert` and
+//! `--client-key`, or else from the profile's `client_cert` and
+//! `client_key`.
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+
+use crate::config::Config;
+
+/// The certificate and key given with `--client-cert` and `--client-key`
+static GIVEN: OnceLock<(PathBuf, PathBuf)> = OnceLock::new();
+
+/// Present the certificate at `cert`, with the key at `key`, to relays for
+/// the rest of this run, whatever the profile says
+pub fn set_client_cert(cert: PathBuf, key: PathBuf) {
+    let _ = GIVEN.set((cert, key));
+}
+
+/// A client for calls to a relay's API, with the client certificate if
+/// one is set
+pub(crate) fn relay_client() -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(identity) = identity()? {
+        builder = builder.identity(identity);
+    }
+    Ok(builder.build()?)
+}
+
+fn identity() -> Result<Option<reqwest::Identity>> {
+    let paths = match GIVEN.get() {
+        Some(paths) => Some(paths.clone()),
+        None => {
+            let config = Config::load();
+            let profile = config.active();
+            profile.client_cert.clone().zip(profile.client_key.clone())
+        }
+    };
+    let Some((cert, key)) = paths else {
+        return Ok(None);
+    };
+
+    let read = |path: &PathBuf| {
+        std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))
+    };
+    let mut pem = read(&cert)?;
+    pem.push(b'\n');
+    pem.extend(read(&key)?);
+    let identity = reqwest::Identity::from_pem(&pem).with_context(|| {
+        format!(
+            "{} and {} aren't a PEM certificate and key",
+            cert.display(),
+            key.display()
+        )
+    })?;
+    Ok(Some(identity))
+}
@@ -0,0 +1,341 @@
+//! `zap tui`: a live dashboard of every running transfer
+//!
+//! Shows what `zap ctl list` does, refreshed twice a second, along with
+//! each transfer's speed and receiver, and pauses, resumes or cancels the
+//! selected one. Transfers are found through the same sockets as `zap ctl`.
+
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Cell, Paragraph, Row, Table, TableState};
+
+use crate::ctl::{Request, State, TransferInfo};
+use crate::format_bytes;
+
+/// How often the transfers are fetched again
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// A transfer: the pid of its process and its id there
+type Key = (u32, u64);
+
+struct Listed {
+    key: Key,
+    info: TransferInfo,
+    /// Bytes a second since the last refresh
+    speed: Option<u64>,
+}
+
+/// What the dashboard shows, kept between refreshes to work out speeds
+#[derive(Default)]
+struct Dashboard {
+    transfers: Vec<Listed>,
+    selected: Option<Key>,
+    /// Each transfer's bytes at the last refresh
+    seen: HashMap<Key, (Instant, u64)>,
+    /// A transfer to cancel once `c` is pressed again
+    cancelling: Option<Key>,
+    /// The outcome of the last action, shown instead of the key help
+    status: Option<String>,
+}
+
+impl Dashboard {
+    fn update(&mut self, processes: Vec<(u32, Vec<TransferInfo>)>, now: Instant) {
+        let mut seen = HashMap::new();
+        self.transfers = processes
+            .into_iter()
+            .flat_map(|(pid, transfers)| transfers.into_iter().map(move |info| (pid, info)))
+            .map(|(pid, info)| {
+                let key = (pid, info.id);
+                let speed = self.seen.get(&key).and_then(|&(at, bytes)| {
+                    let elapsed = now.duration_since(at).as_secs_f64();
+                    (elapsed > 0.0)
+                        .then(|| (info.bytes.saturating_sub(bytes) as f64 / elapsed) as u64)
+                });
+                seen.insert(key, (now, info.bytes));
+                Listed { key, info, speed }
+            })
+            .collect();
+        self.seen = seen;
+
+        // The selection stays on its transfer until that one is gone
+        if self.position().is_none() {
+            self.selected = self.transfers.first().map(|t| t.key);
+        }
+    }
+
+    fn position(&self) -> Option<usize> {
+        self.transfers
+            .iter()
+            .position(|t| Some(t.key) == self.selected)
+    }
+
+    /// Move the selection `by` rows, stopping at either end
+    fn select(&mut self, by: isize) {
+        let Some(current) = self.position() else {
+            return;
+        };
+        let last = self.transfers.len() as isize - 1;
+        let next = (current as isize + by).clamp(0, last) as usize;
+        self.selected = Some(self.transfers[next].key);
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let speed: u64 = self.transfers.iter().filter_map(|t| t.speed).sum();
+        let summary = format!(
+            " {} transfers, {}/s",
+            self.transfers.len(),
+            format_bytes(speed)
+        );
+        frame.render_widget(
+            Line::from(vec!["⚡ zap".cyan().bold(), summary.into()]),
+            header,
+        );
+
+        if self.transfers.is_empty() {
+            frame.render_widget(Paragraph::new("No transfers running".dim()), body);
+        } else {
+            let rows = self.transfers.iter().map(|t| {
+                let state = match t.info.state {
+                    State::Waiting => "waiting".dim(),
+                    State::Sending => "sending".green(),
+                    State::Paused => "paused".yellow(),
+                };
+                Row::new(vec![
+                    Cell::from(format!("{}.{}", t.key.0, t.key.1)),
+                    Cell::from(t.info.name.clone()),
+                    Cell::from(state),
+                    Cell::from(progress(&t.info)),
+                    Cell::from(
+                        t.speed
+                            .map(|s| format!("{}/s", format_bytes(s)))
+                            .unwrap_or_default(),
+                    ),
+                    Cell::from(short_peer(t.info.peer.as_deref())),
+                    Cell::from(t.info.code.clone().unwrap_or_default().green()),
+                ])
+            });
+            let widths = [
+                Constraint::Length(12),
+                Constraint::Fill(1),
+                Constraint::Length(8),
+                Constraint::Length(26),
+                Constraint::Length(12),
+                Constraint::Length(10),
+                Constraint::Length(24),
+            ];
+            let titles = ["ID", "NAME", "STATE", "PROGRESS", "SPEED", "PEER", "CODE"];
+            let table = Table::new(rows, widths)
+                .header(Row::new(titles).dim())
+                .row_highlight_style(Style::new().reversed());
+            let mut state = TableState::default().with_selected(self.position());
+            frame.render_stateful_widget(table, body, &mut state);
+        }
+
+        let help = "↑/↓ select  p pause  r resume  c cancel  q quit";
+        let footer_text = match &self.status {
+            Some(status) => status.clone().yellow(),
+            None => help.dim(),
+        };
+        frame.render_widget(Line::from(footer_text), footer);
+    }
+}
+
+/// Bytes sent out of the total, with a percentage when it's known
+fn progress(info: &TransferInfo) -> String {
+    match info.total {
+        Some(total) if total > 0 => format!(
+            "{} / {} ({}%)",
+            format_bytes(info.bytes),
+            format_bytes(total),
+            info.bytes * 100 / total
+        ),
+        _ => format_bytes(info.bytes),
+    }
+}
+
+/// The start of a node id, which is enough to tell receivers apart
+fn short_peer(peer: Option<&str>) -> String {
+    peer.map(|p| p.chars().take(8).collect())
+        .unwrap_or_default()
+}
+
+/// What a key press asks for
+enum Action {
+    Quit,
+    Select(isize),
+    Send(fn(u64) -> Request, &'static str),
+    Cancel,
+    Nothing,
+}
+
+fn action(key: KeyEvent) -> Action {
+    match key.code {
+        KeyCode::Char('q') | KeyCode::Esc => Action::Quit,
+        // Raw mode swallows Ctrl-C, so handle it here
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Action::Quit,
+        KeyCode::Up | KeyCode::Char('k') => Action::Select(-1),
+        KeyCode::Down | KeyCode::Char('j') => Action::Select(1),
+        KeyCode::Char('p') => Action::Send(|id| Request::Pause { id }, "Paused"),
+        KeyCode::Char('r') => Action::Send(|id| Request::Resume { id }, "Resumed"),
+        KeyCode::Char('c') => Action::Cancel,
+        _ => Action::Nothing,
+    }
+}
+
+/// Show the dashboard until `q` is pressed
+pub async fn run_tui() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminal = ratatui::try_init()?;
+        let result = unix::run(&mut terminal).await;
+        ratatui::restore();
+        result
+    }
+
+    #[cfg(not(unix))]
+    anyhow::bail!("zap tui needs unix domain sockets, which this platform doesn't have")
+}
+
+#[cfg(unix)]
+mod unix {
+    use crossterm::event::{self, Event, KeyEventKind};
+    use ratatui::DefaultTerminal;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::ctl;
+
+    pub(super) async fn run(terminal: &mut DefaultTerminal) -> Result<()> {
+        let (key_tx, mut keys) = mpsc::unbounded_channel();
+        std::thread::spawn(move || read_keys(&key_tx));
+
+        let mut dashboard = Dashboard::default();
+        let mut refresh = tokio::time::interval(REFRESH);
+        loop {
+            tokio::select! {
+                _ = refresh.tick() => match ctl::transfers().await {
+                    Ok(processes) => dashboard.update(processes, Instant::now()),
+                    Err(e) => dashboard.status = Some(format!("Can't list transfers: {}", e)),
+                },
+                key = keys.recv() => {
+                    let Some(key) = key else {
+                        return Ok(());
+                    };
+                    let cancelling = dashboard.cancelling.take();
+                    dashboard.status = None;
+                    match action(key) {
+                        Action::Quit => return Ok(()),
+                        Action::Select(by) => dashboard.select(by),
+                        Action::Send(make, done) => {
+                            if let Some(key) = dashboard.selected {
+                                act(&mut dashboard, key, make(key.1), done).await;
+                                refresh.reset_immediately();
+                            }
+                        }
+                        // Cancelling can't be undone, so it takes a second press
+                        Action::Cancel => match dashboard.selected {
+                            Some(key) if cancelling == Some(key) => {
+                                act(&mut dashboard, key, Request::Cancel { id: key.1 }, "Cancelled").await;
+                                refresh.reset_immediately();
+                            }
+                            Some(key) => {
+                                dashboard.cancelling = Some(key);
+                                dashboard.status =
+                                    Some(format!("Press c again to cancel {}.{}", key.0, key.1));
+                            }
+                            None => {}
+                        },
+                        Action::Nothing => {}
+                    }
+                }
+            }
+            terminal.draw(|frame| dashboard.draw(frame))?;
+        }
+    }
+
+    async fn act(dashboard: &mut Dashboard, (pid, id): Key, request: Request, done: &str) {
+        dashboard.status = Some(match ctl::act(pid, &request).await {
+            Ok(()) => format!("{} {}.{}", done, pid, id),
+            Err(e) => e.to_string(),
+        });
+    }
+
+    /// Pass key presses on until the dashboard is gone
+    fn read_keys(keys: &mpsc::UnboundedSender<KeyEvent>) {
+        while !keys.is_closed() {
+            // Poll with a timeout so the thread notices when it should stop
+            if !event::poll(Duration::from_millis(100)).unwrap_or(false) {
+                continue;
+            }
+            match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    let _ = keys.send(key);
+                }
+                Ok(_) => {}
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(id: u64, bytes: u64) -> TransferInfo {
+        TransferInfo {
+            id,
+            name: format!("{}.txt", id),
+            code: None,
+            state: State::Sending,
+            bytes,
+            total: Some(1000),
+            peer: None,
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let mut dashboard = Dashboard::default();
+        let start = Instant::now();
+        dashboard.update(vec![(10, vec![info(1, 0), info(2, 0)])], start);
+        assert_eq!(dashboard.selected, Some((10, 1)));
+        assert_eq!(dashboard.transfers[0].speed, None);
+
+        dashboard.select(1);
+        dashboard.select(1);
+        assert_eq!(dashboard.selected, Some((10, 2)));
+
+        // Speeds come from the bytes since the last refresh
+        let later = start + Duration::from_secs(2);
+        dashboard.update(vec![(10, vec![info(1, 500), info(2, 100)])], later);
+        assert_eq!(dashboard.transfers[0].speed, Some(250));
+        assert_eq!(dashboard.transfers[1].speed, Some(50));
+        assert_eq!(dashboard.selected, Some((10, 2)));
+
+        // A finished transfer's selection moves to what's left
+        dashboard.update(vec![(10, vec![info(1, 600)])], later);
+        assert_eq!(dashboard.selected, Some((10, 1)));
+    }
+
+    #[test]
+    fn test_progress() {
+        assert_eq!(progress(&info(1, 250)), "250 B / 1000 B (25%)");
+        assert_eq!(short_peer(Some("0123456789abcdef")), "01234567");
+    }
+}
@@ -0,0 +1,146 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use anyhow::Result;
+use console::style;
+use tokio::sync::mpsc;
+use zap_core::TunnelEvent;
+
+use crate::code::resolve_ticket;
+use crate::net::start_node;
+use crate::output::{accent, success, symbol, warning};
+use crate::{format_bytes, register_ticket};
+
+/// Run `zap tunnel`, either exposing a local port or forwarding to a peer's
+pub async fn run_tunnel(
+    code: Option<String>,
+    expose: Option<u16>,
+    local: u16,
+    no_relay: bool,
+    relay: String,
+) -> Result<()> {
+    match (expose, code) {
+        (Some(port), None) => run_expose(port, no_relay, &relay).await,
+        (None, Some(code)) => run_forward(&code, local, &relay).await,
+        _ => anyhow::bail!("Pass either --expose <port> or a code to connect to"),
+    }
+}
+
+async fn run_expose(port: u16, no_relay: bool, relay: &str) -> Result<()> {
+    let node = start_node(None).await?;
+    let (ticket, events) = node.expose(port).await?;
+
+    let code_info = if no_relay {
+        None
+    } else {
+        match register_ticket(relay, &ticket.to_string(), None, None, None).await {
+            Ok(info) => Some(info),
+            Err(e) => {
+                eprintln!(
+                    "{} Could not register with relay: {}",
+                    warning(symbol("⚠")),
+                    e
+                );
+                None
+            }
+        }
+    };
+
+    // The relay only ever sees the ticket; the key goes with the code
+    let key = ticket.key;
+    let with_key = |code: &str| match key {
+        Some(key) => format!("{}.{}", code, key),
+        None => code.to_string(),
+    };
+    println!(
+        "\n{} Exposing {} — share this with your peer:\n",
+        accent(symbol("⚡")),
+        success(format!("localhost:{}", port))
+    );
+    match code_info {
+        Some(info) => {
+            println!("  Code:  {}", success(with_key(&info.code)).bold());
+            println!("  Words: {}", accent(with_key(&info.words)).bold());
+            println!();
+            println!(
+                "  {}",
+                style(format!(
+                    "Peer runs: zap tunnel {} --local <port>",
+                    with_key(&info.code)
+                ))
+                .dim()
+            );
+        }
+        None => println!("  {}", success(ticket.share())),
+    }
+    println!();
+
+    watch_events(events).await?;
+    node.shutdown().await?;
+    Ok(())
+}
+
+async fn run_forward(code: &str, local: u16, relay: &str) -> Result<()> {
+    let ticket = resolve_ticket(code, relay).await?;
+    let node = start_node(None).await?;
+
+    let local_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, local));
+    let events = node.forward(ticket, local_addr).await?;
+
+    println!(
+        "\n{} Forwarding {} to the remote port",
+        accent(symbol("⚡")),
+        success(local_addr)
+    );
+
+    watch_events(events).await?;
+    node.shutdown().await?;
+    Ok(())
+}
+
+/// Print tunnel events until Ctrl+C or a fatal error
+async fn watch_events(mut events: mpsc::Receiver<TunnelEvent>) -> Result<()> {
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    return Ok(());
+                };
+                match event {
+                    TunnelEvent::Listening => {
+                        println!("{}", style("Waiting for peer... (Ctrl+C to stop)").dim());
+                    }
+                    TunnelEvent::Connected => {
+                        println!("{}", success("Peer connected!"));
+                    }
+                    TunnelEvent::StreamOpened => {
+                        println!("{}", style("  connection opened").dim());
+                    }
+                    TunnelEvent::StreamClosed {
+                        bytes_sent,
+                        bytes_received,
+                    } => {
+                        println!(
+                            "{}",
+                            style(format!(
+                                "  connection closed ({} sent, {} received)",
+                                format_bytes(bytes_sent),
+                                format_bytes(bytes_received)
+                            ))
+                            .dim()
+                        );
+                    }
+                    TunnelEvent::StreamError(e) => {
+                        eprintln!("{} {}", warning(symbol("⚠")), e);
+                    }
+                    TunnelEvent::Error(e) => {
+                        anyhow::bail!("Tunnel failed: {}", e);
+                    }
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                println!("\n{}", style("Tunnel closed").dim());
+                return Ok(());
+            }
+        }
+    }
+}
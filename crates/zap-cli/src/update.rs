@@ -0,0 +1,238 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use console::style;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::output::{accent, success, symbol, warning};
+
+/// Minisign public key used to verify release artifacts, baked in at build time
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("ZAP_RELEASE_PUBKEY");
+
+/// Release manifest served by the relay at `/api/releases/latest`
+#[derive(Debug, Deserialize)]
+struct ReleaseManifest {
+    version: String,
+    artifacts: Vec<ReleaseArtifact>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseArtifact {
+    platform: String,
+    url: String,
+    sha256: String,
+    /// Contents of the `.minisig` file for this artifact
+    minisig: Option<String>,
+}
+
+/// Check the relay for a newer release and replace the running binary with it
+pub async fn run_update(relay: String, check_only: bool, skip_verify: bool) -> Result<()> {
+    let current = env!("CARGO_PKG_VERSION");
+
+    println!("{} Checking for updates...", accent(symbol("⚡")));
+    let manifest = fetch_manifest(&relay).await?;
+
+    if !is_newer(&manifest.version, current) {
+        println!(
+            "{} Already up to date ({})",
+            success(symbol("✓")).bold(),
+            accent(current)
+        );
+        return Ok(());
+    }
+
+    println!(
+        "New version available: {} (installed: {})",
+        success(&manifest.version).bold(),
+        current
+    );
+
+    if check_only {
+        return Ok(());
+    }
+
+    let platform = current_platform();
+    let artifact = manifest
+        .artifacts
+        .iter()
+        .find(|a| a.platform == platform)
+        .with_context(|| format!("No release artifact for platform {}", platform))?;
+
+    println!("Downloading {}", style(&artifact.url).dim());
+    let client = reqwest::Client::new();
+    let resp = client.get(&artifact.url).send().await?;
+    if !resp.status().is_success() {
+        anyhow::bail!("Download failed: {}", resp.status());
+    }
+    let bytes = resp.bytes().await?;
+
+    verify_sha256(&bytes, &artifact.sha256)?;
+
+    if skip_verify {
+        eprintln!("{} Skipping signature verification", warning(symbol("⚠")));
+    } else {
+        let minisig = artifact
+            .minisig
+            .as_deref()
+            .context("Release artifact is not signed")?;
+        verify_signature(&bytes, minisig, &manifest.version, &artifact_name(&platform))?;
+    }
+
+    let exe = std::env::current_exe()?;
+    replace_binary(&exe, &bytes)?;
+
+    println!(
+        "\n{} Updated to {}",
+        success(symbol("✓")).bold(),
+        accent(&manifest.version)
+    );
+    Ok(())
+}
+
+async fn fetch_manifest(relay: &str) -> Result<ReleaseManifest> {
+    let client = crate::tls::relay_client()?;
+    let resp = client
+        .get(format!("{}/api/releases/latest", relay))
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        anyhow::bail!("Relay returned error: {}", resp.status());
+    }
+
+    Ok(resp.json().await?)
+}
+
+/// Platform identifier as used in release artifact names (e.g. `linux-x86_64`)
+pub(crate) fn current_platform() -> String {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        other => other,
+    };
+    let arch = match std::env::consts::ARCH {
+        "aarch64" => "arm64",
+        // Releases are built for ARMv7 with hardware floats, as on a
+        // Raspberry Pi 2 or later
+        "arm" => "armv7",
+        other => other,
+    };
+    format!("{}-{}", os, arch)
+}
+
+/// Name of a platform's release artifact, which its signature's trusted
+/// comment names
+fn artifact_name(platform: &str) -> String {
+    format!("zap-{}", platform)
+}
+
+/// Compare dotted version strings, ignoring a leading `v`
+fn is_newer(candidate: &str, current: &str) -> bool {
+    fn parse(v: &str) -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split('.')
+            .map(|p| p.parse().unwrap_or(0))
+            .collect()
+    }
+    parse(candidate) > parse(current)
+}
+
+fn verify_sha256(bytes: &[u8], expected: &str) -> Result<()> {
+    let actual: String = Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        anyhow::bail!("Checksum mismatch: expected {}, got {}", expected, actual);
+    }
+    Ok(())
+}
+
+/// Check `bytes` were signed with the built-in key as the release `version`
+/// of the artifact `name`
+fn verify_signature(bytes: &[u8], minisig: &str, version: &str, name: &str) -> Result<()> {
+    // Only the key baked in at build time is trusted, never one from the
+    // environment the update runs in
+    let key = RELEASE_PUBLIC_KEY.context(
+        "No release public key built in; rebuild with ZAP_RELEASE_PUBKEY or pass --skip-verify",
+    )?;
+
+    let public_key = minisign_verify::PublicKey::from_base64(key.trim())
+        .map_err(|e| anyhow::anyhow!("Invalid release public key: {}", e))?;
+    let signature = minisign_verify::Signature::decode(minisig)
+        .map_err(|e| anyhow::anyhow!("Invalid signature: {}", e))?;
+
+    public_key
+        .verify(bytes, &signature, false)
+        .map_err(|e| anyhow::anyhow!("Signature verification failed: {}", e))?;
+    check_trusted_comment(signature.trusted_comment(), version, name)
+}
+
+/// Check a verified signature's trusted comment, `zap v<version> <name>` as
+/// `cargo xtask dist` writes it, so an older release or another platform's
+/// binary can't be passed off with its own valid signature
+fn check_trusted_comment(comment: &str, version: &str, name: &str) -> Result<()> {
+    let expected = format!("zap v{} {}", version.trim_start_matches('v'), name);
+    if comment.trim() != expected {
+        anyhow::bail!("Signature is for {:?}, not {:?}", comment.trim(), expected);
+    }
+    Ok(())
+}
+
+/// Atomically swap the running executable for the downloaded one
+fn replace_binary(exe: &Path, bytes: &[u8]) -> Result<()> {
+    let staged = exe.with_extension("new");
+    std::fs::write(&staged, bytes)
+        .with_context(|| format!("Could not write {}", staged.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    // Windows can't overwrite a running executable, but it can rename it
+    #[cfg(windows)]
+    {
+        let old = exe.with_extension("old");
+        let _ = std::fs::remove_file(&old);
+        std::fs::rename(exe, &old)?;
+    }
+
+    std::fs::rename(&staged, exe)
+        .with_context(|| format!("Could not replace {}", exe.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.2.0", "0.1.0"));
+        assert!(is_newer("v0.1.1", "0.1.0"));
+        assert!(is_newer("1.0.0", "0.9.9"));
+        assert!(!is_newer("0.1.0", "0.1.0"));
+        assert!(!is_newer("v0.0.9", "0.1.0"));
+    }
+
+    #[test]
+    fn test_verify_sha256() {
+        let digest = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(verify_sha256(b"hello", digest).is_ok());
+        assert!(verify_sha256(b"hello", &digest.to_uppercase()).is_ok());
+        assert!(verify_sha256(b"hell0", digest).is_err());
+    }
+
+    #[test]
+    fn test_trusted_comment() {
+        let name = artifact_name("linux-x86_64");
+        assert!(check_trusted_comment("zap v0.3.0 zap-linux-x86_64", "0.3.0", &name).is_ok());
+        assert!(check_trusted_comment("zap v0.3.0 zap-linux-x86_64", "v0.3.0", &name).is_ok());
+        assert!(check_trusted_comment("zap v0.2.0 zap-linux-x86_64", "0.3.0", &name).is_err());
+        assert!(check_trusted_comment("zap v0.3.0 zap-darwin-arm64", "0.3.0", &name).is_err());
+        assert!(check_trusted_comment("timestamp:1700000000", "0.3.0", &name).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! `zap stats`: how much this machine has sent and received each month,
+//! for people on metered connections
+//!
+//! Totals are kept in `usage.toml` next to the config, one entry per
+//! month. `zap send` and `zap receive` add what they moved when they're
+//! done, including what a failed transfer got through. With a limit set,
+//! the transfer that takes the month past it says so.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use console::style;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::format_bytes;
+use crate::output::{accent, success, symbol, warning};
+
+/// Which way a transfer went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Sent,
+    Received,
+}
+
+/// Bytes moved in one month
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Month {
+    pub(crate) sent: u64,
+    pub(crate) received: u64,
+}
+
+impl Month {
+    pub(crate) fn total(&self) -> u64 {
+        self.sent.saturating_add(self.received)
+    }
+}
+
+/// Every month's totals, by `YYYY-MM`
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Usage {
+    pub(crate) months: BTreeMap<String, Month>,
+}
+
+impl Usage {
+    fn path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("zap").join("usage.toml"))
+    }
+
+    /// Load the totals, starting afresh if there are none or they're
+    /// unreadable
+    pub(crate) fn load() -> Self {
+        Self::path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|text| toml::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path().context("no config directory on this system")?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, toml::to_string(self)?)
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Add `bytes` to `month`, returning its totals before and after
+    fn add(&mut self, month: &str, direction: Direction, bytes: u64) -> (Month, Month) {
+        let entry = self.months.entry(month.to_string()).or_default();
+        let before = *entry;
+        match direction {
+            Direction::Sent => entry.sent = entry.sent.saturating_add(bytes),
+            Direction::Received => entry.received = entry.received.saturating_add(bytes),
+        }
+        (before, *entry)
+    }
+}
+
+/// The month it is now, as usage is filed under
+fn this_month() -> String {
+    chrono::Local::now().format("%Y-%m").to_string()
+}
+
+/// Count a transfer towards this month, warning if it took the month past
+/// the limit. Failing to keep count never fails the transfer.
+pub(crate) fn record(direction: Direction, bytes: u64) {
+    if bytes == 0 {
+        return;
+    }
+    let mut usage = Usage::load();
+    let (before, after) = usage.add(&this_month(), direction, bytes);
+    if let Err(e) = usage.save() {
+        tracing::warn!("could not record usage: {}", e);
+    }
+    if let Some(limit) = Config::load().usage_limit
+        && crossed(limit, &before, &after)
+    {
+        eprintln!(
+            "{} {} moved this month, over your limit of {}",
+            warning(symbol("⚠")).bold(),
+            format_bytes(after.total()),
+            format_bytes(limit)
+        );
+    }
+}
+
+/// Whether a month went past `limit` between `before` and `after`
+fn crossed(limit: u64, before: &Month, after: &Month) -> bool {
+    before.total() <= limit && after.total() > limit
+}
+
+/// `zap stats`: show each month's totals, or set or clear the monthly
+/// limit
+pub async fn run_stats(months: usize, limit: Option<u64>, no_limit: bool) -> Result<()> {
+    let mut config = Config::load();
+    if limit.is_some() || no_limit {
+        config.usage_limit = limit;
+        config.save()?;
+        match limit {
+            Some(limit) => println!(
+                "{} You'll be told when a month goes over {}",
+                success(symbol("✓")).bold(),
+                format_bytes(limit)
+            ),
+            None => println!("{} Monthly limit cleared", success(symbol("✓")).bold()),
+        }
+        return Ok(());
+    }
+
+    let usage = Usage::load();
+    if usage.months.is_empty() {
+        println!("{}", style("Nothing sent or received yet").dim());
+    }
+    for (month, totals) in usage.months.iter().rev().take(months) {
+        println!(
+            "{}  sent {:>10}  received {:>10}  total {:>10}",
+            accent(month),
+            format_bytes(totals.sent),
+            format_bytes(totals.received),
+            format_bytes(totals.total())
+        );
+    }
+    if let Some(limit) = config.usage_limit {
+        let used = usage.months.get(&this_month()).map_or(0, Month::total);
+        let line = format!(
+            "Limit {} a month, {} used this month",
+            format_bytes(limit),
+            format_bytes(used)
+        );
+        if used > limit {
+            println!("{} {}", warning(symbol("⚠")).bold(), line);
+        } else {
+            println!("{}", style(line).dim());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_and_limit() {
+        let mut usage = Usage::default();
+        usage.add("2026-09", Direction::Received, 500);
+        let (before, after) = usage.add("2026-10", Direction::Sent, 300);
+        assert_eq!(before, Month::default());
+        let (before, after2) = usage.add("2026-10", Direction::Received, 400);
+        assert_eq!(before, after);
+        assert_eq!(
+            after2,
+            Month {
+                sent: 300,
+                received: 400
+            }
+        );
+        assert_eq!(usage.months["2026-09"].total(), 500);
+
+        // Only the transfer that goes past the limit is reported
+        assert!(crossed(600, &before, &after2));
+        assert!(!crossed(800, &before, &after2));
+        assert!(!crossed(200, &before, &after2));
+
+        let text = toml::to_string(&usage).unwrap();
+        assert_eq!(toml::from_str::<Usage>(&text).unwrap(), usage);
+    }
+}
@@ -0,0 +1,440 @@
+//! `zap webdav`: browse a shared folder from a file manager
+//!
+//! The sender has to be sharing (`zap send --share`), since every download
+//! is its own transfer. The listing comes from the offer alone; a file is
+//! only fetched when it's first opened, by accepting just that entry, and
+//! then kept in a temporary directory for later requests.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use axum::Router;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode, Uri, header};
+use axum::response::{IntoResponse, Response};
+use console::style;
+use tokio::sync::OnceCell;
+use zap_core::directory::safe_join;
+use zap_core::protocol::{DirEntry, OfferKind};
+use zap_core::{EntryChooser, ReceiveOptions, ReceiveProgress, Ticket, ZapNode};
+
+use crate::code::resolve_ticket;
+use crate::format_bytes;
+use crate::net::start_node;
+use crate::output::{accent, success, symbol, warning};
+
+/// Serve a sharing sender's files over WebDAV on localhost until Ctrl-C
+pub async fn run_webdav(code: String, port: u16, relay: String) -> Result<()> {
+    let ticket = resolve_ticket(&code, &relay).await?;
+    let node = start_node(None).await?;
+
+    let offer = node.peek(&ticket).await?;
+    let (entries, is_dir) = match offer.kind {
+        OfferKind::Directory { entries } => (entries, true),
+        OfferKind::File => {
+            let Some(size) = offer.size else {
+                anyhow::bail!("{} is a stream, which can't be browsed", offer.name);
+            };
+            let entry = DirEntry {
+                path: offer.name.clone(),
+                size,
+            };
+            (vec![entry], false)
+        }
+        OfferKind::Archive => anyhow::bail!(
+            "{} is sent as an archive, which can't be browsed; ask the sender to share it \
+             without --auto-archive",
+            offer.name
+        ),
+    };
+
+    let cache = std::env::temp_dir().join(format!("zap-webdav-{:016x}", rand::random::<u64>()));
+    tokio::fs::create_dir_all(&cache).await?;
+
+    let node = Arc::new(node);
+    let dav = Arc::new(Dav {
+        node: node.clone(),
+        ticket,
+        name: offer.name.clone(),
+        entries,
+        is_dir,
+        cache: cache.clone(),
+        fetches: Mutex::new(HashMap::new()),
+    });
+
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("listening on {}", addr))?;
+
+    println!(
+        "\n{} Browsing {} ({} files, {})",
+        accent(symbol("⚡")),
+        accent(&offer.name),
+        dav.entries.len(),
+        format_bytes(dav.entries.iter().map(|e| e.size).sum())
+    );
+    println!(
+        "  Connect your file manager to {}",
+        success(format!("http://{}/", addr)).bold()
+    );
+    println!(
+        "  {}",
+        style("Read-only; files are downloaded when opened. Press Ctrl-C to stop").dim()
+    );
+
+    let app = Router::new().fallback(handle).with_state(dav);
+    let served = axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await;
+
+    let _ = tokio::fs::remove_dir_all(&cache).await;
+    if let Ok(node) = Arc::try_unwrap(node) {
+        node.shutdown().await?;
+    }
+    served?;
+    Ok(())
+}
+
+/// What the WebDAV server knows about the share
+struct Dav {
+    node: Arc<ZapNode>,
+    ticket: Ticket,
+    /// The offered name, which fetched directories are saved under
+    name: String,
+    entries: Vec<DirEntry>,
+    /// Whether the offer is a directory, rather than a single file
+    is_dir: bool,
+    cache: PathBuf,
+    /// Files fetched or being fetched, by entry index
+    fetches: Mutex<HashMap<usize, Arc<OnceCell<PathBuf>>>>,
+}
+
+impl Dav {
+    /// The local copy of an entry, fetching it first if needed
+    ///
+    /// Concurrent requests for the same file share one fetch; a failed
+    /// fetch is tried again on the next request.
+    async fn fetch(&self, index: usize) -> Result<PathBuf> {
+        let cell = self
+            .fetches
+            .lock()
+            .unwrap()
+            .entry(index)
+            .or_default()
+            .clone();
+        let path = cell.get_or_try_init(|| self.receive(index)).await?;
+        Ok(path.clone())
+    }
+
+    async fn receive(&self, index: usize) -> Result<PathBuf> {
+        let entry = &self.entries[index];
+        let options = ReceiveOptions {
+            choose_entries: self
+                .is_dir
+                .then(|| EntryChooser::new(move |_| async move { Some(vec![index]) })),
+            ..Default::default()
+        };
+        let mut progress_rx = self
+            .node
+            .receive_with_options(self.ticket.clone(), Some(&self.cache), options)
+            .await?;
+
+        while let Some(progress) = progress_rx.recv().await {
+            match progress {
+                ReceiveProgress::Complete { path, .. } => {
+                    println!(
+                        "{} Fetched {} ({})",
+                        success(symbol("✓")),
+                        entry.path,
+                        format_bytes(entry.size)
+                    );
+                    if !self.is_dir {
+                        return Ok(path);
+                    }
+                    return Ok(safe_join(&self.cache.join(&self.name), &entry.path)?);
+                }
+                ReceiveProgress::Error(e) => anyhow::bail!("fetching {}: {}", entry.path, e),
+                _ => {}
+            }
+        }
+        anyhow::bail!("fetching {} ended early", entry.path)
+    }
+}
+
+/// An item in the share's tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    File { index: usize, size: u64 },
+    Dir,
+}
+
+/// Find what a `/`-separated path inside the share is, without the
+/// leading slash; the empty path is the root
+fn lookup(entries: &[DirEntry], path: &str) -> Option<Item> {
+    if path.is_empty() {
+        return Some(Item::Dir);
+    }
+    if let Some(index) = entries.iter().position(|e| e.path == path) {
+        return Some(Item::File {
+            index,
+            size: entries[index].size,
+        });
+    }
+    let prefix = format!("{}/", path);
+    entries
+        .iter()
+        .any(|e| e.path.starts_with(&prefix))
+        .then_some(Item::Dir)
+}
+
+/// The names directly inside a directory, sorted, with what they are
+fn children(entries: &[DirEntry], dir: &str) -> Vec<(String, Item)> {
+    let prefix = if dir.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", dir)
+    };
+
+    let mut children: Vec<(String, Item)> = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let Some(rest) = entry.path.strip_prefix(&prefix) else {
+            continue;
+        };
+        let item = match rest.split_once('/') {
+            Some((name, _)) => (name.to_string(), Item::Dir),
+            None => (
+                rest.to_string(),
+                Item::File {
+                    index,
+                    size: entry.size,
+                },
+            ),
+        };
+        if !children.iter().any(|(name, _)| *name == item.0) {
+            children.push(item);
+        }
+    }
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+    children
+}
+
+async fn handle(
+    State(dav): State<Arc<Dav>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let Some(path) = decode_path(uri.path()) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let path = path.trim_matches('/').to_string();
+
+    match method.as_str() {
+        "OPTIONS" => (
+            StatusCode::OK,
+            [
+                (header::HeaderName::from_static("dav"), "1"),
+                (header::ALLOW, "OPTIONS, PROPFIND, GET, HEAD"),
+            ],
+        )
+            .into_response(),
+        "PROPFIND" => {
+            let Some(item) = lookup(&dav.entries, &path) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let depth_zero = headers
+                .get("depth")
+                .is_some_and(|depth| depth.as_bytes() == b"0");
+            let mut items = vec![(path.clone(), item.clone())];
+            if item == Item::Dir && !depth_zero {
+                for (name, child) in children(&dav.entries, &path) {
+                    let child_path = if path.is_empty() {
+                        name
+                    } else {
+                        format!("{}/{}", path, name)
+                    };
+                    items.push((child_path, child));
+                }
+            }
+            (
+                StatusCode::MULTI_STATUS,
+                [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+                multistatus(&items),
+            )
+                .into_response()
+        }
+        "GET" | "HEAD" => {
+            let Some(Item::File { index, size }) = lookup(&dav.entries, &path) else {
+                return StatusCode::NOT_FOUND.into_response();
+            };
+            let headers = [
+                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+                (header::CONTENT_LENGTH, size.to_string()),
+            ];
+            if method == Method::HEAD {
+                return (headers, Body::empty()).into_response();
+            }
+
+            let file = match dav.fetch(index).await {
+                Ok(local) => tokio::fs::File::open(local).await.map_err(Into::into),
+                Err(e) => Err(e),
+            };
+            match file {
+                Ok(file) => {
+                    let body = Body::from_stream(tokio_util::io::ReaderStream::new(file));
+                    (headers, body).into_response()
+                }
+                Err(e) => {
+                    eprintln!("{} {}", warning(symbol("⚠")), e);
+                    (StatusCode::BAD_GATEWAY, e.to_string()).into_response()
+                }
+            }
+        }
+        // Everything that would change the share
+        _ => StatusCode::FORBIDDEN.into_response(),
+    }
+}
+
+/// A PROPFIND response describing `items`
+fn multistatus(items: &[(String, Item)]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    for (path, item) in items {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        let (href, props) = match item {
+            Item::Dir => (
+                format!(
+                    "/{}{}",
+                    encode_path(path),
+                    if path.is_empty() { "" } else { "/" }
+                ),
+                "<D:resourcetype><D:collection/></D:resourcetype>".to_string(),
+            ),
+            Item::File { size, .. } => (
+                format!("/{}", encode_path(path)),
+                format!(
+                    "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+                     <D:getcontenttype>application/octet-stream</D:getcontenttype>",
+                    size
+                ),
+            ),
+        };
+        xml.push_str(&format!(
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+             <D:displayname>{}</D:displayname>{}</D:prop>\
+             <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+            escape_xml(&href),
+            escape_xml(name),
+            props
+        ));
+    }
+    xml.push_str("</D:multistatus>\n");
+    xml
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Percent-encode each segment of a path, keeping the slashes
+fn encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+/// Decode a percent-encoded request path, if it's valid UTF-8
+pub(crate) fn decode_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<DirEntry> {
+        ["b.txt", "photos/2024/x.jpg", "photos/a.jpg", "a.txt"]
+            .iter()
+            .map(|path| DirEntry {
+                path: path.to_string(),
+                size: 3,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tree() {
+        let entries = entries();
+        assert_eq!(lookup(&entries, ""), Some(Item::Dir));
+        assert_eq!(lookup(&entries, "photos"), Some(Item::Dir));
+        assert_eq!(
+            lookup(&entries, "photos/a.jpg"),
+            Some(Item::File { index: 2, size: 3 })
+        );
+        assert_eq!(lookup(&entries, "phot"), None);
+
+        let names = |dir| {
+            children(&entries, dir)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(""), ["a.txt", "b.txt", "photos"]);
+        assert_eq!(names("photos"), ["2024", "a.jpg"]);
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(encode_path("my docs/a&b.txt"), "my%20docs/a%26b.txt");
+        assert_eq!(
+            decode_path("/my%20docs/caf%C3%A9.txt").unwrap(),
+            "/my docs/café.txt"
+        );
+        assert_eq!(decode_path("/bad%2"), None);
+        assert_eq!(decode_path("/bad%FF"), None);
+    }
+
+    #[test]
+    fn test_multistatus() {
+        let xml = multistatus(&[
+            (String::new(), Item::Dir),
+            ("a <1>.txt".to_string(), Item::File { index: 0, size: 3 }),
+        ]);
+        assert!(xml.contains("<D:href>/</D:href>"));
+        assert!(xml.contains("<D:collection/>"));
+        assert!(xml.contains("<D:href>/a%20%3C1%3E.txt</D:href>"));
+        assert!(xml.contains("<D:displayname>a &lt;1&gt;.txt</D:displayname>"));
+        assert!(xml.contains("<D:getcontentlength>3</D:getcontentlength>"));
+    }
+}
@@ -0,0 +1,225 @@
+//! Sharing a sender's bandwidth fairly between its receivers
+//!
+//! Every receiver gets a token bucket of its own, refilled at its share of
+//! the cap. Shares start out even and are worked out again about once a
+//! second from what each receiver actually took: one that couldn't use its
+//! whole share, say over a slow link, keeps a little more than it used, and
+//! the rest is split evenly between the others. So a fast receiver can't
+//! starve the others, and a slow one doesn't hold bandwidth back from them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::protocol::CHUNK_SIZE;
+
+/// How often shares are worked out again
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes a receiver can send at once after waiting a while
+const BURST: f64 = (CHUNK_SIZE * 4) as f64;
+
+/// The smallest share a receiver is given, so one that stalled for a
+/// moment isn't stuck waiting for a whole chunk's tokens
+const MIN_SHARE: u64 = CHUNK_SIZE as u64;
+
+/// A receiver that didn't use its whole share gets this much more than it
+/// used, so it can speed up again
+const HEADROOM: f64 = 1.25;
+
+/// A limit on how fast a send goes out, shared fairly between receivers
+///
+/// Pass it in [`SendOptions::bandwidth`](crate::SendOptions::bandwidth).
+/// Clones share the same limit, so one `Bandwidth` passed to several sends
+/// caps them all together.
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Bytes a second for all receivers together
+    max_rate: Option<u64>,
+    /// Bytes a second for any one receiver
+    per_receiver: Option<u64>,
+    lanes: HashMap<u64, Lane>,
+    next_id: u64,
+    rebalanced: Instant,
+}
+
+/// One receiver's bucket
+#[derive(Debug)]
+struct Lane {
+    /// Bytes a second, `u64::MAX` when unlimited
+    share: u64,
+    tokens: f64,
+    refilled: Instant,
+    /// When `sent` started counting
+    since: Instant,
+    sent: u64,
+    /// What the receiver used at the last rebalance, if it left some of
+    /// its share unused
+    demand: Option<u64>,
+}
+
+impl Bandwidth {
+    /// Send at most `max_rate` bytes a second to all receivers together,
+    /// and at most `per_receiver` to any one of them
+    pub fn new(max_rate: Option<u64>, per_receiver: Option<u64>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                max_rate,
+                per_receiver,
+                lanes: HashMap::new(),
+                next_id: 0,
+                rebalanced: Instant::now(),
+            })),
+        }
+    }
+
+    /// Start pacing a receiver, until the returned [`Pace`] is dropped
+    pub(crate) fn join(&self) -> Pace {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.lanes.insert(
+            id,
+            Lane {
+                share: 0,
+                tokens: BURST,
+                refilled: now,
+                since: now,
+                sent: 0,
+                demand: None,
+            },
+        );
+        state.assign();
+        Pace {
+            bandwidth: self.clone(),
+            id,
+        }
+    }
+}
+
+impl State {
+    /// Give every lane its share, from the demands last measured
+    fn assign(&mut self) {
+        let ids: Vec<u64> = self.lanes.keys().copied().collect();
+        let shares = match self.max_rate {
+            Some(max_rate) => {
+                let demands: Vec<_> = ids
+                    .iter()
+                    .map(|id| self.lanes[id].demand.map(|d| d.max(MIN_SHARE)))
+                    .collect();
+                fair_shares(max_rate, &demands)
+            }
+            None => vec![u64::MAX; ids.len()],
+        };
+        for (id, share) in ids.iter().zip(shares) {
+            let share = self.per_receiver.map_or(share, |max| share.min(max));
+            self.lanes.get_mut(id).expect("listed above").share = share.max(1);
+        }
+    }
+
+    /// Measure what every lane used since the last rebalance and share the
+    /// cap out again
+    fn rebalance(&mut self, now: Instant) {
+        self.rebalanced = now;
+        for lane in self.lanes.values_mut() {
+            let elapsed = now.duration_since(lane.since).as_secs_f64();
+            let used = lane.sent as f64 / elapsed.max(f64::EPSILON);
+            lane.since = now;
+            lane.sent = 0;
+            // Lanes that used nearly all of their share might want more
+            lane.demand = (used < lane.share as f64 * 0.9).then_some((used * HEADROOM) as u64);
+        }
+        self.assign();
+    }
+
+    /// Take `bytes` from a lane's bucket, or say how long until it has
+    /// tokens again
+    fn take(&mut self, id: u64, bytes: u64, now: Instant) -> Option<Duration> {
+        if now.duration_since(self.rebalanced) >= REBALANCE_INTERVAL {
+            self.rebalance(now);
+        }
+        let lane = self.lanes.get_mut(&id)?;
+        if lane.share == u64::MAX {
+            lane.sent += bytes;
+            return None;
+        }
+        let elapsed = now.duration_since(lane.refilled).as_secs_f64();
+        lane.tokens = (lane.tokens + elapsed * lane.share as f64).min(BURST);
+        lane.refilled = now;
+        if lane.tokens <= 0.0 {
+            return Some(Duration::from_secs_f64(-lane.tokens / lane.share as f64));
+        }
+        // A chunk may be bigger than what's left; the lane owes the rest
+        lane.tokens -= bytes as f64;
+        lane.sent += bytes;
+        None
+    }
+}
+
+/// A receiver's place in a [`Bandwidth`]
+#[derive(Debug)]
+pub(crate) struct Pace {
+    bandwidth: Bandwidth,
+    id: u64,
+}
+
+impl Pace {
+    /// Wait until `bytes` may be sent to this receiver
+    pub(crate) async fn take(&mut self, bytes: u64) {
+        loop {
+            let wait = self
+                .bandwidth
+                .state
+                .lock()
+                .unwrap()
+                .take(self.id, bytes, Instant::now());
+            match wait {
+                // Shares can change while waiting, so look again now and then
+                Some(wait) => tokio::time::sleep(wait.min(REBALANCE_INTERVAL)).await,
+                None => return,
+            }
+        }
+    }
+
+    /// This receiver's share in bytes a second, if it's limited
+    pub(crate) fn share(&self) -> Option<u64> {
+        let state = self.bandwidth.state.lock().unwrap();
+        state
+            .lanes
+            .get(&self.id)
+            .map(|lane| lane.share)
+            .filter(|share| *share != u64::MAX)
+    }
+}
+
+impl Drop for Pace {
+    fn drop(&mut self) {
+        let mut state = self.bandwidth.state.lock().unwrap();
+        state.lanes.remove(&self.id);
+        state.assign();
+    }
+}
+
+/// Split `max_rate` max-min fairly: receivers that want less than an even
+/// split get what they want, and the others split what's left evenly.
+/// `None` is a receiver that wants all it can get.
+pub(crate) fn fair_shares(max_rate: u64, demands: &[Option<u64>]) -> Vec<u64> {
+    let mut order: Vec<usize> = (0..demands.len()).collect();
+    order.sort_by_key(|&i| demands[i].unwrap_or(u64::MAX));
+
+    let mut shares = vec![0; demands.len()];
+    let mut left = max_rate;
+    for (served, &i) in order.iter().enumerate() {
+        let even = left / (demands.len() - served) as u64;
+        let share = demands[i].map_or(even, |demand| demand.min(even));
+        shares[i] = share;
+        left -= share;
+    }
+    shares
+}
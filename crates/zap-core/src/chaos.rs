@@ -0,0 +1,272 @@
+//! A flaky transport for testing how receives cope with a bad network
+//!
+//! [`Flaky`] wraps the sender a receiver talks to and gets in the way of
+//! what it says: every frame can arrive late, one can stall for a while,
+//! two chunks can swap places, a chunk can be cut short, the stream can
+//! break while the connection stays up, and the connection can drop after
+//! a given frame. A gap longer than the idle
+//! timeout drops the connection too, as QUIC's does, so senders have to
+//! keep a paused transfer alive with heartbeats.
+//!
+//! [`ScriptedSender`] is the sender on the other end: a file offered and
+//! sent from memory, answering the receiver the way a real sender would.
+//! Tests run with tokio's clock paused, so the delays cost nothing.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+
+use crate::protocol::{ChunkData, FileOffer, Message, OfferKind};
+use crate::transfer::{self, Peer, ReceiveOptions, ReceiveProgress};
+use crate::tree::HashTree;
+use crate::{Error, Result};
+
+/// What goes wrong on the way from the sender, by the index of the frame
+/// the receiver reads, counting the offer as `0`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Faults {
+    /// Added before every frame
+    pub(crate) latency: Duration,
+    /// A frame held up this much longer than the others
+    pub(crate) stall: Option<(usize, Duration)>,
+    /// A frame that swaps places with the one after it
+    pub(crate) reorder: Option<usize>,
+    /// A chunk that loses the second half of its data
+    pub(crate) truncate: Option<usize>,
+    /// The stream breaks instead of delivering this frame, and the
+    /// receiver can open a new one
+    pub(crate) reset: Option<usize>,
+    /// The connection drops instead of delivering this frame
+    pub(crate) disconnect: Option<usize>,
+    /// The connection drops if nothing arrives for this long
+    pub(crate) idle_timeout: Option<Duration>,
+}
+
+/// A sender seen through a transport with [`Faults`]
+pub(crate) struct Flaky<P> {
+    inner: P,
+    faults: Faults,
+    /// Frames read so far
+    frames: usize,
+    /// A frame read early to deliver another first
+    held: Option<Message>,
+    /// The stream broke and hasn't been reopened
+    broken: bool,
+}
+
+impl<P: Peer> Flaky<P> {
+    pub(crate) fn new(inner: P, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults,
+            frames: 0,
+            held: None,
+            broken: false,
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+impl<P: Peer> Peer for Flaky<P> {
+    async fn send(&mut self, msg: &Message) -> Result<()> {
+        self.inner.send(msg).await
+    }
+
+    async fn recv(&mut self) -> Result<Message> {
+        let index = self.frames;
+        self.frames += 1;
+        if self.faults.disconnect == Some(index) {
+            return Err(Error::ConnectionFailed("connection lost".into()));
+        }
+        if self.faults.reset == Some(index) {
+            self.broken = true;
+            return Err(Error::TransferFailed("stream reset".into()));
+        }
+
+        let mut delay = self.faults.latency;
+        if let Some((at, stall)) = self.faults.stall
+            && at == index
+        {
+            delay += stall;
+        }
+        if let Some(idle) = self.faults.idle_timeout
+            && delay > idle
+        {
+            tokio::time::sleep(idle).await;
+            return Err(Error::ConnectionFailed("timed out".into()));
+        }
+        tokio::time::sleep(delay).await;
+
+        let mut msg = match self.held.take() {
+            Some(msg) => msg,
+            None => self.inner.recv().await?,
+        };
+        if self.faults.reorder == Some(index) {
+            let next = self.inner.recv().await?;
+            self.held = Some(std::mem::replace(&mut msg, next));
+        }
+        if self.faults.truncate == Some(index)
+            && let Message::Chunk(chunk) = &mut msg
+        {
+            chunk.data.truncate(chunk.data.len() / 2);
+        }
+        Ok(msg)
+    }
+
+    async fn reject(&mut self, reason: String) -> Error {
+        self.inner.reject(reason).await
+    }
+
+    async fn reopen(&mut self) -> Result<()> {
+        if !std::mem::take(&mut self.broken) {
+            return Err(Error::ConnectionFailed("connection lost".into()));
+        }
+        // What was in flight on the old stream is gone
+        self.held = None;
+        Ok(())
+    }
+}
+
+/// A sender offering `data` as a file, in chunks of `chunk_size` bytes
+pub(crate) struct ScriptedSender {
+    name: String,
+    data: Vec<u8>,
+    chunk_size: usize,
+    /// Heartbeats sent while paused after this many chunks
+    pause: Option<(usize, usize)>,
+    /// What's still to be sent
+    queue: VecDeque<Message>,
+    /// Everything the receiver said
+    pub(crate) replies: Vec<Message>,
+}
+
+impl ScriptedSender {
+    pub(crate) fn new(name: &str, data: &[u8], chunk_size: usize) -> Self {
+        let offer = Message::Offer(FileOffer {
+            name: name.to_string(),
+            size: Some(data.len() as u64),
+            checksum: None,
+            kind: OfferKind::File,
+            label: None,
+            batch: false,
+        });
+        Self {
+            name: name.to_string(),
+            data: data.to_vec(),
+            chunk_size,
+            pause: None,
+            queue: VecDeque::from([offer]),
+            replies: Vec::new(),
+        }
+    }
+
+    /// Pause after `chunks` chunks, sending `heartbeats` `Pause` frames
+    /// before resuming
+    pub(crate) fn pause_after(mut self, chunks: usize, heartbeats: usize) -> Self {
+        self.pause = Some((chunks, heartbeats));
+        self
+    }
+
+    fn checksum(&self) -> [u8; 32] {
+        *blake3::hash(&self.data).as_bytes()
+    }
+
+    /// Queue the data from `offset` on, and `Done`
+    fn send_data(&mut self, offset: u64) {
+        let skip = offset as usize / self.chunk_size;
+        for (i, data) in self.data.chunks(self.chunk_size).enumerate().skip(skip) {
+            if let Some((after, heartbeats)) = self.pause
+                && after == i
+            {
+                self.queue
+                    .extend(std::iter::repeat_n(Message::Pause, heartbeats));
+                self.queue.push_back(Message::Resume);
+            }
+            self.queue.push_back(Message::Chunk(ChunkData {
+                offset: (i * self.chunk_size) as u64,
+                data: data.to_vec(),
+            }));
+        }
+        self.send_done();
+    }
+
+    fn send_done(&mut self) {
+        self.queue.push_back(Message::Done {
+            checksum: self.checksum(),
+            size: self.data.len() as u64,
+        });
+    }
+}
+
+impl Peer for ScriptedSender {
+    async fn send(&mut self, msg: &Message) -> Result<()> {
+        match msg {
+            Message::Accept => self.send_data(0),
+            Message::AcceptTree => {
+                let pieces = HashTree::of(&self.data).pieces().to_vec();
+                self.queue.push_back(Message::Tree { pieces });
+            }
+            Message::AskChecksum => {
+                let entries = vec![self.checksum()];
+                self.queue.push_back(Message::Checksums { entries });
+            }
+            Message::AlreadyHave { checksum } if *checksum == self.checksum() => self.send_done(),
+            Message::AlreadyHave { .. } => self.send_data(0),
+            // Everything since then is sent again on the new stream
+            Message::Continue { offset } => {
+                self.queue.clear();
+                self.send_data(*offset);
+            }
+            _ => {}
+        }
+        self.replies.push(msg.clone());
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Message> {
+        self.queue
+            .pop_front()
+            .ok_or_else(|| Error::ConnectionFailed(format!("{} has nothing more", self.name)))
+    }
+
+    async fn reject(&mut self, reason: String) -> Error {
+        let _ = self
+            .send(&Message::Reject {
+                reason: reason.clone(),
+            })
+            .await;
+        Error::TransferFailed(reason)
+    }
+}
+
+/// Receive from `peer` into `output_dir`, returning how it ended and the
+/// progress it reported
+pub(crate) async fn receive_from(
+    peer: &mut impl Peer,
+    output_dir: &Path,
+    options: ReceiveOptions,
+) -> (Result<()>, Vec<ReceiveProgress>) {
+    let (tx, mut rx) = mpsc::channel(1024);
+    let result = transfer::receive(
+        peer,
+        Message::Ready,
+        None,
+        Some(output_dir.to_path_buf()),
+        options,
+        &tx,
+        Instant::now(),
+    )
+    .await;
+    drop(tx);
+
+    let mut progress = Vec::new();
+    while let Some(event) = rx.recv().await {
+        progress.push(event);
+    }
+    (result, progress)
+}
@@ -0,0 +1,138 @@
+//! Checking a received file against a hash the receiver got some other way
+//!
+//! A transfer checks its data against what the sender hashed, which can't
+//! tell whether the sender was the right one: a mistyped code, or a relay
+//! that hands out another ticket for it, ends in a perfectly intact file
+//! from someone else. A hash passed along separately, e.g. in a chat
+//! message, catches that.
+
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{Error, Result};
+
+/// The hash a received file is expected to have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// As `zap send`, the web page and `b3sum` give it
+    Blake3([u8; 32]),
+    /// As `sha256sum` gives it
+    Sha256([u8; 32]),
+}
+
+impl Checksum {
+    /// Check the file at `path`, whose BLAKE3 the transfer worked out as
+    /// `blake3`
+    ///
+    /// Only single files have a hash to compare; a folder fails.
+    pub async fn verify(&self, path: &Path, blake3: &[u8; 32]) -> Result<()> {
+        if tokio::fs::metadata(path).await?.is_dir() {
+            return Err(Error::InvalidChecksum(
+                "only a single file can be checked against a checksum".into(),
+            ));
+        }
+        let actual = match self {
+            Self::Blake3(_) => Self::Blake3(*blake3),
+            Self::Sha256(_) => Self::Sha256(sha256_file(path).await?),
+        };
+        if actual != *self {
+            return Err(Error::ChecksumMismatch {
+                expected: self.to_string(),
+                actual: actual.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+async fn sha256_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 256 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// `blake3:<hex>` or `sha256:<hex>`
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (algorithm, hash) = match self {
+            Self::Blake3(hash) => ("blake3", hash),
+            Self::Sha256(hash) => ("sha256", hash),
+        };
+        write!(f, "{}:{}", algorithm, data_encoding::HEXLOWER.encode(hash))
+    }
+}
+
+/// Hex with a `blake3:` or `sha256:` prefix, or BLAKE3 without one; a file
+/// name after the hash, as `b3sum` and `sha256sum` print it, is ignored
+impl std::str::FromStr for Checksum {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.split_whitespace().next().unwrap_or_default();
+        let (algorithm, hex) = s.split_once(':').unwrap_or(("blake3", s));
+        let hash: [u8; 32] = data_encoding::HEXLOWER_PERMISSIVE
+            .decode(hex.as_bytes())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| Error::InvalidChecksum(format!("{:?} isn't 64 hex digits", hex)))?;
+        match algorithm.to_lowercase().as_str() {
+            "blake3" | "b3" => Ok(Self::Blake3(hash)),
+            "sha256" | "sha-256" => Ok(Self::Sha256(hash)),
+            other => Err(Error::InvalidChecksum(format!(
+                "unknown hash {:?}, expected blake3 or sha256",
+                other
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, b"not really a photo").unwrap();
+        let blake3: [u8; 32] = blake3::hash(b"not really a photo").into();
+        let sha256: [u8; 32] = Sha256::digest(b"not really a photo").into();
+
+        let hex = data_encoding::HEXLOWER.encode(&blake3);
+        let expected: Checksum = format!("{}  photo.jpg", hex).parse().unwrap();
+        assert_eq!(expected, Checksum::Blake3(blake3));
+        expected.verify(&path, &blake3).await.unwrap();
+
+        let expected: Checksum = format!("SHA256:{}", data_encoding::HEXUPPER.encode(&sha256))
+            .parse()
+            .unwrap();
+        assert_eq!(expected.to_string().parse::<Checksum>().unwrap(), expected);
+        expected.verify(&path, &blake3).await.unwrap();
+
+        let wrong = Checksum::Sha256(blake3);
+        assert!(matches!(
+            wrong.verify(&path, &blake3).await,
+            Err(Error::ChecksumMismatch { .. })
+        ));
+        assert!(
+            Checksum::Blake3(blake3)
+                .verify(dir.path(), &blake3)
+                .await
+                .is_err()
+        );
+
+        for invalid in ["", "abc", "md5:00", &format!("md5:{}", hex)] {
+            assert!(invalid.parse::<Checksum>().is_err(), "{}", invalid);
+        }
+    }
+}
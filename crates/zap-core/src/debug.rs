@@ -0,0 +1,249 @@
+//! Capturing a transfer's protocol frames and replaying them
+//!
+//! With `ZAP_CAPTURE=<file>` set, or after [`capture_to`], every frame this
+//! process sends or receives is appended to the file, in the encoding it
+//! went over the wire in. A user whose transfer fails can send the capture
+//! along, and [`replay`] feeds the sender's side of it to the receiver
+//! again, without the network it happened on.
+//!
+//! Frames from every transfer in the process go to the same file, so
+//! capture one transfer at a time. Writing them slows transfers down.
+//!
+//! A capture starts with [`MAGIC`]; each frame then has a direction byte
+//! (`0` sent, `1` received), the microseconds since capturing started as a
+//! big-endian `u64`, and the frame itself: its big-endian `u32` length
+//! prefix and the message.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::mem::discriminant;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::protocol::{Message, WireFormat};
+use crate::transfer::{self, Peer, ReceiveOptions, ReceiveProgress};
+use crate::{Error, Result};
+
+/// The start of every capture file
+pub const MAGIC: &[u8; 8] = b"ZAPCAP1\n";
+
+/// Which way a frame went, from where it was captured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A frame read back from a capture
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub direction: Direction,
+    /// When it was captured, from the start of capturing
+    pub at: Duration,
+    pub format: WireFormat,
+    pub message: Message,
+}
+
+struct Recorder {
+    file: BufWriter<File>,
+    started: Instant,
+}
+
+static CAPTURE: OnceLock<Option<Mutex<Recorder>>> = OnceLock::new();
+
+fn open(path: &Path) -> Result<Mutex<Recorder>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.flush()?;
+    Ok(Mutex::new(Recorder {
+        file,
+        started: Instant::now(),
+    }))
+}
+
+/// Capture every frame from now on into a new file at `path`
+///
+/// Fails if capturing has already started, or was already found to be off
+/// because `ZAP_CAPTURE` isn't set and a frame went by.
+pub fn capture_to(path: &Path) -> Result<()> {
+    let recorder = open(path)?;
+    CAPTURE
+        .set(Some(recorder))
+        .map_err(|_| Error::TransferFailed("capturing was already set up".into()))
+}
+
+/// The capture, started from `ZAP_CAPTURE` on first use
+fn capture() -> Option<&'static Mutex<Recorder>> {
+    CAPTURE
+        .get_or_init(|| {
+            let path = PathBuf::from(std::env::var_os("ZAP_CAPTURE")?);
+            match open(&path) {
+                Ok(recorder) => {
+                    debug!(path = %path.display(), "capturing protocol frames");
+                    Some(recorder)
+                }
+                Err(e) => {
+                    warn!(path = %path.display(), "can't capture protocol frames: {}", e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Append a frame to the capture, if there is one
+pub(crate) fn record(direction: Direction, prefix: u32, body: &[u8]) {
+    let Some(capture) = capture() else {
+        return;
+    };
+    let Ok(mut recorder) = capture.lock() else {
+        return;
+    };
+
+    let at = recorder.started.elapsed().as_micros() as u64;
+    let direction = match direction {
+        Direction::Sent => 0u8,
+        Direction::Received => 1,
+    };
+    let written = (|| {
+        recorder.file.write_all(&[direction])?;
+        recorder.file.write_all(&at.to_be_bytes())?;
+        recorder.file.write_all(&prefix.to_be_bytes())?;
+        recorder.file.write_all(body)?;
+        recorder.file.flush()
+    })();
+    if let Err(e) = written {
+        warn!("can't write to the capture: {}", e);
+    }
+}
+
+/// Read every frame of a capture
+pub fn read_capture(path: &Path) -> Result<Vec<Frame>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::Protocol(format!(
+            "{} is not a zap capture",
+            path.display()
+        )));
+    }
+
+    let mut frames = Vec::new();
+    loop {
+        let mut direction = [0u8; 1];
+        match file.read_exact(&mut direction) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let direction = match direction[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            other => {
+                return Err(Error::Protocol(format!(
+                    "unknown frame direction {} in capture",
+                    other
+                )));
+            }
+        };
+
+        let mut header = [0u8; 12];
+        file.read_exact(&mut header)?;
+        let at = u64::from_be_bytes(header[..8].try_into().expect("8 bytes"));
+        let prefix = u32::from_be_bytes(header[8..].try_into().expect("4 bytes"));
+        let (format, len) = WireFormat::from_prefix(prefix);
+
+        let mut body = vec![0u8; len];
+        file.read_exact(&mut body)?;
+        frames.push(Frame {
+            direction,
+            at: Duration::from_micros(at),
+            format,
+            message: Message::decode(format, &body)?,
+        });
+    }
+
+    Ok(frames)
+}
+
+/// Receive the transfer in a capture again, into `output_dir`
+///
+/// The capture may be from either end. The receiver speaks first, so the
+/// direction of the first frame tells which frames were the receiver's;
+/// the others are what the sender said, and are played back in order.
+/// What the receiver says in return isn't sent anywhere, but differences
+/// from the capture are logged.
+pub async fn replay(
+    path: &Path,
+    output_dir: Option<PathBuf>,
+    options: ReceiveOptions,
+    progress: mpsc::Sender<ReceiveProgress>,
+) -> Result<()> {
+    let frames = read_capture(path)?;
+    let Some(first) = frames.first() else {
+        return Err(Error::Protocol("the capture has no frames".into()));
+    };
+    let receiver = first.direction;
+    let ready = first.message.clone();
+
+    let (replies, messages) = frames
+        .into_iter()
+        .partition::<Vec<_>, _>(|frame| frame.direction == receiver);
+    let mut peer = Recording {
+        messages: messages.into_iter(),
+        replies: replies.into_iter(),
+    };
+
+    let _ = progress.send(ReceiveProgress::Connected).await;
+    transfer::receive(
+        &mut peer,
+        ready,
+        None,
+        output_dir,
+        options,
+        &progress,
+        Instant::now(),
+    )
+    .await
+}
+
+/// A sender played back from a capture
+struct Recording {
+    messages: std::vec::IntoIter<Frame>,
+    /// What the receiver said when the capture was made
+    replies: std::vec::IntoIter<Frame>,
+}
+
+impl Peer for Recording {
+    async fn send(&mut self, msg: &Message) -> Result<()> {
+        match self.replies.next() {
+            Some(captured) if discriminant(&captured.message) == discriminant(msg) => {}
+            captured => {
+                let captured = captured.map(|frame| frame.message);
+                warn!(?captured, replayed = ?msg, "receiver replied differently");
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Message> {
+        self.messages
+            .next()
+            .map(|frame| frame.message)
+            .ok_or_else(|| Error::ConnectionFailed("the capture ends here".into()))
+    }
+
+    async fn reject(&mut self, reason: String) -> Error {
+        let _ = self
+            .send(&Message::Reject {
+                reason: reason.clone(),
+            })
+            .await;
+        Error::TransferFailed(reason)
+    }
+}
@@ -0,0 +1,256 @@
+//! Why a connection is relayed or failing, and what might fix it
+//!
+//! Transfers report the path they use as it changes; combined with the
+//! endpoint's view of the local network this explains an opaque timeout
+//! or a slow relayed transfer.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use iroh::endpoint::{Connection, PathInfoList};
+use iroh::{Endpoint, TransportAddr, Watcher};
+use tokio::sync::mpsc;
+
+/// The route a connection takes to the peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionPath {
+    /// Whether data goes straight to the peer rather than through a relay
+    pub direct: bool,
+
+    /// The peer's address on this path, or the relay URL
+    pub remote: String,
+
+    /// Round-trip time on this path
+    pub rtt: Duration,
+
+    /// Direct addresses of the peer tried so far, by hole punching
+    pub direct_attempts: usize,
+}
+
+/// What the endpoint knows about the local network and a connection
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionReport {
+    /// Addresses the endpoint is bound to
+    pub local_addrs: Vec<SocketAddr>,
+
+    /// Public addresses as seen from outside, when discovered
+    pub public_addrs: Vec<SocketAddr>,
+
+    /// Whether UDP works over IPv4 and IPv6
+    pub udp_v4: bool,
+    pub udp_v6: bool,
+
+    /// Whether the NAT maps each destination to a different port, which
+    /// defeats hole punching; `None` when it couldn't be determined
+    pub symmetric_nat: Option<bool>,
+
+    /// The relay this endpoint is homed on
+    pub relay: Option<String>,
+
+    /// The last path of the transfer's connection, if it got that far
+    pub path: Option<ConnectionPath>,
+}
+
+impl ConnectionReport {
+    /// Gather the endpoint's view of the network, with the transfer's path
+    pub(crate) fn gather(endpoint: &Endpoint, path: Option<ConnectionPath>) -> Self {
+        let mut report = ConnectionReport {
+            local_addrs: endpoint.bound_sockets(),
+            path,
+            ..Default::default()
+        };
+
+        if let Some(net) = endpoint.net_report().get() {
+            report.udp_v4 = net.udp_v4;
+            report.udp_v6 = net.udp_v6;
+            report.symmetric_nat = net.mapping_varies_by_dest_ipv4;
+            report.relay = net.preferred_relay.map(|url| url.to_string());
+            report
+                .public_addrs
+                .extend(net.global_v4.map(SocketAddr::V4));
+            report
+                .public_addrs
+                .extend(net.global_v6.map(SocketAddr::V6));
+        }
+
+        report
+    }
+
+    /// Whether the connection only got through a relay, or not at all
+    pub fn is_degraded(&self) -> bool {
+        !self.path.as_ref().is_some_and(|path| path.direct)
+    }
+
+    /// Concrete steps that might get a direct connection
+    pub fn advice(&self) -> Vec<String> {
+        let mut advice = Vec::new();
+        if !self.is_degraded() {
+            return advice;
+        }
+
+        if !self.udp_v4 && !self.udp_v6 {
+            advice.push(
+                "UDP seems to be blocked on this network; allow outbound UDP in your \
+                 firewall or try another network"
+                    .to_string(),
+            );
+        }
+
+        if self.symmetric_nat == Some(true) {
+            advice.push(
+                "Your NAT uses a different port for every destination (symmetric NAT), \
+                 which defeats hole punching; forward a UDP port to this machine and pass \
+                 it with --port"
+                    .to_string(),
+            );
+        }
+
+        match &self.path {
+            Some(path) if path.direct_attempts > 0 => advice.push(format!(
+                "Hole punching tried {} of the peer's addresses without success; if the \
+                 peer is behind a strict firewall, ask them to open a UDP port and use --port",
+                path.direct_attempts
+            )),
+            Some(_) => advice.push(
+                "No direct address of the peer was tried; the peer may only be reachable \
+                 through its relay, so ask them to run with --debug-connection too"
+                    .to_string(),
+            ),
+            None => advice.push(
+                "The peer was never reached; check that the sender is still running and \
+                 that the code hasn't expired"
+                    .to_string(),
+            ),
+        }
+
+        if self.public_addrs.is_empty() && (self.udp_v4 || self.udp_v6) {
+            advice.push(
+                "No public address was discovered for this machine; forwarding a UDP \
+                 port and passing it with --port lets peers reach you directly"
+                    .to_string(),
+            );
+        }
+
+        advice
+    }
+}
+
+impl fmt::Display for ConnectionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |addrs: &[SocketAddr]| {
+            if addrs.is_empty() {
+                "none".to_string()
+            } else {
+                addrs
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+        };
+        let yes_no = |v: bool| if v { "yes" } else { "no" };
+
+        writeln!(f, "Local addresses:    {}", list(&self.local_addrs))?;
+        writeln!(f, "Public addresses:   {}", list(&self.public_addrs))?;
+        writeln!(
+            f,
+            "UDP:                IPv4 {}, IPv6 {}",
+            yes_no(self.udp_v4),
+            yes_no(self.udp_v6)
+        )?;
+        let nat = match self.symmetric_nat {
+            Some(true) => "symmetric (hole punching unlikely to work)",
+            Some(false) => "endpoint-independent",
+            None => "unknown",
+        };
+        writeln!(f, "NAT mapping:        {}", nat)?;
+        writeln!(
+            f,
+            "Home relay:         {}",
+            self.relay.as_deref().unwrap_or("none")
+        )?;
+
+        match &self.path {
+            Some(path) => {
+                let kind = if path.direct { "direct" } else { "relayed" };
+                writeln!(
+                    f,
+                    "Connection:         {} via {} ({} ms)",
+                    kind,
+                    path.remote,
+                    path.rtt.as_millis()
+                )?;
+                write!(f, "Direct attempts:    {}", path.direct_attempts)
+            }
+            None => write!(f, "Connection:         never established"),
+        }
+    }
+}
+
+/// Send the connection's path as progress each time it changes, until the
+/// connection closes
+pub(crate) fn watch_paths<P: Send + 'static>(
+    conn: &Connection,
+    progress: mpsc::Sender<P>,
+    wrap: fn(ConnectionPath) -> P,
+) {
+    let mut paths = conn.paths();
+    tokio::spawn(async move {
+        let mut tried = HashSet::new();
+        let mut last = None;
+        let mut list = paths.get();
+        loop {
+            if let Some(path) = selected_path(&list, &mut tried)
+                && last.as_ref() != Some(&path)
+            {
+                #[cfg(feature = "metrics")]
+                if last.as_ref().is_none_or(|last| last.direct != path.direct) {
+                    crate::metrics::path(path.direct);
+                }
+                last = Some(path.clone());
+                if progress.send(wrap(path)).await.is_err() {
+                    break;
+                }
+            }
+            match paths.updated().await {
+                Ok(next) => list = next,
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// The round trip on the connection's path in use, if it has one yet
+pub(crate) fn rtt(conn: &Connection) -> Option<Duration> {
+    let list = conn.paths().get();
+    list.iter()
+        .find(|path| path.is_selected())
+        .map(|path| path.rtt())
+}
+
+/// The path in use, counting every direct address seen in `tried`
+fn selected_path(list: &PathInfoList, tried: &mut HashSet<SocketAddr>) -> Option<ConnectionPath> {
+    let mut selected = None;
+    for path in list.iter() {
+        if let TransportAddr::Ip(addr) = path.remote_addr() {
+            tried.insert(*addr);
+        }
+        if path.is_selected() {
+            selected = Some(path);
+        }
+    }
+
+    let path = selected?;
+    let remote = match path.remote_addr() {
+        TransportAddr::Ip(addr) => addr.to_string(),
+        TransportAddr::Relay(url) => url.to_string(),
+    };
+    Some(ConnectionPath {
+        direct: !path.is_relay(),
+        remote,
+        rtt: path.rtt(),
+        direct_attempts: tried.len(),
+    })
+}
@@ -0,0 +1,282 @@
+//! Directory sends: walking a tree, packing it into an archive, and
+//! recreating it on the receiving side
+
+#[cfg(feature = "archive")]
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use tokio::fs;
+
+use crate::filename::{local_name, local_path};
+use crate::protocol::DirEntry;
+use crate::transfer::{Durability, ReceivedFile};
+use crate::{Error, Result};
+
+/// When a directory send is packed into a single tar archive instead of
+/// being sent file by file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchivePolicy {
+    /// Archive directories holding more than this many files
+    pub max_files: usize,
+
+    /// Archive directories with files nested deeper than this
+    pub max_depth: usize,
+}
+
+impl Default for ArchivePolicy {
+    fn default() -> Self {
+        Self {
+            max_files: 100,
+            max_depth: 8,
+        }
+    }
+}
+
+impl ArchivePolicy {
+    /// Whether a directory with these entries should be sent as an archive
+    ///
+    /// Never, without the `archive` feature.
+    pub fn should_archive(&self, entries: &[DirEntry]) -> bool {
+        cfg!(feature = "archive")
+            && (entries.len() > self.max_files
+                || entries
+                    .iter()
+                    .any(|e| e.path.split('/').count() > self.max_depth))
+    }
+}
+
+/// List the regular files under `root`, sorted, with `/`-separated paths
+/// relative to it
+///
+/// Symlinks are skipped so a link back up the tree can't loop forever.
+pub fn walk(root: &Path) -> Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        for item in std::fs::read_dir(&dir)? {
+            let item = item?;
+            let file_type = item.file_type()?;
+            let path = item.path();
+
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() {
+                entries.push(DirEntry {
+                    path: relative_path(root, &path)?,
+                    size: item.metadata()?.len(),
+                });
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// List the regular files making up a selection of files and directories
+/// directly inside `root`, with paths relative to it
+pub fn walk_selection(root: &Path, names: &[String]) -> Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+
+    for name in names {
+        let path = root.join(name);
+        let metadata = std::fs::metadata(&path)?;
+        if metadata.is_dir() {
+            entries.extend(walk(&path)?.into_iter().map(|e| DirEntry {
+                path: format!("{}/{}", name, e.path),
+                size: e.size,
+            }));
+        } else {
+            entries.push(DirEntry {
+                path: name.clone(),
+                size: metadata.len(),
+            });
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+fn relative_path(root: &Path, path: &Path) -> Result<String> {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let parts: Option<Vec<&str>> = relative.iter().map(|p| p.to_str()).collect();
+    parts.map(|p| p.join("/")).ok_or_else(|| {
+        Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("file name is not valid UTF-8: {}", path.display()),
+        ))
+    })
+}
+
+/// Pack `entries` from `root` into a tar archive at `dest`
+///
+/// `on_file` is called with the number of files written so far. This does
+/// blocking IO, so run it off the async runtime.
+#[cfg(feature = "archive")]
+pub fn write_archive(
+    root: &Path,
+    entries: &[DirEntry],
+    dest: &Path,
+    on_file: impl FnMut(usize),
+) -> Result<()> {
+    let sources: Vec<_> = entries.iter().map(|e| root.join(&e.path)).collect();
+    write_archive_from(&sources, entries, dest, on_file)
+}
+
+/// Pack files into a tar archive at `dest`, each read from the path at the
+/// same place in `sources` and stored under its entry's path
+#[cfg(feature = "archive")]
+pub(crate) fn write_archive_from(
+    sources: &[PathBuf],
+    entries: &[DirEntry],
+    dest: &Path,
+    mut on_file: impl FnMut(usize),
+) -> Result<()> {
+    let file = std::fs::File::create(dest)?;
+    let mut builder = tar::Builder::new(std::io::BufWriter::new(file));
+
+    for (i, (source, entry)) in sources.iter().zip(entries).enumerate() {
+        builder.append_path_with_name(source, &entry.path)?;
+        on_file(i + 1);
+    }
+
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Pack a whole directory into a tar archive at `dest`
+#[cfg(feature = "archive")]
+pub fn archive_dir(root: &Path, dest: &Path) -> Result<()> {
+    let entries = walk(root)?;
+    write_archive(root, &entries, dest, |_| {})
+}
+
+/// Unpack a tar archive into `dest`, which is created if needed
+///
+/// Entries that would land outside `dest` are skipped. This does blocking
+/// IO, so run it off the async runtime.
+#[cfg(feature = "archive")]
+pub fn unpack_archive(archive: &Path, dest: &Path) -> Result<()> {
+    std::fs::create_dir_all(dest)?;
+    let file = std::fs::File::open(archive)?;
+    tar::Archive::new(std::io::BufReader::new(file)).unpack(dest)?;
+    Ok(())
+}
+
+/// Join a `/`-separated path from an offer onto `root`, rejecting anything
+/// that could escape it
+///
+/// Each part is made into a name this platform can create, see
+/// [`local_name`].
+pub fn safe_join(root: &Path, relative: &str) -> Result<PathBuf> {
+    let mut path = root.to_path_buf();
+
+    for part in relative.split('/') {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (None, _) | (Some(Component::CurDir), None) => {}
+            (Some(Component::Normal(_)), None) => path.push(&*local_name(part)),
+            _ => {
+                return Err(Error::Protocol(format!(
+                    "invalid path in offer: {}",
+                    relative
+                )));
+            }
+        }
+    }
+
+    if path == root {
+        return Err(Error::Protocol(format!(
+            "invalid path in offer: {}",
+            relative
+        )));
+    }
+
+    Ok(path)
+}
+
+/// Recreates a directory from the concatenated contents of its files
+///
+/// Data is split across files using the sizes from the offer, so chunks
+/// don't need to line up with file boundaries.
+pub(crate) struct DirectoryWriter {
+    root: PathBuf,
+    entries: std::vec::IntoIter<DirEntry>,
+    /// The file being written and how many bytes it still expects
+    current: Option<(ReceivedFile, u64)>,
+    durability: Durability,
+}
+
+impl DirectoryWriter {
+    pub(crate) async fn create(
+        root: PathBuf,
+        entries: Vec<DirEntry>,
+        durability: Durability,
+    ) -> Result<Self> {
+        fs::create_dir_all(local_path(&root)).await?;
+
+        let mut writer = Self {
+            root,
+            entries: entries.into_iter(),
+            current: None,
+            durability,
+        };
+        writer.advance().await?;
+        Ok(writer)
+    }
+
+    /// Move on to the next file that expects data, creating any empty
+    /// files along the way
+    async fn advance(&mut self) -> Result<()> {
+        if let Some((file, _)) = self.current.take() {
+            file.finish().await?;
+        }
+
+        for entry in self.entries.by_ref() {
+            let joined = safe_join(&self.root, &entry.path)?;
+            let path = local_path(&joined);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+
+            let file = ReceivedFile::create(&path, self.durability).await?;
+            if entry.size > 0 {
+                self.current = Some((file, entry.size));
+                break;
+            }
+            file.finish().await?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let Some((file, remaining)) = self.current.as_mut() else {
+                return Err(Error::Protocol("more data than the offer describes".into()));
+            };
+
+            let n = (*remaining).min(data.len() as u64) as usize;
+            file.write(&data[..n]).await?;
+            *remaining -= n as u64;
+            data = &data[n..];
+
+            if *remaining == 0 {
+                self.advance().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn finish(self) -> Result<()> {
+        if self.current.is_some() {
+            return Err(Error::TransferFailed(
+                "directory transfer ended early".into(),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -17,9 +19,30 @@ pub enum Error {
     #[error("transfer failed: {0}")]
     TransferFailed(String),
 
+    #[error("stream broken: {0}")]
+    StreamBroken(String),
+
     #[error("protocol error: {0}")]
     Protocol(String),
 
+    #[error("invalid offer: {0}")]
+    InvalidOffer(#[from] crate::validate::OfferViolation),
+
+    #[error("ALPN mismatch: the peer does not accept {0:?}, both sides need the same ALPN")]
+    AlpnMismatch(String),
+
+    #[error("the receiver doesn't have the transfer key")]
+    WrongKey,
+
+    #[error("invalid checksum: {0}")]
+    InvalidChecksum(String),
+
+    #[error("the received file doesn't match the checksum: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
+    #[error("{} changed while it was being sent", .0.display())]
+    FileChanged(PathBuf),
+
     #[error("timeout")]
     Timeout,
 
@@ -41,19 +64,19 @@ impl From<iroh::endpoint::ConnectingError> for Error {
 
 impl From<iroh::endpoint::ClosedStream> for Error {
     fn from(e: iroh::endpoint::ClosedStream) -> Self {
-        Error::TransferFailed(e.to_string())
+        Error::StreamBroken(e.to_string())
     }
 }
 
 impl From<iroh::endpoint::WriteError> for Error {
     fn from(e: iroh::endpoint::WriteError) -> Self {
-        Error::TransferFailed(e.to_string())
+        Error::StreamBroken(e.to_string())
     }
 }
 
 impl From<iroh::endpoint::ReadExactError> for Error {
     fn from(e: iroh::endpoint::ReadExactError) -> Self {
-        Error::TransferFailed(e.to_string())
+        Error::StreamBroken(e.to_string())
     }
 }
 
@@ -69,4 +92,32 @@ impl From<iroh::endpoint::ConnectError> for Error {
     }
 }
 
+impl Error {
+    /// Whether the connection to the peer or a stream on it broke, rather
+    /// than the transfer itself failing
+    pub fn is_interruption(&self) -> bool {
+        matches!(self, Error::ConnectionFailed(_) | Error::StreamBroken(_))
+    }
+
+    /// A short name for the kind of error, to group failures by
+    pub fn category(&self) -> &'static str {
+        match self {
+            Error::Iroh(_) => "iroh",
+            Error::Io(_) => "io",
+            Error::InvalidTicket(_) => "ticket",
+            Error::ConnectionFailed(_) => "connection",
+            Error::TransferFailed(_) => "transfer",
+            Error::StreamBroken(_) => "stream",
+            Error::Protocol(_) => "protocol",
+            Error::InvalidOffer(_) => "offer",
+            Error::AlpnMismatch(_) => "alpn",
+            Error::WrongKey => "key",
+            Error::InvalidChecksum(_) | Error::ChecksumMismatch { .. } => "checksum",
+            Error::FileChanged(_) => "changed",
+            Error::Timeout => "timeout",
+            Error::Cancelled => "cancelled",
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
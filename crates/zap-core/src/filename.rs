@@ -0,0 +1,102 @@
+//! Making received names something this platform can create
+//!
+//! Offers are already checked for names that are hostile everywhere (see
+//! [`crate::validate`]), but a name that's fine on the sender can still
+//! fail `File::create` on the receiver. Windows refuses device names such
+//! as `CON` or `nul.txt`, the characters `<>:"|?*` and names ending in a
+//! dot or space, and paths longer than `MAX_PATH` unless they carry the
+//! `\\?\` prefix. Such names are adjusted rather than refused, so a
+//! transfer from Linux or macOS still arrives.
+//!
+//! Names travel as UTF-8, so whatever arrives always has a valid UTF-16
+//! form. A file or folder sent under a name that isn't valid Unicode, such
+//! as a Windows name with an unpaired surrogate, is offered with the
+//! invalid parts replaced by U+FFFD.
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Characters Windows doesn't allow in file names
+const WINDOWS_FORBIDDEN: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Device names Windows reserves, whatever the extension
+const WINDOWS_RESERVED: &[&str] = &[
+    "con", "prn", "aux", "nul", "conin$", "conout$", "com0", "com1", "com2", "com3", "com4",
+    "com5", "com6", "com7", "com8", "com9", "com¹", "com²", "com³", "lpt0", "lpt1", "lpt2", "lpt3",
+    "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9", "lpt¹", "lpt²", "lpt³",
+];
+
+/// Longest path Windows creates without the `\\?\` prefix, in UTF-16
+/// units; `CreateDirectoryW` stops a little short of `MAX_PATH`
+const WINDOWS_MAX_PATH: usize = 248;
+
+/// A single file name from an offer, changed if needed so it can be
+/// created here
+pub fn local_name(name: &str) -> Cow<'_, str> {
+    if cfg!(windows) {
+        windows_name(name)
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// `path` in a form that can be created here however long it is
+pub fn local_path(path: &Path) -> Cow<'_, Path> {
+    if !cfg!(windows) {
+        return Cow::Borrowed(path);
+    }
+    let Ok(absolute) = std::path::absolute(path) else {
+        return Cow::Borrowed(path);
+    };
+    match absolute.to_str().and_then(verbatim) {
+        Some(long) => Cow::Owned(PathBuf::from(long)),
+        None => Cow::Borrowed(path),
+    }
+}
+
+/// The name Windows can create for `name`: forbidden characters become
+/// `_`, trailing dots and spaces go, and device names get a `_` after them
+pub(crate) fn windows_name(name: &str) -> Cow<'_, str> {
+    let mut fixed: String = name
+        .chars()
+        .map(|c| {
+            if WINDOWS_FORBIDDEN.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    fixed.truncate(fixed.trim_end_matches(['.', ' ']).len());
+    if fixed.is_empty() {
+        fixed.push('_');
+    }
+
+    // `nul.tar.gz` is as much a device as `nul`, and so is `nul .txt`
+    let stem = fixed.split('.').next().unwrap_or_default();
+    let stem_len = stem.len();
+    if WINDOWS_RESERVED.contains(&stem.trim_end().to_lowercase().as_str()) {
+        fixed.insert(stem_len, '_');
+    }
+
+    if fixed == name {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(fixed)
+    }
+}
+
+/// `path`, an absolute Windows path, with the `\\?\` prefix if it's too
+/// long to use without it
+pub(crate) fn verbatim(path: &str) -> Option<String> {
+    if path.encode_utf16().count() < WINDOWS_MAX_PATH || path.starts_with(r"\\?\") {
+        return None;
+    }
+    // Verbatim paths skip normalization, so they need backslashes
+    let path = path.replace('/', r"\");
+    match path.strip_prefix(r"\\") {
+        Some(share) => Some(format!(r"\\?\UNC\{}", share)),
+        None => Some(format!(r"\\?\{}", path)),
+    }
+}
@@ -0,0 +1,147 @@
+//! Hashing transfer data on other threads while it moves
+//!
+//! One core hashes BLAKE3 at about the speed of a 10 Gbit/s link, so
+//! hashing each chunk on the task that sends or receives it would hold the
+//! transfer back. A [`StreamHasher`] hashes the data in [`PIECE`]-sized
+//! subtrees instead, a few at a time on tokio's blocking threads, and adds
+//! them up like a [`HashTree`] at the end, which gives the same hash as
+//! hashing it in one go.
+//!
+//! How many pieces of one transfer are hashed at once is [`threads`]: by
+//! default up to 4, fewer on a machine with fewer cores, and set with
+//! `ZAP_HASH_THREADS` or [`set_threads`].
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+use tokio::task::JoinHandle;
+
+use crate::tree::{HashTree, PIECE, subtree_hash};
+use crate::{Error, Result};
+
+static THREADS: OnceLock<usize> = OnceLock::new();
+
+/// How many pieces of a transfer are hashed at once
+pub fn threads() -> usize {
+    *THREADS.get_or_init(|| {
+        let configured = std::env::var("ZAP_HASH_THREADS")
+            .ok()
+            .and_then(|n| n.trim().parse::<usize>().ok());
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        configured.unwrap_or(cores.min(4)).max(1)
+    })
+}
+
+/// Hash up to `n` pieces of a transfer at once, instead of what
+/// `ZAP_HASH_THREADS` says; too late, and false, once a transfer has
+/// hashed anything
+pub fn set_threads(n: usize) -> bool {
+    THREADS.set(n.max(1)).is_ok()
+}
+
+/// The BLAKE3 hash of data given to it in order, worked out on other
+/// threads
+pub(crate) struct StreamHasher {
+    /// The piece being filled, hashed once more data comes after it, since
+    /// the last piece is hashed differently when it's the only one
+    piece: Vec<u8>,
+    /// Pieces before `piece`
+    index: u64,
+    /// Pieces being hashed, oldest first
+    hashing: VecDeque<JoinHandle<[u8; 32]>>,
+    /// The hashes of the pieces before those
+    hashes: Vec<[u8; 32]>,
+    threads: usize,
+}
+
+impl StreamHasher {
+    pub(crate) fn new() -> Self {
+        Self {
+            piece: Vec::with_capacity(PIECE as usize),
+            index: 0,
+            hashing: VecDeque::new(),
+            hashes: Vec::new(),
+            threads: threads(),
+        }
+    }
+
+    /// Add the next bytes, waiting for the oldest piece if as many as
+    /// [`threads`] are being hashed
+    pub(crate) async fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            if self.piece.len() as u64 == PIECE {
+                self.hash_piece().await?;
+            }
+            let n = (PIECE as usize - self.piece.len()).min(data.len());
+            self.piece.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    async fn hash_piece(&mut self) -> Result<()> {
+        while self.hashing.len() >= self.threads {
+            self.collect_one().await?;
+        }
+        let piece = std::mem::replace(&mut self.piece, Vec::with_capacity(PIECE as usize));
+        let index = self.index;
+        self.hashing.push_back(tokio::task::spawn_blocking(move || {
+            subtree_hash(index, &piece)
+        }));
+        self.index += 1;
+        Ok(())
+    }
+
+    async fn collect_one(&mut self) -> Result<()> {
+        if let Some(hashing) = self.hashing.pop_front() {
+            let hash = hashing
+                .await
+                .map_err(|e| Error::TransferFailed(format!("hashing failed: {}", e)))?;
+            self.hashes.push(hash);
+        }
+        Ok(())
+    }
+
+    /// The hash of everything added, after which the hasher is spent
+    pub(crate) async fn finalize(&mut self) -> Result<[u8; 32]> {
+        if self.index == 0 {
+            // The only piece, hashed as the whole of the data
+            return Ok(*blake3::hash(&self.piece).as_bytes());
+        }
+        let size = self.index * PIECE + self.piece.len() as u64;
+        // The last piece, kept back until now
+        self.hash_piece().await?;
+        while !self.hashing.is_empty() {
+            self.collect_one().await?;
+        }
+        Ok(HashTree::new(size, std::mem::take(&mut self.hashes))?.root())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stream_hasher() {
+        let piece = PIECE as usize;
+        for size in [0, 1, piece, piece + 1, 3 * piece, 5 * piece + 123] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            // Any split of the data hashes the same as all of it at once
+            for split in [7, piece - 1, piece, 3 * piece] {
+                let mut hasher = StreamHasher::new();
+                for chunk in data.chunks(split) {
+                    hasher.update(chunk).await.unwrap();
+                }
+                let hash = hasher.finalize().await.unwrap();
+                assert_eq!(
+                    hash,
+                    *blake3::hash(&data).as_bytes(),
+                    "{} by {}",
+                    size,
+                    split
+                );
+            }
+        }
+    }
+}
@@ -1,14 +1,68 @@
+/// Count something in [`metrics`], e.g. `count!(bytes(Send, len))`, with
+/// the `metrics` feature
+macro_rules! count {
+    ($what:ident($direction:ident $(, $arg:expr)*)) => {
+        #[cfg(feature = "metrics")]
+        crate::metrics::$what(crate::metrics::Direction::$direction $(, $arg)*);
+    };
+}
+
+pub mod bandwidth;
+pub mod checksum;
+pub mod debug;
+pub mod diagnostics;
+pub mod directory;
 pub mod error;
+pub mod filename;
+pub mod hashing;
+#[cfg(feature = "logs")]
+pub mod logs;
+pub mod manifest;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod mirror;
+pub mod mnemonic;
 pub mod node;
+pub mod probe;
 pub mod protocol;
+pub mod receipt;
+pub mod relay_hint;
+pub mod remote;
+pub mod resume;
+#[cfg(feature = "otel")]
+pub mod telemetry;
 pub mod ticket;
 pub mod transfer;
+pub mod tree;
+pub mod tuning;
+#[cfg(feature = "tunnel")]
+pub mod tunnel;
+pub mod validate;
 
+#[cfg(test)]
+mod chaos;
 #[cfg(test)]
 mod tests;
 
+pub use bandwidth::Bandwidth;
+pub use checksum::Checksum;
+pub use diagnostics::{ConnectionPath, ConnectionReport};
+pub use directory::ArchivePolicy;
 pub use error::{Error, Result};
-pub use iroh::EndpointAddr;
-pub use node::ZapNode;
-pub use ticket::Ticket;
-pub use transfer::{ReceiveProgress, SendProgress, TransferHandle};
+pub use iroh::{EndpointAddr, PublicKey, RelayUrl, SecretKey};
+pub use node::{ZapNode, ZapNodeBuilder};
+pub use probe::Negotiated;
+pub use receipt::Receipt;
+pub use relay_hint::RelayHint;
+pub use remote::{Inbox, ListenEvent, ListenOptions};
+pub use ticket::{Ticket, TransferKey};
+pub use transfer::{
+    Durability, EntryChooser, FileMeta, OfferCheck, OfferConfirm, PhaseTimings, RangeReader,
+    ReceiveOptions, ReceiveProgress, Renamer, SendOptions, SendProgress, SendSource,
+    TransferHandle, Transform,
+};
+pub use tree::HashTree;
+pub use tuning::Tuning;
+#[cfg(feature = "tunnel")]
+pub use tunnel::TunnelEvent;
+pub use validate::{OfferViolation, validate_label, validate_offer};
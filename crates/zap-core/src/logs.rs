@@ -0,0 +1,232 @@
+//! Keeping each transfer's log, to hand over when it fails
+//!
+//! Add [`layer`] to the tracing subscriber and run a transfer inside a
+//! [`span`], and everything logged in it, down to zap's debug messages, is
+//! kept for that transfer whatever the subscriber prints. [`get`] returns
+//! it as text, so "it just failed" can come with the trail that led there.
+//!
+//! Only the last [`MAX_LINES`] lines of each transfer are kept, and the
+//! logs of the last [`MAX_TRANSFERS`] transfers.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Instant;
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Span, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Lines kept per transfer
+pub const MAX_LINES: usize = 2000;
+
+/// Transfers whose logs are kept
+pub const MAX_TRANSFERS: usize = 256;
+
+/// Longer lines are cut short
+const MAX_LINE_LEN: usize = 1024;
+
+/// The field on a span naming the transfer logged in it
+const FIELD: &str = "zap.transfer";
+
+static LOGS: OnceLock<Mutex<Logs>> = OnceLock::new();
+
+/// A span whose events go to `transfer`'s log, along with those of the
+/// spans and tasks inside it
+pub fn span(transfer: &str) -> Span {
+    tracing::info_span!("transfer", zap.transfer = transfer)
+}
+
+/// A layer keeping what's logged in [`span`]s: debug messages from zap,
+/// and warnings from the crates it uses
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let targets = Targets::new()
+        .with_target("zap", Level::DEBUG)
+        .with_target("zap_core", Level::DEBUG)
+        .with_target("zap_cli", Level::DEBUG)
+        .with_target("zap_web", Level::DEBUG)
+        .with_default(Level::WARN);
+    Capture.with_filter(targets)
+}
+
+/// `transfer`'s log, a line per event
+pub fn get(transfer: &str) -> Option<String> {
+    let logs = logs();
+    let log = logs.transfers.get(transfer)?;
+    let mut text = String::new();
+    if log.dropped > 0 {
+        let _ = writeln!(text, "({} earlier lines dropped)", log.dropped);
+    }
+    for line in &log.lines {
+        text.push_str(line);
+        text.push('\n');
+    }
+    Some(text)
+}
+
+/// Forget `transfer`'s log
+pub fn remove(transfer: &str) {
+    let mut logs = logs();
+    logs.transfers.remove(transfer);
+    logs.order.retain(|t| t != transfer);
+}
+
+fn logs() -> MutexGuard<'static, Logs> {
+    LOGS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Default)]
+struct Logs {
+    transfers: HashMap<String, Log>,
+    /// Transfers by when their log started, oldest first
+    order: VecDeque<String>,
+}
+
+struct Log {
+    started: Instant,
+    lines: VecDeque<String>,
+    /// Lines that didn't fit
+    dropped: usize,
+}
+
+impl Logs {
+    fn push(&mut self, transfer: &str, level: &Level, target: &str, message: &str) {
+        if !self.transfers.contains_key(transfer) {
+            if self.order.len() >= MAX_TRANSFERS
+                && let Some(oldest) = self.order.pop_front()
+            {
+                self.transfers.remove(&oldest);
+            }
+            self.order.push_back(transfer.to_string());
+            let log = Log {
+                started: Instant::now(),
+                lines: VecDeque::new(),
+                dropped: 0,
+            };
+            self.transfers.insert(transfer.to_string(), log);
+        }
+        let log = self.transfers.get_mut(transfer).expect("inserted above");
+
+        if log.lines.len() >= MAX_LINES {
+            log.lines.pop_front();
+            log.dropped += 1;
+        }
+        let elapsed = log.started.elapsed().as_secs_f64();
+        let mut line = format!("[{:9.3}s] {:>5} {}: {}", elapsed, level, target, message);
+        if line.len() > MAX_LINE_LEN {
+            let mut end = MAX_LINE_LEN;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+            line.push('…');
+        }
+        log.lines.push_back(line);
+    }
+}
+
+/// The layer behind [`layer`]
+struct Capture;
+
+/// The transfer a span is for, kept in its extensions
+struct Transfer(String);
+
+impl<S> Layer<S> for Capture
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut field = TransferField(None);
+        attrs.record(&mut field);
+        if let Some(transfer) = field.0
+            && let Some(span) = ctx.span(id)
+        {
+            span.extensions_mut().insert(Transfer(transfer));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(scope) = ctx.event_scope(event) else {
+            return;
+        };
+        let transfer = scope
+            .into_iter()
+            .find_map(|span| span.extensions().get::<Transfer>().map(|t| t.0.clone()));
+        let Some(transfer) = transfer else {
+            return;
+        };
+
+        let mut message = Message::default();
+        event.record(&mut message);
+        let metadata = event.metadata();
+        logs().push(
+            &transfer,
+            metadata.level(),
+            metadata.target(),
+            &message.text(),
+        );
+    }
+}
+
+/// Finds [`FIELD`] among a span's fields
+struct TransferField(Option<String>);
+
+impl Visit for TransferField {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == FIELD {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == FIELD {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// An event's message followed by its other fields as `name=value`
+#[derive(Default)]
+struct Message {
+    message: String,
+    fields: String,
+}
+
+impl Message {
+    fn text(self) -> String {
+        match (self.message.is_empty(), self.fields.is_empty()) {
+            (_, true) => self.message,
+            (true, false) => self.fields,
+            (false, false) => format!("{} {}", self.message, self.fields),
+        }
+    }
+}
+
+impl Visit for Message {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+            return;
+        }
+        if !self.fields.is_empty() {
+            self.fields.push(' ');
+        }
+        let _ = write!(self.fields, "{}={:?}", field.name(), value);
+    }
+}
@@ -0,0 +1,92 @@
+//! Checking a directory file by file as it arrives
+//!
+//! A directory offer lists each file's path and size, but the checksum at
+//! the end covers all of the data at once: a bad file fails the whole
+//! transfer without saying which one it was, and a transfer that breaks
+//! partway leaves files behind with no telling which are whole. A receiver
+//! that asks gets a [`Message::Manifest`](crate::protocol::Message::Manifest)
+//! with every file's BLAKE3 hash before any data, so each file is checked as
+//! soon as its last byte is in.
+
+use std::collections::VecDeque;
+
+use crate::protocol::{DirEntry, ManifestEntry};
+use crate::{Error, Result};
+
+/// Checks the files of a directory against a manifest as their data comes
+/// in, in order
+pub(crate) struct FileChecker {
+    /// The files still to come, the first being received
+    files: VecDeque<ManifestEntry>,
+    hasher: blake3::Hasher,
+    /// Bytes of the first file received so far
+    received: u64,
+}
+
+impl FileChecker {
+    /// Check the files in `manifest`, which has to list the same paths and
+    /// sizes as `entries`, the ones being received
+    pub(crate) fn new(manifest: Vec<ManifestEntry>, entries: &[DirEntry]) -> Result<Self> {
+        let matches = manifest.len() == entries.len()
+            && manifest
+                .iter()
+                .zip(entries)
+                .all(|(file, entry)| file.path == entry.path && file.size == entry.size);
+        if !matches {
+            return Err(Error::Protocol(
+                "the manifest doesn't match the offer".into(),
+            ));
+        }
+        Ok(Self {
+            files: manifest.into(),
+            hasher: blake3::Hasher::new(),
+            received: 0,
+        })
+    }
+
+    /// Check `data`, the next bytes of the directory, returning the paths
+    /// of the files it completes, and failing at the first one that doesn't
+    /// match its hash
+    pub(crate) fn update(&mut self, mut data: &[u8]) -> Result<Vec<String>> {
+        let mut complete = self.finish_empty()?;
+        while !data.is_empty() {
+            let Some(file) = self.files.front() else {
+                return Err(Error::TransferFailed(
+                    "sender sent more than its manifest covers".into(),
+                ));
+            };
+            let n = (file.size - self.received).min(data.len() as u64) as usize;
+            self.hasher.update(&data[..n]);
+            self.received += n as u64;
+            data = &data[n..];
+            if self.received == file.size {
+                complete.push(self.check()?);
+                complete.extend(self.finish_empty()?);
+            }
+        }
+        Ok(complete)
+    }
+
+    /// Check the empty files up next, which are complete without any data
+    fn finish_empty(&mut self) -> Result<Vec<String>> {
+        let mut complete = Vec::new();
+        while self.received == 0 && self.files.front().is_some_and(|file| file.size == 0) {
+            complete.push(self.check()?);
+        }
+        Ok(complete)
+    }
+
+    fn check(&mut self) -> Result<String> {
+        let file = self.files.pop_front().expect("a file being received");
+        let hash = std::mem::take(&mut self.hasher).finalize();
+        self.received = 0;
+        // Hashes compare in constant time
+        if hash != blake3::Hash::from(file.checksum) {
+            return Err(Error::TransferFailed(format!(
+                "{} doesn't match the sender's manifest",
+                file.path
+            )));
+        }
+        Ok(file.path)
+    }
+}
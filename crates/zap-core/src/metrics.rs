@@ -0,0 +1,308 @@
+//! Counters of the transfers this process has run
+//!
+//! Every send and receive counts itself here: transfers started, finished
+//! and failed, bytes moved, failures by
+//! [`Error::category`](crate::Error::category), and the kinds of path
+//! connections took. Whatever embeds zap reads them the same way, as a
+//! [`Snapshot`], in the Prometheus text format from [`prometheus`], or
+//! with the `otel` feature through an OpenTelemetry meter from
+//! [`observe`].
+//!
+//! A send starts when a receiver accepts its offer, so a share counts once
+//! for each receiver; a receive starts when it accepts the offer. Failures
+//! count from the moment a receiver connects or a receive begins, whether
+//! or not the transfer got as far as starting.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::Result;
+
+/// Which way a transfer goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Send => "send",
+            Self::Receive => "receive",
+        }
+    }
+}
+
+/// The counters of one direction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transfers {
+    pub started: u64,
+    pub completed: u64,
+    pub failed: u64,
+    /// Data sent or received, including that of transfers that failed
+    pub bytes: u64,
+}
+
+/// Every counter at one moment
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub sends: Transfers,
+    pub receives: Transfers,
+    /// Failed transfers by direction and
+    /// [`Error::category`](crate::Error::category)
+    pub errors: BTreeMap<(Direction, &'static str), u64>,
+    /// Connections that went direct, counting again one that went back to
+    /// a relay and direct once more
+    pub direct_paths: u64,
+    /// Connections that went through a relay
+    pub relay_paths: u64,
+}
+
+struct Counters {
+    started: AtomicU64,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            started: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn load(&self) -> Transfers {
+        Transfers {
+            started: self.started.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static SENDS: Counters = Counters::new();
+static RECEIVES: Counters = Counters::new();
+static ERRORS: Mutex<BTreeMap<(Direction, &'static str), u64>> = Mutex::new(BTreeMap::new());
+static DIRECT_PATHS: AtomicU64 = AtomicU64::new(0);
+static RELAY_PATHS: AtomicU64 = AtomicU64::new(0);
+
+fn counters(direction: Direction) -> &'static Counters {
+    match direction {
+        Direction::Send => &SENDS,
+        Direction::Receive => &RECEIVES,
+    }
+}
+
+pub(crate) fn started(direction: Direction) {
+    counters(direction).started.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn completed(direction: Direction) {
+    counters(direction)
+        .completed
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn bytes(direction: Direction, bytes: u64) {
+    counters(direction)
+        .bytes
+        .fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Count a transfer as failed if `result` is an error
+pub(crate) fn failure<T>(direction: Direction, result: &Result<T>) {
+    let Err(e) = result else {
+        return;
+    };
+    counters(direction).failed.fetch_add(1, Ordering::Relaxed);
+    let mut errors = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    *errors.entry((direction, e.category())).or_default() += 1;
+}
+
+/// Count a connection switching to a direct or relayed path
+pub(crate) fn path(direct: bool) {
+    let paths = if direct { &DIRECT_PATHS } else { &RELAY_PATHS };
+    paths.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The counters as they are now
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        sends: SENDS.load(),
+        receives: RECEIVES.load(),
+        errors: ERRORS.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+        direct_paths: DIRECT_PATHS.load(Ordering::Relaxed),
+        relay_paths: RELAY_PATHS.load(Ordering::Relaxed),
+    }
+}
+
+/// The counters in the Prometheus text format, named `zap_core_*`
+pub fn prometheus() -> String {
+    let snapshot = snapshot();
+    let directions = [
+        (Direction::Send, snapshot.sends),
+        (Direction::Receive, snapshot.receives),
+    ];
+    let mut out = String::new();
+    let by_direction = |out: &mut String, name, help, value: fn(&Transfers) -> u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (direction, transfers) in &directions {
+            let direction = direction.as_str();
+            let _ = writeln!(
+                out,
+                "{}{{direction=\"{}\"}} {}",
+                name,
+                direction,
+                value(transfers)
+            );
+        }
+    };
+    by_direction(
+        &mut out,
+        "zap_core_transfers_started_total",
+        "Transfers whose offer was accepted",
+        |t| t.started,
+    );
+    by_direction(
+        &mut out,
+        "zap_core_transfers_completed_total",
+        "Transfers that finished",
+        |t| t.completed,
+    );
+    by_direction(
+        &mut out,
+        "zap_core_transfers_failed_total",
+        "Transfers that ended in an error",
+        |t| t.failed,
+    );
+    by_direction(
+        &mut out,
+        "zap_core_bytes_total",
+        "File data sent or received",
+        |t| t.bytes,
+    );
+
+    let name = "zap_core_errors_total";
+    let _ = writeln!(out, "# HELP {} Failed transfers by kind of error", name);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for ((direction, category), n) in &snapshot.errors {
+        let _ = writeln!(
+            out,
+            "{}{{direction=\"{}\",category=\"{}\"}} {}",
+            name,
+            direction.as_str(),
+            category,
+            n
+        );
+    }
+
+    let name = "zap_core_paths_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} Paths connections switched to, by kind",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{}{{path=\"direct\"}} {}", name, snapshot.direct_paths);
+    let _ = writeln!(out, "{}{{path=\"relay\"}} {}", name, snapshot.relay_paths);
+    out
+}
+
+/// Report the counters through `meter`, named `zap.core.*`, whenever its
+/// provider collects
+#[cfg(feature = "otel")]
+pub fn observe(meter: &opentelemetry::metrics::Meter) {
+    use opentelemetry::KeyValue;
+
+    let by_direction = |name: &'static str, help: &'static str, value: fn(&Transfers) -> u64| {
+        meter
+            .u64_observable_counter(name)
+            .with_description(help)
+            .with_callback(move |observer| {
+                let snapshot = snapshot();
+                for (direction, transfers) in [
+                    (Direction::Send, snapshot.sends),
+                    (Direction::Receive, snapshot.receives),
+                ] {
+                    let direction = KeyValue::new("direction", direction.as_str());
+                    observer.observe(value(&transfers), &[direction]);
+                }
+            })
+            .build();
+    };
+    by_direction(
+        "zap.core.transfers.started",
+        "Transfers whose offer was accepted",
+        |t| t.started,
+    );
+    by_direction(
+        "zap.core.transfers.completed",
+        "Transfers that finished",
+        |t| t.completed,
+    );
+    by_direction(
+        "zap.core.transfers.failed",
+        "Transfers that ended in an error",
+        |t| t.failed,
+    );
+    by_direction("zap.core.bytes", "File data sent or received", |t| t.bytes);
+
+    meter
+        .u64_observable_counter("zap.core.errors")
+        .with_description("Failed transfers by kind of error")
+        .with_callback(|observer| {
+            for ((direction, category), n) in snapshot().errors {
+                let attributes = [
+                    KeyValue::new("direction", direction.as_str()),
+                    KeyValue::new("category", category),
+                ];
+                observer.observe(n, &attributes);
+            }
+        })
+        .build();
+    meter
+        .u64_observable_counter("zap.core.paths")
+        .with_description("Paths connections switched to, by kind")
+        .with_callback(|observer| {
+            let snapshot = snapshot();
+            observer.observe(snapshot.direct_paths, &[KeyValue::new("path", "direct")]);
+            observer.observe(snapshot.relay_paths, &[KeyValue::new("path", "relay")]);
+        })
+        .build();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn test_counters() {
+        // Other tests transfer at the same time, so only look at changes
+        // nothing else makes
+        let before = snapshot();
+        failure::<()>(Direction::Receive, &Err(Error::WrongKey));
+        failure(Direction::Receive, &Ok(()));
+        let after = snapshot();
+        let key = (Direction::Receive, "key");
+        assert_eq!(
+            after.errors.get(&key).copied().unwrap_or(0),
+            before.errors.get(&key).copied().unwrap_or(0) + 1
+        );
+        assert!(after.receives.failed > before.receives.failed);
+
+        let text = prometheus();
+        assert!(text.contains("# TYPE zap_core_bytes_total counter\n"));
+        assert!(text.contains("zap_core_errors_total{direction=\"receive\",category=\"key\"} "));
+        assert!(text.contains("zap_core_paths_total{path=\"direct\"} "));
+    }
+}
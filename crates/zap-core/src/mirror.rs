@@ -0,0 +1,227 @@
+//! Small files left on a relay for the receiver to pick up later
+//!
+//! A sender that won't be around when the receiver is can upload the file
+//! to the relay instead of serving it. The relay only ever holds it sealed
+//! under a [`TransferKey`], which goes with the code the receiver is given
+//! but is never sent to the relay, so the relay can't read what it holds.
+//!
+//! The file name and contents are sealed with XChaCha20-Poly1305 in the
+//! STREAM construction: [`SEGMENT`] bytes at a time, each authenticated on
+//! its own and numbered, with the last one marked, so neither end has to
+//! hold the whole file and a blob that was cut short or had segments
+//! swapped doesn't open.
+
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::KeyInit;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+
+use crate::ticket::TransferKey;
+use crate::validate::validate_name;
+use crate::{Error, Result};
+
+/// The start of every sealed file, then its format's version
+const MAGIC: &[u8; 4] = b"ZAPM";
+const VERSION: u8 = 2;
+
+/// blake3 context for the cipher key derived from a transfer key
+const CIPHER_CONTEXT: &str = "zap relay mirror cipher v2";
+
+/// The stream's nonce prefix; the other five bytes of the XChaCha20 nonce
+/// number the segments and mark the last one
+const NONCE_LEN: usize = 19;
+const TAG_LEN: usize = 16;
+
+/// Bytes of the name and contents sealed together in each segment
+pub const SEGMENT: usize = 64 * 1024;
+
+/// What goes before the first segment
+const HEADER_LEN: usize = MAGIC.len() + 1 + NONCE_LEN;
+
+/// Everything sealing adds to a file of up to one segment besides its name
+pub const OVERHEAD: usize = HEADER_LEN + 2 + TAG_LEN;
+
+fn cipher(key: &TransferKey) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(&blake3::derive_key(CIPHER_CONTEXT, &key.0).into())
+}
+
+/// Seals a file a piece at a time, for the relay to hold
+pub struct Sealer {
+    encryptor: EncryptorBE32<XChaCha20Poly1305>,
+    /// Plaintext not sealed yet, less than a segment until more comes
+    pending: Vec<u8>,
+}
+
+impl Sealer {
+    /// Start sealing a file named `name` under `key`, returning the header
+    /// to send before anything [`update`](Self::update) returns
+    pub fn new(key: &TransferKey, name: &str) -> Result<(Self, Vec<u8>)> {
+        validate_name(name).map_err(|e| Error::Protocol(e.to_string()))?;
+        let name_len = u16::try_from(name.len())
+            .map_err(|_| Error::Protocol(format!("file name too long: {}", name)))?;
+
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&nonce);
+
+        let mut pending = Vec::with_capacity(SEGMENT);
+        pending.extend_from_slice(&name_len.to_be_bytes());
+        pending.extend_from_slice(name.as_bytes());
+        let encryptor = EncryptorBE32::from_aead(cipher(key), GenericArray::from_slice(&nonce));
+        Ok((Self { encryptor, pending }, header))
+    }
+
+    /// Seal the next `data` of the file, returning the segments it
+    /// completed
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        let mut sealed = Vec::new();
+        // The last segment is sealed differently, so a full one waits
+        // until there's more after it
+        let mut start = 0;
+        while self.pending.len() - start > SEGMENT {
+            let segment = &self.pending[start..start + SEGMENT];
+            sealed.extend(self.encryptor.encrypt_next(segment).map_err(seal_failed)?);
+            start += SEGMENT;
+        }
+        self.pending.drain(..start);
+        Ok(sealed)
+    }
+
+    /// Seal the rest of the file as its last segment
+    pub fn finish(self) -> Result<Vec<u8>> {
+        self.encryptor
+            .encrypt_last(self.pending.as_slice())
+            .map_err(seal_failed)
+    }
+}
+
+fn seal_failed(_: chacha20poly1305::aead::Error) -> Error {
+    Error::Protocol("a segment is too large to seal".into())
+}
+
+/// Opens a file sealed by a [`Sealer`] a piece at a time
+pub struct Opener {
+    key: TransferKey,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    /// Sealed bytes not opened yet
+    pending: Vec<u8>,
+    /// Opened bytes of the first segment while the name is still cut off
+    start: Vec<u8>,
+    name: Option<String>,
+}
+
+impl Opener {
+    pub fn new(key: TransferKey) -> Self {
+        Self {
+            key,
+            decryptor: None,
+            pending: Vec::new(),
+            start: Vec::new(),
+            name: None,
+        }
+    }
+
+    /// The file's name, once the segment it's in has been opened
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Open the next `sealed` bytes, returning the file contents in the
+    /// segments they completed
+    ///
+    /// Fails with [`Error::WrongKey`] if the file was sealed under another
+    /// key or changed since.
+    pub fn update(&mut self, sealed: &[u8]) -> Result<Vec<u8>> {
+        self.pending.extend_from_slice(sealed);
+        if self.decryptor.is_none() {
+            if self.pending.len() < HEADER_LEN {
+                return Ok(Vec::new());
+            }
+            if !self.pending.starts_with(MAGIC) {
+                return Err(Error::Protocol("not a file sealed for the relay".into()));
+            }
+            if self.pending[MAGIC.len()] != VERSION {
+                return Err(Error::Protocol(
+                    "the file was sealed by another version of zap".into(),
+                ));
+            }
+            let nonce = GenericArray::from_slice(&self.pending[MAGIC.len() + 1..HEADER_LEN]);
+            self.decryptor = Some(DecryptorBE32::from_aead(cipher(&self.key), nonce));
+            self.pending.drain(..HEADER_LEN);
+        }
+
+        let decryptor = self.decryptor.as_mut().expect("the header was read");
+        let mut opened = Vec::new();
+        let mut start = 0;
+        while self.pending.len() - start > SEGMENT + TAG_LEN {
+            let segment = &self.pending[start..start + SEGMENT + TAG_LEN];
+            opened.extend(
+                decryptor
+                    .decrypt_next(segment)
+                    .map_err(|_| Error::WrongKey)?,
+            );
+            start += SEGMENT + TAG_LEN;
+        }
+        self.pending.drain(..start);
+        self.take_name(opened)
+    }
+
+    /// Open the last segment, once all of the file has been read, failing
+    /// if it was cut short
+    pub fn finish(mut self) -> Result<(String, Vec<u8>)> {
+        let Some(decryptor) = self.decryptor.take() else {
+            return Err(Error::Protocol("not a file sealed for the relay".into()));
+        };
+        let opened = decryptor
+            .decrypt_last(self.pending.as_slice())
+            .map_err(|_| Error::WrongKey)?;
+        let rest = self.take_name(opened)?;
+        match self.name {
+            Some(name) => Ok((name, rest)),
+            None => Err(Error::Protocol("sealed file name is cut off".into())),
+        }
+    }
+
+    /// Split the name off the start of the file's opened bytes
+    fn take_name(&mut self, opened: Vec<u8>) -> Result<Vec<u8>> {
+        if self.name.is_some() {
+            return Ok(opened);
+        }
+        self.start.extend(opened);
+        let Some(len) = self.start.get(..2) else {
+            return Ok(Vec::new());
+        };
+        let name_len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let Some(name) = self.start.get(2..2 + name_len) else {
+            return Ok(Vec::new());
+        };
+        let name = String::from_utf8(name.to_vec())
+            .map_err(|_| Error::Protocol("sealed file name isn't UTF-8".into()))?;
+        validate_name(&name).map_err(|e| Error::Protocol(e.to_string()))?;
+        self.name = Some(name);
+        Ok(self.start.split_off(2 + name_len))
+    }
+}
+
+/// Seal a whole file named `name` under `key`, for the relay to hold
+pub fn seal(key: &TransferKey, name: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let (mut sealer, mut sealed) = Sealer::new(key, name)?;
+    sealed.extend(sealer.update(data)?);
+    sealed.extend(sealer.finish()?);
+    Ok(sealed)
+}
+
+/// Open a whole file sealed with [`seal`], returning its name and contents
+///
+/// Fails with [`Error::WrongKey`] if it was sealed under another key or
+/// changed since.
+pub fn open(key: &TransferKey, sealed: &[u8]) -> Result<(String, Vec<u8>)> {
+    let mut opener = Opener::new(*key);
+    let mut data = opener.update(sealed)?;
+    let (name, rest) = opener.finish()?;
+    data.extend(rest);
+    Ok((name, data))
+}
@@ -0,0 +1,150 @@
+//! Tickets as words, for reading out over the phone
+//!
+//! Without a relay to give out short codes, the ticket itself has to get
+//! across. [`to_words`] spells out the sender's node id, and the transfer
+//! key if it requires one, a word per byte; the receiver finds the
+//! sender's addresses through discovery, as when dialing a paired peer.
+//! That's 35 words, or 51 with a key, which is more than a code but can
+//! be read out loud, unlike a ticket.
+//!
+//! No two words start with the same three letters, so those are enough
+//! when typing them back in. The last two words are a checksum.
+
+use iroh::{EndpointAddr, PublicKey};
+
+use crate::ticket::{Ticket, TransferKey};
+use crate::{Error, Result};
+
+/// One word for each byte value
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "agent", "alarm", "album", "alley", "amber", "angle", "ankle",
+    "apple", "april", "arena", "armor", "arrow", "atlas", "attic", "audio", "autumn", "bacon",
+    "badge", "bagel", "baker", "bamboo", "banjo", "barn", "basil", "beach", "bell", "berry",
+    "bike", "bingo", "bison", "blade", "bloom", "bonus", "bottle", "bread", "brick", "broom",
+    "bubble", "bucket", "cabin", "cactus", "camel", "candle", "carpet", "castle", "cedar", "cello",
+    "chalk", "cherry", "chief", "cider", "circus", "clay", "cliff", "clock", "cobra", "cocoa",
+    "comet", "coral", "cotton", "cousin", "crab", "crown", "daisy", "dance", "delta", "denim",
+    "desert", "diary", "donkey", "dragon", "drum", "duck", "dune", "eagle", "easel", "echo",
+    "elbow", "elder", "ember", "engine", "fabric", "falcon", "farm", "fence", "ferry", "fiddle",
+    "finch", "flag", "flute", "forest", "fossil", "fox", "frog", "galaxy", "garden", "gecko",
+    "ghost", "giant", "ginger", "glove", "goat", "gold", "grape", "guitar", "hammer", "harbor",
+    "hazel", "helmet", "heron", "hippo", "honey", "hotel", "igloo", "iris", "island", "ivory",
+    "jacket", "jaguar", "jazz", "jelly", "jewel", "jigsaw", "judge", "juice", "jungle", "kayak",
+    "kettle", "kiwi", "koala", "label", "ladder", "lagoon", "lamp", "laser", "lemon", "lentil",
+    "lily", "lion", "lizard", "llama", "locket", "lotus", "lunar", "magnet", "mango", "maple",
+    "marble", "meadow", "melon", "mirror", "mitten", "monkey", "moose", "mosaic", "motor",
+    "muffin", "museum", "nectar", "needle", "nest", "nickel", "noodle", "nutmeg", "oasis", "ocean",
+    "olive", "onion", "opera", "orange", "orbit", "orchid", "otter", "oyster", "paddle", "panda",
+    "paper", "parrot", "peach", "pebble", "pencil", "pepper", "piano", "pickle", "pilot", "planet",
+    "pocket", "pony", "potato", "puzzle", "quail", "quilt", "rabbit", "radar", "raisin", "raven",
+    "rhino", "ribbon", "river", "robin", "rocket", "ruby", "saddle", "salad", "sandal", "satin",
+    "scarf", "shadow", "shell", "silver", "sketch", "sloth", "snail", "spider", "spoon", "squid",
+    "stamp", "storm", "sugar", "summer", "sunset", "swan", "table", "taco", "tango", "teapot",
+    "temple", "tennis", "tiger", "timber", "toast", "tomato", "topaz", "torch", "tulip", "tunnel",
+    "turtle", "tuxedo", "valley", "velvet", "violin", "voyage", "wagon", "walnut", "water",
+    "whale", "wheat", "willow", "window", "wizard", "yacht", "zebra", "zipper",
+];
+
+/// Set in the first byte when a transfer key follows the node id
+const WITH_KEY: u8 = 1;
+
+const CHECKSUM_LEN: usize = 2;
+
+/// The fewest words a ticket takes
+pub const MIN_WORDS: usize = 1 + 32 + CHECKSUM_LEN;
+
+/// Spell out a ticket's node id, and its key if it requires one
+///
+/// Fails for a ticket that requires a key but doesn't have it, and for one
+/// that expires, as the words would keep working after it.
+pub fn to_words(ticket: &Ticket) -> Result<String> {
+    if ticket.expires_at.is_some() {
+        return Err(Error::InvalidTicket(
+            "a ticket that expires can't be put into words".into(),
+        ));
+    }
+    let mut bytes = vec![0];
+    bytes.extend_from_slice(ticket.addr.id.as_bytes());
+    match (ticket.key_hash, &ticket.key) {
+        (None, _) => {}
+        (Some(_), Some(key)) => {
+            bytes[0] |= WITH_KEY;
+            bytes.extend_from_slice(&key.0);
+        }
+        (Some(_), None) => {
+            return Err(Error::InvalidTicket(
+                "the ticket's key is needed to put it into words".into(),
+            ));
+        }
+    }
+    let checksum = checksum(&bytes);
+    bytes.extend_from_slice(&checksum);
+
+    let words: Vec<_> = bytes.iter().map(|&b| WORDS[b as usize]).collect();
+    Ok(words.join("-"))
+}
+
+/// Read a ticket back from [`to_words`], separated by hyphens or spaces,
+/// with each word in full or just its first three letters
+pub fn from_words(words: &str) -> Result<Ticket> {
+    let bytes = words
+        .split(|c: char| c == '-' || c == ',' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let word = word.to_lowercase();
+            WORDS
+                .iter()
+                .position(|w| *w == word || (word.len() == 3 && w.starts_with(&word)))
+                .map(|b| b as u8)
+                .ok_or_else(|| Error::InvalidTicket(format!("{:?} isn't a ticket word", word)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if bytes.len() < MIN_WORDS {
+        return Err(Error::InvalidTicket(format!(
+            "expected at least {} words, got {}",
+            MIN_WORDS,
+            bytes.len()
+        )));
+    }
+    let (payload, checksum_words) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+    if checksum(payload) != checksum_words {
+        return Err(Error::InvalidTicket(
+            "the words don't add up; check for a missing or misheard one".into(),
+        ));
+    }
+
+    let (&flags, rest) = payload.split_first().expect("checked above");
+    let key_len = if flags & WITH_KEY != 0 { 16 } else { 0 };
+    if flags & !WITH_KEY != 0 || rest.len() != 32 + key_len {
+        return Err(Error::InvalidTicket(
+            "the words are from a newer version of zap".into(),
+        ));
+    }
+
+    let id: [u8; 32] = rest[..32].try_into().expect("32 bytes");
+    let id = PublicKey::from_bytes(&id)
+        .map_err(|e| Error::InvalidTicket(format!("invalid node id: {}", e)))?;
+    let ticket = Ticket::new(EndpointAddr::new(id));
+    if key_len == 0 {
+        return Ok(ticket);
+    }
+    let key: [u8; 16] = rest[32..].try_into().expect("16 bytes");
+    Ok(ticket.requiring(TransferKey(key)))
+}
+
+/// Whether `input` looks like words from [`to_words`] rather than a code
+pub fn is_words(input: &str) -> bool {
+    input
+        .split(|c: char| c == '-' || c == ',' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .count()
+        >= MIN_WORDS
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = blake3::hash(bytes);
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&hash.as_bytes()[..CHECKSUM_LEN]);
+    checksum
+}
@@ -1,39 +1,203 @@
-use std::path::Path;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use iroh::{Endpoint, EndpointAddr, SecretKey};
+use iroh::{Endpoint, EndpointAddr, PublicKey, RelayMap, RelayMode, RelayUrl, SecretKey};
+use tokio::io::AsyncRead;
 use tokio::sync::mpsc;
-use tracing::{debug, info};
+use tracing::{Instrument, debug, info};
 
-use crate::protocol::ZAP_ALPN;
+use crate::diagnostics::{ConnectionPath, ConnectionReport};
+use crate::directory::ArchivePolicy;
+use crate::protocol::{self, FileOffer, ZAP_ALPN};
+use crate::remote::{self, ListenEvent, ListenOptions};
+use crate::resume;
 use crate::ticket::Ticket;
-use crate::transfer::{self, ReceiveProgress, SendProgress};
+#[cfg(feature = "tunnel")]
+use crate::ticket::TransferKey;
+use crate::transfer::{
+    self, Durability, FileMeta, RangeReader, ReceiveOptions, ReceiveProgress, SendOptions,
+    SendProgress, SendSource,
+};
+use crate::tuning::{DEFAULT_ACK_WINDOW, Tuning};
+#[cfg(feature = "tunnel")]
+use crate::tunnel::{self, TunnelEvent};
 use crate::{Error, Result};
 
 /// A zap node that can send and receive files
 pub struct ZapNode {
     endpoint: Endpoint,
+    alpn: Vec<u8>,
+    /// Chunks a send has out ahead of the receiver's acks, unless its
+    /// options say otherwise
+    ack_window: usize,
+}
+
+/// Options for starting a [`ZapNode`]
+#[derive(Debug, Default)]
+pub struct ZapNodeBuilder {
+    secret_key: Option<SecretKey>,
+    port: Option<u16>,
+    alpn: Option<Vec<u8>>,
+    relay: Option<RelayUrl>,
+    offline: bool,
+    tuning: Option<Tuning>,
+}
+
+/// How long an offline node waits to learn its own addresses
+const LOCAL_ADDRS_TIMEOUT: Duration = Duration::from_secs(3);
+
+impl ZapNodeBuilder {
+    /// Use a specific secret key instead of a random one
+    pub fn secret_key(mut self, secret_key: SecretKey) -> Self {
+        self.secret_key = Some(secret_key);
+        self
+    }
+
+    /// Listen on a fixed UDP port, e.g. one forwarded through a NAT so
+    /// peers can connect directly
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Speak a private protocol namespace such as `zap/acme/1` instead of
+    /// [`ZAP_ALPN`]
+    ///
+    /// Nodes only connect to peers using the same ALPN, which keeps public
+    /// zap clients away from a private deployment. Tunnels use the ALPN
+    /// with `/tunnel` appended.
+    pub fn alpn(mut self, alpn: impl Into<Vec<u8>>) -> Self {
+        self.alpn = Some(alpn.into());
+        self
+    }
+
+    /// Use this relay server instead of the default ones, e.g. the one
+    /// nearest to the node in a deployment with relays in several regions
+    pub fn relay(mut self, url: RelayUrl) -> Self {
+        self.relay = Some(url);
+        self
+    }
+
+    /// Use no relay servers and no address lookups, for networks without
+    /// internet access
+    ///
+    /// Tickets then hold only the node's own socket addresses, so peers
+    /// have to reach it directly, e.g. on the same LAN; a fixed
+    /// [`port`](Self::port) makes that easier to allow through a firewall.
+    pub fn offline(mut self) -> Self {
+        self.offline = true;
+        self
+    }
+
+    /// Size flow-control windows for a link unlike an ordinary internet
+    /// connection, such as [`Tuning::lan`] or [`Tuning::satellite`]
+    ///
+    /// Both ends of a transfer benefit: the receiver's windows bound what
+    /// the sender may have in flight.
+    pub fn tuning(mut self, tuning: Tuning) -> Self {
+        self.tuning = Some(tuning);
+        self
+    }
+
+    /// Bind the endpoint and wait until it's online
+    pub async fn build(self) -> Result<ZapNode> {
+        let alpn = self.alpn.unwrap_or_else(|| ZAP_ALPN.to_vec());
+        if alpn.is_empty() {
+            return Err(Error::Protocol("ALPN must not be empty".into()));
+        }
+
+        // Generated before any await so the thread-local rng isn't held
+        // across it, which would make the future !Send
+        let secret_key = self
+            .secret_key
+            .unwrap_or_else(|| SecretKey::generate(&mut rand::rng()));
+
+        let builder = if self.offline {
+            Endpoint::empty_builder(RelayMode::Disabled)
+        } else {
+            Endpoint::builder()
+        };
+        let mut builder = builder.secret_key(secret_key).alpns(vec![
+            alpn.clone(),
+            protocol::tunnel_alpn(&alpn),
+            protocol::remote_alpn(&alpn),
+        ]);
+        if let Some(url) = self.relay.filter(|_| !self.offline) {
+            builder = builder.relay_mode(RelayMode::Custom(RelayMap::from(url)));
+        }
+        if let Some(port) = self.port {
+            builder = builder.bind_addr(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))?;
+        }
+        if let Some(tuning) = &self.tuning {
+            builder = builder.transport_config(tuning.transport_config());
+        }
+        let endpoint = builder.bind().await?;
+
+        if self.offline {
+            // There's no relay to be online with, only addresses to know
+            wait_for_local_addrs(&endpoint).await;
+        } else {
+            // Wait for the endpoint to be online (connected to relay)
+            endpoint.online().await;
+        }
+
+        info!(
+            node_id = %endpoint.id(),
+            alpn = %String::from_utf8_lossy(&alpn),
+            "zap node started"
+        );
+
+        let ack_window = self
+            .tuning
+            .map_or(DEFAULT_ACK_WINDOW, |tuning| tuning.ack_window);
+        Ok(ZapNode {
+            endpoint,
+            alpn,
+            ack_window,
+        })
+    }
+}
+
+/// Wait until the endpoint has found its own socket addresses, which go in
+/// tickets, or give up after a short while
+async fn wait_for_local_addrs(endpoint: &Endpoint) {
+    let found = tokio::time::timeout(LOCAL_ADDRS_TIMEOUT, async {
+        while endpoint.addr().ip_addrs().next().is_none() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    if found.is_err() {
+        debug!("no local addresses yet, tickets only hold the bound sockets");
+    }
 }
 
 impl ZapNode {
     /// Create a new zap node
     pub async fn new() -> Result<Self> {
-        Self::with_secret_key(SecretKey::generate(&mut rand::rng())).await
+        Self::builder().build().await
+    }
+
+    /// Configure a node before starting it
+    pub fn builder() -> ZapNodeBuilder {
+        ZapNodeBuilder::default()
     }
 
     /// Create a new zap node with a specific secret key
     pub async fn with_secret_key(secret_key: SecretKey) -> Result<Self> {
-        let endpoint = Endpoint::builder()
-            .secret_key(secret_key)
-            .alpns(vec![ZAP_ALPN.to_vec()])
-            .bind()
-            .await?;
-
-        // Wait for the endpoint to be online (connected to relay)
-        endpoint.online().await;
+        Self::builder().secret_key(secret_key).build().await
+    }
 
-        info!(node_id = %endpoint.id(), "zap node started");
+    /// Create a new zap node listening on a fixed UDP port, e.g. one
+    /// forwarded through a NAT so peers can connect directly
+    pub async fn with_port(port: u16) -> Result<Self> {
+        Self::builder().port(port).build().await
+    }
 
-        Ok(Self { endpoint })
+    /// The ALPN this node speaks
+    pub fn alpn(&self) -> &[u8] {
+        &self.alpn
     }
 
     /// Get this node's endpoint address for sharing
@@ -55,45 +219,242 @@ impl ZapNode {
         self.endpoint.id()
     }
 
+    /// Explain how this node reaches peers, with the last path a transfer
+    /// reported, for diagnosing relayed or failed connections
+    pub fn connection_report(&self, path: Option<ConnectionPath>) -> ConnectionReport {
+        ConnectionReport::gather(&self.endpoint, path)
+    }
+
     /// Generate a ticket for others to connect to this node
     pub fn ticket(&self) -> Ticket {
         Ticket::new(self.addr())
     }
 
-    /// Send a file to a receiver
+    /// Send a file or directory to a receiver
     ///
     /// Returns a channel that will receive progress updates
     pub async fn send<P: AsRef<Path>>(
         &self,
         path: P,
     ) -> Result<(Ticket, mpsc::Receiver<SendProgress>)> {
-        let path = path.as_ref().to_path_buf();
+        self.send_with_options(path, SendOptions::default()).await
+    }
 
-        // Validate file exists
-        if !path.exists() {
-            return Err(Error::Io(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("file not found: {}", path.display()),
-            )));
+    /// Send a file or directory to a receiver with the given options
+    ///
+    /// Returns a channel that will receive progress updates
+    pub async fn send_with_options<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: SendOptions,
+    ) -> Result<(Ticket, mpsc::Receiver<SendProgress>)> {
+        let source = send_source(path.as_ref(), options.auto_archive)?;
+        self.spawn_sender(source, options)
+    }
+
+    /// Send several files and directories from the same directory
+    ///
+    /// The receiver gets them inside a directory named after the common
+    /// parent. Returns a channel that will receive progress updates
+    pub async fn send_many<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+        options: SendOptions,
+    ) -> Result<(Ticket, mpsc::Receiver<SendProgress>)> {
+        let invalid =
+            |msg: String| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+
+        let mut root: Option<PathBuf> = None;
+        let mut names = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path = path.as_ref();
+            if !path.exists() {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("file not found: {}", path.display()),
+                )));
+            }
+
+            let name = path.file_name().and_then(|n| n.to_str());
+            let (Some(parent), Some(name)) = (path.parent(), name) else {
+                return Err(invalid(format!("cannot send {}", path.display())));
+            };
+
+            match &root {
+                Some(root) if root != parent => {
+                    return Err(invalid("all paths must be in the same directory".into()));
+                }
+                Some(_) => {}
+                None => root = Some(parent.to_path_buf()),
+            }
+            names.push(name.to_string());
+        }
+
+        let Some(root) = root else {
+            return Err(invalid("nothing to send".into()));
+        };
+
+        self.spawn_sender(
+            SendSource::Selection {
+                root,
+                names,
+                auto_archive: options.auto_archive,
+            },
+            options,
+        )
+    }
+
+    /// Send files and directories from anywhere under one ticket
+    ///
+    /// The receiver saves them side by side where it saves, rather than in
+    /// a directory, so their names have to differ. Progress includes
+    /// [`SendProgress::FileSent`] as each file arrives. Receivers from
+    /// before batches were added turn the offer down.
+    pub async fn send_batch<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+        options: SendOptions,
+    ) -> Result<(Ticket, mpsc::Receiver<SendProgress>)> {
+        let invalid =
+            |msg: String| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+
+        let mut names = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path = path.as_ref();
+            if !path.exists() {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("file not found: {}", path.display()),
+                )));
+            }
+            let name = path.file_name().and_then(|n| n.to_str());
+            let (Some(_), Some(name)) = (path.parent(), name) else {
+                return Err(invalid(format!("cannot send {}", path.display())));
+            };
+            if names.contains(&name) {
+                return Err(invalid(format!("more than one path is named {}", name)));
+            }
+            names.push(name);
+        }
+        if names.is_empty() {
+            return Err(invalid("nothing to send".into()));
         }
 
-        if !path.is_file() {
+        let paths = paths.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        self.spawn_sender(SendSource::Batch(paths), options)
+    }
+
+    /// Send data as it's produced, from a pipe, a socket or a command's
+    /// output, under the name and length in `meta`
+    ///
+    /// Nothing is staged on disk: the receiver gets the data as it's read.
+    /// Returns a channel that will receive progress updates
+    pub async fn send_from<R>(
+        &self,
+        reader: R,
+        meta: FileMeta,
+    ) -> Result<(Ticket, mpsc::Receiver<SendProgress>)>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        self.send_from_with_options(reader, meta, SendOptions::default())
+            .await
+    }
+
+    /// Send data from a stream with the given options
+    ///
+    /// Returns a channel that will receive progress updates
+    pub async fn send_from_with_options<R>(
+        &self,
+        reader: R,
+        meta: FileMeta,
+        options: SendOptions,
+    ) -> Result<(Ticket, mpsc::Receiver<SendProgress>)>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        if options.keep_serving {
             return Err(Error::Io(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                "path must be a file",
+                "a stream can only be sent once",
             )));
         }
 
+        self.spawn_sender(
+            SendSource::Reader {
+                meta,
+                reader: Box::new(reader),
+            },
+            options,
+        )
+    }
+
+    /// Send data of unknown length, such as a pipe, under the given name
+    ///
+    /// Returns a channel that will receive progress updates
+    pub async fn send_reader<R>(
+        &self,
+        name: impl Into<String>,
+        reader: R,
+    ) -> Result<(Ticket, mpsc::Receiver<SendProgress>)>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        self.send_reader_with_options(name, reader, SendOptions::default())
+            .await
+    }
+
+    /// Send data of unknown length under the given name with the given
+    /// options
+    ///
+    /// Returns a channel that will receive progress updates
+    pub async fn send_reader_with_options<R>(
+        &self,
+        name: impl Into<String>,
+        reader: R,
+        options: SendOptions,
+    ) -> Result<(Ticket, mpsc::Receiver<SendProgress>)>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        self.send_from_with_options(reader, FileMeta::new(name), options)
+            .await
+    }
+
+    fn spawn_sender(
+        &self,
+        source: SendSource,
+        mut options: SendOptions,
+    ) -> Result<(Ticket, mpsc::Receiver<SendProgress>)> {
+        options.ack_window.get_or_insert(self.ack_window);
         let (progress_tx, progress_rx) = mpsc::channel(32);
         let endpoint = self.endpoint.clone();
-        let ticket = self.ticket();
+        let alpn = self.alpn.clone();
+        let mut ticket = match options.key {
+            Some(key) => self.ticket().requiring(key),
+            None => self.ticket(),
+        };
+        if let Some(ttl) = options.ticket_ttl {
+            ticket = ticket.expiring_in(ttl);
+        }
+        let handle = options.handle.clone().unwrap_or_default();
 
-        // Spawn the sender task
-        tokio::spawn(async move {
-            if let Err(e) = transfer::run_sender(endpoint, path, progress_tx.clone()).await {
+        // Spawn the sender task, under the caller's span if any
+        let task = async move {
+            if let Err(e) = transfer::run_sender(
+                endpoint,
+                &alpn,
+                source,
+                handle,
+                options,
+                progress_tx.clone(),
+            )
+            .await
+            {
                 let _ = progress_tx.send(SendProgress::Error(e.to_string())).await;
             }
-        });
+        };
+        tokio::spawn(task.in_current_span());
 
         Ok((ticket, progress_rx))
     }
@@ -105,24 +466,250 @@ impl ZapNode {
         &self,
         ticket: Ticket,
         output_dir: Option<&Path>,
+    ) -> Result<mpsc::Receiver<ReceiveProgress>> {
+        self.receive_with_options(ticket, output_dir, ReceiveOptions::default())
+            .await
+    }
+
+    /// Receive a file from a sender with the given options
+    ///
+    /// Returns a channel that will receive progress updates
+    pub async fn receive_with_options(
+        &self,
+        ticket: Ticket,
+        output_dir: Option<&Path>,
+        options: ReceiveOptions,
     ) -> Result<mpsc::Receiver<ReceiveProgress>> {
         let (progress_tx, progress_rx) = mpsc::channel(32);
         let endpoint = self.endpoint.clone();
+        let alpn = self.alpn.clone();
         let output_dir = output_dir.map(|p| p.to_path_buf());
 
         // Connect to the sender
         debug!(node_id = %ticket.addr.id, "connecting to sender");
 
-        tokio::spawn(async move {
+        let task = async move {
+            if let Err(e) = transfer::run_receiver(
+                endpoint,
+                &alpn,
+                ticket,
+                output_dir,
+                options,
+                progress_tx.clone(),
+            )
+            .await
+            {
+                let _ = progress_tx
+                    .send(ReceiveProgress::Error(e.to_string()))
+                    .await;
+            }
+        };
+        tokio::spawn(task.in_current_span());
+
+        Ok(progress_rx)
+    }
+
+    /// Carry on with a receive that broke off, from the state file it left
+    /// next to the partial file, which may have been moved here from
+    /// another machine
+    ///
+    /// The sender has to be [sharing](SendOptions::keep_serving). Returns a
+    /// channel that will receive progress updates.
+    pub async fn resume(
+        &self,
+        state: &Path,
+        durability: Durability,
+    ) -> Result<mpsc::Receiver<ReceiveProgress>> {
+        let (progress_tx, progress_rx) = mpsc::channel(32);
+        let endpoint = self.endpoint.clone();
+        let alpn = self.alpn.clone();
+        let state = state.to_path_buf();
+
+        let task = async move {
             if let Err(e) =
-                transfer::run_receiver(endpoint, ticket, output_dir, progress_tx.clone()).await
+                resume::run_resume(endpoint, &alpn, state, durability, progress_tx.clone()).await
             {
                 let _ = progress_tx
                     .send(ReceiveProgress::Error(e.to_string()))
                     .await;
             }
+        };
+        tokio::spawn(task.in_current_span());
+
+        Ok(progress_rx)
+    }
+
+    /// Look at what a sender offers without receiving it
+    ///
+    /// The sender sees the offer turned down, which ends a sender that
+    /// isn't [keeping it open](SendOptions::keep_serving).
+    pub async fn peek(&self, ticket: &Ticket) -> Result<FileOffer> {
+        transfer::peek_offer(&self.endpoint, &self.alpn, ticket).await
+    }
+
+    /// Read part of one file from a sender that is
+    /// [sharing](SendOptions::keep_serving), without downloading the rest
+    ///
+    /// `entry` is `0` for a file offer, or the index of a directory offer's
+    /// entry, as in [`FileOffer::kind`].
+    pub async fn read_range(
+        &self,
+        ticket: &Ticket,
+        entry: u32,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        transfer::fetch_range(&self.endpoint, &self.alpn, ticket, entry, offset, len).await
+    }
+
+    /// Read one range after another from a sender that is
+    /// [sharing](SendOptions::keep_serving), over one connection instead of
+    /// a new one for each, as [`read_range`](Self::read_range) does
+    pub fn range_reader(&self, ticket: &Ticket) -> RangeReader {
+        RangeReader::new(self.endpoint.clone(), self.alpn.clone(), ticket.clone())
+    }
+
+    /// Get the offer of a sender that is [sharing](SendOptions::keep_serving)
+    /// and the BLAKE3 hash of each of its files, without downloading them:
+    /// one for a file offer, or one per entry of a directory offer
+    pub async fn checksums(&self, ticket: &Ticket) -> Result<(FileOffer, Vec<[u8; 32]>)> {
+        transfer::fetch_checksums(&self.endpoint, &self.alpn, ticket).await
+    }
+
+    /// Expose a local TCP port to the first peer that connects with the
+    /// returned ticket
+    ///
+    /// The ticket requires a fresh [`TransferKey`], so give the peer its
+    /// [`share`](Ticket::share) string. Returns a channel that will receive
+    /// tunnel events
+    #[cfg(feature = "tunnel")]
+    pub async fn expose(&self, port: u16) -> Result<(Ticket, mpsc::Receiver<TunnelEvent>)> {
+        let (event_tx, event_rx) = mpsc::channel(32);
+        let endpoint = self.endpoint.clone();
+        let alpn = protocol::tunnel_alpn(&self.alpn);
+        let key = TransferKey::generate();
+        let ticket = self.ticket().requiring(key);
+
+        tokio::spawn(async move {
+            if let Err(e) =
+                tunnel::run_exposer(endpoint, &alpn, key, port, event_tx.clone()).await
+            {
+                let _ = event_tx.send(TunnelEvent::Error(e.to_string())).await;
+            }
         });
 
+        Ok((ticket, event_rx))
+    }
+
+    /// Forward connections on a local address to a peer's exposed port
+    ///
+    /// Returns a channel that will receive tunnel events
+    #[cfg(feature = "tunnel")]
+    pub async fn forward(
+        &self,
+        ticket: Ticket,
+        local_addr: SocketAddr,
+    ) -> Result<mpsc::Receiver<TunnelEvent>> {
+        let (event_tx, event_rx) = mpsc::channel(32);
+        let endpoint = self.endpoint.clone();
+        let alpn = protocol::tunnel_alpn(&self.alpn);
+
+        tokio::spawn(async move {
+            if let Err(e) =
+                tunnel::run_forwarder(endpoint, &alpn, ticket, local_addr, event_tx.clone()).await
+            {
+                let _ = event_tx.send(TunnelEvent::Error(e.to_string())).await;
+            }
+        });
+
+        Ok(event_rx)
+    }
+
+    /// Serve a directory to paired peers, who copy from and to it with
+    /// [`copy_from`](Self::copy_from) and [`copy_to`](Self::copy_to)
+    ///
+    /// Peers are recognized by node id, so both sides should start their
+    /// nodes with a [saved key](ZapNodeBuilder::secret_key). Returns a
+    /// channel that will receive listener events
+    pub async fn listen(&self, options: ListenOptions) -> Result<mpsc::Receiver<ListenEvent>> {
+        let (event_tx, event_rx) = mpsc::channel(32);
+        let endpoint = self.endpoint.clone();
+        let alpn = self.alpn.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = remote::run_listener(endpoint, &alpn, options, event_tx.clone()).await {
+                let _ = event_tx.send(ListenEvent::Error(e.to_string())).await;
+            }
+        });
+
+        Ok(event_rx)
+    }
+
+    /// Copy a file or directory from a listening peer, `path` being
+    /// relative to the directory it serves
+    ///
+    /// Returns a channel that will receive progress updates
+    pub async fn copy_from(
+        &self,
+        peer: PublicKey,
+        path: &str,
+        output_dir: Option<&Path>,
+    ) -> Result<mpsc::Receiver<ReceiveProgress>> {
+        let (progress_tx, progress_rx) = mpsc::channel(32);
+        let endpoint = self.endpoint.clone();
+        let alpn = self.alpn.clone();
+        let path = path.to_string();
+        let output_dir = output_dir.map(|p| p.to_path_buf());
+
+        let task = async move {
+            if let Err(e) =
+                remote::run_get(endpoint, &alpn, peer, path, output_dir, progress_tx.clone()).await
+            {
+                let _ = progress_tx
+                    .send(ReceiveProgress::Error(e.to_string()))
+                    .await;
+            }
+        };
+        tokio::spawn(task.in_current_span());
+
+        Ok(progress_rx)
+    }
+
+    /// Copy a file or directory to a listening peer, into the directory at
+    /// `path` there
+    ///
+    /// Returns a channel that will receive progress updates
+    pub async fn copy_to<P: AsRef<Path>>(
+        &self,
+        peer: PublicKey,
+        source: P,
+        path: &str,
+    ) -> Result<mpsc::Receiver<SendProgress>> {
+        let source = send_source(source.as_ref(), None)?;
+        let (progress_tx, progress_rx) = mpsc::channel(32);
+        let endpoint = self.endpoint.clone();
+        let alpn = self.alpn.clone();
+        let path = path.to_string();
+        let ticket = self.ticket();
+        let ack_window = self.ack_window;
+
+        let task = async move {
+            let put = remote::Put { peer, path, ticket };
+            if let Err(e) = remote::run_put(
+                endpoint,
+                &alpn,
+                put,
+                source,
+                ack_window,
+                progress_tx.clone(),
+            )
+            .await
+            {
+                let _ = progress_tx.send(SendProgress::Error(e.to_string())).await;
+            }
+        };
+        tokio::spawn(task.in_current_span());
+
         Ok(progress_rx)
     }
 
@@ -132,3 +719,30 @@ impl ZapNode {
         Ok(())
     }
 }
+
+/// What sending `path` means: a file, or a directory
+fn send_source(path: &Path, auto_archive: Option<ArchivePolicy>) -> Result<SendSource> {
+    // Validate file exists
+    if !path.exists() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("file not found: {}", path.display()),
+        )));
+    }
+
+    if path.is_dir() {
+        return Ok(SendSource::Directory {
+            path: path.to_path_buf(),
+            auto_archive,
+        });
+    }
+
+    if !path.is_file() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "path must be a file or directory",
+        )));
+    }
+
+    Ok(SendSource::File(path.to_path_buf()))
+}
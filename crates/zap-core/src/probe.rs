@@ -0,0 +1,143 @@
+//! Sizing a connection from its first data
+//!
+//! One chunk size and ack window can't suit every link: a LAN wants few,
+//! large chunks and a satellite hop a window big enough to cover half a
+//! second of data. Before settling on them, a sender sends the data after
+//! the receiver's first ack in bursts of [`ACK_INTERVAL`] chunks, one burst
+//! per size in [`PROBE_SIZES`], and times how long the receiver takes to
+//! ack each. The rest of the transfer uses the size that moved data
+//! fastest, with an ack window covering what the link carries in a round
+//! trip, up to what the node was [tuned](crate::Tuning::ack_window) for.
+//! The bursts are the data itself, so probing costs a few round trips and
+//! nothing more, and receivers from before this was added take chunks of
+//! any size as well. A receiver that never acks is never probed.
+
+use std::time::{Duration, Instant};
+
+use crate::protocol::{ACK_INTERVAL, CHUNK_SIZE};
+
+/// Chunk sizes tried, smallest first
+pub const PROBE_SIZES: [usize; 3] = [16 * 1024, 64 * 1024, CHUNK_SIZE];
+
+/// How long a sender waits for a burst's ack before giving up on the probe
+/// and keeping the usual chunk size and window
+pub(crate) const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a sender settled on for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Round trip to the receiver on the path in use, or the quickest
+    /// burst's ack if the path's isn't known
+    pub rtt: Duration,
+
+    /// How fast the link carried the fastest burst, in bytes a second
+    pub bytes_per_sec: u64,
+
+    /// Bytes in each chunk from then on
+    pub chunk_size: usize,
+
+    /// Chunks sent ahead of the receiver's acks from then on
+    pub ack_window: usize,
+}
+
+/// How long one burst took to be acked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Sample {
+    pub(crate) chunk_size: usize,
+    pub(crate) bytes: u64,
+    pub(crate) took: Duration,
+}
+
+/// A probe under way, following the bursts as they go out
+#[derive(Debug)]
+pub(crate) struct Probe {
+    samples: Vec<Sample>,
+    /// Where the current burst started, in the data and in time
+    started: Option<(u64, Instant)>,
+    /// Chunks of the current burst sent so far
+    sent: usize,
+    /// The window the node was tuned for, in chunks of [`CHUNK_SIZE`]
+    max_window: usize,
+}
+
+impl Probe {
+    pub(crate) fn new(max_window: usize) -> Self {
+        Self {
+            samples: Vec::new(),
+            started: None,
+            sent: 0,
+            max_window,
+        }
+    }
+
+    /// The size of the next chunk to send
+    pub(crate) fn chunk_size(&self) -> usize {
+        PROBE_SIZES[self.samples.len()]
+    }
+
+    /// Note a chunk of `len` bytes going out at `offset`, returning where
+    /// the burst ends once it's all out, to wait for the ack of
+    pub(crate) fn sent(&mut self, offset: u64, len: usize) -> Option<u64> {
+        self.started.get_or_insert((offset, Instant::now()));
+        self.sent += 1;
+        (self.sent == ACK_INTERVAL).then_some(offset + len as u64)
+    }
+
+    /// The receiver acked the burst, which ended at `end`; once every size
+    /// has been tried, what to use from now on
+    pub(crate) fn acked(&mut self, end: u64, rtt: Option<Duration>) -> Option<Negotiated> {
+        let (start, at) = self.started.take()?;
+        self.samples.push(Sample {
+            chunk_size: self.chunk_size(),
+            bytes: end - start,
+            took: at.elapsed(),
+        });
+        self.sent = 0;
+        (self.samples.len() == PROBE_SIZES.len()).then(|| pick(&self.samples, rtt, self.max_window))
+    }
+}
+
+/// Settle on a chunk size and window from the bursts' `samples`, with a
+/// window of at most `max_window` chunks of [`CHUNK_SIZE`]
+pub(crate) fn pick(samples: &[Sample], rtt: Option<Duration>, max_window: usize) -> Negotiated {
+    let rate = |sample: &Sample| sample.bytes as f64 / sample.took.as_secs_f64().max(1e-6);
+    let fastest = samples
+        .iter()
+        .max_by(|a, b| rate(a).total_cmp(&rate(b)))
+        .copied()
+        .unwrap_or(Sample {
+            chunk_size: CHUNK_SIZE,
+            bytes: 0,
+            took: Duration::ZERO,
+        });
+    let rtt = rtt.unwrap_or_else(|| {
+        samples
+            .iter()
+            .map(|sample| sample.took)
+            .min()
+            .unwrap_or_default()
+    });
+
+    // A burst's ack takes a round trip on top of sending it, so what's
+    // left is how long the link took to carry it
+    let carrying = fastest.took.saturating_sub(rtt);
+    let bytes_per_sec = if carrying.is_zero() {
+        rate(&fastest)
+    } else {
+        fastest.bytes as f64 / carrying.as_secs_f64()
+    };
+
+    // Twice what's in flight over a round trip keeps the link busy while
+    // acks come back; below two acks' worth the sender would stall on
+    // every one
+    let chunk_size = fastest.chunk_size;
+    let in_flight = 2.0 * bytes_per_sec * rtt.as_secs_f64() / chunk_size as f64;
+    let most = (max_window * CHUNK_SIZE / chunk_size).max(1);
+    let ack_window = (in_flight.ceil() as usize).clamp((2 * ACK_INTERVAL).min(most), most);
+    Negotiated {
+        rtt,
+        bytes_per_sec: bytes_per_sec as u64,
+        chunk_size,
+        ack_window,
+    }
+}
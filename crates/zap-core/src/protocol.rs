@@ -1,11 +1,50 @@
+use std::sync::OnceLock;
+
 use serde::{Deserialize, Serialize};
 
+use crate::Error;
+use crate::receipt::Receipt;
+use crate::ticket::Ticket;
+
 /// ALPN protocol identifier for zap
 pub const ZAP_ALPN: &[u8] = b"zap/1";
 
+/// ALPN protocol identifier for TCP tunnels
+pub const TUNNEL_ALPN: &[u8] = b"zap-tunnel/1";
+
+/// The tunnel ALPN that goes with a transfer ALPN
+///
+/// Private namespaces get their tunnels under the same prefix, so
+/// `zap/acme/1` pairs with `zap/acme/1/tunnel`.
+pub fn tunnel_alpn(alpn: &[u8]) -> Vec<u8> {
+    if alpn == ZAP_ALPN {
+        return TUNNEL_ALPN.to_vec();
+    }
+    let mut tunnel = alpn.to_vec();
+    tunnel.extend_from_slice(b"/tunnel");
+    tunnel
+}
+
+/// ALPN protocol identifier for `zap cp` requests to a listening peer
+pub const REMOTE_ALPN: &[u8] = b"zap-remote/1";
+
+/// The remote copy ALPN that goes with a transfer ALPN, named like
+/// [`tunnel_alpn`]
+pub fn remote_alpn(alpn: &[u8]) -> Vec<u8> {
+    if alpn == ZAP_ALPN {
+        return REMOTE_ALPN.to_vec();
+    }
+    let mut remote = alpn.to_vec();
+    remote.extend_from_slice(b"/remote");
+    remote
+}
+
 /// Chunk size for file transfers (256 KB)
 pub const CHUNK_SIZE: usize = 256 * 1024;
 
+/// How many chunks the receiver takes between acks
+pub const ACK_INTERVAL: usize = 8;
+
 /// Messages sent over the wire
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
@@ -25,11 +64,121 @@ pub enum Message {
     /// File data chunk
     Chunk(ChunkData),
 
-    /// Transfer complete
-    Done { checksum: [u8; 32] },
+    /// Transfer complete, with the actual size and BLAKE3 hash of the data
+    Done { checksum: [u8; 32], size: u64 },
 
     /// Error occurred
     Error { message: String },
+
+    /// Sender paused sending data; repeated while paused to keep the
+    /// connection alive
+    Pause,
+
+    /// Sender resumed sending data
+    Resume,
+
+    /// Receiver accepts only some entries of a directory offer, by their
+    /// index in the offer, in ascending order
+    AcceptSome { entries: Vec<u32> },
+
+    /// Receiver wants `len` bytes from `offset` of one file instead of the
+    /// whole offer: entry `0` of a file offer, or the entry at that index of
+    /// a directory offer. Chunk offsets then count from the start of the
+    /// range. Only a sharing sender serves ranges.
+    AcceptRange { entry: u32, offset: u64, len: u64 },
+
+    /// Ask a listening peer to send the file or directory at `path`,
+    /// relative to the directory it serves; answered with `Ticket` or
+    /// `Reject`, and the asker sends `Done` or `Error` once it's received
+    Get { path: String },
+
+    /// Ask a listening peer to receive what `ticket` offers into the
+    /// directory at `path`; answered with `Accept` or `Reject`, then `Done`
+    /// or `Error` once the transfer ends
+    Put { path: String, ticket: Ticket },
+
+    /// Where to receive what was asked for with `Get`
+    Ticket(Ticket),
+
+    /// Receiver wants the BLAKE3 hash of each file in the offer instead of
+    /// the data, answered with `Checksums`. Only a sharing sender answers.
+    AcceptChecksums,
+
+    /// The hash of each file in the offer, in order: one for a file offer,
+    /// one per entry for a directory offer
+    Checksums { entries: Vec<[u8; 32]> },
+
+    /// `Ready`, with proof that the receiver holds the transfer key the
+    /// ticket requires, from [`TransferKey::proof`](crate::ticket::TransferKey::proof)
+    ReadyWithKey { proof: [u8; 32] },
+
+    /// Receiver already has a file with this BLAKE3 hash, in answer to a
+    /// file offer, having compared its own with the hash `AskChecksum` got.
+    /// A sender whose file matches answers with `Done` and no data;
+    /// otherwise it sends the file as if accepted.
+    AlreadyHave { checksum: [u8; 32] },
+
+    /// Receiver has everything before `offset`, so the sender can forget
+    /// it. Sent once with `0` after accepting, to say the receiver acks,
+    /// then every [`ACK_INTERVAL`] chunks. Senders from before this was
+    /// added never read it.
+    Ack { offset: u64 },
+
+    /// First message on a stream the receiver opened after the last one
+    /// broke: the sender carries on from `offset`, which is never before
+    /// the last `Ack`, sending again what it sent since
+    Continue { offset: u64 },
+
+    /// Receiver wants the file's [hash tree](crate::tree::HashTree) before
+    /// any data, answered with `Tree`, after which it accepts as usual.
+    /// Only for a file offer; senders from before this was added fail the
+    /// transfer when asked.
+    AcceptTree,
+
+    /// The hash of each [`PIECE`](crate::tree::PIECE) of the offered file,
+    /// in order
+    Tree { pieces: Vec<[u8; 32]> },
+
+    /// `Offer`, with a short description from the sender. Only sent for a
+    /// labeled offer, which receivers from before this was added fail.
+    LabeledOffer { offer: FileOffer, label: String },
+
+    /// `Offer` of unrelated files as a directory offer, whose entries are
+    /// saved side by side rather than in a folder, with the sender's label
+    /// if any. Only sent for a batch, which receivers from before this was
+    /// added fail.
+    BatchOffer {
+        offer: FileOffer,
+        label: Option<String>,
+    },
+
+    /// Receiver wants the [manifest](crate::manifest) of a directory offer
+    /// before any data, answered with `Manifest`, after which it accepts as
+    /// usual. Senders from before this was added fail the transfer when
+    /// asked.
+    AcceptManifest,
+
+    /// Every file of the offered directory with its BLAKE3 hash, in the
+    /// offer's order
+    Manifest { files: Vec<ManifestEntry> },
+
+    /// Receiver signs for what it got, once `Done` checked out and it's
+    /// saved. Senders from before this was added ignore it.
+    Receipt(Receipt),
+
+    /// Receiver already has the first `offset` bytes of a file offer, whose
+    /// BLAKE3 hash is `checksum`, and wants the rest. The sender checks the
+    /// hash against its own file and sends the rest as it would a range, or
+    /// rejects the offer if they differ. Senders from before this was added
+    /// fail the transfer.
+    AcceptFrom { offset: u64, checksum: [u8; 32] },
+
+    /// Receiver has files the size of the offered one and wants the file's
+    /// BLAKE3 hash to compare them with, answered with `Checksums`, after
+    /// which it accepts as usual or sends `AlreadyHave`. The receiver's
+    /// hashes never leave it. Only for a file offer; senders from before
+    /// this was added fail the transfer when asked.
+    AskChecksum,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,11 +186,77 @@ pub struct FileOffer {
     /// Original filename
     pub name: String,
 
-    /// Total size in bytes
-    pub size: u64,
+    /// Total size in bytes, or `None` when streaming data of unknown length
+    pub size: Option<u64>,
 
     /// BLAKE3 hash of the file (computed incrementally)
     pub checksum: Option<[u8; 32]>,
+
+    /// How the data is laid out
+    pub kind: OfferKind,
+
+    /// A short description from the sender, like "Q3 report v2", to tell
+    /// transfers apart. It travels in [`Message::LabeledOffer`] rather than
+    /// in the offer itself, so unlabeled offers are the same as ever.
+    #[serde(skip)]
+    pub label: Option<String>,
+
+    /// Whether this is a directory offer of separate files, saved where the
+    /// receiver saves instead of in a folder named after the offer. It
+    /// travels in [`Message::BatchOffer`].
+    #[serde(skip)]
+    pub batch: bool,
+}
+
+impl FileOffer {
+    /// The message that makes this offer
+    pub fn into_message(self) -> Message {
+        if self.batch {
+            let label = self.label.clone();
+            return Message::BatchOffer { offer: self, label };
+        }
+        match self.label.clone() {
+            Some(label) => Message::LabeledOffer { offer: self, label },
+            None => Message::Offer(self),
+        }
+    }
+}
+
+/// What an offer contains
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OfferKind {
+    /// A single file
+    File,
+
+    /// A directory packed into a tar archive, unpacked by the receiver
+    Archive,
+
+    /// A directory sent file by file: the data is the contents of each
+    /// entry in order
+    Directory { entries: Vec<DirEntry> },
+}
+
+/// A file inside a directory offer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirEntry {
+    /// Path relative to the directory, `/`-separated
+    pub path: String,
+
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// A file of a directory offer, with its hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path relative to the directory, as in the offer
+    pub path: String,
+
+    /// Size in bytes
+    pub size: u64,
+
+    /// BLAKE3 hash of the file
+    pub checksum: [u8; 32],
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,4 +278,70 @@ impl Message {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, postcard::Error> {
         postcard::from_bytes(bytes)
     }
+
+    /// Serialize message to bytes in `format`
+    pub fn encode(&self, format: WireFormat) -> crate::Result<Vec<u8>> {
+        let encoded = match format {
+            WireFormat::Postcard => self.to_bytes().map_err(|e| e.to_string()),
+            WireFormat::Json => serde_json::to_vec(self).map_err(|e| e.to_string()),
+        };
+        encoded.map_err(|e| Error::Protocol(format!("serialization error: {}", e)))
+    }
+
+    /// Deserialize message from bytes in `format`
+    pub fn decode(format: WireFormat, bytes: &[u8]) -> crate::Result<Self> {
+        let decoded = match format {
+            WireFormat::Postcard => Self::from_bytes(bytes).map_err(|e| e.to_string()),
+            WireFormat::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+        };
+        decoded.map_err(|e| Error::Protocol(format!("deserialization error: {}", e)))
+    }
+}
+
+/// How messages are encoded on the wire
+///
+/// Postcard is compact and the default. `ZAP_WIRE=json` sends JSON instead,
+/// so a stream can be read by eye or from a logging proxy, and logs each
+/// message at trace level under the `zap::wire` target. Frames carry
+/// [`JSON_FRAME`] in their length prefix when they hold JSON, so one end
+/// can be switched without the other: every frame is read in the format it
+/// says it's in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Postcard,
+    Json,
+}
+
+/// Set in a frame's length prefix when the message is JSON. Messages are
+/// far smaller than 2 GB, so the bit is otherwise always clear.
+pub const JSON_FRAME: u32 = 1 << 31;
+
+impl WireFormat {
+    /// The format chosen with `ZAP_WIRE`, read once
+    pub fn from_env() -> Self {
+        static FORMAT: OnceLock<WireFormat> = OnceLock::new();
+        *FORMAT.get_or_init(|| match std::env::var("ZAP_WIRE").as_deref() {
+            Ok("json") => WireFormat::Json,
+            _ => WireFormat::Postcard,
+        })
+    }
+
+    /// The length prefix for a message of `len` bytes in this format
+    pub fn prefix(self, len: usize) -> u32 {
+        match self {
+            WireFormat::Postcard => len as u32,
+            WireFormat::Json => len as u32 | JSON_FRAME,
+        }
+    }
+
+    /// The format and length of a message from its length prefix
+    pub fn from_prefix(prefix: u32) -> (Self, usize) {
+        let format = if prefix & JSON_FRAME != 0 {
+            WireFormat::Json
+        } else {
+            WireFormat::Postcard
+        };
+        (format, (prefix & !JSON_FRAME) as usize)
+    }
 }
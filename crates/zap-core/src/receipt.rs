@@ -0,0 +1,116 @@
+//! Proof that a receiver got what was sent
+//!
+//! Once a receive has checked out and been saved, the receiver signs a
+//! [`Receipt`] with its node key and sends it back in a
+//! [`Message::Receipt`](crate::protocol::Message::Receipt). It names both
+//! nodes, the data's BLAKE3 hash and size, and when it arrived, so whoever
+//! holds it can show that this receiver got exactly this data, and nobody
+//! without the receiver's key can make one up. Receivers from before this
+//! was added don't send one, and senders from before it ignore it.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use iroh::{PublicKey, SecretKey, Signature};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Error, Result};
+
+/// What a receiver's signature covers starts with this, so it can't pass
+/// for anything else the key signs
+const CONTEXT: &[u8] = b"zap receipt v1";
+
+/// A receiver's signed statement that it received data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    /// The node the data came from
+    pub sender: PublicKey,
+
+    /// The node that received it, whose key signed this
+    pub receiver: PublicKey,
+
+    /// BLAKE3 hash of the data
+    pub checksum: [u8; 32],
+
+    /// Size of the data in bytes
+    pub size: u64,
+
+    /// When the data was in, in seconds since the Unix epoch
+    pub received_at: u64,
+
+    #[serde(with = "signature_bytes")]
+    pub signature: Signature,
+}
+
+impl Receipt {
+    /// Sign for `size` bytes with `checksum` from `sender`, received just
+    /// now by the node with `key`
+    pub fn sign(key: &SecretKey, sender: PublicKey, checksum: [u8; 32], size: u64) -> Self {
+        let received_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let receiver = key.public();
+        let signed = signed_bytes(&sender, &receiver, &checksum, size, received_at);
+        Self {
+            sender,
+            receiver,
+            checksum,
+            size,
+            received_at,
+            signature: key.sign(&signed),
+        }
+    }
+
+    /// Check that the receiver signed this as it reads
+    pub fn verify(&self) -> Result<()> {
+        let signed = signed_bytes(
+            &self.sender,
+            &self.receiver,
+            &self.checksum,
+            self.size,
+            self.received_at,
+        );
+        self.receiver
+            .verify(&signed, &self.signature)
+            .map_err(|_| Error::Protocol("the receipt's signature doesn't match".into()))
+    }
+}
+
+fn signed_bytes(
+    sender: &PublicKey,
+    receiver: &PublicKey,
+    checksum: &[u8; 32],
+    size: u64,
+    received_at: u64,
+) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(CONTEXT.len() + 112);
+    bytes.extend_from_slice(CONTEXT);
+    bytes.extend_from_slice(sender.as_bytes());
+    bytes.extend_from_slice(receiver.as_bytes());
+    bytes.extend_from_slice(checksum);
+    bytes.extend_from_slice(&size.to_le_bytes());
+    bytes.extend_from_slice(&received_at.to_le_bytes());
+    bytes
+}
+
+/// Signatures as their 64 bytes
+mod signature_bytes {
+    use super::*;
+
+    pub(super) fn serialize<S: Serializer>(
+        signature: &Signature,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&signature.to_bytes())
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Signature, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let bytes: [u8; 64] = bytes
+            .try_into()
+            .map_err(|_| serde::de::Error::custom("a signature is 64 bytes"))?;
+        Ok(Signature::from_bytes(&bytes))
+    }
+}
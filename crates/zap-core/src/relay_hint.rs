@@ -0,0 +1,104 @@
+//! Codes that say which relay they're on
+//!
+//! A short code only means something on the relay that gave it out, so a
+//! receiver using another relay has to be told which one with `--relay`.
+//! A [`RelayHint`] is a code together with its relay's host, written as
+//! `zapper.cloud:abc123`, or as a token like `zap1...` that can be pasted
+//! whole. The token ends in a checksum, so a mistyped one is caught instead
+//! of being looked up on some other relay.
+
+use std::fmt;
+
+use crate::{Error, Result};
+
+/// What every token starts with; `1` isn't in the base32 alphabet, so no
+/// ticket does
+pub const TOKEN_PREFIX: &str = "zap1";
+
+const CHECKSUM_LEN: usize = 4;
+
+/// A code and the relay it was registered on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayHint {
+    /// The relay's host, with its port if it has one; plain HTTP relays
+    /// keep their `http://`
+    pub relay: String,
+    pub code: String,
+}
+
+impl RelayHint {
+    /// The hint for `code` on the relay at `relay_url`
+    pub fn new(relay_url: &str, code: &str) -> Self {
+        let relay = relay_url.trim_end_matches('/');
+        let relay = relay.strip_prefix("https://").unwrap_or(relay);
+        Self {
+            relay: relay.to_string(),
+            code: code.to_string(),
+        }
+    }
+
+    /// The relay's URL, over HTTPS unless it says otherwise
+    pub fn url(&self) -> String {
+        if self.relay.starts_with("http://") || self.relay.starts_with("https://") {
+            self.relay.clone()
+        } else {
+            format!("https://{}", self.relay)
+        }
+    }
+
+    /// The hint as a single token
+    pub fn token(&self) -> String {
+        let mut bytes = self.payload();
+        let checksum = checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+        let encoded = data_encoding::BASE32_NOPAD.encode(&bytes).to_lowercase();
+        format!("{}{}", TOKEN_PREFIX, encoded)
+    }
+
+    /// Read a token made by [`token`](Self::token)
+    pub fn from_token(token: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidTicket(format!("relay token {}", reason));
+        let token = token.trim().to_lowercase();
+        let encoded = token
+            .strip_prefix(TOKEN_PREFIX)
+            .ok_or_else(|| invalid("doesn't start with zap1"))?;
+        let bytes = data_encoding::BASE32_NOPAD
+            .decode(encoded.to_uppercase().as_bytes())
+            .map_err(|_| invalid("isn't base32"))?;
+        if bytes.len() <= CHECKSUM_LEN {
+            return Err(invalid("is too short"));
+        }
+
+        let (payload, sum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        if checksum(payload) != sum {
+            return Err(invalid("doesn't check out, so it may be mistyped"));
+        }
+        let payload = std::str::from_utf8(payload).map_err(|_| invalid("isn't text"))?;
+        let (relay, code) = payload
+            .rsplit_once(':')
+            .filter(|(relay, code)| !relay.is_empty() && !code.is_empty())
+            .ok_or_else(|| invalid("names no relay"))?;
+        Ok(Self {
+            relay: relay.to_string(),
+            code: code.to_string(),
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+/// `relay:code`
+impl fmt::Display for RelayHint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.relay, self.code)
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = blake3::hash(payload);
+    let mut sum = [0; CHECKSUM_LEN];
+    sum.copy_from_slice(&hash.as_bytes()[..CHECKSUM_LEN]);
+    sum
+}
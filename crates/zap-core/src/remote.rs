@@ -0,0 +1,487 @@
+//! Copies to and from a paired peer, for `zap cp`
+//!
+//! A listening peer serves one directory to the node ids it trusts. A
+//! request only carries a path, and a ticket when copying in; the data
+//! itself moves as an ordinary transfer. On the listening side that
+//! transfer runs on a node of its own, so the listener's endpoint keeps
+//! accepting requests.
+
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use iroh::{Endpoint, EndpointAddr, PublicKey};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+use crate::node::ZapNode;
+use crate::protocol::{self, Message};
+use crate::ticket::Ticket;
+use crate::transfer::{
+    self, PhaseTimings, ReceiveOptions, ReceiveProgress, SendOptions, SendProgress, SendSource,
+    TransferHandle, finish, recv_message, reject, send_message,
+};
+use crate::{Error, Result};
+
+/// What a listener was asked and how it went
+#[derive(Debug, Clone)]
+pub enum ListenEvent {
+    /// Waiting for requests
+    Listening,
+
+    /// A peer that isn't paired tried to connect and was turned away
+    Refused { peer: PublicKey },
+
+    /// A peer copied a file or directory from here
+    Sent { peer: PublicKey, path: PathBuf },
+
+    /// A peer copied a file or directory in
+    Received { peer: PublicKey, path: PathBuf },
+
+    /// A request was turned down or its transfer failed; the listener
+    /// keeps going
+    Failed { peer: PublicKey, message: String },
+
+    /// Error occurred
+    Error(String),
+}
+
+/// What a listener serves, and to whom
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+    /// Directory remote paths are relative to; nothing outside it can be
+    /// reached, even through symlinks
+    pub root: PathBuf,
+
+    /// Node ids allowed to make requests
+    pub peers: Vec<PublicKey>,
+
+    /// Refuse copies into the directory
+    pub read_only: bool,
+
+    /// Sort copies in into folders of the listener's choosing, instead of
+    /// the directories peers name
+    pub inbox: Option<Inbox>,
+}
+
+/// Decides where a peer's copy in goes, and how it's received
+///
+/// Given the peer, the inbox returns a directory relative to the root,
+/// which is created if needed, and the options to receive with, e.g. to
+/// rename what's already there or to turn down what's over a quota.
+#[derive(Clone)]
+pub struct Inbox(Arc<InboxFn>);
+
+type InboxFn = dyn Fn(&PublicKey) -> (PathBuf, ReceiveOptions) + Send + Sync;
+
+impl Inbox {
+    pub fn new<F>(place: F) -> Self
+    where
+        F: Fn(&PublicKey) -> (PathBuf, ReceiveOptions) + Send + Sync + 'static,
+    {
+        Self(Arc::new(place))
+    }
+}
+
+impl fmt::Debug for Inbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Inbox")
+    }
+}
+
+/// Answer requests from paired peers until the endpoint closes
+pub async fn run_listener(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    options: ListenOptions,
+    progress: mpsc::Sender<ListenEvent>,
+) -> Result<()> {
+    let root = tokio::fs::canonicalize(&options.root).await?;
+    let remote = protocol::remote_alpn(alpn);
+    let _ = progress.send(ListenEvent::Listening).await;
+
+    loop {
+        let Some(incoming) = endpoint.accept().await else {
+            return Err(Error::ConnectionFailed("endpoint closed".into()));
+        };
+
+        let conn = match incoming.accept() {
+            Ok(connecting) => match connecting.await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    debug!("incoming request connection failed: {}", e);
+                    continue;
+                }
+            },
+            Err(e) => {
+                debug!("could not accept request connection: {}", e);
+                continue;
+            }
+        };
+
+        if conn.alpn() != remote {
+            debug!("ignoring connection with wrong ALPN");
+            continue;
+        }
+
+        let peer = conn.remote_id();
+        if !options.peers.contains(&peer) {
+            info!(%peer, "refused a peer that isn't paired");
+            conn.close(0u32.into(), b"not paired");
+            let _ = progress.send(ListenEvent::Refused { peer }).await;
+            continue;
+        }
+
+        let request = Request {
+            peer,
+            alpn: alpn.to_vec(),
+            root: root.clone(),
+            read_only: options.read_only,
+            inbox: options.inbox.clone(),
+        };
+        let progress = progress.clone();
+        tokio::spawn(async move {
+            let event = match request.serve(conn).await {
+                Ok(event) => event,
+                Err(e) => ListenEvent::Failed {
+                    peer,
+                    message: e.to_string(),
+                },
+            };
+            let _ = progress.send(event).await;
+        });
+    }
+}
+
+/// One request from a paired peer
+struct Request {
+    peer: PublicKey,
+    alpn: Vec<u8>,
+    /// The served directory, canonicalized
+    root: PathBuf,
+    read_only: bool,
+    inbox: Option<Inbox>,
+}
+
+impl Request {
+    async fn serve(self, conn: Connection) -> Result<ListenEvent> {
+        let (mut send_stream, mut recv_stream) = conn.accept_bi().await?;
+        match recv_message(&mut recv_stream).await? {
+            Message::Get { path } => {
+                let path = self.get(&mut send_stream, &mut recv_stream, &path).await?;
+                Ok(ListenEvent::Sent {
+                    peer: self.peer,
+                    path,
+                })
+            }
+            Message::Put { path, ticket } => {
+                let path = self.put(&mut send_stream, &path, ticket).await?;
+                Ok(ListenEvent::Received {
+                    peer: self.peer,
+                    path,
+                })
+            }
+            _ => Err(Error::Protocol("expected Get or Put".into())),
+        }
+    }
+
+    /// Send `path` on a node of its own, until the peer says how it went
+    async fn get(
+        &self,
+        send_stream: &mut SendStream,
+        recv_stream: &mut RecvStream,
+        path: &str,
+    ) -> Result<PathBuf> {
+        let source = match resolve(&self.root, path).await {
+            Ok(source) => source,
+            Err(reason) => return Err(reject(send_stream, reason).await),
+        };
+        info!(peer = %self.peer, path = %source.display(), "sending to a paired peer");
+
+        let node = ZapNode::builder().alpn(self.alpn.clone()).build().await?;
+        let (ticket, _sending) = node.send(&source).await?;
+        send_message(send_stream, &Message::Ticket(ticket)).await?;
+
+        let reply = recv_message(recv_stream).await;
+        let _ = node.shutdown().await;
+        match reply? {
+            Message::Done { .. } => Ok(source),
+            Message::Error { message } => Err(Error::TransferFailed(message)),
+            _ => Err(Error::Protocol("expected Done or Error".into())),
+        }
+    }
+
+    /// Receive what `ticket` offers into `path`, on a node of its own
+    async fn put(
+        &self,
+        send_stream: &mut SendStream,
+        path: &str,
+        ticket: Ticket,
+    ) -> Result<PathBuf> {
+        if self.read_only {
+            return Err(reject(send_stream, "copies in are turned off here".into()).await);
+        }
+        // Only fetch from the peer that asked, so a request can't point
+        // this node at anyone else
+        if ticket.addr.id != self.peer {
+            return Err(reject(send_stream, "the ticket must be your own".into()).await);
+        }
+        let (dir, options) = match &self.inbox {
+            // The peer's choice of directory doesn't matter then
+            Some(inbox) => {
+                let (dir, options) = (inbox.0)(&self.peer);
+                match self.inbox_dir(&dir).await {
+                    Ok(dir) => (dir, options),
+                    Err(reason) => return Err(reject(send_stream, reason).await),
+                }
+            }
+            None => match resolve(&self.root, path).await {
+                Ok(dir) if dir.is_dir() => (dir, ReceiveOptions::default()),
+                Ok(_) => {
+                    return Err(reject(send_stream, format!("{} is not a directory", path)).await);
+                }
+                Err(reason) => return Err(reject(send_stream, reason).await),
+            },
+        };
+        info!(peer = %self.peer, dir = %dir.display(), "receiving from a paired peer");
+
+        send_message(send_stream, &Message::Accept).await?;
+        let node = ZapNode::builder().alpn(self.alpn.clone()).build().await?;
+        let receiving = node
+            .receive_with_options(ticket, Some(&dir), options)
+            .await?;
+        let outcome = watch_receive(receiving, None).await;
+        let _ = node.shutdown().await;
+
+        let (reply, result) = match outcome {
+            Ok((path, checksum, size, _)) => (Message::Done { checksum, size }, Ok(path)),
+            Err(e) => (
+                Message::Error {
+                    message: e.to_string(),
+                },
+                Err(e),
+            ),
+        };
+        send_message(send_stream, &reply).await?;
+        finish(send_stream).await;
+        result
+    }
+
+    /// Create an inbox's directory, which has to stay inside the root
+    async fn inbox_dir(&self, dir: &Path) -> std::result::Result<PathBuf, String> {
+        if !dir.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("{} is not a plain relative path", dir.display()));
+        }
+        tokio::fs::create_dir_all(self.root.join(dir))
+            .await
+            .map_err(|e| format!("can't create {}: {}", dir.display(), e))?;
+        resolve(&self.root, &dir.to_string_lossy()).await
+    }
+}
+
+/// Copy `path` from a listening peer into `output_dir`
+pub async fn run_get(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    peer: PublicKey,
+    path: String,
+    output_dir: Option<PathBuf>,
+    progress: mpsc::Sender<ReceiveProgress>,
+) -> Result<()> {
+    let _ = progress.send(ReceiveProgress::Connecting).await;
+    let (mut send_stream, mut recv_stream) = open(&endpoint, alpn, peer).await?;
+    send_message(&mut send_stream, &Message::Get { path }).await?;
+    let ticket = match recv_message(&mut recv_stream).await? {
+        Message::Ticket(ticket) => ticket,
+        Message::Reject { reason } => return Err(Error::TransferFailed(reason)),
+        _ => return Err(Error::Protocol("expected Ticket or Reject".into())),
+    };
+
+    let (receive_tx, receive_rx) = mpsc::channel(32);
+    let receive = async move {
+        let options = ReceiveOptions::default();
+        let result = transfer::run_receiver(
+            endpoint,
+            alpn,
+            ticket,
+            output_dir,
+            options,
+            receive_tx.clone(),
+        )
+        .await;
+        if let Err(e) = result {
+            let _ = receive_tx.send(ReceiveProgress::Error(e.to_string())).await;
+        }
+    };
+    let ((), outcome) = tokio::join!(receive, watch_receive(receive_rx, Some(&progress)));
+
+    // The listener hears how it went before the caller does, who may exit
+    // as soon as it sees the transfer end
+    let reply = match &outcome {
+        Ok((_, checksum, size, _)) => Message::Done {
+            checksum: *checksum,
+            size: *size,
+        },
+        Err(e) => Message::Error {
+            message: e.to_string(),
+        },
+    };
+    let _ = send_message(&mut send_stream, &reply).await;
+    finish(&mut send_stream).await;
+
+    let (path, checksum, _, timings) = outcome?;
+    let _ = progress
+        .send(ReceiveProgress::Complete {
+            path,
+            checksum,
+            timings,
+        })
+        .await;
+    Ok(())
+}
+
+/// Where [`run_put`] copies to
+pub struct Put {
+    /// The listening peer
+    pub peer: PublicKey,
+    /// The directory there to copy into
+    pub path: String,
+    /// This node's own ticket, which the listener receives from
+    pub ticket: Ticket,
+}
+
+/// Copy `source` to a listening peer, into the directory `put` names
+///
+/// `ack_window` is how many chunks go out ahead of the listener's acks.
+pub async fn run_put(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    put: Put,
+    source: SendSource,
+    ack_window: usize,
+    progress: mpsc::Sender<SendProgress>,
+) -> Result<()> {
+    let Put { peer, path, ticket } = put;
+    let (mut send_stream, mut recv_stream) = open(&endpoint, alpn, peer).await?;
+    send_message(&mut send_stream, &Message::Put { path, ticket }).await?;
+    match recv_message(&mut recv_stream).await? {
+        Message::Accept => {}
+        Message::Reject { reason } => return Err(Error::TransferFailed(reason)),
+        _ => return Err(Error::Protocol("expected Accept or Reject".into())),
+    }
+
+    // Progress is passed on as it comes, except `Complete`, which waits
+    // for the listener to confirm it saved everything
+    let (send_tx, mut send_rx) = mpsc::channel(32);
+    let handle = TransferHandle::new();
+    let send = transfer::run_sender(
+        endpoint,
+        alpn,
+        source,
+        handle,
+        SendOptions {
+            ack_window: Some(ack_window),
+            ..Default::default()
+        },
+        send_tx,
+    );
+    let forward = async {
+        let mut complete = None;
+        while let Some(update) = send_rx.recv().await {
+            match update {
+                SendProgress::Complete { .. } => complete = Some(update),
+                _ => {
+                    let _ = progress.send(update).await;
+                }
+            }
+        }
+        complete
+    };
+    let sending = async {
+        let (result, complete) = tokio::join!(send, forward);
+        result.map(|()| complete)
+    };
+    tokio::pin!(sending);
+
+    // The listener may fail before it ever connects, which only the
+    // request stream would tell
+    let mut sent = None;
+    let reply = tokio::select! {
+        reply = recv_message(&mut recv_stream) => reply,
+        result = &mut sending => {
+            sent = Some(result);
+            recv_message(&mut recv_stream).await
+        }
+    }?;
+    match reply {
+        Message::Done { .. } => {
+            let complete = match sent {
+                Some(result) => result?,
+                None => sending.await?,
+            };
+            if let Some(complete) = complete {
+                let _ = progress.send(complete).await;
+            }
+            Ok(())
+        }
+        Message::Error { message } => Err(Error::TransferFailed(message)),
+        _ => Err(Error::Protocol("expected Done or Error".into())),
+    }
+}
+
+/// Open a request stream to a listening peer
+async fn open(
+    endpoint: &Endpoint,
+    alpn: &[u8],
+    peer: PublicKey,
+) -> Result<(SendStream, RecvStream)> {
+    debug!(%peer, "connecting to listening peer");
+    let remote = protocol::remote_alpn(alpn);
+    let conn = transfer::connect(endpoint, EndpointAddr::new(peer), &remote).await?;
+    Ok(conn.open_bi().await?)
+}
+
+/// Pass a receive's progress on until it ends, returning where it was
+/// saved, its checksum, size and timings
+///
+/// The final `Complete` isn't passed on, so the caller can report first.
+async fn watch_receive(
+    mut receiving: mpsc::Receiver<ReceiveProgress>,
+    progress: Option<&mpsc::Sender<ReceiveProgress>>,
+) -> Result<(PathBuf, [u8; 32], u64, PhaseTimings)> {
+    let mut size = 0;
+    while let Some(update) = receiving.recv().await {
+        match update {
+            ReceiveProgress::Complete {
+                path,
+                checksum,
+                timings,
+            } => return Ok((path, checksum, size, timings)),
+            ReceiveProgress::Error(message) => return Err(Error::TransferFailed(message)),
+            ReceiveProgress::Receiving { bytes_received, .. } => size = bytes_received,
+            _ => {}
+        }
+        if let Some(progress) = progress {
+            let _ = progress.send(update).await;
+        }
+    }
+    Err(Error::TransferFailed(
+        "the transfer ended unexpectedly".into(),
+    ))
+}
+
+/// Resolve a peer's path inside `root`, which must be canonical
+///
+/// A leading `/` means the root itself. Symlinks are followed before the
+/// check, so none can lead outside.
+pub(crate) async fn resolve(root: &Path, path: &str) -> std::result::Result<PathBuf, String> {
+    let joined = root.join(path.trim_start_matches('/'));
+    let resolved = tokio::fs::canonicalize(&joined)
+        .await
+        .map_err(|_| format!("{} does not exist", path))?;
+    if !resolved.starts_with(root) {
+        return Err(format!("{} is outside the shared directory", path));
+    }
+    Ok(resolved)
+}
@@ -0,0 +1,327 @@
+//! Carrying on with a file whose receive broke off, here or on another
+//! machine
+//!
+//! With [`ReceiveOptions::keep_state`](crate::ReceiveOptions::keep_state),
+//! a file being received gets a `.zap-state` file next to it: the ticket,
+//! the file's name and size as offered, and the BLAKE3 hash of every
+//! [`BLOCK`] received so far. If the receive fails, both are left behind.
+//! Move them together to another machine if need be, and [`run_resume`]
+//! checks the blocks, keeps the partial file up to the last one still
+//! intact, and asks for the rest with the hash of what it kept, which the
+//! sender checks against its own file. Only a sender still there answers:
+//! one that [keeps serving](crate::SendOptions::keep_serving), or that
+//! waits for the receiver to [come back](crate::SendOptions::resume).
+
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use iroh::Endpoint;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::filename::local_path;
+use crate::protocol::{ACK_INTERVAL, Message, OfferKind};
+use crate::ticket::Ticket;
+use crate::transfer::{
+    Durability, PhaseTimings, ReceiveProgress, ReceivedFile, connect, expect_offer, ready,
+    recv_message, reject, send_message,
+};
+use crate::validate::validate_offer;
+use crate::{Error, Result};
+
+/// What state files end in, after the name of the file they're for
+pub const STATE_EXTENSION: &str = "zap-state";
+
+/// How much of the file each hash in the state covers
+pub const BLOCK: u64 = 16 * 1024 * 1024;
+
+/// What a `.zap-state` file holds, as JSON
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeState {
+    /// The sender's ticket, with its key if it has one
+    pub ticket: String,
+    /// The file's name as offered
+    pub name: String,
+    /// The file's size as offered
+    pub size: u64,
+    /// The BLAKE3 hash of each whole block received, in hex
+    pub blocks: Vec<String>,
+}
+
+impl ResumeState {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let json = tokio::fs::read(local_path(path)).await?;
+        serde_json::from_slice(&json)
+            .map_err(|e| Error::Protocol(format!("invalid state file {}: {}", path.display(), e)))
+    }
+}
+
+/// The state file kept for `file`
+pub fn state_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".");
+    path.push(STATE_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// The partial file a state file is for
+pub fn partial_path(state: &Path) -> Result<PathBuf> {
+    match state.extension() {
+        Some(extension) if extension == STATE_EXTENSION => Ok(state.with_extension("")),
+        _ => Err(Error::Protocol(format!(
+            "{} isn't a .{} file",
+            state.display(),
+            STATE_EXTENSION
+        ))),
+    }
+}
+
+/// Keeps the state file of a file being received up to date
+pub(crate) struct StateFile {
+    path: PathBuf,
+    state: ResumeState,
+    /// The block being received
+    block: blake3::Hasher,
+    block_len: u64,
+}
+
+impl StateFile {
+    /// The state for `file`, which has the blocks in `state` so far
+    pub(crate) fn new(file: &Path, state: ResumeState) -> Self {
+        Self {
+            path: state_path(file),
+            state,
+            block: blake3::Hasher::new(),
+            block_len: 0,
+        }
+    }
+
+    /// Count `data` as written to the file, returning whether a block was
+    /// completed, which makes the state worth saving
+    pub(crate) fn update(&mut self, mut data: &[u8]) -> bool {
+        let mut completed = false;
+        while !data.is_empty() {
+            let n = ((BLOCK - self.block_len) as usize).min(data.len());
+            self.block.update(&data[..n]);
+            self.block_len += n as u64;
+            data = &data[n..];
+            if self.block_len == BLOCK {
+                let hash = std::mem::take(&mut self.block).finalize();
+                self.state.blocks.push(hash.to_hex().to_string());
+                self.block_len = 0;
+                completed = true;
+            }
+        }
+        completed
+    }
+
+    /// Write the state out, replacing what was there in one go; a failure
+    /// is only logged, since the receive itself is fine
+    pub(crate) async fn save(&self) {
+        let json = serde_json::to_vec_pretty(&self.state).expect("state serializes");
+        let tmp = self.path.with_extension(format!("{}.tmp", STATE_EXTENSION));
+        let written = async {
+            tokio::fs::write(local_path(&tmp), json).await?;
+            tokio::fs::rename(local_path(&tmp), local_path(&self.path)).await
+        };
+        if let Err(e) = written.await {
+            warn!(path = %self.path.display(), "couldn't save the state file: {}", e);
+        }
+    }
+
+    /// Remove the state file, once the receive is done with it
+    pub(crate) async fn remove(self) {
+        let _ = tokio::fs::remove_file(local_path(&self.path)).await;
+    }
+}
+
+/// Carry on receiving the file `state_path` is for, into the partial file
+/// next to it
+pub(crate) async fn run_resume(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    state_path: PathBuf,
+    durability: Durability,
+    progress: mpsc::Sender<ReceiveProgress>,
+) -> Result<()> {
+    let started = Instant::now();
+    let mut timings = PhaseTimings::default();
+    let path = partial_path(&state_path)?;
+    let mut state = ResumeState::load(&state_path).await?;
+    let ticket = Ticket::deserialize(&state.ticket)?;
+
+    // Whatever doesn't match its hash, and everything after it, is fetched
+    // again
+    let mut whole = blake3::Hasher::new();
+    let verified = verify_blocks(&path, &state.blocks, &mut whole).await?;
+    if verified < state.blocks.len() {
+        warn!(
+            verified,
+            blocks = state.blocks.len(),
+            "the partial file doesn't match its state past this block"
+        );
+    }
+    state.blocks.truncate(verified);
+    let offset = verified as u64 * BLOCK;
+    let len = state.size.checked_sub(offset).ok_or_else(|| {
+        Error::Protocol("the state file has more blocks than the file has".into())
+    })?;
+    info!(offset, "resuming");
+
+    let _ = progress.send(ReceiveProgress::Connecting).await;
+    let ready = ready(&endpoint, &ticket)?;
+    let conn = connect(&endpoint, ticket.addr.clone(), alpn).await?;
+    let _ = progress.send(ReceiveProgress::Connected).await;
+    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
+    send_message(&mut send_stream, &ready).await?;
+
+    let offer = expect_offer(recv_message(&mut recv_stream).await?)?;
+    if let Err(violation) = validate_offer(&offer) {
+        let _ = reject(&mut send_stream, violation.to_string()).await;
+        return Err(violation.into());
+    }
+    if offer.kind != OfferKind::File || offer.name != state.name || offer.size != Some(state.size) {
+        let reason = "the sender is offering something else now".to_string();
+        return Err(reject(&mut send_stream, reason).await);
+    }
+    let _ = progress
+        .send(ReceiveProgress::Offer {
+            name: offer.name.clone(),
+            size: offer.size,
+            kind: offer.kind.clone(),
+            label: offer.label.clone(),
+        })
+        .await;
+    let _ = progress
+        .send(ReceiveProgress::Receiving {
+            bytes_received: offset,
+            total_bytes: offer.size,
+        })
+        .await;
+
+    // The sender checks what's kept against its file before carrying on
+    let request = Message::AcceptFrom {
+        offset,
+        checksum: *whole.finalize().as_bytes(),
+    };
+    send_message(&mut send_stream, &request).await?;
+    send_message(&mut send_stream, &Message::Ack { offset: 0 }).await?;
+    timings.handshake = started.elapsed();
+    let transfer_start = Instant::now();
+
+    let mut file = ReceivedFile::open_at(&local_path(&path), offset, durability).await?;
+    let mut state = StateFile::new(&path, state);
+    state.save().await;
+    let mut range = blake3::Hasher::new();
+    let mut received = 0u64;
+    let mut unacked = 0;
+    loop {
+        match recv_message(&mut recv_stream).await? {
+            Message::Chunk(chunk) => {
+                if chunk.offset != received || received + chunk.data.len() as u64 > len {
+                    return Err(Error::Protocol("chunk outside the requested range".into()));
+                }
+                file.write(&chunk.data).await?;
+                range.update(&chunk.data);
+                whole.update(&chunk.data);
+                received += chunk.data.len() as u64;
+                if state.update(&chunk.data) {
+                    file.flush().await?;
+                    state.save().await;
+                }
+                unacked += 1;
+                if unacked == ACK_INTERVAL {
+                    unacked = 0;
+                    let ack = Message::Ack { offset: received };
+                    let _ = send_message(&mut send_stream, &ack).await;
+                }
+                let _ = progress
+                    .send(ReceiveProgress::Receiving {
+                        bytes_received: offset + received,
+                        total_bytes: offer.size,
+                    })
+                    .await;
+            }
+            Message::Done { checksum, size } => {
+                if size != len || received != len {
+                    return Err(Error::TransferFailed(format!(
+                        "size mismatch: expected {} bytes, received {}",
+                        len, received
+                    )));
+                }
+                if *range.finalize().as_bytes() != checksum {
+                    return Err(Error::TransferFailed("checksum mismatch".into()));
+                }
+                break;
+            }
+            Message::Pause => {
+                let _ = progress.send(ReceiveProgress::Paused).await;
+            }
+            Message::Resume => {
+                let _ = progress.send(ReceiveProgress::Resumed).await;
+            }
+            Message::Reject { reason } => return Err(Error::TransferFailed(reason)),
+            Message::Error { message } => return Err(Error::TransferFailed(message)),
+            _ => return Err(Error::Protocol("unexpected message".into())),
+        }
+    }
+    timings.transfer = transfer_start.elapsed();
+
+    let verification_start = Instant::now();
+    file.finish().await?;
+    state.remove().await;
+    timings.verification = verification_start.elapsed();
+    let checksum = *whole.finalize().as_bytes();
+    let _ = progress
+        .send(ReceiveProgress::Complete {
+            path: path.clone(),
+            checksum,
+            timings,
+        })
+        .await;
+    info!(path = %path.display(), resumed_at = offset, "transfer complete");
+    Ok(())
+}
+
+/// How many of `blocks` the start of the file at `path` still matches,
+/// feeding those into `whole`
+pub(crate) async fn verify_blocks(
+    path: &Path,
+    blocks: &[String],
+    whole: &mut blake3::Hasher,
+) -> Result<usize> {
+    let mut file = match File::open(local_path(path)).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("no partial file, starting over");
+            return Ok(0);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut buffer = vec![0u8; BLOCK as usize];
+    for (i, expected) in blocks.iter().enumerate() {
+        let read = read_block(&mut file, &mut buffer).await?;
+        let hash = blake3::hash(&buffer[..read]);
+        if read as u64 != BLOCK || blake3::Hash::from_hex(expected).ok() != Some(hash) {
+            return Ok(i);
+        }
+        whole.update(&buffer);
+    }
+    Ok(blocks.len())
+}
+
+/// Fill `buffer` from `file` as far as it goes, returning how much was read
+async fn read_block(file: &mut File, buffer: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        let n = file.read(&mut buffer[read..]).await?;
+        if n == 0 {
+            break;
+        }
+        read += n;
+    }
+    Ok(read)
+}
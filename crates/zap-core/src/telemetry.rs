@@ -0,0 +1,60 @@
+//! OTLP export of transfer spans
+//!
+//! Every send and receive runs in its own span (`zap.send`/`zap.receive`)
+//! with events for the handshake, the first byte, completion and errors.
+//! With the `otel` feature these can be exported to an OpenTelemetry
+//! collector over OTLP/HTTP, configured with the standard
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` and `OTEL_EXPORTER_OTLP_HEADERS` variables.
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::Subscriber;
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Flushes buffered spans when dropped, so keep it alive until exit
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("failed to flush traces: {}", e);
+        }
+    }
+}
+
+/// Build a tracing layer exporting spans over OTLP, if
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set
+pub fn from_env<S>(service_name: &str) -> Option<(impl Layer<S>, Telemetry)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT")?;
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("failed to set up OTLP export: {}", e);
+            return None;
+        }
+    };
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(service_name.to_string())
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer("zap");
+
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer);
+    Some((layer, Telemetry { provider }))
+}
@@ -1,15 +1,47 @@
 #[cfg(test)]
 mod unit_tests {
-    use crate::protocol::{ChunkData, FileOffer, Message, CHUNK_SIZE};
-    use crate::ticket::Ticket;
+    use crate::bandwidth::fair_shares;
+    #[cfg(feature = "archive")]
+    use crate::directory::ArchivePolicy;
+    use crate::directory::{safe_join, walk_selection};
+    use crate::filename::{verbatim, windows_name};
+    use crate::manifest::FileChecker;
+    use crate::probe::{Sample, pick};
+    use crate::protocol::{
+        CHUNK_SIZE, ChunkData, DirEntry, FileOffer, ManifestEntry, Message, OfferKind, REMOTE_ALPN,
+        TUNNEL_ALPN, WireFormat, ZAP_ALPN, remote_alpn, tunnel_alpn,
+    };
+    use crate::remote::resolve;
+    use crate::resume::{self, BLOCK, ResumeState, StateFile};
+    use crate::ticket::{Ticket, TransferKey};
+    #[cfg(feature = "detect-type")]
+    use crate::transfer::detect_extension;
+    use crate::transfer::{
+        Durability, FileEnds, Part, ReadAhead, ReplayWindow, Stamp, Throughput, add_extension,
+        cached_copies, cut, hash_prefix, is_alpn_mismatch, read_parts,
+    };
+    use crate::tree::{HashTree, PIECE, TreeVerifier};
+    use crate::validate::{
+        MAX_LABEL_LEN, MAX_NAME_LEN, MAX_OFFER_SIZE, OfferViolation, validate_offer,
+    };
+    use crate::{
+        Bandwidth, ConnectionPath, ConnectionReport, Error, Receipt, RelayHint, TransferHandle,
+        Tuning,
+    };
+    use crate::{mirror, mnemonic};
     use iroh::{EndpointAddr, SecretKey};
+    use std::ffi::OsStr;
+    use std::time::Duration;
 
     #[test]
     fn test_message_serialization_offer() {
         let offer = Message::Offer(FileOffer {
             name: "test.txt".to_string(),
-            size: 1024,
+            size: Some(1024),
             checksum: None,
+            kind: OfferKind::File,
+            label: None,
+            batch: false,
         });
 
         let bytes = offer.to_bytes().unwrap();
@@ -18,13 +50,461 @@ mod unit_tests {
         match decoded {
             Message::Offer(o) => {
                 assert_eq!(o.name, "test.txt");
-                assert_eq!(o.size, 1024);
+                assert_eq!(o.size, Some(1024));
                 assert!(o.checksum.is_none());
+                assert_eq!(o.kind, OfferKind::File);
             }
             _ => panic!("expected Offer message"),
         }
     }
 
+    #[test]
+    fn test_message_serialization_directory_offer() {
+        let entries = vec![
+            DirEntry {
+                path: "a.txt".to_string(),
+                size: 3,
+            },
+            DirEntry {
+                path: "sub/b.txt".to_string(),
+                size: 0,
+            },
+        ];
+        let offer = Message::Offer(FileOffer {
+            name: "photos".to_string(),
+            size: Some(3),
+            checksum: None,
+            kind: OfferKind::Directory {
+                entries: entries.clone(),
+            },
+            label: None,
+            batch: false,
+        });
+
+        let bytes = offer.to_bytes().unwrap();
+        match Message::from_bytes(&bytes).unwrap() {
+            Message::Offer(o) => assert_eq!(o.kind, OfferKind::Directory { entries }),
+            _ => panic!("expected Offer message"),
+        }
+    }
+
+    #[test]
+    fn test_labeled_offer() {
+        let offer = FileOffer {
+            name: "report.pdf".to_string(),
+            size: Some(10),
+            checksum: None,
+            kind: OfferKind::File,
+            label: None,
+            batch: false,
+        };
+        // Without a label, the offer is exactly what it always was
+        let plain = offer.clone().into_message().to_bytes().unwrap();
+        assert_eq!(plain, Message::Offer(offer.clone()).to_bytes().unwrap());
+
+        let labeled = FileOffer {
+            label: Some("Q3 report v2".to_string()),
+            ..offer
+        };
+        let bytes = labeled.into_message().to_bytes().unwrap();
+        let decoded = crate::transfer::expect_offer(Message::from_bytes(&bytes).unwrap()).unwrap();
+        assert_eq!(decoded.name, "report.pdf");
+        assert_eq!(decoded.label.as_deref(), Some("Q3 report v2"));
+    }
+
+    #[test]
+    fn test_batch_offer() {
+        let entry = |path: &str, size| DirEntry {
+            path: path.to_string(),
+            size,
+        };
+        let offer = FileOffer {
+            name: "a.txt and 1 more".to_string(),
+            size: Some(3),
+            checksum: None,
+            kind: OfferKind::Directory {
+                entries: vec![entry("a.txt", 1), entry("photos/b.jpg", 2)],
+            },
+            label: Some("for Sam".to_string()),
+            batch: true,
+        };
+        let bytes = offer.into_message().to_bytes().unwrap();
+        assert!(matches!(
+            Message::from_bytes(&bytes).unwrap(),
+            Message::BatchOffer { .. }
+        ));
+        let decoded = crate::transfer::expect_offer(Message::from_bytes(&bytes).unwrap()).unwrap();
+        assert!(decoded.batch);
+        assert_eq!(decoded.label.as_deref(), Some("for Sam"));
+    }
+
+    #[test]
+    fn test_file_ends() {
+        let entry = |path: &str, size| DirEntry {
+            path: path.to_string(),
+            size,
+        };
+        let mut ends = FileEnds::new(&OfferKind::Directory {
+            entries: vec![entry("empty", 0), entry("a", 100), entry("b", 50)],
+        });
+        // An empty file is whole before any data
+        assert_eq!(ends.reached(0), ["empty"]);
+        assert!(ends.reached(99).is_empty());
+        assert_eq!(ends.reached(100), ["a"]);
+        // Each file is reported once, even if data arrives again
+        assert!(ends.reached(100).is_empty());
+        assert_eq!(ends.reached(150), ["b"]);
+
+        assert!(FileEnds::new(&OfferKind::File).reached(u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_cut() {
+        let mut data = vec![1u8; 10];
+        let mut taken = 0;
+        // The size can change between chunks
+        assert_eq!(cut(&mut data, &mut taken, 4).unwrap().len(), 4);
+        assert_eq!(cut(&mut data, &mut taken, 2).unwrap().len(), 2);
+        assert_eq!(cut(&mut data, &mut taken, 16).unwrap().len(), 4);
+        assert!(cut(&mut data, &mut taken, 16).is_none());
+
+        let mut data = vec![2u8; 10];
+        let mut taken = 0;
+        assert_eq!(cut(&mut data, &mut taken, 16).unwrap(), vec![2u8; 10]);
+        assert!(cut(&mut data, &mut taken, 16).is_none());
+    }
+
+    #[test]
+    fn test_probe_pick() {
+        let bursts = |took: [u64; 3]| -> Vec<Sample> {
+            [16 * 1024, 64 * 1024, CHUNK_SIZE]
+                .into_iter()
+                .zip(took)
+                .map(|(chunk_size, ms)| Sample {
+                    chunk_size,
+                    bytes: 8 * chunk_size as u64,
+                    took: Duration::from_millis(ms),
+                })
+                .collect()
+        };
+
+        // A LAN: the largest chunks, and the smallest window that keeps up
+        let lan = pick(&bursts([2, 3, 6]), Some(Duration::from_millis(1)), 32);
+        assert_eq!(lan.chunk_size, CHUNK_SIZE);
+        assert_eq!(lan.ack_window, 16);
+        assert_eq!(lan.rtt, Duration::from_millis(1));
+
+        // A satellite hop wants a window covering its long round trip, up
+        // to what the node was tuned for
+        let satellite = bursts([610, 640, 800]);
+        let rtt = Some(Duration::from_millis(600));
+        assert_eq!(pick(&satellite, rtt, 32).ack_window, 32);
+        let tuned = pick(&satellite, rtt, 64);
+        assert_eq!(tuned.chunk_size, CHUNK_SIZE);
+        assert_eq!(tuned.ack_window, 48);
+        assert_eq!(tuned.bytes_per_sec, 10_485_760);
+
+        // Where large bursts stall, small chunks win, with the window
+        // counted in them
+        let lossy = pick(
+            &bursts([50, 400, 2000]),
+            Some(Duration::from_millis(40)),
+            32,
+        );
+        assert_eq!(lossy.chunk_size, 16 * 1024);
+        assert!(lossy.ack_window >= 16 && lossy.ack_window <= 32 * 16);
+
+        // Without the path's round trip, the quickest burst stands in
+        let guessed = pick(&bursts([50, 400, 2000]), None, 32);
+        assert_eq!(guessed.rtt, Duration::from_millis(50));
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn test_archive_policy() {
+        let entry = |path: &str| DirEntry {
+            path: path.to_string(),
+            size: 1,
+        };
+        let policy = ArchivePolicy {
+            max_files: 2,
+            max_depth: 2,
+        };
+
+        assert!(!policy.should_archive(&[entry("a"), entry("b/c")]));
+        assert!(policy.should_archive(&[entry("a"), entry("b"), entry("c")]));
+        assert!(policy.should_archive(&[entry("a/b/c")]));
+    }
+
+    #[test]
+    fn test_safe_join() {
+        let root = std::path::Path::new("/tmp/out");
+
+        assert_eq!(
+            safe_join(root, "a/b.txt").unwrap(),
+            root.join("a").join("b.txt")
+        );
+        assert_eq!(safe_join(root, "./a").unwrap(), root.join("a"));
+        assert!(safe_join(root, "../escape").is_err());
+        assert!(safe_join(root, "a/../../escape").is_err());
+        assert!(safe_join(root, "/etc/passwd").is_ok_and(|p| p.starts_with(root)));
+        assert!(safe_join(root, "").is_err());
+    }
+
+    #[test]
+    fn test_windows_name() {
+        assert_eq!(windows_name("photo.jpg"), "photo.jpg");
+        assert_eq!(windows_name("CON"), "CON_");
+        assert_eq!(windows_name("nul.tar.gz"), "nul_.tar.gz");
+        assert_eq!(windows_name("Com1.txt"), "Com1_.txt");
+        assert_eq!(windows_name("console.log"), "console.log");
+        assert_eq!(windows_name("notes. "), "notes");
+        assert_eq!(windows_name("..."), "_");
+        assert_eq!(windows_name("what?: \"a\" <b>|*"), "what__ _a_ _b___");
+        assert_eq!(windows_name("日本語.txt"), "日本語.txt");
+    }
+
+    #[test]
+    fn test_verbatim() {
+        let short = r"C:\Users\me\photo.jpg";
+        assert_eq!(verbatim(short), None);
+
+        let long = format!(r"C:\Users\me\{}\photo.jpg", "d".repeat(250));
+        assert_eq!(verbatim(&long), Some(format!(r"\\?\{}", long)));
+        assert_eq!(verbatim(&format!(r"\\?\{}", long)), None);
+
+        let share = format!(r"\\server\share\{}", "d".repeat(250));
+        assert_eq!(
+            verbatim(&share),
+            Some(format!(r"\\?\UNC\server\share\{}", "d".repeat(250)))
+        );
+    }
+
+    #[test]
+    fn test_throughput() {
+        let start = std::time::Instant::now();
+        let mut throughput = Throughput::new(start);
+
+        // Nothing until a whole interval has passed
+        assert_eq!(
+            throughput.sample(1000, start + Duration::from_millis(500)),
+            None
+        );
+        assert_eq!(
+            throughput.sample(2000, start + Duration::from_secs(1)),
+            Some(2000)
+        );
+
+        // Each sample covers only the bytes since the last one
+        assert_eq!(
+            throughput.sample(5000, start + Duration::from_secs(3)),
+            Some(1500)
+        );
+    }
+
+    #[test]
+    fn test_fair_shares() {
+        assert_eq!(fair_shares(900, &[None, None, None]), vec![300, 300, 300]);
+        // What a slow receiver leaves goes to the others
+        assert_eq!(
+            fair_shares(900, &[None, Some(100), None]),
+            vec![400, 100, 400]
+        );
+        // Nobody gets more than they want, or more than an even split of
+        // what the others leave
+        assert_eq!(fair_shares(900, &[Some(800), Some(50)]), vec![800, 50]);
+        assert_eq!(fair_shares(900, &[Some(800), None]), vec![450, 450]);
+        assert!(fair_shares(900, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_bandwidth_shares() {
+        let bandwidth = Bandwidth::new(Some(10_000_000), None);
+        let first = bandwidth.join();
+        assert_eq!(first.share(), Some(10_000_000));
+
+        // A new receiver gets an even share straight away
+        let second = bandwidth.join();
+        assert_eq!(first.share(), Some(5_000_000));
+        assert_eq!(second.share(), Some(5_000_000));
+
+        // And gives it back when it's done
+        drop(second);
+        assert_eq!(first.share(), Some(10_000_000));
+
+        let per_receiver = Bandwidth::new(Some(10_000_000), Some(2_000_000));
+        assert_eq!(per_receiver.join().share(), Some(2_000_000));
+        assert_eq!(Bandwidth::new(None, None).join().share(), None);
+    }
+
+    #[test]
+    fn test_durability() {
+        assert_eq!("none".parse(), Ok(Durability::None));
+        assert_eq!("Done".parse(), Ok(Durability::OnDone));
+        assert_eq!("64".parse(), Ok(Durability::Every(64 * 1024 * 1024)));
+        assert_eq!("8MB".parse(), Ok(Durability::Every(8 * 1024 * 1024)));
+        assert!("0".parse::<Durability>().is_err());
+        assert!("always".parse::<Durability>().is_err());
+
+        assert!(!Durability::OnDone.due(u64::MAX));
+        assert!(!Durability::Every(100).due(99));
+        assert!(Durability::Every(100).due(100));
+        assert!(!Durability::None.on_done());
+    }
+
+    #[test]
+    fn test_walk_selection() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir_all(root.join("photos/2024")).unwrap();
+        std::fs::write(root.join("photos/2024/a.jpg"), b"aaa").unwrap();
+        std::fs::write(root.join("notes.txt"), b"hi").unwrap();
+        std::fs::write(root.join("skipped.txt"), b"no").unwrap();
+
+        let names = vec!["photos".to_string(), "notes.txt".to_string()];
+        let entries = walk_selection(root, &names).unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                DirEntry {
+                    path: "notes.txt".to_string(),
+                    size: 2,
+                },
+                DirEntry {
+                    path: "photos/2024/a.jpg".to_string(),
+                    size: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "detect-type")]
+    fn test_detect_extension() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(detect_extension(png), Some("png"));
+        assert_eq!(detect_extension(b"%PDF-1.7\n"), Some("pdf"));
+        assert_eq!(detect_extension(b"just some text"), None);
+        assert_eq!(detect_extension(b""), None);
+    }
+
+    #[tokio::test]
+    async fn test_add_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        std::fs::write(dir.join("scan.pdf"), b"already here").unwrap();
+        std::fs::write(dir.join("scan"), b"%PDF-1.7\n").unwrap();
+
+        let renamed = add_extension(&dir.join("scan"), dir, "scan", "pdf")
+            .await
+            .unwrap();
+        assert_eq!(renamed, dir.join("scan-1.pdf"));
+        assert_eq!(std::fs::read(&renamed).unwrap(), b"%PDF-1.7\n");
+        assert_eq!(
+            std::fs::read(dir.join("scan.pdf")).unwrap(),
+            b"already here"
+        );
+        assert!(!dir.join("scan").exists());
+    }
+
+    #[test]
+    fn test_validate_offer() {
+        let offer = |name: &str, size: Option<u64>, kind: OfferKind| FileOffer {
+            name: name.to_string(),
+            size,
+            checksum: None,
+            kind,
+            label: None,
+            batch: false,
+        };
+        let labeled = |label: &str| FileOffer {
+            label: Some(label.to_string()),
+            ..offer("report.pdf", Some(1), OfferKind::File)
+        };
+        let entries = |paths: &[&str]| OfferKind::Directory {
+            entries: paths
+                .iter()
+                .map(|p| DirEntry {
+                    path: p.to_string(),
+                    size: 1,
+                })
+                .collect(),
+        };
+
+        assert!(validate_offer(&offer("photo.jpg", Some(10), OfferKind::File)).is_ok());
+        assert!(validate_offer(&offer("stream", None, OfferKind::File)).is_ok());
+        let docs = offer("docs", Some(2), entries(&["a.txt", "sub/b.txt"]));
+        assert!(validate_offer(&docs).is_ok());
+
+        assert_eq!(
+            validate_offer(&offer("", Some(1), OfferKind::File)),
+            Err(OfferViolation::EmptyName)
+        );
+        assert!(matches!(
+            validate_offer(&offer(&"a".repeat(MAX_NAME_LEN + 1), None, OfferKind::File)),
+            Err(OfferViolation::NameTooLong { len, .. }) if len == MAX_NAME_LEN + 1
+        ));
+        assert_eq!(
+            validate_offer(&offer("evil\x1b[2J.txt", None, OfferKind::File)),
+            Err(OfferViolation::ControlCharacter("evil\x1b[2J.txt".into()))
+        );
+        for name in ["..", "../etc/passwd", "a/b", "a\\b"] {
+            assert_eq!(
+                validate_offer(&offer(name, None, OfferKind::File)),
+                Err(OfferViolation::NotAFileName(name.into())),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            validate_offer(&offer("big", Some(MAX_OFFER_SIZE + 1), OfferKind::File)),
+            Err(OfferViolation::TooLarge(MAX_OFFER_SIZE + 1))
+        );
+
+        // Every component of an entry path is checked
+        for path in ["../escape", "/abs", "a//b", "sub/.."] {
+            assert!(
+                validate_offer(&offer("docs", Some(1), entries(&[path]))).is_err(),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(
+            validate_offer(&offer("docs", Some(5), entries(&["a", "b"]))),
+            Err(OfferViolation::SizeMismatch {
+                entries: 2,
+                size: 5
+            })
+        );
+
+        assert!(validate_offer(&labeled("Q3 report v2")).is_ok());
+        assert_eq!(
+            validate_offer(&labeled(&"a".repeat(MAX_LABEL_LEN + 1))),
+            Err(OfferViolation::LabelTooLong(MAX_LABEL_LEN + 1))
+        );
+        assert_eq!(
+            validate_offer(&labeled("fine\x1b[2J")),
+            Err(OfferViolation::LabelControlCharacter("fine\x1b[2J".into()))
+        );
+    }
+
+    #[test]
+    fn test_alpn_namespaces() {
+        assert_eq!(tunnel_alpn(ZAP_ALPN), TUNNEL_ALPN);
+        assert_eq!(tunnel_alpn(b"zap/acme/1"), b"zap/acme/1/tunnel");
+        assert_eq!(remote_alpn(ZAP_ALPN), REMOTE_ALPN);
+        assert_eq!(remote_alpn(b"zap/acme/1"), b"zap/acme/1/remote");
+
+        assert!(is_alpn_mismatch(
+            "connecting failed: the cryptographic handshake failed: error 120"
+        ));
+        assert!(is_alpn_mismatch(
+            "peer sent fatal alert NoApplicationProtocol"
+        ));
+        assert!(!is_alpn_mismatch("timed out connecting to 10.0.0.120:4120"));
+    }
+
     #[test]
     fn test_message_serialization_chunk() {
         let data = vec![1, 2, 3, 4, 5];
@@ -45,6 +525,35 @@ mod unit_tests {
         }
     }
 
+    #[test]
+    fn test_message_serialization_json() {
+        let offer = Message::Offer(FileOffer {
+            name: "notes.txt".to_string(),
+            size: Some(5),
+            checksum: None,
+            kind: OfferKind::File,
+            label: None,
+            batch: false,
+        });
+        let bytes = offer.encode(WireFormat::Json).unwrap();
+        let text = std::str::from_utf8(&bytes).unwrap();
+        assert!(text.contains("\"notes.txt\""), "{}", text);
+        match Message::decode(WireFormat::Json, &bytes).unwrap() {
+            Message::Offer(o) => assert_eq!(o.name, "notes.txt"),
+            _ => panic!("expected Offer message"),
+        }
+
+        // Postcard bytes aren't JSON
+        let bytes = offer.encode(WireFormat::Postcard).unwrap();
+        assert!(Message::decode(WireFormat::Json, &bytes).is_err());
+
+        // The length prefix says which one a frame holds
+        for format in [WireFormat::Postcard, WireFormat::Json] {
+            assert_eq!(WireFormat::from_prefix(format.prefix(1234)), (format, 1234));
+        }
+        assert_eq!(WireFormat::Postcard.prefix(1234), 1234);
+    }
+
     #[test]
     fn test_message_serialization_accept() {
         let msg = Message::Accept;
@@ -72,13 +581,17 @@ mod unit_tests {
     #[test]
     fn test_message_serialization_done() {
         let checksum = [42u8; 32];
-        let msg = Message::Done { checksum };
+        let msg = Message::Done {
+            checksum,
+            size: 4096,
+        };
         let bytes = msg.to_bytes().unwrap();
         let decoded = Message::from_bytes(&bytes).unwrap();
 
         match decoded {
-            Message::Done { checksum: c } => {
+            Message::Done { checksum: c, size } => {
                 assert_eq!(c, checksum);
+                assert_eq!(size, 4096);
             }
             _ => panic!("expected Done message"),
         }
@@ -100,6 +613,400 @@ mod unit_tests {
         }
     }
 
+    #[test]
+    fn test_message_serialization_pause_resume() {
+        let bytes = Message::Pause.to_bytes().unwrap();
+        assert!(matches!(
+            Message::from_bytes(&bytes).unwrap(),
+            Message::Pause
+        ));
+
+        let bytes = Message::Resume.to_bytes().unwrap();
+        assert!(matches!(
+            Message::from_bytes(&bytes).unwrap(),
+            Message::Resume
+        ));
+    }
+
+    #[test]
+    fn test_connection_advice() {
+        let direct = ConnectionPath {
+            direct: true,
+            remote: "203.0.113.7:4433".to_string(),
+            rtt: Duration::from_millis(20),
+            direct_attempts: 1,
+        };
+        let report = ConnectionReport {
+            udp_v4: true,
+            path: Some(direct.clone()),
+            ..Default::default()
+        };
+        assert!(!report.is_degraded());
+        assert!(report.advice().is_empty());
+
+        // Relayed behind a symmetric NAT, after trying two addresses
+        let report = ConnectionReport {
+            udp_v4: true,
+            symmetric_nat: Some(true),
+            path: Some(ConnectionPath {
+                direct: false,
+                direct_attempts: 2,
+                ..direct
+            }),
+            ..Default::default()
+        };
+        assert!(report.is_degraded());
+        let advice = report.advice().join("\n");
+        assert!(advice.contains("symmetric NAT"));
+        assert!(advice.contains("tried 2"));
+        assert!(advice.contains("--port"));
+
+        // No UDP and never connected
+        let report = ConnectionReport::default();
+        let advice = report.advice().join("\n");
+        assert!(advice.contains("UDP seems to be blocked"));
+        assert!(advice.contains("never reached"));
+        assert!(report.to_string().contains("never established"));
+    }
+
+    #[test]
+    fn test_message_serialization_accept_some() {
+        let msg = Message::AcceptSome {
+            entries: vec![0, 2, 5],
+        };
+        let bytes = msg.to_bytes().unwrap();
+        match Message::from_bytes(&bytes).unwrap() {
+            Message::AcceptSome { entries } => assert_eq!(entries, vec![0, 2, 5]),
+            _ => panic!("expected AcceptSome message"),
+        }
+    }
+
+    #[test]
+    fn test_message_serialization_accept_range() {
+        let msg = Message::AcceptRange {
+            entry: 3,
+            offset: 1 << 40,
+            len: 4096,
+        };
+        let bytes = msg.to_bytes().unwrap();
+        match Message::from_bytes(&bytes).unwrap() {
+            Message::AcceptRange { entry, offset, len } => {
+                assert_eq!((entry, offset, len), (3, 1 << 40, 4096));
+            }
+            _ => panic!("expected AcceptRange message"),
+        }
+    }
+
+    #[test]
+    fn test_message_serialization_checksums() {
+        let msg = Message::Checksums {
+            entries: vec![[1u8; 32], [2u8; 32]],
+        };
+        let bytes = msg.to_bytes().unwrap();
+        match Message::from_bytes(&bytes).unwrap() {
+            Message::Checksums { entries } => assert_eq!(entries, vec![[1u8; 32], [2u8; 32]]),
+            _ => panic!("expected Checksums message"),
+        }
+    }
+
+    #[test]
+    fn test_message_serialization_already_have() {
+        let msg = Message::AlreadyHave {
+            checksum: [7u8; 32],
+        };
+        let bytes = msg.to_bytes().unwrap();
+        match Message::from_bytes(&bytes).unwrap() {
+            Message::AlreadyHave { checksum } => assert_eq!(checksum, [7u8; 32]),
+            _ => panic!("expected AlreadyHave message"),
+        }
+    }
+
+    #[test]
+    fn test_cached_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("other.txt"), b"12345").unwrap();
+        std::fs::write(dir.path().join("sub/report.txt"), b"67890").unwrap();
+        std::fs::write(dir.path().join("report.txt"), b"123").unwrap();
+
+        // The same name comes first, wherever it is, then every other
+        // file of the size
+        let name = OsStr::new("report.txt");
+        assert_eq!(
+            cached_copies(dir.path(), name, 5),
+            [
+                dir.path().join("sub/report.txt"),
+                dir.path().join("other.txt")
+            ]
+        );
+        assert_eq!(cached_copies(dir.path(), OsStr::new("new.txt"), 5).len(), 2);
+        assert!(cached_copies(dir.path(), name, 4).is_empty());
+        assert!(cached_copies(&dir.path().join("missing"), name, 5).is_empty());
+    }
+
+    #[test]
+    fn test_message_serialization_remote() {
+        let public = SecretKey::generate(&mut rand::rng()).public();
+        let ticket = Ticket::new(EndpointAddr::new(public));
+
+        let msg = Message::Put {
+            path: "incoming/".to_string(),
+            ticket: ticket.clone(),
+        };
+        match Message::from_bytes(&msg.to_bytes().unwrap()).unwrap() {
+            Message::Put { path, ticket: t } => {
+                assert_eq!(path, "incoming/");
+                assert_eq!(t.addr.id, ticket.addr.id);
+            }
+            _ => panic!("expected Put message"),
+        }
+
+        let msg = Message::Ticket(ticket.clone());
+        match Message::from_bytes(&msg.to_bytes().unwrap()).unwrap() {
+            Message::Ticket(t) => assert_eq!(t.addr.id, ticket.addr.id),
+            _ => panic!("expected Ticket message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_resolve() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("shared");
+        std::fs::create_dir_all(root.join("logs")).unwrap();
+        std::fs::write(root.join("logs/app.log"), b"log").unwrap();
+        std::fs::write(temp_dir.path().join("secret"), b"key").unwrap();
+        let root = std::fs::canonicalize(&root).unwrap();
+
+        assert_eq!(
+            resolve(&root, "logs/app.log").await.unwrap(),
+            root.join("logs/app.log")
+        );
+        // A leading slash means the shared directory, not the filesystem root
+        assert_eq!(resolve(&root, "/logs").await.unwrap(), root.join("logs"));
+        assert_eq!(resolve(&root, "").await.unwrap(), root);
+
+        assert!(resolve(&root, "../secret").await.is_err());
+        assert!(resolve(&root, "logs/missing").await.is_err());
+
+        #[cfg(unix)]
+        {
+            let secret = temp_dir.path().join("secret");
+            std::os::unix::fs::symlink(secret, root.join("link")).unwrap();
+            assert!(resolve(&root, "link").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_parts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let big = temp_dir.path().join("big");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        std::fs::write(&big, &data).unwrap();
+        let small = temp_dir.path().join("small");
+        std::fs::write(&small, b"tail").unwrap();
+
+        let parts = vec![
+            Part::Entry(big.clone(), Stamp::read(&big).await.unwrap()),
+            Part::File(small.clone(), Stamp::read(&small).await.unwrap()),
+        ];
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        tokio::spawn(read_parts(parts, tx));
+
+        let mut received = Vec::new();
+        let checksum = loop {
+            match rx.recv().await.unwrap().unwrap() {
+                ReadAhead::Chunk(chunk) => {
+                    assert!(chunk.len() <= CHUNK_SIZE);
+                    received.extend_from_slice(&chunk);
+                }
+                ReadAhead::Done(checksum) => break checksum,
+            }
+        };
+        let mut expected = data.clone();
+        expected.extend_from_slice(b"tail");
+        assert_eq!(received, expected);
+        assert_eq!(checksum, *blake3::hash(&expected).as_bytes());
+        assert!(rx.recv().await.is_none());
+
+        // A file that's not as it was offered fails the read, whether it's
+        // a different size or was written to since
+        let read_error = |part| async move {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+            tokio::spawn(read_parts(vec![part], tx));
+            loop {
+                match rx.recv().await.unwrap() {
+                    Err(e) => return e,
+                    Ok(ReadAhead::Done(_)) => panic!("read a changed file"),
+                    Ok(ReadAhead::Chunk(_)) => {}
+                }
+            }
+        };
+        let stamp = Stamp::read(&small).await.unwrap();
+        let longer = Stamp { len: 10, ..stamp };
+        let error = read_error(Part::Entry(small.clone(), longer)).await;
+        assert!(matches!(error, Error::FileChanged(path) if path == small));
+
+        std::fs::write(&small, b"TAIL").unwrap();
+        let later = std::time::SystemTime::now() + Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&small)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let error = read_error(Part::File(small.clone(), stamp)).await;
+        assert!(matches!(error, Error::FileChanged(_)));
+
+        // So does one cut short after reading has started
+        let stamp = Stamp::read(&big).await.unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(read_parts(vec![Part::File(big.clone(), stamp)], tx));
+        assert!(matches!(rx.recv().await, Some(Ok(ReadAhead::Chunk(_)))));
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&big)
+            .unwrap()
+            .set_len(1)
+            .unwrap();
+        let error = loop {
+            match rx.recv().await.unwrap() {
+                Err(e) => break e,
+                Ok(ReadAhead::Done(_)) => panic!("read a changed file"),
+                Ok(ReadAhead::Chunk(_)) => {}
+            }
+        };
+        assert!(matches!(error, Error::FileChanged(_)));
+    }
+
+    #[tokio::test]
+    async fn test_read_sized_stream() {
+        let data: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let read = |size: u64| {
+            let reader = Box::new(std::io::Cursor::new(data.clone()));
+            async move {
+                let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+                tokio::spawn(read_parts(vec![Part::Reader(reader, Some(size))], tx));
+                let mut received = 0;
+                loop {
+                    match rx.recv().await.unwrap() {
+                        Ok(ReadAhead::Chunk(chunk)) => received += chunk.len() as u64,
+                        Ok(ReadAhead::Done(_)) => return Ok(received),
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        };
+
+        let len = data.len() as u64;
+        assert_eq!(read(len).await.unwrap(), len);
+        // A stream that isn't the length it was offered at fails, rather
+        // than the receiver getting less or more than it was told
+        let error = read(len + 1).await.unwrap_err().to_string();
+        assert!(error.contains("ended after"), "{}", error);
+        let error = read(len - 1).await.unwrap_err().to_string();
+        assert!(error.contains("longer than"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_replay_capture() {
+        use crate::debug::{Direction, MAGIC, read_capture, replay};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data = b"hello from a capture";
+        let checksum = *blake3::hash(data).as_bytes();
+
+        // A capture made on the receiving end, the sender's side in JSON
+        let frames = [
+            (Direction::Sent, WireFormat::Postcard, Message::Ready),
+            (
+                Direction::Received,
+                WireFormat::Json,
+                Message::Offer(FileOffer {
+                    name: "greeting.txt".to_string(),
+                    size: Some(data.len() as u64),
+                    checksum: None,
+                    kind: OfferKind::File,
+                    label: None,
+                    batch: false,
+                }),
+            ),
+            (Direction::Sent, WireFormat::Postcard, Message::Accept),
+            (
+                Direction::Received,
+                WireFormat::Json,
+                Message::Chunk(ChunkData {
+                    offset: 0,
+                    data: data.to_vec(),
+                }),
+            ),
+            (
+                Direction::Received,
+                WireFormat::Postcard,
+                Message::Done {
+                    checksum,
+                    size: data.len() as u64,
+                },
+            ),
+        ];
+        let mut capture = MAGIC.to_vec();
+        for (direction, format, message) in &frames {
+            let body = message.encode(*format).unwrap();
+            capture.push(u8::from(*direction == Direction::Received));
+            capture.extend_from_slice(&0u64.to_be_bytes());
+            capture.extend_from_slice(&format.prefix(body.len()).to_be_bytes());
+            capture.extend_from_slice(&body);
+        }
+        let path = temp_dir.path().join("transfer.zapcap");
+        std::fs::write(&path, &capture).unwrap();
+
+        let read = read_capture(&path).unwrap();
+        assert_eq!(read.len(), frames.len());
+        assert_eq!(read[1].format, WireFormat::Json);
+
+        let out = temp_dir.path().join("out");
+        std::fs::create_dir(&out).unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(64);
+        replay(&path, Some(out.clone()), Default::default(), tx)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(out.join("greeting.txt")).unwrap(), data);
+
+        // Cut off before the end, the replay fails like the transfer would
+        let cut = temp_dir.path().join("cut.zapcap");
+        let last = frames
+            .last()
+            .unwrap()
+            .2
+            .encode(WireFormat::Postcard)
+            .unwrap();
+        std::fs::write(&cut, &capture[..capture.len() - last.len() - 13]).unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(64);
+        assert!(
+            replay(&cut, Some(out), Default::default(), tx)
+                .await
+                .is_err()
+        );
+
+        std::fs::write(&cut, b"not a capture").unwrap();
+        assert!(read_capture(&cut).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_handle() {
+        let handle = TransferHandle::new();
+        assert!(!handle.is_paused());
+
+        handle.pause();
+        assert!(handle.is_paused());
+        handle.resume();
+        assert!(!handle.is_paused());
+
+        // A cancelled transfer can't be paused or resumed again
+        handle.cancel().await;
+        handle.pause();
+        assert!(!handle.is_paused());
+    }
+
     #[test]
     fn test_ticket_roundtrip() {
         let secret = SecretKey::generate(&mut rand::rng());
@@ -158,6 +1065,129 @@ mod unit_tests {
         assert_eq!(ticket.addr.id, decoded.addr.id);
     }
 
+    #[test]
+    fn test_ticket_words() {
+        let id = SecretKey::generate(&mut rand::rng()).public();
+        let ticket = Ticket::new(EndpointAddr::new(id));
+        let words = mnemonic::to_words(&ticket).unwrap();
+        assert_eq!(words.split('-').count(), mnemonic::MIN_WORDS);
+        assert!(mnemonic::is_words(&words));
+
+        let decoded = mnemonic::from_words(&words).unwrap();
+        assert_eq!(decoded.addr.id, id);
+        assert!(decoded.key_hash.is_none());
+
+        // Read out and typed back in: spaces, capitals, first three letters
+        let typed: Vec<_> = words.split('-').map(|w| w[..3].to_uppercase()).collect();
+        let decoded = mnemonic::from_words(&typed.join(" ")).unwrap();
+        assert_eq!(decoded.addr.id, id);
+
+        // A key travels in the words too
+        let keyed = ticket.requiring(TransferKey::generate());
+        let words = mnemonic::to_words(&keyed).unwrap();
+        assert_eq!(words.split('-').count(), mnemonic::MIN_WORDS + 16);
+        let decoded = mnemonic::from_words(&words).unwrap();
+        assert_eq!(decoded.key_hash, keyed.key_hash);
+        assert_eq!(decoded.key, keyed.key);
+
+        // Without the key itself, there's nothing to spell out
+        let without_key = Ticket::deserialize(&keyed.serialize()).unwrap();
+        assert!(mnemonic::to_words(&without_key).is_err());
+
+        // The words would outlive an expiring ticket
+        let expiring = keyed.expiring_in(Duration::from_secs(60));
+        assert!(mnemonic::to_words(&expiring).is_err());
+    }
+
+    #[test]
+    fn test_ticket_words_mistakes() {
+        let id = SecretKey::generate(&mut rand::rng()).public();
+        let words = mnemonic::to_words(&Ticket::new(EndpointAddr::new(id))).unwrap();
+        let mut list: Vec<_> = words.split('-').collect();
+
+        let missing = list[1..].join("-");
+        assert!(mnemonic::from_words(&missing).is_err());
+
+        list.swap(3, 4);
+        if list[3] != list[4] {
+            assert!(mnemonic::from_words(&list.join("-")).is_err());
+        }
+
+        assert!(mnemonic::from_words(&words.replacen('-', "-unknownword-", 1)).is_err());
+        assert!(!mnemonic::is_words("alpha-bravo-charlie"));
+    }
+
+    #[test]
+    fn test_mirror_seal() {
+        let key = TransferKey::generate();
+        let sealed = mirror::seal(&key, "notes.txt", b"for later").unwrap();
+        assert_eq!(sealed.len(), mirror::OVERHEAD + 9 + 9);
+        let (name, data) = mirror::open(&key, &sealed).unwrap();
+        assert_eq!(name, "notes.txt");
+        assert_eq!(data, b"for later");
+
+        // Nothing of the file shows through
+        assert!(!sealed.windows(9).any(|w| w == b"for later"));
+
+        let other = TransferKey::generate();
+        assert!(matches!(
+            mirror::open(&other, &sealed),
+            Err(crate::Error::WrongKey)
+        ));
+        let mut tampered = sealed.clone();
+        tampered[mirror::OVERHEAD] ^= 1;
+        assert!(matches!(
+            mirror::open(&key, &tampered),
+            Err(crate::Error::WrongKey)
+        ));
+        assert!(mirror::open(&key, b"ZAPM").is_err());
+
+        assert!(mirror::seal(&key, "../escape", b"").is_err());
+    }
+
+    #[test]
+    fn test_mirror_segments() {
+        let key = TransferKey::generate();
+        let data: Vec<u8> = (0..3 * mirror::SEGMENT).map(|i| i as u8).collect();
+
+        // Sealed and opened in pieces that don't line up with segments
+        let (mut sealer, mut sealed) = mirror::Sealer::new(&key, "data.bin").unwrap();
+        for piece in data.chunks(10_000) {
+            sealed.extend(sealer.update(piece).unwrap());
+        }
+        sealed.extend(sealer.finish().unwrap());
+        assert_eq!(
+            mirror::seal(&key, "data.bin", &data).unwrap().len(),
+            sealed.len()
+        );
+
+        let mut opener = mirror::Opener::new(key);
+        let mut opened = Vec::new();
+        for piece in sealed.chunks(7_000) {
+            opened.extend(opener.update(piece).unwrap());
+            if !opened.is_empty() {
+                assert_eq!(opener.name(), Some("data.bin"));
+            }
+        }
+        let (name, rest) = opener.finish().unwrap();
+        opened.extend(rest);
+        assert_eq!(name, "data.bin");
+        assert_eq!(opened, data);
+
+        // A blob cut off at a segment boundary doesn't pass for the whole
+        let cut = &sealed[..sealed.len() - 2 - 8 - 16];
+        assert!(mirror::open(&key, cut).is_err());
+        let mut swapped = sealed.clone();
+        let first = 24;
+        let segment = mirror::SEGMENT + 16;
+        let (a, b) = swapped[first..].split_at_mut(segment);
+        a.swap_with_slice(&mut b[..segment]);
+        assert!(matches!(
+            mirror::open(&key, &swapped),
+            Err(crate::Error::WrongKey)
+        ));
+    }
+
     #[test]
     fn test_ticket_invalid_base32() {
         let result = Ticket::deserialize("not-valid-base32!");
@@ -177,38 +1207,366 @@ mod unit_tests {
     }
 
     #[test]
-    fn test_chunk_size_reasonable() {
-        // Chunk size should be reasonable for network transfer
-        assert!(CHUNK_SIZE >= 64 * 1024); // At least 64KB
-        assert!(CHUNK_SIZE <= 1024 * 1024); // At most 1MB
+    fn test_relay_hint_token() {
+        let hint = RelayHint::new("https://zapper.cloud/", "abc123");
+        assert_eq!(hint.to_string(), "zapper.cloud:abc123");
+        assert_eq!(hint.url(), "https://zapper.cloud");
+
+        let token = hint.token();
+        assert!(token.starts_with("zap1"));
+        assert_eq!(RelayHint::from_token(&token).unwrap(), hint);
+        assert_eq!(RelayHint::from_token(&token.to_uppercase()).unwrap(), hint);
+
+        // Plain HTTP relays say so
+        let local = RelayHint::new("http://localhost:8080", "abc123");
+        let parsed = RelayHint::from_token(&local.token()).unwrap();
+        assert_eq!(parsed.url(), "http://localhost:8080");
+
+        // A token for another code doesn't pass for this one
+        let other = RelayHint::new("https://zapper.cloud", "abc124").token();
+        let spliced = format!("{}{}", &token[..token.len() - 7], &other[other.len() - 7..]);
+        assert!(RelayHint::from_token(&spliced).is_err());
+        assert!(RelayHint::from_token("zap1").is_err());
+        assert!(RelayHint::from_token("zap1!!!").is_err());
+        assert!(RelayHint::from_token(&token[1..]).is_err());
     }
 
-    #[test]
-    fn test_large_chunk_serialization() {
-        let data = vec![42u8; CHUNK_SIZE];
-        let chunk = Message::Chunk(ChunkData { offset: 0, data });
+    #[tokio::test]
+    async fn test_resume_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.bin");
+        let block = BLOCK as usize;
+        let data: Vec<u8> = (0..2 * block + 1000).map(|i| (i % 251) as u8).collect();
+        let state = ResumeState {
+            ticket: "ticket".into(),
+            name: "big.bin".into(),
+            size: data.len() as u64,
+            blocks: Vec::new(),
+        };
 
-        let bytes = chunk.to_bytes().unwrap();
-        let decoded = Message::from_bytes(&bytes).unwrap();
+        // Chunks needn't line up with blocks
+        let mut file = StateFile::new(&path, state);
+        let completed = data
+            .chunks(CHUNK_SIZE + 7)
+            .filter(|chunk| file.update(chunk))
+            .count();
+        assert_eq!(completed, 2);
+        file.save().await;
+        let state_path = resume::state_path(&path);
+        assert_eq!(state_path, dir.path().join("big.bin.zap-state"));
+        assert_eq!(resume::partial_path(&state_path).unwrap(), path);
+        assert!(resume::partial_path(&path).is_err());
+        let saved = ResumeState::load(&state_path).await.unwrap();
+        assert_eq!(saved.blocks.len(), 2);
+        assert_eq!(
+            saved.blocks[0],
+            blake3::hash(&data[..block]).to_hex().as_str()
+        );
+
+        // The partial file is kept up to the first block that doesn't match
+        let verified = |contents: Option<&[u8]>| {
+            match contents {
+                Some(contents) => std::fs::write(&path, contents).unwrap(),
+                None => std::fs::remove_file(&path).unwrap(),
+            }
+            let blocks = saved.blocks.clone();
+            let path = path.clone();
+            async move {
+                let mut whole = blake3::Hasher::new();
+                let verified = resume::verify_blocks(&path, &blocks, &mut whole).await;
+                (verified.unwrap(), whole.finalize())
+            }
+        };
+        let (blocks, whole) = verified(Some(&data)).await;
+        assert_eq!(blocks, 2);
+        assert_eq!(whole, blake3::hash(&data[..2 * block]));
+        let mut damaged = data.clone();
+        damaged[block + 5] ^= 1;
+        assert_eq!(verified(Some(&damaged)).await.0, 1);
+        assert_eq!(verified(Some(&data[..block + 10])).await.0, 1);
+        assert_eq!(verified(None).await.0, 0);
+
+        file.remove().await;
+        assert!(!state_path.exists());
+    }
 
-        match decoded {
-            Message::Chunk(c) => {
-                assert_eq!(c.data.len(), CHUNK_SIZE);
-                assert!(c.data.iter().all(|&b| b == 42));
+    #[tokio::test]
+    async fn test_accept_from() {
+        let msg = Message::AcceptFrom {
+            offset: 4096,
+            checksum: [3; 32],
+        };
+        match Message::from_bytes(&msg.to_bytes().unwrap()).unwrap() {
+            Message::AcceptFrom { offset, checksum } => {
+                assert_eq!(offset, 4096);
+                assert_eq!(checksum, [3; 32]);
             }
-            _ => panic!("expected Chunk message"),
+            _ => panic!("wrong message type"),
         }
+
+        // What the sender checks a resuming receiver's data against
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.bin");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        for len in [0, 100, CHUNK_SIZE + 1, data.len()] {
+            let prefix = hash_prefix(&path, len as u64).await.unwrap().unwrap();
+            assert_eq!(prefix.finalize(), blake3::hash(&data[..len]));
+        }
+        let longer = data.len() as u64 + 1;
+        assert!(hash_prefix(&path, longer).await.unwrap().is_none());
+
+        // Only a broken connection is worth waiting out
+        assert!(Error::ConnectionFailed("lost".into()).is_interruption());
+        assert!(Error::StreamBroken("reset".into()).is_interruption());
+        assert!(!Error::TransferFailed("checksum mismatch".into()).is_interruption());
+        assert!(!Error::Timeout.is_interruption());
+        assert!(!Error::Cancelled.is_interruption());
+        assert!(!Error::FileChanged(path).is_interruption());
     }
-}
 
-#[cfg(test)]
-mod integration_tests {
-    use crate::ZapNode;
+    #[test]
+    fn test_replay_window() {
+        let chunk = |i: u64| ChunkData {
+            offset: i * 100,
+            data: vec![i as u8; 100],
+        };
+        let offsets = |window: &ReplayWindow, from| {
+            window
+                .since(from)
+                .map(|chunks| chunks.map(|c| c.offset).collect::<Vec<_>>())
+        };
+
+        // Until the receiver acks, the oldest chunks make way and the
+        // sender never waits
+        let mut window = ReplayWindow::default();
+        for i in 0..100 {
+            window.push(chunk(i));
+        }
+        assert!(!window.is_full());
+        assert_eq!(offsets(&window, 0), None);
+        assert_eq!(offsets(&window, 9800), Some(vec![9800, 9900]));
+        assert_eq!(window.delivered(), 10_000);
+
+        let mut window = ReplayWindow::default();
+        window.ack(0);
+        for i in 0..4 {
+            window.push(chunk(i));
+        }
+        assert_eq!(offsets(&window, 100), Some(vec![100, 200, 300]));
+        assert_eq!(offsets(&window, 400), Some(vec![]));
+        assert_eq!(offsets(&window, 150), None);
+        assert_eq!(window.delivered(), 0);
+
+        // Acked chunks are forgotten, and a full window waits for an ack
+        window.ack(200);
+        // Progress goes by what the receiver has, not what went out
+        assert_eq!(window.delivered(), 200);
+        window.ack(100);
+        assert_eq!(window.delivered(), 200);
+        assert_eq!(offsets(&window, 100), None);
+        assert_eq!(offsets(&window, 200), Some(vec![200, 300]));
+        for i in 4..200 {
+            window.push(chunk(i));
+        }
+        assert!(window.is_full());
+        window.ack(19_000);
+        assert!(!window.is_full());
+        assert_eq!(offsets(&window, 19_000).map(|o| o.len()), Some(10));
+
+        // A tuned node keeps as many chunks out as it was told to
+        let mut window = ReplayWindow::new(Tuning::lan().ack_window);
+        window.ack(0);
+        for i in 0..63 {
+            window.push(chunk(i));
+        }
+        assert!(!window.is_full());
+        window.push(chunk(63));
+        assert!(window.is_full());
+    }
 
     #[tokio::test]
-    async fn test_node_creation() {
-        let node = ZapNode::new().await;
-        assert!(node.is_ok(), "should create node successfully");
+    async fn test_hash_tree() {
+        // The pieces add up to the file's hash, at every size
+        for size in [0, 1, PIECE, PIECE + 1, 2 * PIECE, 5 * PIECE + 123] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let tree = HashTree::of(&data);
+            let hash = *blake3::hash(&data).as_bytes();
+            assert_eq!(tree.root(), hash, "{} bytes", size);
+            assert!(HashTree::new(size, tree.pieces().to_vec()).is_ok());
+        }
+        assert!(HashTree::new(PIECE + 1, vec![[0; 32]]).is_err());
+
+        let data: Vec<u8> = (0..3 * PIECE + 10).map(|i| (i % 251) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, &data).unwrap();
+        let tree = HashTree::of_file(&path).await.unwrap();
+        assert_eq!(tree, HashTree::of(&data));
+
+        // Data is checked piece by piece, however it's split up
+        let mut verifier = TreeVerifier::new(tree.clone());
+        for chunk in data.chunks(1000) {
+            verifier.update(chunk).unwrap();
+        }
+        verifier.finish().unwrap();
+
+        let mut damaged = data.clone();
+        damaged[PIECE as usize + 5] ^= 1;
+        let mut verifier = TreeVerifier::new(tree.clone());
+        verifier.update(&damaged[..PIECE as usize]).unwrap();
+        let result = verifier.update(&damaged[PIECE as usize..]);
+        assert!(
+            matches!(&result, Err(crate::Error::TransferFailed(e)) if e.contains("at 262144")),
+            "{:?}",
+            result
+        );
+
+        let mut verifier = TreeVerifier::new(tree);
+        verifier.update(&data[..PIECE as usize]).unwrap();
+        assert!(verifier.finish().is_err());
+    }
+
+    #[test]
+    fn test_receipt() {
+        let sender = iroh::SecretKey::generate(&mut rand::rng()).public();
+        let key = iroh::SecretKey::generate(&mut rand::rng());
+        let receipt = Receipt::sign(&key, sender, [7; 32], 1024);
+        assert_eq!(receipt.receiver, key.public());
+        receipt.verify().unwrap();
+
+        let bytes = Message::Receipt(receipt.clone()).to_bytes().unwrap();
+        match Message::from_bytes(&bytes).unwrap() {
+            Message::Receipt(decoded) => assert_eq!(decoded, receipt),
+            _ => panic!("wrong message type"),
+        }
+        let text = serde_json::to_string(&receipt).unwrap();
+        assert_eq!(serde_json::from_str::<Receipt>(&text).unwrap(), receipt);
+
+        // Nothing it covers can change without the receiver's key
+        let forged = Receipt {
+            size: 2048,
+            ..receipt.clone()
+        };
+        assert!(forged.verify().is_err());
+        let other = iroh::SecretKey::generate(&mut rand::rng()).public();
+        let forged = Receipt {
+            receiver: other,
+            ..receipt
+        };
+        assert!(forged.verify().is_err());
+    }
+
+    #[test]
+    fn test_file_checker() {
+        let file = |path: &str, data: &[u8]| ManifestEntry {
+            path: path.to_string(),
+            size: data.len() as u64,
+            checksum: *blake3::hash(data).as_bytes(),
+        };
+        let files = vec![
+            file("a.txt", b"first"),
+            file("empty", b""),
+            file("nested/b.txt", b"second"),
+        ];
+        let entries: Vec<_> = files
+            .iter()
+            .map(|f| DirEntry {
+                path: f.path.clone(),
+                size: f.size,
+            })
+            .collect();
+
+        let msg = Message::Manifest {
+            files: files.clone(),
+        };
+        match Message::from_bytes(&msg.to_bytes().unwrap()).unwrap() {
+            Message::Manifest { files: decoded } => assert_eq!(decoded, files),
+            _ => panic!("wrong message type"),
+        }
+
+        // Files are checked as they complete, however the data is split up
+        let mut checker = FileChecker::new(files.clone(), &entries).unwrap();
+        assert!(checker.update(b"fir").unwrap().is_empty());
+        assert_eq!(checker.update(b"stsec").unwrap(), ["a.txt", "empty"]);
+        assert_eq!(checker.update(b"ond").unwrap(), ["nested/b.txt"]);
+        assert!(checker.update(b"!").is_err());
+
+        let mut checker = FileChecker::new(files.clone(), &entries).unwrap();
+        let result = checker.update(b"firstsecOND");
+        assert!(
+            matches!(&result, Err(crate::Error::TransferFailed(e)) if e.contains("nested/b.txt")),
+            "{:?}",
+            result
+        );
+
+        // A manifest for other files is no use
+        assert!(FileChecker::new(files[..2].to_vec(), &entries).is_err());
+        let mut resized = files;
+        resized[0].size = 4;
+        assert!(FileChecker::new(resized, &entries).is_err());
+    }
+
+    #[test]
+    fn test_chunk_size_reasonable() {
+        // Chunk size should be reasonable for network transfer
+        assert!(CHUNK_SIZE >= 64 * 1024); // At least 64KB
+        assert!(CHUNK_SIZE <= 1024 * 1024); // At most 1MB
+    }
+
+    #[test]
+    fn test_large_chunk_serialization() {
+        let data = vec![42u8; CHUNK_SIZE];
+        let chunk = Message::Chunk(ChunkData { offset: 0, data });
+
+        let bytes = chunk.to_bytes().unwrap();
+        let decoded = Message::from_bytes(&bytes).unwrap();
+
+        match decoded {
+            Message::Chunk(c) => {
+                assert_eq!(c.data.len(), CHUNK_SIZE);
+                assert!(c.data.iter().all(|&b| b == 42));
+            }
+            _ => panic!("expected Chunk message"),
+        }
+    }
+
+    #[cfg(feature = "logs")]
+    #[test]
+    fn test_transfer_logs() {
+        use crate::logs;
+        use tracing_subscriber::prelude::*;
+
+        let subscriber = tracing_subscriber::registry().with(logs::layer());
+        tracing::subscriber::with_default(subscriber, || {
+            let _transfer = logs::span("logs-test").entered();
+            tracing::debug!(size = 42, "received offer");
+            let _inner = tracing::info_span!("inner").entered();
+            tracing::warn!("stalled");
+            // Too quiet for the log
+            tracing::trace!("frame");
+        });
+
+        let log = logs::get("logs-test").unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", log);
+        assert!(lines[0].ends_with("DEBUG zap_core::tests::unit_tests: received offer size=42"));
+        assert!(lines[1].ends_with(" WARN zap_core::tests::unit_tests: stalled"));
+        assert!(logs::get("other").is_none());
+
+        logs::remove("logs-test");
+        assert!(logs::get("logs-test").is_none());
+    }
+}
+
+#[cfg(test)]
+mod integration_tests {
+    use crate::{SendOptions, ZapNode};
+
+    #[tokio::test]
+    async fn test_node_creation() {
+        let node = ZapNode::new().await;
+        assert!(node.is_ok(), "should create node successfully");
 
         let node = node.unwrap();
         let addr = node.addr();
@@ -243,16 +1601,42 @@ mod integration_tests {
     }
 
     #[tokio::test]
-    async fn test_send_directory_fails() {
+    async fn test_send_directory() {
         let node = ZapNode::new().await.unwrap();
 
         // Create temp directory
         let temp_dir = tempfile::tempdir().unwrap();
         let result = node.send(temp_dir.path()).await;
 
-        assert!(result.is_err());
-        let err = result.unwrap_err();
-        assert!(err.to_string().contains("must be a file"));
+        assert!(result.is_ok(), "directories can be sent");
+
+        node.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_many_requires_same_directory() {
+        let node = ZapNode::new().await.unwrap();
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let nested = temp_dir.path().join("nested");
+        std::fs::create_dir(&nested).unwrap();
+        let a = temp_dir.path().join("a.txt");
+        let b = nested.join("b.txt");
+        std::fs::write(&a, b"a").unwrap();
+        std::fs::write(&b, b"b").unwrap();
+
+        let options = SendOptions::default();
+        assert!(
+            node.send_many(&[&a, &nested], options.clone())
+                .await
+                .is_ok()
+        );
+        assert!(node.send_many(&[&a, &b], options.clone()).await.is_err());
+        assert!(
+            node.send_many::<&std::path::Path>(&[], options)
+                .await
+                .is_err()
+        );
 
         node.shutdown().await.unwrap();
     }
@@ -260,7 +1644,17 @@ mod integration_tests {
 
 #[cfg(test)]
 mod e2e_tests {
-    use crate::{ReceiveProgress, SendProgress, ZapNode};
+    #[cfg(feature = "archive")]
+    use crate::ArchivePolicy;
+    use crate::protocol::{DirEntry, OfferKind};
+    use crate::{
+        Bandwidth, EntryChooser, Error, Inbox, ListenEvent, ListenOptions, OfferCheck,
+        ReceiveOptions, ReceiveProgress, Renamer, SendOptions, SendProgress, Ticket,
+        TransferHandle, TransferKey, Transform, ZapNode,
+    };
+    use iroh::EndpointAddr;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::path::{Path, PathBuf};
     use std::time::Duration;
     use tokio::fs;
     use tokio::time::timeout;
@@ -302,7 +1696,7 @@ mod e2e_tests {
                     Some(p) = sender_progress.recv() => {
                         println!("Sender progress: {:?}", p);
                         match p {
-                            SendProgress::Connected => {
+                            SendProgress::Connected { .. } => {
                                 sender_connected = true;
                             }
                             SendProgress::Error(e) => {
@@ -377,7 +1771,8 @@ mod e2e_tests {
                     Some(progress) = sender_progress.recv() => {
                         println!("Sender: {:?}", progress);
                         match progress {
-                            SendProgress::Complete => {
+                            SendProgress::Complete { timings, .. } => {
+                                assert!(timings.handshake > Duration::ZERO);
                                 sender_done = true;
                             }
                             SendProgress::Error(e) => {
@@ -389,7 +1784,9 @@ mod e2e_tests {
                     Some(progress) = receiver_progress.recv() => {
                         println!("Receiver: {:?}", progress);
                         match progress {
-                            ReceiveProgress::Complete { path } => {
+                            ReceiveProgress::Complete { path, checksum, timings } => {
+                                assert_eq!(checksum, *blake3::hash(test_content).as_bytes());
+                                assert!(timings.handshake > Duration::ZERO);
                                 receiver_done = true;
                                 received_path = Some(path);
                             }
@@ -422,6 +1819,428 @@ mod e2e_tests {
         receiver_node.shutdown().await.unwrap();
     }
 
+    /// Test that a file without an extension gets one detected from its content
+    #[tokio::test]
+    #[cfg(feature = "detect-type")]
+    async fn test_receive_detects_extension() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut test_content = b"%PDF-1.7\n".to_vec();
+        test_content.resize(4096, b' ');
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node
+            .send_reader("scan", std::io::Cursor::new(test_content.clone()))
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let options = ReceiveOptions {
+            detect_extension: true,
+            ..Default::default()
+        };
+        let mut receiver_progress = receiver_node
+            .receive_with_options(ticket, Some(temp_dir.path()), options)
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut received_path = None;
+
+            while !sender_done || received_path.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::Complete { .. } => sender_done = true,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(progress) = receiver_progress.recv() => match progress {
+                        ReceiveProgress::Complete { path, .. } => received_path = Some(path),
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    },
+                }
+            }
+
+            received_path.unwrap()
+        })
+        .await;
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+
+        let path = result.expect("transfer should complete within timeout");
+        assert_eq!(path, temp_dir.path().join("scan.pdf"));
+        assert_eq!(fs::read(&path).await.unwrap(), test_content);
+        assert!(!temp_dir.path().join("scan").exists());
+    }
+
+    /// Test saving a file under a name picked by the receiver
+    #[tokio::test]
+    async fn test_receive_renamed() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("report.txt");
+        fs::write(&test_file, b"quarterly numbers").await.unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir(&output_dir).await.unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node.send(&test_file).await.unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let options = ReceiveOptions {
+            rename: Some(Renamer::new(|offer| format!("2024-01-01-{}", offer.name))),
+            ..Default::default()
+        };
+        let mut receiver_progress = receiver_node
+            .receive_with_options(ticket, Some(output_dir.as_path()), options)
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut received_path = None;
+
+            while !sender_done || received_path.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::Complete { .. } => sender_done = true,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(progress) = receiver_progress.recv() => match progress {
+                        ReceiveProgress::Complete { path, .. } => received_path = Some(path),
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    },
+                }
+            }
+
+            received_path.unwrap()
+        })
+        .await;
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+
+        let path = result.expect("transfer should complete within timeout");
+        assert_eq!(path, output_dir.join("2024-01-01-report.txt"));
+        assert_eq!(fs::read(&path).await.unwrap(), b"quarterly numbers");
+    }
+
+    /// Send `file` to a receiver with dedup on, returning where it was
+    /// saved and whether it was skipped as already there
+    async fn receive_deduplicated(
+        file: &Path,
+        output_dir: &Path,
+        cache_dir: Option<PathBuf>,
+    ) -> (PathBuf, bool) {
+        let sender_node = ZapNode::new().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node.send(file).await.unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let options = ReceiveOptions {
+            dedup: true,
+            cache_dir,
+            ..Default::default()
+        };
+        let mut receiver_progress = receiver_node
+            .receive_with_options(ticket, Some(output_dir), options)
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut skipped = false;
+            let mut received_path = None;
+
+            while !sender_done || received_path.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::Complete { .. } => sender_done = true,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(progress) = receiver_progress.recv() => match progress {
+                        ReceiveProgress::AlreadyHave { .. } => skipped = true,
+                        ReceiveProgress::Complete { path, .. } => received_path = Some(path),
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    },
+                }
+            }
+
+            (received_path.unwrap(), skipped)
+        })
+        .await;
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+        result.expect("transfer should complete within timeout")
+    }
+
+    /// Test skipping a file the receiver already has, and sending one that
+    /// only looks the same
+    #[tokio::test]
+    async fn test_dedup() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("report.txt");
+        fs::write(&test_file, b"quarterly numbers").await.unwrap();
+        let cache_dir = temp_dir.path().join("cache");
+        fs::create_dir_all(cache_dir.join("2024")).await.unwrap();
+        fs::write(cache_dir.join("2024/q1.txt"), b"quarterly numbers")
+            .await
+            .unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir(&output_dir).await.unwrap();
+
+        // Copied from the cache
+        let (path, skipped) =
+            receive_deduplicated(&test_file, &output_dir, Some(cache_dir.clone())).await;
+        assert!(skipped);
+        assert_eq!(path, output_dir.join("report.txt"));
+        assert_eq!(fs::read(&path).await.unwrap(), b"quarterly numbers");
+
+        // Already at the destination
+        let (_, skipped) = receive_deduplicated(&test_file, &output_dir, None).await;
+        assert!(skipped);
+
+        // The same size, but not the same file
+        fs::write(&path, b"quarterly NUMBERS").await.unwrap();
+        let (path, skipped) = receive_deduplicated(&test_file, &output_dir, None).await;
+        assert!(!skipped);
+        assert_eq!(fs::read(&path).await.unwrap(), b"quarterly numbers");
+    }
+
+    /// Test receiving a small file into memory, and rejecting a large one
+    #[tokio::test]
+    async fn test_receive_in_memory() {
+        let test_content = b"fn main() {}\n".to_vec();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node
+            .send_reader("main.rs", std::io::Cursor::new(test_content.clone()))
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let options = ReceiveOptions {
+            in_memory: Some(1024),
+            ..Default::default()
+        };
+        let mut receiver_progress = receiver_node
+            .receive_with_options(ticket, None, options)
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut loaded = None;
+
+            while !sender_done || loaded.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::Complete { .. } => sender_done = true,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(progress) = receiver_progress.recv() => match progress {
+                        ReceiveProgress::Loaded { name, data, .. } => loaded = Some((name, data)),
+                        ReceiveProgress::Complete { .. } => panic!("should be kept in memory"),
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    },
+                }
+            }
+
+            loaded.unwrap()
+        })
+        .await;
+
+        let (name, data) = result.expect("transfer should complete within timeout");
+        assert_eq!(name, "main.rs");
+        assert_eq!(&data[..], &test_content[..]);
+
+        // Over the limit, the offer is rejected before any data is sent
+        let temp_dir = tempfile::tempdir().unwrap();
+        let big_file = temp_dir.path().join("big.bin");
+        fs::write(&big_file, vec![0u8; 4096]).await.unwrap();
+        let (ticket, mut sender_progress) = sender_node.send(&big_file).await.unwrap();
+        let options = ReceiveOptions {
+            in_memory: Some(1024),
+            ..Default::default()
+        };
+        let mut receiver_progress = receiver_node
+            .receive_with_options(ticket, None, options)
+            .await
+            .unwrap();
+
+        let rejected = timeout(Duration::from_secs(30), async {
+            while let Some(progress) = receiver_progress.recv().await {
+                match progress {
+                    ReceiveProgress::Error(_) => return true,
+                    ReceiveProgress::Loaded { .. } => return false,
+                    _ => {}
+                }
+            }
+            false
+        })
+        .await
+        .expect("receiver should finish within timeout");
+        assert!(rejected);
+
+        let sender_failed = timeout(Duration::from_secs(30), async {
+            while let Some(progress) = sender_progress.recv().await {
+                if let SendProgress::Error(_) = progress {
+                    return true;
+                }
+            }
+            false
+        })
+        .await
+        .expect("sender should finish within timeout");
+        assert!(sender_failed);
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test that a sender asked to retry waits again after a rejection,
+    /// and sends to the next receiver
+    #[tokio::test]
+    async fn test_retry_after_reject() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file = temp_dir.path().join("big.bin");
+        fs::write(&file, vec![7u8; 4096]).await.unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let options = SendOptions {
+            retry_rejected: true,
+            ..Default::default()
+        };
+        let (ticket, mut sender_progress) =
+            sender_node.send_with_options(&file, options).await.unwrap();
+
+        // The first receiver only takes small files into memory
+        let receiver_node = ZapNode::new().await.unwrap();
+        let options = ReceiveOptions {
+            in_memory: Some(1024),
+            ..Default::default()
+        };
+        let mut receiver_progress = receiver_node
+            .receive_with_options(ticket.clone(), None, options)
+            .await
+            .unwrap();
+        let reason = timeout(Duration::from_secs(30), async {
+            loop {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::Rejected { reason } => return reason,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(_) = receiver_progress.recv() => {}
+                }
+            }
+        })
+        .await
+        .expect("sender should hear of the rejection within timeout");
+        assert!(!reason.is_empty());
+
+        let waiting = timeout(Duration::from_secs(30), sender_progress.recv())
+            .await
+            .expect("sender should wait again within timeout");
+        assert!(matches!(waiting, Some(SendProgress::Waiting)));
+
+        // The same ticket works for the next try
+        let output_dir = tempfile::tempdir().unwrap();
+        let mut receiver_progress = receiver_node
+            .receive(ticket, Some(output_dir.path()))
+            .await
+            .unwrap();
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut received = None;
+            while !sender_done || received.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::Complete { .. } => sender_done = true,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(progress) = receiver_progress.recv() => match progress {
+                        ReceiveProgress::Complete { path, .. } => received = Some(path),
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    },
+                }
+            }
+            received.unwrap()
+        })
+        .await
+        .expect("retry should complete within timeout");
+        assert_eq!(fs::read(&result).await.unwrap(), vec![7u8; 4096]);
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test a transfer between offline nodes, with nothing but a loopback
+    /// address to connect to: no relay, no lookups
+    #[tokio::test]
+    async fn test_offline_loopback() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file = temp_dir.path().join("notes.txt");
+        fs::write(&file, b"no internet needed").await.unwrap();
+
+        let sender_node = ZapNode::builder().offline().build().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node.send(&file).await.unwrap();
+        assert_eq!(ticket.addr.relay_urls().count(), 0);
+
+        // Only the port from the ticket, on loopback
+        let port = ticket
+            .addr
+            .ip_addrs()
+            .map(|addr| addr.port())
+            .find(|port| *port != 0)
+            .expect("ticket should hold the sender's port");
+        let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let ticket = Ticket::new(EndpointAddr::new(ticket.addr.id).with_ip_addr(loopback));
+
+        let receiver_node = ZapNode::builder().offline().build().await.unwrap();
+        let output_dir = tempfile::tempdir().unwrap();
+        let mut receiver_progress = receiver_node
+            .receive(ticket, Some(output_dir.path()))
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut received = None;
+            while !sender_done || received.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::Complete { .. } => sender_done = true,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(progress) = receiver_progress.recv() => match progress {
+                        ReceiveProgress::Complete { path, .. } => received = Some(path),
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    },
+                }
+            }
+            received.unwrap()
+        })
+        .await
+        .expect("offline transfer should complete within timeout");
+        assert_eq!(fs::read(&result).await.unwrap(), b"no internet needed");
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
     /// Test transfer of a larger file
     #[tokio::test]
     async fn test_file_transfer_large() {
@@ -462,10 +2281,10 @@ mod e2e_tests {
                         match progress {
                             SendProgress::Sending { bytes_sent, total_bytes } => {
                                 assert!(bytes_sent >= last_bytes_sent, "progress should not go backwards");
-                                assert_eq!(total_bytes, size as u64);
+                                assert_eq!(total_bytes, Some(size as u64));
                                 last_bytes_sent = bytes_sent;
                             }
-                            SendProgress::Complete => {
+                            SendProgress::Complete { .. } => {
                                 sender_done = true;
                             }
                             SendProgress::Error(e) => {
@@ -478,10 +2297,10 @@ mod e2e_tests {
                         match progress {
                             ReceiveProgress::Receiving { bytes_received, total_bytes } => {
                                 assert!(bytes_received >= last_bytes_received, "progress should not go backwards");
-                                assert_eq!(total_bytes, size as u64);
+                                assert_eq!(total_bytes, Some(size as u64));
                                 last_bytes_received = bytes_received;
                             }
-                            ReceiveProgress::Complete { path } => {
+                            ReceiveProgress::Complete { path, .. } => {
                                 receiver_done = true;
                                 received_path = Some(path);
                             }
@@ -537,11 +2356,11 @@ mod e2e_tests {
         let result = timeout(Duration::from_secs(30), async {
             let mut got_offer = false;
             let mut offer_name = String::new();
-            let mut offer_size = 0u64;
+            let mut offer_size = None;
 
             while let Some(progress) = receiver_progress.recv().await {
                 match progress {
-                    ReceiveProgress::Offer { name, size } => {
+                    ReceiveProgress::Offer { name, size, .. } => {
                         got_offer = true;
                         offer_name = name;
                         offer_size = size;
@@ -564,7 +2383,7 @@ mod e2e_tests {
         let (got_offer, name, size) = result.unwrap();
         assert!(got_offer, "should receive offer");
         assert_eq!(name, "metadata_test.txt");
-        assert_eq!(size, test_content.len() as u64);
+        assert_eq!(size, Some(test_content.len() as u64));
 
         sender_node.shutdown().await.unwrap();
         receiver_node.shutdown().await.unwrap();
@@ -601,14 +2420,14 @@ mod e2e_tests {
                     tokio::select! {
                         Some(progress) = sender_progress.recv() => {
                             match progress {
-                                SendProgress::Complete => sender_done = true,
+                                SendProgress::Complete { .. } => sender_done = true,
                                 SendProgress::Error(e) => panic!("sender error: {}", e),
                                 _ => {}
                             }
                         }
                         Some(progress) = receiver_progress.recv() => {
                             match progress {
-                                ReceiveProgress::Complete { path } => {
+                                ReceiveProgress::Complete { path, .. } => {
                                     receiver_done = true;
                                     received_path = Some(path);
                                 }
@@ -636,4 +2455,1466 @@ mod e2e_tests {
             receiver_node.shutdown().await.unwrap();
         }
     }
+
+    /// Test streaming data of unknown length from a reader
+    #[tokio::test]
+    async fn test_send_reader_unknown_size() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_content: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node
+            .send_reader("piped.bin", std::io::Cursor::new(test_content.clone()))
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir(&output_dir).await.unwrap();
+
+        let mut receiver_progress = receiver_node
+            .receive(ticket, Some(output_dir.as_path()))
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut received_path = None;
+
+            while !sender_done || received_path.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => {
+                        match progress {
+                            SendProgress::Sending { total_bytes, .. } => {
+                                assert_eq!(total_bytes, None);
+                            }
+                            SendProgress::Complete { .. } => sender_done = true,
+                            SendProgress::Error(e) => panic!("sender error: {}", e),
+                            _ => {}
+                        }
+                    }
+                    Some(progress) = receiver_progress.recv() => {
+                        match progress {
+                            ReceiveProgress::Offer { name, size, .. } => {
+                                assert_eq!(name, "piped.bin");
+                                assert_eq!(size, None);
+                            }
+                            ReceiveProgress::Complete { path, .. } => received_path = Some(path),
+                            ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+
+            received_path
+        })
+        .await;
+
+        assert!(result.is_ok(), "transfer should complete within timeout");
+        let received_content = fs::read(result.unwrap().unwrap()).await.unwrap();
+        assert_eq!(received_content, test_content);
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Send a small tree and return the received path and the offered kind
+    async fn transfer_directory(options: SendOptions) -> (tempfile::TempDir, PathBuf, OfferKind) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("tree");
+        fs::create_dir_all(source.join("nested/deeper"))
+            .await
+            .unwrap();
+        fs::write(source.join("top.txt"), b"top level")
+            .await
+            .unwrap();
+        fs::write(source.join("empty.txt"), b"").await.unwrap();
+        fs::write(source.join("nested/deeper/big.bin"), vec![7u8; 600_000])
+            .await
+            .unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node
+            .send_with_options(&source, options)
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir(&output_dir).await.unwrap();
+
+        let mut receiver_progress = receiver_node
+            .receive(ticket, Some(output_dir.as_path()))
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut received = None;
+            let mut offer_kind = None;
+
+            while !sender_done || received.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => {
+                        match progress {
+                            SendProgress::Complete { .. } => sender_done = true,
+                            SendProgress::Error(e) => panic!("sender error: {}", e),
+                            _ => {}
+                        }
+                    }
+                    Some(progress) = receiver_progress.recv() => {
+                        match progress {
+                            ReceiveProgress::Offer { name, kind, .. } => {
+                                assert_eq!(name, "tree");
+                                offer_kind = Some(kind);
+                            }
+                            ReceiveProgress::Complete { path, .. } => received = Some(path),
+                            ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+
+            (received.unwrap(), offer_kind.unwrap())
+        })
+        .await;
+
+        assert!(result.is_ok(), "transfer should complete within timeout");
+        let (path, kind) = result.unwrap();
+
+        assert_eq!(fs::read(path.join("top.txt")).await.unwrap(), b"top level");
+        assert!(fs::read(path.join("empty.txt")).await.unwrap().is_empty());
+        assert_eq!(
+            fs::read(path.join("nested/deeper/big.bin")).await.unwrap(),
+            vec![7u8; 600_000]
+        );
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+        (temp_dir, path, kind)
+    }
+
+    /// Test that small directories are sent file by file
+    #[tokio::test]
+    async fn test_directory_transfer() {
+        let (_temp_dir, _, kind) = transfer_directory(SendOptions::default()).await;
+        assert!(matches!(kind, OfferKind::Directory { entries } if entries.len() == 3));
+    }
+
+    /// Test that directories over the archive threshold arrive as an archive
+    #[tokio::test]
+    #[cfg(feature = "archive")]
+    async fn test_directory_transfer_archived() {
+        let options = SendOptions {
+            auto_archive: Some(ArchivePolicy {
+                max_files: 2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let (_temp_dir, path, kind) = transfer_directory(options).await;
+        assert_eq!(kind, OfferKind::Archive);
+
+        // The staged archive is cleaned up after unpacking
+        let staged = path.parent().unwrap().join(".tree.zap-archive");
+        assert!(!staged.exists());
+    }
+
+    /// Test that a transform changes files before they're offered, keeping
+    /// their folders and skipping ones whose new name is taken
+    #[tokio::test]
+    async fn test_directory_transfer_transformed() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("photos");
+        fs::create_dir_all(source.join("trip")).await.unwrap();
+        fs::write(source.join("trip/beach.heic"), b"beach")
+            .await
+            .unwrap();
+        fs::write(source.join("notes.txt"), b"notes").await.unwrap();
+        fs::write(source.join("taken.heic"), b"kept").await.unwrap();
+        fs::write(source.join("taken.jpg"), b"already here")
+            .await
+            .unwrap();
+
+        let transform = Transform::new(
+            |name| {
+                name.strip_suffix(".heic")
+                    .map(|stem| format!("{}.jpg", stem))
+            },
+            |source, dest| {
+                assert_eq!(dest.extension().unwrap(), "jpg");
+                let data = std::fs::read(source)?;
+                std::fs::write(dest, data.to_ascii_uppercase())
+            },
+        );
+        let options = SendOptions {
+            transform: Some(transform),
+            ..Default::default()
+        };
+        let sender_node = ZapNode::new().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node
+            .send_with_options(&source, options)
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir(&output_dir).await.unwrap();
+        let mut receiver_progress = receiver_node
+            .receive(ticket, Some(output_dir.as_path()))
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut transformed = None;
+            let mut received = None;
+            while received.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => {
+                        match progress {
+                            SendProgress::Transforming { files_done, total_files } => {
+                                transformed = Some((files_done, total_files));
+                            }
+                            SendProgress::Error(e) => panic!("sender error: {}", e),
+                            _ => {}
+                        }
+                    }
+                    Some(progress) = receiver_progress.recv() => {
+                        match progress {
+                            ReceiveProgress::Complete { path, .. } => received = Some(path),
+                            ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            (transformed, received.unwrap())
+        })
+        .await;
+
+        assert!(result.is_ok(), "transfer should complete within timeout");
+        let (transformed, path) = result.unwrap();
+        assert_eq!(transformed, Some((1, 1)));
+        assert_eq!(
+            fs::read(path.join("trip/beach.jpg")).await.unwrap(),
+            b"BEACH"
+        );
+        assert!(!path.join("trip/beach.heic").exists());
+        assert_eq!(fs::read(path.join("notes.txt")).await.unwrap(), b"notes");
+        assert_eq!(fs::read(path.join("taken.heic")).await.unwrap(), b"kept");
+        assert_eq!(
+            fs::read(path.join("taken.jpg")).await.unwrap(),
+            b"already here"
+        );
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test that the receiver can pick which files of a directory to receive
+    #[tokio::test]
+    async fn test_directory_transfer_selected() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("tree");
+        fs::create_dir_all(source.join("nested")).await.unwrap();
+        fs::write(source.join("a.txt"), b"first").await.unwrap();
+        fs::write(source.join("b.txt"), b"second").await.unwrap();
+        fs::write(source.join("nested/c.txt"), b"third")
+            .await
+            .unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node.send(&source).await.unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir(&output_dir).await.unwrap();
+        let options = ReceiveOptions {
+            choose_entries: Some(EntryChooser::new(|entries: Vec<DirEntry>| async move {
+                let chosen = entries
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, e)| e.path != "b.txt")
+                    .map(|(i, _)| i)
+                    .collect();
+                Some(chosen)
+            })),
+            ..Default::default()
+        };
+        let mut receiver_progress = receiver_node
+            .receive_with_options(ticket, Some(output_dir.as_path()), options)
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut received = None;
+            let mut total = None;
+
+            while !sender_done || received.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::Complete { .. } => sender_done = true,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(progress) = receiver_progress.recv() => match progress {
+                        ReceiveProgress::Receiving { total_bytes, .. } => total = total_bytes,
+                        ReceiveProgress::Complete { path, .. } => received = Some(path),
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    },
+                }
+            }
+
+            (received.unwrap(), total)
+        })
+        .await;
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+
+        let (path, total) = result.expect("transfer should complete within timeout");
+        assert_eq!(total, Some(10));
+        assert_eq!(fs::read(path.join("a.txt")).await.unwrap(), b"first");
+        assert_eq!(fs::read(path.join("nested/c.txt")).await.unwrap(), b"third");
+        assert!(!path.join("b.txt").exists());
+    }
+
+    /// Test that files from different directories arrive side by side
+    #[tokio::test]
+    async fn test_batch_transfer() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let docs = temp_dir.path().join("docs");
+        let photos = temp_dir.path().join("photos");
+        fs::create_dir_all(photos.join("trip")).await.unwrap();
+        fs::create_dir_all(&docs).await.unwrap();
+        fs::write(docs.join("a.txt"), b"first").await.unwrap();
+        fs::write(photos.join("trip/b.jpg"), b"second")
+            .await
+            .unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let paths = [docs.join("a.txt"), photos.join("trip")];
+        let (ticket, mut sender_progress) = sender_node
+            .send_batch(&paths, SendOptions::default())
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir(&output_dir).await.unwrap();
+        let mut receiver_progress = receiver_node
+            .receive(ticket, Some(output_dir.as_path()))
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut received = None;
+            let mut sent_files = Vec::new();
+
+            while !sender_done || received.is_none() {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::FileSent { path } => sent_files.push(path),
+                        SendProgress::Complete { .. } => sender_done = true,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(progress) = receiver_progress.recv() => match progress {
+                        ReceiveProgress::Complete { path, .. } => received = Some(path),
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    },
+                }
+            }
+
+            (received.unwrap(), sent_files)
+        })
+        .await;
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+
+        let (path, sent_files) = result.expect("transfer should complete within timeout");
+        assert_eq!(path, output_dir);
+        assert_eq!(sent_files, ["a.txt", "trip/b.jpg"]);
+        assert_eq!(fs::read(output_dir.join("a.txt")).await.unwrap(), b"first");
+        assert_eq!(
+            fs::read(output_dir.join("trip/b.jpg")).await.unwrap(),
+            b"second"
+        );
+
+        // Two paths with the same name would overwrite each other
+        let node = ZapNode::new().await.unwrap();
+        let clash = [docs.join("a.txt"), docs.join("../docs/a.txt")];
+        assert!(
+            node.send_batch(&clash, SendOptions::default())
+                .await
+                .is_err()
+        );
+        node.shutdown().await.unwrap();
+    }
+
+    /// Test that a paused send holds off until it is resumed
+    #[tokio::test]
+    async fn test_paused_transfer() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("paused.txt");
+        let test_content = b"Paused, then resumed";
+        fs::write(&test_file, test_content).await.unwrap();
+
+        // Pause before the receiver even connects
+        let handle = TransferHandle::new();
+        handle.pause();
+        let options = SendOptions {
+            handle: Some(handle.clone()),
+            ..Default::default()
+        };
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let (ticket, mut sender_progress) = sender_node
+            .send_with_options(&test_file, options)
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir(&output_dir).await.unwrap();
+        let mut receiver_progress = receiver_node
+            .receive(ticket, Some(output_dir.as_path()))
+            .await
+            .unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            let mut sender_done = false;
+            let mut receiver_paused = false;
+            let mut received_path = None;
+
+            loop {
+                tokio::select! {
+                    Some(progress) = sender_progress.recv() => match progress {
+                        SendProgress::Sending { .. } => {
+                            assert!(!handle.is_paused(), "no data while paused");
+                        }
+                        SendProgress::Complete { .. } => sender_done = true,
+                        SendProgress::Error(e) => panic!("sender error: {}", e),
+                        _ => {}
+                    },
+                    Some(progress) = receiver_progress.recv() => match progress {
+                        ReceiveProgress::Paused => {
+                            receiver_paused = true;
+                            handle.resume();
+                        }
+                        ReceiveProgress::Complete { path, .. } => received_path = Some(path),
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    },
+                }
+
+                if sender_done && received_path.is_some() {
+                    break (receiver_paused, received_path.unwrap());
+                }
+            }
+        })
+        .await;
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+
+        let (receiver_paused, path) = result.expect("should complete within timeout");
+        assert!(receiver_paused, "receiver should see the pause");
+        assert_eq!(fs::read(path).await.unwrap(), test_content);
+    }
+
+    /// Test that a sender keeping its offer open serves several receivers
+    #[tokio::test]
+    async fn test_keep_serving() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("shared.txt");
+        fs::write(&test_file, b"for everyone").await.unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let options = SendOptions {
+            keep_serving: true,
+            ..Default::default()
+        };
+        let (ticket, mut sender_progress) = sender_node
+            .send_with_options(&test_file, options)
+            .await
+            .unwrap();
+        tokio::spawn(async move { while sender_progress.recv().await.is_some() {} });
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let offer = receiver_node.peek(&ticket).await.unwrap();
+        assert_eq!(offer.name, "shared.txt");
+        assert_eq!(offer.size, Some(12));
+
+        for i in 0..2 {
+            let output_dir = temp_dir.path().join(format!("output{}", i));
+            fs::create_dir(&output_dir).await.unwrap();
+            let mut progress = receiver_node
+                .receive(ticket.clone(), Some(output_dir.as_path()))
+                .await
+                .unwrap();
+
+            let path = timeout(Duration::from_secs(30), async {
+                while let Some(p) = progress.recv().await {
+                    match p {
+                        ReceiveProgress::Complete { path, .. } => return path,
+                        ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                        _ => {}
+                    }
+                }
+                panic!("receiver ended without completing");
+            })
+            .await
+            .expect("transfer should complete within timeout");
+            assert_eq!(fs::read(&path).await.unwrap(), b"for everyone");
+        }
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test that a share stops once enough receivers have everything, and
+    /// that peeks don't count
+    #[tokio::test]
+    async fn test_max_downloads() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("limited.txt");
+        fs::write(&test_file, b"just once").await.unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let options = SendOptions {
+            keep_serving: true,
+            max_downloads: Some(1),
+            ..Default::default()
+        };
+        let (ticket, mut sender_progress) = sender_node
+            .send_with_options(&test_file, options)
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        receiver_node.peek(&ticket).await.unwrap();
+        let mut progress = receiver_node
+            .receive(ticket.clone(), Some(temp_dir.path()))
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(30), async {
+            while let Some(p) = progress.recv().await {
+                match p {
+                    ReceiveProgress::Complete { .. } => return,
+                    ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                    _ => {}
+                }
+            }
+            panic!("receiver ended without completing");
+        })
+        .await
+        .expect("transfer should complete within timeout");
+
+        // The sender is done, so its progress ends without an error
+        timeout(Duration::from_secs(10), async {
+            while let Some(p) = sender_progress.recv().await {
+                if let SendProgress::Error(e) = p {
+                    panic!("sender error: {}", e);
+                }
+            }
+        })
+        .await
+        .expect("sender should stop after the download");
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test that a bandwidth limit slows the send down and is reported
+    #[tokio::test]
+    async fn test_bandwidth_limit() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("paced.bin");
+        fs::write(&test_file, vec![7u8; 3 * 1024 * 1024])
+            .await
+            .unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let options = SendOptions {
+            bandwidth: Some(Bandwidth::new(Some(1024 * 1024), None)),
+            ..Default::default()
+        };
+        let (ticket, mut sender_progress) = sender_node
+            .send_with_options(&test_file, options)
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let output = tempfile::tempdir().unwrap();
+        let started = std::time::Instant::now();
+        let mut progress = receiver_node
+            .receive(ticket, Some(output.path()))
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(30), async {
+            while let Some(p) = progress.recv().await {
+                match p {
+                    ReceiveProgress::Complete { .. } => return,
+                    ReceiveProgress::Error(e) => panic!("receiver error: {}", e),
+                    _ => {}
+                }
+            }
+            panic!("receiver ended without completing");
+        })
+        .await
+        .expect("transfer should complete within timeout");
+        // The first megabyte is a burst, the other two take a second each
+        assert!(started.elapsed() >= Duration::from_millis(1500));
+
+        let mut share = None;
+        while let Ok(p) = sender_progress.try_recv() {
+            if let SendProgress::Throughput { share: Some(s), .. } = p {
+                share = Some(s);
+            }
+        }
+        assert_eq!(share, Some(1024 * 1024));
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test that a sender nobody connects to gives up after the idle timeout
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("unclaimed.txt");
+        fs::write(&test_file, b"nobody came").await.unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let options = SendOptions {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let (_, mut sender_progress) = sender_node
+            .send_with_options(&test_file, options)
+            .await
+            .unwrap();
+
+        let error = timeout(Duration::from_secs(10), async {
+            while let Some(p) = sender_progress.recv().await {
+                if let SendProgress::Error(e) = p {
+                    return e;
+                }
+            }
+            panic!("sender ended without an error");
+        })
+        .await
+        .expect("sender should give up within timeout");
+        assert_eq!(error, Error::Timeout.to_string());
+
+        sender_node.shutdown().await.unwrap();
+    }
+
+    /// Test that cancelling a sender nobody has connected to ends its wait
+    #[tokio::test]
+    async fn test_cancel_while_waiting() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("unclaimed.txt");
+        fs::write(&test_file, b"nobody came").await.unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let handle = TransferHandle::new();
+        let options = SendOptions {
+            handle: Some(handle.clone()),
+            ..Default::default()
+        };
+        let (_, mut sender_progress) = sender_node
+            .send_with_options(&test_file, options)
+            .await
+            .unwrap();
+
+        let error = timeout(Duration::from_secs(10), async {
+            while let Some(p) = sender_progress.recv().await {
+                match p {
+                    SendProgress::Waiting => handle.cancel().await,
+                    SendProgress::Error(e) => return e,
+                    _ => {}
+                }
+            }
+            panic!("sender ended without an error");
+        })
+        .await
+        .expect("sender should stop waiting once cancelled");
+        assert_eq!(error, Error::Cancelled.to_string());
+
+        sender_node.shutdown().await.unwrap();
+    }
+
+    /// Test reading ranges of a shared directory's files
+    #[tokio::test]
+    async fn test_read_range() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("data");
+        fs::create_dir_all(source.join("sub")).await.unwrap();
+        fs::write(source.join("a.txt"), b"0123456789")
+            .await
+            .unwrap();
+        fs::write(source.join("sub/b.txt"), b"abcdefghij")
+            .await
+            .unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let options = SendOptions {
+            keep_serving: true,
+            ..Default::default()
+        };
+        let (ticket, mut sender_progress) = sender_node
+            .send_with_options(&source, options)
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            while let Some(p) = sender_progress.recv().await {
+                assert!(
+                    !matches!(p, SendProgress::Complete { .. }),
+                    "ranges aren't reported as transfers"
+                );
+            }
+        });
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let offer = receiver_node.peek(&ticket).await.unwrap();
+        let OfferKind::Directory { entries } = offer.kind else {
+            panic!("expected a directory offer");
+        };
+        let b = entries.iter().position(|e| e.path == "sub/b.txt").unwrap() as u32;
+
+        let data = timeout(
+            Duration::from_secs(30),
+            receiver_node.read_range(&ticket, b, 3, 4),
+        )
+        .await
+        .expect("range should arrive within timeout")
+        .unwrap();
+        assert_eq!(data, b"defg");
+
+        // A range past the end of the file is refused
+        let result = receiver_node.read_range(&ticket, b, 8, 4).await;
+        assert!(result.is_err());
+
+        // A reader keeps reading over one connection, and connects again
+        // after one the share gave up on
+        let a = entries.iter().position(|e| e.path == "a.txt").unwrap() as u32;
+        let reader = receiver_node.range_reader(&ticket);
+        assert_eq!(reader.read(b, 0, 3).await.unwrap(), b"abc");
+        assert_eq!(reader.read(a, 5, 5).await.unwrap(), b"56789");
+        assert!(reader.read(a, 8, 4).await.is_err());
+        assert_eq!(reader.read(b, 7, 3).await.unwrap(), b"hij");
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test getting the checksum of each file in a share
+    #[tokio::test]
+    async fn test_checksums() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("data");
+        fs::create_dir_all(source.join("sub")).await.unwrap();
+        fs::write(source.join("a.txt"), b"0123456789")
+            .await
+            .unwrap();
+        fs::write(source.join("sub/b.txt"), b"abcdefghij")
+            .await
+            .unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let options = SendOptions {
+            keep_serving: true,
+            ..Default::default()
+        };
+        let (ticket, _sender_progress) = sender_node
+            .send_with_options(&source, options)
+            .await
+            .unwrap();
+
+        let receiver_node = ZapNode::new().await.unwrap();
+        let (offer, checksums) = timeout(Duration::from_secs(30), receiver_node.checksums(&ticket))
+            .await
+            .expect("checksums should arrive within timeout")
+            .unwrap();
+        let OfferKind::Directory { entries } = offer.kind else {
+            panic!("expected a directory offer");
+        };
+        assert_eq!(checksums.len(), entries.len());
+        for (entry, checksum) in entries.iter().zip(&checksums) {
+            let data = fs::read(source.join(&entry.path)).await.unwrap();
+            assert_eq!(checksum, blake3::hash(&data).as_bytes());
+        }
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test that a sender requiring a transfer key only serves receivers
+    /// that have it
+    #[tokio::test]
+    async fn test_transfer_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("secret.txt");
+        fs::write(&test_file, b"keyed").await.unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let options = SendOptions {
+            keep_serving: true,
+            key: Some(TransferKey::generate()),
+            ..Default::default()
+        };
+        let (ticket, mut sender_progress) = sender_node
+            .send_with_options(&test_file, options)
+            .await
+            .unwrap();
+        tokio::spawn(async move { while sender_progress.recv().await.is_some() {} });
+
+        // What a relay would have: the ticket, but not the key
+        let receiver_node = ZapNode::new().await.unwrap();
+        let without_key = Ticket::deserialize(&ticket.serialize()).unwrap();
+        assert!(receiver_node.peek(&without_key).await.is_err());
+
+        // Leaving the key's hash out of the ticket doesn't get around it
+        let forged = Ticket::new(ticket.addr.clone());
+        let refused = timeout(Duration::from_secs(30), receiver_node.peek(&forged))
+            .await
+            .expect("the sender should answer within timeout");
+        assert!(refused.is_err());
+
+        let with_key = Ticket::deserialize(&ticket.share()).unwrap();
+        let offer = timeout(Duration::from_secs(30), receiver_node.peek(&with_key))
+            .await
+            .expect("offer should arrive within timeout")
+            .unwrap();
+        assert_eq!(offer.name, "secret.txt");
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test that the sender itself turns receivers away once its ticket
+    /// expires, even ones that ignore the expiry written in the ticket
+    #[tokio::test]
+    async fn test_ticket_expiry_on_sender() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("contract.pdf");
+        fs::write(&test_file, b"signed").await.unwrap();
+
+        let sender_node = ZapNode::new().await.unwrap();
+        let options = SendOptions {
+            keep_serving: true,
+            ticket_ttl: Some(Duration::from_secs(3)),
+            ..Default::default()
+        };
+        let (ticket, mut sender_progress) = sender_node
+            .send_with_options(&test_file, options)
+            .await
+            .unwrap();
+        let sender_done =
+            tokio::spawn(async move { while sender_progress.recv().await.is_some() {} });
+
+        // A leaked copy, with the expiry taken out
+        let receiver_node = ZapNode::new().await.unwrap();
+        let mut leaked = ticket.clone();
+        leaked.expires_at = None;
+        let offer = timeout(Duration::from_secs(30), receiver_node.peek(&leaked))
+            .await
+            .expect("offer should arrive within timeout")
+            .unwrap();
+        assert_eq!(offer.name, "contract.pdf");
+
+        // Past the expiry the share ends, and nobody gets the offer
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        timeout(Duration::from_secs(10), sender_done)
+            .await
+            .expect("the share should end once the ticket expires")
+            .unwrap();
+        let refused = timeout(Duration::from_secs(10), receiver_node.peek(&leaked)).await;
+        assert!(!matches!(refused, Ok(Ok(_))));
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+    }
+
+    /// Test copying to and from a listening peer, and that only paired
+    /// peers are served
+    #[tokio::test]
+    async fn test_remote_copy() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("shared");
+        fs::create_dir_all(root.join("incoming")).await.unwrap();
+        fs::write(root.join("app.log"), b"started").await.unwrap();
+        let local = temp_dir.path().join("local");
+        fs::create_dir(&local).await.unwrap();
+        fs::write(local.join("notes.txt"), b"hello").await.unwrap();
+
+        let listener = ZapNode::new().await.unwrap();
+        let client = ZapNode::new().await.unwrap();
+        let options = ListenOptions {
+            root: root.clone(),
+            peers: vec![client.id()],
+            read_only: false,
+            inbox: None,
+        };
+        let mut events = listener.listen(options).await.unwrap();
+
+        // Copy a file in
+        let mut progress = client
+            .copy_to(listener.id(), local.join("notes.txt"), "incoming")
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(30), async {
+            while let Some(p) = progress.recv().await {
+                match p {
+                    SendProgress::Complete { .. } => return,
+                    SendProgress::Error(e) => panic!("copy to failed: {}", e),
+                    _ => {}
+                }
+            }
+            panic!("copy ended without completing");
+        })
+        .await
+        .expect("copy should complete within timeout");
+        let copied = fs::read(root.join("incoming/notes.txt")).await.unwrap();
+        assert_eq!(copied, b"hello");
+
+        // And one out
+        let mut progress = client
+            .copy_from(listener.id(), "app.log", Some(local.as_path()))
+            .await
+            .unwrap();
+        let path = timeout(Duration::from_secs(30), async {
+            while let Some(p) = progress.recv().await {
+                match p {
+                    ReceiveProgress::Complete { path, .. } => return path,
+                    ReceiveProgress::Error(e) => panic!("copy from failed: {}", e),
+                    _ => {}
+                }
+            }
+            panic!("copy ended without completing");
+        })
+        .await
+        .expect("copy should complete within timeout");
+        assert_eq!(fs::read(&path).await.unwrap(), b"started");
+
+        // Nothing outside the shared directory can be reached
+        let mut progress = client
+            .copy_from(listener.id(), "../local/notes.txt", Some(local.as_path()))
+            .await
+            .unwrap();
+        loop {
+            match progress.recv().await {
+                Some(ReceiveProgress::Error(_)) => break,
+                Some(ReceiveProgress::Complete { .. }) | None => {
+                    panic!("a path outside the root should be refused")
+                }
+                Some(_) => {}
+            }
+        }
+
+        // A peer that isn't paired is turned away
+        let stranger = ZapNode::new().await.unwrap();
+        let mut progress = stranger
+            .copy_from(listener.id(), "app.log", Some(local.as_path()))
+            .await
+            .unwrap();
+        while let Some(p) = progress.recv().await {
+            assert!(!matches!(p, ReceiveProgress::Complete { .. }));
+        }
+        let refused = timeout(Duration::from_secs(30), async {
+            while let Some(event) = events.recv().await {
+                if let ListenEvent::Refused { peer } = event {
+                    return peer;
+                }
+            }
+            panic!("listener ended");
+        })
+        .await
+        .unwrap();
+        assert_eq!(refused, stranger.id());
+
+        listener.shutdown().await.unwrap();
+        client.shutdown().await.unwrap();
+        stranger.shutdown().await.unwrap();
+    }
+
+    /// Test that an inbox decides where copies in go and which it takes
+    #[tokio::test]
+    async fn test_inbox() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("shared");
+        fs::create_dir(&root).await.unwrap();
+        let local = temp_dir.path().join("local");
+        fs::create_dir(&local).await.unwrap();
+        fs::write(local.join("notes.txt"), b"hello").await.unwrap();
+        fs::write(local.join("big.bin"), vec![0u8; 1000])
+            .await
+            .unwrap();
+
+        let listener = ZapNode::new().await.unwrap();
+        let client = ZapNode::new().await.unwrap();
+        let inbox = Inbox::new(|peer| {
+            let options = ReceiveOptions {
+                check: Some(OfferCheck::new(|offer| match offer.size {
+                    Some(size) if size > 100 => Err("over quota".to_string()),
+                    _ => Ok(()),
+                })),
+                ..Default::default()
+            };
+            (PathBuf::from("inbox").join(peer.to_string()), options)
+        });
+        let options = ListenOptions {
+            root: root.clone(),
+            peers: vec![client.id()],
+            read_only: false,
+            inbox: Some(inbox),
+        };
+        let _events = listener.listen(options).await.unwrap();
+
+        // The directory the peer names doesn't matter
+        let mut progress = client
+            .copy_to(listener.id(), local.join("notes.txt"), "elsewhere")
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(30), async {
+            while let Some(p) = progress.recv().await {
+                match p {
+                    SendProgress::Complete { .. } => return,
+                    SendProgress::Error(e) => panic!("copy to failed: {}", e),
+                    _ => {}
+                }
+            }
+            panic!("copy ended without completing");
+        })
+        .await
+        .expect("copy should complete within timeout");
+        let inbox = root.join("inbox").join(client.id().to_string());
+        assert_eq!(fs::read(inbox.join("notes.txt")).await.unwrap(), b"hello");
+
+        // What the check turns down fails with its reason
+        let mut progress = client
+            .copy_to(listener.id(), local.join("big.bin"), "")
+            .await
+            .unwrap();
+        let error = timeout(Duration::from_secs(30), async {
+            while let Some(p) = progress.recv().await {
+                match p {
+                    SendProgress::Error(e) => return e,
+                    SendProgress::Complete { .. } => panic!("the check should refuse it"),
+                    _ => {}
+                }
+            }
+            panic!("copy ended without an error");
+        })
+        .await
+        .unwrap();
+        assert!(error.contains("over quota"), "{}", error);
+        assert!(!inbox.join("big.bin").exists());
+
+        listener.shutdown().await.unwrap();
+        client.shutdown().await.unwrap();
+    }
+
+    /// Test that nodes in different ALPN namespaces refuse each other
+    #[tokio::test]
+    async fn test_alpn_mismatch() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let test_file = temp_dir.path().join("test.txt");
+        fs::write(&test_file, b"private").await.unwrap();
+
+        let sender_node = ZapNode::builder().alpn("zap/acme/1").build().await.unwrap();
+        let receiver_node = ZapNode::new().await.unwrap();
+
+        let (ticket, _sender_progress) = sender_node.send(&test_file).await.unwrap();
+        let mut receiver_progress = receiver_node
+            .receive(ticket, Some(temp_dir.path()))
+            .await
+            .unwrap();
+
+        let error = timeout(Duration::from_secs(30), async {
+            while let Some(p) = receiver_progress.recv().await {
+                if let ReceiveProgress::Error(e) = p {
+                    return Some(e);
+                }
+            }
+            None
+        })
+        .await
+        .expect("should fail within timeout")
+        .expect("receiver should report an error");
+
+        sender_node.shutdown().await.unwrap();
+        receiver_node.shutdown().await.unwrap();
+
+        assert!(
+            error.contains("ALPN mismatch"),
+            "unexpected error: {}",
+            error
+        );
+    }
+
+    /// Test that a tunnel forwards TCP traffic in both directions
+    #[tokio::test]
+    #[cfg(feature = "tunnel")]
+    async fn test_tunnel_echo() {
+        use crate::TunnelEvent;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        // Local echo server that the exposer forwards to
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        let exposer = ZapNode::new().await.unwrap();
+        let (ticket, _exposer_events) = exposer.expose(echo_port).await.unwrap();
+
+        // Pick a free local port for the forwarder
+        let local_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let forwarder = ZapNode::new().await.unwrap();
+        let mut forwarder_events = forwarder.forward(ticket, local_addr).await.unwrap();
+
+        let result = timeout(Duration::from_secs(30), async {
+            loop {
+                match forwarder_events.recv().await {
+                    Some(TunnelEvent::Connected) => break,
+                    Some(TunnelEvent::Error(e)) => panic!("tunnel error: {}", e),
+                    Some(_) => {}
+                    None => panic!("tunnel closed"),
+                }
+            }
+
+            let mut stream = TcpStream::connect(local_addr).await.unwrap();
+            stream.write_all(b"ping over zap").await.unwrap();
+
+            let mut buf = [0u8; 13];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        })
+        .await;
+
+        assert!(result.is_ok(), "tunnel should work within timeout");
+        assert_eq!(&result.unwrap(), b"ping over zap");
+
+        exposer.shutdown().await.unwrap();
+        forwarder.shutdown().await.unwrap();
+    }
+
+    /// Test that a tunnel's ticket is no use without its key
+    #[tokio::test]
+    #[cfg(feature = "tunnel")]
+    async fn test_tunnel_needs_key() {
+        use crate::TunnelEvent;
+        use tokio::net::TcpListener;
+
+        let exposer = ZapNode::new().await.unwrap();
+        let (ticket, _exposer_events) = exposer.expose(1).await.unwrap();
+        assert!(ticket.key_hash.is_some());
+        let ticket = Ticket {
+            key: None,
+            ..ticket
+        };
+
+        let local_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let forwarder = ZapNode::new().await.unwrap();
+        let mut events = forwarder.forward(ticket, local_addr).await.unwrap();
+        let event = timeout(Duration::from_secs(10), events.recv())
+            .await
+            .unwrap();
+        assert!(matches!(event, Some(TunnelEvent::Error(_))), "{:?}", event);
+
+        exposer.shutdown().await.unwrap();
+        forwarder.shutdown().await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod chaos_tests {
+    use crate::chaos::{Faults, Flaky, ScriptedSender, receive_from};
+    use crate::protocol::{CHUNK_SIZE, Message};
+    use crate::{Error, ReceiveOptions, ReceiveProgress};
+    use std::time::Duration;
+
+    /// 10 KB in 1 KB chunks, so frame 1 is the first chunk and 11 is Done
+    fn data() -> Vec<u8> {
+        (0..10 * 1024).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn sender() -> ScriptedSender {
+        ScriptedSender::new("data.bin", &data(), 1024)
+    }
+
+    fn completed(progress: &[ReceiveProgress]) -> bool {
+        progress
+            .iter()
+            .any(|p| matches!(p, ReceiveProgress::Complete { .. }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_latency() {
+        let dir = tempfile::tempdir().unwrap();
+        let faults = Faults {
+            latency: Duration::from_millis(300),
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let mut peer = Flaky::new(sender(), faults);
+
+        let started = tokio::time::Instant::now();
+        let (result, progress) = receive_from(&mut peer, dir.path(), Default::default()).await;
+        result.unwrap();
+        assert!(completed(&progress));
+        assert!(started.elapsed() >= Duration::from_millis(300 * 12));
+        assert_eq!(std::fs::read(dir.path().join("data.bin")).unwrap(), data());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_reordered_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let faults = Faults {
+            reorder: Some(3),
+            ..Default::default()
+        };
+        let mut peer = Flaky::new(sender(), faults);
+
+        // Chunks are written as they come, so the checksum catches it
+        let (result, progress) = receive_from(&mut peer, dir.path(), Default::default()).await;
+        assert!(
+            matches!(&result, Err(Error::TransferFailed(e)) if e == "checksum mismatch"),
+            "{:?}",
+            result
+        );
+        assert!(!completed(&progress));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_truncated_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let faults = Faults {
+            truncate: Some(5),
+            ..Default::default()
+        };
+        let mut peer = Flaky::new(sender(), faults);
+
+        let (result, _) = receive_from(&mut peer, dir.path(), Default::default()).await;
+        assert!(
+            matches!(&result, Err(Error::TransferFailed(e)) if e.starts_with("size mismatch")),
+            "{:?}",
+            result
+        );
+    }
+
+    /// A dropped connection fails the receive, and trying again over a
+    /// working one replaces what was left behind
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_disconnect_and_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let faults = Faults {
+            disconnect: Some(4),
+            ..Default::default()
+        };
+        let mut peer = Flaky::new(sender(), faults);
+        let (result, progress) = receive_from(&mut peer, dir.path(), Default::default()).await;
+        assert!(matches!(result, Err(Error::ConnectionFailed(_))));
+        assert!(!completed(&progress));
+        let partial = std::fs::metadata(dir.path().join("data.bin"))
+            .unwrap()
+            .len();
+        assert!(partial < data().len() as u64);
+
+        let mut peer = Flaky::new(sender(), Faults::default());
+        let (result, _) = receive_from(&mut peer, dir.path(), Default::default()).await;
+        result.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("data.bin")).unwrap(), data());
+
+        // A third try with dedup finds the file there and skips the data
+        let options = ReceiveOptions {
+            dedup: true,
+            ..Default::default()
+        };
+        let mut peer = Flaky::new(sender(), Faults::default());
+        let (result, progress) = receive_from(&mut peer, dir.path(), options).await;
+        result.unwrap();
+        assert!(
+            progress
+                .iter()
+                .any(|p| matches!(p, ReceiveProgress::AlreadyHave { .. }))
+        );
+        // Only after the sender's hash was compared here
+        let replies = peer.into_inner().replies;
+        assert!(matches!(replies[1], Message::AskChecksum));
+        assert!(matches!(replies[2], Message::AlreadyHave { .. }));
+    }
+
+    /// A broken stream is replaced and the transfer carries on from where
+    /// it was, while the receiver acks as it goes
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_stream_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let faults = Faults {
+            reset: Some(4),
+            ..Default::default()
+        };
+        let mut peer = Flaky::new(sender(), faults);
+
+        let (result, progress) = receive_from(&mut peer, dir.path(), Default::default()).await;
+        result.unwrap();
+        assert!(completed(&progress));
+        assert_eq!(std::fs::read(dir.path().join("data.bin")).unwrap(), data());
+
+        let replies = peer.into_inner().replies;
+        assert!(matches!(
+            &replies[..],
+            [
+                Message::Ready,
+                Message::Accept,
+                Message::Ack { offset: 0 },
+                Message::Continue { offset: 3072 },
+                Message::Ack { offset: 8192 },
+            ]
+        ));
+    }
+
+    /// With the hash tree, a bad piece fails the receive as soon as it's
+    /// in, naming where it starts
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_verified_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let sender = || ScriptedSender::new("data.bin", &data, CHUNK_SIZE / 2);
+        let options = || ReceiveOptions {
+            verify_pieces: true,
+            ..Default::default()
+        };
+
+        let mut peer = Flaky::new(sender(), Faults::default());
+        let (result, _) = receive_from(&mut peer, dir.path(), options()).await;
+        result.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("data.bin")).unwrap(), data);
+        let replies = peer.into_inner().replies;
+        assert!(matches!(
+            &replies[..3],
+            [Message::Ready, Message::AcceptTree, Message::Accept]
+        ));
+
+        // Frame 4 is the first half of the second piece, swapped with the
+        // second half
+        let faults = Faults {
+            reorder: Some(4),
+            ..Default::default()
+        };
+        let mut peer = Flaky::new(sender(), faults);
+        let (result, progress) = receive_from(&mut peer, dir.path(), options()).await;
+        assert!(
+            matches!(&result, Err(Error::TransferFailed(e)) if e.contains("at 262144")),
+            "{:?}",
+            result
+        );
+        assert!(!completed(&progress));
+    }
+
+    /// A stall longer than the idle timeout drops the connection
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_stall_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let faults = Faults {
+            stall: Some((6, Duration::from_secs(60))),
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let mut peer = Flaky::new(sender(), faults);
+
+        let started = tokio::time::Instant::now();
+        let (result, _) = receive_from(&mut peer, dir.path(), Default::default()).await;
+        assert!(matches!(&result, Err(Error::ConnectionFailed(e)) if e == "timed out"));
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+    }
+
+    /// A pause longer than the idle timeout survives on heartbeats, and
+    /// the transfer resumes where it stopped
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_pause_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let faults = Faults {
+            // Heartbeats come this often while paused
+            latency: Duration::from_secs(10),
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let mut peer = Flaky::new(sender().pause_after(4, 6), faults);
+
+        let (result, progress) = receive_from(&mut peer, dir.path(), Default::default()).await;
+        result.unwrap();
+        let paused = progress
+            .iter()
+            .filter(|p| matches!(p, ReceiveProgress::Paused))
+            .count();
+        assert_eq!(paused, 1, "heartbeats don't report the pause again");
+        assert!(
+            progress
+                .iter()
+                .any(|p| matches!(p, ReceiveProgress::Resumed))
+        );
+        assert_eq!(std::fs::read(dir.path().join("data.bin")).unwrap(), data());
+    }
+
+    /// Nothing is accepted until the offer is confirmed, and declining
+    /// rejects it
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_confirm() {
+        let dir = tempfile::tempdir().unwrap();
+        let confirm = |answer: bool| {
+            crate::OfferConfirm::new(move |offer| async move {
+                assert_eq!(offer.name, "data.bin");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                answer
+            })
+        };
+
+        let mut peer = sender();
+        let options = ReceiveOptions {
+            confirm: Some(confirm(false)),
+            ..Default::default()
+        };
+        let (result, _) = receive_from(&mut peer, dir.path(), options).await;
+        assert!(matches!(&result, Err(Error::TransferFailed(e)) if e == "the receiver declined"));
+        assert!(matches!(
+            &peer.replies[..],
+            [Message::Ready, Message::Reject { .. }]
+        ));
+        assert!(!dir.path().join("data.bin").exists());
+
+        let options = ReceiveOptions {
+            confirm: Some(confirm(true)),
+            ..Default::default()
+        };
+        let (result, _) = receive_from(&mut sender(), dir.path(), options).await;
+        result.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("data.bin")).unwrap(), data());
+    }
 }
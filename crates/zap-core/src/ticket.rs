@@ -1,4 +1,6 @@
-use iroh::EndpointAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use iroh::{EndpointAddr, PublicKey};
 use serde::{Deserialize, Serialize};
 
 use crate::{Error, Result};
@@ -8,29 +10,217 @@ use crate::{Error, Result};
 pub struct Ticket {
     /// The sender's endpoint address
     pub addr: EndpointAddr,
+
+    /// Hash of the [`TransferKey`] the sender requires receivers to prove
+    /// they hold, if it requires one
+    pub key_hash: Option<[u8; 32]>,
+
+    /// The transfer key itself, when known. It's never part of the
+    /// serialized ticket, which may pass through a relay; see
+    /// [`share`](Self::share).
+    #[serde(skip)]
+    pub key: Option<TransferKey>,
+
+    /// When the ticket stops working, in seconds since the Unix epoch;
+    /// receivers and relays refuse it after that
+    pub expires_at: Option<u64>,
+}
+
+/// Tickets from before transfer keys, which end after the address
+#[derive(Deserialize)]
+struct TicketV1 {
+    addr: EndpointAddr,
+}
+
+/// Tickets from before expiry, which end after the key hash
+#[derive(Deserialize)]
+struct TicketV2 {
+    addr: EndpointAddr,
+    key_hash: Option<[u8; 32]>,
+}
+
+/// Why a ticket used after it expired is refused, on either end
+pub(crate) const EXPIRED: &str = "the ticket has expired; ask the sender for a new one";
+
+pub(crate) fn expired() -> Error {
+    Error::InvalidTicket(EXPIRED.into())
 }
 
 impl Ticket {
     /// Create a new ticket from an endpoint address
     pub fn new(addr: EndpointAddr) -> Self {
-        Self { addr }
+        Self {
+            addr,
+            key_hash: None,
+            key: None,
+            expires_at: None,
+        }
+    }
+
+    /// Stop the ticket from working after `ttl` from now
+    pub fn expiring_in(mut self, ttl: Duration) -> Self {
+        let at = SystemTime::now() + ttl;
+        self.expires_at = Some(at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+        self
+    }
+
+    /// How long the ticket keeps working, `None` if it doesn't expire and
+    /// zero once it has
+    pub fn remaining(&self) -> Option<Duration> {
+        let at = UNIX_EPOCH + Duration::from_secs(self.expires_at?);
+        Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+
+    /// Fail if the ticket has expired
+    pub fn check_expiry(&self) -> Result<()> {
+        match self.remaining() {
+            Some(Duration::ZERO) => Err(expired()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Require receivers to prove they hold `key`
+    pub fn requiring(mut self, key: TransferKey) -> Self {
+        self.key_hash = Some(key.hash());
+        self.key = Some(key);
+        self
     }
 
-    /// Serialize to a human-friendly string
+    /// Add the key this ticket requires, checking it's the right one
+    pub fn with_key(mut self, key: TransferKey) -> Result<Self> {
+        match self.key_hash {
+            Some(hash) if hash == key.hash() => {
+                self.key = Some(key);
+                Ok(self)
+            }
+            Some(_) => Err(Error::InvalidTicket("wrong transfer key".into())),
+            None => Err(Error::InvalidTicket(
+                "the sender doesn't use a transfer key".into(),
+            )),
+        }
+    }
+
+    /// Serialize to a human-friendly string, without the key
     pub fn serialize(&self) -> String {
         let bytes = postcard::to_allocvec(self).expect("ticket serialization cannot fail");
         data_encoding::BASE32_NOPAD.encode(&bytes).to_lowercase()
     }
 
-    /// Parse from a human-friendly string
+    /// The string to give the receiver: the serialized ticket, followed by
+    /// `.` and the key if there is one
+    pub fn share(&self) -> String {
+        match &self.key {
+            Some(key) => format!("{}.{}", self.serialize(), key),
+            None => self.serialize(),
+        }
+    }
+
+    /// Parse from a human-friendly string, with the key after a `.` if the
+    /// sender requires one
     pub fn deserialize(s: &str) -> Result<Self> {
+        let (s, key) = split_key(s);
         let s = s.trim().to_uppercase();
         let bytes = data_encoding::BASE32_NOPAD
             .decode(s.as_bytes())
             .map_err(|e| Error::InvalidTicket(format!("invalid base32: {}", e)))?;
 
-        postcard::from_bytes(&bytes)
-            .map_err(|e| Error::InvalidTicket(format!("invalid ticket data: {}", e)))
+        let ticket = match postcard::from_bytes::<Ticket>(&bytes) {
+            Ok(ticket) => ticket,
+            Err(e) => match postcard::from_bytes::<TicketV2>(&bytes) {
+                Ok(v2) => Ticket {
+                    key_hash: v2.key_hash,
+                    ..Ticket::new(v2.addr)
+                },
+                Err(_) => match postcard::from_bytes::<TicketV1>(&bytes) {
+                    Ok(v1) => Ticket::new(v1.addr),
+                    Err(_) => {
+                        return Err(Error::InvalidTicket(format!("invalid ticket data: {}", e)));
+                    }
+                },
+            },
+        };
+        match key {
+            Some(key) => ticket.with_key(key),
+            None => Ok(ticket),
+        }
+    }
+}
+
+/// Split off the transfer key some senders add after a `.` to a ticket,
+/// code or link, as in `abc123.<key>`, ignoring punctuation pasted along
+/// with it; anything after a `.` that isn't a key stays, since links have
+/// dots of their own
+pub fn split_key(s: &str) -> (&str, Option<TransferKey>) {
+    let trimmed = s
+        .trim()
+        .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '>', '"', '\''])
+        .trim_end();
+    match trimmed.rsplit_once('.') {
+        Some((rest, key)) => match key.parse() {
+            Ok(key) => (rest, Some(key)),
+            Err(_) => (s, None),
+        },
+        None => (s, None),
+    }
+}
+
+/// A random key for one transfer, so that holding its ticket isn't enough
+/// to receive it
+///
+/// Short codes map to tickets on a relay, and whoever runs the relay sees
+/// them. The key goes to the receiver alongside the code instead, and only
+/// its hash is in the ticket. The receiver proves it has the key when it
+/// connects, with [`proof`](Self::proof), and the sender turns away anyone
+/// who can't.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TransferKey(pub(crate) [u8; 16]);
+
+impl TransferKey {
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        blake3::hash(&self.0).into()
+    }
+
+    /// Proof that the receiver `receiver` holds this key, bound to both
+    /// ends so it can't be replayed to another sender or by another node
+    pub fn proof(&self, sender: &PublicKey, receiver: &PublicKey) -> [u8; 32] {
+        let mac_key = blake3::derive_key("zap transfer key proof v1", &self.0);
+        let mut hasher = blake3::Hasher::new_keyed(&mac_key);
+        hasher.update(sender.as_bytes());
+        hasher.update(receiver.as_bytes());
+        hasher.finalize().into()
+    }
+}
+
+impl std::fmt::Display for TransferKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let encoded = data_encoding::BASE32_NOPAD.encode(&self.0);
+        write!(f, "{}", encoded.to_lowercase())
+    }
+}
+
+/// Only the hash, so keys don't end up in logs
+impl std::fmt::Debug for TransferKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hash = data_encoding::HEXLOWER.encode(&self.hash()[..4]);
+        write!(f, "TransferKey({}..)", hash)
+    }
+}
+
+impl std::str::FromStr for TransferKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = data_encoding::BASE32_NOPAD
+            .decode(s.trim().to_uppercase().as_bytes())
+            .map_err(|e| Error::InvalidTicket(format!("invalid transfer key: {}", e)))?;
+        let key = bytes
+            .try_into()
+            .map_err(|_| Error::InvalidTicket("invalid transfer key: wrong length".into()))?;
+        Ok(Self(key))
     }
 }
 
@@ -65,4 +255,99 @@ mod tests {
 
         assert_eq!(ticket.addr.id, decoded.addr.id);
     }
+
+    #[test]
+    fn test_ticket_key() {
+        let addr = EndpointAddr::new(SecretKey::generate(&mut rand::rng()).public());
+        let key = TransferKey::generate();
+        let ticket = Ticket::new(addr.clone()).requiring(key);
+
+        // The serialized ticket has the hash but not the key
+        let encoded = ticket.serialize();
+        assert!(!encoded.contains(&key.to_string()));
+        let decoded = Ticket::deserialize(&encoded).unwrap();
+        assert_eq!(decoded.key_hash, Some(key.hash()));
+        assert_eq!(decoded.key, None);
+
+        let shared = Ticket::deserialize(&ticket.share()).unwrap();
+        assert_eq!(shared.key, Some(key));
+        let other = TransferKey::generate();
+        assert!(decoded.clone().with_key(other).is_err());
+        assert!(Ticket::deserialize(&format!("{}.{}", encoded, other)).is_err());
+        assert!(Ticket::deserialize(&format!("{}.nope", encoded)).is_err());
+    }
+
+    #[test]
+    fn test_split_key() {
+        let key = TransferKey::generate();
+        assert_eq!(split_key(&format!("abc123.{}", key)), ("abc123", Some(key)));
+        assert_eq!(split_key(&format!(" \"abc123.{}\". ", key)).1, Some(key));
+        assert_eq!(
+            split_key(&format!("https://zapper.cloud/r/abc123.{}", key)),
+            ("https://zapper.cloud/r/abc123", Some(key))
+        );
+
+        assert_eq!(split_key("abc123."), ("abc123.", None));
+        assert_eq!(
+            split_key("https://zapper.cloud/r/abc123"),
+            ("https://zapper.cloud/r/abc123", None)
+        );
+    }
+
+    #[test]
+    fn test_ticket_before_keys() {
+        let addr = EndpointAddr::new(SecretKey::generate(&mut rand::rng()).public());
+        let bytes = postcard::to_allocvec(&addr).unwrap();
+        let encoded = data_encoding::BASE32_NOPAD.encode(&bytes).to_lowercase();
+
+        let decoded = Ticket::deserialize(&encoded).unwrap();
+        assert_eq!(decoded.addr.id, addr.id);
+        assert_eq!(decoded.key_hash, None);
+    }
+
+    #[test]
+    fn test_ticket_expiry() {
+        let addr = EndpointAddr::new(SecretKey::generate(&mut rand::rng()).public());
+        let key = TransferKey::generate();
+
+        // Tickets from before expiry still read, with the key hash
+        let bytes = postcard::to_allocvec(&(&addr, Some(key.hash()))).unwrap();
+        let encoded = data_encoding::BASE32_NOPAD.encode(&bytes).to_lowercase();
+        let decoded = Ticket::deserialize(&encoded).unwrap();
+        assert_eq!(decoded.key_hash, Some(key.hash()));
+        assert_eq!(decoded.expires_at, None);
+        assert!(decoded.check_expiry().is_ok());
+
+        let ticket = Ticket::new(addr.clone())
+            .requiring(key)
+            .expiring_in(Duration::from_secs(3600));
+        let decoded = Ticket::deserialize(&ticket.share()).unwrap();
+        assert_eq!(decoded.expires_at, ticket.expires_at);
+        assert_eq!(decoded.key, Some(key));
+        assert!(decoded.remaining().unwrap() > Duration::from_secs(3500));
+        assert!(decoded.check_expiry().is_ok());
+
+        let expired = Ticket {
+            expires_at: Some(1),
+            ..Ticket::new(addr)
+        };
+        let decoded = Ticket::deserialize(&expired.serialize()).unwrap();
+        assert_eq!(decoded.remaining(), Some(Duration::ZERO));
+        assert!(decoded.check_expiry().is_err());
+    }
+
+    #[test]
+    fn test_key_proof() {
+        let key = TransferKey::generate();
+        let sender = SecretKey::generate(&mut rand::rng()).public();
+        let receiver = SecretKey::generate(&mut rand::rng()).public();
+
+        assert_eq!(key.proof(&sender, &receiver), key.proof(&sender, &receiver));
+        assert_ne!(key.proof(&sender, &receiver), key.proof(&receiver, &sender));
+        assert_ne!(
+            key.proof(&sender, &receiver),
+            TransferKey::generate().proof(&sender, &receiver)
+        );
+        assert_eq!(key.to_string().parse::<TransferKey>().unwrap(), key);
+    }
 }
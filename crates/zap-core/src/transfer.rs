@@ -1,306 +1,3496 @@
-use std::path::PathBuf;
+use std::collections::{HashSet, VecDeque, vec_deque};
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use iroh::Endpoint;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use iroh::{Endpoint, EndpointAddr};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::sync::mpsc;
-use tracing::{debug, info};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::{Semaphore, mpsc, oneshot, watch};
+use tracing::{Instrument, Span, debug, info, instrument, trace, warn};
 
-use crate::protocol::{ChunkData, FileOffer, Message, CHUNK_SIZE, ZAP_ALPN};
-use crate::ticket::Ticket;
+use crate::bandwidth::Bandwidth;
+use crate::debug::{self, Direction};
+use crate::diagnostics::{self, ConnectionPath};
+use crate::directory::{self, ArchivePolicy, DirectoryWriter};
+use crate::filename::{local_name, local_path};
+use crate::hashing::StreamHasher;
+use crate::manifest::FileChecker;
+use crate::probe::{Negotiated, PROBE_TIMEOUT, Probe};
+use crate::protocol::{
+    ACK_INTERVAL, CHUNK_SIZE, ChunkData, DirEntry, FileOffer, ManifestEntry, Message, OfferKind,
+    WireFormat,
+};
+use crate::receipt::Receipt;
+use crate::resume::{ResumeState, StateFile};
+use crate::ticket::{self, Ticket, TransferKey};
+use crate::tree::{HashTree, TreeVerifier};
+use crate::tuning::DEFAULT_ACK_WINDOW;
+use crate::validate::{validate_name, validate_offer};
 use crate::{Error, Result};
 
 /// Progress updates for sending
 #[derive(Debug, Clone)]
 pub enum SendProgress {
+    /// Changing files with [`SendOptions::transform`] before sending
+    Transforming {
+        files_done: usize,
+        total_files: usize,
+    },
+
+    /// Packing a directory into an archive before sending
+    Archiving {
+        files_done: usize,
+        total_files: usize,
+    },
+
     /// Waiting for receiver to connect
     Waiting,
 
-    /// Receiver connected
-    Connected,
+    /// Receiver connected
+    Connected {
+        /// The receiver's node id
+        peer: iroh::PublicKey,
+    },
+
+    /// The route to the receiver changed
+    Path(ConnectionPath),
+
+    /// The chunk size and ack window the sender settled on for the
+    /// receiver, from timing the first bursts of data; see
+    /// [`probe`](crate::probe). Not sent for data too small to probe with.
+    Negotiated(Negotiated),
+
+    /// Sending file data (total is unknown for streamed sources)
+    ///
+    /// `bytes_sent` is what the receiver has confirmed, so it keeps pace
+    /// with the receiver's own progress. A receiver from before acks were
+    /// added confirms nothing; then it's what went out on the connection.
+    Sending {
+        bytes_sent: u64,
+        total_bytes: Option<u64>,
+    },
+
+    /// How fast a receiver confirmed data since the last sample, about
+    /// once a second while sending to it
+    Throughput {
+        /// The receiver's node id, since a share sends to several at once
+        peer: iroh::PublicKey,
+        bytes_per_sec: u64,
+        /// The receiver's share of [`SendOptions::bandwidth`] in bytes a
+        /// second, if it's limited
+        share: Option<u64>,
+    },
+
+    /// A file changed while it was being sent, and the receiver was told
+    /// to stop. With [`SendOptions::retry_on_change`] the sender goes back
+    /// to [`Waiting`](SendProgress::Waiting) to offer the new version once
+    /// it stops changing; otherwise the send fails.
+    Changed { path: PathBuf },
+
+    /// The connection to the receiver broke partway. With
+    /// [`SendOptions::resume`] the sender goes back to
+    /// [`Waiting`](SendProgress::Waiting) for the receiver to carry on from
+    /// what it has.
+    Interrupted { reason: String },
+
+    /// The receiver has all of one file of a directory or batch, by its
+    /// path in the offer
+    FileSent { path: String },
+
+    /// Sending paused through the [`TransferHandle`]
+    Paused,
+
+    /// Sending resumed
+    Resumed,
+
+    /// The receiver turned the offer down, e.g. as too big. With
+    /// [`SendOptions::retry_rejected`] the sender goes back to
+    /// [`Waiting`](SendProgress::Waiting) for another try.
+    Rejected { reason: String },
+
+    /// The receiver signed for the data, just before
+    /// [`Complete`](SendProgress::Complete). Receivers from before
+    /// receipts were added don't.
+    Receipt(Receipt),
+
+    /// Transfer complete
+    Complete {
+        /// The receiver's node id
+        peer: iroh::PublicKey,
+        /// Bytes sent
+        size: u64,
+        /// BLAKE3 hash of the data sent
+        checksum: [u8; 32],
+        /// How long each phase took
+        timings: PhaseTimings,
+    },
+
+    /// Error occurred
+    Error(String),
+}
+
+/// Progress updates for receiving
+#[derive(Debug, Clone)]
+pub enum ReceiveProgress {
+    /// Connecting to sender
+    Connecting,
+
+    /// Connected to sender
+    Connected,
+
+    /// The route to the sender changed
+    Path(ConnectionPath),
+
+    /// Received file offer (size is unknown for streamed sources)
+    Offer {
+        name: String,
+        size: Option<u64>,
+        kind: OfferKind,
+        /// The sender's description of it, if it gave one
+        label: Option<String>,
+    },
+
+    /// Receiving file data
+    Receiving {
+        bytes_received: u64,
+        total_bytes: Option<u64>,
+    },
+
+    /// How fast data came in since the last sample, about once a second
+    /// while receiving
+    Throughput { bytes_per_sec: u64 },
+
+    /// Every file of a directory offer with its hash, from the sender's
+    /// manifest, before any data
+    Manifest { files: Vec<ManifestEntry> },
+
+    /// All of one file of a directory or batch came in, by its path in the
+    /// offer. With a manifest, it also matched its hash.
+    FileReceived { path: String },
+
+    /// The sender paused the transfer
+    Paused,
+
+    /// The sender resumed the transfer
+    Resumed,
+
+    /// Unpacking a received archive
+    Unpacking,
+
+    /// The offered file was already at `source`, so nothing was sent; it's
+    /// copied to the destination if it isn't there already, then `Complete`
+    /// follows
+    AlreadyHave { source: PathBuf },
+
+    /// Transfer complete, with the verified BLAKE3 hash of the data sent
+    /// (for directories, of every file's contents in offer order)
+    Complete {
+        path: PathBuf,
+        checksum: [u8; 32],
+        timings: PhaseTimings,
+    },
+
+    /// Transfer complete, for receives kept in memory with
+    /// [`ReceiveOptions::in_memory`], which end with this instead of
+    /// `Complete`
+    Loaded {
+        name: String,
+        data: Bytes,
+        checksum: [u8; 32],
+        timings: PhaseTimings,
+    },
+
+    /// Error occurred
+    Error(String),
+}
+
+/// How long each phase of a transfer took, to tell slow connection setup
+/// from slow throughput
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PhaseTimings {
+    /// Setting up the connection and agreeing on the offer
+    pub handshake: Duration,
+    /// Waiting on people: for a receiver to connect, for files to be
+    /// picked, or while paused
+    pub waiting: Duration,
+    /// Moving the data
+    pub transfer: Duration,
+    /// After the data: the receiver checking and writing it out, or the
+    /// sender waiting for it to confirm
+    pub verification: Duration,
+}
+
+/// How often a paused sender reminds the receiver it is still there
+const PAUSE_HEARTBEAT: Duration = Duration::from_secs(10);
+
+/// How often progress includes a throughput sample
+const THROUGHPUT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a sender waits for the receiver to open a new stream after
+/// the last one broke
+const REATTACH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times a transfer carries on over a new stream before a broken
+/// one fails it
+const MAX_REATTACH: usize = 5;
+
+/// How long a sender waits for the receipt once all of the data is in,
+/// enough for the receiver to unpack an archive
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many receivers a share serves at once
+const MAX_SERVING: usize = 64;
+
+/// How long a share keeps a connection open for another range after the
+/// last one
+const RANGE_IDLE: Duration = Duration::from_secs(30);
+
+/// Turns a running byte count into a rate, one sample per
+/// [`THROUGHPUT_INTERVAL`]
+#[derive(Debug)]
+pub(crate) struct Throughput {
+    since: Instant,
+    bytes: u64,
+}
+
+impl Throughput {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            since: now,
+            bytes: 0,
+        }
+    }
+
+    /// Bytes per second since the last sample, once the next one is due
+    pub(crate) fn sample(&mut self, bytes: u64, now: Instant) -> Option<u64> {
+        let elapsed = now.duration_since(self.since);
+        if elapsed < THROUGHPUT_INTERVAL {
+            return None;
+        }
+        let rate = (bytes - self.bytes) as f64 / elapsed.as_secs_f64();
+        self.since = now;
+        self.bytes = bytes;
+        Some(rate as u64)
+    }
+}
+
+/// Handle to control an ongoing transfer
+///
+/// Create one, pass it in [`SendOptions::handle`], and keep a clone to
+/// pause, resume or cancel the send while it runs.
+#[derive(Debug, Clone)]
+pub struct TransferHandle {
+    control: Arc<watch::Sender<Control>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+impl Default for TransferHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransferHandle {
+    pub fn new() -> Self {
+        Self {
+            control: Arc::new(watch::channel(Control::Running).0),
+        }
+    }
+
+    /// Stop sending data after the current chunk, keeping the connection
+    pub fn pause(&self) {
+        self.set(Control::Paused, Control::Running);
+    }
+
+    /// Continue sending after [`pause`](Self::pause)
+    pub fn resume(&self) {
+        self.set(Control::Running, Control::Paused);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.control.borrow() == Control::Paused
+    }
+
+    /// Cancel the transfer
+    pub async fn cancel(&self) {
+        self.control.send_replace(Control::Cancelled);
+    }
+
+    /// Move to `to`, but only from `from`, so a cancelled transfer stays
+    /// cancelled
+    fn set(&self, to: Control, from: Control) {
+        self.control.send_if_modified(|state| {
+            let change = *state == from;
+            if change {
+                *state = to;
+            }
+            change
+        });
+    }
+}
+
+/// What the receiver is told about data sent from a stream, such as a
+/// command's output or a socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMeta {
+    /// The name to offer the data under
+    pub name: String,
+
+    /// The length in bytes, when known up front, so the receiver can show
+    /// how far along it is. A stream that turns out shorter or longer
+    /// fails the transfer.
+    pub size: Option<u64>,
+}
+
+impl FileMeta {
+    /// Data of unknown length named `name`
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            size: None,
+        }
+    }
+
+    /// The same, `size` bytes long
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+}
+
+/// Options for sending a file or directory
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    /// Pack directories matching this policy into a single archive; when
+    /// unset, directories are always sent file by file
+    pub auto_archive: Option<ArchivePolicy>,
+
+    /// Handle to pause, resume or cancel the send
+    pub handle: Option<TransferHandle>,
+
+    /// Keep serving every receiver with the ticket, several at once, until
+    /// the node shuts down, instead of only the first
+    ///
+    /// Up to 64 receivers are served at once; the ones after that wait for
+    /// a turn.
+    ///
+    /// Progress is reported for each receiver in turn, so `Complete` is
+    /// sent once per receiver. Receivers that fail are logged, not
+    /// reported. Streams can't be served more than once.
+    pub keep_serving: bool,
+
+    /// With `keep_serving`, stop once this many receivers have everything
+    ///
+    /// Receivers still downloading then get to finish, but no more are let
+    /// in. Peeks and ranges don't count.
+    pub max_downloads: Option<u32>,
+
+    /// Stop once nobody has been connected for this long
+    ///
+    /// Without `keep_serving`, the send fails with [`Error::Timeout`] if no
+    /// receiver comes in time; a share just ends.
+    pub idle_timeout: Option<Duration>,
+
+    /// Only serve receivers that prove they hold this key
+    ///
+    /// The ticket then carries the key's hash, and the key itself is shared
+    /// separately with [`Ticket::share`], so whoever only sees the ticket,
+    /// like a relay that stores it, can't fetch the transfer.
+    pub key: Option<TransferKey>,
+
+    /// Limit how fast data goes out, sharing the limit fairly between
+    /// receivers so a fast one can't starve the others
+    pub bandwidth: Option<Bandwidth>,
+
+    /// When the receiver rejects the offer, wait for it or someone else to
+    /// try again instead of failing. A stream can't be offered twice, so
+    /// sending one still fails.
+    pub retry_rejected: bool,
+
+    /// When the connection breaks partway, wait for the receiver to come
+    /// back and carry on from what it already has, as it does with
+    /// [`ZapNode::resume`](crate::ZapNode::resume), instead of failing. A
+    /// stream can't be sent twice, so sending one still fails.
+    pub resume: bool,
+
+    /// When a file changes while it's being sent, wait until it stops
+    /// changing and offer it again, to the same receiver or someone else,
+    /// instead of failing. A share fails the receivers it was sending to
+    /// either way, and keeps offering what it started with.
+    pub retry_on_change: bool,
+
+    /// A short description shown to receivers with the offer, see
+    /// [`FileOffer::label`]
+    pub label: Option<String>,
+
+    /// Change files before they're offered, e.g. convert photos to another
+    /// format. Streams are sent as they are.
+    pub transform: Option<Transform>,
+
+    /// Have the ticket stop working after this long, so a leaked copy
+    /// can't be used later; see [`Ticket::expiring_in`]
+    pub ticket_ttl: Option<Duration>,
+
+    /// Chunks to send ahead of the receiver's acks, instead of what the
+    /// node was [tuned](crate::ZapNodeBuilder::tuning) for
+    pub ack_window: Option<usize>,
+}
+
+/// Options for receiving a transfer
+#[derive(Debug, Clone, Default)]
+pub struct ReceiveOptions {
+    /// When an offered file's name has no extension, add one detected from
+    /// its content, e.g. `scan` becomes `scan.pdf`. Needs the `detect-type`
+    /// feature.
+    pub detect_extension: bool,
+
+    /// Keep the data in memory instead of writing it to disk, rejecting
+    /// directories and files larger than this many bytes
+    pub in_memory: Option<u64>,
+
+    /// Ask someone whether to take an offer before anything else is done
+    /// with it
+    pub confirm: Option<OfferConfirm>,
+
+    /// Pick which files of a directory offer to receive
+    pub choose_entries: Option<EntryChooser>,
+
+    /// Save under another name than the offered one
+    pub rename: Option<Renamer>,
+
+    /// Turn down offers this finds a reason to
+    pub check: Option<OfferCheck>,
+
+    /// Skip sending a file that's already here with the same contents,
+    /// at its destination or in `cache_dir`. Senders from before this was
+    /// added fail the transfer when asked.
+    pub dedup: bool,
+
+    /// Another folder to look in for files with `dedup`, searched through
+    pub cache_dir: Option<PathBuf>,
+
+    /// When received data is synced to disk
+    pub durability: Durability,
+
+    /// Keep a `.zap-state` file next to a file while it's received, so a
+    /// receive that breaks off can be [resumed](crate::ZapNode::resume),
+    /// here or on another machine. Removed once the file is complete.
+    pub keep_state: bool,
+
+    /// Get a file's [hash tree](crate::tree) from the sender first and check
+    /// each piece as it arrives, stopping at the first one that's wrong
+    /// instead of at the end. For a directory, get its
+    /// [manifest](crate::manifest) and check each file instead. Senders
+    /// from before this was added fail the transfer when asked. Resumed
+    /// receives aren't checked piece by piece.
+    pub verify_pieces: bool,
+}
+
+/// When received data is synced to disk with fsync
+///
+/// Without syncing, a transfer that reports success can still be lost if
+/// the machine crashes or a removable drive is pulled before the OS writes
+/// it out, which on network filesystems and SD cards can take a while.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave it to the OS
+    #[default]
+    None,
+    /// Sync each file once all of it has arrived
+    OnDone,
+    /// Sync every this many bytes as well as once all of it has arrived
+    Every(u64),
+}
+
+impl Durability {
+    /// Whether `unsynced` bytes are enough to sync now
+    pub(crate) fn due(self, unsynced: u64) -> bool {
+        matches!(self, Durability::Every(bytes) if unsynced >= bytes)
+    }
+
+    /// Whether finished files are synced
+    pub(crate) fn on_done(self) -> bool {
+        self != Durability::None
+    }
+}
+
+impl std::str::FromStr for Durability {
+    type Err = String;
+
+    /// `none`, `done`, or a number of megabytes between syncs such as `64`
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Durability::None),
+            "done" => Ok(Durability::OnDone),
+            other => match other.trim_end_matches("mb").parse::<u64>() {
+                Ok(mb) if mb > 0 => Ok(Durability::Every(mb * 1024 * 1024)),
+                _ => Err(format!(
+                    "expected none, done or a number of megabytes, not {:?}",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Asks whether to take an offer
+///
+/// The confirmation is given the offer as sent, and returns whether to take
+/// it. Declining rejects the offer.
+#[derive(Clone)]
+pub struct OfferConfirm(Arc<ConfirmFn>);
+
+type ConfirmFn = dyn Fn(FileOffer) -> BoxFuture<'static, bool> + Send + Sync;
+
+impl OfferConfirm {
+    pub fn new<F, Fut>(confirm: F) -> Self
+    where
+        F: Fn(FileOffer) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        Self(Arc::new(move |offer| Box::pin(confirm(offer))))
+    }
+}
+
+impl fmt::Debug for OfferConfirm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OfferConfirm")
+    }
+}
+
+/// Picks which entries of a directory offer to receive
+///
+/// The chooser is given the offered entries and returns the indices of the
+/// ones to receive, or `None` to receive everything. Choosing nothing
+/// rejects the offer.
+#[derive(Clone)]
+pub struct EntryChooser(Arc<ChooseFn>);
+
+type ChooseFn = dyn Fn(Vec<DirEntry>) -> BoxFuture<'static, Option<Vec<usize>>> + Send + Sync;
+
+impl EntryChooser {
+    pub fn new<F, Fut>(choose: F) -> Self
+    where
+        F: Fn(Vec<DirEntry>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<Vec<usize>>> + Send + 'static,
+    {
+        Self(Arc::new(move |entries| Box::pin(choose(entries))))
+    }
+}
+
+impl fmt::Debug for EntryChooser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EntryChooser")
+    }
+}
+
+/// Decides the name an accepted offer is saved under
+///
+/// The renamer sees the offer after any selection, so a directory's size
+/// only counts the chosen files, and after any [`OfferCheck`] has passed
+/// it, so it can take the name it returns by creating it. The name must be
+/// a plain file name; anything else rejects the offer.
+#[derive(Clone)]
+pub struct Renamer(Arc<RenameFn>);
+
+type RenameFn = dyn Fn(&FileOffer) -> String + Send + Sync;
+
+impl Renamer {
+    pub fn new<F>(rename: F) -> Self
+    where
+        F: Fn(&FileOffer) -> String + Send + Sync + 'static,
+    {
+        Self(Arc::new(rename))
+    }
+}
+
+impl fmt::Debug for Renamer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Renamer")
+    }
+}
+
+/// Decides whether to take an offer at all
+///
+/// The check sees the offer as it would be saved, after any selection and
+/// renaming. The reason it returns is sent to the sender.
+#[derive(Clone)]
+pub struct OfferCheck(Arc<CheckFn>);
+
+type CheckFn = dyn Fn(&FileOffer) -> std::result::Result<(), String> + Send + Sync;
+
+impl OfferCheck {
+    pub fn new<F>(check: F) -> Self
+    where
+        F: Fn(&FileOffer) -> std::result::Result<(), String> + Send + Sync + 'static,
+    {
+        Self(Arc::new(check))
+    }
+}
+
+impl fmt::Debug for OfferCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OfferCheck")
+    }
+}
+
+/// Changes files before they're offered, such as converting HEIC photos to
+/// JPEG
+///
+/// Each file's name is given to `rename`, which returns the name to send it
+/// under once changed, or `None` to send it as it is. For a new name,
+/// `apply` is given the file and a path to write the changed one to, which
+/// ends in the new name's extension; it runs off the async runtime, so it
+/// may block. Files in a directory are
+/// renamed in place, keeping their folder.
+#[derive(Clone)]
+pub struct Transform {
+    rename: Arc<TransformRenameFn>,
+    apply: Arc<TransformApplyFn>,
+}
+
+type TransformRenameFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+type TransformApplyFn = dyn Fn(&Path, &Path) -> std::io::Result<()> + Send + Sync;
+
+impl Transform {
+    pub fn new<R, A>(rename: R, apply: A) -> Self
+    where
+        R: Fn(&str) -> Option<String> + Send + Sync + 'static,
+        A: Fn(&Path, &Path) -> std::io::Result<()> + Send + Sync + 'static,
+    {
+        Self {
+            rename: Arc::new(rename),
+            apply: Arc::new(apply),
+        }
+    }
+}
+
+impl fmt::Debug for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Transform")
+    }
+}
+
+/// Where the sender reads file data from
+pub enum SendSource {
+    /// A file on disk, with a known size
+    File(PathBuf),
+
+    /// A directory on disk, archived first if it matches the policy
+    Directory {
+        path: PathBuf,
+        auto_archive: Option<ArchivePolicy>,
+    },
+
+    /// Some of the files and directories inside `root`, sent as a directory
+    /// named after it
+    Selection {
+        root: PathBuf,
+        names: Vec<String>,
+        auto_archive: Option<ArchivePolicy>,
+    },
+
+    /// Files and directories from anywhere, which the receiver saves side
+    /// by side; their names have to differ
+    Batch(Vec<PathBuf>),
+
+    /// A stream, such as stdin, of the length in `meta` if known
+    Reader {
+        meta: FileMeta,
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+    },
+}
+
+impl SendSource {
+    /// The same source, to prepare again, unless it's a stream
+    fn reuse(&self) -> Option<SendSource> {
+        Some(match self {
+            SendSource::File(path) => SendSource::File(path.clone()),
+            SendSource::Directory { path, auto_archive } => SendSource::Directory {
+                path: path.clone(),
+                auto_archive: *auto_archive,
+            },
+            SendSource::Selection {
+                root,
+                names,
+                auto_archive,
+            } => SendSource::Selection {
+                root: root.clone(),
+                names: names.clone(),
+                auto_archive: *auto_archive,
+            },
+            SendSource::Batch(paths) => SendSource::Batch(paths.clone()),
+            SendSource::Reader { .. } => return None,
+        })
+    }
+}
+
+/// Run the sender side of a transfer
+#[instrument(
+    name = "zap.send",
+    skip_all,
+    err(Display),
+    fields(zap.name, zap.size, zap.kind, zap.bytes)
+)]
+pub async fn run_sender(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    source: SendSource,
+    handle: TransferHandle,
+    options: SendOptions,
+    progress: mpsc::Sender<SendProgress>,
+) -> Result<()> {
+    // Receivers that ignore the expiry in the ticket are turned away here
+    // too, so a leaked copy stops working either way
+    let expires = options.ticket_ttl.map(|ttl| Instant::now() + ttl);
+    let expiry = move || async move {
+        match expires {
+            Some(at) => tokio::time::sleep_until(at.into()).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    // Kept to offer again what changed while it was sent
+    let again = source.reuse().filter(|_| options.retry_on_change);
+    let ack_window = options.ack_window.unwrap_or(DEFAULT_ACK_WINDOW);
+    let mut payload = prepare(source, options.transform.as_ref(), &progress).await?;
+    payload.label = options.label.clone();
+    record_offer(&payload.name, payload.size, &payload.kind);
+
+    let mut waiting_since = Instant::now();
+    let _ = progress.send(SendProgress::Waiting).await;
+
+    if !options.keep_serving {
+        // A rejected offer is made again to the next receiver to connect
+        loop {
+            // Anyone without the key is turned away, and the wait goes on
+            let wait = async {
+                loop {
+                    let (conn, connecting) = accept(&endpoint, alpn).await?;
+                    match open_streams(&endpoint, &conn, options.key.as_ref(), expires).await {
+                        Ok(streams) => return Ok((conn, streams, connecting)),
+                        Err(Error::WrongKey) => {
+                            warn!(peer = %conn.remote_id(), "turned away a receiver without the key");
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            };
+            let wait = async {
+                match options.idle_timeout {
+                    Some(idle) => tokio::time::timeout(idle, wait)
+                        .await
+                        .map_err(|_| Error::Timeout)?,
+                    None => wait.await,
+                }
+            };
+            // A cancel ends the wait, so the endpoint is free for the next
+            // send when it outlives this one
+            let mut control = handle.control.subscribe();
+            let (conn, streams, connecting) = tokio::select! {
+                accepted = wait => accepted?,
+                _ = control.wait_for(|state| *state == Control::Cancelled) => {
+                    return Err(Error::Cancelled);
+                }
+                () = expiry() => return Err(ticket::expired()),
+            };
+            let timings = PhaseTimings {
+                handshake: connecting,
+                waiting: waiting_since.elapsed().saturating_sub(connecting),
+                ..Default::default()
+            };
+            let control = handle.control.subscribe();
+            let bandwidth = options.bandwidth.clone();
+            // Resuming after an interruption and retrying after a rejection
+            // are separate options, so each arm below checks its own
+            let reused = payload
+                .reuse()
+                .filter(|_| options.retry_rejected || options.resume);
+            let serving = Serving {
+                control,
+                ranges: false,
+                bandwidth,
+                ack_window,
+            };
+            let served = serve(conn, streams, payload, serving, timings, &progress).await;
+            count!(failure(Send, &served));
+            let served = match served {
+                Err(Error::FileChanged(path)) if again.is_some() => {
+                    let next = again.as_ref().and_then(SendSource::reuse);
+                    info!(path = %path.display(), "a file changed while it was sent");
+                    let _ = progress
+                        .send(SendProgress::Changed { path: path.clone() })
+                        .await;
+                    let mut control = handle.control.subscribe();
+                    tokio::select! {
+                        () = settle(&path) => {}
+                        _ = control.wait_for(|state| *state == Control::Cancelled) => {
+                            return Err(Error::Cancelled);
+                        }
+                    }
+                    let Some(source) = next else {
+                        return Err(Error::FileChanged(path));
+                    };
+                    payload = prepare(source, options.transform.as_ref(), &progress).await?;
+                    payload.label = options.label.clone();
+                    record_offer(&payload.name, payload.size, &payload.kind);
+                    waiting_since = Instant::now();
+                    let _ = progress.send(SendProgress::Waiting).await;
+                    continue;
+                }
+                Err(e) if options.resume && e.is_interruption() => {
+                    let Some(next) = reused else {
+                        return Err(e);
+                    };
+                    info!("lost the receiver partway, waiting for it to resume: {}", e);
+                    let reason = e.to_string();
+                    let _ = progress.send(SendProgress::Interrupted { reason }).await;
+                    payload = next;
+                    waiting_since = Instant::now();
+                    let _ = progress.send(SendProgress::Waiting).await;
+                    continue;
+                }
+                served => served?,
+            };
+            match (served, reused.filter(|_| options.retry_rejected)) {
+                (Served::Rejected(_), Some(next)) => {
+                    payload = next;
+                    waiting_since = Instant::now();
+                    let _ = progress.send(SendProgress::Waiting).await;
+                }
+                (Served::Rejected(reason), None) => {
+                    return Err(Error::TransferFailed(format!(
+                        "receiver rejected: {}",
+                        reason
+                    )));
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Every receiver gets its own copy of the payload, until the node shuts
+    // down, enough receivers have everything, or nobody has been connected
+    // for the idle timeout; a failed receiver doesn't end the share.
+    // Connections past the first MAX_SERVING wait to be accepted until
+    // someone is done
+    let (served_tx, mut served) = mpsc::unbounded_channel();
+    let slots = Arc::new(Semaphore::new(MAX_SERVING));
+    let mut active = 0usize;
+    let mut downloads = 0u32;
+    let mut idle_since = Instant::now();
+    loop {
+        let enough = options.max_downloads.is_some_and(|max| downloads >= max);
+        if enough && active == 0 {
+            info!(downloads, "served every download allowed");
+            return Ok(());
+        }
+        // Receivers still being served finish, but nobody new is let in
+        let expired = expires.is_some_and(|at| Instant::now() >= at);
+        if expired && active == 0 {
+            info!(downloads, "the ticket expired");
+            return Ok(());
+        }
+        let idle_until = match options.idle_timeout {
+            Some(idle) if active == 0 => Some(idle_since + idle),
+            _ => None,
+        };
+        let idle = async move {
+            match idle_until {
+                Some(until) => tokio::time::sleep_until(until.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            (incoming, slot) = async {
+                let slot = slots.clone().acquire_owned().await.expect("never closed");
+                (endpoint.accept().await, slot)
+            }, if !enough && !expired => {
+                let Some(incoming) = incoming else {
+                    return Err(Error::ConnectionFailed("endpoint closed".into()));
+                };
+                let Some(payload) = payload.reuse() else {
+                    return Err(Error::Protocol("a stream can only be sent once".into()));
+                };
+                let alpn = alpn.to_vec();
+                let control = handle.control.subscribe();
+                let progress = progress.clone();
+                let endpoint = endpoint.clone();
+                let key = options.key;
+                let bandwidth = options.bandwidth.clone();
+                let served_tx = served_tx.clone();
+
+                let task = async move {
+                    let connecting = Instant::now();
+                    let conn = incoming.accept()?.await?;
+                    if conn.alpn() != alpn {
+                        debug!("ignoring connection with wrong ALPN");
+                        return Ok(Served::Nothing);
+                    }
+                    let serving = || Serving {
+                        control: control.clone(),
+                        ranges: true,
+                        bandwidth: bandwidth.clone(),
+                        ack_window,
+                    };
+                    let streams = open_streams(&endpoint, &conn, key.as_ref(), expires).await?;
+                    // Nobody waits for a particular receiver of a share
+                    let timings = PhaseTimings {
+                        handshake: connecting.elapsed(),
+                        ..Default::default()
+                    };
+                    let mut served = serve(
+                        conn.clone(),
+                        streams,
+                        payload.reuse().expect("only reusable payloads are shared"),
+                        serving(),
+                        timings,
+                        &progress,
+                    )
+                    .await?;
+                    // A mount reads one range after another, each on a
+                    // stream of its own over the same connection
+                    while served == Served::Nothing {
+                        let next = open_streams(&endpoint, &conn, key.as_ref(), expires);
+                        let Ok(Ok(streams)) = tokio::time::timeout(RANGE_IDLE, next).await else {
+                            break;
+                        };
+                        let payload = payload.reuse().expect("only reusable payloads are shared");
+                        let timings = PhaseTimings::default();
+                        served = serve(conn.clone(), streams, payload, serving(), timings, &progress)
+                            .await?;
+                    }
+                    Ok::<_, Error>(served)
+                };
+                tokio::spawn(
+                    async move {
+                        let result = task.await;
+                        drop(slot);
+                        count!(failure(Send, &result));
+                        if let Err(e) = &result {
+                            warn!("serving a receiver failed: {}", e);
+                        }
+                        let _ = served_tx.send(matches!(result, Ok(Served::Everything)));
+                    }
+                    .in_current_span(),
+                );
+                active += 1;
+            }
+            Some(everything) = served.recv() => {
+                active -= 1;
+                if everything {
+                    downloads += 1;
+                }
+                if active == 0 {
+                    idle_since = Instant::now();
+                }
+            }
+            () = idle => {
+                info!(downloads, "nobody connected for the idle timeout");
+                return Ok(());
+            }
+            () = expiry(), if !expired => {}
+        }
+    }
+}
+
+/// What a receiver got from [`serve`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Served {
+    /// The whole offer, or the files it picked
+    Everything,
+    /// A range or the checksums, or nothing at all
+    Nothing,
+    /// Nothing, since it turned the offer down for this reason
+    Rejected(String),
+}
+
+/// Wait for a receiver speaking `alpn`, returning how long its connection
+/// took to set up
+async fn accept(endpoint: &Endpoint, alpn: &[u8]) -> Result<(Connection, Duration)> {
+    loop {
+        let Some(incoming) = endpoint.accept().await else {
+            return Err(Error::ConnectionFailed("endpoint closed".into()));
+        };
+
+        let connecting = Instant::now();
+        let conn = incoming.accept()?.await?;
+
+        // Check ALPN
+        if conn.alpn() == alpn {
+            return Ok((conn, connecting.elapsed()));
+        }
+
+        debug!("ignoring connection with wrong ALPN");
+    }
+}
+
+/// Accept a receiver's stream and wait for its `Ready`, which has to prove
+/// it holds `key` if there is one and come before the ticket `expires`
+async fn open_streams(
+    endpoint: &Endpoint,
+    conn: &Connection,
+    key: Option<&TransferKey>,
+    expires: Option<Instant>,
+) -> Result<(SendStream, RecvStream)> {
+    // The receiver sends Ready first to trigger stream creation (QUIC streams are lazy)
+    let (mut send_stream, mut recv_stream) = conn.accept_bi().await?;
+    debug!("accepted bidirectional stream");
+
+    let proven = match (recv_message(&mut recv_stream).await?, key) {
+        (Message::Ready | Message::ReadyWithKey { .. }, None) => true,
+        (Message::ReadyWithKey { proof }, Some(key)) => {
+            // Hashes compare in constant time
+            let expected = key.proof(&endpoint.id(), &conn.remote_id());
+            blake3::Hash::from(proof) == blake3::Hash::from(expected)
+        }
+        (Message::Ready, Some(_)) => false,
+        _ => return Err(Error::Protocol("expected Ready message".into())),
+    };
+    if !proven {
+        let reason = "this transfer needs its key, which comes after the code".into();
+        let _ = reject(&mut send_stream, reason).await;
+        return Err(Error::WrongKey);
+    }
+    if expires.is_some_and(|at| Instant::now() >= at) {
+        let _ = reject(&mut send_stream, ticket::EXPIRED.into()).await;
+        return Err(ticket::expired());
+    }
+    debug!("received Ready from receiver");
+
+    Ok((send_stream, recv_stream))
+}
+
+/// How a send serves each of its receivers
+struct Serving {
+    control: watch::Receiver<Control>,
+    /// Whether the receiver may also ask for a byte range of one file
+    ranges: bool,
+    bandwidth: Option<Bandwidth>,
+    /// Chunks sent ahead of the receiver's acks
+    ack_window: usize,
+}
+
+/// Offer the payload to a connected receiver and send what it accepts
+///
+/// Ranges aren't reported as sending or complete, since a mounted share
+/// reads in many small ones.
+async fn serve(
+    conn: Connection,
+    (mut send_stream, mut recv_stream): (SendStream, RecvStream),
+    mut payload: Payload,
+    serving: Serving,
+    mut timings: PhaseTimings,
+    progress: &mpsc::Sender<SendProgress>,
+) -> Result<Served> {
+    let Serving {
+        mut control,
+        ranges,
+        bandwidth,
+        ack_window,
+    } = serving;
+    let handshake_start = Instant::now();
+    let _ = progress
+        .send(SendProgress::Connected {
+            peer: conn.remote_id(),
+        })
+        .await;
+    info!("receiver connected");
+    diagnostics::watch_paths(&conn, progress.clone(), SendProgress::Path);
+
+    // Send offer (the checksum is only known once all data has been read,
+    // so it is sent in Done instead)
+    let offer = FileOffer {
+        name: payload.name.clone(),
+        size: payload.size,
+        checksum: None,
+        kind: payload.kind.clone(),
+        label: payload.label.clone(),
+        batch: payload.batch,
+    }
+    .into_message();
+    send_message(&mut send_stream, &offer).await?;
+    debug!("sent offer");
+
+    // Wait for accept/reject, after sending the hash tree if it's asked
+    // for first
+    let mut response = recv_message(&mut recv_stream).await?;
+    if let Message::AcceptTree = response {
+        let tree = match (&payload.kind, payload.files()) {
+            (OfferKind::File, Ok(files)) => HashTree::of_file(&files[0]).await?,
+            _ => {
+                let reason = "only a file has a hash tree".into();
+                return Err(reject(&mut send_stream, reason).await);
+            }
+        };
+        debug!(pieces = tree.pieces().len(), "sending the hash tree");
+        let pieces = tree.pieces().to_vec();
+        send_message(&mut send_stream, &Message::Tree { pieces }).await?;
+        response = recv_message(&mut recv_stream).await?;
+    }
+    if let Message::AcceptManifest = response {
+        let files: Vec<_> = match (&payload.kind, payload.files()) {
+            (OfferKind::Directory { entries }, Ok(paths)) => entries
+                .iter()
+                .zip(checksums(&paths).await?)
+                .map(|(entry, checksum)| ManifestEntry {
+                    path: entry.path.clone(),
+                    size: entry.size,
+                    checksum,
+                })
+                .collect(),
+            _ => {
+                let reason = "only a directory has a manifest".into();
+                return Err(reject(&mut send_stream, reason).await);
+            }
+        };
+        debug!(files = files.len(), "sending the manifest");
+        send_message(&mut send_stream, &Message::Manifest { files }).await?;
+        response = recv_message(&mut recv_stream).await?;
+    }
+    if let Message::AskChecksum = response {
+        let entries = match (&payload.kind, payload.files()) {
+            (OfferKind::File, Ok(files)) => checksums(&files).await?,
+            _ => {
+                let reason = "only a file can be compared".into();
+                return Err(reject(&mut send_stream, reason).await);
+            }
+        };
+        debug!("sending the checksum to compare with");
+        send_message(&mut send_stream, &Message::Checksums { entries }).await?;
+        response = recv_message(&mut recv_stream).await?;
+    }
+    let mut skip = None;
+    // Where a resumed transfer picks up, with the hash of the data before
+    // it, to carry on to the whole file's
+    let mut resumed = None;
+    let range = match response {
+        Message::Accept => {
+            info!("handshake complete");
+            false
+        }
+        Message::AcceptSome { entries } => {
+            payload.select(&entries)?;
+            info!(
+                files = entries.len(),
+                "handshake complete, sending a selection"
+            );
+            false
+        }
+        Message::AcceptFrom { offset, checksum } => {
+            let prefix = match (&payload.kind, payload.files(), payload.size) {
+                (OfferKind::File, Ok(files), Some(size)) if offset <= size => {
+                    hash_prefix(&files[0], offset).await?
+                }
+                _ => None,
+            };
+            let Some(prefix) = prefix.filter(|prefix| prefix.finalize() == checksum) else {
+                let reason = "the data already received doesn't match the file".into();
+                return Err(reject(&mut send_stream, reason).await);
+            };
+            let size = payload.size.unwrap_or(0);
+            payload.range(0, offset, size - offset)?;
+            info!(
+                offset,
+                "handshake complete, carrying on where the receiver got to"
+            );
+            resumed = Some((offset, prefix));
+            false
+        }
+        Message::AcceptRange { entry, offset, len } if ranges => {
+            payload.range(entry, offset, len)?;
+            debug!(entry, offset, len, "sending a range");
+            true
+        }
+        Message::AlreadyHave { checksum } => {
+            // Only a file that's still there as offered can be skipped
+            let have = match (&payload.kind, payload.files()) {
+                (OfferKind::File, Ok(files)) => checksums(&files).await?.first() == Some(&checksum),
+                _ => false,
+            };
+            if have {
+                info!("handshake complete, receiver already has the file");
+                skip = Some(checksum);
+            } else {
+                info!("handshake complete, receiver has a different file");
+            }
+            false
+        }
+        Message::AcceptChecksums if ranges => {
+            let entries = checksums(&payload.files()?).await?;
+            debug!(files = entries.len(), "sending checksums");
+            send_message(&mut send_stream, &Message::Checksums { entries }).await?;
+            send_stream.finish()?;
+            let _ = send_stream.stopped().await;
+            return Ok(Served::Nothing);
+        }
+        Message::Reject { reason } => {
+            info!(%reason, "receiver rejected the offer");
+            let _ = progress
+                .send(SendProgress::Rejected {
+                    reason: reason.clone(),
+                })
+                .await;
+            return Ok(Served::Rejected(reason));
+        }
+        _ => {
+            return Err(Error::Protocol("unexpected message".into()));
+        }
+    };
+
+    timings.handshake += handshake_start.elapsed();
+    if !range {
+        count!(started(Send));
+    }
+    let transfer_start = Instant::now();
+    let mut paused = Duration::ZERO;
+    let mut throughput = Throughput::new(transfer_start);
+    let mut pace = bandwidth.map(|bandwidth| bandwidth.join());
+
+    // Chunks are read ahead in their own task, so the disk and the network
+    // are busy at the same time
+    let (chunk_tx, mut chunks) = mpsc::channel(READ_AHEAD);
+    let mut offset = 0u64;
+    match skip {
+        // Nothing to read when the receiver already has it
+        Some(checksum) => {
+            offset = payload.size.unwrap_or(0);
+            let _ = chunk_tx.send(Ok(ReadAhead::Done(checksum))).await;
+        }
+        None => {
+            tokio::spawn(read_parts(payload.parts, chunk_tx).in_current_span());
+        }
+    }
+
+    // From here on the receiver only acks, and if the stream breaks, opens
+    // a new one to carry on
+    let mut out = Outgoing::new(conn.clone(), send_stream, recv_stream, ack_window);
+    let mut files = FileEnds::new(&payload.kind);
+    let mut reported = 0;
+    // Ranges are too small to tell anything from. The probe waits on acks,
+    // so it only starts once the receiver has acked something: one that
+    // doesn't ack gets the usual chunk size and window without a wait
+    let mut probe = (!range && skip.is_none()).then(|| Probe::new(ack_window));
+    let mut chunk_size = CHUNK_SIZE;
+    // Progress counts what a resumed receiver had already
+    let before = resumed.as_ref().map_or(0, |(offset, _)| *offset);
+    let total = payload.size.map(|size| before + size);
+    let checksum = loop {
+        let waited = wait_while_paused(&mut control, &mut out, progress).await?;
+        if !waited.is_zero() {
+            // A burst that spans a pause says nothing about the link
+            probe = None;
+        }
+        paused += waited;
+
+        let data = match chunks.recv().await {
+            Some(Ok(ReadAhead::Chunk(data))) => data,
+            Some(Ok(ReadAhead::Done(checksum))) => break checksum,
+            Some(Err(Error::FileChanged(path))) => {
+                // Without this the receiver only sees the stream end early;
+                // the sender's paths are its own business
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let message = format!("the sender's {} changed while it was being sent", name);
+                let _ = out.message(&Message::Error { message }).await;
+                return Err(Error::FileChanged(path));
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::TransferFailed("reading the files stopped".into())),
+        };
+        // Cut to the chunk size in use, which the probe changes partway
+        let mut read = data;
+        let mut taken = 0;
+        while let Some(data) = cut(
+            &mut read,
+            &mut taken,
+            probe
+                .as_ref()
+                .filter(|_| out.window.acking)
+                .map_or(chunk_size, Probe::chunk_size),
+        ) {
+            let probing = out.window.acking;
+            let len = data.len() as u64;
+            if let Some(pace) = &mut pace {
+                pace.take(len).await;
+            }
+            if let Some((_, whole)) = &mut resumed {
+                whole.update(&data);
+            }
+            out.chunk(ChunkData { offset, data }).await?;
+            count!(bytes(Send, len));
+            if offset == 0 {
+                info!("first byte sent");
+            }
+
+            let burst = probe
+                .as_mut()
+                .filter(|_| probing)
+                .and_then(|probe| probe.sent(offset, len as usize));
+            if let Some(end) = burst {
+                if !out.acked(end, PROBE_TIMEOUT).await {
+                    debug!("no ack for a probe burst, keeping the usual chunk size");
+                    probe = None;
+                } else if let Some(negotiated) = probe
+                    .as_mut()
+                    .and_then(|probe| probe.acked(end, diagnostics::rtt(&conn)))
+                {
+                    info!(
+                        chunk_size = negotiated.chunk_size,
+                        ack_window = negotiated.ack_window,
+                        rtt_ms = negotiated.rtt.as_millis() as u64,
+                        "settled on a chunk size and window"
+                    );
+                    probe = None;
+                    chunk_size = negotiated.chunk_size;
+                    out.window.limit = negotiated.ack_window;
+                    let _ = progress.send(SendProgress::Negotiated(negotiated)).await;
+                }
+            }
+
+            offset += len;
+            let delivered = out.window.delivered();
+            if range || delivered == reported {
+                continue;
+            }
+            reported = delivered;
+            let _ = progress
+                .send(SendProgress::Sending {
+                    bytes_sent: before + delivered,
+                    total_bytes: total,
+                })
+                .await;
+            for path in files.reached(delivered) {
+                let _ = progress.send(SendProgress::FileSent { path }).await;
+            }
+            if let Some(bytes_per_sec) = throughput.sample(delivered, Instant::now()) {
+                let _ = progress
+                    .send(SendProgress::Throughput {
+                        peer: conn.remote_id(),
+                        bytes_per_sec,
+                        share: pace.as_ref().and_then(|pace| pace.share()),
+                    })
+                    .await;
+            }
+        }
+    };
+
+    // Send done with the final size and checksum
+    let done = Message::Done {
+        checksum,
+        size: offset,
+    };
+    out.message(&done).await?;
+    debug!("sent done message");
+
+    // Finish the stream and wait for it to be fully sent
+    out.send.finish()?;
+    timings.waiting += paused;
+    timings.transfer = transfer_start.elapsed().saturating_sub(paused);
+    let verification_start = Instant::now();
+
+    // Wait for the stream to be fully acknowledged, which ensures the
+    // receiver has time to read the Done message. Acks still coming in
+    // until then carry the progress on.
+    let stopped = loop {
+        tokio::select! {
+            stopped = out.send.stopped() => break stopped,
+            Some(acked) = out.acks.recv(), if !range => {
+                out.window.ack(acked);
+                let delivered = out.window.delivered();
+                let _ = progress
+                    .send(SendProgress::Sending {
+                        bytes_sent: before + delivered,
+                        total_bytes: total,
+                    })
+                    .await;
+                for path in files.reached(delivered) {
+                    let _ = progress.send(SendProgress::FileSent { path }).await;
+                }
+            }
+        }
+    };
+    match stopped {
+        Ok(_) => debug!("stream finished cleanly"),
+        Err(e) => debug!("stream stopped: {:?}", e),
+    }
+    timings.verification = verification_start.elapsed();
+
+    if range {
+        debug!(bytes = offset, "range sent");
+        return Ok(Served::Nothing);
+    }
+
+    // A resumed receiver ends up with the whole file, not just the rest
+    let (size, checksum) = match resumed {
+        Some((before, whole)) => (before + offset, *whole.finalize().as_bytes()),
+        None => (offset, checksum),
+    };
+
+    // The receiver signs for it once it's checked and saved
+    match tokio::time::timeout(RECEIPT_TIMEOUT, out.receipt).await {
+        Ok(Ok(receipt)) => {
+            let ours = receipt.receiver == conn.remote_id()
+                && receipt.checksum == checksum
+                && receipt.size == size;
+            match receipt.verify() {
+                Ok(()) if ours => {
+                    debug!("got a receipt");
+                    let _ = progress.send(SendProgress::Receipt(receipt)).await;
+                }
+                Ok(()) => warn!("ignoring a receipt for other data"),
+                Err(e) => warn!("ignoring a receipt: {}", e),
+            }
+        }
+        Ok(Err(_)) => debug!("the receiver sent no receipt"),
+        Err(_) => debug!("gave up waiting for a receipt"),
+    }
+
+    count!(completed(Send));
+    let _ = progress
+        .send(SendProgress::Complete {
+            peer: conn.remote_id(),
+            size,
+            checksum,
+            timings,
+        })
+        .await;
+    Span::current().record("zap.bytes", offset);
+    info!(?timings, "transfer complete");
+
+    Ok(Served::Everything)
+}
+
+/// Run the receiver side of a transfer
+#[instrument(
+    name = "zap.receive",
+    skip_all,
+    err(Display),
+    fields(zap.peer = %ticket.addr.id, zap.name, zap.size, zap.kind, zap.bytes)
+)]
+pub async fn run_receiver(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    ticket: Ticket,
+    output_dir: Option<PathBuf>,
+    options: ReceiveOptions,
+    progress: mpsc::Sender<ReceiveProgress>,
+) -> Result<()> {
+    let result = connect_and_receive(endpoint, alpn, ticket, output_dir, options, progress).await;
+    count!(failure(Receive, &result));
+    result
+}
+
+/// Connect to the ticket's sender and receive from it
+async fn connect_and_receive(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    ticket: Ticket,
+    output_dir: Option<PathBuf>,
+    options: ReceiveOptions,
+    progress: mpsc::Sender<ReceiveProgress>,
+) -> Result<()> {
+    let ready = ready(&endpoint, &ticket)?;
+    let _ = progress.send(ReceiveProgress::Connecting).await;
+    let started = Instant::now();
+
+    debug!(addr = ?ticket.addr, "connecting to sender");
+
+    // Connect to sender
+    let conn = connect(&endpoint, ticket.addr.clone(), alpn).await?;
+
+    let _ = progress.send(ReceiveProgress::Connected).await;
+    info!("connected to sender");
+    diagnostics::watch_paths(&conn, progress.clone(), ReceiveProgress::Path);
+
+    // Open bidirectional stream
+    let (send_stream, recv_stream) = conn.open_bi().await?;
+    debug!("opened bidirectional stream");
+
+    let mut peer = Streams {
+        conn,
+        send: send_stream,
+        recv: recv_stream,
+        key: endpoint.secret_key().clone(),
+    };
+    receive(
+        &mut peer,
+        ready,
+        Some(&ticket),
+        output_dir,
+        options,
+        &progress,
+        started,
+    )
+    .await
+}
+
+/// The `Ready` a receiver opens with, proving it holds the transfer key
+/// when the ticket requires one; fails if the ticket has expired
+pub(crate) fn ready(endpoint: &Endpoint, ticket: &Ticket) -> Result<Message> {
+    ticket.check_expiry()?;
+    match (ticket.key_hash, &ticket.key) {
+        (None, _) => Ok(Message::Ready),
+        (Some(_), Some(key)) => Ok(Message::ReadyWithKey {
+            proof: key.proof(&ticket.addr.id, &endpoint.id()),
+        }),
+        (Some(_), None) => Err(Error::InvalidTicket(
+            "the sender requires the transfer key, which comes after the code".into(),
+        )),
+    }
+}
+
+/// The receiving side of a transfer once connected, talking to `peer`,
+/// starting with `ready`; the state file names `ticket`, and the handshake
+/// is timed from `started`
+pub(crate) async fn receive(
+    peer: &mut impl Peer,
+    ready: Message,
+    ticket: Option<&Ticket>,
+    output_dir: Option<PathBuf>,
+    options: ReceiveOptions,
+    progress: &mpsc::Sender<ReceiveProgress>,
+    started: Instant,
+) -> Result<()> {
+    let mut timings = PhaseTimings::default();
+    // Send Ready message to trigger stream creation on sender side
+    // (QUIC streams are lazy - only created when data is sent)
+    peer.send(&ready).await?;
+    debug!("sent Ready message");
+
+    // Receive offer
+    let mut offer = expect_offer(peer.recv().await?)?;
+
+    // Nothing from the offer is shown or used until it's known to be sane
+    if let Err(violation) = validate_offer(&offer) {
+        let _ = peer.reject(violation.to_string()).await;
+        return Err(violation.into());
+    }
+
+    let _ = progress
+        .send(ReceiveProgress::Offer {
+            name: offer.name.clone(),
+            size: offer.size,
+            kind: offer.kind.clone(),
+            label: offer.label.clone(),
+        })
+        .await;
+
+    info!(name = %offer.name, size = offer.size, "received offer");
+    record_offer(&offer.name, offer.size, &offer.kind);
+    // A resume asks for the file by the name it was offered under
+    let offered_name = offer.name.clone();
+
+    if let Some(limit) = options.in_memory
+        && let Some(reason) = memory_rejection(&offer, limit)
+    {
+        return Err(peer.reject(reason).await);
+    }
+
+    if cfg!(not(feature = "archive")) && offer.kind == OfferKind::Archive {
+        let reason = "this receiver was built without archive support".to_string();
+        return Err(peer.reject(reason).await);
+    }
+
+    // Confirming and choosing count as waiting, since they usually ask
+    // someone
+    let choosing = Instant::now();
+    if let Some(confirm) = &options.confirm
+        && !(confirm.0)(offer.clone()).await
+    {
+        return Err(peer.reject("the receiver declined".into()).await);
+    }
+
+    // Let the chooser narrow a directory offer down to the files it wants
+    let mut accept = Message::Accept;
+    if let Some(chooser) = &options.choose_entries
+        && let OfferKind::Directory { entries } = &offer.kind
+        && let Some(mut chosen) = (chooser.0)(entries.clone()).await
+    {
+        chosen.sort_unstable();
+        chosen.dedup();
+        chosen.retain(|&i| i < entries.len());
+        if chosen.is_empty() {
+            return Err(peer.reject("no files selected".into()).await);
+        }
+
+        let selected: Vec<DirEntry> = chosen.iter().map(|&i| entries[i].clone()).collect();
+        info!(files = selected.len(), "receiving a selection");
+        accept = Message::AcceptSome {
+            entries: chosen.iter().map(|&i| i as u32).collect(),
+        };
+        offer.size = Some(selected.iter().map(|e| e.size).sum());
+        offer.kind = OfferKind::Directory { entries: selected };
+    }
+    timings.waiting = choosing.elapsed();
+
+    // A name that's fine where it came from may not be here
+    let name = local_name(&offer.name).into_owned();
+    if name != offer.name {
+        info!(%name, "renamed for this platform");
+        offer.name = name;
+    }
+
+    if let Some(check) = &options.check
+        && let Err(reason) = (check.0)(&offer)
+    {
+        return Err(peer.reject(reason).await);
+    }
+
+    // Last, since a renamer may take the name it picks by creating it
+    if let Some(renamer) = &options.rename {
+        let name = (renamer.0)(&offer);
+        if let Err(violation) = validate_name(&name) {
+            let reason = format!("can't save as {:?}: {}", name, violation);
+            return Err(peer.reject(reason).await);
+        }
+        info!(%name, "saving under a new name");
+        offer.name = local_name(&name).into_owned();
+    }
+
+    let output_dir = output_dir.unwrap_or_else(|| std::env::current_dir().unwrap_or_default());
+    let mut output_path = output_dir.join(&offer.name);
+    // A batch's files go where the receiver saves, not in a folder
+    if offer.batch {
+        if !matches!(offer.kind, OfferKind::Directory { .. }) {
+            return Err(peer.reject("a batch has to list its files".into()).await);
+        }
+        output_path = output_dir.clone();
+    }
+
+    // The hash tree comes before anything is accepted
+    let mut verifier = None;
+    if let (true, OfferKind::File, Some(size)) = (options.verify_pieces, &offer.kind, offer.size) {
+        peer.send(&Message::AcceptTree).await?;
+        let pieces = match peer.recv().await? {
+            Message::Tree { pieces } => pieces,
+            Message::Reject { reason } => return Err(Error::TransferFailed(reason)),
+            _ => return Err(Error::Protocol("expected the hash tree".into())),
+        };
+        let tree = HashTree::new(size, pieces)?;
+        debug!(root = %blake3::Hash::from(tree.root()), "got the hash tree");
+        verifier = Some(TreeVerifier::new(tree));
+    }
+
+    // So does a directory's manifest
+    let mut checker = None;
+    if let (true, OfferKind::Directory { entries }) = (options.verify_pieces, &offer.kind) {
+        peer.send(&Message::AcceptManifest).await?;
+        let files = match peer.recv().await? {
+            Message::Manifest { files } => files,
+            Message::Reject { reason } => return Err(Error::TransferFailed(reason)),
+            _ => return Err(Error::Protocol("expected the manifest".into())),
+        };
+        // It lists all of the offer, of which only the chosen files come
+        let files = match &accept {
+            Message::AcceptSome { entries } => entries
+                .iter()
+                .filter_map(|&i| files.get(i as usize).cloned())
+                .collect(),
+            _ => files,
+        };
+        checker = Some(FileChecker::new(files.clone(), entries)?);
+        debug!(files = files.len(), "got the manifest");
+        let _ = progress.send(ReceiveProgress::Manifest { files }).await;
+    }
+
+    // Ask to skip a file that's already here, and send accept otherwise.
+    // The files it may be are checked against the sender's hash here, so
+    // the sender never learns what else is. A sender whose file changed
+    // since sends it all the same.
+    let mut first = None;
+    let dedup = options.dedup && offer.kind == OfferKind::File && options.in_memory.is_none();
+    let copies = match offer.size {
+        Some(size) if dedup => find_copies(&output_path, options.cache_dir.clone(), size).await,
+        _ => Vec::new(),
+    };
+    count!(started(Receive));
+    let mut copy = None;
+    if !copies.is_empty() {
+        peer.send(&Message::AskChecksum).await?;
+        let checksum = match peer.recv().await? {
+            Message::Checksums { entries } if entries.len() == 1 => entries[0],
+            Message::Reject { reason } => return Err(Error::TransferFailed(reason)),
+            _ => return Err(Error::Protocol("expected the file's checksum".into())),
+        };
+        copy = matching_copy(copies, checksum)
+            .await
+            .map(|copy| (copy, checksum));
+    }
+    if let Some((copy, checksum)) = copy {
+        peer.send(&Message::AlreadyHave { checksum }).await?;
+        info!(path = %copy.display(), "handshake complete, asked to skip a file already here");
+        match peer.recv().await? {
+            // A hash that matches means the same size too
+            Message::Done { checksum: sent, .. } if sent == checksum => {
+                timings.handshake = started.elapsed().saturating_sub(timings.waiting);
+                let durability = options.durability;
+                return receive_copy(copy, output_path, checksum, durability, progress, timings)
+                    .await;
+            }
+            msg => first = Some(msg),
+        }
+    } else {
+        peer.send(&accept).await?;
+        info!("handshake complete");
+    }
+    // Tells the sender to keep what it sends until it's acked, so a broken
+    // stream can be replaced without starting over
+    peer.send(&Message::Ack { offset: 0 }).await?;
+    timings.handshake = started.elapsed().saturating_sub(timings.waiting);
+    let transfer_start = Instant::now();
+
+    // Prepare output file (archives are staged next to their destination,
+    // and the staged copy goes however the receive ends)
+    let archive_path = output_dir.join(format!(".{}.zap-archive", offer.name));
+    let _staged = (offer.kind == OfferKind::Archive && options.in_memory.is_none())
+        .then(|| TempFile(local_path(&archive_path).into_owned()));
+
+    let durability = options.durability;
+    let mut sink = match (&offer.kind, options.in_memory) {
+        (_, Some(limit)) => Sink::Memory(Vec::new(), limit),
+        (OfferKind::File, None) => {
+            Sink::File(ReceivedFile::create(&local_path(&output_path), durability).await?)
+        }
+        (OfferKind::Archive, None) => {
+            Sink::File(ReceivedFile::create(&local_path(&archive_path), durability).await?)
+        }
+        (OfferKind::Directory { entries }, None) => Sink::Directory(
+            DirectoryWriter::create(output_path.clone(), entries.clone(), durability).await?,
+        ),
+    };
+    let mut state = match (&sink, ticket, offer.size) {
+        (Sink::File(_), Some(ticket), Some(size))
+            if options.keep_state && offer.kind == OfferKind::File =>
+        {
+            let state = ResumeState {
+                ticket: ticket.share(),
+                name: offered_name,
+                size,
+                blocks: Vec::new(),
+            };
+            let state = StateFile::new(&output_path, state);
+            state.save().await;
+            Some(state)
+        }
+        _ => None,
+    };
+    let mut bytes_received = 0u64;
+    let mut files = FileEnds::new(&offer.kind);
+    let mut hasher = StreamHasher::new();
+    let mut paused_since = None;
+    let mut paused = Duration::ZERO;
+    let mut throughput = Throughput::new(transfer_start);
+
+    // The start of the file, for detecting its type
+    let sniff = options.detect_extension
+        && offer.kind == OfferKind::File
+        && Path::new(&offer.name).extension().is_none();
+    let mut head = Vec::new();
+    let mut unacked = 0;
+    let mut reopened = 0;
+
+    // Receive chunks
+    let checksum = loop {
+        let msg = match first.take() {
+            Some(msg) => msg,
+            None => match peer.recv().await {
+                Ok(msg) => msg,
+                // The sender carries on where this left off on a new
+                // stream, as long as the connection is still there
+                Err(e) => {
+                    if reopened == MAX_REATTACH || peer.reopen().await.is_err() {
+                        return Err(e);
+                    }
+                    reopened += 1;
+                    warn!(
+                        offset = bytes_received,
+                        "stream from the sender broke, opened a new one: {}", e
+                    );
+                    peer.send(&Message::Continue {
+                        offset: bytes_received,
+                    })
+                    .await?;
+                    continue;
+                }
+            },
+        };
+        match msg {
+            Message::Chunk(chunk) => {
+                if bytes_received == 0 {
+                    info!("first byte received");
+                }
+                let received = bytes_received + chunk.data.len() as u64;
+                if let Some(size) = offer.size
+                    && received > size
+                {
+                    return Err(Error::TransferFailed(format!(
+                        "sender sent more than the {} bytes it offered",
+                        size
+                    )));
+                }
+                if let Some(verifier) = &mut verifier {
+                    verifier.update(&chunk.data)?;
+                }
+                sink.write(&chunk.data).await?;
+                count!(bytes(Receive, chunk.data.len() as u64));
+                hasher.update(&chunk.data).await?;
+                if let Some(state) = &mut state
+                    && state.update(&chunk.data)
+                {
+                    sink.flush().await?;
+                    state.save().await;
+                }
+                if sniff && head.len() < SNIFF_LEN {
+                    let n = (SNIFF_LEN - head.len()).min(chunk.data.len());
+                    head.extend_from_slice(&chunk.data[..n]);
+                }
+                bytes_received = received;
+                unacked += 1;
+                if unacked == ACK_INTERVAL {
+                    unacked = 0;
+                    // A broken stream shows up on the next read anyway
+                    let _ = peer
+                        .send(&Message::Ack {
+                            offset: bytes_received,
+                        })
+                        .await;
+                }
+
+                let _ = progress
+                    .send(ReceiveProgress::Receiving {
+                        bytes_received,
+                        total_bytes: offer.size,
+                    })
+                    .await;
+                let complete = match &mut checker {
+                    Some(checker) => checker.update(&chunk.data)?,
+                    None => files.reached(bytes_received),
+                };
+                for path in complete {
+                    let _ = progress.send(ReceiveProgress::FileReceived { path }).await;
+                }
+                if let Some(bytes_per_sec) = throughput.sample(bytes_received, Instant::now()) {
+                    let _ = progress
+                        .send(ReceiveProgress::Throughput { bytes_per_sec })
+                        .await;
+                }
+            }
+            Message::Done { checksum, size } => {
+                if size != bytes_received {
+                    return Err(Error::TransferFailed(format!(
+                        "size mismatch: sender sent {} bytes, received {}",
+                        size, bytes_received
+                    )));
+                }
+                if checksum != hasher.finalize().await? {
+                    return Err(Error::TransferFailed("checksum mismatch".into()));
+                }
+                if let Some(verifier) = verifier.take() {
+                    verifier.finish()?;
+                }
+                break checksum;
+            }
+            Message::Pause => {
+                if paused_since.is_none() {
+                    paused_since = Some(Instant::now());
+                    let _ = progress.send(ReceiveProgress::Paused).await;
+                }
+            }
+            Message::Resume => {
+                if let Some(since) = paused_since.take() {
+                    paused += since.elapsed();
+                }
+                let _ = progress.send(ReceiveProgress::Resumed).await;
+            }
+            Message::Error { message } => {
+                return Err(Error::TransferFailed(message));
+            }
+            _ => {
+                return Err(Error::Protocol("unexpected message".into()));
+            }
+        }
+    };
+
+    timings.waiting += paused;
+    timings.transfer = transfer_start.elapsed().saturating_sub(paused);
+    let verification_start = Instant::now();
+
+    let data = sink.finish().await?;
+    // All of it is here, so there's nothing left to resume
+    if let Some(state) = state {
+        state.remove().await;
+    }
+    if let Some(data) = data {
+        timings.verification = verification_start.elapsed();
+        peer.send_receipt(checksum, bytes_received).await;
+        count!(completed(Receive));
+        let _ = progress
+            .send(ReceiveProgress::Loaded {
+                name: offer.name.clone(),
+                data: data.into(),
+                checksum,
+                timings,
+            })
+            .await;
+        Span::current().record("zap.bytes", bytes_received);
+        info!("transfer complete, kept in memory");
+        return Ok(());
+    }
+
+    #[cfg(feature = "archive")]
+    if offer.kind == OfferKind::Archive {
+        let _ = progress.send(ReceiveProgress::Unpacking).await;
+        let (archive, dest) = (archive_path.clone(), output_path.clone());
+        blocking(move || directory::unpack_archive(&archive, &dest)).await?;
+    }
+
+    if sniff && let Some(ext) = detect_extension(&head) {
+        output_path = add_extension(&output_path, &output_dir, &offer.name, ext).await?;
+        info!(extension = ext, "added extension detected from content");
+    }
+
+    timings.verification = verification_start.elapsed();
+    peer.send_receipt(checksum, bytes_received).await;
+    count!(completed(Receive));
+    let _ = progress
+        .send(ReceiveProgress::Complete {
+            path: output_path.clone(),
+            checksum,
+            timings,
+        })
+        .await;
+    Span::current().record("zap.bytes", bytes_received);
+    info!(path = %output_path.display(), ?timings, "transfer complete");
+
+    Ok(())
+}
+
+/// Files of `size` bytes that may be the one offered for `dest`: the one
+/// already there, then the ones in `cache_dir`, those of the same name
+/// first
+async fn find_copies(dest: &Path, cache_dir: Option<PathBuf>, size: u64) -> Vec<PathBuf> {
+    let mut copies = Vec::new();
+    if let Ok(metadata) = tokio::fs::metadata(local_path(dest)).await
+        && metadata.is_file()
+        && metadata.len() == size
+    {
+        copies.push(dest.to_path_buf());
+    }
+    if let (Some(cache_dir), Some(name)) = (cache_dir, dest.file_name()) {
+        let name = name.to_owned();
+        let cached = blocking(move || Ok(cached_copies(&cache_dir, &name, size))).await;
+        copies.extend(cached.unwrap_or_default());
+    }
+    copies
+}
+
+/// The first of `copies` whose BLAKE3 hash is `checksum`
+async fn matching_copy(copies: Vec<PathBuf>, checksum: [u8; 32]) -> Option<PathBuf> {
+    for copy in copies {
+        // One that can't be read is no copy
+        if let Ok(hashed) = checksums(std::slice::from_ref(&copy)).await
+            && hashed[0] == checksum
+        {
+            return Some(copy);
+        }
+    }
+    None
+}
+
+/// Files of `size` bytes anywhere under `dir`, those named `name` first
+pub(crate) fn cached_copies(dir: &Path, name: &std::ffi::OsStr, size: u64) -> Vec<PathBuf> {
+    let mut dirs = vec![dir.to_path_buf()];
+    let (mut named, mut others) = (Vec::new(), Vec::new());
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => dirs.push(entry.path()),
+                Ok(kind) if kind.is_file() => {
+                    if entry.metadata().map_or(true, |m| m.len() != size) {
+                        continue;
+                    }
+                    if entry.file_name() == name {
+                        named.push(entry.path());
+                    } else {
+                        others.push(entry.path());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    named.extend(others);
+    named
+}
+
+/// Finish a receive the sender skipped because `copy` already holds the
+/// offered file, copying it to `output_path` if it's somewhere else
+async fn receive_copy(
+    copy: PathBuf,
+    output_path: PathBuf,
+    checksum: [u8; 32],
+    durability: Durability,
+    progress: &mpsc::Sender<ReceiveProgress>,
+    mut timings: PhaseTimings,
+) -> Result<()> {
+    let _ = progress
+        .send(ReceiveProgress::AlreadyHave {
+            source: copy.clone(),
+        })
+        .await;
+    let copying = Instant::now();
+    if copy != output_path {
+        tokio::fs::copy(local_path(&copy), local_path(&output_path)).await?;
+        if durability.on_done() {
+            let file = File::open(local_path(&output_path)).await?;
+            file.sync_all().await?;
+        }
+    }
+    timings.transfer = copying.elapsed();
+
+    count!(completed(Receive));
+    let _ = progress
+        .send(ReceiveProgress::Complete {
+            path: output_path.clone(),
+            checksum,
+            timings,
+        })
+        .await;
+    Span::current().record("zap.bytes", 0);
+    info!(path = %output_path.display(), from = %copy.display(), "transfer skipped, already here");
+    Ok(())
+}
+
+/// The offer a sender opens with, or why there isn't one
+pub(crate) fn expect_offer(message: Message) -> Result<FileOffer> {
+    match message {
+        Message::Offer(offer) => Ok(offer),
+        Message::LabeledOffer { mut offer, label } => {
+            offer.label = Some(label);
+            Ok(offer)
+        }
+        Message::BatchOffer { mut offer, label } => {
+            offer.label = label;
+            offer.batch = true;
+            Ok(offer)
+        }
+        // Turned away before any offer, e.g. for not having the key
+        Message::Reject { reason } => Err(Error::TransferFailed(reason)),
+        _ => Err(Error::Protocol("expected offer".into())),
+    }
+}
+
+/// Fetch a sender's offer without receiving anything, then turn it down
+///
+/// A sender that isn't keeping its offer open ends when turned down, so
+/// this is meant for senders with [`SendOptions::keep_serving`].
+pub async fn peek_offer(endpoint: &Endpoint, alpn: &[u8], ticket: &Ticket) -> Result<FileOffer> {
+    let ready = ready(endpoint, ticket)?;
+    let conn = connect(endpoint, ticket.addr.clone(), alpn).await?;
+    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
+    send_message(&mut send_stream, &ready).await?;
+
+    let offer = expect_offer(recv_message(&mut recv_stream).await?)?;
+    if let Err(violation) = validate_offer(&offer) {
+        let _ = reject(&mut send_stream, violation.to_string()).await;
+        return Err(violation.into());
+    }
+
+    let _ = reject(&mut send_stream, "only looking at the offer".into()).await;
+    Ok(offer)
+}
+
+/// Read `len` bytes from `offset` of one file in a share: entry `0` of a
+/// file offer, or the entry at that index of a directory offer
+pub async fn fetch_range(
+    endpoint: &Endpoint,
+    alpn: &[u8],
+    ticket: &Ticket,
+    entry: u32,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    let ready = ready(endpoint, ticket)?;
+    let conn = connect(endpoint, ticket.addr.clone(), alpn).await?;
+    read_range(&conn, ready, entry, offset, len).await
+}
+
+/// Reads ranges of a share's files over one connection, each on a stream
+/// of its own, connecting again if the share let the connection go
+pub struct RangeReader {
+    endpoint: Endpoint,
+    alpn: Vec<u8>,
+    ticket: Ticket,
+    conn: tokio::sync::Mutex<Option<Connection>>,
+}
+
+impl RangeReader {
+    pub(crate) fn new(endpoint: Endpoint, alpn: Vec<u8>, ticket: Ticket) -> Self {
+        Self {
+            endpoint,
+            alpn,
+            ticket,
+            conn: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Read `len` bytes from `offset` of one file, as [`fetch_range`] does
+    pub async fn read(&self, entry: u32, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let ready = ready(&self.endpoint, &self.ticket)?;
+        let conn = self.connection().await?;
+        match read_range(&conn, ready.clone(), entry, offset, len).await {
+            // The share closes connections that have been idle for a while
+            Err(_) if conn.close_reason().is_some() => {
+                let conn = self.connection().await?;
+                read_range(&conn, ready, entry, offset, len).await
+            }
+            read => read,
+        }
+    }
+
+    /// The open connection to the share, connecting if there isn't one
+    async fn connection(&self) -> Result<Connection> {
+        let mut conn = self.conn.lock().await;
+        if let Some(open) = conn.as_ref().filter(|c| c.close_reason().is_none()) {
+            return Ok(open.clone());
+        }
+        let open = connect(&self.endpoint, self.ticket.addr.clone(), &self.alpn).await?;
+        *conn = Some(open.clone());
+        Ok(open)
+    }
+}
+
+/// Read a range over a new stream of a connection to a share
+async fn read_range(
+    conn: &Connection,
+    ready: Message,
+    entry: u32,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
+    send_message(&mut send_stream, &ready).await?;
+
+    let offer = expect_offer(recv_message(&mut recv_stream).await?)?;
+    if let Err(violation) = validate_offer(&offer) {
+        let _ = reject(&mut send_stream, violation.to_string()).await;
+        return Err(violation.into());
+    }
+    if !matches!(offer.kind, OfferKind::File | OfferKind::Directory { .. }) {
+        return Err(reject(&mut send_stream, "only files can be read in ranges".into()).await);
+    }
+
+    let request = Message::AcceptRange { entry, offset, len };
+    send_message(&mut send_stream, &request).await?;
+
+    let mut data = Vec::with_capacity(len.min(CHUNK_SIZE as u64 * 64) as usize);
+    loop {
+        match recv_message(&mut recv_stream).await? {
+            Message::Chunk(chunk) => {
+                if chunk.offset != data.len() as u64
+                    || data.len() as u64 + chunk.data.len() as u64 > len
+                {
+                    return Err(Error::Protocol("chunk outside the requested range".into()));
+                }
+                data.extend_from_slice(&chunk.data);
+            }
+            Message::Done { checksum, size } => {
+                if size != len || data.len() as u64 != len {
+                    return Err(Error::TransferFailed(format!(
+                        "expected {} bytes, got {}",
+                        len,
+                        data.len()
+                    )));
+                }
+                if *blake3::hash(&data).as_bytes() != checksum {
+                    return Err(Error::TransferFailed("checksum mismatch".into()));
+                }
+                return Ok(data);
+            }
+            Message::Pause | Message::Resume => {}
+            Message::Error { message } => return Err(Error::TransferFailed(message)),
+            _ => return Err(Error::Protocol("unexpected message".into())),
+        }
+    }
+}
+
+/// Ask a share for the BLAKE3 hash of each of its files, without
+/// transferring them
+pub async fn fetch_checksums(
+    endpoint: &Endpoint,
+    alpn: &[u8],
+    ticket: &Ticket,
+) -> Result<(FileOffer, Vec<[u8; 32]>)> {
+    let ready = ready(endpoint, ticket)?;
+    let conn = connect(endpoint, ticket.addr.clone(), alpn).await?;
+    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
+    send_message(&mut send_stream, &ready).await?;
+
+    let offer = expect_offer(recv_message(&mut recv_stream).await?)?;
+    if let Err(violation) = validate_offer(&offer) {
+        let _ = reject(&mut send_stream, violation.to_string()).await;
+        return Err(violation.into());
+    }
+    let expected = match &offer.kind {
+        OfferKind::File if offer.size.is_some() => 1,
+        OfferKind::Directory { entries } => entries.len(),
+        _ => {
+            let reason = "only files and folders have checksums".into();
+            return Err(reject(&mut send_stream, reason).await);
+        }
+    };
+
+    send_message(&mut send_stream, &Message::AcceptChecksums).await?;
+    match recv_message(&mut recv_stream).await? {
+        Message::Checksums { entries } if entries.len() == expected => Ok((offer, entries)),
+        Message::Checksums { .. } => Err(Error::Protocol("wrong number of checksums".into())),
+        Message::Error { message } => Err(Error::TransferFailed(message)),
+        _ => Err(Error::Protocol("unexpected message".into())),
+    }
+}
+
+/// Connect to a peer, reporting a peer that only speaks other ALPNs as
+/// such rather than as a generic handshake failure
+pub(crate) async fn connect(
+    endpoint: &Endpoint,
+    addr: EndpointAddr,
+    alpn: &[u8],
+) -> Result<Connection> {
+    endpoint.connect(addr, alpn).await.map_err(|e| {
+        let message = e.to_string();
+        if is_alpn_mismatch(&message) {
+            Error::AlpnMismatch(String::from_utf8_lossy(alpn).into_owned())
+        } else {
+            Error::ConnectionFailed(message)
+        }
+    })
+}
+
+/// Whether a handshake failed with TLS alert 120, no_application_protocol
+pub(crate) fn is_alpn_mismatch(message: &str) -> bool {
+    message.contains("no_application_protocol")
+        || message.contains("NoApplicationProtocol")
+        || message.contains("handshake failed: error 120")
+}
+
+/// The sender, as the receiver sees it
+///
+/// A QUIC stream pair, or a recording played back by
+/// [`debug::replay`](crate::debug::replay).
+pub(crate) trait Peer {
+    async fn send(&mut self, msg: &Message) -> Result<()>;
+
+    async fn recv(&mut self) -> Result<Message>;
+
+    /// Turn down the offer, returning the error the receiver ends with
+    async fn reject(&mut self, reason: String) -> Error;
+
+    /// Open a new stream to the sender after the last one broke, failing
+    /// if the connection is gone too
+    async fn reopen(&mut self) -> Result<()> {
+        Err(Error::ConnectionFailed(
+            "the stream can't be reopened".into(),
+        ))
+    }
+
+    /// Sign for `size` bytes with `checksum` and send the sender the
+    /// receipt, if this end has a node key to sign with
+    async fn send_receipt(&mut self, _checksum: [u8; 32], _size: u64) {}
+}
+
+/// A sender on the other end of a QUIC stream
+struct Streams {
+    conn: Connection,
+    send: iroh::endpoint::SendStream,
+    recv: iroh::endpoint::RecvStream,
+    /// The receiving node's key, for receipts
+    key: iroh::SecretKey,
+}
+
+impl Peer for Streams {
+    async fn send(&mut self, msg: &Message) -> Result<()> {
+        send_message(&mut self.send, msg).await
+    }
+
+    async fn recv(&mut self) -> Result<Message> {
+        recv_message(&mut self.recv).await
+    }
+
+    async fn reject(&mut self, reason: String) -> Error {
+        reject(&mut self.send, reason).await
+    }
+
+    async fn reopen(&mut self) -> Result<()> {
+        if let Some(reason) = self.conn.close_reason() {
+            return Err(reason.into());
+        }
+        // Whatever the sender still writes to the old stream fails, so it
+        // knows to wait for this one
+        let _ = self.send.reset(0u32.into());
+        let _ = self.recv.stop(0u32.into());
+        let (send, recv) = self.conn.open_bi().await?;
+        self.send = send;
+        self.recv = recv;
+        Ok(())
+    }
+
+    async fn send_receipt(&mut self, checksum: [u8; 32], size: u64) {
+        let receipt = Receipt::sign(&self.key, self.conn.remote_id(), checksum, size);
+        let sent = send_message(&mut self.send, &Message::Receipt(receipt)).await;
+        // Waiting until it's there keeps a node that shuts down right away
+        // from cutting it off
+        if sent.is_ok() && self.send.finish().is_ok() {
+            let _ = tokio::time::timeout(RECEIPT_TIMEOUT, self.send.stopped()).await;
+        }
+    }
+}
+
+/// Turn down an offer or request, telling the other end why
+pub(crate) async fn reject(send_stream: &mut iroh::endpoint::SendStream, reason: String) -> Error {
+    let reject = Message::Reject {
+        reason: reason.clone(),
+    };
+    if send_message(send_stream, &reject).await.is_ok() {
+        finish(send_stream).await;
+    }
+    Error::TransferFailed(reason)
+}
+
+/// Finish a stream and wait for the other end to read it
+pub(crate) async fn finish(send_stream: &mut iroh::endpoint::SendStream) {
+    if send_stream.finish().is_ok() {
+        let _ = send_stream.stopped().await;
+    }
+}
+
+/// Why an offer can't be received into memory, if it can't
+fn memory_rejection(offer: &FileOffer, limit: u64) -> Option<String> {
+    match (&offer.kind, offer.size) {
+        (OfferKind::File, Some(size)) if size > limit => Some(format!(
+            "{} bytes is too large to keep in memory (limit {})",
+            size, limit
+        )),
+        (OfferKind::File, _) => None,
+        _ => Some("directories can't be kept in memory".into()),
+    }
+}
+
+/// How much of a file is looked at to detect its type
+const SNIFF_LEN: usize = 8192;
+
+/// Guess a file extension from the start of a file's content
+#[cfg(feature = "detect-type")]
+pub(crate) fn detect_extension(head: &[u8]) -> Option<&'static str> {
+    infer::get(head).map(|kind| kind.extension())
+}
+
+/// Move a received file to `name.ext` in `dir`, or `name-1.ext`, ... if
+/// that's taken, returning where it went
+///
+/// The new name is taken by creating it before the file is moved onto it,
+/// so a file that's already there is never written over.
+pub(crate) async fn add_extension(
+    path: &Path,
+    dir: &Path,
+    name: &str,
+    ext: &str,
+) -> Result<PathBuf> {
+    let mut n = 0;
+    loop {
+        let renamed = match n {
+            0 => dir.join(format!("{}.{}", name, ext)),
+            n => dir.join(format!("{}-{}.{}", name, n, ext)),
+        };
+        let taken = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(local_path(&renamed))
+            .await;
+        match taken {
+            Ok(_) => {
+                tokio::fs::rename(local_path(path), local_path(&renamed)).await?;
+                return Ok(renamed);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Without the `detect-type` feature no content is recognised
+#[cfg(not(feature = "detect-type"))]
+pub(crate) fn detect_extension(_head: &[u8]) -> Option<&'static str> {
+    None
+}
+
+/// Describe an offer on the current transfer span
+fn record_offer(name: &str, size: Option<u64>, kind: &OfferKind) {
+    let kind = match (kind, size) {
+        (OfferKind::Directory { .. }, _) => "directory",
+        (OfferKind::Archive, _) => "archive",
+        (OfferKind::File, None) => "stream",
+        (OfferKind::File, Some(_)) => "file",
+    };
+
+    let span = Span::current();
+    span.record("zap.name", name);
+    span.record("zap.kind", kind);
+    if let Some(size) = size {
+        span.record("zap.size", size);
+    }
+}
+
+/// Hold the sender while the transfer is paused, telling the receiver,
+/// and return how long it was held
+async fn wait_while_paused(
+    control: &mut watch::Receiver<Control>,
+    out: &mut Outgoing,
+    progress: &mpsc::Sender<SendProgress>,
+) -> Result<Duration> {
+    let mut state = *control.borrow_and_update();
+    if state == Control::Running {
+        return Ok(Duration::ZERO);
+    }
+    let paused_at = Instant::now();
+
+    if state == Control::Paused {
+        info!("transfer paused");
+        let _ = progress.send(SendProgress::Paused).await;
+        while state == Control::Paused {
+            out.message(&Message::Pause).await?;
+            match tokio::time::timeout(PAUSE_HEARTBEAT, control.changed()).await {
+                Ok(Ok(())) => state = *control.borrow_and_update(),
+                // Every handle was dropped, so nothing can resume it later
+                Ok(Err(_)) => state = Control::Running,
+                Err(_) => {}
+            }
+        }
+    }
+
+    if state == Control::Cancelled {
+        let cancelled = Message::Error {
+            message: "sender cancelled the transfer".into(),
+        };
+        let _ = send_message(&mut out.send, &cancelled).await;
+        return Err(Error::Cancelled);
+    }
+
+    info!("transfer resumed");
+    out.message(&Message::Resume).await?;
+    let _ = progress.send(SendProgress::Resumed).await;
+    Ok(paused_at.elapsed())
+}
+
+/// The stream data goes out to a receiver on, replaced by a new one from
+/// the same receiver if it breaks
+struct Outgoing {
+    conn: Connection,
+    send: SendStream,
+    /// Offsets the receiver acked
+    acks: mpsc::UnboundedReceiver<u64>,
+    /// The receiver's receipt, once all of it is in
+    receipt: oneshot::Receiver<Receipt>,
+    window: ReplayWindow,
+    /// Streams replaced so far
+    reattached: usize,
+}
+
+impl Outgoing {
+    fn new(conn: Connection, send: SendStream, recv: RecvStream, ack_window: usize) -> Self {
+        let (acks, receipt) = read_acks(recv);
+        Self {
+            conn,
+            send,
+            acks,
+            receipt,
+            window: ReplayWindow::new(ack_window),
+            reattached: 0,
+        }
+    }
+
+    /// Send a chunk, keeping it until the receiver acks it
+    async fn chunk(&mut self, chunk: ChunkData) -> Result<()> {
+        while let Ok(offset) = self.acks.try_recv() {
+            self.window.ack(offset);
+        }
+        while self.window.is_full() {
+            match self.acks.recv().await {
+                Some(offset) => self.window.ack(offset),
+                None => {
+                    let broken = Error::StreamBroken("the receiver stopped acking".into());
+                    self.reattach(broken).await?;
+                }
+            }
+        }
+
+        // Kept even if it didn't go out, to go out again on a new stream
+        let msg = Message::Chunk(chunk);
+        let sent = send_message(&mut self.send, &msg).await;
+        if let Message::Chunk(chunk) = msg {
+            self.window.push(chunk);
+        }
+        match sent {
+            Ok(()) => Ok(()),
+            Err(e) => self.reattach(e).await,
+        }
+    }
+
+    /// Wait for the receiver to ack everything before `offset`, which
+    /// fails if it doesn't within `timeout`, e.g. as it doesn't ack at all
+    async fn acked(&mut self, offset: u64, timeout: Duration) -> bool {
+        let wait = async {
+            while !self.window.acking || self.window.acked < offset {
+                match self.acks.recv().await {
+                    Some(acked) => self.window.ack(acked),
+                    None => return false,
+                }
+            }
+            true
+        };
+        tokio::time::timeout(timeout, wait).await.unwrap_or(false)
+    }
+
+    /// Send anything but a chunk
+    async fn message(&mut self, msg: &Message) -> Result<()> {
+        if let Err(e) = send_message(&mut self.send, msg).await {
+            self.reattach(e).await?;
+            send_message(&mut self.send, msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Carry on after the stream broke with `broken`, once the receiver
+    /// opens a new one and says where it got to; fails with `broken` if
+    /// the receiver can't or doesn't
+    async fn reattach(&mut self, broken: Error) -> Result<()> {
+        if !self.window.acking
+            || self.reattached == MAX_REATTACH
+            || self.conn.close_reason().is_some()
+        {
+            return Err(broken);
+        }
+        warn!(
+            "stream to the receiver broke, waiting for a new one: {}",
+            broken
+        );
+
+        let reopened = tokio::time::timeout(REATTACH_TIMEOUT, async {
+            let (send, mut recv) = self.conn.accept_bi().await?;
+            match recv_message(&mut recv).await? {
+                Message::Continue { offset } => Ok((send, recv, offset)),
+                _ => Err(Error::Protocol("expected Continue".into())),
+            }
+        })
+        .await;
+        let (send, recv, offset) = match reopened {
+            Ok(Ok(reopened)) => reopened,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(broken),
+        };
+        let Some(missed) = self.window.since(offset) else {
+            return Err(Error::TransferFailed(format!(
+                "can't carry on from byte {}, which is no longer kept",
+                offset
+            )));
+        };
+
+        self.send = send;
+        (self.acks, self.receipt) = read_acks(recv);
+        self.reattached += 1;
+        info!(offset, "carrying on over a new stream");
+        for chunk in missed {
+            send_message(&mut self.send, &Message::Chunk(chunk.clone())).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Pass on the offsets the receiver acks on `recv`, and its receipt at
+/// the end, until the stream ends
+fn read_acks(mut recv: RecvStream) -> (mpsc::UnboundedReceiver<u64>, oneshot::Receiver<Receipt>) {
+    let (acks_tx, acks) = mpsc::unbounded_channel();
+    let (receipt_tx, receipt) = oneshot::channel();
+    let mut receipt_tx = Some(receipt_tx);
+    tokio::spawn(
+        async move {
+            loop {
+                match recv_message(&mut recv).await {
+                    Ok(Message::Ack { offset }) => {
+                        if acks_tx.send(offset).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(Message::Receipt(receipt)) => {
+                        if let Some(tx) = receipt_tx.take() {
+                            let _ = tx.send(receipt);
+                        }
+                    }
+                    Ok(_) => debug!("ignoring a message from the receiver while sending"),
+                    Err(_) => break,
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    (acks, receipt)
+}
 
-    /// Sending file data
-    Sending { bytes_sent: u64, total_bytes: u64 },
+/// The next chunk of at most `size` bytes of `data`, past the `taken`
+/// bytes already cut from it; `data` itself when it fits whole
+pub(crate) fn cut(data: &mut Vec<u8>, taken: &mut usize, size: usize) -> Option<Vec<u8>> {
+    if *taken == data.len() {
+        return None;
+    }
+    if *taken == 0 && data.len() <= size {
+        return Some(std::mem::take(data));
+    }
+    let end = (*taken + size).min(data.len());
+    let chunk = data[*taken..end].to_vec();
+    *taken = end;
+    Some(chunk)
+}
 
-    /// Transfer complete
-    Complete,
+/// Where each file of a directory offer ends in its data, to tell when one
+/// has arrived whole
+#[derive(Debug, Default)]
+pub(crate) struct FileEnds {
+    ends: VecDeque<(u64, String)>,
+}
 
-    /// Error occurred
-    Error(String),
+impl FileEnds {
+    pub(crate) fn new(kind: &OfferKind) -> Self {
+        let OfferKind::Directory { entries } = kind else {
+            return Self::default();
+        };
+        let mut end = 0;
+        let ends = entries
+            .iter()
+            .map(|entry| {
+                end += entry.size;
+                (end, entry.path.clone())
+            })
+            .collect();
+        Self { ends }
+    }
+
+    /// The files whole once the data before `offset` is in, each only the
+    /// first time
+    pub(crate) fn reached(&mut self, offset: u64) -> Vec<String> {
+        let whole = self.ends.partition_point(|(end, _)| *end <= offset);
+        self.ends.drain(..whole).map(|(_, path)| path).collect()
+    }
 }
 
-/// Progress updates for receiving
-#[derive(Debug, Clone)]
-pub enum ReceiveProgress {
-    /// Connecting to sender
-    Connecting,
+/// Chunks sent to a receiver that it hasn't acked yet, to send again over
+/// a new stream if the one they went out on breaks
+#[derive(Debug)]
+pub(crate) struct ReplayWindow {
+    chunks: VecDeque<ChunkData>,
+    /// Chunks kept at most; the sender waits for an ack once this many
+    /// are out
+    limit: usize,
+    /// Where the next chunk starts
+    end: u64,
+    /// The furthest offset the receiver acked
+    acked: u64,
+    /// Whether the receiver acks at all; for one that doesn't, only the
+    /// last chunks are kept, and the sender never waits
+    acking: bool,
+}
 
-    /// Connected to sender
-    Connected,
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new(DEFAULT_ACK_WINDOW)
+    }
+}
 
-    /// Received file offer
-    Offer { name: String, size: u64 },
+impl ReplayWindow {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            limit: limit.max(1),
+            end: 0,
+            acked: 0,
+            acking: false,
+        }
+    }
 
-    /// Receiving file data
-    Receiving {
-        bytes_received: u64,
-        total_bytes: u64,
-    },
+    pub(crate) fn push(&mut self, chunk: ChunkData) {
+        if !self.acking && self.chunks.len() >= self.limit {
+            self.chunks.pop_front();
+        }
+        self.end = chunk.offset + chunk.data.len() as u64;
+        self.chunks.push_back(chunk);
+    }
 
-    /// Transfer complete
-    Complete { path: PathBuf },
+    /// Forget the chunks before `offset`, which the receiver has
+    pub(crate) fn ack(&mut self, offset: u64) {
+        self.acking = true;
+        self.acked = self.acked.max(offset);
+        while let Some(chunk) = self.chunks.front()
+            && chunk.offset + chunk.data.len() as u64 <= offset
+        {
+            self.chunks.pop_front();
+        }
+    }
 
-    /// Error occurred
-    Error(String),
+    /// How far the receiver is known to have got: what it acked, or for
+    /// one that doesn't ack, everything sent
+    pub(crate) fn delivered(&self) -> u64 {
+        if self.acking { self.acked } else { self.end }
+    }
+
+    /// Whether to wait for an ack before sending more
+    pub(crate) fn is_full(&self) -> bool {
+        self.acking && self.chunks.len() >= self.limit
+    }
+
+    /// The chunks from `offset` on, unless some of them were forgotten
+    pub(crate) fn since(&self, offset: u64) -> Option<vec_deque::Iter<'_, ChunkData>> {
+        let start = if offset == self.end {
+            self.chunks.len()
+        } else {
+            self.chunks
+                .iter()
+                .position(|chunk| chunk.offset == offset)?
+        };
+        Some(self.chunks.range(start..))
+    }
 }
 
-/// Handle to control an ongoing transfer
-pub struct TransferHandle {
-    cancel_tx: mpsc::Sender<()>,
+/// Everything the sender needs to make an offer and stream its data
+struct Payload {
+    name: String,
+    size: Option<u64>,
+    kind: OfferKind,
+    label: Option<String>,
+    /// Whether the directory's entries are separate files, offered as a
+    /// batch
+    batch: bool,
+    parts: Vec<Part>,
+    /// Temporary archive or transformed files, removed once every transfer
+    /// of them ends
+    _temp_files: Vec<Arc<TempFile>>,
 }
 
-impl TransferHandle {
-    /// Cancel the transfer
-    pub async fn cancel(&self) {
-        let _ = self.cancel_tx.send(()).await;
+impl Payload {
+    /// A copy for another receiver, unless it streams from a reader that
+    /// can only be read once
+    fn reuse(&self) -> Option<Payload> {
+        let parts = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::File(path, stamp) => Some(Part::File(path.clone(), *stamp)),
+                Part::Entry(path, stamp) => Some(Part::Entry(path.clone(), *stamp)),
+                Part::Range(path, stamp, start, len) => {
+                    Some(Part::Range(path.clone(), *stamp, *start, *len))
+                }
+                Part::Reader(..) => None,
+            })
+            .collect::<Option<_>>()?;
+        Some(Payload {
+            name: self.name.clone(),
+            size: self.size,
+            kind: self.kind.clone(),
+            label: self.label.clone(),
+            batch: self.batch,
+            parts,
+            _temp_files: self._temp_files.clone(),
+        })
     }
-}
 
-/// Run the sender side of a transfer
-pub async fn run_sender(
-    endpoint: Endpoint,
-    path: PathBuf,
-    progress: mpsc::Sender<SendProgress>,
-) -> Result<()> {
-    let _ = progress.send(SendProgress::Waiting).await;
+    /// Narrow a directory payload down to the entries at `indices`, which
+    /// must be ascending
+    fn select(&mut self, indices: &[u32]) -> Result<()> {
+        let OfferKind::Directory { entries } = &mut self.kind else {
+            return Err(Error::Protocol(
+                "selection for an offer that isn't a directory".into(),
+            ));
+        };
+        let ascending = indices.windows(2).all(|w| w[0] < w[1]);
+        if !ascending || indices.last().is_some_and(|&i| i as usize >= entries.len()) {
+            return Err(Error::Protocol("invalid selection".into()));
+        }
 
-    // Accept incoming connection
-    let conn = loop {
-        let Some(incoming) = endpoint.accept().await else {
-            return Err(Error::ConnectionFailed("endpoint closed".into()));
+        // Parts of a directory payload line up with its entries
+        let keep = |i: usize| indices.binary_search(&(i as u32)).is_ok();
+        *entries = std::mem::take(entries)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, entry)| keep(i).then_some(entry))
+            .collect();
+        self.parts = std::mem::take(&mut self.parts)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, part)| keep(i).then_some(part))
+            .collect();
+        self.size = Some(entries.iter().map(|e| e.size).sum());
+        Ok(())
+    }
+
+    /// The path of each file, for a payload of whole files
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::File(path, _) | Part::Entry(path, _) => Ok(path.clone()),
+                _ => Err(Error::Protocol("only files have checksums".into())),
+            })
+            .collect()
+    }
+
+    /// Narrow the payload down to `len` bytes from `offset` of one file
+    fn range(&mut self, entry: u32, offset: u64, len: u64) -> Result<()> {
+        let (path, stamp) = match (&self.kind, self.parts.get(entry as usize)) {
+            (OfferKind::File, Some(Part::File(path, stamp))) if entry == 0 => {
+                (path.clone(), *stamp)
+            }
+            (OfferKind::Directory { .. }, Some(Part::Entry(path, stamp))) => (path.clone(), *stamp),
+            _ => return Err(Error::Protocol("invalid range".into())),
         };
+        if offset.checked_add(len).is_none_or(|end| end > stamp.len) {
+            return Err(Error::Protocol("range past the end of the file".into()));
+        }
 
-        let conn = incoming.accept()?.await?;
+        self.parts = vec![Part::Range(path, stamp, offset, len)];
+        self.size = Some(len);
+        Ok(())
+    }
+}
 
-        // Check ALPN
-        if conn.alpn() == ZAP_ALPN {
-            break conn;
+/// A hasher fed the first `len` bytes of the file at `path`, unless it's
+/// shorter than that
+///
+/// The hasher carries on where this leaves off, so it can't be split up
+/// like a [`StreamHasher`]; the whole prefix is hashed on a blocking thread
+/// instead.
+pub(crate) async fn hash_prefix(path: &Path, len: u64) -> Result<Option<blake3::Hasher>> {
+    let path = path.to_path_buf();
+    blocking(move || {
+        let mut file = std::io::Read::take(std::fs::File::open(path)?, len);
+        let mut hasher = blake3::Hasher::new();
+        let read = std::io::copy(&mut file, &mut hasher)?;
+        Ok((read == len).then_some(hasher))
+    })
+    .await
+}
+
+/// The BLAKE3 hash of each file, read from disk and hashed on other threads
+async fn checksums(paths: &[PathBuf]) -> Result<Vec<[u8; 32]>>{
+    let mut checksums = Vec::with_capacity(paths.len());
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    for path in paths {
+        let mut file = File::open(path).await?;
+        let mut hasher = StreamHasher::new();
+        loop {
+            let n = file.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]).await?;
         }
+        checksums.push(hasher.finalize().await?);
+    }
+    Ok(checksums)
+}
 
-        debug!("ignoring connection with wrong ALPN");
-    };
+/// A piece of the payload, sent in order
+pub(crate) enum Part {
+    /// A whole file, as it was when offered
+    File(PathBuf, Stamp),
 
-    let _ = progress.send(SendProgress::Connected).await;
-    info!("receiver connected");
+    /// A file inside a directory offer, as it was when offered
+    Entry(PathBuf, Stamp),
 
-    // Accept bidirectional stream from the receiver
-    // The receiver sends Ready first to trigger stream creation (QUIC streams are lazy)
-    let (mut send_stream, mut recv_stream) = conn.accept_bi().await?;
-    debug!("accepted bidirectional stream");
+    /// Part of a file: its path, how it was when offered, where to start
+    /// and how many bytes
+    Range(PathBuf, Stamp, u64, u64),
+
+    /// A stream, and its length if known
+    Reader(Box<dyn AsyncRead + Send + Unpin>, Option<u64>),
+}
+
+/// A file's size and modification time, to tell when it changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    pub(crate) len: u64,
+    pub(crate) modified: Option<SystemTime>,
+}
 
-    // Wait for Ready message from receiver
-    let ready_msg = recv_message(&mut recv_stream).await?;
-    if !matches!(ready_msg, Message::Ready) {
-        return Err(Error::Protocol("expected Ready message".into()));
+impl Stamp {
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        }
     }
-    debug!("received Ready from receiver");
 
-    // Read file metadata
-    let file = File::open(&path).await?;
-    let metadata = file.metadata().await?;
-    let file_name = path
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("file")
-        .to_string();
-    let file_size = metadata.len();
-
-    // Send offer
-    let offer = Message::Offer(FileOffer {
-        name: file_name.clone(),
-        size: file_size,
-        checksum: None, // TODO: compute checksum
-    });
-    send_message(&mut send_stream, &offer).await?;
-    debug!("sent offer");
+    pub(crate) async fn read(path: &Path) -> Result<Self> {
+        Ok(Self::of(&tokio::fs::metadata(path).await?))
+    }
 
-    // Wait for accept/reject
-    let response = recv_message(&mut recv_stream).await?;
-    match response {
-        Message::Accept => {
-            info!("receiver accepted transfer");
+    /// Fail unless `path` is still as it was
+    async fn check(&self, path: &Path) -> Result<()> {
+        match Self::read(path).await {
+            Ok(now) if now == *self => Ok(()),
+            _ => Err(Error::FileChanged(path.to_path_buf())),
         }
-        Message::Reject { reason } => {
-            return Err(Error::TransferFailed(format!(
-                "receiver rejected: {}",
-                reason
-            )));
+    }
+}
+
+/// How often the sender checks that a file it's reading hasn't changed, in
+/// chunks
+const STAMP_INTERVAL: u64 = 16;
+
+/// How long a file that changed has to stay the same before it's offered
+/// again
+const SETTLE: Duration = Duration::from_secs(2);
+
+/// Wait until `path` stops changing, or is gone
+async fn settle(path: &Path) {
+    let mut last = Stamp::read(path).await.ok();
+    loop {
+        tokio::time::sleep(SETTLE).await;
+        let now = Stamp::read(path).await.ok();
+        if now == last {
+            return;
         }
-        _ => {
-            return Err(Error::Protocol("unexpected message".into()));
+        last = now;
+    }
+}
+
+/// How many chunks the sender reads ahead of the network
+const READ_AHEAD: usize = 4;
+
+/// What the read-ahead task hands the network writer
+pub(crate) enum ReadAhead {
+    Chunk(Vec<u8>),
+    /// Everything was read, with this checksum
+    Done([u8; 32]),
+}
+
+/// Read `parts` one after another into chunks for the sender, hashing them
+/// on the way, on other threads
+///
+/// Stops at the first error, which is passed on, or once the sender stops
+/// listening.
+pub(crate) async fn read_parts(parts: Vec<Part>, chunks: mpsc::Sender<Result<ReadAhead>>) {
+    let mut hasher = StreamHasher::new();
+    for part in parts {
+        if let Err(e) = read_part(part, &mut hasher, &chunks).await {
+            let _ = chunks.send(Err(e)).await;
+            return;
         }
     }
+    let done = hasher.finalize().await.map(ReadAhead::Done);
+    let _ = chunks.send(done).await;
+}
 
-    // Send file chunks
-    let mut reader = BufReader::new(file);
-    let mut buffer = vec![0u8; CHUNK_SIZE];
-    let mut offset = 0u64;
+async fn read_part(
+    part: Part,
+    hasher: &mut StreamHasher,
+    chunks: &mpsc::Sender<Result<ReadAhead>>,
+) -> Result<()> {
+    // Files are read as far as they were offered, and checked along the
+    // way, so the receiver never gets a mix of old and new data
+    let mut length = None;
+    let (reader, watched): (Box<dyn AsyncRead + Send + Unpin>, _) = match part {
+        Part::File(path, stamp) | Part::Entry(path, stamp) => {
+            let file = open_unchanged(&path, &stamp).await?;
+            (
+                Box::new(file.take(stamp.len)),
+                Some((path, stamp, stamp.len)),
+            )
+        }
+        Part::Range(path, stamp, start, len) => {
+            let mut file = open_unchanged(&path, &stamp).await?;
+            file.seek(SeekFrom::Start(start)).await?;
+            (Box::new(file.take(len)), Some((path, stamp, len)))
+        }
+        Part::Reader(reader, size) => {
+            length = size;
+            (reader, None)
+        }
+    };
 
+    let mut reader = BufReader::new(reader);
+    let mut read = 0u64;
+    let mut read_chunks = 0u64;
     loop {
+        let mut buffer = vec![0u8; CHUNK_SIZE];
         let bytes_read = reader.read(&mut buffer).await?;
         if bytes_read == 0 {
             break;
         }
+        buffer.truncate(bytes_read);
+        hasher.update(&buffer).await?;
+        read += bytes_read as u64;
+        read_chunks += 1;
+        if let Some(length) = length
+            && read > length
+        {
+            return Err(Error::TransferFailed(format!(
+                "the stream is longer than the {} bytes offered",
+                length
+            )));
+        }
+        if let Some((path, stamp, _)) = &watched
+            && read_chunks.is_multiple_of(STAMP_INTERVAL)
+        {
+            stamp.check(path).await?;
+        }
+        if chunks.send(Ok(ReadAhead::Chunk(buffer))).await.is_err() {
+            return Err(Error::Cancelled);
+        }
+    }
 
-        let chunk = Message::Chunk(ChunkData {
-            offset,
-            data: buffer[..bytes_read].to_vec(),
-        });
-        send_message(&mut send_stream, &chunk).await?;
+    // Checked once more before the receiver is told it has everything; a
+    // file cut short ends early, and the receiver splits directory data by
+    // the sizes in the offer
+    if let Some(length) = length
+        && read != length
+    {
+        return Err(Error::TransferFailed(format!(
+            "the stream ended after {} of the {} bytes offered",
+            read, length
+        )));
+    }
+    if let Some((path, stamp, expected)) = watched {
+        if read != expected {
+            return Err(Error::FileChanged(path));
+        }
+        stamp.check(&path).await?;
+    }
+    Ok(())
+}
 
-        offset += bytes_read as u64;
-        let _ = progress
-            .send(SendProgress::Sending {
-                bytes_sent: offset,
-                total_bytes: file_size,
+/// Open `path`, failing if it's changed since `stamp`
+async fn open_unchanged(path: &Path, stamp: &Stamp) -> Result<File> {
+    let file = File::open(path).await?;
+    if Stamp::of(&file.metadata().await?) != *stamp {
+        return Err(Error::FileChanged(path.to_path_buf()));
+    }
+    Ok(file)
+}
+
+/// Resolve a send source into a payload, transforming files and archiving
+/// directories if needed
+async fn prepare(
+    source: SendSource,
+    transform: Option<&Transform>,
+    progress: &mpsc::Sender<SendProgress>,
+) -> Result<Payload> {
+    match source {
+        SendSource::File(path) => {
+            let mut files = vec![(path.clone(), file_name(&path))];
+            let temp_files = transform_files(transform, &mut files, progress).await?;
+            let (path, name) = files.remove(0);
+            let stamp = Stamp::read(&path).await?;
+            Ok(Payload {
+                name,
+                size: Some(stamp.len),
+                kind: OfferKind::File,
+                label: None,
+                batch: false,
+                parts: vec![Part::File(path, stamp)],
+                _temp_files: temp_files,
             })
-            .await;
+        }
+        SendSource::Directory { path, auto_archive } => {
+            let root = path.clone();
+            let entries = blocking(move || directory::walk(&root)).await?;
+            let files = entries_in(&path, entries);
+            prepare_directory(file_name(&path), files, auto_archive, transform, progress).await
+        }
+        SendSource::Selection {
+            root,
+            names,
+            auto_archive,
+        } => {
+            let dir = root.clone();
+            let entries = blocking(move || directory::walk_selection(&dir, &names)).await?;
+            let files = entries_in(&root, entries);
+            prepare_directory(file_name(&root), files, auto_archive, transform, progress).await
+        }
+        SendSource::Batch(paths) => {
+            let name = match paths.as_slice() {
+                [path] => file_name(path),
+                [path, rest @ ..] => format!("{} and {} more", file_name(path), rest.len()),
+                [] => return Err(Error::Protocol("nothing to send".into())),
+            };
+            let mut files = Vec::new();
+            for path in paths {
+                let (Some(root), Some(item)) = (path.parent(), path.file_name()) else {
+                    return Err(Error::Protocol(format!("cannot send {}", path.display())));
+                };
+                let (root, names) = (root.to_path_buf(), vec![item.to_string_lossy().into()]);
+                let dir = root.clone();
+                let entries = blocking(move || directory::walk_selection(&dir, &names)).await?;
+                files.extend(entries_in(&root, entries));
+            }
+            // Never archived, since an archive unpacks into a folder
+            let mut payload = prepare_directory(name, files, None, transform, progress).await?;
+            payload.batch = true;
+            Ok(payload)
+        }
+        SendSource::Reader { meta, reader } => Ok(Payload {
+            name: meta.name,
+            size: meta.size,
+            kind: OfferKind::File,
+            label: None,
+            batch: false,
+            parts: vec![Part::Reader(reader, meta.size)],
+            _temp_files: Vec::new(),
+        }),
     }
+}
 
-    // Send done
-    let done = Message::Done {
-        checksum: [0u8; 32], // TODO: actual checksum
-    };
-    send_message(&mut send_stream, &done).await?;
-    debug!("sent done message");
+/// The files of `entries` under `root`, with the names they're offered
+/// under
+fn entries_in(root: &Path, entries: Vec<DirEntry>) -> Vec<(PathBuf, String)> {
+    entries
+        .into_iter()
+        .map(|e| (root.join(&e.path), e.path))
+        .collect()
+}
 
-    // Finish the stream and wait for it to be fully sent
-    send_stream.finish()?;
+/// Offer `files` as a directory called `name`, one by one, or packed into
+/// an archive if they match the policy
+#[cfg_attr(not(feature = "archive"), allow(unused_variables))]
+async fn prepare_directory(
+    name: String,
+    mut files: Vec<(PathBuf, String)>,
+    auto_archive: Option<ArchivePolicy>,
+    transform: Option<&Transform>,
+    progress: &mpsc::Sender<SendProgress>,
+) -> Result<Payload> {
+    let mut temp_files = transform_files(transform, &mut files, progress).await?;
+    let mut entries = Vec::with_capacity(files.len());
+    let mut stamps = Vec::with_capacity(files.len());
+    for (source, name) in &files {
+        let stamp = Stamp::read(source).await?;
+        entries.push(DirEntry {
+            path: name.clone(),
+            size: stamp.len,
+        });
+        stamps.push(stamp);
+    }
 
-    // Wait for the stream to be fully acknowledged
-    // This ensures the receiver has time to read the Done message
-    match send_stream.stopped().await {
-        Ok(_) => debug!("stream finished cleanly"),
-        Err(e) => debug!("stream stopped: {:?}", e),
+    if !auto_archive.is_some_and(|policy| policy.should_archive(&entries)) {
+        return Ok(Payload {
+            name,
+            size: Some(entries.iter().map(|e| e.size).sum()),
+            parts: files
+                .into_iter()
+                .zip(stamps)
+                .map(|((source, _), stamp)| Part::Entry(source, stamp))
+                .collect(),
+            kind: OfferKind::Directory { entries },
+            label: None,
+            batch: false,
+            _temp_files: temp_files,
+        });
     }
 
-    let _ = progress.send(SendProgress::Complete).await;
-    info!("transfer complete");
+    info!(files = entries.len(), "archiving directory");
+    let archive =
+        TempFile(std::env::temp_dir().join(format!("zap-{:016x}.tar", rand::random::<u64>())));
 
-    Ok(())
-}
+    // Without the `archive` feature the policy never asks for one
+    #[cfg(feature = "archive")]
+    {
+        let (dest, tx) = (archive.0.clone(), progress.clone());
+        let sources: Vec<_> = files.into_iter().map(|(source, _)| source).collect();
+        let total_files = entries.len();
+        blocking(move || {
+            directory::write_archive_from(&sources, &entries, &dest, |files_done| {
+                let _ = tx.blocking_send(SendProgress::Archiving {
+                    files_done,
+                    total_files,
+                });
+            })
+        })
+        .await?;
+    }
 
-/// Run the receiver side of a transfer
-pub async fn run_receiver(
-    endpoint: Endpoint,
-    ticket: Ticket,
-    output_dir: Option<PathBuf>,
-    progress: mpsc::Sender<ReceiveProgress>,
-) -> Result<()> {
-    let _ = progress.send(ReceiveProgress::Connecting).await;
+    // The transformed files are in the archive now
+    temp_files.clear();
+    let stamp = Stamp::read(&archive.0).await?;
+    Ok(Payload {
+        name,
+        size: Some(stamp.len),
+        kind: OfferKind::Archive,
+        label: None,
+        batch: false,
+        parts: vec![Part::File(archive.0.clone(), stamp)],
+        _temp_files: vec![Arc::new(archive)],
+    })
+}
 
-    debug!(addr = ?ticket.addr, "connecting to sender");
+/// Replace the files `transform` changes, given as source paths with the
+/// `/`-separated names they're offered under, by their changed versions
+///
+/// The changed files are written to temporary files, returned to keep them
+/// around until the send ends. A file isn't changed when its new name is
+/// already taken, so nothing is offered twice.
+async fn transform_files(
+    transform: Option<&Transform>,
+    files: &mut [(PathBuf, String)],
+    progress: &mpsc::Sender<SendProgress>,
+) -> Result<Vec<Arc<TempFile>>> {
+    let Some(transform) = transform else {
+        return Ok(Vec::new());
+    };
 
-    // Connect to sender
-    let conn = endpoint.connect(ticket.addr.clone(), ZAP_ALPN).await?;
+    let mut taken: HashSet<String> = files.iter().map(|(_, name)| name.clone()).collect();
+    let mut changes = Vec::new();
+    for (i, (_, name)) in files.iter().enumerate() {
+        let (dir, file) = match name.rsplit_once('/') {
+            Some((dir, file)) => (Some(dir), file),
+            None => (None, name.as_str()),
+        };
+        let Some(renamed) = (transform.rename)(file) else {
+            continue;
+        };
+        if let Err(e) = validate_name(&renamed) {
+            warn!(name = %name, error = %e, "not transforming, the new name is invalid");
+            continue;
+        }
+        let renamed = match dir {
+            Some(dir) => format!("{}/{}", dir, renamed),
+            None => renamed,
+        };
+        if renamed != *name && !taken.insert(renamed.clone()) {
+            warn!(name = %name, renamed = %renamed, "not transforming, the new name is taken");
+            continue;
+        }
+        changes.push((i, renamed));
+    }
 
-    let _ = progress.send(ReceiveProgress::Connected).await;
-    info!("connected to sender");
+    let total_files = changes.len();
+    let mut temp_files = Vec::with_capacity(total_files);
+    for (files_done, (i, renamed)) in changes.into_iter().enumerate() {
+        let _ = progress
+            .send(SendProgress::Transforming {
+                files_done,
+                total_files,
+            })
+            .await;
+        // Keep the new extension, which converters often go by
+        let extension = Path::new(&renamed)
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_default();
+        let temp = TempFile(std::env::temp_dir().join(format!(
+            "zap-{:016x}{}",
+            rand::random::<u64>(),
+            extension
+        )));
+        let (source, name) = &files[i];
+        info!(name = %name, renamed = %renamed, "transforming file");
+        let apply = transform.apply.clone();
+        let (source, dest, name) = (source.clone(), temp.0.clone(), name.clone());
+        blocking(move || {
+            apply(&source, &dest).map_err(|e| {
+                Error::TransferFailed(format!("changing {} before sending: {}", name, e))
+            })
+        })
+        .await?;
+        files[i] = (temp.0.clone(), renamed);
+        temp_files.push(Arc::new(temp));
+    }
+    if total_files > 0 {
+        let _ = progress
+            .send(SendProgress::Transforming {
+                files_done: total_files,
+                total_files,
+            })
+            .await;
+    }
+    Ok(temp_files)
+}
 
-    // Open bidirectional stream
-    let (mut send_stream, mut recv_stream) = conn.open_bi().await?;
-    debug!("opened bidirectional stream");
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "file".to_string())
+}
 
-    // Send Ready message to trigger stream creation on sender side
-    // (QUIC streams are lazy - only created when data is sent)
-    send_message(&mut send_stream, &Message::Ready).await?;
-    debug!("sent Ready message");
+/// How much received data is buffered before it's written, so small
+/// chunks from a pipe go to disk in batches
+const WRITE_BUFFER: usize = 1024 * 1024;
 
-    // Receive offer
-    let offer = match recv_message(&mut recv_stream).await? {
-        Message::Offer(offer) => offer,
-        _ => return Err(Error::Protocol("expected offer".into())),
-    };
+/// A file being received, synced as its [`Durability`] asks
+pub(crate) struct ReceivedFile {
+    writer: BufWriter<File>,
+    durability: Durability,
+    /// Bytes written since the last sync
+    unsynced: u64,
+}
 
-    let _ = progress
-        .send(ReceiveProgress::Offer {
-            name: offer.name.clone(),
-            size: offer.size,
+impl ReceivedFile {
+    pub(crate) async fn create(path: &Path, durability: Durability) -> Result<Self> {
+        let file = File::create(path).await?;
+        Ok(Self {
+            writer: BufWriter::with_capacity(WRITE_BUFFER, file),
+            durability,
+            unsynced: 0,
         })
-        .await;
+    }
 
-    info!(name = %offer.name, size = offer.size, "received offer");
+    /// Open a file received up to `offset` to write the rest, dropping
+    /// anything after that
+    pub(crate) async fn open_at(path: &Path, offset: u64, durability: Durability) -> Result<Self> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Self {
+            writer: BufWriter::with_capacity(WRITE_BUFFER, file),
+            durability,
+            unsynced: 0,
+        })
+    }
 
-    // Send accept
-    send_message(&mut send_stream, &Message::Accept).await?;
+    pub(crate) async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.write_all(data).await?;
+        self.unsynced += data.len() as u64;
+        if self.durability.due(self.unsynced) {
+            self.writer.flush().await?;
+            self.writer.get_ref().sync_data().await?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
 
-    // Prepare output file
-    let output_path = output_dir
-        .unwrap_or_else(|| std::env::current_dir().unwrap_or_default())
-        .join(&offer.name);
+    /// Write out what's buffered, so a state file can count it
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        self.writer.flush().await?;
+        Ok(())
+    }
 
-    let file = File::create(&output_path).await?;
-    let mut writer = BufWriter::new(file);
-    let mut bytes_received = 0u64;
+    /// Write out what's buffered, syncing it if the policy says so
+    pub(crate) async fn finish(mut self) -> Result<()> {
+        self.writer.flush().await?;
+        if self.durability.on_done() {
+            self.writer.get_ref().sync_all().await?;
+        }
+        Ok(())
+    }
+}
 
-    // Receive chunks
-    loop {
-        let msg = recv_message(&mut recv_stream).await?;
-        match msg {
-            Message::Chunk(chunk) => {
-                writer.write_all(&chunk.data).await?;
-                bytes_received += chunk.data.len() as u64;
+/// Where the receiver writes incoming data
+enum Sink {
+    File(ReceivedFile),
+    Directory(DirectoryWriter),
+    /// Data kept in memory, up to a limit
+    Memory(Vec<u8>, u64),
+}
 
-                let _ = progress
-                    .send(ReceiveProgress::Receiving {
-                        bytes_received,
-                        total_bytes: offer.size,
-                    })
-                    .await;
-            }
-            Message::Done { checksum: _ } => {
-                break;
-            }
-            Message::Error { message } => {
-                return Err(Error::TransferFailed(message));
-            }
-            _ => {
-                return Err(Error::Protocol("unexpected message".into()));
+impl Sink {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Sink::File(file) => file.write(data).await?,
+            Sink::Directory(writer) => writer.write(data).await?,
+            Sink::Memory(buf, limit) => {
+                if (buf.len() + data.len()) as u64 > *limit {
+                    return Err(Error::TransferFailed(format!(
+                        "more than {} bytes, too large to keep in memory",
+                        limit
+                    )));
+                }
+                buf.extend_from_slice(data);
             }
         }
+        Ok(())
+    }
+
+    /// Write out what's buffered to the file, if there is one
+    async fn flush(&mut self) -> Result<()> {
+        match self {
+            Sink::File(file) => file.flush().await,
+            Sink::Directory(_) | Sink::Memory(..) => Ok(()),
+        }
     }
 
-    writer.flush().await?;
-    drop(writer);
+    /// Flush what was written, handing back the data if it was kept in memory
+    async fn finish(self) -> Result<Option<Vec<u8>>> {
+        match self {
+            Sink::File(file) => file.finish().await?,
+            Sink::Directory(writer) => writer.finish().await?,
+            Sink::Memory(buf, _) => return Ok(Some(buf)),
+        }
+        Ok(None)
+    }
+}
 
-    let _ = progress
-        .send(ReceiveProgress::Complete {
-            path: output_path.clone(),
-        })
-        .await;
-    info!(path = %output_path.display(), "transfer complete");
+/// A file that is removed when dropped
+struct TempFile(PathBuf);
 
-    Ok(())
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Run blocking filesystem work off the async runtime
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::TransferFailed(e.to_string()))?
 }
 
 /// Send a length-prefixed message
-async fn send_message(stream: &mut iroh::endpoint::SendStream, msg: &Message) -> Result<()> {
-    let bytes = msg
-        .to_bytes()
-        .map_err(|e| Error::Protocol(format!("serialization error: {}", e)))?;
+pub(crate) async fn send_message(
+    stream: &mut iroh::endpoint::SendStream,
+    msg: &Message,
+) -> Result<()> {
+    let format = WireFormat::from_env();
+    let bytes = msg.encode(format)?;
+    if format == WireFormat::Json {
+        trace!(target: "zap::wire", message = %String::from_utf8_lossy(&bytes), "sent");
+    }
 
-    let len = (bytes.len() as u32).to_be_bytes();
-    stream.write_all(&len).await?;
+    let prefix = format.prefix(bytes.len());
+    debug::record(Direction::Sent, prefix, &bytes);
+    stream.write_all(&prefix.to_be_bytes()).await?;
     stream.write_all(&bytes).await?;
 
     Ok(())
 }
 
 /// Receive a length-prefixed message
-async fn recv_message(stream: &mut iroh::endpoint::RecvStream) -> Result<Message> {
+pub(crate) async fn recv_message(stream: &mut iroh::endpoint::RecvStream) -> Result<Message> {
     let mut len_buf = [0u8; 4];
     stream.read_exact(&mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf) as usize;
+    let prefix = u32::from_be_bytes(len_buf);
+    let (format, len) = WireFormat::from_prefix(prefix);
 
     if len > 10 * 1024 * 1024 {
         return Err(Error::Protocol("message too large".into()));
@@ -308,6 +3498,10 @@ async fn recv_message(stream: &mut iroh::endpoint::RecvStream) -> Result<Message
 
     let mut buf = vec![0u8; len];
     stream.read_exact(&mut buf).await?;
+    debug::record(Direction::Received, prefix, &buf);
 
-    Message::from_bytes(&buf).map_err(|e| Error::Protocol(format!("deserialization error: {}", e)))
+    if format == WireFormat::Json {
+        trace!(target: "zap::wire", message = %String::from_utf8_lossy(&buf), "received");
+    }
+    Message::decode(format, &buf)
 }
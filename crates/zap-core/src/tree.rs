@@ -0,0 +1,212 @@
+//! Checking a file piece by piece as it arrives
+//!
+//! A file's BLAKE3 hash is the root of a tree over its data, so the hashes
+//! of its [`CHUNK_SIZE`] pieces, taken as subtrees, are enough to work out
+//! the file's hash and to check each piece on its own. A receiver that asks
+//! gets these in a [`Message::Tree`](crate::protocol::Message::Tree) before
+//! any data, and a piece that doesn't match is caught as soon as it's in
+//! instead of by the checksum at the end.
+//!
+//! Only a receive from the start is checked this way. A
+//! [resumed](crate::resume) one checks what it kept against the blocks in
+//! its state file and the rest against the checksum at the end, and range
+//! reads are only checked as a whole.
+
+use std::path::Path;
+
+use blake3::hazmat::{HasherExt, Mode, merge_subtrees_non_root, merge_subtrees_root};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::protocol::CHUNK_SIZE;
+use crate::{Error, Result};
+
+/// How much of the file each hash in a tree covers
+pub const PIECE: u64 = CHUNK_SIZE as u64;
+
+/// The hash of each piece of a file, which add up to the file's BLAKE3
+/// hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashTree {
+    size: u64,
+    pieces: Vec<[u8; 32]>,
+}
+
+impl HashTree {
+    /// The tree of a `size` byte file with these piece hashes, failing if
+    /// there are too many or too few of them
+    pub fn new(size: u64, pieces: Vec<[u8; 32]>) -> Result<Self> {
+        if pieces.len() as u64 != piece_count(size) {
+            return Err(Error::Protocol(format!(
+                "a {} byte file has {} pieces, not {}",
+                size,
+                piece_count(size),
+                pieces.len()
+            )));
+        }
+        Ok(Self { size, pieces })
+    }
+
+    /// The tree of `data`
+    pub fn of(data: &[u8]) -> Self {
+        let size = data.len() as u64;
+        let pieces = if data.is_empty() {
+            vec![piece_hash(size, 0, data)]
+        } else {
+            data.chunks(CHUNK_SIZE)
+                .enumerate()
+                .map(|(i, piece)| piece_hash(size, i as u64, piece))
+                .collect()
+        };
+        Self { size, pieces }
+    }
+
+    /// The tree of the file at `path`, read from disk
+    pub async fn of_file(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let mut pieces = Vec::with_capacity(piece_count(size) as usize);
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        for index in 0..piece_count(size) {
+            let len = (size - index * PIECE).min(PIECE) as usize;
+            file.read_exact(&mut buffer[..len]).await?;
+            pieces.push(piece_hash(size, index, &buffer[..len]));
+        }
+        Ok(Self { size, pieces })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn pieces(&self) -> &[[u8; 32]] {
+        &self.pieces
+    }
+
+    /// The file's BLAKE3 hash
+    pub fn root(&self) -> [u8; 32] {
+        if self.size <= PIECE {
+            // A single piece was hashed as the whole file
+            return self.pieces[0];
+        }
+        let left = blake3::hazmat::left_subtree_len(self.size);
+        let hash = merge_subtrees_root(
+            &self.subtree(0, left),
+            &self.subtree(left, self.size - left),
+            Mode::Hash,
+        );
+        *hash.as_bytes()
+    }
+
+    /// The chaining value of the `len` bytes from `offset`, which start a
+    /// piece
+    fn subtree(&self, offset: u64, len: u64) -> [u8; 32] {
+        if len <= PIECE {
+            return self.pieces[(offset / PIECE) as usize];
+        }
+        // Left subtrees are a power of two long, so a whole number of
+        // pieces
+        let left = blake3::hazmat::left_subtree_len(len);
+        merge_subtrees_non_root(
+            &self.subtree(offset, left),
+            &self.subtree(offset + left, len - left),
+            Mode::Hash,
+        )
+    }
+}
+
+/// How many pieces a `size` byte file has; an empty file still has one
+fn piece_count(size: u64) -> u64 {
+    size.div_ceil(PIECE).max(1)
+}
+
+/// The hash of piece `index` of a `size` byte file, which is the file's
+/// own hash when it's the only one
+fn piece_hash(size: u64, index: u64, data: &[u8]) -> [u8; 32] {
+    if size <= PIECE {
+        return *blake3::hash(data).as_bytes();
+    }
+    subtree_hash(index, data)
+}
+
+/// The hash of piece `index` of data with more than one piece
+pub(crate) fn subtree_hash(index: u64, data: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.set_input_offset(index * PIECE);
+    hasher.update(data);
+    hasher.finalize_non_root()
+}
+
+/// Checks data against a [`HashTree`] as it comes in, in order
+pub(crate) struct TreeVerifier {
+    tree: HashTree,
+    /// The piece being received
+    index: u64,
+    piece: Vec<u8>,
+}
+
+impl TreeVerifier {
+    pub(crate) fn new(tree: HashTree) -> Self {
+        Self {
+            tree,
+            index: 0,
+            piece: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Check `data`, the next bytes of the file, as far as it completes
+    /// pieces, failing at the first one that doesn't match
+    pub(crate) fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let want = self.piece_len().saturating_sub(self.piece.len() as u64) as usize;
+            if want == 0 {
+                return Err(Error::TransferFailed(
+                    "sender sent more than its hash tree covers".into(),
+                ));
+            }
+            let n = want.min(data.len());
+            self.piece.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.piece.len() as u64 == self.piece_len() {
+                self.check_piece()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that all of the file came in, which for an empty file is when
+    /// its one piece is checked
+    pub(crate) fn finish(mut self) -> Result<()> {
+        if self.tree.size == 0 && self.index == 0 {
+            self.check_piece()?;
+        }
+        if self.index != self.tree.pieces.len() as u64 {
+            return Err(Error::TransferFailed(format!(
+                "only {} of {} pieces arrived",
+                self.index,
+                self.tree.pieces.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn piece_len(&self) -> u64 {
+        let start = self.index * PIECE;
+        self.tree.size.saturating_sub(start).min(PIECE)
+    }
+
+    fn check_piece(&mut self) -> Result<()> {
+        let hash = piece_hash(self.tree.size, self.index, &self.piece);
+        let expected = self.tree.pieces[self.index as usize];
+        // Hashes compare in constant time
+        if blake3::Hash::from(hash) != blake3::Hash::from(expected) {
+            return Err(Error::TransferFailed(format!(
+                "the data at {} doesn't match the sender's hash tree",
+                self.index * PIECE
+            )));
+        }
+        self.index += 1;
+        self.piece.clear();
+        Ok(())
+    }
+}
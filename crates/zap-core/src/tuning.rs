@@ -0,0 +1,83 @@
+//! Transport settings for links far from an ordinary internet connection
+//!
+//! How fast one transfer can go is capped by how much data may be in flight
+//! before the other side acknowledges it, divided by the round trip. The
+//! defaults suit round trips of tens of milliseconds at a few hundred
+//! Mbit/s. A satellite hop's round trip is ten times that, and a 10GbE LAN
+//! moves ten times the data, so both need larger windows to keep the link
+//! busy. Windows that are too large only cost memory, a few of them per
+//! connection.
+
+use std::time::Duration;
+
+use iroh::endpoint::{TransportConfig, VarInt};
+
+use crate::protocol::ACK_INTERVAL;
+
+/// Windows and round-trip estimate for a node's connections, set with
+/// [`ZapNodeBuilder::tuning`](crate::ZapNodeBuilder::tuning)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    /// Chunks of [`CHUNK_SIZE`](crate::protocol::CHUNK_SIZE) a sender
+    /// sends ahead of the receiver's acks; it waits for one once this many
+    /// are out
+    pub ack_window: usize,
+
+    /// Bytes of one stream the receiving side buffers ahead of reading them
+    pub stream_window: u64,
+
+    /// Bytes of all of a connection's streams together the receiving side
+    /// buffers
+    pub connection_window: u64,
+
+    /// Bytes the sending side keeps in flight before they're acknowledged
+    pub send_window: u64,
+
+    /// The round trip assumed until one is measured, which sets how fast a
+    /// new connection speeds up and how soon a lost packet is sent again
+    pub initial_rtt: Duration,
+}
+
+/// Chunks sent ahead of acks unless tuned otherwise, a few acks' worth so a
+/// sender doesn't stop for every one
+pub(crate) const DEFAULT_ACK_WINDOW: usize = 4 * ACK_INTERVAL;
+
+impl Tuning {
+    /// For a fast local network, such as 10GbE: large windows, and a round
+    /// trip measured in microseconds
+    pub fn lan() -> Self {
+        Self {
+            ack_window: 64,
+            stream_window: 32 << 20,
+            connection_window: 64 << 20,
+            send_window: 64 << 20,
+            initial_rtt: Duration::from_millis(1),
+        }
+    }
+
+    /// For links with round trips of half a second or more, such as
+    /// geostationary satellites: windows for about 100 Mbit/s at 700 ms
+    pub fn satellite() -> Self {
+        Self {
+            ack_window: 48,
+            stream_window: 16 << 20,
+            connection_window: 32 << 20,
+            send_window: 32 << 20,
+            initial_rtt: Duration::from_millis(600),
+        }
+    }
+
+    pub(crate) fn transport_config(&self) -> TransportConfig {
+        let mut config = TransportConfig::default();
+        config
+            .stream_receive_window(var_int(self.stream_window))
+            .receive_window(var_int(self.connection_window))
+            .send_window(self.send_window)
+            .initial_rtt(self.initial_rtt);
+        config
+    }
+}
+
+fn var_int(bytes: u64) -> VarInt {
+    VarInt::from_u32(u32::try_from(bytes).unwrap_or(u32::MAX))
+}
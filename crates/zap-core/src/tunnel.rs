@@ -0,0 +1,263 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use iroh::{Endpoint, PublicKey};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::protocol::Message;
+use crate::ticket::{Ticket, TransferKey};
+use crate::transfer::{self, recv_message, send_message};
+use crate::{Error, Result};
+
+/// Progress updates for a tunnel
+#[derive(Debug, Clone)]
+pub enum TunnelEvent {
+    /// Waiting for a peer to connect
+    Listening,
+
+    /// Peer connected
+    Connected,
+
+    /// A forwarded TCP connection was opened
+    StreamOpened,
+
+    /// A forwarded TCP connection was closed
+    StreamClosed {
+        bytes_sent: u64,
+        bytes_received: u64,
+    },
+
+    /// A single forwarded connection failed; the tunnel stays up
+    StreamError(String),
+
+    /// Error occurred
+    Error(String),
+}
+
+/// Run the exposing side of a tunnel, forwarding streams to a local port
+///
+/// Streams are only forwarded once they prove they hold `key`, and the first
+/// peer to do so has the tunnel to itself: everyone else is turned away.
+pub async fn run_exposer(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    key: TransferKey,
+    port: u16,
+    progress: mpsc::Sender<TunnelEvent>,
+) -> Result<()> {
+    let _ = progress.send(TunnelEvent::Listening).await;
+    let target = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let pinned = Arc::new(OnceLock::new());
+
+    loop {
+        let Some(incoming) = endpoint.accept().await else {
+            return Err(Error::ConnectionFailed("endpoint closed".into()));
+        };
+
+        let conn = match incoming.accept() {
+            Ok(connecting) => match connecting.await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    debug!("incoming tunnel connection failed: {}", e);
+                    continue;
+                }
+            },
+            Err(e) => {
+                debug!("could not accept tunnel connection: {}", e);
+                continue;
+            }
+        };
+
+        if conn.alpn() != alpn {
+            debug!("ignoring connection with wrong ALPN");
+            continue;
+        }
+
+        let peer = conn.remote_id();
+        if pinned.get().is_some_and(|pinned| *pinned != peer) {
+            info!(%peer, "refused a second tunnel peer");
+            conn.close(0u32.into(), b"tunnel in use");
+            continue;
+        }
+
+        let proof = key.proof(&endpoint.id(), &peer);
+        tokio::spawn(serve_peer(
+            conn,
+            proof,
+            pinned.clone(),
+            target,
+            progress.clone(),
+        ));
+    }
+}
+
+/// Accept streams from a connected peer and bridge each one to the target,
+/// once it has shown `proof` and the tunnel is `pinned` to it
+async fn serve_peer(
+    conn: Connection,
+    proof: [u8; 32],
+    pinned: Arc<OnceLock<PublicKey>>,
+    target: SocketAddr,
+    progress: mpsc::Sender<TunnelEvent>,
+) {
+    let peer = conn.remote_id();
+    let mut connected = false;
+    loop {
+        let (send_stream, mut recv_stream) = match conn.accept_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                debug!("tunnel peer disconnected: {}", e);
+                return;
+            }
+        };
+
+        // The forwarder sends Ready first so the stream exists even for
+        // protocols where the server speaks first, and proves with it that
+        // it holds the key
+        match recv_message(&mut recv_stream).await {
+            // Hashes compare in constant time
+            Ok(Message::ReadyWithKey { proof: shown })
+                if blake3::Hash::from(shown) == blake3::Hash::from(proof) => {}
+            Ok(_) => {
+                warn!(%peer, "refused a tunnel peer without the key");
+                conn.close(0u32.into(), b"wrong key");
+                return;
+            }
+            Err(e) => {
+                debug!("tunnel stream closed before Ready: {}", e);
+                continue;
+            }
+        }
+        if *pinned.get_or_init(|| peer) != peer {
+            info!(%peer, "refused a second tunnel peer");
+            conn.close(0u32.into(), b"tunnel in use");
+            return;
+        }
+        if !connected {
+            connected = true;
+            let _ = progress.send(TunnelEvent::Connected).await;
+            info!("tunnel peer connected");
+        }
+
+        let progress = progress.clone();
+        tokio::spawn(async move {
+            let tcp = match TcpStream::connect(target).await {
+                Ok(tcp) => tcp,
+                Err(e) => {
+                    let _ = progress
+                        .send(TunnelEvent::StreamError(format!(
+                            "could not connect to {}: {}",
+                            target, e
+                        )))
+                        .await;
+                    return;
+                }
+            };
+
+            run_stream(tcp, send_stream, recv_stream, progress).await;
+        });
+    }
+}
+
+/// Run the forwarding side of a tunnel, listening on a local address
+pub async fn run_forwarder(
+    endpoint: Endpoint,
+    alpn: &[u8],
+    ticket: Ticket,
+    local_addr: SocketAddr,
+    progress: mpsc::Sender<TunnelEvent>,
+) -> Result<()> {
+    let ready = transfer::ready(&endpoint, &ticket)?;
+    let listener = TcpListener::bind(local_addr).await?;
+    let _ = progress.send(TunnelEvent::Listening).await;
+
+    debug!(addr = ?ticket.addr, "connecting to tunnel peer");
+    let conn = transfer::connect(&endpoint, ticket.addr.clone(), alpn).await?;
+
+    let _ = progress.send(TunnelEvent::Connected).await;
+    info!(%local_addr, "tunnel established");
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (tcp, peer_addr) = accepted?;
+                debug!(%peer_addr, "accepted local connection");
+
+                let conn = conn.clone();
+                let ready = ready.clone();
+                let progress = progress.clone();
+                tokio::spawn(async move {
+                    let (mut send_stream, recv_stream) = match conn.open_bi().await {
+                        Ok(streams) => streams,
+                        Err(e) => {
+                            let _ = progress.send(TunnelEvent::StreamError(e.to_string())).await;
+                            return;
+                        }
+                    };
+
+                    if let Err(e) = send_message(&mut send_stream, &ready).await {
+                        let _ = progress.send(TunnelEvent::StreamError(e.to_string())).await;
+                        return;
+                    }
+
+                    run_stream(tcp, send_stream, recv_stream, progress).await;
+                });
+            }
+            err = conn.closed() => {
+                return Err(err.into());
+            }
+        }
+    }
+}
+
+/// Bridge one TCP connection and report its lifecycle
+async fn run_stream(
+    tcp: TcpStream,
+    send_stream: SendStream,
+    recv_stream: RecvStream,
+    progress: mpsc::Sender<TunnelEvent>,
+) {
+    let _ = progress.send(TunnelEvent::StreamOpened).await;
+
+    match bridge(tcp, send_stream, recv_stream).await {
+        Ok((bytes_sent, bytes_received)) => {
+            let _ = progress
+                .send(TunnelEvent::StreamClosed {
+                    bytes_sent,
+                    bytes_received,
+                })
+                .await;
+        }
+        Err(e) => {
+            debug!("tunnel stream failed: {}", e);
+            let _ = progress.send(TunnelEvent::StreamError(e.to_string())).await;
+        }
+    }
+}
+
+/// Copy data in both directions until each side has finished
+async fn bridge(
+    tcp: TcpStream,
+    mut send_stream: SendStream,
+    mut recv_stream: RecvStream,
+) -> Result<(u64, u64)> {
+    let (mut tcp_read, mut tcp_write) = tcp.into_split();
+
+    let upstream = async {
+        let n = tokio::io::copy(&mut tcp_read, &mut send_stream).await?;
+        send_stream.finish()?;
+        Ok::<_, Error>(n)
+    };
+
+    let downstream = async {
+        let n = tokio::io::copy(&mut recv_stream, &mut tcp_write).await?;
+        tcp_write.shutdown().await?;
+        Ok::<_, Error>(n)
+    };
+
+    tokio::try_join!(upstream, downstream)
+}
@@ -0,0 +1,154 @@
+//! Checks on offers from the sender before anything is accepted
+//!
+//! Offers come from whoever holds the ticket, so names and sizes are
+//! treated as hostile: a name could try to escape the output directory or
+//! smuggle terminal escapes into a progress line, and a manifest could be
+//! large enough to exhaust memory on its own.
+
+use thiserror::Error;
+
+use crate::protocol::{FileOffer, OfferKind};
+
+/// Longest file name or path component, in bytes
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Longest path of a directory entry, in bytes
+pub const MAX_PATH_LEN: usize = 4096;
+
+/// Largest offer accepted, 1 PiB
+pub const MAX_OFFER_SIZE: u64 = 1 << 50;
+
+/// Most files in a directory offer
+pub const MAX_ENTRIES: usize = 1_000_000;
+
+/// Longest label on an offer, in bytes
+pub const MAX_LABEL_LEN: usize = 200;
+
+/// Why an offer was refused
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum OfferViolation {
+    #[error("empty name")]
+    EmptyName,
+
+    #[error("name {name:?} is {len} bytes, the limit is {MAX_NAME_LEN}")]
+    NameTooLong { name: String, len: usize },
+
+    #[error("path {path:?} is {len} bytes, the limit is {MAX_PATH_LEN}")]
+    PathTooLong { path: String, len: usize },
+
+    #[error("name {0:?} contains a control character")]
+    ControlCharacter(String),
+
+    #[error("name {0:?} is not a plain file name")]
+    NotAFileName(String),
+
+    #[error("{0} bytes is more than the {MAX_OFFER_SIZE} byte limit")]
+    TooLarge(u64),
+
+    #[error("{0} entries is more than the {MAX_ENTRIES} entry limit")]
+    TooManyEntries(usize),
+
+    #[error("entries add up to {entries} bytes but the offer says {size}")]
+    SizeMismatch { entries: u64, size: u64 },
+
+    #[error("label is {0} bytes, the limit is {MAX_LABEL_LEN}")]
+    LabelTooLong(usize),
+
+    #[error("label {0:?} contains a control character")]
+    LabelControlCharacter(String),
+}
+
+/// Check an offer's names, size and manifest against the limits above
+///
+/// Names are already valid UTF-8, since the wire format checks strings as
+/// it decodes them.
+pub fn validate_offer(offer: &FileOffer) -> Result<(), OfferViolation> {
+    validate_name(&offer.name)?;
+    if let Some(label) = &offer.label {
+        validate_label(label)?;
+    }
+
+    if let Some(size) = offer.size
+        && size > MAX_OFFER_SIZE
+    {
+        return Err(OfferViolation::TooLarge(size));
+    }
+
+    if let OfferKind::Directory { entries } = &offer.kind {
+        if entries.len() > MAX_ENTRIES {
+            return Err(OfferViolation::TooManyEntries(entries.len()));
+        }
+
+        let mut total = 0u64;
+        for entry in entries {
+            validate_path(&entry.path)?;
+            total = total
+                .checked_add(entry.size)
+                .filter(|&total| total <= MAX_OFFER_SIZE)
+                .ok_or(OfferViolation::TooLarge(u64::MAX))?;
+        }
+
+        if let Some(size) = offer.size
+            && size != total
+        {
+            return Err(OfferViolation::SizeMismatch {
+                entries: total,
+                size,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Check a single file name, which may not contain separators
+pub(crate) fn validate_name(name: &str) -> Result<(), OfferViolation> {
+    if name.is_empty() {
+        return Err(OfferViolation::EmptyName);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(OfferViolation::NameTooLong {
+            name: truncate(name),
+            len: name.len(),
+        });
+    }
+    if name.chars().any(char::is_control) {
+        return Err(OfferViolation::ControlCharacter(name.to_string()));
+    }
+    if name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(OfferViolation::NotAFileName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Check an offer's label, which is shown as it is, so senders can check
+/// theirs before offering it
+pub fn validate_label(label: &str) -> Result<(), OfferViolation> {
+    if label.len() > MAX_LABEL_LEN {
+        return Err(OfferViolation::LabelTooLong(label.len()));
+    }
+    if label.chars().any(char::is_control) {
+        return Err(OfferViolation::LabelControlCharacter(label.to_string()));
+    }
+    Ok(())
+}
+
+/// Check a `/`-separated path inside a directory offer
+fn validate_path(path: &str) -> Result<(), OfferViolation> {
+    if path.len() > MAX_PATH_LEN {
+        return Err(OfferViolation::PathTooLong {
+            path: truncate(path),
+            len: path.len(),
+        });
+    }
+    path.split('/').try_for_each(validate_name)
+}
+
+/// The start of an overlong name, enough to recognize it in an error
+fn truncate(name: &str) -> String {
+    let mut end = 64.min(name.len());
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}…", &name[..end])
+}
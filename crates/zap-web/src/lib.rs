@@ -1,9 +1,27 @@
+pub mod scan;
 pub mod server;
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use anyhow::Result;
 
+pub use server::ServerConfig;
+
 pub async fn run_server(addr: SocketAddr) -> Result<()> {
-    server::run(addr).await
+    server::run(addr, scan::from_env()?, ServerConfig::from_env()).await
+}
+
+/// Run the server, scanning staged files with a custom scanner
+pub async fn run_server_with_scanner(
+    addr: SocketAddr,
+    scanner: Arc<dyn scan::Scanner>,
+) -> Result<()> {
+    server::run(addr, Some(scanner), ServerConfig::from_env()).await
+}
+
+/// Run the server with settings given in code rather than read from the
+/// environment
+pub async fn run_server_with_config(addr: SocketAddr, config: ServerConfig) -> Result<()> {
+    server::run(addr, scan::from_env()?, config).await
 }
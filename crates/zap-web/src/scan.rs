@@ -0,0 +1,215 @@
+//! Malware scanning for files staged on the relay
+//!
+//! Staged files are scanned before they are sent on or offered for
+//! download. Configure a scanner with `ZAP_SCAN_CLAMD` (a clamd unix socket
+//! path or `host:port`) or `ZAP_SCAN_COMMAND` (a command run with the file
+//! path appended, where exit status 0 means clean and 1 means infected), or
+//! implement [`Scanner`] and pass it to [`crate::run_server_with_scanner`].
+//! Either variable set but empty is an error rather than no scanning, so a
+//! typo in the config doesn't let files through unscanned.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Outcome of scanning a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    Infected { reason: String },
+}
+
+/// Something that can decide whether a staged file is safe to hand out
+///
+/// Returning an error rejects the file, so a scanner outage fails closed.
+pub trait Scanner: Send + Sync {
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<Verdict>>;
+}
+
+/// Build the scanner configured in the environment, if any
+pub fn from_env() -> Result<Option<Arc<dyn Scanner>>> {
+    if let Ok(addr) = std::env::var("ZAP_SCAN_CLAMD") {
+        if addr.trim().is_empty() {
+            anyhow::bail!("ZAP_SCAN_CLAMD is set but empty");
+        }
+        return Ok(Some(Arc::new(ClamdScanner::new(addr.trim()))));
+    }
+    if let Ok(command) = std::env::var("ZAP_SCAN_COMMAND") {
+        let scanner = CommandScanner::parse(&command).context("invalid ZAP_SCAN_COMMAND")?;
+        return Ok(Some(Arc::new(scanner)));
+    }
+    Ok(None)
+}
+
+/// Scans files by streaming them to clamd with `INSTREAM`
+pub struct ClamdScanner {
+    addr: ClamdAddr,
+}
+
+enum ClamdAddr {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl ClamdScanner {
+    /// Connect to clamd at a unix socket path or a `host:port` address
+    pub fn new(addr: &str) -> Self {
+        let addr = if addr.starts_with('/') {
+            ClamdAddr::Unix(PathBuf::from(addr))
+        } else {
+            ClamdAddr::Tcp(addr.to_string())
+        };
+        Self { addr }
+    }
+
+    async fn scan_file(&self, path: &Path) -> Result<Verdict> {
+        let file = File::open(path).await?;
+        match &self.addr {
+            #[cfg(unix)]
+            ClamdAddr::Unix(socket) => {
+                let stream = tokio::net::UnixStream::connect(socket)
+                    .await
+                    .with_context(|| format!("connecting to clamd at {}", socket.display()))?;
+                instream(stream, file).await
+            }
+            #[cfg(not(unix))]
+            ClamdAddr::Unix(_) => anyhow::bail!("clamd unix sockets are not supported here"),
+            ClamdAddr::Tcp(addr) => {
+                let stream = tokio::net::TcpStream::connect(addr)
+                    .await
+                    .with_context(|| format!("connecting to clamd at {}", addr))?;
+                instream(stream, file).await
+            }
+        }
+    }
+}
+
+impl Scanner for ClamdScanner {
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<Verdict>> {
+        Box::pin(self.scan_file(path))
+    }
+}
+
+/// Send a file with clamd's `INSTREAM` command and parse the reply
+async fn instream<S>(mut stream: S, mut file: File) -> Result<Verdict>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    // Chunks are length-prefixed, and a zero length ends the stream
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        stream.write_all(&(n as u32).to_be_bytes()).await?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&buf[..n]).await?;
+    }
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    parse_clamd_reply(&String::from_utf8_lossy(&reply))
+}
+
+fn parse_clamd_reply(reply: &str) -> Result<Verdict> {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected {
+            reason: signature.to_string(),
+        })
+    } else {
+        anyhow::bail!("clamd: {}", reply)
+    }
+}
+
+/// Scans files by running an external command with the path appended
+///
+/// Follows the `clamscan` convention: exit status 0 is clean, 1 is
+/// infected, anything else is an error.
+pub struct CommandScanner {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandScanner {
+    /// Parse a command line split like a shell would, e.g.
+    /// `clamscan --no-summary` or `scan --config "/etc/my scanner.conf"`
+    pub fn parse(command: &str) -> Result<Self> {
+        let mut parts = shell_words::split(command)?.into_iter();
+        let program = parts.next().context("the command is empty")?;
+        Ok(Self {
+            program,
+            args: parts.collect(),
+        })
+    }
+
+    async fn scan_file(&self, path: &Path) -> Result<Verdict> {
+        let output = tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .arg(path)
+            .output()
+            .await
+            .with_context(|| format!("running {}", self.program))?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match output.status.code() {
+            Some(0) => Ok(Verdict::Clean),
+            Some(1) => Ok(Verdict::Infected {
+                reason: stdout
+                    .lines()
+                    .rfind(|l| !l.trim().is_empty())
+                    .unwrap_or("rejected by scanner")
+                    .to_string(),
+            }),
+            _ => anyhow::bail!(
+                "{} failed: {}",
+                self.program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        }
+    }
+}
+
+impl Scanner for CommandScanner {
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<Verdict>> {
+        Box::pin(self.scan_file(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(parse_clamd_reply("stream: OK\0").unwrap(), Verdict::Clean);
+        assert_eq!(
+            parse_clamd_reply("stream: Eicar-Signature FOUND\0").unwrap(),
+            Verdict::Infected {
+                reason: "Eicar-Signature".to_string()
+            }
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let scanner = CommandScanner::parse("scan --config '/etc/my scanner.conf' -q").unwrap();
+        assert_eq!(scanner.program, "scan");
+        assert_eq!(scanner.args, ["--config", "/etc/my scanner.conf", "-q"]);
+
+        assert!(CommandScanner::parse("").is_err());
+        assert!(CommandScanner::parse("   ").is_err());
+        assert!(CommandScanner::parse("scan 'unclosed").is_err());
+    }
+}
@@ -5,21 +5,62 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use axum::Router;
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
-use axum::extract::{DefaultBodyLimit, Multipart, Path, State};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
 use axum::response::{Html, IntoResponse, Response};
-use axum::routing::{get, post};
-use axum::Router;
+use axum::routing::{delete, get, post, put};
+use futures::{StreamExt, TryStreamExt};
 use iroh::SecretKey;
 use serde::{Deserialize, Serialize};
 use tokio::fs::{self, File};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::{mpsc, RwLock};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex, RwLock, mpsc, oneshot, watch};
+#[cfg(feature = "compression")]
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
-use tracing::{debug, info, warn};
+use tracing::{debug, info, instrument, warn};
 use uuid::Uuid;
-use zap_core::{ReceiveProgress, SendProgress, Ticket, ZapNode};
+use zap_core::protocol::DirEntry;
+use zap_core::{
+    Checksum, EntryChooser, FileMeta, OfferConfirm, ReceiveOptions, ReceiveProgress, RelayHint,
+    SendOptions, SendProgress, Ticket, TransferHandle, ZapNode,
+};
+
+use crate::scan::{Scanner, Verdict};
+
+mod accounting;
+mod admin;
+mod assets;
+mod catalog;
+mod code_format;
+mod codes;
+mod control;
+mod events;
+mod fetch;
+mod mirror;
+mod orphans;
+mod range;
+mod steering;
+mod tasks;
+mod tls;
+mod upload;
+
+use accounting::{Accounting, Direction};
+use assets::Precompressed;
+use catalog::{Catalog, Visibility};
+use code_format::{Alphabet, CodeFormat};
+use codes::CodeStore;
+use control::{ControlKey, ControlQuery, receiver_owner, receiver_secret};
+use events::{CodeEvent, CodeEvents};
+use mirror::MirrorStore;
+use orphans::OrphanPolicy;
+use steering::Steering;
+use tasks::{TaskKind, TaskRegistry};
+use tls::Tls;
+use upload::Upload;
 
 /// Maximum file size (1 GB)
 const MAX_FILE_SIZE: usize = 1024 * 1024 * 1024;
@@ -27,29 +68,112 @@ const MAX_FILE_SIZE: usize = 1024 * 1024 * 1024;
 /// How long to keep completed transfers before cleanup (1 hour)
 const TRANSFER_TTL: Duration = Duration::from_secs(60 * 60);
 
+/// How long a code keeps working (1 day)
+const CODE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long a code registered with an API key keeps working (1 week)
+const KEYED_CODE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 /// Cleanup interval (5 minutes)
 const CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
+/// How long the browser gets to confirm an offer or pick files from a
+/// folder offer (10 minutes)
+const CHOICE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Where to fetch the release manifest from unless `ZAP_RELEASE_MANIFEST` is set
+const DEFAULT_RELEASE_MANIFEST: &str =
+    "https://github.com/voidash/zapper.cloud/releases/latest/download/manifest.json";
+
+/// How long a fetched release manifest is served from cache (10 minutes)
+const RELEASE_MANIFEST_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How many generated codes registering a ticket tries before giving up,
+/// should they all be taken
+const CODE_ATTEMPTS: usize = 8;
+
 /// Generate a short, easy-to-share code (6 characters, alphanumeric)
 fn generate_short_code() -> String {
-    use rand::Rng;
-    const CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789"; // No confusing chars (0,1,i,l,o)
-    let mut rng = rand::rng();
-    (0..6)
-        .map(|_| {
-            let idx = rng.random_range(0..CHARSET.len());
-            CHARSET[idx] as char
-        })
-        .collect()
+    CodeFormat::default().generate()
 }
 
 /// Application state shared across handlers
 #[derive(Clone)]
 pub struct AppState {
     transfers: Arc<RwLock<HashMap<String, TransferState>>>,
-    /// Maps short codes to full tickets for easy sharing
-    ticket_codes: Arc<RwLock<HashMap<String, String>>>,
+    /// Maps short codes to full tickets for easy sharing, encrypted
+    codes: Arc<CodeStore>,
     temp_dir: PathBuf,
+    /// Cached release manifest and when it was fetched
+    release_manifest: Arc<RwLock<Option<(Instant, ReleaseManifest)>>>,
+    /// Bearer token for the admin pages; they are disabled when unset
+    admin_token: Option<String>,
+    /// Bandwidth usage per code and API key
+    accounting: Accounting,
+    /// Checks staged files before they leave the relay
+    scanner: Option<Arc<dyn Scanner>>,
+    /// Receives waiting for the browser to pick files, by transfer id
+    choices: Arc<Mutex<HashMap<String, oneshot::Sender<Vec<usize>>>>>,
+    /// Receives waiting for the browser to accept an offer, by transfer id
+    confirmations: Arc<Mutex<HashMap<String, oneshot::Sender<bool>>>>,
+    /// Signs the tokens pages control their transfers with
+    control_key: Arc<ControlKey>,
+    /// Private protocol namespace the relay's nodes speak, from `ZAP_ALPN`
+    alpn: Option<String>,
+    /// The relay's address as senders' receivers reach it, from
+    /// `ZAP_PUBLIC_URL`; the `Host` header of each request otherwise
+    public_url: Option<String>,
+    /// Whether pages can have the relay fetch a link and send it, from
+    /// `ZAP_FETCH_URLS`
+    fetch_urls: bool,
+    /// `/install.sh`, filled in from the [`ServerConfig`]
+    install_script: Arc<Precompressed>,
+    /// Send and receive tasks, by transfer id
+    tasks: Arc<TaskRegistry>,
+    /// Sealed files kept for `zap send --async`
+    mirror: Arc<MirrorStore>,
+    /// The iroh relay each client region is pointed at, from
+    /// `ZAP_IROH_RELAYS`
+    steering: Arc<Steering>,
+    /// Files senders have listed for anyone to receive, with
+    /// `ZAP_CATALOG`
+    catalog: Arc<Catalog>,
+    /// Lookups and downloads of registered codes, for their senders
+    events: Arc<CodeEvents>,
+    /// What codes registered without asking for a format are made of, from
+    /// `ZAP_CODE_ALPHABET` and `ZAP_CODE_LENGTH`
+    code_format: CodeFormat,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State with everything off but the admin token, staging in `temp_dir`
+    async fn for_test(temp_dir: PathBuf, admin_token: Option<&str>) -> Self {
+        fs::create_dir_all(&temp_dir).await.unwrap();
+        let script = ServerConfig::default().install_script().unwrap();
+        Self {
+            transfers: Arc::new(RwLock::new(HashMap::new())),
+            codes: Arc::new(CodeStore::new(None)),
+            release_manifest: Arc::new(RwLock::new(None)),
+            admin_token: admin_token.map(String::from),
+            accounting: Accounting::default(),
+            scanner: None,
+            choices: Arc::new(Mutex::new(HashMap::new())),
+            confirmations: Arc::new(Mutex::new(HashMap::new())),
+            control_key: Arc::new(ControlKey::generate()),
+            alpn: None,
+            public_url: None,
+            fetch_urls: false,
+            install_script: Arc::new(Precompressed::new(script, "text/x-shellscript")),
+            tasks: Arc::new(TaskRegistry::default()),
+            mirror: Arc::new(MirrorStore::from_env(&temp_dir).await.unwrap()),
+            steering: Arc::new(Steering::default()),
+            catalog: Arc::new(Catalog::new(false)),
+            events: Arc::new(CodeEvents::default()),
+            code_format: CodeFormat::default(),
+            temp_dir,
+        }
+    }
 }
 
 struct TransferState {
@@ -57,8 +181,31 @@ struct TransferState {
     ticket: Option<String>,
     short_code: Option<String>,
     file_name: Option<String>,
+    /// The sender's `--label`, once the offer is in
+    label: Option<String>,
+    /// Started from the receive form, whose code then finds it again
+    receive: bool,
+    /// For receives, the hash of the secret of the browser that started
+    /// them, the only one that can find them again by their code
+    owner: Option<String>,
     file_path: Option<PathBuf>,
+    /// Name of the API key that started the transfer, for accounting
+    api_key: Option<String>,
+    /// Hex BLAKE3 of the file at `file_path`, when known
+    checksum: Option<String>,
+    /// What the receive form was told the file's hash would be
+    expected_checksum: Option<Checksum>,
+    /// Set when the file is uploaded with `PUT /send/{id}`, which may still
+    /// be going on
+    upload: Option<Arc<watch::Sender<Upload>>>,
+    /// Delete the staged file once it's been downloaded or sent, instead of
+    /// keeping it until it expires
+    delete_after_download: bool,
+    /// Pauses and cancels a send for the page, once it has started
+    handle: Option<TransferHandle>,
     progress_tx: mpsc::Sender<ProgressUpdate>,
+    /// The latest throughput sample, sent along with progress
+    bytes_per_sec: Option<u64>,
     created_at: Instant,
     completed_at: Option<Instant>,
 }
@@ -69,21 +216,163 @@ enum TransferStatus {
     Pending,
     Waiting,
     Connected,
-    Transferring { bytes: u64, total: u64 },
-    Complete { path: Option<String> },
-    Error { message: String },
+    /// `total` is `None` when the sender is streaming data of unknown length
+    Transferring {
+        bytes: u64,
+        total: Option<u64>,
+    },
+    /// The page paused a send; the receiver is still connected
+    Paused,
+    /// An offer came in and the browser is asked to accept it; `fingerprint`
+    /// is the start of the sender's node id, `files` is set for folders
+    Confirming {
+        fingerprint: String,
+        sender: String,
+        name: String,
+        size: Option<u64>,
+        files: Option<usize>,
+        label: Option<String>,
+    },
+    /// A folder was offered and the browser is picking which files to get
+    Choosing {
+        entries: Vec<DirEntry>,
+    },
+    /// A staged file is being checked by the malware scanner
+    Scanning,
+    /// The browser is uploading the file; only sent to the page, alongside
+    /// the transfer's own status
+    Uploading {
+        bytes: u64,
+        total: u64,
+    },
+    /// A throughput sample for the page's speed graph, about once a second
+    /// while transferring; also only sent to the page
+    Throughput {
+        bytes_per_sec: u64,
+    },
+    /// `checksum` is the hex BLAKE3 of the file, for checking downloads
+    Complete {
+        path: Option<String>,
+        checksum: Option<String>,
+    },
+    Error {
+        message: String,
+    },
 }
 
 #[derive(Clone, Debug, Serialize)]
 struct ProgressUpdate {
+    /// Which transfer this is about, for pages following several
+    transfer_id: String,
     status: TransferStatus,
     #[serde(skip_serializing_if = "Option::is_none")]
     short_code: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    /// How much of the transfer, or of the upload while uploading, is
+    /// done, from 0 to 100, when its size is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent: Option<u8>,
+    /// The latest throughput sample, while transferring
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_per_sec: Option<u64>,
+}
+
+impl ProgressUpdate {
+    /// Tell the page following `transfer` about `status`, which needn't be
+    /// the transfer's own
+    fn new(transfer_id: &str, transfer: &TransferState, status: TransferStatus) -> Self {
+        let percent = match &status {
+            TransferStatus::Transferring {
+                bytes,
+                total: Some(total),
+            }
+            | TransferStatus::Uploading { bytes, total } => Some(percent(*bytes, *total)),
+            TransferStatus::Complete { .. } => Some(100),
+            _ => None,
+        };
+        let bytes_per_sec = match &status {
+            TransferStatus::Transferring { .. } | TransferStatus::Throughput { .. } => {
+                transfer.bytes_per_sec
+            }
+            _ => None,
+        };
+        Self {
+            transfer_id: transfer_id.to_string(),
+            status,
+            short_code: transfer.short_code.clone(),
+            file_name: transfer.file_name.clone(),
+            label: transfer.label.clone(),
+            percent,
+            bytes_per_sec,
+        }
+    }
 }
 
-pub async fn run(addr: SocketAddr) -> Result<()> {
+/// `bytes` of `total` in whole percent, only 100 once all of it is there
+fn percent(bytes: u64, total: u64) -> u8 {
+    if total == 0 {
+        return 100;
+    }
+    (u128::from(bytes.min(total)) * 100 / u128::from(total)) as u8
+}
+
+/// Relay settings that can be given in code; everything else is read from
+/// `ZAP_*` environment variables
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    /// GitHub repository `/install.sh` names for building from source
+    pub install_repo: String,
+    /// Where `/install.sh` puts `zap` if it can write there, before
+    /// falling back to `~/.local/bin`
+    pub install_dir: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            install_repo: "voidash/zapper.cloud".to_string(),
+            install_dir: "/usr/local/bin".to_string(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The defaults, changed by `ZAP_INSTALL_REPO` and `ZAP_INSTALL_DIR`
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        let defaults = Self::default();
+        Self {
+            install_repo: var("ZAP_INSTALL_REPO").unwrap_or(defaults.install_repo),
+            install_dir: var("ZAP_INSTALL_DIR").unwrap_or(defaults.install_dir),
+        }
+    }
+
+    /// The install script with these settings filled in
+    fn install_script(&self) -> Result<String> {
+        // Both end up inside double quotes in the script
+        for value in [&self.install_repo, &self.install_dir] {
+            if value.contains(['"', '\\', '`', '\n']) {
+                anyhow::bail!("{:?} can't be used in the install script", value);
+            }
+        }
+        Ok(INSTALL_SCRIPT
+            .replace("@REPO@", &self.install_repo)
+            .replace("@INSTALL_DIR@", &self.install_dir))
+    }
+}
+
+pub async fn run(
+    addr: SocketAddr,
+    scanner: Option<Arc<dyn Scanner>>,
+    config: ServerConfig,
+) -> Result<()> {
+    let script = Precompressed::new(config.install_script()?, "text/x-shellscript");
+    script.prepare();
+    let tls = Tls::from_env()?;
+
     let temp_dir = std::env::var("ZAP_TEMP_DIR")
         .map(PathBuf::from)
         .unwrap_or_else(|_| std::env::temp_dir().join("zap-uploads"));
@@ -91,50 +380,116 @@ pub async fn run(addr: SocketAddr) -> Result<()> {
     fs::create_dir_all(&temp_dir).await?;
     info!("using temp directory: {}", temp_dir.display());
 
+    let mirror = Arc::new(MirrorStore::from_env(&temp_dir).await?);
     let state = AppState {
         transfers: Arc::new(RwLock::new(HashMap::new())),
-        ticket_codes: Arc::new(RwLock::new(HashMap::new())),
+        codes: Arc::new(CodeStore::from_env().await?),
         temp_dir,
+        release_manifest: Arc::new(RwLock::new(None)),
+        admin_token: std::env::var("ZAP_ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+        accounting: Accounting::from_env()?,
+        scanner,
+        choices: Arc::new(Mutex::new(HashMap::new())),
+        confirmations: Arc::new(Mutex::new(HashMap::new())),
+        control_key: Arc::new(ControlKey::generate()),
+        alpn: std::env::var("ZAP_ALPN").ok().filter(|a| !a.is_empty()),
+        public_url: std::env::var("ZAP_PUBLIC_URL").ok().filter(|u| !u.is_empty()),
+        fetch_urls: std::env::var("ZAP_FETCH_URLS").is_ok_and(|v| v == "1" || v == "true"),
+        install_script: Arc::new(script),
+        tasks: Arc::new(TaskRegistry::default()),
+        mirror,
+        steering: Arc::new(Steering::from_env()?),
+        catalog: Arc::new(Catalog::from_env()),
+        events: Arc::new(CodeEvents::default()),
+        code_format: CodeFormat::from_env()?,
     };
 
+    // Transfers from a previous run are only known from what's on disk
+    orphans::recover(&state, OrphanPolicy::from_env()?).await?;
+
     // Start background cleanup task
     let cleanup_state = state.clone();
     tokio::spawn(async move {
         cleanup_loop(cleanup_state).await;
     });
 
+    INDEX_PAGE.prepare();
+    INSTALL_PAGE.prepare();
+    admin::ADMIN_PAGE.prepare();
+    catalog::CATALOG_PAGE.prepare();
+
     // Configure CORS for production
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
 
-    let app = Router::new()
+    let pages = Router::new()
         .route("/", get(index))
         .route("/health", get(health))
         .route("/ready", get(ready))
         .route("/install", get(install_page))
         .route("/install.sh", get(install_script))
+        .route("/catalog", get(catalog::catalog_page))
         .route("/send", post(handle_send))
+        .route("/send/start", post(upload::start_send))
+        .route("/send/url", post(fetch::fetch_url))
         .route("/receive", post(handle_receive))
-        .route("/ws/{id}", get(handle_websocket))
-        .route("/download/{id}", get(handle_download))
         // API routes for CLI support
         .route("/api/register", post(api_register_ticket))
         .route("/api/lookup/{code}", get(api_lookup_ticket))
+        .route("/api/catalog", get(catalog::api_catalog))
+        .route("/api/codes/{code}/events", get(events::api_code_events))
+        .route("/api/transfers/{id}/select", post(api_select_entries))
+        .route("/api/transfer/{id}/log", get(api_transfer_log))
+        .route("/api/releases/latest", get(api_latest_release))
+        .route("/api/releases/latest/{platform}", get(api_latest_release_artifact))
+        // Operator routes, enabled by ZAP_ADMIN_TOKEN
+        .route("/admin", get(admin::admin_page))
+        .route("/admin/api/files", get(admin::api_list_files))
+        .route("/admin/api/files/{name}", delete(admin::api_delete_file))
+        .route("/admin/api/purge", post(admin::api_purge))
+        .route("/admin/api/stats", get(admin::api_stats))
+        .route("/metrics", get(admin::metrics));
+    // Pages and the API are compressed; file data below is sent as is
+    #[cfg(feature = "compression")]
+    let pages = pages.layer(CompressionLayer::new());
+
+    let app = pages
+        .route("/send/{id}", put(upload::upload_file))
+        .route("/ws/{id}", get(handle_websocket))
+        .route("/download/{id}", get(handle_download))
+        .route("/api/mirror", post(mirror::upload))
+        .route("/api/mirror/{code}", get(mirror::download))
         .with_state(state)
         .layer(DefaultBodyLimit::max(MAX_FILE_SIZE))
         .layer(cors)
         .layer(TraceLayer::new_for_http());
-
-    info!("zap web server listening on {}", addr);
+    let app = match &tls {
+        Some(tls) if tls.client_auth => {
+            app.layer(axum::middleware::from_fn(tls::require_client_cert))
+        }
+        _ => app,
+    };
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
 
     // Graceful shutdown on SIGTERM
-    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    match tls {
+        Some(tls) => {
+            info!("zap web server listening on https://{}", addr);
+            tls::serve(listener, tls, app, shutdown_signal()).await?;
+        }
+        None => {
+            info!("zap web server listening on {}", addr);
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown_signal())
+            .await?;
+        }
+    }
 
     info!("server shut down gracefully");
     Ok(())
@@ -173,6 +528,29 @@ async fn cleanup_loop(state: AppState) {
 }
 
 async fn cleanup_old_transfers(state: &AppState) {
+    let expired = state.codes.prune().await;
+    if expired > 0 {
+        info!("forgot {} expired codes", expired);
+    }
+    let expired = state.events.prune();
+    if expired > 0 {
+        debug!("stopped following {} expired codes", expired);
+    }
+    let expired = state.mirror.prune().await;
+    if expired > 0 {
+        info!("deleted {} expired mirrored files", expired);
+    }
+    let mut gone = Vec::new();
+    for code in state.accounting.codes() {
+        if state.codes.get(&code).await.is_none() {
+            gone.push(code);
+        }
+    }
+    let expired = state.accounting.forget_codes(&gone);
+    if expired > 0 {
+        debug!("dropped the usage of {} expired codes", expired);
+    }
+
     let now = Instant::now();
     let mut to_remove = Vec::new();
 
@@ -196,6 +574,7 @@ async fn cleanup_old_transfers(state: &AppState) {
         let mut transfers = state.transfers.write().await;
         for (id, file_path) in to_remove {
             transfers.remove(&id);
+            zap_core::logs::remove(&id);
 
             // Clean up files
             if let Some(path) = file_path {
@@ -209,26 +588,30 @@ async fn cleanup_old_transfers(state: &AppState) {
             }
         }
     }
+
+    // A transfer's task can outlive the page that started it, e.g. a send
+    // waiting for a receiver, so it's stopped once the transfer is gone
+    let transfers = state.transfers.read().await;
+    let aborted = state.tasks.abort_where(|id| !transfers.contains_key(id));
+    if aborted > 0 {
+        info!("stopped {} tasks of expired transfers", aborted);
+    }
 }
 
-async fn index() -> Html<&'static str> {
-    Html(INDEX_HTML)
+async fn index(headers: HeaderMap) -> Response {
+    INDEX_PAGE.respond(&headers)
 }
 
 async fn health() -> &'static str {
     "ok"
 }
 
-async fn install_page() -> Html<&'static str> {
-    Html(INSTALL_HTML)
+async fn install_page(headers: HeaderMap) -> Response {
+    INSTALL_PAGE.respond(&headers)
 }
 
-async fn install_script() -> Response {
-    (
-        [(axum::http::header::CONTENT_TYPE, "text/x-shellscript")],
-        INSTALL_SCRIPT,
-    )
-        .into_response()
+async fn install_script(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    state.install_script.respond(&headers)
 }
 
 async fn ready(State(state): State<AppState>) -> Response {
@@ -237,7 +620,12 @@ async fn ready(State(state): State<AppState>) -> Response {
     match fs::write(&test_file, b"ok").await {
         Ok(_) => {
             let _ = fs::remove_file(&test_file).await;
-            "ready".into_response()
+            let active = state.tasks.active();
+            format!(
+                "ready, {} sending, {} receiving",
+                active.sends, active.receives
+            )
+            .into_response()
         }
         Err(e) => {
             (axum::http::StatusCode::SERVICE_UNAVAILABLE, format!("not ready: {}", e))
@@ -249,9 +637,34 @@ async fn ready(State(state): State<AppState>) -> Response {
 #[derive(Deserialize)]
 struct ReceiveForm {
     ticket: String,
+    /// The hash the receiver expects, see [`Checksum`]
+    #[serde(default)]
+    checksum: String,
 }
 
-async fn handle_send(State(state): State<AppState>, mut multipart: Multipart) -> Response {
+async fn handle_send(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    let api_key = match state.accounting.identify(&headers) {
+        Ok(key) => key,
+        Err(status) => {
+            return (
+                status,
+                Html(r##"<div class="text-red-400">Invalid API key</div>"##),
+            )
+                .into_response();
+        }
+    };
+    if let Err(status) = state.accounting.check_quota(api_key.as_deref()) {
+        return (
+            status,
+            Html(r##"<div class="text-red-400">Monthly quota exceeded</div>"##),
+        )
+            .into_response();
+    }
+
     let transfer_id = Uuid::new_v4().to_string();
     let transfer_dir = state.temp_dir.join(&transfer_id);
 
@@ -266,17 +679,34 @@ async fn handle_send(State(state): State<AppState>, mut multipart: Multipart) ->
     // Stream file to disk instead of loading into memory
     let mut file_name = None;
     let mut file_path = None;
+    let mut file_size = 0;
+    let mut checksum = None;
+    let mut delete_after_download = false;
 
     while let Ok(Some(field)) = multipart.next_field().await {
-        if field.name() == Some("file") {
+        if field.name() == Some("delete_after_download") {
+            let value = field.text().await.unwrap_or_default();
+            delete_after_download = matches!(value.as_str(), "on" | "true" | "1");
+        } else if field.name() == Some("file") && file_path.is_none() {
             let name = field.file_name().unwrap_or("file").to_string();
             let path = transfer_dir.join(&name);
 
-            // Stream to file
-            match stream_to_file(field, &path).await {
-                Ok(_) => {
+            // Stream to file, stopping at whatever quota the key has left
+            let limit = state.accounting.remaining(api_key.as_deref());
+            match stream_to_file(field, &path, limit).await {
+                Ok((size, hash)) => {
                     file_name = Some(name);
                     file_path = Some(path);
+                    file_size = size;
+                    checksum = Some(hash.to_hex().to_string());
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::FileTooLarge => {
+                    let _ = fs::remove_dir_all(&transfer_dir).await;
+                    return (
+                        axum::http::StatusCode::PAYLOAD_TOO_LARGE,
+                        Html(r##"<div class="text-red-400">File exceeds the remaining monthly quota</div>"##),
+                    )
+                        .into_response();
                 }
                 Err(e) => {
                     let _ = fs::remove_dir_all(&transfer_dir).await;
@@ -287,7 +717,6 @@ async fn handle_send(State(state): State<AppState>, mut multipart: Multipart) ->
                     .into_response();
                 }
             }
-            break;
         }
     }
 
@@ -303,6 +732,19 @@ async fn handle_send(State(state): State<AppState>, mut multipart: Multipart) ->
     // Create progress channel
     let (progress_tx, _) = mpsc::channel(32);
 
+    // The code is picked now so the upload can be accounted to it; it is
+    // registered once the node is up and has a ticket
+    let short_code = generate_short_code();
+    state
+        .accounting
+        .record_transfer(api_key.as_deref(), Some(&short_code));
+    state.accounting.record(
+        Direction::Staged,
+        api_key.as_deref(),
+        Some(&short_code),
+        file_size,
+    );
+
     // Store transfer state
     {
         let mut transfers = state.transfers.write().await;
@@ -311,143 +753,301 @@ async fn handle_send(State(state): State<AppState>, mut multipart: Multipart) ->
             TransferState {
                 status: TransferStatus::Pending,
                 ticket: None,
-                short_code: None,
+                short_code: Some(short_code),
                 file_name: Some(file_name.clone()),
+                label: None,
+                receive: false,
+                owner: None,
                 file_path: Some(file_path),
+                api_key,
+                checksum,
+                expected_checksum: None,
+                upload: None,
+                delete_after_download,
+                handle: None,
                 progress_tx,
+                bytes_per_sec: None,
                 created_at: Instant::now(),
                 completed_at: None,
             },
         );
     }
+    orphans::save_record(&state, &transfer_id).await;
+
+    let token = state.control_key.token(&transfer_id);
+    Html(send_status_html(&transfer_id, &file_name, &token)).into_response()
+}
 
-    Html(format!(
+/// The page fragment that follows a send over its WebSocket, controlling
+/// it with `token`
+///
+/// It's a card of its own, with no ids but its transfer's, so the page can
+/// show several at once.
+fn send_status_html(transfer_id: &str, file_name: &str, token: &str) -> String {
+    format!(
         r##"
-        <div id="transfer-status" class="text-center">
-            <div id="status-text" class="animate-pulse text-gray-400 mb-4">Starting transfer...</div>
+        <div id="transfer-{transfer_id}" class="transfer-card relative text-center mb-4 pb-4 border-b border-gray-700" data-transfer-id="{transfer_id}">
+            <button data-part="dismiss" class="hidden absolute top-0 right-0 px-2 text-gray-500 hover:text-gray-300" title="Dismiss">&times;</button>
+            <div data-part="status" class="animate-pulse text-gray-400 mb-4">Starting transfer...</div>
             <div class="text-sm text-gray-500 mb-4">File: {file_name}</div>
-            <div id="code-display" class="hidden">
+            <div data-part="upload" class="hidden text-xs text-gray-500 mb-4"></div>
+            <div data-part="code-display" class="hidden">
                 <p class="text-sm text-gray-400 mb-3">Share this code with the receiver:</p>
                 <div class="flex items-center justify-center gap-3">
-                    <code id="short-code" class="text-3xl font-bold tracking-widest text-cyan-400 bg-gray-800 px-6 py-3 rounded-lg"></code>
-                    <button onclick="navigator.clipboard.writeText(document.getElementById('short-code').textContent); this.textContent='Copied!'; setTimeout(() => this.textContent='Copy', 1500)"
-                            class="px-4 py-2 bg-cyan-600 hover:bg-cyan-500 rounded-lg text-sm font-medium transition">Copy</button>
+                    <code data-part="code" class="text-3xl font-bold tracking-widest text-cyan-400 bg-gray-800 px-6 py-3 rounded-lg"></code>
+                    <button data-part="copy" class="px-4 py-2 bg-cyan-600 hover:bg-cyan-500 rounded-lg text-sm font-medium transition">Copy</button>
                 </div>
             </div>
-            <div id="progress-bar" class="hidden mt-4 w-full bg-gray-700 rounded-full h-2">
-                <div id="progress-fill" class="bg-cyan-500 h-2 rounded-full transition-all" style="width: 0%"></div>
+            <div data-part="progress-bar" class="hidden mt-4 w-full bg-gray-700 rounded-full h-2">
+                <div data-part="progress-fill" class="bg-cyan-500 h-2 rounded-full transition-all" style="width: 0%"></div>
+            </div>
+            <div data-part="speed" class="hidden mt-3">
+                <svg class="w-full h-8 text-cyan-500" viewBox="0 0 60 20" preserveAspectRatio="none">
+                    <polyline fill="none" stroke="currentColor" stroke-width="1" vector-effect="non-scaling-stroke"></polyline>
+                </svg>
+                <div class="text-xs text-gray-500"></div>
             </div>
+            <div data-part="controls" class="hidden mt-4">
+                <button data-part="pause" class="hidden px-4 py-2 mr-2 bg-gray-700 hover:bg-gray-600 rounded-lg text-sm font-medium transition">Pause</button>
+                <button data-part="cancel" class="px-4 py-2 bg-gray-700 hover:bg-gray-600 rounded-lg text-sm font-medium transition">Cancel</button>
+            </div>
+            <button data-part="notify" class="hidden mt-4 text-xs text-gray-500 underline">Notify me when it's done</button>
+            <div data-part="checksum" class="hidden mt-4 text-xs text-gray-500 break-all"></div>
+            <a data-part="log-link" href="/api/transfer/{transfer_id}/log?token={token}" class="hidden mt-4 inline-block text-xs text-gray-500 underline">Download the transfer's log for a bug report</a>
         </div>
         <script>
             (function() {{
                 let completed = false;
-                const wsUrl = (location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/ws/{transfer_id}';
+                const speeds = [];
+                const card = document.getElementById('transfer-{transfer_id}');
+                const part = function(name) {{ return card.querySelector('[data-part="' + name + '"]'); }};
+                const wsUrl = (location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/ws/{transfer_id}?token={token}';
                 const ws = new WebSocket(wsUrl);
+                const statusText = part('status');
+                const codeDisplay = part('code-display');
+                const shortCode = part('code');
+                const progressBar = part('progress-bar');
+                const progressFill = part('progress-fill');
+                const controls = part('controls');
+                const pauseButton = part('pause');
+                const dismiss = part('dismiss');
+                dismiss.onclick = function() {{ card.remove(); }};
+                part('copy').onclick = function() {{ copyText(shortCode.textContent, this); }};
+                pauseButton.onclick = function() {{
+                    const pausing = pauseButton.textContent === 'Pause';
+                    ws.send(JSON.stringify({{ type: pausing ? 'pause' : 'resume' }}));
+                    pauseButton.textContent = pausing ? 'Resume' : 'Pause';
+                }};
+                part('cancel').onclick = function() {{
+                    ws.send(JSON.stringify({{ type: 'cancel' }}));
+                }};
+                offerNotifications(part('notify'));
                 ws.onmessage = function(event) {{
                     const data = JSON.parse(event.data);
-                    const statusText = document.getElementById('status-text');
-                    const codeDisplay = document.getElementById('code-display');
-                    const shortCode = document.getElementById('short-code');
-                    const progressBar = document.getElementById('progress-bar');
-                    const progressFill = document.getElementById('progress-fill');
-
                     if (data.short_code) {{
                         shortCode.textContent = data.short_code;
                         codeDisplay.classList.remove('hidden');
                     }}
 
                     switch(data.status.type) {{
+                        case 'Uploading':
+                            const uploaded = part('upload');
+                            const done = data.status.bytes >= data.status.total;
+                            uploaded.textContent = done ? 'Upload complete' : 'Uploading... ' + data.percent + '%';
+                            uploaded.classList.remove('hidden');
+                            break;
+                        case 'Throughput':
+                            plotSpeed(speeds, data.status.bytes_per_sec, part('speed'));
+                            break;
+                        case 'Scanning':
+                            statusText.textContent = 'Scanning file...';
+                            statusText.className = 'animate-pulse text-gray-400 mb-4';
+                            break;
                         case 'Waiting':
                             statusText.textContent = 'Waiting for receiver...';
                             statusText.className = 'animate-pulse text-yellow-400 mb-4';
+                            controls.classList.remove('hidden');
                             break;
                         case 'Connected':
                             statusText.textContent = 'Receiver connected! Transferring...';
                             statusText.className = 'text-cyan-400 mb-4';
                             codeDisplay.classList.add('hidden');
                             progressBar.classList.remove('hidden');
+                            controls.classList.remove('hidden');
+                            pauseButton.classList.remove('hidden');
                             break;
                         case 'Transferring':
-                            const pct = Math.round((data.status.bytes / data.status.total) * 100);
+                            const pct = data.percent ?? 0;
                             statusText.textContent = 'Transferring... ' + pct + '%';
+                            statusText.className = 'text-cyan-400 mb-4';
                             progressFill.style.width = pct + '%';
+                            pauseButton.textContent = 'Pause';
+                            showTabProgress('{transfer_id}', pct);
+                            break;
+                        case 'Paused':
+                            statusText.textContent = 'Paused';
+                            statusText.className = 'text-yellow-400 mb-4';
+                            pauseButton.textContent = 'Resume';
                             break;
                         case 'Complete':
                             completed = true;
                             statusText.textContent = 'Transfer complete!';
                             statusText.className = 'text-green-400 mb-4';
                             progressFill.style.width = '100%';
+                            controls.classList.add('hidden');
+                            dismiss.classList.remove('hidden');
+                            part('notify').classList.add('hidden');
+                            showChecksum(data.status.checksum, part('checksum'));
+                            showTabProgress('{transfer_id}', null);
+                            notifyDone('Sent ' + (data.file_name || 'the file'), 'The receiver has all of it');
                             break;
                         case 'Error':
                             statusText.textContent = 'Error: ' + data.status.message;
                             statusText.className = 'text-red-400 mb-4';
+                            controls.classList.add('hidden');
+                            dismiss.classList.remove('hidden');
+                            part('notify').classList.add('hidden');
+                            part('log-link').classList.remove('hidden');
+                            showTabProgress('{transfer_id}', null);
+                            notifyDone('Sending failed', data.status.message);
                             break;
                     }}
                 }};
                 ws.onerror = function() {{
                     if (!completed) {{
-                        document.getElementById('status-text').textContent = 'Connection error';
-                        document.getElementById('status-text').className = 'text-red-400 mb-4';
+                        statusText.textContent = 'Connection error';
+                        statusText.className = 'text-red-400 mb-4';
+                        dismiss.classList.remove('hidden');
                     }}
                 }};
                 ws.onclose = function() {{
                     if (!completed) {{
-                        document.getElementById('status-text').textContent = 'Connection closed';
-                        document.getElementById('status-text').className = 'text-red-400 mb-4';
+                        statusText.textContent = 'Connection closed';
+                        statusText.className = 'text-red-400 mb-4';
+                        dismiss.classList.remove('hidden');
                     }}
                 }};
             }})();
         </script>
         "##
-    ))
-    .into_response()
+    )
 }
 
 async fn stream_to_file(
     mut field: axum::extract::multipart::Field<'_>,
     path: &std::path::Path,
-) -> Result<u64, std::io::Error> {
+    limit: Option<u64>,
+) -> Result<(u64, blake3::Hash), std::io::Error> {
     let mut file = File::create(path).await?;
     let mut total = 0u64;
+    let mut hasher = blake3::Hasher::new();
 
     while let Some(chunk) = field
         .chunk()
         .await
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
     {
-        file.write_all(&chunk).await?;
         total += chunk.len() as u64;
+        if limit.is_some_and(|limit| total > limit) {
+            return Err(std::io::ErrorKind::FileTooLarge.into());
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
     }
 
     file.flush().await?;
-    Ok(total)
+    Ok((total, hasher.finalize()))
 }
 
 async fn handle_receive(
     State(state): State<AppState>,
+    headers: HeaderMap,
     axum::Form(form): axum::Form<ReceiveForm>,
 ) -> Response {
+    let api_key = match state.accounting.identify(&headers) {
+        Ok(key) => key,
+        Err(status) => {
+            return (
+                status,
+                Html(r##"<div class="text-red-400">Invalid API key</div>"##),
+            )
+                .into_response();
+        }
+    };
+    if let Err(status) = state.accounting.check_quota(api_key.as_deref()) {
+        return (
+            status,
+            Html(r##"<div class="text-red-400">Monthly quota exceeded</div>"##),
+        )
+            .into_response();
+    }
+
     let transfer_id = Uuid::new_v4().to_string();
     let input = form.ticket.trim().to_lowercase();
-
-    // Check if input is a short code (6 alphanumeric chars) or full ticket
-    let ticket_str = if input.len() <= 8 && input.chars().all(|c| c.is_alphanumeric()) {
-        // Look up short code (case-insensitive)
-        let codes = state.ticket_codes.read().await;
-        match codes.get(&input) {
-            Some(full_ticket) => full_ticket.clone(),
-            None => {
-                return Html(r##"<div class="text-red-400">Invalid code. Please check and try again.</div>"##.to_string())
-                    .into_response();
+    let expected_checksum = match form.checksum.trim() {
+        "" => None,
+        checksum => match checksum.parse::<Checksum>() {
+            Ok(checksum) => Some(checksum),
+            Err(e) => {
+                return Html(format!(r##"<div class="text-red-400">{}</div>"##, e)).into_response();
             }
-        }
+        },
+    };
+
+    // Check if input is a short or custom code, or a full ticket
+    let is_code = input.len() <= codes::MAX_CUSTOM_LEN
+        && input.chars().all(|c| c.is_alphanumeric() || c == '-');
+    let found = if is_code {
+        lookup_code(&state, &input).await
     } else {
-        input.to_string()
+        None
+    };
+    let code = found
+        .as_ref()
+        .map_or(input.clone(), |(code, _)| code.clone());
+
+    let (secret, set_cookie) = receiver_secret(
+        &headers,
+        public_url(&state, &headers).starts_with("https://"),
+    );
+    let owner = receiver_owner(&secret);
+    let respond = |html: String| match &set_cookie {
+        Some(cookie) => ([(header::SET_COOKIE, cookie.clone())], Html(html)).into_response(),
+        None => Html(html).into_response(),
     };
 
+    // The same code again from the same browser, e.g. after reloading the
+    // page, follows the receive already running or staged for it instead
+    // of pulling twice
+    if is_code && let Some(existing) = find_receive(&state, &code, &owner, api_key.as_deref()).await
+    {
+        let token = state.control_key.token(&existing);
+        return respond(receive_status_html(&existing, &token));
+    }
+
+    let mut delete_after_download = false;
+    let ticket_str = match found {
+        Some((code, full_ticket)) => {
+            delete_after_download = state.codes.deletes_after_download(&code).await;
+            full_ticket
+        }
+        None if is_code => {
+            return Html(
+                r##"<div class="text-red-400">Invalid code. Please check and try again.</div>"##
+                    .to_string(),
+            )
+            .into_response();
+        }
+        None => input.to_string(),
+    };
+    if ticket_str.starts_with(mirror::PREFIX) {
+        return Html(r##"<div class="text-red-400">This file was left on the relay for the zap app. Receive it with <code>zap receive</code>.</div>"##.to_string())
+            .into_response();
+    }
+
     // Validate ticket
     // Validate ticket format (actual transfer will be started when WebSocket connects)
-    let _ticket = match Ticket::deserialize(&ticket_str) {
+    let ticket = match Ticket::deserialize(&ticket_str) {
         Ok(t) => t,
         Err(e) => {
             return Html(format!(
@@ -458,9 +1058,23 @@ async fn handle_receive(
         }
     };
 
+    // The key is there to keep this server out of the transfer
+    if ticket.key_hash.is_some() {
+        return Html(r##"<div class="text-red-400">This transfer needs a key, so it can only be received with the zap CLI: zap receive &lt;code&gt;.&lt;key&gt;</div>"##.to_string())
+            .into_response();
+    }
+
     // Create progress channel
     let (progress_tx, _) = mpsc::channel(32);
 
+    let short_code = is_code.then_some(code);
+    state
+        .accounting
+        .record_transfer(api_key.as_deref(), short_code.as_deref());
+    if let Some(code) = &short_code {
+        state.events.record(code, CodeEvent::Claimed { web: true });
+    }
+
     // Store transfer state (use ticket_str which is the full ticket after short code lookup)
     {
         let mut transfers = state.transfers.write().await;
@@ -469,10 +1083,20 @@ async fn handle_receive(
             TransferState {
                 status: TransferStatus::Pending,
                 ticket: Some(ticket_str),
-                short_code: None,
+                short_code,
                 file_name: None,
+                label: None,
+                receive: true,
+                owner: Some(owner),
                 file_path: None,
+                api_key,
+                checksum: None,
+                expected_checksum,
+                upload: None,
+                delete_after_download,
+                handle: None,
                 progress_tx,
+                bytes_per_sec: None,
                 created_at: Instant::now(),
                 completed_at: None,
             },
@@ -482,78 +1106,264 @@ async fn handle_receive(
     // Note: receive task will be started when WebSocket connects (in handle_socket)
     // This ensures progress updates are sent to the correct channel
 
-    Html(format!(
+    let token = state.control_key.token(&transfer_id);
+    respond(receive_status_html(&transfer_id, &token))
+}
+
+/// The registered code `input` stands for, as typed or spelled out in
+/// words, and its ticket
+async fn lookup_code(state: &AppState, input: &str) -> Option<(String, String)> {
+    let code = input.to_lowercase();
+    if let Some(ticket) = state.codes.get(&code).await {
+        return Some((code, ticket));
+    }
+    if !code.contains('-') {
+        return None;
+    }
+    // Word-based code like "apple-banana-cherry"
+    let code = words_to_code(&code);
+    let ticket = state.codes.get(&code).await?;
+    Some((code, ticket))
+}
+
+/// The receive the browser `owner` started for `code`, with the same API
+/// key, that is still going or has its file staged, if there is one
+async fn find_receive(
+    state: &AppState,
+    code: &str,
+    owner: &str,
+    api_key: Option<&str>,
+) -> Option<String> {
+    let transfers = state.transfers.read().await;
+    transfers
+        .iter()
+        .filter(|(_, t)| t.receive && t.short_code.as_deref() == Some(code))
+        .filter(|(_, t)| t.owner.as_deref() == Some(owner))
+        .filter(|(_, t)| t.api_key.as_deref() == api_key)
+        .filter(|(_, t)| match t.status {
+            TransferStatus::Error { .. } => false,
+            TransferStatus::Complete { .. } => t.file_path.as_ref().is_some_and(|p| p.exists()),
+            _ => true,
+        })
+        .max_by_key(|(_, t)| t.created_at)
+        .map(|(id, _)| id.clone())
+}
+
+/// The page fragment that follows a receive over its WebSocket, a card of
+/// its own like [`send_status_html`]'s
+fn receive_status_html(transfer_id: &str, token: &str) -> String {
+    format!(
         r##"
-        <div id="recv-transfer-status" class="text-center">
-            <div id="recv-status-text" class="animate-pulse text-gray-400 mb-4">Connecting to sender...</div>
-            <div id="recv-progress-bar" class="hidden mt-4 w-full bg-gray-700 rounded-full h-2">
-                <div id="recv-progress-fill" class="bg-purple-500 h-2 rounded-full transition-all" style="width: 0%"></div>
+        <div id="transfer-{transfer_id}" class="transfer-card relative text-center mb-4 pb-4 border-b border-gray-700" data-transfer-id="{transfer_id}">
+            <button data-part="dismiss" class="hidden absolute top-0 right-0 px-2 text-gray-500 hover:text-gray-300" title="Dismiss">&times;</button>
+            <div data-part="status" class="animate-pulse text-gray-400 mb-4">Connecting to sender...</div>
+            <div data-part="label" class="hidden -mt-2 mb-4 text-sm text-gray-300 break-all"></div>
+            <div data-part="progress-bar" class="hidden mt-4 w-full bg-gray-700 rounded-full h-2">
+                <div data-part="progress-fill" class="bg-purple-500 h-2 rounded-full transition-all" style="width: 0%"></div>
             </div>
-            <div id="recv-download-link" class="hidden mt-4"></div>
+            <div data-part="speed" class="hidden mt-3">
+                <svg class="w-full h-8 text-purple-500" viewBox="0 0 60 20" preserveAspectRatio="none">
+                    <polyline fill="none" stroke="currentColor" stroke-width="1" vector-effect="non-scaling-stroke"></polyline>
+                </svg>
+                <div class="text-xs text-gray-500"></div>
+            </div>
+            <div data-part="confirm" class="hidden mt-4"></div>
+            <div data-part="choose" class="hidden mt-4 text-left"></div>
+            <div data-part="download-link" class="hidden mt-4"></div>
+            <div data-part="checksum" class="hidden mt-4 text-xs text-gray-500 break-all"></div>
+            <a data-part="log-link" href="/api/transfer/{transfer_id}/log?token={token}" class="hidden mt-4 block text-xs text-gray-500 underline">Download the transfer's log for a bug report</a>
+            <button data-part="notify" class="hidden mt-4 block mx-auto text-xs text-gray-500 underline">Notify me when it's done</button>
+            <button data-part="cancel" class="mt-4 px-4 py-2 bg-gray-700 hover:bg-gray-600 rounded-lg text-sm font-medium transition">Cancel</button>
         </div>
         <script>
             (function() {{
                 let completed = false;
-                const wsUrl = (location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/ws/{transfer_id}';
+                const speeds = [];
+                const card = document.getElementById('transfer-{transfer_id}');
+                const part = function(name) {{ return card.querySelector('[data-part="' + name + '"]'); }};
+                const wsUrl = (location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/ws/{transfer_id}?token={token}';
                 const ws = new WebSocket(wsUrl);
+                const statusText = part('status');
+                const progressBar = part('progress-bar');
+                const progressFill = part('progress-fill');
+                const downloadLink = part('download-link');
+                const cancelButton = part('cancel');
+                const dismiss = part('dismiss');
+                dismiss.onclick = function() {{ card.remove(); }};
+                cancelButton.onclick = function() {{
+                    ws.send(JSON.stringify({{ type: 'cancel' }}));
+                }};
+                offerNotifications(part('notify'));
                 ws.onmessage = function(event) {{
                     const data = JSON.parse(event.data);
-                    const statusText = document.getElementById('recv-status-text');
-                    const progressBar = document.getElementById('recv-progress-bar');
-                    const progressFill = document.getElementById('recv-progress-fill');
-                    const downloadLink = document.getElementById('recv-download-link');
+                    if (data.label) {{
+                        const label = part('label');
+                        label.textContent = data.label;
+                        label.classList.remove('hidden');
+                    }}
 
                     switch(data.status.type) {{
+                        case 'Scanning':
+                            statusText.textContent = 'Scanning file...';
+                            statusText.className = 'animate-pulse text-purple-400 mb-4';
+                            break;
                         case 'Connected':
                             statusText.textContent = 'Connected! Receiving file...';
                             statusText.className = 'text-purple-400 mb-4';
                             progressBar.classList.remove('hidden');
                             break;
+                        case 'Confirming':
+                            statusText.textContent = 'Accept this transfer?';
+                            statusText.className = 'text-purple-400 mb-4';
+                            showConfirm(data.status);
+                            break;
+                        case 'Choosing':
+                            statusText.textContent = 'Pick the files to receive';
+                            statusText.className = 'text-purple-400 mb-4';
+                            showChoices(data.status.entries);
+                            break;
+                        case 'Throughput':
+                            plotSpeed(speeds, data.status.bytes_per_sec, part('speed'));
+                            break;
                         case 'Transferring':
-                            const pct = Math.round((data.status.bytes / data.status.total) * 100);
-                            statusText.textContent = 'Receiving... ' + pct + '%';
-                            progressFill.style.width = pct + '%';
+                            if (data.percent != null) {{
+                                statusText.textContent = 'Receiving... ' + data.percent + '%';
+                                progressFill.style.width = data.percent + '%';
+                                showTabProgress('{transfer_id}', data.percent);
+                            }} else {{
+                                statusText.textContent = 'Receiving... ' + (data.status.bytes / 1048576).toFixed(1) + ' MB';
+                            }}
                             break;
                         case 'Complete':
                             completed = true;
                             statusText.textContent = 'Transfer complete!';
                             statusText.className = 'text-green-400 mb-4';
                             progressFill.style.width = '100%';
+                            cancelButton.classList.add('hidden');
+                            dismiss.classList.remove('hidden');
                             if (data.status.path) {{
-                                downloadLink.innerHTML = '<a href="/download/{transfer_id}" class="inline-block mt-2 px-6 py-2 bg-purple-600 hover:bg-purple-500 rounded-lg font-medium">Download ' + (data.file_name || 'File') + '</a>';
+                                const link = document.createElement('a');
+                                link.href = '/download/{transfer_id}';
+                                link.className = 'inline-block mt-2 px-6 py-2 bg-purple-600 hover:bg-purple-500 rounded-lg font-medium';
+                                link.textContent = 'Download ' + (data.file_name || 'File');
+                                downloadLink.replaceChildren(link);
                                 downloadLink.classList.remove('hidden');
                             }}
+                            showChecksum(data.status.checksum, part('checksum'));
+                            part('notify').classList.add('hidden');
+                            showTabProgress('{transfer_id}', null);
+                            notifyDone('Received ' + (data.file_name || 'the file'), 'Ready to download');
                             break;
                         case 'Error':
                             statusText.textContent = 'Error: ' + data.status.message;
                             statusText.className = 'text-red-400 mb-4';
+                            cancelButton.classList.add('hidden');
+                            dismiss.classList.remove('hidden');
+                            part('notify').classList.add('hidden');
+                            part('log-link').classList.remove('hidden');
+                            showTabProgress('{transfer_id}', null);
+                            notifyDone('Receiving failed', data.status.message);
                             break;
                     }}
                 }};
+                function showConfirm(offer) {{
+                    const box = part('confirm');
+                    box.replaceChildren();
+                    const name = document.createElement('div');
+                    name.className = 'font-medium break-all';
+                    let details = offer.size == null ? 'size unknown' : (offer.size / 1048576).toFixed(1) + ' MB';
+                    if (offer.files != null) {{
+                        details = 'folder, ' + offer.files + ' files, ' + details;
+                    }}
+                    name.textContent = offer.name + ' (' + details + ')';
+                    const label = document.createElement('div');
+                    label.className = 'mt-1 text-sm text-gray-300 break-all';
+                    label.textContent = offer.label || '';
+                    const sender = document.createElement('div');
+                    sender.className = 'mt-2 text-sm text-gray-400';
+                    sender.textContent = 'From ';
+                    const fingerprint = document.createElement('code');
+                    fingerprint.className = 'text-purple-300';
+                    fingerprint.textContent = offer.fingerprint;
+                    fingerprint.title = offer.sender;
+                    sender.append(fingerprint);
+                    const hint = document.createElement('div');
+                    hint.className = 'text-xs text-gray-500';
+                    hint.textContent = "The start of the sender's node id; hover for all of it";
+                    const answer = function(type) {{
+                        ws.send(JSON.stringify({{ type: type }}));
+                        box.classList.add('hidden');
+                        statusText.textContent = type === 'accept' ? 'Accepted, waiting for data...' : 'Rejected';
+                    }};
+                    const accept = document.createElement('button');
+                    accept.textContent = 'Accept';
+                    accept.className = 'mt-3 mr-2 px-6 py-2 bg-purple-600 hover:bg-purple-500 rounded-lg font-medium';
+                    accept.onclick = function() {{ answer('accept'); }};
+                    const reject = document.createElement('button');
+                    reject.textContent = 'Reject';
+                    reject.className = 'mt-3 px-6 py-2 bg-gray-700 hover:bg-gray-600 rounded-lg font-medium';
+                    reject.onclick = function() {{ answer('reject'); }};
+                    box.append(name, label, sender, hint, accept, reject);
+                    box.classList.remove('hidden');
+                }}
+                function showChoices(entries) {{
+                    const list = part('choose');
+                    list.replaceChildren();
+                    entries.forEach(function(entry, i) {{
+                        const label = document.createElement('label');
+                        label.className = 'flex items-center gap-2 py-1 break-all';
+                        const box = document.createElement('input');
+                        box.type = 'checkbox';
+                        box.checked = true;
+                        box.value = i;
+                        label.append(box, entry.path + ' (' + (entry.size / 1048576).toFixed(1) + ' MB)');
+                        list.append(label);
+                    }});
+                    const button = document.createElement('button');
+                    button.textContent = 'Receive selected';
+                    button.className = 'mt-2 px-6 py-2 bg-purple-600 hover:bg-purple-500 rounded-lg font-medium';
+                    button.onclick = function() {{
+                        const chosen = Array.from(list.querySelectorAll('input:checked')).map(function(box) {{ return Number(box.value); }});
+                        list.classList.add('hidden');
+                        fetch('/api/transfers/{transfer_id}/select?token={token}', {{
+                            method: 'POST',
+                            headers: {{ 'Content-Type': 'application/json' }},
+                            body: JSON.stringify({{ entries: chosen }})
+                        }});
+                    }};
+                    list.append(button);
+                    list.classList.remove('hidden');
+                }}
                 ws.onerror = function() {{
                     if (!completed) {{
-                        document.getElementById('recv-status-text').textContent = 'Connection error';
-                        document.getElementById('recv-status-text').className = 'text-red-400 mb-4';
+                        statusText.textContent = 'Connection error';
+                        statusText.className = 'text-red-400 mb-4';
+                        dismiss.classList.remove('hidden');
                     }}
                 }};
                 ws.onclose = function() {{
                     if (!completed) {{
-                        document.getElementById('recv-status-text').textContent = 'Connection closed';
-                        document.getElementById('recv-status-text').className = 'text-red-400 mb-4';
+                        statusText.textContent = 'Connection closed';
+                        statusText.className = 'text-red-400 mb-4';
+                        dismiss.classList.remove('hidden');
                     }}
                 }};
             }})();
         </script>
         "##
-    ))
-    .into_response()
+    )
 }
 
 async fn handle_websocket(
     State(state): State<AppState>,
     Path(transfer_id): Path<String>,
+    Query(query): Query<ControlQuery>,
     ws: WebSocketUpgrade,
 ) -> Response {
+    // The socket starts the transfer and takes commands for it
+    if !state.control_key.verify(&transfer_id, &query.token) {
+        return control::forbidden();
+    }
     ws.on_upgrade(move |socket| handle_socket(socket, state, transfer_id))
 }
 
@@ -564,7 +1374,7 @@ async fn handle_socket(mut socket: WebSocket, state: AppState, transfer_id: Stri
     let (tx, mut rx) = mpsc::channel::<ProgressUpdate>(32);
 
     // Check what kind of transfer this is and update channel
-    let (should_start_send, should_start_receive, ticket_str) = {
+    let (should_start_send, should_start_receive, ticket_str, current) = {
         let mut transfers = state.transfers.write().await;
         if let Some(transfer) = transfers.get_mut(&transfer_id) {
             // Update channel before starting any transfer
@@ -573,11 +1383,29 @@ async fn handle_socket(mut socket: WebSocket, state: AppState, transfer_id: Stri
             let is_send = matches!(transfer.status, TransferStatus::Pending) && transfer.file_path.is_some();
             let is_receive = matches!(transfer.status, TransferStatus::Pending) && transfer.ticket.is_some() && transfer.file_path.is_none();
             let ticket = transfer.ticket.clone();
-            (is_send, is_receive, ticket)
+            // A page coming back to a transfer that's under way starts
+            // from where it is
+            let current = (!is_send && !is_receive)
+                .then(|| ProgressUpdate::new(&transfer_id, transfer, transfer.status.clone()));
+            (is_send, is_receive, ticket, current)
         } else {
-            (false, false, None)
+            (false, false, None, None)
         }
     };
+    if let Some(update) = current {
+        let done = matches!(
+            update.status,
+            TransferStatus::Complete { .. } | TransferStatus::Error { .. }
+        );
+        if socket
+            .send(Message::Text(render_progress(&update).into()))
+            .await
+            .is_err()
+            || done
+        {
+            return;
+        }
+    }
 
     if should_start_send {
         // Generate secret key before spawning (ThreadRng is !Send)
@@ -585,7 +1413,7 @@ async fn handle_socket(mut socket: WebSocket, state: AppState, transfer_id: Stri
 
         let state_clone = state.clone();
         let transfer_id_clone = transfer_id.clone();
-        tokio::spawn(async move {
+        state.tasks.spawn(&transfer_id, TaskKind::Send, async move {
             run_send_transfer(state_clone, transfer_id_clone, secret_key).await;
         });
     }
@@ -596,16 +1424,22 @@ async fn handle_socket(mut socket: WebSocket, state: AppState, transfer_id: Stri
                 let secret_key = SecretKey::generate(&mut rand::rng());
                 let state_clone = state.clone();
                 let transfer_id_clone = transfer_id.clone();
-                tokio::spawn(async move {
+                state.tasks.spawn(&transfer_id, TaskKind::Receive, async move {
                     run_receive_transfer(state_clone, transfer_id_clone, ticket, secret_key).await;
                 });
             }
         }
     }
 
-    // Listen for progress updates and send to WebSocket
+    // Listen for progress updates and send to WebSocket, and for the
+    // page's answers
     loop {
         tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => control::handle(&state, &transfer_id, &text).await,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
             Some(update) = rx.recv() => {
                 let html = render_progress(&update);
                 if socket.send(Message::Text(html.into())).await.is_err() {
@@ -625,36 +1459,144 @@ async fn handle_socket(mut socket: WebSocket, state: AppState, transfer_id: Stri
 async fn handle_download(
     State(state): State<AppState>,
     Path(transfer_id): Path<String>,
+    headers: HeaderMap,
 ) -> Response {
     let transfers = state.transfers.read().await;
     if let Some(transfer) = transfers.get(&transfer_id) {
+        // A file still being uploaded can't be served whole yet
+        let uploading = transfer
+            .upload
+            .as_ref()
+            .is_some_and(|u| !u.borrow().is_complete());
         if let Some(ref path) = transfer.file_path {
-            if path.exists() {
+            if path.exists() && !uploading {
                 let file_name = transfer
                     .file_name
                     .clone()
                     .unwrap_or_else(|| "file".to_string());
 
+                // Downloads count against the key that started the transfer
+                if let Err(status) = state.accounting.check_quota(transfer.api_key.as_deref()) {
+                    return (status, "Monthly quota exceeded").into_response();
+                }
+
                 // Use tokio_util for streaming instead of loading into memory
-                match File::open(path).await {
-                    Ok(file) => {
-                        let stream = tokio_util::io::ReaderStream::new(file);
+                let opened = async {
+                    let file = File::open(path).await?;
+                    let metadata = file.metadata().await?;
+                    std::io::Result::Ok((file, metadata))
+                };
+                match opened.await {
+                    Ok((mut file, metadata)) => {
+                        let len = metadata.len();
+                        // Both stay the same when the file is adopted after
+                        // a restart, so resuming works across one
+                        let etag = transfer.checksum.as_ref().map(|c| format!("\"{}\"", c));
+                        let last_modified = metadata.modified().ok().map(assets::http_date);
+                        let requested = range::requested(
+                            &headers,
+                            len,
+                            etag.as_deref(),
+                            last_modified.as_deref(),
+                        );
+                        let (start, end) = match requested {
+                            range::Requested::Full => (0, len.saturating_sub(1)),
+                            range::Requested::Partial { start, end } => (start, end),
+                            range::Requested::Unsatisfiable => {
+                                return (
+                                    StatusCode::RANGE_NOT_SATISFIABLE,
+                                    [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+                                )
+                                    .into_response();
+                            }
+                        };
+                        if start > 0
+                            && let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await
+                        {
+                            return Html(format!("Error reading file: {}", e)).into_response();
+                        }
+                        let served = if len == 0 { 0 } else { end - start + 1 };
+
+                        let accounting = state.accounting.clone();
+                        let api_key = transfer.api_key.clone();
+                        let short_code = transfer.short_code.clone();
+                        let reader = tokio_util::io::ReaderStream::new(file.take(served));
+                        let stream = reader.inspect_ok(move |chunk| {
+                            accounting.record(
+                                Direction::Served,
+                                api_key.as_deref(),
+                                short_code.as_deref(),
+                                chunk.len() as u64,
+                            );
+                        });
+
+                        // Only a download that got to the end counts; the
+                        // file is closed by the time the chained cleanup runs
+                        let to_end = end + 1 >= len;
+                        let cleanup = (transfer.delete_after_download && to_end).then(|| {
+                            let state = state.clone();
+                            futures::stream::once(async move {
+                                discard_staged(&state, &transfer_id).await;
+                            })
+                            .filter_map(|()| async { None })
+                        });
+                        // The sender hears of each receive through the
+                        // relay that's downloaded in full
+                        let downloaded = transfer
+                            .short_code
+                            .clone()
+                            .filter(|_| transfer.receive && to_end);
+                        let downloaded = downloaded.map(|code| {
+                            let state = state.clone();
+                            let file_name = transfer.file_name.clone();
+                            futures::stream::once(async move {
+                                state
+                                    .events
+                                    .record(&code, CodeEvent::Downloaded { file_name });
+                            })
+                            .filter_map(|()| async { None })
+                        });
+                        let stream = stream
+                            .chain(futures::stream::iter(downloaded).flatten())
+                            .chain(futures::stream::iter(cleanup).flatten());
                         let body = axum::body::Body::from_stream(stream);
 
-                        return (
+                        let mut response = (
                             [
+                                (header::CONTENT_TYPE, "application/octet-stream".to_string()),
                                 (
-                                    axum::http::header::CONTENT_TYPE,
-                                    "application/octet-stream",
-                                ),
-                                (
-                                    axum::http::header::CONTENT_DISPOSITION,
-                                    &format!("attachment; filename=\"{}\"", file_name),
+                                    header::CONTENT_DISPOSITION,
+                                    format!("attachment; filename=\"{}\"", file_name),
                                 ),
+                                (header::CONTENT_LENGTH, served.to_string()),
+                                (header::ACCEPT_RANGES, "bytes".to_string()),
                             ],
                             body,
                         )
                             .into_response();
+                        if let range::Requested::Partial { start, end } = requested {
+                            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                            let content_range = format!("bytes {}-{}/{}", start, end, len);
+                            if let Ok(value) = HeaderValue::from_str(&content_range) {
+                                response.headers_mut().insert(header::CONTENT_RANGE, value);
+                            }
+                        }
+                        let validators =
+                            [(header::ETAG, etag), (header::LAST_MODIFIED, last_modified)];
+                        for (name, value) in validators {
+                            let value = value.and_then(|v| HeaderValue::from_str(&v).ok());
+                            if let Some(value) = value {
+                                response.headers_mut().insert(name, value);
+                            }
+                        }
+
+                        // Lets clients verify the download against the sender's hash
+                        if let Some(checksum) = &transfer.checksum
+                            && let Ok(value) = axum::http::HeaderValue::from_str(checksum)
+                        {
+                            response.headers_mut().insert("x-zap-checksum", value);
+                        }
+                        return response;
                     }
                     Err(e) => {
                         return Html(format!("Error reading file: {}", e)).into_response();
@@ -672,92 +1614,459 @@ async fn handle_download(
 struct RegisterTicketRequest {
     ticket: String,
     #[serde(default)]
-    #[allow(dead_code)]
     file_name: Option<String>,
+    /// The file's size, shown in the catalog
+    #[serde(default)]
+    size: Option<u64>,
+    /// Custom code to register the ticket under instead of a generated one
+    #[serde(default)]
+    code: Option<String>,
+    /// Delete what web receivers fetch through the relay once they've
+    /// downloaded it
+    #[serde(default)]
+    delete_after_download: bool,
+    /// List the code in the catalog
+    #[serde(default)]
+    list: Option<Visibility>,
+    /// Generate the code from these characters or words instead of the
+    /// relay's
+    #[serde(default)]
+    alphabet: Option<Alphabet>,
+    /// Characters or words in the generated code
+    #[serde(default)]
+    length: Option<usize>,
 }
 
 #[derive(Serialize)]
 struct RegisterTicketResponse {
     code: String,
     words: String,
+    /// The code with this relay's address, which works against any relay
+    token: String,
+    /// Follows the code's lookups and downloads at
+    /// `/api/codes/{code}/events`
+    events_token: String,
 }
 
 #[derive(Serialize)]
 struct LookupTicketResponse {
-    ticket: String,
+    /// Left out for a code that names a file kept on the relay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ticket: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     file_name: Option<String>,
+    /// Fetch the sealed file from `/api/mirror/{code}` instead of
+    /// connecting to a sender
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    mirror: bool,
+    /// The iroh relay nearest the client, when this relay knows of one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iroh_relay: Option<String>,
 }
 
 /// API endpoint for CLI to register a ticket and get a short code
 async fn api_register_ticket(
     State(state): State<AppState>,
+    headers: HeaderMap,
     axum::Json(req): axum::Json<RegisterTicketRequest>,
 ) -> Response {
+    let api_key = match state.accounting.identify(&headers) {
+        Ok(key) => key,
+        Err(status) => {
+            return (
+                status,
+                axum::Json(serde_json::json!({"error": "Invalid API key"})),
+            )
+                .into_response();
+        }
+    };
+    if let Err(status) = state.accounting.check_quota(api_key.as_deref()) {
+        return (
+            status,
+            axum::Json(serde_json::json!({"error": "Monthly quota exceeded"})),
+        )
+            .into_response();
+    }
+
     // Validate the ticket is parseable
-    if Ticket::deserialize(&req.ticket).is_err() {
+    let Ok(ticket) = Ticket::deserialize(&req.ticket) else {
         return (
             axum::http::StatusCode::BAD_REQUEST,
             axum::Json(serde_json::json!({"error": "Invalid ticket format"})),
         )
             .into_response();
+    };
+    if ticket.check_expiry().is_err() {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            axum::Json(serde_json::json!({"error": "Ticket has expired"})),
+        )
+            .into_response();
     }
 
-    // Generate short code
-    let short_code = generate_short_code();
-    let words = code_to_words(&short_code);
+    // Senders with an API key get to keep their code for longer, and no
+    // code outlives its ticket
+    let ttl = if api_key.is_some() {
+        KEYED_CODE_TTL
+    } else {
+        CODE_TTL
+    };
+    let ttl = ticket
+        .remaining()
+        .map_or(ttl, |remaining| ttl.min(remaining));
 
-    // Store the mapping
+    if let Some(visibility) = req.list
+        && let Err((status, message)) = state.catalog.check(visibility, api_key.as_deref())
     {
-        let mut codes = state.ticket_codes.write().await;
-        codes.insert(short_code.clone(), req.ticket.clone());
+        return (status, axum::Json(serde_json::json!({ "error": message }))).into_response();
+    }
+
+    let (short_code, words) = match req.code {
+        Some(code) => {
+            if api_key.is_none() {
+                return (
+                    axum::http::StatusCode::FORBIDDEN,
+                    axum::Json(serde_json::json!({"error": "Custom codes need an API key"})),
+                )
+                    .into_response();
+            }
+            let code = match state.codes.check_custom(&code) {
+                Ok(code) => code,
+                Err(message) => {
+                    return (
+                        axum::http::StatusCode::BAD_REQUEST,
+                        axum::Json(serde_json::json!({ "error": message })),
+                    )
+                        .into_response();
+                }
+            };
+            let claimed = state
+                .codes
+                .claim(&code, &req.ticket, ttl, req.delete_after_download)
+                .await;
+            if !claimed {
+                return (
+                    axum::http::StatusCode::CONFLICT,
+                    axum::Json(serde_json::json!({"error": "Code is already taken"})),
+                )
+                    .into_response();
+            }
+            // A custom code is as easy to say as it gets
+            (code.clone(), code)
+        }
+        None => {
+            let format = match (req.alphabet, req.length) {
+                (None, None) => Ok(state.code_format),
+                (alphabet, length) => {
+                    CodeFormat::new(alphabet.unwrap_or(state.code_format.alphabet), length)
+                }
+            };
+            let format = match format {
+                Ok(format) => format,
+                Err(e) => {
+                    return (
+                        axum::http::StatusCode::BAD_REQUEST,
+                        axum::Json(serde_json::json!({ "error": e.to_string() })),
+                    )
+                        .into_response();
+                }
+            };
+            // Digit and word codes can be as long as custom ones, so a
+            // code is only handed out once it's known to be free
+            let mut claimed = None;
+            for _ in 0..CODE_ATTEMPTS {
+                let code = format.generate();
+                if state
+                    .codes
+                    .claim(&code, &req.ticket, ttl, req.delete_after_download)
+                    .await
+                {
+                    claimed = Some(code);
+                    break;
+                }
+            }
+            let Some(short_code) = claimed else {
+                return (
+                    axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                    axum::Json(serde_json::json!({"error": "No free code, try a longer one"})),
+                )
+                    .into_response();
+            };
+            let words = format.words(&short_code);
+            (short_code, words)
+        }
+    };
+    state
+        .accounting
+        .record_transfer(api_key.as_deref(), Some(&short_code));
+    state.events.open(&short_code, ttl);
+    if let Some(visibility) = req.list {
+        let name = req.file_name.as_deref().unwrap_or("file");
+        state.catalog.add(
+            &short_code,
+            name,
+            req.size,
+            ttl,
+            visibility,
+            api_key.as_deref(),
+        );
     }
 
+    let token = RelayHint::new(&public_url(&state, &headers), &short_code).token();
+    let events_token = events::token(&state.control_key, &short_code);
     axum::Json(RegisterTicketResponse {
         code: short_code,
         words,
+        token,
+        events_token,
     })
     .into_response()
 }
 
+/// This relay's address, as the request reached it unless `ZAP_PUBLIC_URL`
+/// says otherwise
+fn public_url(state: &AppState, headers: &HeaderMap) -> String {
+    if let Some(url) = &state.public_url {
+        return url.clone();
+    }
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let host = header(axum::http::header::HOST).unwrap_or("localhost");
+    let local = ["localhost", "127.0.0.1", "[::1]"]
+        .iter()
+        .any(|local| host == *local || host.starts_with(&format!("{}:", local)));
+    // Proxies in a chain each add theirs; the first is the client's
+    let proto = header(axum::http::HeaderName::from_static("x-forwarded-proto"))
+        .and_then(|protos| protos.split(',').next())
+        .map(str::trim);
+    let scheme = match proto {
+        Some(proto) => proto,
+        None if local => "http",
+        None => "https",
+    };
+    format!("{}://{}", scheme, host)
+}
+
 /// API endpoint for CLI to look up a ticket by short code or words
 async fn api_lookup_ticket(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Path(code): Path<String>,
 ) -> Response {
-    // Normalize: could be a short code or word-based code
-    let lookup_code = if code.contains('-') {
-        // Word-based code like "apple-banana-cherry"
-        words_to_code(&code)
+    // Normalize: could be a short, custom or word-based code
+    match lookup_code(&state, &code).await {
+        Some((code, ticket)) => {
+            state
+                .events
+                .record(&code, CodeEvent::Claimed { web: false });
+            let mirror = ticket.starts_with(mirror::PREFIX);
+            axum::Json(LookupTicketResponse {
+                ticket: (!mirror).then_some(ticket),
+                file_name: None,
+                mirror,
+                iroh_relay: state
+                    .steering
+                    .relay_for_request(&headers, peer)
+                    .map(str::to_string),
+            })
+            .into_response()
+        }
+        None => (
+            axum::http::StatusCode::NOT_FOUND,
+            axum::Json(serde_json::json!({"error": "Code not found or expired"})),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct SelectEntriesRequest {
+    /// Indices of the offered entries to receive
+    entries: Vec<usize>,
+}
+
+/// API endpoint for the browser to pick files from a folder offer
+async fn api_select_entries(
+    State(state): State<AppState>,
+    Path(transfer_id): Path<String>,
+    Query(query): Query<ControlQuery>,
+    axum::Json(req): axum::Json<SelectEntriesRequest>,
+) -> Response {
+    if !state.control_key.verify(&transfer_id, &query.token) {
+        return control::forbidden();
+    }
+    match state.choices.lock().await.remove(&transfer_id) {
+        Some(choice) => {
+            let _ = choice.send(req.entries);
+            axum::http::StatusCode::NO_CONTENT.into_response()
+        }
+        None => (
+            axum::http::StatusCode::NOT_FOUND,
+            axum::Json(serde_json::json!({"error": "No selection pending for this transfer"})),
+        )
+            .into_response(),
+    }
+}
+
+/// API endpoint for a transfer's log, to attach to a bug report
+async fn api_transfer_log(
+    State(state): State<AppState>,
+    Path(transfer_id): Path<String>,
+    Query(query): Query<ControlQuery>,
+) -> Response {
+    if !state.control_key.verify(&transfer_id, &query.token) {
+        return control::forbidden();
+    }
+    match zap_core::logs::get(&transfer_id) {
+        Some(log) => (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    "text/plain; charset=utf-8".to_string(),
+                ),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"zap-{}.log\"", transfer_id),
+                ),
+            ],
+            log,
+        )
+            .into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            axum::Json(serde_json::json!({"error": "Nothing was logged for this transfer"})),
+        )
+            .into_response(),
+    }
+}
+
+// ============ Release Manifest ============
+
+/// Machine-readable description of the latest release, generated by CI
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ReleaseManifest {
+    version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    published_at: Option<String>,
+    artifacts: Vec<ReleaseArtifact>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ReleaseArtifact {
+    /// Platform identifier, e.g. `linux-x86_64` or `darwin-arm64`
+    platform: String,
+    name: String,
+    url: String,
+    sha256: String,
+    /// Contents of the minisign `.minisig` file for this artifact
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    minisig: Option<String>,
+}
+
+/// Load the release manifest from `ZAP_RELEASE_MANIFEST` (a URL or local path)
+async fn load_release_manifest() -> Result<ReleaseManifest> {
+    let source = std::env::var("ZAP_RELEASE_MANIFEST")
+        .unwrap_or_else(|_| DEFAULT_RELEASE_MANIFEST.to_string());
+
+    let body = if source.starts_with("http://") || source.starts_with("https://") {
+        let resp = reqwest::get(&source).await?;
+        if !resp.status().is_success() {
+            anyhow::bail!("manifest fetch returned {}", resp.status());
+        }
+        resp.text().await?
     } else {
-        code.to_lowercase()
+        fs::read_to_string(&source).await?
     };
 
-    let codes = state.ticket_codes.read().await;
-    match codes.get(&lookup_code) {
-        Some(ticket) => axum::Json(LookupTicketResponse {
-            ticket: ticket.clone(),
-            file_name: None,
-        })
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// Get the release manifest, refreshing the cache when it is stale
+async fn cached_release_manifest(state: &AppState) -> Result<ReleaseManifest> {
+    {
+        let cache = state.release_manifest.read().await;
+        if let Some((fetched_at, manifest)) = cache.as_ref()
+            && fetched_at.elapsed() < RELEASE_MANIFEST_TTL
+        {
+            return Ok(manifest.clone());
+        }
+    }
+
+    match load_release_manifest().await {
+        Ok(manifest) => {
+            let mut cache = state.release_manifest.write().await;
+            *cache = Some((Instant::now(), manifest.clone()));
+            Ok(manifest)
+        }
+        Err(e) => {
+            // Serve a stale manifest rather than failing installs during an outage
+            warn!("failed to refresh release manifest: {}", e);
+            let cache = state.release_manifest.read().await;
+            cache.as_ref().map(|(_, m)| m.clone()).ok_or(e)
+        }
+    }
+}
+
+/// API endpoint for the installer and `zap update` to discover the latest release
+async fn api_latest_release(State(state): State<AppState>) -> Response {
+    match cached_release_manifest(&state).await {
+        Ok(manifest) => axum::Json(manifest).into_response(),
+        Err(e) => (
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            axum::Json(
+                serde_json::json!({"error": format!("Release manifest unavailable: {}", e)}),
+            ),
+        )
+            .into_response(),
+    }
+}
+
+/// API endpoint returning the latest artifact for a single platform
+async fn api_latest_release_artifact(
+    State(state): State<AppState>,
+    Path(platform): Path<String>,
+) -> Response {
+    let manifest = match cached_release_manifest(&state).await {
+        Ok(m) => m,
+        Err(e) => {
+            return (
+                axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                axum::Json(
+                    serde_json::json!({"error": format!("Release manifest unavailable: {}", e)}),
+                ),
+            )
+                .into_response();
+        }
+    };
+
+    match manifest.artifacts.into_iter().find(|a| a.platform == platform) {
+        Some(artifact) => axum::Json(serde_json::json!({
+            "version": manifest.version,
+            "name": artifact.name,
+            "url": artifact.url,
+            "sha256": artifact.sha256,
+            "minisig": artifact.minisig,
+        }))
         .into_response(),
         None => (
             axum::http::StatusCode::NOT_FOUND,
-            axum::Json(serde_json::json!({"error": "Code not found or expired"})),
+            axum::Json(serde_json::json!({"error": "No release for this platform"})),
         )
             .into_response(),
     }
 }
 
+/// Words short codes are spelled out with, one per character of
+/// `a`-`z` and `2`-`9`; easy to spell, no ambiguity
+const CODE_WORDS: &[&str] = &[
+    "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel", "india", "juliet",
+    "kilo", "lima", "mike", "november", "oscar", "papa", "quebec", "romeo", "sierra", "tango",
+    "uniform", "victor", "whiskey", "xray", "yankee", "zulu", "zero", "one", "two", "three",
+    "four", "five",
+];
+
 /// Convert a short code to human-readable words
 fn code_to_words(code: &str) -> String {
-    // Simple word list - easy to spell, no ambiguity
-    const WORDS: &[&str] = &[
-        "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel",
-        "india", "juliet", "kilo", "lima", "mike", "november", "oscar", "papa",
-        "quebec", "romeo", "sierra", "tango", "uniform", "victor", "whiskey",
-        "xray", "yankee", "zulu", "zero", "one", "two", "three", "four", "five",
-    ];
-
     code.chars()
         .filter_map(|c| {
             let idx = match c {
@@ -765,7 +2074,7 @@ fn code_to_words(code: &str) -> String {
                 '2'..='9' => 26 + (c as usize) - ('2' as usize),
                 _ => return None,
             };
-            WORDS.get(idx).copied()
+            CODE_WORDS.get(idx).copied()
         })
         .collect::<Vec<_>>()
         .join("-")
@@ -773,20 +2082,13 @@ fn code_to_words(code: &str) -> String {
 
 /// Convert word-based code back to short code
 fn words_to_code(words: &str) -> String {
-    const WORDS: &[&str] = &[
-        "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel",
-        "india", "juliet", "kilo", "lima", "mike", "november", "oscar", "papa",
-        "quebec", "romeo", "sierra", "tango", "uniform", "victor", "whiskey",
-        "xray", "yankee", "zulu", "zero", "one", "two", "three", "four", "five",
-    ];
-
     const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz23456789";
 
     words
         .split('-')
         .filter_map(|word| {
             let word_lower = word.to_lowercase();
-            WORDS.iter().position(|&w| w == word_lower).map(|idx| {
+            CODE_WORDS.iter().position(|&w| w == word_lower).map(|idx| {
                 if idx < CHARSET.len() {
                     CHARSET[idx] as char
                 } else {
@@ -797,12 +2099,25 @@ fn words_to_code(words: &str) -> String {
         .collect()
 }
 
+/// Whether every part of `code` is one of [`CODE_WORDS`], so that it reads
+/// as some short code spelled out
+fn spells_code(code: &str) -> bool {
+    code.split('-').all(|word| CODE_WORDS.contains(&word))
+}
+
+#[instrument(name = "relay.send", skip_all, fields(transfer.id = %transfer_id))]
 async fn run_send_transfer(state: AppState, transfer_id: String, secret_key: SecretKey) {
-    let file_path = {
+    let (file_path, file_name, upload, delete_after_download) = {
         let transfers = state.transfers.read().await;
-        transfers
-            .get(&transfer_id)
-            .and_then(|t| t.file_path.clone())
+        match transfers.get(&transfer_id) {
+            Some(t) => (
+                t.file_path.clone(),
+                t.file_name.clone(),
+                t.upload.as_ref().map(|u| u.subscribe()),
+                t.delete_after_download,
+            ),
+            None => (None, None, None, false),
+        }
     };
 
     let file_path = match file_path {
@@ -810,7 +2125,29 @@ async fn run_send_transfer(state: AppState, transfer_id: String, secret_key: Sec
         None => return,
     };
 
-    let node = match ZapNode::with_secret_key(secret_key).await {
+    // A file that's still being uploaded is sent as it arrives, unless the
+    // scanner has to see all of it first
+    let mut upload = upload.filter(|u| !u.borrow().is_complete());
+    if let Some(rx) = &mut upload
+        && state.scanner.is_some()
+    {
+        let uploaded = rx
+            .wait_for(|u| u.finished.is_some())
+            .await
+            .is_ok_and(|u| u.is_complete());
+        if !uploaded {
+            // The upload handler has already reported why
+            return;
+        }
+        upload = None;
+    }
+
+    if upload.is_none() && !scan_staged(&state, &transfer_id, &file_path).await {
+        return;
+    }
+    let upload_size = upload.as_ref().map(|u| u.borrow().size);
+
+    let node = match start_node(&state, secret_key).await {
         Ok(n) => n,
         Err(e) => {
             update_transfer_status(
@@ -825,7 +2162,32 @@ async fn run_send_transfer(state: AppState, transfer_id: String, secret_key: Sec
         }
     };
 
-    let (ticket, mut progress_rx) = match node.send(&file_path).await {
+    // Kept for the page to pause and cancel the send with
+    let handle = TransferHandle::new();
+    if let Some(transfer) = state.transfers.write().await.get_mut(&transfer_id) {
+        transfer.handle = Some(handle.clone());
+    }
+    let options = SendOptions {
+        handle: Some(handle),
+        ..Default::default()
+    };
+    let sent = match upload {
+        Some(upload) => {
+            // The upload is held to the size the page announced, so the
+            // receiver can be told it up front
+            let size = upload.borrow().size;
+            match upload::growing_reader(&file_path, upload).await {
+                Ok(reader) => {
+                    let name = file_name.unwrap_or_else(|| "file".to_string());
+                    let meta = FileMeta::new(name).with_size(size);
+                    node.send_from_with_options(reader, meta, options).await
+                }
+                Err(e) => Err(e.into()),
+            }
+        }
+        None => node.send_with_options(&file_path, options).await,
+    };
+    let (ticket, mut progress_rx) = match sent {
         Ok(r) => r,
         Err(e) => {
             update_transfer_status(
@@ -840,14 +2202,20 @@ async fn run_send_transfer(state: AppState, transfer_id: String, secret_key: Sec
         }
     };
 
-    // Generate short code and store ticket mapping
-    let short_code = generate_short_code();
+    // Register the short code picked at upload time
+    let short_code = {
+        let transfers = state.transfers.read().await;
+        transfers
+            .get(&transfer_id)
+            .and_then(|t| t.short_code.clone())
+            .unwrap_or_else(generate_short_code)
+    };
     let ticket_str = ticket.to_string();
 
-    {
-        let mut codes = state.ticket_codes.write().await;
-        codes.insert(short_code.clone(), ticket_str.clone());
-    }
+    state
+        .codes
+        .insert(&short_code, &ticket_str, CODE_TTL, delete_after_download)
+        .await;
 
     {
         let mut transfers = state.transfers.write().await;
@@ -861,18 +2229,48 @@ async fn run_send_transfer(state: AppState, transfer_id: String, secret_key: Sec
     update_transfer_status(&state, &transfer_id, TransferStatus::Waiting).await;
 
     // Process progress updates
+    let mut bytes_accounted = 0;
     while let Some(progress) = progress_rx.recv().await {
         let status = match progress {
+            SendProgress::Transforming { .. } | SendProgress::Archiving { .. } => {
+                TransferStatus::Pending
+            }
             SendProgress::Waiting => TransferStatus::Waiting,
-            SendProgress::Connected => TransferStatus::Connected,
+            SendProgress::Connected { .. } => TransferStatus::Connected,
             SendProgress::Sending {
                 bytes_sent,
                 total_bytes,
-            } => TransferStatus::Transferring {
-                bytes: bytes_sent,
-                total: total_bytes,
+            } => {
+                record_usage(&state, &transfer_id, Direction::Served, bytes_sent - bytes_accounted)
+                    .await;
+                bytes_accounted = bytes_sent;
+                TransferStatus::Transferring {
+                    bytes: bytes_sent,
+                    // A file sent while uploading is a stream of the size
+                    // the page announced
+                    total: total_bytes.or(upload_size),
+                }
+            }
+            SendProgress::Throughput { bytes_per_sec, .. } => {
+                let status = TransferStatus::Throughput { bytes_per_sec };
+                notify(&state, &transfer_id, status).await;
+                continue;
+            }
+            SendProgress::Paused => TransferStatus::Paused,
+            // Sending picks up again with the next progress
+            SendProgress::Path(_)
+            | SendProgress::Negotiated(_)
+            | SendProgress::Resumed
+            | SendProgress::FileSent { .. }
+            | SendProgress::Receipt(_) => continue,
+            // Followed by the error, since pages don't offer again
+            SendProgress::Rejected { .. }
+            | SendProgress::Changed { .. }
+            | SendProgress::Interrupted { .. } => continue,
+            SendProgress::Complete { checksum, .. } => TransferStatus::Complete {
+                path: None,
+                checksum: Some(blake3::Hash::from(checksum).to_hex().to_string()),
             },
-            SendProgress::Complete => TransferStatus::Complete { path: None },
             SendProgress::Error(msg) => TransferStatus::Error { message: msg },
         };
 
@@ -880,14 +2278,20 @@ async fn run_send_transfer(state: AppState, transfer_id: String, secret_key: Sec
             status,
             TransferStatus::Complete { .. } | TransferStatus::Error { .. }
         );
+        let is_complete = matches!(status, TransferStatus::Complete { .. });
 
         update_transfer_status(&state, &transfer_id, status).await;
 
         if is_terminal {
             // Mark as completed for cleanup
-            let mut transfers = state.transfers.write().await;
-            if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                transfer.completed_at = Some(Instant::now());
+            {
+                let mut transfers = state.transfers.write().await;
+                if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                    transfer.completed_at = Some(Instant::now());
+                }
+            }
+            if is_complete && delete_after_download {
+                discard_staged(&state, &transfer_id).await;
             }
             break;
         }
@@ -896,6 +2300,118 @@ async fn run_send_transfer(state: AppState, transfer_id: String, secret_key: Sec
     let _ = node.shutdown().await;
 }
 
+/// Delete a transfer's staged file now rather than when it expires
+async fn discard_staged(state: &AppState, transfer_id: &str) {
+    {
+        let mut transfers = state.transfers.write().await;
+        if let Some(transfer) = transfers.get_mut(transfer_id) {
+            transfer.file_path = None;
+        }
+    }
+    match fs::remove_dir_all(state.temp_dir.join(transfer_id)).await {
+        Ok(()) => info!(
+            "deleted staged file of transfer {} after download",
+            transfer_id
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!(
+            "failed to delete staged file of transfer {}: {}",
+            transfer_id, e
+        ),
+    }
+}
+
+/// Run the configured scanner over a staged file
+///
+/// Returns whether the transfer may go ahead. Rejected files are deleted and
+/// the transfer is marked as failed.
+async fn scan_staged(state: &AppState, transfer_id: &str, path: &std::path::Path) -> bool {
+    let Some(scanner) = state.scanner.clone() else {
+        return true;
+    };
+
+    update_transfer_status(state, transfer_id, TransferStatus::Scanning).await;
+
+    let message = match scanner.scan(path).await {
+        Ok(Verdict::Clean) => return true,
+        Ok(Verdict::Infected { reason }) => {
+            warn!("scanner rejected transfer {}: {}", transfer_id, reason);
+            format!("File rejected by malware scan: {}", reason)
+        }
+        Err(e) => {
+            warn!("scan failed for transfer {}: {:#}", transfer_id, e);
+            "File could not be scanned".to_string()
+        }
+    };
+
+    {
+        let mut transfers = state.transfers.write().await;
+        if let Some(transfer) = transfers.get_mut(transfer_id) {
+            transfer.file_path = None;
+            transfer.completed_at = Some(Instant::now());
+        }
+    }
+    let _ = fs::remove_dir_all(state.temp_dir.join(transfer_id)).await;
+
+    update_transfer_status(state, transfer_id, TransferStatus::Error { message }).await;
+    false
+}
+
+/// Check a received file against the checksum the page gave, deleting it
+/// and failing the transfer if it doesn't match
+async fn verify_staged(
+    state: &AppState,
+    transfer_id: &str,
+    path: &std::path::Path,
+    blake3: &[u8; 32],
+) -> bool {
+    let expected = {
+        let transfers = state.transfers.read().await;
+        transfers.get(transfer_id).and_then(|t| t.expected_checksum)
+    };
+    let Some(expected) = expected else {
+        return true;
+    };
+    let Err(e) = expected.verify(path, blake3).await else {
+        return true;
+    };
+    warn!("transfer {} failed its checksum: {}", transfer_id, e);
+
+    {
+        let mut transfers = state.transfers.write().await;
+        if let Some(transfer) = transfers.get_mut(transfer_id) {
+            transfer.file_path = None;
+            transfer.completed_at = Some(Instant::now());
+        }
+    }
+    let _ = fs::remove_dir_all(state.temp_dir.join(transfer_id)).await;
+
+    let message = e.to_string();
+    update_transfer_status(state, transfer_id, TransferStatus::Error { message }).await;
+    false
+}
+
+/// Browsers download single files, so received folders are packed into a
+/// tar archive next to them
+async fn downloadable_path(path: &std::path::Path) -> std::io::Result<PathBuf> {
+    if !path.is_dir() {
+        return Ok(path.to_path_buf());
+    }
+
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tar");
+    let archive = path.with_file_name(name);
+
+    let (root, dest) = (path.to_path_buf(), archive.clone());
+    tokio::task::spawn_blocking(move || zap_core::directory::archive_dir(&root, &dest))
+        .await
+        .map_err(std::io::Error::other)?
+        .map_err(std::io::Error::other)?;
+
+    Ok(archive)
+}
+
+#[instrument(name = "relay.receive", skip_all, fields(transfer.id = %transfer_id))]
 async fn run_receive_transfer(
     state: AppState,
     transfer_id: String,
@@ -915,7 +2431,7 @@ async fn run_receive_transfer(
         return;
     }
 
-    let node = match ZapNode::with_secret_key(secret_key).await {
+    let node = match start_node(&state, secret_key).await {
         Ok(n) => n,
         Err(e) => {
             update_transfer_status(
@@ -930,7 +2446,30 @@ async fn run_receive_transfer(
         }
     };
 
-    let mut progress_rx = match node.receive(ticket, Some(&output_dir)).await {
+    // Browsers pick the app to open a download by its extension
+    let sender = ticket.addr.id;
+    let options = ReceiveOptions {
+        detect_extension: true,
+        confirm: Some(OfferConfirm::new({
+            let (state, transfer_id) = (state.clone(), transfer_id.clone());
+            move |offer| {
+                let (state, transfer_id) = (state.clone(), transfer_id.clone());
+                async move { control::confirm_in_browser(&state, &transfer_id, &sender, offer).await }
+            }
+        })),
+        choose_entries: Some(EntryChooser::new({
+            let (state, transfer_id) = (state.clone(), transfer_id.clone());
+            move |entries| {
+                let (state, transfer_id) = (state.clone(), transfer_id.clone());
+                async move { choose_in_browser(&state, &transfer_id, entries).await }
+            }
+        })),
+        ..Default::default()
+    };
+    let mut progress_rx = match node
+        .receive_with_options(ticket, Some(&output_dir), options)
+        .await
+    {
         Ok(r) => r,
         Err(e) => {
             update_transfer_status(
@@ -945,16 +2484,18 @@ async fn run_receive_transfer(
         }
     };
 
+    let mut bytes_accounted = 0;
     while let Some(progress) = progress_rx.recv().await {
         let status = match &progress {
             ReceiveProgress::Connecting => TransferStatus::Pending,
             ReceiveProgress::Connected => TransferStatus::Connected,
-            ReceiveProgress::Offer { name, size: _ } => {
+            ReceiveProgress::Offer { name, label, .. } => {
                 // Update file name
                 {
                     let mut transfers = state.transfers.write().await;
                     if let Some(transfer) = transfers.get_mut(&transfer_id) {
                         transfer.file_name = Some(name.clone());
+                        transfer.label = label.clone();
                     }
                 }
                 TransferStatus::Connected
@@ -962,23 +2503,74 @@ async fn run_receive_transfer(
             ReceiveProgress::Receiving {
                 bytes_received,
                 total_bytes,
-            } => TransferStatus::Transferring {
-                bytes: *bytes_received,
-                total: *total_bytes,
-            },
-            ReceiveProgress::Complete { path } => {
-                // Update file path
-                {
-                    let mut transfers = state.transfers.write().await;
-                    if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                        transfer.file_path = Some(path.clone());
-                        transfer.completed_at = Some(Instant::now());
-                    }
-                }
-                TransferStatus::Complete {
-                    path: Some(format!("/download/{}", transfer_id)),
+            } => {
+                record_usage(
+                    &state,
+                    &transfer_id,
+                    Direction::Staged,
+                    bytes_received - bytes_accounted,
+                )
+                .await;
+                bytes_accounted = *bytes_received;
+                TransferStatus::Transferring {
+                    bytes: *bytes_received,
+                    total: *total_bytes,
                 }
             }
+            ReceiveProgress::Throughput { bytes_per_sec } => {
+                let status = TransferStatus::Throughput {
+                    bytes_per_sec: *bytes_per_sec,
+                };
+                notify(&state, &transfer_id, status).await;
+                continue;
+            }
+            ReceiveProgress::Unpacking
+            | ReceiveProgress::AlreadyHave { .. }
+            | ReceiveProgress::Path(_)
+            | ReceiveProgress::Paused
+            | ReceiveProgress::Resumed
+            | ReceiveProgress::Manifest { .. }
+            | ReceiveProgress::FileReceived { .. }
+            | ReceiveProgress::Loaded { .. } => continue,
+            ReceiveProgress::Complete {
+                path: received,
+                checksum,
+                ..
+            } => match downloadable_path(received).await {
+                Ok(path) => {
+                    if !verify_staged(&state, &transfer_id, received, checksum).await
+                        || !scan_staged(&state, &transfer_id, &path).await
+                    {
+                        break;
+                    }
+
+                    // Folders are re-packed for the browser, so the sender's
+                    // checksum only describes single files
+                    let checksum = (&path == received)
+                        .then(|| blake3::Hash::from(*checksum).to_hex().to_string());
+
+                    // Update file path
+                    {
+                        let mut transfers = state.transfers.write().await;
+                        if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                            transfer.file_name = path
+                                .file_name()
+                                .map(|n| n.to_string_lossy().to_string());
+                            transfer.file_path = Some(path);
+                            transfer.checksum = checksum.clone();
+                            transfer.completed_at = Some(Instant::now());
+                        }
+                    }
+                    orphans::save_record(&state, &transfer_id).await;
+                    TransferStatus::Complete {
+                        path: Some(format!("/download/{}", transfer_id)),
+                        checksum,
+                    }
+                }
+                Err(e) => TransferStatus::Error {
+                    message: e.to_string(),
+                },
+            },
             ReceiveProgress::Error(msg) => TransferStatus::Error {
                 message: msg.clone(),
             },
@@ -999,27 +2591,87 @@ async fn run_receive_transfer(
     let _ = node.shutdown().await;
 }
 
+/// Ask the browser which files of a folder offer to receive
+///
+/// Receives nothing if no choice is made in time.
+async fn choose_in_browser(
+    state: &AppState,
+    transfer_id: &str,
+    entries: Vec<DirEntry>,
+) -> Option<Vec<usize>> {
+    // Nothing to pick from
+    if entries.len() < 2 {
+        return None;
+    }
+
+    let (choice_tx, choice_rx) = oneshot::channel();
+    state
+        .choices
+        .lock()
+        .await
+        .insert(transfer_id.to_string(), choice_tx);
+    update_transfer_status(state, transfer_id, TransferStatus::Choosing { entries }).await;
+
+    let chosen = tokio::time::timeout(CHOICE_TIMEOUT, choice_rx).await;
+    state.choices.lock().await.remove(transfer_id);
+    match chosen {
+        Ok(Ok(chosen)) => Some(chosen),
+        _ => Some(Vec::new()),
+    }
+}
+
+/// Count bytes against the key and code a transfer belongs to
+async fn record_usage(state: &AppState, transfer_id: &str, direction: Direction, bytes: u64) {
+    let transfers = state.transfers.read().await;
+    if let Some(transfer) = transfers.get(transfer_id) {
+        state.accounting.record(
+            direction,
+            transfer.api_key.as_deref(),
+            transfer.short_code.as_deref(),
+            bytes,
+        );
+    }
+}
+
+/// Start a node for one transfer, in the configured protocol namespace
+async fn start_node(state: &AppState, secret_key: SecretKey) -> zap_core::Result<ZapNode> {
+    let mut builder = ZapNode::builder().secret_key(secret_key);
+    if let Some(alpn) = &state.alpn {
+        builder = builder.alpn(alpn.as_bytes());
+    }
+    builder.build().await
+}
+
 async fn update_transfer_status(state: &AppState, transfer_id: &str, status: TransferStatus) {
     let mut transfers = state.transfers.write().await;
     if let Some(transfer) = transfers.get_mut(transfer_id) {
         transfer.status = status.clone();
-
-        let update = ProgressUpdate {
-            status,
-            short_code: transfer.short_code.clone(),
-            file_name: transfer.file_name.clone(),
-        };
+        let update = ProgressUpdate::new(transfer_id, transfer, status);
 
         // Try to send, ignore if channel is closed
         let _ = transfer.progress_tx.try_send(update);
     }
 }
 
+/// Tell the page something without replacing the transfer's own status
+async fn notify(state: &AppState, transfer_id: &str, status: TransferStatus) {
+    let mut transfers = state.transfers.write().await;
+    if let Some(transfer) = transfers.get_mut(transfer_id) {
+        if let TransferStatus::Throughput { bytes_per_sec } = status {
+            transfer.bytes_per_sec = Some(bytes_per_sec);
+        }
+        let update = ProgressUpdate::new(transfer_id, transfer, status);
+        let _ = transfer.progress_tx.try_send(update);
+    }
+}
+
 fn render_progress(update: &ProgressUpdate) -> String {
     // Return JSON for plain JavaScript WebSocket handler
     serde_json::to_string(update).unwrap_or_else(|_| r#"{"status":{"type":"Error","message":"Serialization failed"}}"#.to_string())
 }
 
+static INDEX_PAGE: Precompressed = Precompressed::html(INDEX_HTML);
+
 const INDEX_HTML: &str = r##"<!DOCTYPE html>
 <html lang="en">
 <head>
@@ -1030,6 +2682,8 @@ const INDEX_HTML: &str = r##"<!DOCTYPE html>
     <link href="https://fonts.googleapis.com/css2?family=Caveat:wght@400;500;600;700&family=Patrick+Hand&display=swap" rel="stylesheet">
     <script src="https://cdn.tailwindcss.com"></script>
     <script src="https://unpkg.com/htmx.org@2.0.4"></script>
+    <!-- Show 4xx bodies (quota and size errors) instead of dropping them -->
+    <meta name="htmx-config" content='{"responseHandling":[{"code":"204","swap":false},{"code":"[23]..","swap":true},{"code":"4..","swap":true},{"code":"...","swap":false,"error":true}]}'>
     <script src="https://unpkg.com/roughjs@4.6.6/bundled/rough.js"></script>
     <style>
         :root {
@@ -1259,16 +2913,25 @@ const INDEX_HTML: &str = r##"<!DOCTYPE html>
                     <div class="feature-icon" style="transform: rotate(3deg);">📤</div>
                     <h2 class="font-title text-3xl">Send a file</h2>
                 </div>
-                <form hx-post="/send" hx-target="#send-result" hx-swap="innerHTML" hx-encoding="multipart/form-data">
+                <form onsubmit="return sendFile(event)">
                     <label for="file-input" id="drop-zone" class="sketch-drop rounded-lg p-8 text-center cursor-pointer mb-4 block">
                         <input type="file" name="file" id="file-input" required style="position:absolute;width:1px;height:1px;opacity:0;overflow:hidden;" onchange="updateFileName(this)">
                         <div class="text-5xl mb-3">📁</div>
                         <p id="file-name" class="text-lg text-ink-light">click or drop a file here!</p>
                     </label>
+                    <label class="flex items-center gap-2 mb-4 text-ink-light cursor-pointer">
+                        <input type="checkbox" name="delete_after_download" id="delete-after-download">
+                        delete from the relay after the first download
+                    </label>
                     <button type="submit" class="sketch-btn w-full">
                         Send it! →
                     </button>
                 </form>
+                <form hx-post="/send/url" hx-target="#send-result" hx-swap="afterbegin" hx-include="#delete-after-download" hx-on::after-request="this.reset()" class="flex gap-2 mt-4">
+                    <input name="url" type="url" required placeholder="or paste a link to a file"
+                        class="sketch-input flex-1" autocomplete="off" spellcheck="false">
+                    <button type="submit" class="sketch-btn">Fetch →</button>
+                </form>
                 <div id="send-result" class="mt-4"></div>
             </div>
 
@@ -1278,13 +2941,19 @@ const INDEX_HTML: &str = r##"<!DOCTYPE html>
                     <div class="feature-icon" style="transform: rotate(-5deg);">📥</div>
                     <h2 class="font-title text-3xl">Get a file</h2>
                 </div>
-                <form hx-post="/receive" hx-target="#receive-result" hx-swap="innerHTML">
+                <form hx-post="/receive" hx-target="#receive-result" hx-swap="afterbegin" hx-on::after-request="this.reset()">
                     <div class="mb-4">
                         <label class="block text-lg mb-2 text-ink-light">got a code? paste it here:</label>
-                        <input name="ticket" required placeholder="abc123" 
+                        <input id="receive-code" name="ticket" required placeholder="abc123" 
                             class="sketch-input w-full text-center tracking-widest"
                             maxlength="10" autocomplete="off" autocorrect="off" autocapitalize="off" spellcheck="false">
                     </div>
+                    <details id="receive-checksum" class="mb-4 text-ink-light">
+                        <summary class="cursor-pointer">got a checksum too?</summary>
+                        <input id="receive-checksum-input" name="checksum" placeholder="BLAKE3 or sha256:…"
+                            class="sketch-input w-full mt-2 text-sm"
+                            autocomplete="off" autocorrect="off" autocapitalize="off" spellcheck="false">
+                    </details>
                     <button type="submit" class="sketch-btn purple w-full">
                         Get it! ←
                     </button>
@@ -1358,12 +3027,101 @@ const INDEX_HTML: &str = r##"<!DOCTYPE html>
     </div>
 
     <script>
+        // Show a finished transfer's BLAKE3 so downloads can be checked out of band
+        function showChecksum(checksum, el) {
+            if (!checksum) return;
+            el.innerHTML = 'BLAKE3: <code class="select-all"></code>';
+            el.querySelector('code').textContent = checksum;
+            el.classList.remove('hidden');
+        }
+
+        // Fill the receive form from a shared link, /?code=abc123&checksum=<hash>
+        (function () {
+            const params = new URLSearchParams(location.search);
+            if (params.get('code')) document.getElementById('receive-code').value = params.get('code');
+            if (params.get('checksum')) {
+                document.getElementById('receive-checksum-input').value = params.get('checksum');
+                document.getElementById('receive-checksum').open = true;
+            }
+        })();
+
+        // Add a throughput sample to a transfer's speed graph, which shows
+        // the last minute
+        function plotSpeed(samples, bytesPerSec, el) {
+            samples.push(bytesPerSec);
+            if (samples.length > 60) samples.shift();
+            const max = Math.max(...samples, 1);
+            const start = 60 - samples.length;
+            const points = samples.map((s, i) => (start + i) + ',' + (20 - (s / max) * 19).toFixed(1));
+            el.querySelector('polyline').setAttribute('points', points.join(' '));
+            el.querySelector('div').textContent = (bytesPerSec / 1048576).toFixed(1) + ' MB/s';
+            el.classList.remove('hidden');
+        }
+
+        // The tab's title shows how far the slowest transfer under way is,
+        // to follow it from another tab
+        const pageTitle = document.title;
+        const tabProgress = {};
+        function showTabProgress(id, percent) {
+            if (percent == null) delete tabProgress[id];
+            else tabProgress[id] = percent;
+            const shown = Object.values(tabProgress);
+            document.title = shown.length ? Math.min(...shown) + '% · ' + pageTitle : pageTitle;
+        }
+
+        // Browsers only ask for permission to notify after a click, so each
+        // transfer offers a button for it until it's been answered
+        function offerNotifications(button) {
+            if (!('Notification' in window) || Notification.permission !== 'default') return;
+            button.classList.remove('hidden');
+            button.onclick = function () {
+                Notification.requestPermission().then(function () {
+                    document.querySelectorAll('[data-part="notify"]').forEach(function (b) { b.classList.add('hidden'); });
+                });
+            };
+        }
+
+        // Tell someone in another tab that a transfer ended
+        function notifyDone(title, body) {
+            if (!('Notification' in window) || Notification.permission !== 'granted' || !document.hidden) return;
+            new Notification(title, { body: body });
+        }
+
         // File selection
         function updateFileName(input) {
             const name = input.files[0]?.name;
             document.getElementById('file-name').textContent = name ? '📄 ' + name : 'click or drop a file here!';
         }
 
+        // Register the file, then upload it while the transfer gets going.
+        // Each send gets a slot of its own at the top, so several can run.
+        function sendFile(event) {
+            event.preventDefault();
+            const file = document.getElementById('file-input').files[0];
+            if (!file) return false;
+            const slot = document.createElement('div');
+            document.getElementById('send-result').prepend(slot);
+            htmx.ajax('POST', '/send/start', {
+                target: slot,
+                swap: 'innerHTML',
+                values: {
+                    name: file.name,
+                    size: file.size,
+                    delete_after_download: document.getElementById('delete-after-download').checked
+                }
+            }).then(() => {
+                const card = slot.querySelector('[data-transfer-id]');
+                if (!card) return;
+                const xhr = new XMLHttpRequest();
+                xhr.open('PUT', '/send/' + card.dataset.transferId);
+                xhr.send(file);
+            });
+            const input = document.getElementById('file-input');
+            input.value = '';
+            updateFileName(input);
+            return false;
+        }
+
         // Drag and drop
         const dropZone = document.getElementById('drop-zone');
         ['dragenter', 'dragover'].forEach(e => {
@@ -1399,6 +3157,8 @@ const INDEX_HTML: &str = r##"<!DOCTYPE html>
 </body>
 </html>"##;
 
+static INSTALL_PAGE: Precompressed = Precompressed::html(INSTALL_HTML);
+
 const INSTALL_HTML: &str = r##"<!DOCTYPE html>
 <html lang="en" class="dark">
 <head>
@@ -1495,8 +3255,9 @@ set -e
 # zap installer script
 # Usage: curl -fsSL https://zapper.cloud/install.sh | sh
 
-REPO="voidash/zapper.cloud"
-INSTALL_DIR="/usr/local/bin"
+REPO="@REPO@"
+RELAY="${ZAP_RELAY:-https://zapper.cloud}"
+INSTALL_DIR="@INSTALL_DIR@"
 ZAP_BIN=""
 
 # Detect OS and architecture
@@ -1506,6 +3267,7 @@ ARCH=$(uname -m)
 case "$ARCH" in
     x86_64|amd64) ARCH="x86_64" ;;
     arm64|aarch64) ARCH="arm64" ;;
+    armv7l|armv7) ARCH="armv7" ;;
     *) echo "Unsupported architecture: $ARCH"; exit 1 ;;
 esac
 
@@ -1517,11 +3279,13 @@ esac
 
 echo "Detected platform: $PLATFORM"
 
-# Get latest release URL
-LATEST_URL="https://api.github.com/repos/$REPO/releases/latest"
-DOWNLOAD_URL=$(curl -fsSL "$LATEST_URL" | grep "browser_download_url.*$PLATFORM" | cut -d '"' -f 4)
+# Look up the latest release from the relay's release manifest
+RELEASE=$(curl -fsSL "$RELAY/api/releases/latest/$PLATFORM" || true)
+DOWNLOAD_URL=$(printf '%s\n' "$RELEASE" | sed -n 's/.*"url":"\([^"]*\)".*/\1/p')
+EXPECTED_SHA256=$(printf '%s\n' "$RELEASE" | sed -n 's/.*"sha256":"\([^"]*\)".*/\1/p')
+VERSION=$(printf '%s\n' "$RELEASE" | sed -n 's/.*"version":"\([^"]*\)".*/\1/p')
 
-if [ -z "$DOWNLOAD_URL" ]; then
+if [ -z "$DOWNLOAD_URL" ] || [ -z "$EXPECTED_SHA256" ]; then
     echo "Could not find release for $PLATFORM"
     echo ""
     echo "Build from source instead:"
@@ -1530,14 +3294,47 @@ if [ -z "$DOWNLOAD_URL" ]; then
     exit 1
 fi
 
-echo "Downloading from: $DOWNLOAD_URL"
+echo "Downloading zap $VERSION from: $DOWNLOAD_URL"
 
-# Download and install
+# Download and verify
 TMP_FILE=$(mktemp)
 curl -fsSL "$DOWNLOAD_URL" -o "$TMP_FILE"
+
+if command -v sha256sum >/dev/null 2>&1; then
+    ACTUAL_SHA256=$(sha256sum "$TMP_FILE" | cut -d ' ' -f 1)
+else
+    ACTUAL_SHA256=$(shasum -a 256 "$TMP_FILE" | cut -d ' ' -f 1)
+fi
+
+if [ "$ACTUAL_SHA256" != "$EXPECTED_SHA256" ]; then
+    echo "Checksum mismatch for downloaded binary!"
+    echo "  expected: $EXPECTED_SHA256"
+    echo "  actual:   $ACTUAL_SHA256"
+    rm -f "$TMP_FILE"
+    exit 1
+fi
+echo "Checksum verified"
+
+# Verify the minisign signature when a trusted key is provided
+if [ -n "$ZAP_RELEASE_PUBKEY" ]; then
+    if ! command -v minisign >/dev/null 2>&1; then
+        echo "ZAP_RELEASE_PUBKEY is set but minisign is not installed"
+        rm -f "$TMP_FILE"
+        exit 1
+    fi
+    curl -fsSL "$DOWNLOAD_URL.minisig" -o "$TMP_FILE.minisig"
+    if ! minisign -Vm "$TMP_FILE" -x "$TMP_FILE.minisig" -P "$ZAP_RELEASE_PUBKEY" >/dev/null; then
+        echo "Signature verification failed!"
+        rm -f "$TMP_FILE" "$TMP_FILE.minisig"
+        exit 1
+    fi
+    rm -f "$TMP_FILE.minisig"
+    echo "Signature verified"
+fi
+
 chmod +x "$TMP_FILE"
 
-# Try to install to /usr/local/bin, fall back to ~/.local/bin
+# Try to install to $INSTALL_DIR, fall back to ~/.local/bin
 if [ -w "$INSTALL_DIR" ]; then
     mv "$TMP_FILE" "$INSTALL_DIR/zap"
     ZAP_BIN="$INSTALL_DIR/zap"
@@ -0,0 +1,444 @@
+//! Bandwidth accounting per short code and API key
+//!
+//! Keys are configured with `ZAP_API_KEYS` as comma-separated
+//! `name:secret[:quota]` entries, e.g. `ci:s3cr3t:50GB,alice:hunter2`.
+//! Requests present the secret in the `X-Api-Key` header; usage is reported
+//! by key name so secrets never show up in stats or metrics. A key's quota
+//! caps the bytes it stages and serves per calendar month (UTC).
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use axum::http::{HeaderMap, StatusCode};
+use serde::Serialize;
+
+use super::admin::constant_time_eq;
+
+/// Header carrying an API key secret
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Which way bytes moved through the relay
+#[derive(Clone, Copy)]
+pub(super) enum Direction {
+    /// Uploaded to, or received by, the relay
+    Staged,
+    /// Downloaded from, or sent by, the relay
+    Served,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub(super) struct Usage {
+    bytes_staged: u64,
+    bytes_served: u64,
+    transfers: u64,
+}
+
+impl Usage {
+    fn add(&mut self, direction: Direction, bytes: u64) {
+        match direction {
+            Direction::Staged => self.bytes_staged += bytes,
+            Direction::Served => self.bytes_served += bytes,
+        }
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.bytes_staged + self.bytes_served
+    }
+}
+
+struct ApiKey {
+    name: String,
+    secret: String,
+    monthly_quota: Option<u64>,
+}
+
+#[derive(Default)]
+struct KeyUsage {
+    /// Month `this_month` counts, as `YYYY-MM`
+    month: String,
+    this_month: Usage,
+    all_time: Usage,
+}
+
+#[derive(Default)]
+struct Counters {
+    total: Usage,
+    by_code: HashMap<String, Usage>,
+    by_key: HashMap<String, KeyUsage>,
+}
+
+/// Shared usage counters, cheap to clone
+#[derive(Clone, Default)]
+pub(super) struct Accounting {
+    keys: Arc<Vec<ApiKey>>,
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl Accounting {
+    /// Load API keys from `ZAP_API_KEYS`
+    pub(super) fn from_env() -> Result<Self> {
+        let keys = match std::env::var("ZAP_API_KEYS") {
+            Ok(spec) => parse_keys(&spec)?,
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Self {
+            keys: Arc::new(keys),
+            counters: Arc::default(),
+        })
+    }
+
+    /// Resolve the key presented with a request to its name
+    ///
+    /// Requests without a key are anonymous; an unrecognised key is rejected.
+    pub(super) fn identify(&self, headers: &HeaderMap) -> Result<Option<String>, StatusCode> {
+        let Some(secret) = headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok()) else {
+            return Ok(None);
+        };
+
+        self.keys
+            .iter()
+            .find(|k| constant_time_eq(k.secret.as_bytes(), secret.as_bytes()))
+            .map(|k| Some(k.name.clone()))
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+
+    /// Bytes a key may still move this month, or `None` if it has no quota
+    pub(super) fn remaining(&self, key: Option<&str>) -> Option<u64> {
+        let key = key?;
+        let quota = self.keys.iter().find(|k| k.name == key)?.monthly_quota?;
+
+        let mut counters = self.counters.lock().unwrap();
+        let used = current_usage(&mut counters, key).this_month.total_bytes();
+        Some(quota.saturating_sub(used))
+    }
+
+    /// Check a key before starting work, mapping an exhausted quota to 429
+    pub(super) fn check_quota(&self, key: Option<&str>) -> Result<(), StatusCode> {
+        match self.remaining(key) {
+            Some(0) => Err(StatusCode::TOO_MANY_REQUESTS),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count bytes against a key and code
+    pub(super) fn record(
+        &self,
+        direction: Direction,
+        key: Option<&str>,
+        code: Option<&str>,
+        bytes: u64,
+    ) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.total.add(direction, bytes);
+        if let Some(code) = code {
+            counters
+                .by_code
+                .entry(code.to_string())
+                .or_default()
+                .add(direction, bytes);
+        }
+        if let Some(key) = key {
+            let usage = current_usage(&mut counters, key);
+            usage.this_month.add(direction, bytes);
+            usage.all_time.add(direction, bytes);
+        }
+    }
+
+    /// Count a new transfer or registered code
+    pub(super) fn record_transfer(&self, key: Option<&str>, code: Option<&str>) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.total.transfers += 1;
+        if let Some(code) = code {
+            counters
+                .by_code
+                .entry(code.to_string())
+                .or_default()
+                .transfers += 1;
+        }
+        if let Some(key) = key {
+            let usage = current_usage(&mut counters, key);
+            usage.this_month.transfers += 1;
+            usage.all_time.transfers += 1;
+        }
+    }
+
+    /// Codes with usage counted against them
+    pub(super) fn codes(&self) -> Vec<String> {
+        self.counters
+            .lock()
+            .unwrap()
+            .by_code
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Drop the usage of codes that are gone, returning how many there were
+    pub(super) fn forget_codes(&self, codes: &[String]) -> usize {
+        let mut counters = self.counters.lock().unwrap();
+        codes
+            .iter()
+            .filter(|code| counters.by_code.remove(code.as_str()).is_some())
+            .count()
+    }
+
+    /// Stats for `/admin/api/stats`
+    pub(super) fn snapshot(&self) -> serde_json::Value {
+        let mut counters = self.counters.lock().unwrap();
+        let keys: Vec<_> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let usage = current_usage(&mut counters, &key.name);
+                serde_json::json!({
+                    "name": key.name,
+                    "monthly_quota": key.monthly_quota,
+                    "month": usage.month,
+                    "this_month": usage.this_month,
+                    "all_time": usage.all_time,
+                })
+            })
+            .collect();
+
+        serde_json::json!({
+            "total": counters.total,
+            "keys": keys,
+            "codes": counters.by_code,
+        })
+    }
+
+    /// Counters in the Prometheus text exposition format
+    ///
+    /// Per-code counters are left out since codes are short-lived.
+    pub(super) fn prometheus(&self) -> String {
+        let mut counters = self.counters.lock().unwrap();
+        let mut out = String::new();
+
+        let total = counters.total.clone();
+        metric(
+            &mut out,
+            "zap_bytes_staged_total",
+            "Bytes uploaded to or received by the relay",
+            "counter",
+            [("", total.bytes_staged)],
+        );
+        metric(
+            &mut out,
+            "zap_bytes_served_total",
+            "Bytes downloaded from or sent by the relay",
+            "counter",
+            [("", total.bytes_served)],
+        );
+        metric(
+            &mut out,
+            "zap_transfers_total",
+            "Transfers started and codes registered",
+            "counter",
+            [("", total.transfers)],
+        );
+
+        let per_key: Vec<_> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let usage = current_usage(&mut counters, &key.name);
+                (key, usage.all_time.clone(), usage.this_month.total_bytes())
+            })
+            .collect();
+
+        metric(
+            &mut out,
+            "zap_key_bytes_staged_total",
+            "Bytes staged per API key",
+            "counter",
+            per_key
+                .iter()
+                .map(|(k, u, _)| (k.name.as_str(), u.bytes_staged)),
+        );
+        metric(
+            &mut out,
+            "zap_key_bytes_served_total",
+            "Bytes served per API key",
+            "counter",
+            per_key
+                .iter()
+                .map(|(k, u, _)| (k.name.as_str(), u.bytes_served)),
+        );
+        metric(
+            &mut out,
+            "zap_key_transfers_total",
+            "Transfers per API key",
+            "counter",
+            per_key
+                .iter()
+                .map(|(k, u, _)| (k.name.as_str(), u.transfers)),
+        );
+        metric(
+            &mut out,
+            "zap_key_month_bytes",
+            "Bytes moved per API key this month",
+            "gauge",
+            per_key
+                .iter()
+                .map(|(k, _, month)| (k.name.as_str(), *month)),
+        );
+        metric(
+            &mut out,
+            "zap_key_quota_bytes",
+            "Monthly quota per API key",
+            "gauge",
+            per_key
+                .iter()
+                .filter_map(|(k, _, _)| Some((k.name.as_str(), k.monthly_quota?))),
+        );
+
+        out
+    }
+}
+
+/// Write one metric family; an empty key name means no label
+pub(super) fn metric<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    values: impl IntoIterator<Item = (&'a str, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (key, value) in values {
+        if key.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{key=\"{}\"}} {}", name, key, value);
+        }
+    }
+}
+
+/// Usage for a key, starting a fresh month when the calendar has moved on
+fn current_usage<'a>(counters: &'a mut Counters, key: &str) -> &'a mut KeyUsage {
+    let month = chrono::Utc::now().format("%Y-%m").to_string();
+    let usage = counters.by_key.entry(key.to_string()).or_default();
+    if usage.month != month {
+        usage.month = month;
+        usage.this_month = Usage::default();
+    }
+    usage
+}
+
+fn parse_keys(spec: &str) -> Result<Vec<ApiKey>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(3, ':');
+            let name = parts.next().unwrap_or_default();
+            let secret = parts
+                .next()
+                .filter(|s| !s.is_empty())
+                .with_context(|| format!("API key {:?} has no secret", name))?;
+            let monthly_quota = parts.next().map(parse_size).transpose()?;
+
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                anyhow::bail!("invalid API key name {:?}", name);
+            }
+
+            Ok(ApiKey {
+                name: name.to_string(),
+                secret: secret.to_string(),
+                monthly_quota,
+            })
+        })
+        .collect()
+}
+
+/// Parse a size like `500MB`, `10GB` or a plain byte count
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim().to_ascii_uppercase();
+    let (number, multiplier) = [
+        ("TB", 1u64 << 40),
+        ("GB", 1 << 30),
+        ("MB", 1 << 20),
+        ("KB", 1 << 10),
+        ("B", 1),
+    ]
+    .iter()
+    .find_map(|(suffix, m)| s.strip_suffix(suffix).map(|n| (n, *m)))
+    .unwrap_or((&s, 1));
+
+    let number: u64 = number
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid size {:?}", s))?;
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("size {:?} is too large", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = parse_keys("ci:s3cr3t:10GB, alice:hunter2").unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].name, "ci");
+        assert_eq!(keys[0].monthly_quota, Some(10 << 30));
+        assert_eq!(keys[1].secret, "hunter2");
+        assert_eq!(keys[1].monthly_quota, None);
+
+        assert!(parse_keys("nosecret").is_err());
+        assert!(parse_keys("bad name:x").is_err());
+        assert!(parse_keys("ci:x:lots").is_err());
+        assert!(parse_keys("ci:x:99999999999TB").is_err());
+    }
+
+    #[test]
+    fn test_identify() {
+        let accounting = Accounting {
+            keys: Arc::new(parse_keys("ci:s3cr3t").unwrap()),
+            counters: Arc::default(),
+        };
+        let mut headers = HeaderMap::new();
+        assert_eq!(accounting.identify(&headers), Ok(None));
+        headers.insert(API_KEY_HEADER, "s3cr3t".parse().unwrap());
+        assert_eq!(accounting.identify(&headers), Ok(Some("ci".to_string())));
+        headers.insert(API_KEY_HEADER, "s3cr3".parse().unwrap());
+        assert_eq!(accounting.identify(&headers), Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn test_quota() {
+        let accounting = Accounting {
+            keys: Arc::new(parse_keys("ci:s3cr3t:1KB").unwrap()),
+            counters: Arc::default(),
+        };
+
+        assert_eq!(accounting.remaining(Some("ci")), Some(1024));
+        accounting.record(Direction::Staged, Some("ci"), Some("abc123"), 1000);
+        assert_eq!(accounting.remaining(Some("ci")), Some(24));
+        assert!(accounting.check_quota(Some("ci")).is_ok());
+
+        accounting.record(Direction::Served, Some("ci"), Some("abc123"), 1000);
+        assert_eq!(
+            accounting.check_quota(Some("ci")),
+            Err(StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(accounting.remaining(None), None);
+
+        accounting.record_transfer(None, Some("def456"));
+        let gone = ["abc123".to_string(), "unknown".to_string()];
+        assert_eq!(accounting.forget_codes(&gone), 1);
+        assert_eq!(accounting.codes(), ["def456"]);
+        // The key's usage outlives its codes
+        assert_eq!(accounting.remaining(Some("ci")), Some(0));
+    }
+}
@@ -0,0 +1,567 @@
+//! Operator pages for inspecting and purging staged files
+//!
+//! Disabled unless `ZAP_ADMIN_TOKEN` is set. API requests authenticate with
+//! `Authorization: Bearer <token>`; the page itself holds no data and asks
+//! for the token in the browser.
+
+use std::path::Path as FsPath;
+use std::time::SystemTime;
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::fs;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::assets::Precompressed;
+use super::{AppState, TransferStatus};
+
+/// One top-level entry in the temp directory
+#[derive(Serialize)]
+struct StagedEntry {
+    /// Entry name, which is the transfer id for files staged by the web UI
+    name: String,
+    /// Total size on disk in bytes
+    size: u64,
+    /// Seconds since the entry was last modified
+    age_secs: u64,
+    /// Set when the entry belongs to a transfer the server still tracks
+    transfer_id: Option<String>,
+    file_name: Option<String>,
+    status: Option<TransferStatus>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct PurgeRequest {
+    /// Entries to remove; when omitted, everything not in an active transfer
+    #[serde(default)]
+    names: Option<Vec<String>>,
+}
+
+pub(super) async fn admin_page(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if state.admin_token.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    ADMIN_PAGE.respond(&headers)
+}
+
+/// List the transfer directories in the temp directory with their size, age,
+/// and transfer
+pub(super) async fn api_list_files(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(denied) = authorize(&state, &headers) {
+        return denied;
+    }
+
+    match list_staged(&state).await {
+        Ok(entries) => {
+            let total: u64 = entries.iter().map(|e| e.size).sum();
+            Json(json!({ "entries": entries, "total_size": total })).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": format!("Could not read temp directory: {}", e)})),
+        )
+            .into_response(),
+    }
+}
+
+/// Remove a single staged entry and forget its transfer
+pub(super) async fn api_delete_file(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Response {
+    if let Some(denied) = authorize(&state, &headers) {
+        return denied;
+    }
+
+    match remove_entry(&state, &name).await {
+        Ok(freed) => Json(json!({ "removed": 1, "freed": freed })).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({"error": e}))).into_response(),
+    }
+}
+
+/// Remove several staged entries at once
+pub(super) async fn api_purge(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<PurgeRequest>,
+) -> Response {
+    if let Some(denied) = authorize(&state, &headers) {
+        return denied;
+    }
+
+    let names = match req.names {
+        Some(names) => names,
+        None => match list_staged(&state).await {
+            Ok(entries) => entries
+                .into_iter()
+                .filter(|e| !e.status.as_ref().is_some_and(is_active))
+                .map(|e| e.name)
+                .collect(),
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": format!("Could not read temp directory: {}", e)})),
+                )
+                    .into_response();
+            }
+        },
+    };
+
+    let mut removed = 0;
+    let mut freed = 0;
+    for name in &names {
+        match remove_entry(&state, name).await {
+            Ok(size) => {
+                removed += 1;
+                freed += size;
+            }
+            Err(e) => warn!("admin purge skipped {}: {}", name, e),
+        }
+    }
+
+    info!("admin purge removed {} entries ({} bytes)", removed, freed);
+    Json(json!({ "removed": removed, "freed": freed })).into_response()
+}
+
+/// Bandwidth usage per API key and short code
+pub(super) async fn api_stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(denied) = authorize(&state, &headers) {
+        return denied;
+    }
+    Json(state.accounting.snapshot()).into_response()
+}
+
+/// Usage counters for Prometheus, scraped with the admin token
+pub(super) async fn metrics(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(denied) = authorize(&state, &headers) {
+        return denied;
+    }
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        state.accounting.prometheus()
+            + &state.tasks.prometheus()
+            + &state.mirror.prometheus()
+            + &zap_core::metrics::prometheus(),
+    )
+        .into_response()
+}
+
+/// Check the bearer token, returning the response to send if it is wrong
+fn authorize(state: &AppState, headers: &HeaderMap) -> Option<Response> {
+    let Some(expected) = state.admin_token.as_deref() else {
+        return Some(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let provided = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        None
+    } else {
+        Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "Invalid admin token"})),
+            )
+                .into_response(),
+        )
+    }
+}
+
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn is_active(status: &TransferStatus) -> bool {
+    !matches!(
+        status,
+        TransferStatus::Complete { .. } | TransferStatus::Error { .. }
+    )
+}
+
+async fn list_staged(state: &AppState) -> std::io::Result<Vec<StagedEntry>> {
+    let temp_dir = state.temp_dir.clone();
+    let on_disk = tokio::task::spawn_blocking(move || -> std::io::Result<Vec<_>> {
+        let mut found = Vec::new();
+        for item in std::fs::read_dir(&temp_dir)? {
+            let item = item?;
+            // The mirror store and anything else sharing the directory stay
+            // out of the list, so purging can't take them
+            if !is_transfer_dir(&item.file_name().to_string_lossy()) {
+                continue;
+            }
+            let metadata = item.metadata()?;
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|m| SystemTime::now().duration_since(m).ok())
+                .unwrap_or_default();
+            found.push((
+                item.file_name().to_string_lossy().to_string(),
+                disk_usage(&item.path()),
+                age.as_secs(),
+            ));
+        }
+        Ok(found)
+    })
+    .await
+    .map_err(std::io::Error::other)??;
+
+    let transfers = state.transfers.read().await;
+    let mut entries: Vec<StagedEntry> = on_disk
+        .into_iter()
+        .map(|(name, size, age_secs)| {
+            let transfer = transfers.get(&name);
+            StagedEntry {
+                transfer_id: transfer.map(|_| name.clone()),
+                file_name: transfer.and_then(|t| t.file_name.clone()),
+                status: transfer.map(|t| t.status.clone()),
+                name,
+                size,
+                age_secs,
+            }
+        })
+        .collect();
+
+    entries.sort_by_key(|e| std::cmp::Reverse(e.age_secs));
+    Ok(entries)
+}
+
+/// Whether a temp entry is named like a transfer id
+fn is_transfer_dir(name: &str) -> bool {
+    Uuid::parse_str(name).is_ok()
+}
+
+/// Total size of a file or directory tree, ignoring anything unreadable
+fn disk_usage(path: &FsPath) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    std::fs::read_dir(path)
+        .map(|items| {
+            items
+                .filter_map(|item| item.ok())
+                .map(|item| disk_usage(&item.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Delete a top-level temp entry, returning how many bytes were freed
+async fn remove_entry(state: &AppState, name: &str) -> Result<u64, String> {
+    // Only transfer directories directly under the temp dir can be removed
+    if !is_transfer_dir(name) {
+        return Err(format!("invalid entry name: {}", name));
+    }
+
+    let path = state.temp_dir.join(name);
+    let metadata = fs::symlink_metadata(&path)
+        .await
+        .map_err(|e| format!("{}: {}", name, e))?;
+    let size = disk_usage(&path);
+
+    let result = if metadata.is_dir() {
+        fs::remove_dir_all(&path).await
+    } else {
+        fs::remove_file(&path).await
+    };
+    result.map_err(|e| format!("{}: {}", name, e))?;
+
+    state.transfers.write().await.remove(name);
+    state.tasks.abort_where(|id| id == name);
+    info!("admin removed staged entry {}", name);
+    Ok(size)
+}
+
+pub(super) static ADMIN_PAGE: Precompressed = Precompressed::html(ADMIN_HTML);
+
+const ADMIN_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en" class="dark">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>zap admin</title>
+    <script src="https://cdn.tailwindcss.com"></script>
+    <style>
+        body { background: #0f0f0f; }
+    </style>
+</head>
+<body class="min-h-screen text-gray-100">
+    <div class="container mx-auto px-4 py-12 max-w-5xl">
+        <header class="flex items-center justify-between mb-8">
+            <h1 class="text-3xl font-bold"><span class="text-cyan-400">zap</span> admin</h1>
+            <div class="text-gray-400" id="summary"></div>
+        </header>
+
+        <div id="login" class="bg-gray-900 rounded-lg p-6 border border-gray-800 hidden">
+            <label class="block text-gray-400 mb-2" for="token">Admin token</label>
+            <div class="flex gap-2">
+                <input id="token" type="password" class="flex-1 bg-gray-800 rounded px-3 py-2 border border-gray-700">
+                <button id="login-btn" class="bg-cyan-600 hover:bg-cyan-500 rounded px-4 py-2">Sign in</button>
+            </div>
+            <p id="login-error" class="text-red-400 mt-2"></p>
+        </div>
+
+        <div id="panel" class="hidden">
+            <div class="flex gap-2 mb-4">
+                <button id="refresh-btn" class="bg-gray-800 hover:bg-gray-700 rounded px-4 py-2">Refresh</button>
+                <button id="purge-selected-btn" class="bg-red-700 hover:bg-red-600 rounded px-4 py-2">Delete selected</button>
+                <button id="purge-all-btn" class="bg-red-900 hover:bg-red-800 rounded px-4 py-2">Purge inactive</button>
+            </div>
+            <div class="bg-gray-900 rounded-lg border border-gray-800 overflow-x-auto">
+                <table class="w-full text-sm">
+                    <thead class="text-gray-400 text-left">
+                        <tr>
+                            <th class="p-3"><input type="checkbox" id="select-all"></th>
+                            <th class="p-3">Entry</th>
+                            <th class="p-3">File</th>
+                            <th class="p-3">Status</th>
+                            <th class="p-3 text-right">Size</th>
+                            <th class="p-3 text-right">Age</th>
+                            <th class="p-3"></th>
+                        </tr>
+                    </thead>
+                    <tbody id="rows"></tbody>
+                </table>
+            </div>
+        </div>
+    </div>
+
+    <script>
+        const tokenKey = 'zap-admin-token';
+        const $ = (id) => document.getElementById(id);
+
+        function formatBytes(n) {
+            const units = ['B', 'KB', 'MB', 'GB', 'TB'];
+            let i = 0;
+            while (n >= 1024 && i < units.length - 1) { n /= 1024; i++; }
+            return (i === 0 ? n : n.toFixed(1)) + ' ' + units[i];
+        }
+
+        function formatAge(secs) {
+            if (secs < 60) return secs + 's';
+            if (secs < 3600) return Math.floor(secs / 60) + 'm';
+            if (secs < 86400) return Math.floor(secs / 3600) + 'h';
+            return Math.floor(secs / 86400) + 'd';
+        }
+
+        function escapeHtml(s) {
+            const div = document.createElement('div');
+            div.textContent = s ?? '';
+            return div.innerHTML;
+        }
+
+        async function api(method, path, body) {
+            const resp = await fetch(path, {
+                method,
+                headers: {
+                    'Authorization': 'Bearer ' + sessionStorage.getItem(tokenKey),
+                    'Content-Type': 'application/json',
+                },
+                body: body ? JSON.stringify(body) : undefined,
+            });
+            if (resp.status === 401) {
+                sessionStorage.removeItem(tokenKey);
+                showLogin('Invalid token');
+                throw new Error('unauthorized');
+            }
+            return resp.json();
+        }
+
+        function showLogin(error) {
+            $('panel').classList.add('hidden');
+            $('login').classList.remove('hidden');
+            $('login-error').textContent = error || '';
+        }
+
+        async function refresh() {
+            const data = await api('GET', '/admin/api/files');
+            $('login').classList.add('hidden');
+            $('panel').classList.remove('hidden');
+            $('summary').textContent = data.entries.length + ' entries, ' + formatBytes(data.total_size);
+            $('select-all').checked = false;
+            $('rows').innerHTML = data.entries.map((e) => `
+                <tr class="border-t border-gray-800">
+                    <td class="p-3"><input type="checkbox" class="select" value="${escapeHtml(e.name)}"></td>
+                    <td class="p-3 font-mono text-xs">${escapeHtml(e.name)}</td>
+                    <td class="p-3">${escapeHtml(e.file_name)}</td>
+                    <td class="p-3 text-gray-400">${e.status ? escapeHtml(e.status.type) : 'untracked'}</td>
+                    <td class="p-3 text-right">${formatBytes(e.size)}</td>
+                    <td class="p-3 text-right">${formatAge(e.age_secs)}</td>
+                    <td class="p-3 text-right"><button class="delete text-red-400 hover:text-red-300" data-name="${escapeHtml(e.name)}">Delete</button></td>
+                </tr>`).join('');
+        }
+
+        $('login-btn').onclick = () => {
+            sessionStorage.setItem(tokenKey, $('token').value);
+            refresh().catch(() => {});
+        };
+        $('refresh-btn').onclick = () => refresh().catch(() => {});
+        $('select-all').onchange = (ev) => {
+            document.querySelectorAll('.select').forEach((c) => { c.checked = ev.target.checked; });
+        };
+        $('purge-selected-btn').onclick = async () => {
+            const names = [...document.querySelectorAll('.select:checked')].map((c) => c.value);
+            if (names.length === 0 || !confirm('Delete ' + names.length + ' entries?')) return;
+            await api('POST', '/admin/api/purge', { names });
+            refresh().catch(() => {});
+        };
+        $('purge-all-btn').onclick = async () => {
+            if (!confirm('Delete everything not in an active transfer?')) return;
+            await api('POST', '/admin/api/purge', {});
+            refresh().catch(() => {});
+        };
+        $('rows').onclick = async (ev) => {
+            const name = ev.target.dataset && ev.target.dataset.name;
+            if (!name || !confirm('Delete ' + name + '?')) return;
+            await api('DELETE', '/admin/api/files/' + encodeURIComponent(name));
+            refresh().catch(() => {});
+        };
+
+        if (sessionStorage.getItem(tokenKey)) {
+            refresh().catch(() => {});
+        } else {
+            showLogin();
+        }
+    </script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "admin secret";
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        headers
+    }
+
+    async fn body(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let dir = std::env::temp_dir().join(format!("zap-admin-{}", uuid::Uuid::new_v4()));
+        let state = AppState::for_test(dir.clone(), Some(TOKEN)).await;
+
+        let list = |headers| api_list_files(State(state.clone()), headers);
+        assert_eq!(
+            list(HeaderMap::new()).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            list(bearer("wrong")).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            list(bearer(&TOKEN[1..])).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let mut basic = HeaderMap::new();
+        basic.insert(axum::http::header::AUTHORIZATION, TOKEN.parse().unwrap());
+        assert_eq!(list(basic).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(list(bearer(TOKEN)).await.status(), StatusCode::OK);
+
+        let delete = api_delete_file(State(state.clone()), bearer("wrong"), Path("x".into()));
+        assert_eq!(delete.await.status(), StatusCode::UNAUTHORIZED);
+        let purge = api_purge(
+            State(state.clone()),
+            HeaderMap::new(),
+            Json(PurgeRequest { names: None }),
+        );
+        assert_eq!(purge.await.status(), StatusCode::UNAUTHORIZED);
+
+        // Without a token the pages aren't there at all
+        let state = AppState::for_test(dir.clone(), None).await;
+        let list = api_list_files(State(state), bearer(TOKEN));
+        assert_eq!(list.await.status(), StatusCode::NOT_FOUND);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_entry_names() {
+        let root = std::env::temp_dir().join(format!("zap-admin-{}", uuid::Uuid::new_v4()));
+        let dir = root.join("staged");
+        let state = AppState::for_test(dir.clone(), Some(TOKEN)).await;
+        fs::write(root.join("outside"), b"keep me").await.unwrap();
+        let id = uuid::Uuid::new_v4().to_string();
+        fs::create_dir(dir.join(&id)).await.unwrap();
+        fs::write(dir.join(&id).join("upload"), b"staged")
+            .await
+            .unwrap();
+
+        for name in [
+            "",
+            ".",
+            "..",
+            "../outside",
+            "mirror",
+            "mirror/../../outside",
+            "..\\outside",
+        ] {
+            let response = api_delete_file(State(state.clone()), bearer(TOKEN), Path(name.into()));
+            assert_eq!(
+                response.await.status(),
+                StatusCode::BAD_REQUEST,
+                "{:?}",
+                name
+            );
+        }
+        let purge = api_purge(
+            State(state.clone()),
+            bearer(TOKEN),
+            Json(PurgeRequest {
+                names: Some(vec!["..".into(), "../outside".into()]),
+            }),
+        );
+        assert_eq!(body(purge.await).await["removed"], 0);
+        assert!(fs::try_exists(root.join("outside")).await.unwrap());
+        assert!(fs::try_exists(&dir).await.unwrap());
+
+        // A transfer directory goes, and only it is listed
+        let list = api_list_files(State(state.clone()), bearer(TOKEN));
+        assert_eq!(body(list.await).await["entries"][0]["name"], id.as_str());
+        let response = api_delete_file(State(state.clone()), bearer(TOKEN), Path(id.clone()));
+        assert_eq!(body(response.await).await["freed"], 6);
+        assert!(!fs::try_exists(dir.join(&id)).await.unwrap());
+
+        // Purging everything inactive leaves the mirror store alone
+        let purge = api_purge(
+            State(state.clone()),
+            bearer(TOKEN),
+            Json(PurgeRequest { names: None }),
+        );
+        assert_eq!(body(purge.await).await["removed"], 0);
+        assert!(fs::try_exists(dir.join("mirror")).await.unwrap());
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
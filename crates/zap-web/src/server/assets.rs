@@ -0,0 +1,269 @@
+//! Serving the embedded pages and the install script
+//!
+//! These don't change while the server runs, so each is compressed once,
+//! with brotli and gzip at their highest levels, rather than on every
+//! request the way `CompressionLayer` handles the API's responses. A client
+//! gets the best encoding its `Accept-Encoding` allows, and anything else
+//! the content as is. Without the `compression` feature everyone gets it as
+//! is.
+//!
+//! Responses carry an `ETag` from the content's hash and a `Last-Modified`
+//! of when the server started, with `Cache-Control: no-cache`, so browsers
+//! and proxies keep a copy but check it's current with a conditional
+//! request, which gets an empty `304` until the next deploy.
+
+use std::borrow::Cow;
+#[cfg(feature = "compression")]
+use std::io::Write;
+use std::sync::{LazyLock, OnceLock};
+use std::time::SystemTime;
+
+#[cfg(feature = "compression")]
+use axum::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+use axum::http::header::{
+    CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, VARY,
+};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+#[cfg(feature = "compression")]
+use flate2::write::GzEncoder;
+
+/// When the server started, which is when everything here last changed
+static STARTED: LazyLock<DateTime<Utc>> = LazyLock::new(|| {
+    // HTTP dates have whole seconds
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    DateTime::from_timestamp(now.as_secs() as i64, 0).unwrap_or_default()
+});
+
+/// Content served as is and its compressed forms, made on first use
+pub(super) struct Precompressed {
+    body: Cow<'static, str>,
+    content_type: &'static str,
+    encoded: OnceLock<Encoded>,
+}
+
+struct Encoded {
+    #[cfg(feature = "compression")]
+    br: Vec<u8>,
+    #[cfg(feature = "compression")]
+    gzip: Vec<u8>,
+    /// Weak, since every encoding of the content shares it
+    etag: String,
+    last_modified: String,
+}
+
+impl Precompressed {
+    /// An embedded HTML page
+    pub(super) const fn html(html: &'static str) -> Self {
+        Self {
+            body: Cow::Borrowed(html),
+            content_type: "text/html; charset=utf-8",
+            encoded: OnceLock::new(),
+        }
+    }
+
+    pub(super) fn new(body: String, content_type: &'static str) -> Self {
+        Self {
+            body: Cow::Owned(body),
+            content_type,
+            encoded: OnceLock::new(),
+        }
+    }
+
+    fn encoded(&self) -> &Encoded {
+        self.encoded.get_or_init(|| {
+            let hash = blake3::hash(self.body.as_bytes()).to_hex();
+            Encoded {
+                #[cfg(feature = "compression")]
+                br: brotli(self.body.as_bytes()),
+                #[cfg(feature = "compression")]
+                gzip: gzip(self.body.as_bytes()),
+                etag: format!("W/\"{}\"", &hash[..32]),
+                last_modified: http_date(*STARTED),
+            }
+        })
+    }
+
+    /// Compress the content now, so the first client doesn't wait for it
+    pub(super) fn prepare(&self) {
+        self.encoded();
+    }
+
+    /// The content in the best encoding the request accepts, or `304` if
+    /// the client's copy is current
+    pub(super) fn respond(&self, headers: &HeaderMap) -> Response {
+        let encoded = self.encoded();
+        let cache = [
+            (ETAG, encoded.etag.as_str()),
+            (LAST_MODIFIED, encoded.last_modified.as_str()),
+            (CACHE_CONTROL, "no-cache"),
+            (VARY, "accept-encoding"),
+        ];
+        if is_fresh(headers, &encoded.etag) {
+            return (StatusCode::NOT_MODIFIED, cache).into_response();
+        }
+
+        let content_type = [(CONTENT_TYPE, self.content_type)];
+        #[cfg(feature = "compression")]
+        if accepts(headers, "br") {
+            let encoding = [(CONTENT_ENCODING, "br")];
+            return (content_type, cache, encoding, encoded.br.clone()).into_response();
+        } else if accepts(headers, "gzip") {
+            let encoding = [(CONTENT_ENCODING, "gzip")];
+            return (content_type, cache, encoding, encoded.gzip.clone()).into_response();
+        }
+        (content_type, cache, self.body.to_string()).into_response()
+    }
+}
+
+/// `time` as an HTTP date, such as `Sun, 06 Nov 1994 08:49:37 GMT`
+pub(super) fn http_date(time: impl Into<DateTime<Utc>>) -> String {
+    time.into().format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[cfg(feature = "compression")]
+fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 11, 22);
+        writer.write_all(data).expect("writing to a Vec can't fail");
+    }
+    out
+}
+
+#[cfg(feature = "compression")]
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder
+        .write_all(data)
+        .expect("writing to a Vec can't fail");
+    encoder.finish().expect("writing to a Vec can't fail")
+}
+
+/// Whether the client's copy is current: its `If-None-Match` has `etag`,
+/// or, without one, its `If-Modified-Since` isn't before the server started
+fn is_fresh(headers: &HeaderMap, etag: &str) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if let Some(tags) = headers.get(IF_NONE_MATCH) {
+        let Ok(tags) = tags.to_str() else {
+            return false;
+        };
+        return tags
+            .split(',')
+            .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag));
+    }
+    headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+        .is_some_and(|since| since >= *STARTED)
+}
+
+/// Whether `Accept-Encoding` allows `coding`, which it doesn't if it's
+/// missing or given `q=0`
+#[cfg(feature = "compression")]
+fn accepts(headers: &HeaderMap, coding: &str) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let refused = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            name.eq_ignore_ascii_case(coding) && !refused
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "compression")]
+    use std::io::Read;
+
+    use axum::http::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    fn request(name: HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[cfg(feature = "compression")]
+    fn accept(value: &str) -> HeaderMap {
+        request(ACCEPT_ENCODING, value)
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_accepts() {
+        assert!(accepts(&accept("gzip, deflate, br"), "br"));
+        assert!(accepts(&accept("gzip;q=0.8, BR;q=1.0"), "br"));
+        assert!(!accepts(&accept("gzip, br;q=0"), "br"));
+        assert!(!accepts(&accept("identity"), "gzip"));
+        assert!(!accepts(&HeaderMap::new(), "gzip"));
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_compressed_forms() {
+        let page = Precompressed::html("<html>hello hello hello</html>");
+
+        let mut unzipped = String::new();
+        flate2::read::GzDecoder::new(page.encoded().gzip.as_slice())
+            .read_to_string(&mut unzipped)
+            .unwrap();
+        assert_eq!(unzipped, page.body);
+
+        let mut unbrotlied = String::new();
+        brotli::Decompressor::new(page.encoded().br.as_slice(), 4096)
+            .read_to_string(&mut unbrotlied)
+            .unwrap();
+        assert_eq!(unbrotlied, page.body);
+
+        let response = page.respond(&accept("gzip"));
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        let response = page.respond(&HeaderMap::new());
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        let script = Precompressed::new("#!/bin/sh\n".to_string(), "text/x-shellscript");
+        let response = script.respond(&HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/x-shellscript");
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-cache");
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        let last_modified = response.headers()[LAST_MODIFIED]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // A strong form of the tag matches too, as does `*`
+        let strong = etag.trim_start_matches("W/");
+        for tags in [etag.as_str(), strong, "\"other\", *"] {
+            let response = script.respond(&request(IF_NONE_MATCH, tags));
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", tags);
+        }
+        let response = script.respond(&request(IF_NONE_MATCH, "\"other\""));
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = script.respond(&request(IF_MODIFIED_SINCE, &last_modified));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let old = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let response = script.respond(&request(IF_MODIFIED_SINCE, old));
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,337 @@
+//! A public listing of shared files, for senders who opt in
+//!
+//! Off unless `ZAP_CATALOG` is set, so a public relay doesn't become a
+//! board anyone can post to. With it on, `zap send --share --list` puts its
+//! code, file name and size on `/catalog` for as long as the code works,
+//! e.g. for a workshop where one sender shares with a room of receivers
+//! who only have the page open. `--list=key` shows the file only to
+//! requests made with the sender's API key.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::AppState;
+use super::assets::Precompressed;
+
+/// Files listed at once, beyond which senders are turned away
+const MAX_ENTRIES: usize = 1000;
+
+/// Longest file name shown, in characters
+const MAX_NAME: usize = 200;
+
+/// Who sees a listed file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Visibility {
+    /// Everyone who opens the catalog
+    Public,
+    /// Requests with the API key the code was registered with
+    Key,
+}
+
+/// The files senders have listed, by code
+pub(super) struct Catalog {
+    enabled: bool,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    name: String,
+    size: Option<u64>,
+    listed: Instant,
+    expires: Instant,
+    /// The API key it's listed for, with [`Visibility::Key`]
+    key: Option<String>,
+}
+
+/// A file as the catalog shows it
+#[derive(Debug, Serialize)]
+pub(super) struct Listed {
+    pub(super) code: String,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// Seconds until the code stops working
+    expires_in: u64,
+}
+
+impl Catalog {
+    pub(super) fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Read `ZAP_CATALOG`; without it nothing can be listed
+    pub(super) fn from_env() -> Self {
+        Self::new(std::env::var("ZAP_CATALOG").is_ok_and(|v| v == "1" || v == "true"))
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether a sender with `key` can list a file, before its code is
+    /// registered
+    pub(super) fn check(
+        &self,
+        visibility: Visibility,
+        key: Option<&str>,
+    ) -> Result<(), (StatusCode, &'static str)> {
+        if !self.enabled {
+            return Err((StatusCode::BAD_REQUEST, "This relay has no catalog"));
+        }
+        if visibility == Visibility::Key && key.is_none() {
+            return Err((StatusCode::FORBIDDEN, "Listing for an API key needs one"));
+        }
+        let mut entries = self.entries.lock().unwrap();
+        prune(&mut entries);
+        if entries.len() >= MAX_ENTRIES {
+            return Err((StatusCode::SERVICE_UNAVAILABLE, "The catalog is full"));
+        }
+        Ok(())
+    }
+
+    /// List `code` until it expires in `ttl`
+    pub(super) fn add(
+        &self,
+        code: &str,
+        name: &str,
+        size: Option<u64>,
+        ttl: Duration,
+        visibility: Visibility,
+        key: Option<&str>,
+    ) {
+        let now = Instant::now();
+        let entry = Entry {
+            name: name.chars().take(MAX_NAME).collect(),
+            size,
+            listed: now,
+            expires: now + ttl,
+            key: match visibility {
+                Visibility::Public => None,
+                Visibility::Key => key.map(String::from),
+            },
+        };
+        self.entries.lock().unwrap().insert(code.to_string(), entry);
+    }
+
+    /// Stop listing `code`, e.g. once it no longer works
+    pub(super) fn remove(&self, code: &str) {
+        self.entries.lock().unwrap().remove(code);
+    }
+
+    /// The files a request with `key` sees, newest first
+    pub(super) fn list(&self, key: Option<&str>) -> Vec<Listed> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        prune(&mut entries);
+        let mut visible: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| entry.key.is_none() || entry.key.as_deref() == key)
+            .collect();
+        visible.sort_by(|a, b| b.1.listed.cmp(&a.1.listed).then(b.0.cmp(a.0)));
+        visible
+            .into_iter()
+            .map(|(code, entry)| Listed {
+                code: code.clone(),
+                name: entry.name.clone(),
+                size: entry.size,
+                expires_in: entry.expires.saturating_duration_since(now).as_secs(),
+            })
+            .collect()
+    }
+}
+
+fn prune(entries: &mut HashMap<String, Entry>) {
+    let now = Instant::now();
+    entries.retain(|_, entry| entry.expires > now);
+}
+
+pub(super) async fn catalog_page(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !state.catalog.enabled() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    CATALOG_PAGE.respond(&headers)
+}
+
+/// The listed files whose codes still work
+pub(super) async fn api_catalog(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !state.catalog.enabled() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let key = match state.accounting.identify(&headers) {
+        Ok(key) => key,
+        Err(status) => {
+            return (status, axum::Json(json!({"error": "Invalid API key"}))).into_response();
+        }
+    };
+
+    // Single-use and downloaded codes go before they expire
+    let mut entries = Vec::new();
+    for listed in state.catalog.list(key.as_deref()) {
+        if state.codes.get(&listed.code).await.is_some() {
+            entries.push(listed);
+        } else {
+            state.catalog.remove(&listed.code);
+        }
+    }
+    axum::Json(json!({ "entries": entries })).into_response()
+}
+
+pub(super) static CATALOG_PAGE: Precompressed = Precompressed::html(CATALOG_HTML);
+
+const CATALOG_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en" class="dark">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>zap catalog</title>
+    <script src="https://cdn.tailwindcss.com"></script>
+    <style>
+        body { background: #0f0f0f; }
+    </style>
+</head>
+<body class="min-h-screen text-gray-100">
+    <div class="container mx-auto px-4 py-12 max-w-3xl">
+        <header class="flex items-center justify-between mb-8">
+            <h1 class="text-3xl font-bold"><a href="/" class="text-cyan-400">zap</a> catalog</h1>
+            <button id="refresh-btn" class="bg-gray-800 hover:bg-gray-700 rounded px-4 py-2">Refresh</button>
+        </header>
+
+        <p class="text-gray-400 mb-4">Files shared here for anyone to receive. Pick one to download it in your browser.</p>
+
+        <div id="list" class="bg-gray-900 rounded-lg border border-gray-800 divide-y divide-gray-800"></div>
+        <p id="empty" class="text-gray-500 hidden">Nothing is listed right now.</p>
+
+        <details class="mt-8 text-sm text-gray-400">
+            <summary class="cursor-pointer">Files listed for an API key</summary>
+            <div class="flex gap-2 mt-2">
+                <input id="key" type="password" placeholder="API key" class="flex-1 bg-gray-800 rounded px-3 py-2 border border-gray-700">
+                <button id="key-btn" class="bg-cyan-600 hover:bg-cyan-500 rounded px-4 py-2 text-gray-100">Show</button>
+            </div>
+            <p id="key-error" class="text-red-400 mt-2"></p>
+        </details>
+    </div>
+
+    <script>
+        const keyName = 'zap-catalog-key';
+        const $ = (id) => document.getElementById(id);
+
+        function formatBytes(n) {
+            const units = ['B', 'KB', 'MB', 'GB', 'TB'];
+            let i = 0;
+            while (n >= 1024 && i < units.length - 1) { n /= 1024; i++; }
+            return (i === 0 ? n : n.toFixed(1)) + ' ' + units[i];
+        }
+
+        function formatAge(secs) {
+            if (secs < 60) return secs + 's';
+            if (secs < 3600) return Math.floor(secs / 60) + 'm';
+            if (secs < 86400) return Math.floor(secs / 3600) + 'h';
+            return Math.floor(secs / 86400) + 'd';
+        }
+
+        function escapeHtml(s) {
+            const div = document.createElement('div');
+            div.textContent = s ?? '';
+            return div.innerHTML;
+        }
+
+        async function refresh() {
+            const key = sessionStorage.getItem(keyName);
+            const resp = await fetch('/api/catalog', { headers: key ? { 'X-Api-Key': key } : {} });
+            if (resp.status === 401) {
+                sessionStorage.removeItem(keyName);
+                $('key-error').textContent = 'Invalid API key';
+                return refresh();
+            }
+            const data = await resp.json();
+            $('empty').classList.toggle('hidden', data.entries.length > 0);
+            $('list').classList.toggle('hidden', data.entries.length === 0);
+            $('list').innerHTML = data.entries.map((e) => `
+                <a href="/?code=${encodeURIComponent(e.code)}" class="flex items-center justify-between p-4 hover:bg-gray-800">
+                    <span class="truncate">${escapeHtml(e.name)}</span>
+                    <span class="text-gray-400 text-sm whitespace-nowrap ml-4">
+                        ${e.size !== undefined ? formatBytes(e.size) + ' · ' : ''}${formatAge(e.expires_in)} left
+                    </span>
+                </a>`).join('');
+        }
+
+        $('refresh-btn').onclick = () => refresh().catch(() => {});
+        $('key-btn').onclick = () => {
+            $('key-error').textContent = '';
+            sessionStorage.setItem(keyName, $('key').value);
+            refresh().catch(() => {});
+        };
+        refresh().catch(() => {});
+        setInterval(() => refresh().catch(() => {}), 30000);
+    </script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog() {
+        let hour = Duration::from_secs(60 * 60);
+        assert!(Catalog::new(false).check(Visibility::Public, None).is_err());
+
+        let catalog = Catalog::new(true);
+        assert!(catalog.check(Visibility::Public, None).is_ok());
+        assert_eq!(
+            catalog.check(Visibility::Key, None).unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+
+        catalog.add(
+            "abc234",
+            "slides.pdf",
+            Some(1024),
+            hour,
+            Visibility::Public,
+            Some("team"),
+        );
+        catalog.add(
+            "def567",
+            "answers.pdf",
+            None,
+            hour,
+            Visibility::Key,
+            Some("team"),
+        );
+        catalog.add(
+            "gone23",
+            "old.pdf",
+            None,
+            Duration::ZERO,
+            Visibility::Public,
+            None,
+        );
+
+        let codes = |key| {
+            catalog
+                .list(key)
+                .into_iter()
+                .map(|listed| listed.code)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(codes(None), ["abc234"]);
+        assert_eq!(codes(Some("other")), ["abc234"]);
+        assert_eq!(codes(Some("team")), ["def567", "abc234"]);
+
+        catalog.remove("abc234");
+        assert!(codes(None).is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+//! What generated codes are made of
+//!
+//! Codes normally mix letters and digits, which type easily but are often
+//! misheard when read out over a radio or phone. A relay can generate codes
+//! from digits only or from NATO alphabet words instead: `ZAP_CODE_ALPHABET`
+//! is `standard`, `digits` or `words`, and `ZAP_CODE_LENGTH` is how many
+//! characters or words a code has. Senders can ask for a different alphabet
+//! or length per code in `/api/register`.
+
+use std::ops::RangeInclusive;
+
+use anyhow::{Context, Result, bail};
+use rand::Rng;
+use serde::Deserialize;
+
+/// Letters and digits for standard codes, leaving out 0, 1, i, l and o,
+/// which are easily mistaken for each other
+const CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// Words for word codes, one per letter, as spelled over a radio
+const NATO: &[&str] = &[
+    "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel", "india", "juliet",
+    "kilo", "lima", "mike", "november", "oscar", "papa", "quebec", "romeo", "sierra", "tango",
+    "uniform", "victor", "whiskey", "xray", "yankee", "zulu",
+];
+
+/// Characters or words generated codes are made of
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Alphabet {
+    /// Letters and digits, as in `k7mq2x`
+    #[default]
+    Standard,
+    /// Digits, as in `48291375`
+    Digits,
+    /// NATO alphabet words, as in `bravo-kilo-zulu-echo-mike`
+    Words,
+}
+
+impl Alphabet {
+    /// Lengths a code may have; the shortest still take a while to guess
+    fn lengths(self) -> RangeInclusive<usize> {
+        match self {
+            Self::Standard => 6..=16,
+            Self::Digits => 8..=16,
+            Self::Words => 5..=10,
+        }
+    }
+
+    fn default_length(self) -> usize {
+        *self.lengths().start()
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "standard" => Some(Self::Standard),
+            "digits" => Some(Self::Digits),
+            "words" => Some(Self::Words),
+            _ => None,
+        }
+    }
+}
+
+/// The alphabet and length of generated codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct CodeFormat {
+    pub(super) alphabet: Alphabet,
+    length: usize,
+}
+
+impl Default for CodeFormat {
+    fn default() -> Self {
+        Self {
+            alphabet: Alphabet::Standard,
+            length: Alphabet::Standard.default_length(),
+        }
+    }
+}
+
+impl CodeFormat {
+    /// Codes from `alphabet`, of `length` or the alphabet's usual length
+    pub(super) fn new(alphabet: Alphabet, length: Option<usize>) -> Result<Self> {
+        let lengths = alphabet.lengths();
+        let length = length.unwrap_or(alphabet.default_length());
+        if !lengths.contains(&length) {
+            let (codes, unit) = match alphabet {
+                Alphabet::Standard => ("Codes", "characters"),
+                Alphabet::Digits => ("Digit codes", "digits"),
+                Alphabet::Words => ("Word codes", "words"),
+            };
+            bail!(
+                "{} must be {} to {} {} long",
+                codes,
+                lengths.start(),
+                lengths.end(),
+                unit
+            );
+        }
+        Ok(Self { alphabet, length })
+    }
+
+    /// Read `ZAP_CODE_ALPHABET` and `ZAP_CODE_LENGTH`; without them codes
+    /// are six letters and digits
+    pub(super) fn from_env() -> Result<Self> {
+        let var = |name| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let alphabet = match var("ZAP_CODE_ALPHABET") {
+            Some(name) => Alphabet::parse(name.trim()).with_context(|| {
+                format!(
+                    "ZAP_CODE_ALPHABET must be standard, digits or words, not {:?}",
+                    name
+                )
+            })?,
+            None => Alphabet::default(),
+        };
+        let length = var("ZAP_CODE_LENGTH")
+            .map(|length| length.trim().parse())
+            .transpose()
+            .context("invalid ZAP_CODE_LENGTH")?;
+        Self::new(alphabet, length).context("invalid ZAP_CODE_LENGTH")
+    }
+
+    /// A new random code
+    pub(super) fn generate(&self) -> String {
+        let mut rng = rand::rng();
+        match self.alphabet {
+            Alphabet::Standard => (0..self.length)
+                .map(|_| CHARSET[rng.random_range(0..CHARSET.len())] as char)
+                .collect(),
+            Alphabet::Digits => (0..self.length)
+                .map(|_| char::from(b'0' + rng.random_range(0..10)))
+                .collect(),
+            Alphabet::Words => (0..self.length)
+                .map(|_| NATO[rng.random_range(0..NATO.len())])
+                .collect::<Vec<_>>()
+                .join("-"),
+        }
+    }
+
+    /// How `code` is read out: standard codes spelled as words, the others
+    /// as they are
+    pub(super) fn words(&self, code: &str) -> String {
+        match self.alphabet {
+            Alphabet::Standard => super::code_to_words(code),
+            Alphabet::Digits | Alphabet::Words => code.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_formats() {
+        let code = CodeFormat::default().generate();
+        assert_eq!(code.len(), 6);
+        assert!(code.bytes().all(|b| CHARSET.contains(&b)));
+
+        let digits = CodeFormat::new(Alphabet::Digits, Some(10)).unwrap();
+        let code = digits.generate();
+        assert_eq!(code.len(), 10);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(digits.words(&code), code);
+
+        let words = CodeFormat::new(Alphabet::Words, None).unwrap();
+        let code = words.generate();
+        assert_eq!(code.split('-').count(), 5);
+        assert!(code.split('-').all(|word| NATO.contains(&word)));
+
+        // Too easily guessed, or too long to read out
+        assert!(CodeFormat::new(Alphabet::Digits, Some(4)).is_err());
+        assert!(CodeFormat::new(Alphabet::Words, Some(2)).is_err());
+        assert!(CodeFormat::new(Alphabet::Standard, Some(100)).is_err());
+    }
+}
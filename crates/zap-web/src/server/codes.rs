@@ -0,0 +1,597 @@
+//! Short codes and the tickets they stand for, encrypted at rest
+//!
+//! A ticket holds a node's addresses, so a dump of the code table would
+//! let anyone connect to every sender in it. Each ticket is sealed with
+//! XChaCha20-Poly1305 under a key derived from its code and filed under a
+//! hash of the code, so reading one back needs the code itself.
+//! Six-character codes can be guessed offline, so `ZAP_CODE_KEY` mixes a
+//! server secret into both; changing it makes every existing code
+//! unreadable.
+//!
+//! With `ZAP_CODES_FILE` set, codes are also appended to that file, one
+//! JSON object per line, and survive restarts. The file is only written
+//! with `ZAP_CODE_KEY` set, and is rewritten without the lines that are no
+//! longer needed when expired codes are pruned. Lines in the plaintext
+//! format, `{"code": "abc234", "ticket": "..."}`, are sealed when the file
+//! is loaded and the file is rewritten without them, so an existing
+//! mapping can be imported by writing it out that way.
+//!
+//! Besides generated codes, senders with an API key can pick their own,
+//! such as `standup-recording`. Custom codes are at least
+//! [`MIN_CUSTOM_LEN`] characters, so they never collide with six-character
+//! generated ones, and can't contain the words in [`BLOCKED_WORDS`] or
+//! `ZAP_BLOCKED_WORDS`. Nor can they be made only of the words codes are
+//! read out with, such as `one-two-one-two-one-two`, which would stand in
+//! the way of the generated code those words spell.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// blake3 contexts for everything derived from a code
+const ID_CONTEXT: &str = "zapper.cloud relay code id v2";
+const CIPHER_CONTEXT: &str = "zapper.cloud relay ticket cipher v3";
+const SERVER_KEY_CONTEXT: &str = "zapper.cloud relay server key v2";
+
+const NONCE_LEN: usize = 24;
+
+/// Shortest custom code, longer than generated codes
+pub(super) const MIN_CUSTOM_LEN: usize = 8;
+/// Longest custom code
+pub(super) const MAX_CUSTOM_LEN: usize = 32;
+
+/// Words a custom code can't contain as one of its hyphen-separated parts
+const BLOCKED_WORDS: &[&str] = &[
+    "arse", "ass", "asshole", "bastard", "bitch", "bollocks", "cock", "crap", "cum", "cunt",
+    "dick", "dildo", "fag", "nazi", "piss", "porn", "prick", "pussy", "rape", "slut", "tits",
+    "twat", "wank", "wanker",
+];
+
+/// Words a custom code can't contain anywhere, even inside other words
+const BLOCKED_ANYWHERE: &[&str] = &["faggot", "fuck", "nigga", "nigger", "shit", "whore"];
+
+/// One line of `ZAP_CODES_FILE`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    /// Hex code hash, hex nonce and ciphertext, and the Unix time the
+    /// code expires, which lines from before expiry don't have
+    Sealed {
+        id: String,
+        sealed: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        delete_after_download: bool,
+    },
+    /// A code and ticket written before encryption, sealed on load
+    Plain { code: String, ticket: String },
+}
+
+struct Entry {
+    sealed: Vec<u8>,
+    /// Unix time the code stops working, if it ever does
+    expires: Option<u64>,
+    /// Whether the sender asked for files staged on the relay to be deleted
+    /// once they've been downloaded
+    delete_after_download: bool,
+}
+
+impl Entry {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+pub(super) struct CodeStore {
+    /// Derived from `ZAP_CODE_KEY`
+    secret: Option<[u8; 32]>,
+    /// Sealed tickets by code hash
+    entries: RwLock<HashMap<String, Entry>>,
+    /// Where codes are kept across restarts
+    file: Option<PathBuf>,
+    /// Lines in `file`, more than there are entries once some are stale
+    lines: AtomicUsize,
+    /// Extra words custom codes can't contain, from `ZAP_BLOCKED_WORDS`
+    blocked: Vec<String>,
+}
+
+impl CodeStore {
+    /// A store that only lives in memory
+    pub(super) fn new(secret: Option<&str>) -> Self {
+        Self {
+            secret: secret.map(|s| blake3::derive_key(SERVER_KEY_CONTEXT, s.as_bytes())),
+            entries: RwLock::new(HashMap::new()),
+            file: None,
+            lines: AtomicUsize::new(0),
+            blocked: Vec::new(),
+        }
+    }
+
+    /// Read `ZAP_CODE_KEY` and `ZAP_CODES_FILE`, loading any codes kept
+    /// in the file
+    pub(super) async fn from_env() -> Result<Self> {
+        let secret = std::env::var("ZAP_CODE_KEY").ok().filter(|k| !k.is_empty());
+        if secret.is_none() {
+            warn!("ZAP_CODE_KEY is not set; stored tickets are only as strong as their codes");
+        }
+        let mut store = Self::new(secret.as_deref());
+        if let Ok(words) = std::env::var("ZAP_BLOCKED_WORDS") {
+            store.blocked = words
+                .split(',')
+                .map(|w| w.trim().to_lowercase())
+                .filter(|w| !w.is_empty())
+                .collect();
+        }
+        if let Some(file) = std::env::var_os("ZAP_CODES_FILE") {
+            if secret.is_none() {
+                bail!(
+                    "ZAP_CODES_FILE needs ZAP_CODE_KEY, or the tickets in it can be read by \
+                     guessing their codes"
+                );
+            }
+            store.load(PathBuf::from(file)).await?;
+        }
+        Ok(store)
+    }
+
+    /// Keep `ticket` under `code` for `ttl`
+    pub(super) async fn insert(
+        &self,
+        code: &str,
+        ticket: &str,
+        ttl: Duration,
+        delete_after_download: bool,
+    ) {
+        let mut entries = self.entries.write().await;
+        self.put(&mut entries, code, ticket, ttl, delete_after_download)
+            .await;
+    }
+
+    /// Keep `ticket` under `code` for `ttl` unless the code is already in
+    /// use, returning whether it was
+    pub(super) async fn claim(
+        &self,
+        code: &str,
+        ticket: &str,
+        ttl: Duration,
+        delete_after_download: bool,
+    ) -> bool {
+        let mut entries = self.entries.write().await;
+        if entries
+            .get(&self.id(code))
+            .is_some_and(|entry| entry.is_live(unix_now()))
+        {
+            return false;
+        }
+        self.put(&mut entries, code, ticket, ttl, delete_after_download)
+            .await;
+        true
+    }
+
+    async fn put(
+        &self,
+        entries: &mut HashMap<String, Entry>,
+        code: &str,
+        ticket: &str,
+        ttl: Duration,
+        delete_after_download: bool,
+    ) {
+        let id = self.id(code);
+        let entry = Entry {
+            sealed: self.seal(code, ticket),
+            expires: Some(unix_now() + ttl.as_secs()),
+            delete_after_download,
+        };
+        if let Some(path) = &self.file {
+            let line = Line::Sealed {
+                id: id.clone(),
+                sealed: HEXLOWER.encode(&entry.sealed),
+                expires: entry.expires,
+                delete_after_download,
+            };
+            match append(path, &line).await {
+                Ok(()) => {
+                    self.lines.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => warn!("failed to save code to {}: {}", path.display(), e),
+            }
+        }
+        entries.insert(id, entry);
+    }
+
+    /// The ticket kept under `code`, if any and it hasn't expired
+    pub(super) async fn get(&self, code: &str) -> Option<String> {
+        let entries = self.entries.read().await;
+        let entry = entries
+            .get(&self.id(code))
+            .filter(|entry| entry.is_live(unix_now()))?;
+        let ticket = self.open(code, &entry.sealed);
+        if ticket.is_none() {
+            warn!("stored ticket failed to decrypt; was ZAP_CODE_KEY changed?");
+        }
+        ticket
+    }
+
+    /// Whether files received through the relay with `code` should be
+    /// deleted once they've been downloaded
+    pub(super) async fn deletes_after_download(&self, code: &str) -> bool {
+        let entries = self.entries.read().await;
+        entries
+            .get(&self.id(code))
+            .is_some_and(|entry| entry.delete_after_download)
+    }
+
+    /// Forget expired codes, returning how many there were
+    ///
+    /// `ZAP_CODES_FILE` is rewritten with only the live codes, if it has
+    /// any other lines, so it doesn't grow for as long as the relay runs.
+    pub(super) async fn prune(&self) -> usize {
+        let now = unix_now();
+        let mut entries = self.entries.write().await;
+        let before = entries.len();
+        entries.retain(|_, entry| entry.is_live(now));
+        if let Some(path) = &self.file
+            && self.lines.load(Ordering::Relaxed) > entries.len()
+        {
+            match rewrite(path, &entries).await {
+                Ok(()) => self.lines.store(entries.len(), Ordering::Relaxed),
+                Err(e) => warn!("failed to compact {}: {}", path.display(), e),
+            }
+        }
+        before - entries.len()
+    }
+
+    /// Normalize a custom code, or say why it can't be used
+    pub(super) fn check_custom(&self, code: &str) -> Result<String, &'static str> {
+        let code = code.trim().to_lowercase();
+        if code.len() < MIN_CUSTOM_LEN || code.len() > MAX_CUSTOM_LEN {
+            return Err("Custom codes must be 8 to 32 characters long");
+        }
+        if !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            || code.split('-').any(str::is_empty)
+        {
+            return Err(
+                "Custom codes can only have letters, digits and single hyphens between them",
+            );
+        }
+
+        // Look through digits standing in for letters
+        let plain: String = code
+            .chars()
+            .map(|c| match c {
+                '0' => 'o',
+                '1' => 'i',
+                '3' => 'e',
+                '4' => 'a',
+                '5' => 's',
+                '7' => 't',
+                c => c,
+            })
+            .collect();
+        let blocked_part = plain.split('-').any(|part| {
+            BLOCKED_WORDS.contains(&part) || self.blocked.iter().any(|word| word == part)
+        });
+        let joined = plain.replace('-', "");
+        if blocked_part || BLOCKED_ANYWHERE.iter().any(|word| joined.contains(word)) {
+            return Err("That code isn't allowed");
+        }
+        if super::spells_code(&code) {
+            return Err("Custom codes can't be only the words codes are spelled out with");
+        }
+        Ok(code)
+    }
+
+    /// Load the codes in `path`, sealing plaintext lines, and append to it
+    /// from now on
+    async fn load(&mut self, path: PathBuf) -> Result<()> {
+        let contents = match fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+
+        let now = unix_now();
+        let mut entries = HashMap::new();
+        let mut lines = 0;
+        let mut migrated = 0;
+        let mut expired = 0;
+        for (n, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            lines += 1;
+            let line: Line = serde_json::from_str(line)
+                .with_context(|| format!("{} line {}", path.display(), n + 1))?;
+            match line {
+                Line::Sealed {
+                    id,
+                    sealed,
+                    expires,
+                    delete_after_download,
+                } => {
+                    let sealed = HEXLOWER
+                        .decode(sealed.as_bytes())
+                        .with_context(|| format!("{} line {}", path.display(), n + 1))?;
+                    let entry = Entry {
+                        sealed,
+                        expires,
+                        delete_after_download,
+                    };
+                    if entry.is_live(now) {
+                        entries.insert(id, entry);
+                    } else {
+                        // A later line may still renew the code
+                        entries.remove(&id);
+                        expired += 1;
+                    }
+                }
+                Line::Plain { code, ticket } => {
+                    let code = code.to_lowercase();
+                    let entry = Entry {
+                        sealed: self.seal(&code, &ticket),
+                        expires: None,
+                        delete_after_download: false,
+                    };
+                    entries.insert(self.id(&code), entry);
+                    migrated += 1;
+                }
+            }
+        }
+
+        if migrated > 0 || expired > 0 {
+            rewrite(&path, &entries).await?;
+            lines = entries.len();
+            if migrated > 0 {
+                info!("sealed {} plaintext codes in {}", migrated, path.display());
+            }
+            if expired > 0 {
+                info!("dropped {} expired codes from {}", expired, path.display());
+            }
+        }
+
+        info!("loaded {} codes from {}", entries.len(), path.display());
+        self.entries = RwLock::new(entries);
+        self.file = Some(path);
+        self.lines = AtomicUsize::new(lines);
+        Ok(())
+    }
+
+    /// `context`'s key for `code`
+    fn derive(&self, context: &str, code: &str) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new_derive_key(context);
+        if let Some(secret) = &self.secret {
+            hasher.update(secret);
+        }
+        hasher.update(code.as_bytes());
+        *hasher.finalize().as_bytes()
+    }
+
+    /// What a code is filed under
+    fn id(&self, code: &str) -> String {
+        HEXLOWER.encode(&self.derive(ID_CONTEXT, code))
+    }
+
+    fn cipher(&self, code: &str) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.derive(CIPHER_CONTEXT, code).into())
+    }
+
+    /// A random nonce, then the ticket encrypted under the code's key
+    fn seal(&self, code: &str, ticket: &str) -> Vec<u8> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .cipher(code)
+            .encrypt(XNonce::from_slice(&nonce), ticket.as_bytes())
+            .expect("a ticket is far below the cipher's message limit");
+        [&nonce[..], &ciphertext].concat()
+    }
+
+    fn open(&self, code: &str, sealed: &[u8]) -> Option<String> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let data = self
+            .cipher(code)
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        String::from_utf8(data).ok()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Replace the file at `path` with a line for each of `entries`
+async fn rewrite(path: &Path, entries: &HashMap<String, Entry>) -> Result<()> {
+    let mut out = String::new();
+    for (id, entry) in entries {
+        let line = Line::Sealed {
+            id: id.clone(),
+            sealed: HEXLOWER.encode(&entry.sealed),
+            expires: entry.expires,
+            delete_after_download: entry.delete_after_download,
+        };
+        out.push_str(&serde_json::to_string(&line)?);
+        out.push('\n');
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, out)
+        .await
+        .with_context(|| format!("writing {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("replacing {}", path.display()))?;
+    Ok(())
+}
+
+async fn append(path: &Path, line: &Line) -> Result<()> {
+    let mut json = serde_json::to_string(line)?;
+    json.push('\n');
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(json.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICKET: &str = "zap1endpointaddressesandsuch";
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let store = CodeStore::new(Some("server secret"));
+        store.insert("abc234", TICKET, TTL, false).await;
+
+        assert_eq!(store.get("abc234").await.as_deref(), Some(TICKET));
+        assert_eq!(store.get("abc235").await, None);
+
+        // Neither the code nor the ticket is stored as is
+        let entries = store.entries.read().await;
+        let (id, entry) = entries.iter().next().unwrap();
+        assert!(!id.contains("abc234"));
+        assert!(
+            !entry
+                .sealed
+                .windows(TICKET.len())
+                .any(|w| w == TICKET.as_bytes())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_claim_and_expiry() {
+        let store = CodeStore::new(None);
+        assert!(store.claim("standup-recording", TICKET, TTL, false).await);
+        assert!(
+            !store
+                .claim("standup-recording", "another", TTL, false)
+                .await
+        );
+        assert_eq!(
+            store.get("standup-recording").await.as_deref(),
+            Some(TICKET)
+        );
+
+        // An expired code is gone, and free to claim again
+        store.insert("abc234", TICKET, Duration::ZERO, false).await;
+        assert_eq!(store.get("abc234").await, None);
+        assert!(store.claim("abc234", "another", TTL, false).await);
+        store.insert("xyz789", TICKET, Duration::ZERO, false).await;
+        assert_eq!(store.prune().await, 1);
+    }
+
+    #[test]
+    fn test_check_custom() {
+        let mut store = CodeStore::new(None);
+        assert_eq!(
+            store.check_custom(" Standup-Recording ").as_deref(),
+            Ok("standup-recording")
+        );
+        assert!(store.check_custom("grape-harvest").is_ok());
+        assert!(store.check_custom("scunthorpe").is_ok());
+
+        assert!(store.check_custom("abc234").is_err());
+        assert!(store.check_custom(&"a".repeat(33)).is_err());
+        assert!(store.check_custom("under_score").is_err());
+        assert!(store.check_custom("double--hyphen").is_err());
+        assert!(store.check_custom("-leading").is_err());
+        assert!(store.check_custom("my-b1tch-code").is_err());
+        assert!(store.check_custom("holyshitcode").is_err());
+
+        // Spelled out, these are someone else's generated code
+        assert!(store.check_custom("one-two-one-two-one-two").is_err());
+        assert!(store.check_custom("Alpha-Bravo-Charlie").is_err());
+        assert!(store.check_custom("alpha-team-notes").is_ok());
+
+        store.blocked = vec!["acme".to_string()];
+        assert!(store.check_custom("acme-files").is_err());
+    }
+
+    #[test]
+    fn test_wrong_secret() {
+        let store = CodeStore::new(Some("one"));
+        let sealed = store.seal("abc234", TICKET);
+        assert_eq!(store.open("abc234", &sealed).as_deref(), Some(TICKET));
+        assert_eq!(CodeStore::new(Some("two")).open("abc234", &sealed), None);
+        assert_eq!(CodeStore::new(None).open("abc234", &sealed), None);
+
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert_eq!(store.open("abc234", &tampered), None);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_plaintext() {
+        let dir = std::env::temp_dir().join(format!("zap-codes-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("codes.jsonl");
+        fs::write(
+            &path,
+            format!("{{\"code\":\"ABC234\",\"ticket\":\"{}\"}}\n", TICKET),
+        )
+        .await
+        .unwrap();
+
+        let mut store = CodeStore::new(Some("secret"));
+        store.load(path.clone()).await.unwrap();
+        assert_eq!(store.get("abc234").await.as_deref(), Some(TICKET));
+        let contents = fs::read_to_string(&path).await.unwrap();
+        assert!(!contents.contains(TICKET));
+
+        // New codes are appended, and everything is there after a restart
+        store.insert("xyz789", "another", TTL, true).await;
+        let mut reloaded = CodeStore::new(Some("secret"));
+        reloaded.load(path).await.unwrap();
+        assert_eq!(reloaded.get("abc234").await.as_deref(), Some(TICKET));
+        assert_eq!(reloaded.get("xyz789").await.as_deref(), Some("another"));
+        assert!(reloaded.deletes_after_download("xyz789").await);
+        assert!(!reloaded.deletes_after_download("abc234").await);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let dir = std::env::temp_dir().join(format!("zap-codes-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("codes.jsonl");
+        let mut store = CodeStore::new(Some("secret"));
+        store.load(path.clone()).await.unwrap();
+
+        store.insert("abc234", TICKET, TTL, false).await;
+        store.insert("abc234", "renewed", TTL, false).await;
+        store.insert("xyz789", TICKET, Duration::ZERO, false).await;
+        let lines = |contents: String| contents.lines().count();
+        assert_eq!(lines(fs::read_to_string(&path).await.unwrap()), 3);
+
+        // Only the live code is left, as it is now
+        assert_eq!(store.prune().await, 1);
+        assert_eq!(lines(fs::read_to_string(&path).await.unwrap()), 1);
+        let mut reloaded = CodeStore::new(Some("secret"));
+        reloaded.load(path).await.unwrap();
+        assert_eq!(reloaded.get("abc234").await.as_deref(), Some("renewed"));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
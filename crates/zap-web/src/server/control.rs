@@ -0,0 +1,284 @@
+//! What the page says back over its WebSocket
+//!
+//! The page that started a transfer controls it with small JSON commands:
+//! `{"type": "accept"}` or `"reject"` for an offer waiting to be confirmed,
+//! `"cancel"`, and `"pause"` and `"resume"` for sends. The transfer id is
+//! in download links, which get shared, so controlling a transfer takes a
+//! token as well, signed from the id with a key that lives only as long as
+//! the server, and only handed to the page that started it.
+//!
+//! Before a browser receive takes an offer, the page is shown who it's
+//! from and what it is, and the receive waits until the user accepts or
+//! rejects it. The sender is shown by a fingerprint, the start of its node
+//! id, so the user can check with the sender before taking anything.
+//!
+//! Typing a code that's already being received brings back that receive,
+//! but only in the browser that started it: the browser is given a random
+//! secret in an `HttpOnly` cookie, and receives are filed under its hash.
+
+use axum::Json;
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tracing::{debug, info};
+use zap_core::PublicKey;
+use zap_core::protocol::{FileOffer, OfferKind};
+
+use super::{AppState, CHOICE_TIMEOUT, TransferStatus, update_transfer_status};
+
+/// A command from the page, as JSON like `{"type": "accept"}`
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(super) enum ClientMessage {
+    /// Take the offer waiting for confirmation
+    Accept,
+    /// Turn it down
+    Reject,
+    /// Stop the transfer for good
+    Cancel,
+    /// Stop sending data for now, keeping the receiver connected
+    Pause,
+    /// Carry on after a pause
+    Resume,
+}
+
+/// The token on requests that control a transfer, as `?token=`
+#[derive(Debug, Deserialize)]
+pub(super) struct ControlQuery {
+    #[serde(default)]
+    pub(super) token: String,
+}
+
+/// The response to a request without the right token
+pub(super) fn forbidden() -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Json(serde_json::json!({"error": "Not allowed to control this transfer"})),
+    )
+        .into_response()
+}
+
+/// Cookie with the secret a browser's receives are found again by
+const RECEIVER_COOKIE: &str = "zap_receiver";
+
+/// A browser's receiver secret from its cookie, and the `Set-Cookie`
+/// header giving it one if it has none yet
+pub(super) fn receiver_secret(headers: &HeaderMap, secure: bool) -> (String, Option<HeaderValue>) {
+    let existing = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == RECEIVER_COOKIE)
+        .map(|(_, secret)| secret)
+        .filter(|secret| blake3::Hash::from_hex(secret).is_ok());
+    if let Some(secret) = existing {
+        return (secret.to_string(), None);
+    }
+
+    let secret = blake3::Hash::from_bytes(rand::random())
+        .to_hex()
+        .to_string();
+    let cookie = format!(
+        "{}={}; Path=/; Max-Age=86400; HttpOnly; SameSite=Strict{}",
+        RECEIVER_COOKIE,
+        secret,
+        if secure { "; Secure" } else { "" }
+    );
+    (secret, HeaderValue::from_str(&cookie).ok())
+}
+
+/// What the receives of the browser with `secret` are filed under, so the
+/// secret itself is never kept
+pub(super) fn receiver_owner(secret: &str) -> String {
+    blake3::hash(secret.as_bytes()).to_hex().to_string()
+}
+
+/// Signs transfer ids into the tokens that control them
+pub(super) struct ControlKey([u8; 32]);
+
+impl ControlKey {
+    pub(super) fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    /// The token that controls `transfer_id`
+    pub(super) fn token(&self, transfer_id: &str) -> String {
+        self.sign(transfer_id).to_hex().to_string()
+    }
+
+    /// Whether `token` controls `transfer_id`
+    pub(super) fn verify(&self, transfer_id: &str, token: &str) -> bool {
+        // Hashes compare in constant time
+        blake3::Hash::from_hex(token).is_ok_and(|token| token == self.sign(transfer_id))
+    }
+
+    fn sign(&self, transfer_id: &str) -> blake3::Hash {
+        blake3::keyed_hash(&self.0, transfer_id.as_bytes())
+    }
+}
+
+/// Act on a command the page sent for `transfer_id`
+pub(super) async fn handle(state: &AppState, transfer_id: &str, text: &str) {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(e) => {
+            debug!("ignoring message from the page for {}: {}", transfer_id, e);
+            return;
+        }
+    };
+    debug!(?message, "command from the page for {}", transfer_id);
+
+    // An offer waiting for an answer is the only thing there is to control
+    if let Some(confirmation) = state.confirmations.lock().await.remove(transfer_id) {
+        let _ = confirmation.send(message == ClientMessage::Accept);
+        return;
+    }
+
+    let (handle, connected) = {
+        let transfers = state.transfers.read().await;
+        let Some(transfer) = transfers.get(transfer_id) else {
+            return;
+        };
+        let connected = matches!(
+            transfer.status,
+            TransferStatus::Connected
+                | TransferStatus::Transferring { .. }
+                | TransferStatus::Paused
+        );
+        (transfer.handle.clone(), connected)
+    };
+    match (message, handle) {
+        (ClientMessage::Accept | ClientMessage::Reject, _) => {
+            debug!("no offer waiting for confirmation on {}", transfer_id)
+        }
+        (ClientMessage::Pause, Some(handle)) => handle.pause(),
+        (ClientMessage::Resume, Some(handle)) => handle.resume(),
+        (ClientMessage::Pause | ClientMessage::Resume, None) => {
+            debug!("only sends can be paused, not {}", transfer_id)
+        }
+        // A connected send tells its receiver and reports the cancel itself
+        (ClientMessage::Cancel, Some(handle)) if connected => handle.cancel().await,
+        (ClientMessage::Cancel, _) => {
+            info!("transfer {} cancelled from the page", transfer_id);
+            state.tasks.abort(transfer_id);
+            let status = TransferStatus::Error {
+                message: "cancelled".to_string(),
+            };
+            update_transfer_status(state, transfer_id, status).await;
+            if let Some(transfer) = state.transfers.write().await.get_mut(transfer_id) {
+                transfer.completed_at = Some(std::time::Instant::now());
+            }
+        }
+    }
+}
+
+/// Show the browser who an offer is from and wait for it to be accepted
+///
+/// Rejects the offer if nobody answers in time.
+pub(super) async fn confirm_in_browser(
+    state: &AppState,
+    transfer_id: &str,
+    sender: &PublicKey,
+    offer: FileOffer,
+) -> bool {
+    let (confirm_tx, confirm_rx) = oneshot::channel();
+    state
+        .confirmations
+        .lock()
+        .await
+        .insert(transfer_id.to_string(), confirm_tx);
+    let files = match &offer.kind {
+        OfferKind::Directory { entries } => Some(entries.len()),
+        _ => None,
+    };
+    let status = TransferStatus::Confirming {
+        fingerprint: fingerprint(sender),
+        sender: sender.to_string(),
+        name: offer.name,
+        size: offer.size,
+        files,
+        label: offer.label,
+    };
+    update_transfer_status(state, transfer_id, status).await;
+
+    let answer = tokio::time::timeout(CHOICE_TIMEOUT, confirm_rx).await;
+    state.confirmations.lock().await.remove(transfer_id);
+    matches!(answer, Ok(Ok(true)))
+}
+
+/// The start of a node id in groups of four, short enough to compare by
+/// eye
+pub(super) fn fingerprint(id: &PublicKey) -> String {
+    let id = id.to_string();
+    let start: Vec<char> = id.chars().take(16).collect();
+    start
+        .chunks(4)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_message() {
+        let parse = |text| serde_json::from_str::<ClientMessage>(text).ok();
+        assert_eq!(parse(r#"{"type":"accept"}"#), Some(ClientMessage::Accept));
+        assert_eq!(parse(r#"{"type":"reject"}"#), Some(ClientMessage::Reject));
+        assert_eq!(parse(r#"{"type":"cancel"}"#), Some(ClientMessage::Cancel));
+        assert_eq!(parse(r#"{"type":"pause"}"#), Some(ClientMessage::Pause));
+        assert_eq!(parse(r#"{"type":"resume"}"#), Some(ClientMessage::Resume));
+        assert_eq!(parse(r#"{"type":"explode"}"#), None);
+        assert_eq!(parse("accept"), None);
+    }
+
+    #[test]
+    fn test_control_key() {
+        let key = ControlKey::generate();
+        let token = key.token("transfer-1");
+        assert!(key.verify("transfer-1", &token));
+        assert!(!key.verify("transfer-2", &token));
+        assert!(!key.verify("transfer-1", &key.token("transfer-2")));
+        assert!(!key.verify("transfer-1", ""));
+        assert!(!key.verify("transfer-1", "not hex"));
+        // Another server's tokens don't work here
+        assert!(!ControlKey::generate().verify("transfer-1", &token));
+    }
+
+    #[test]
+    fn test_receiver_secret() {
+        let (secret, cookie) = receiver_secret(&HeaderMap::new(), true);
+        let cookie = cookie.unwrap();
+        let cookie = cookie.to_str().unwrap();
+        assert!(cookie.starts_with(&format!("zap_receiver={};", secret)));
+        assert!(cookie.contains("HttpOnly") && cookie.ends_with("; Secure"));
+
+        // The browser sends it back with its other cookies
+        let mut headers = HeaderMap::new();
+        let sent = format!("theme=dark; zap_receiver={}", secret);
+        headers.insert(header::COOKIE, sent.parse().unwrap());
+        assert_eq!(receiver_secret(&headers, true), (secret.clone(), None));
+
+        // Anything but a secret this server could have made gets a new one
+        headers.insert(header::COOKIE, "zap_receiver=guess".parse().unwrap());
+        let (other, cookie) = receiver_secret(&headers, false);
+        assert_ne!(other, "guess");
+        assert!(!cookie.unwrap().to_str().unwrap().contains("Secure"));
+        assert_ne!(receiver_owner(&secret), receiver_owner(&other));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let id = zap_core::SecretKey::from_bytes(&[7; 32]).public();
+        let fingerprint = fingerprint(&id);
+        assert_eq!(fingerprint.len(), 19);
+        let groups: Vec<&str> = fingerprint.split(' ').collect();
+        assert_eq!(groups.len(), 4);
+        assert_eq!(groups.concat(), id.to_string()[..16]);
+    }
+}
@@ -0,0 +1,217 @@
+//! What happens to a code, told to the sender who registered it
+//!
+//! A CLI sender only hears from a receiver once it connects, which can
+//! take a while, and hears nothing of the browsers that download through
+//! the relay after it. `GET /api/codes/{code}/events` tells it instead:
+//! each time the code is looked up, and each time a browser finishes
+//! downloading what the relay received with it.
+//!
+//! It's a long poll. With `?after=n` it answers with the events after the
+//! `n`th, waiting up to [`POLL_TIMEOUT`] for one if there are none yet.
+//! Only the sender can ask, with the token it got back for the code, which
+//! is signed like the tokens that control transfers and so stops working
+//! when the relay restarts.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::watch;
+
+use super::AppState;
+use super::control::ControlKey;
+
+/// How long a poll waits for something to happen
+const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Events kept per code; a sender that falls further behind still has the
+/// counts
+const MAX_EVENTS: usize = 100;
+
+/// Something that happened to a code
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(super) enum CodeEvent {
+    /// A receiver looked the code up, with the zap CLI or the web page
+    Claimed { web: bool },
+    /// A browser finished downloading what the relay received with the code
+    Downloaded { file_name: Option<String> },
+}
+
+/// An event as the sender gets it
+#[derive(Debug, Clone, Serialize)]
+struct Numbered {
+    /// Counting from 1 for each code
+    seq: u64,
+    #[serde(flatten)]
+    event: CodeEvent,
+    /// Lookups of the code so far, this one included
+    claims: u64,
+    /// Downloads through the relay so far
+    downloads: u64,
+}
+
+#[derive(Debug, Default)]
+struct Log {
+    seq: u64,
+    claims: u64,
+    downloads: u64,
+    events: VecDeque<Numbered>,
+}
+
+/// The events of the codes registered with the API, by code
+#[derive(Default)]
+pub(super) struct CodeEvents {
+    logs: Mutex<HashMap<String, (Instant, watch::Sender<Log>)>>,
+}
+
+impl CodeEvents {
+    /// Start keeping events for `code`, until it expires in `ttl`
+    pub(super) fn open(&self, code: &str, ttl: Duration) {
+        let (log, _) = watch::channel(Log::default());
+        self.logs
+            .lock()
+            .unwrap()
+            .insert(code.to_string(), (Instant::now() + ttl, log));
+    }
+
+    /// Note `event` for `code`, if its events are kept
+    pub(super) fn record(&self, code: &str, event: CodeEvent) {
+        let logs = self.logs.lock().unwrap();
+        let Some((_, log)) = logs.get(code) else {
+            return;
+        };
+        log.send_modify(|log| {
+            log.seq += 1;
+            match event {
+                CodeEvent::Claimed { .. } => log.claims += 1,
+                CodeEvent::Downloaded { .. } => log.downloads += 1,
+            }
+            if log.events.len() == MAX_EVENTS {
+                log.events.pop_front();
+            }
+            log.events.push_back(Numbered {
+                seq: log.seq,
+                event,
+                claims: log.claims,
+                downloads: log.downloads,
+            });
+        });
+    }
+
+    fn subscribe(&self, code: &str) -> Option<watch::Receiver<Log>> {
+        let logs = self.logs.lock().unwrap();
+        logs.get(code)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, log)| log.subscribe())
+    }
+
+    /// Forget the events of expired codes, ending their polls, returning
+    /// how many there were
+    pub(super) fn prune(&self) -> usize {
+        let now = Instant::now();
+        let mut logs = self.logs.lock().unwrap();
+        let before = logs.len();
+        logs.retain(|_, (expires, _)| *expires > now);
+        before - logs.len()
+    }
+}
+
+/// The token the sender polls `code`'s events with
+pub(super) fn token(key: &ControlKey, code: &str) -> String {
+    key.token(&subject(code))
+}
+
+/// What the token signs; transfer ids are UUIDs, so never this
+fn subject(code: &str) -> String {
+    format!("code/{}", code)
+}
+
+#[derive(Deserialize)]
+pub(super) struct EventsQuery {
+    #[serde(default)]
+    token: String,
+    /// The last event the sender has seen
+    #[serde(default)]
+    after: u64,
+}
+
+/// The events of a code after `?after=`, as soon as there are any
+pub(super) async fn api_code_events(
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+    Query(query): Query<EventsQuery>,
+) -> Response {
+    let code = code.to_lowercase();
+    if !state.control_key.verify(&subject(&code), &query.token) {
+        return (
+            StatusCode::FORBIDDEN,
+            axum::Json(json!({"error": "Only the sender can follow this code"})),
+        )
+            .into_response();
+    }
+    let Some(mut log) = state.events.subscribe(&code) else {
+        return (
+            StatusCode::NOT_FOUND,
+            axum::Json(json!({"error": "Code not found or expired"})),
+        )
+            .into_response();
+    };
+
+    // Ends early with what there is if the code expires meanwhile
+    let _ = tokio::time::timeout(POLL_TIMEOUT, log.wait_for(|log| log.seq > query.after)).await;
+    let events: Vec<_> = log
+        .borrow()
+        .events
+        .iter()
+        .filter(|event| event.seq > query.after)
+        .cloned()
+        .collect();
+    axum::Json(json!({ "events": events })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_code_events() {
+        let events = CodeEvents::default();
+        let hour = Duration::from_secs(60 * 60);
+        events.record("abc234", CodeEvent::Claimed { web: false });
+        assert!(events.subscribe("abc234").is_none());
+
+        events.open("abc234", hour);
+        let mut log = events.subscribe("abc234").unwrap();
+        events.record("abc234", CodeEvent::Claimed { web: false });
+        events.record("abc234", CodeEvent::Claimed { web: true });
+        let file_name = Some("slides.pdf".to_string());
+        events.record("abc234", CodeEvent::Downloaded { file_name });
+        log.wait_for(|log| log.seq == 3).await.unwrap();
+        {
+            let log = log.borrow();
+            let last = log.events.back().unwrap();
+            assert_eq!((last.seq, last.claims, last.downloads), (3, 2, 1));
+            let json = serde_json::to_value(last).unwrap();
+            assert_eq!(json["type"], "downloaded");
+            assert_eq!(json["file_name"], "slides.pdf");
+        }
+
+        for _ in 0..MAX_EVENTS {
+            events.record("abc234", CodeEvent::Claimed { web: false });
+        }
+        let log = events.subscribe("abc234").unwrap();
+        let log = log.borrow();
+        assert_eq!(log.events.len(), MAX_EVENTS);
+        assert_eq!(log.events.front().unwrap().seq, 4);
+
+        events.open("def567", Duration::ZERO);
+        assert!(events.subscribe("def567").is_none());
+        assert_eq!(events.prune(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+//! `POST /send/url`: the relay downloads a file from elsewhere and sends it
+//!
+//! The page gives a link instead of a file. The relay fetches it into the
+//! staged file the way `PUT /send/{id}` would, so the transfer gets going
+//! while it downloads. Only relays started with `ZAP_FETCH_URLS=1` do this,
+//! and only from public addresses, so a link can't reach into the relay's
+//! own network, not even by redirecting there.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use reqwest::Url;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::CONTENT_DISPOSITION;
+use reqwest::redirect::Policy;
+use serde::Deserialize;
+use tracing::{Instrument, warn};
+
+use super::upload::{register, stage};
+use super::{AppState, send_status_html};
+
+/// How long the linked server has to start answering
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+pub(super) struct FetchForm {
+    url: String,
+    /// A checkbox, which is only sent when ticked
+    #[serde(default)]
+    delete_after_download: Option<String>,
+}
+
+/// Start sending the file at a link, fetched by the relay
+pub(super) async fn fetch_url(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    axum::Form(form): axum::Form<FetchForm>,
+) -> Response {
+    let failed = |status: StatusCode, message: &str| {
+        let html = format!(r##"<div class="text-red-400">{}</div>"##, message);
+        (status, Html(html)).into_response()
+    };
+    if !state.fetch_urls {
+        return failed(StatusCode::NOT_FOUND, "This relay doesn't fetch links");
+    }
+    let url = match Url::parse(form.url.trim()) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && is_public_url(&url) => url,
+        _ => {
+            return failed(
+                StatusCode::BAD_REQUEST,
+                "Only public http and https links can be sent",
+            );
+        }
+    };
+
+    let response = match client() {
+        Ok(client) => client.get(url.clone()).send().await,
+        Err(e) => Err(e),
+    };
+    let response = match response.and_then(|response| response.error_for_status()) {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("fetching {} failed: {}", url, e);
+            return failed(StatusCode::BAD_GATEWAY, "Could not fetch the link");
+        }
+    };
+    let Some(size) = response.content_length() else {
+        return failed(
+            StatusCode::BAD_GATEWAY,
+            "The link's server doesn't say how big the file is",
+        );
+    };
+    let name = file_name(&url, response.headers());
+
+    let delete_after_download = form.delete_after_download.is_some();
+    let (transfer_id, name) =
+        match register(&state, &headers, &name, size, delete_after_download).await {
+            Ok(registered) => registered,
+            Err(response) => return response,
+        };
+
+    let body = futures::stream::try_unfold(response, |mut response| async move {
+        let chunk = response.chunk().await.map_err(std::io::Error::other)?;
+        Ok::<_, std::io::Error>(chunk.map(|chunk| (chunk, response)))
+    });
+    let fetching = {
+        let state = state.clone();
+        let transfer_id = transfer_id.clone();
+        async move {
+            // How it went is recorded on the transfer, as for an upload
+            stage(&state, &transfer_id, Box::pin(body)).await;
+        }
+    };
+    tokio::spawn(fetching.instrument(zap_core::logs::span(&transfer_id)));
+
+    let token = state.control_key.token(&transfer_id);
+    Html(send_status_html(&transfer_id, &name, &token)).into_response()
+}
+
+/// A client that only connects to public addresses, wherever it's
+/// redirected
+fn client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(PublicOnly))
+        .redirect(Policy::custom(|attempt| {
+            if attempt.previous().len() >= 10 {
+                attempt.error("too many redirects")
+            } else if !is_public_url(attempt.url()) {
+                attempt.error("redirected to a private address")
+            } else {
+                attempt.follow()
+            }
+        }))
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+}
+
+/// Resolves names, leaving out addresses that aren't public
+struct PublicOnly;
+
+impl Resolve for PublicOnly {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether a URL's host is a name, which [`PublicOnly`] checks, or a
+/// public address
+fn is_public_url(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    // IPv6 addresses come in brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.parse().map_or(true, is_public)
+}
+
+/// Whether `ip` is reachable on the internet, rather than loopback, a
+/// private network or the like
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7, and link local, fe80::/10
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The name the linked server gives the file, or else the last part of the
+/// link's path
+fn file_name(url: &Url, headers: &reqwest::header::HeaderMap) -> String {
+    let from_header = headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value.split(';').find_map(|param| {
+                let (key, name) = param.trim().split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("filename")
+                    .then(|| name.trim().trim_matches('"').to_string())
+            })
+        });
+    let from_path = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(str::to_string);
+    // `register` keeps only the last component of whichever it is
+    [from_header, from_path]
+        .into_iter()
+        .flatten()
+        .find(|name| !name.is_empty())
+        .unwrap_or_else(|| "file".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_public_addresses() {
+        let public = |s: &str| is_public(s.parse().unwrap());
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:2800:220:1:248:1893:25c8:1946"));
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "172.16.5.4",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!public(private), "{}", private);
+        }
+
+        let url = |s: &str| is_public_url(&Url::parse(s).unwrap());
+        assert!(url("https://example.com/big.iso"));
+        assert!(!url("http://169.254.169.254/latest/meta-data"));
+        assert!(!url("http://[::1]:8080/"));
+    }
+}
@@ -0,0 +1,475 @@
+//! Sealed files left on the relay for receivers to pick up later
+//!
+//! `zap send --async` uploads a small file sealed under a key the relay
+//! never sees, see `zap_core::mirror`, and gets a code for it like any
+//! other. The code's entry in the [`CodeStore`](super::codes::CodeStore)
+//! names the blob instead of holding a ticket, and `/api/lookup` tells the
+//! receiver to fetch it from `/api/mirror/{code}` rather than connect to
+//! anyone, so the sender can be long gone by then.
+//!
+//! Blobs are capped at `ZAP_MIRROR_MAX_SIZE` bytes (100 MB by default, `0`
+//! turns mirroring off) and kept for `ZAP_MIRROR_TTL` seconds (a day by
+//! default), or less if the sender asks. They can also be deleted after
+//! their first download. Uploads are written to disk as they arrive, and
+//! the ones without an API key share `ZAP_MIRROR_ANONYMOUS_QUOTA` bytes
+//! (1 GiB by default, `0` requires a key), counting blobs still kept and
+//! uploads still coming in.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::accounting::{Direction, metric};
+use super::{AppState, code_to_words, generate_short_code, words_to_code};
+
+/// What a code's stored "ticket" starts with when it names a blob
+pub(super) const PREFIX: &str = "mirror:";
+
+/// Blobs are kept in this directory under the temp directory
+const DIR: &str = "mirror";
+
+const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_ANONYMOUS_QUOTA: u64 = 1024 * 1024 * 1024;
+
+/// A blob on disk
+struct Blob {
+    expires: SystemTime,
+    size: u64,
+    /// Uploaded without an API key, so it counts against the anonymous
+    /// quota
+    anonymous: bool,
+}
+
+#[derive(Default)]
+struct Kept {
+    blobs: HashMap<String, Blob>,
+    /// Bytes of anonymous blobs kept and anonymous uploads coming in
+    anonymous: u64,
+}
+
+impl Kept {
+    fn remove(&mut self, id: &str) -> Option<Blob> {
+        let blob = self.blobs.remove(id)?;
+        if blob.anonymous {
+            self.anonymous -= blob.size;
+        }
+        Some(blob)
+    }
+}
+
+/// Blobs on disk and when each expires
+pub(super) struct MirrorStore {
+    dir: PathBuf,
+    /// Largest blob accepted, 0 when mirroring is off
+    pub(super) max_size: u64,
+    ttl: Duration,
+    /// Bytes anonymous uploads may hold at once, 0 when they need a key
+    anonymous_quota: u64,
+    kept: Mutex<Kept>,
+}
+
+impl MirrorStore {
+    /// Read `ZAP_MIRROR_MAX_SIZE`, `ZAP_MIRROR_TTL` and
+    /// `ZAP_MIRROR_ANONYMOUS_QUOTA`, and pick up the blobs a previous run
+    /// left, which expire a full TTL after they were written and count as
+    /// anonymous
+    pub(super) async fn from_env(temp_dir: &std::path::Path) -> Result<Self> {
+        let var = |name: &str, default: u64| -> Result<u64> {
+            match std::env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .with_context(|| format!("invalid {} {:?}", name, value)),
+                Err(_) => Ok(default),
+            }
+        };
+        let store = Self {
+            dir: temp_dir.join(DIR),
+            max_size: var("ZAP_MIRROR_MAX_SIZE", DEFAULT_MAX_SIZE)?,
+            ttl: Duration::from_secs(var("ZAP_MIRROR_TTL", DEFAULT_TTL.as_secs())?),
+            anonymous_quota: var("ZAP_MIRROR_ANONYMOUS_QUOTA", DEFAULT_ANONYMOUS_QUOTA)?,
+            kept: Mutex::new(Kept::default()),
+        };
+        fs::create_dir_all(&store.dir).await?;
+
+        let mut dir = fs::read_dir(&store.dir).await?;
+        let mut kept = Kept::default();
+        while let Some(entry) = dir.next_entry().await? {
+            let Some(id) = entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            if Uuid::parse_str(&id).is_err() {
+                // Left by an upload that didn't finish
+                let _ = fs::remove_file(entry.path()).await;
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            let blob = Blob {
+                expires: metadata.modified()? + store.ttl,
+                size: metadata.len(),
+                anonymous: true,
+            };
+            kept.anonymous += blob.size;
+            kept.blobs.insert(id, blob);
+        }
+        *store.kept.lock().unwrap() = kept;
+        Ok(store)
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    /// Delete expired blobs, returning how many there were
+    pub(super) async fn prune(&self) -> usize {
+        let now = SystemTime::now();
+        let expired: Vec<_> = {
+            let mut kept = self.kept.lock().unwrap();
+            let expired: Vec<_> = kept
+                .blobs
+                .iter()
+                .filter(|(_, blob)| blob.expires <= now)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &expired {
+                kept.remove(id);
+            }
+            expired
+        };
+        for id in &expired {
+            self.remove_file(id).await;
+        }
+        expired.len()
+    }
+
+    async fn remove_file(&self, id: &str) {
+        let path = self.path(id);
+        if let Err(e) = fs::remove_file(&path).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("failed to remove mirrored blob {:?}: {}", path, e);
+        }
+    }
+
+    /// Gauges of the kept blobs, in the Prometheus text format
+    pub(super) fn prometheus(&self) -> String {
+        let mut out = String::new();
+        metric(
+            &mut out,
+            "zap_mirrored_files",
+            "Sealed files kept on the relay for later pickup",
+            "gauge",
+            [("", self.kept.lock().unwrap().blobs.len() as u64)],
+        );
+        out
+    }
+
+    /// Write `body` to disk as blob `id`, failing once it's over `limit`
+    /// bytes or takes anonymous bytes past the quota, returning its size
+    ///
+    /// The bytes of an anonymous upload stay counted if it succeeds, for
+    /// the blob the caller keeps.
+    async fn receive(
+        &self,
+        body: Body,
+        id: &str,
+        limit: u64,
+        anonymous: bool,
+    ) -> Result<u64, Response> {
+        // Written under another name until it's all there, so a restart
+        // doesn't pick up half a blob
+        let partial = self.path(&format!("{}.part", id));
+        let mut size = 0;
+        let result = self
+            .write(body, &partial, limit, anonymous, &mut size)
+            .await;
+        let result = match result {
+            Ok(()) => fs::rename(&partial, self.path(id)).await.map_err(failed),
+            Err(response) => Err(response),
+        };
+        if let Err(response) = result {
+            let _ = fs::remove_file(&partial).await;
+            if anonymous {
+                self.kept.lock().unwrap().anonymous -= size;
+            }
+            return Err(response);
+        }
+        Ok(size)
+    }
+
+    /// Write `body` to `path`, counting what's written in `size`
+    async fn write(
+        &self,
+        body: Body,
+        path: &std::path::Path,
+        limit: u64,
+        anonymous: bool,
+        size: &mut u64,
+    ) -> Result<(), Response> {
+        let mut file = fs::File::create_new(path).await.map_err(failed)?;
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|_| error(StatusCode::BAD_REQUEST, "Upload cut short"))?;
+            let len = chunk.len() as u64;
+            if *size + len > limit {
+                let message = format!("Files kept on this relay must be under {} bytes", limit);
+                return Err(error(StatusCode::PAYLOAD_TOO_LARGE, &message));
+            }
+            if anonymous {
+                let mut kept = self.kept.lock().unwrap();
+                if kept.anonymous + len > self.anonymous_quota {
+                    return Err(error(
+                        StatusCode::INSUFFICIENT_STORAGE,
+                        "This relay is holding all the files it keeps without an API key",
+                    ));
+                }
+                kept.anonymous += len;
+            }
+            *size += len;
+            file.write_all(&chunk).await.map_err(failed)?;
+        }
+        file.flush().await.map_err(failed)?;
+        if *size < zap_core::mirror::OVERHEAD as u64 {
+            return Err(error(StatusCode::BAD_REQUEST, "Not a sealed file"));
+        }
+        Ok(())
+    }
+}
+
+fn failed(e: std::io::Error) -> Response {
+    warn!("failed to keep mirrored blob: {}", e);
+    error(StatusCode::INTERNAL_SERVER_ERROR, "Could not keep the file")
+}
+
+#[derive(Deserialize)]
+pub(super) struct UploadQuery {
+    /// Seconds to keep the blob, at most the relay's TTL
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(default)]
+    delete_after_download: bool,
+}
+
+#[derive(Serialize)]
+struct UploadResponse {
+    code: String,
+    words: String,
+    /// Seconds until the blob and its code are gone
+    expires_in: u64,
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, axum::Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+/// Keep a sealed blob and hand out a code for it
+pub(super) async fn upload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<UploadQuery>,
+    body: Body,
+) -> Response {
+    let mirror = &state.mirror;
+    if mirror.max_size == 0 {
+        return error(StatusCode::NOT_FOUND, "This relay doesn't keep files");
+    }
+    let api_key = match state.accounting.identify(&headers) {
+        Ok(key) => key,
+        Err(status) => return error(status, "Invalid API key"),
+    };
+    if let Err(status) = state.accounting.check_quota(api_key.as_deref()) {
+        return error(status, "Monthly quota exceeded");
+    }
+    let anonymous = api_key.is_none();
+    if anonymous && mirror.anonymous_quota == 0 {
+        return error(
+            StatusCode::UNAUTHORIZED,
+            "This relay only keeps files for API keys",
+        );
+    }
+    let limit = match state.accounting.remaining(api_key.as_deref()) {
+        Some(remaining) => remaining.min(mirror.max_size),
+        None => mirror.max_size,
+    };
+
+    let ttl = query
+        .expires_in
+        .map(Duration::from_secs)
+        .unwrap_or(mirror.ttl)
+        .min(mirror.ttl);
+    let id = Uuid::new_v4().to_string();
+    let size = match mirror.receive(body, &id, limit, anonymous).await {
+        Ok(size) => size,
+        Err(response) => return response,
+    };
+    let blob = Blob {
+        expires: SystemTime::now() + ttl,
+        size,
+        anonymous,
+    };
+    mirror.kept.lock().unwrap().blobs.insert(id.clone(), blob);
+
+    let code = generate_short_code();
+    let target = format!("{}{}", PREFIX, id);
+    state
+        .codes
+        .insert(&code, &target, ttl, query.delete_after_download)
+        .await;
+    state
+        .accounting
+        .record(Direction::Staged, api_key.as_deref(), Some(&code), size);
+    state
+        .accounting
+        .record_transfer(api_key.as_deref(), Some(&code));
+    info!(bytes = size, "keeping a sealed file");
+
+    axum::Json(UploadResponse {
+        words: code_to_words(&code),
+        code,
+        expires_in: ttl.as_secs(),
+    })
+    .into_response()
+}
+
+/// The sealed blob a code names
+pub(super) async fn download(State(state): State<AppState>, Path(code): Path<String>) -> Response {
+    let mut code = code.to_lowercase();
+    let mut target = state.codes.get(&code).await;
+    if target.is_none() && code.contains('-') {
+        code = words_to_code(&code);
+        target = state.codes.get(&code).await;
+    }
+    let Some(id) = target.as_deref().and_then(|t| t.strip_prefix(PREFIX)) else {
+        return error(StatusCode::NOT_FOUND, "Code not found or expired");
+    };
+
+    let mirror = &state.mirror;
+    let once = state.codes.deletes_after_download(&code).await;
+    // A blob deleted after its download is taken out under the lock, so
+    // only one of two downloads racing for it gets it
+    let claimed = {
+        let mut kept = mirror.kept.lock().unwrap();
+        if once {
+            kept.remove(id).is_some()
+        } else {
+            kept.blobs.contains_key(id)
+        }
+    };
+    if !claimed {
+        return error(
+            StatusCode::GONE,
+            "The file was already downloaded or has expired",
+        );
+    }
+    let file = fs::File::open(mirror.path(id)).await;
+    if once {
+        // Still readable through the open file
+        mirror.remove_file(id).await;
+    }
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("failed to read mirrored blob: {}", e);
+            return error(StatusCode::GONE, "The file is no longer on the relay");
+        }
+    };
+    let size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    state
+        .accounting
+        .record(Direction::Served, None, Some(&code), size);
+
+    let body = Body::from_stream(tokio_util::io::ReaderStream::new(file));
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prune() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).await.unwrap();
+        let store = MirrorStore {
+            dir: dir.clone(),
+            max_size: DEFAULT_MAX_SIZE,
+            ttl: DEFAULT_TTL,
+            anonymous_quota: DEFAULT_ANONYMOUS_QUOTA,
+            kept: Mutex::new(Kept::default()),
+        };
+        let now = SystemTime::now();
+        for (id, expires) in [("old", now - DEFAULT_TTL), ("new", now + DEFAULT_TTL)] {
+            fs::write(store.path(id), b"sealed").await.unwrap();
+            let blob = Blob {
+                expires,
+                size: 6,
+                anonymous: true,
+            };
+            let mut kept = store.kept.lock().unwrap();
+            kept.anonymous += blob.size;
+            kept.blobs.insert(id.to_string(), blob);
+        }
+
+        assert_eq!(store.prune().await, 1);
+        assert!(!store.path("old").exists());
+        assert!(store.path("new").exists());
+        assert_eq!(store.kept.lock().unwrap().anonymous, 6);
+        assert!(store.prometheus().contains("zap_mirrored_files 1\n"));
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_quota() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).await.unwrap();
+        let store = MirrorStore {
+            dir: dir.clone(),
+            max_size: DEFAULT_MAX_SIZE,
+            ttl: DEFAULT_TTL,
+            anonymous_quota: 100,
+            kept: Mutex::new(Kept::default()),
+        };
+        let sealed = || Body::from(vec![0u8; 60]);
+
+        assert_eq!(
+            store.receive(sealed(), "a", 1000, true).await.ok(),
+            Some(60)
+        );
+        assert_eq!(store.kept.lock().unwrap().anonymous, 60);
+        let refused = store.receive(sealed(), "b", 1000, true).await.unwrap_err();
+        assert_eq!(refused.status(), StatusCode::INSUFFICIENT_STORAGE);
+        // What the refused upload reserved is given back and nothing's left
+        assert_eq!(store.kept.lock().unwrap().anonymous, 60);
+        assert!(!store.path("b.part").exists());
+        // Uploads with a key only have the size limit
+        assert_eq!(
+            store.receive(sealed(), "c", 1000, false).await.ok(),
+            Some(60)
+        );
+        let refused = store.receive(sealed(), "d", 50, false).await.unwrap_err();
+        assert_eq!(refused.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,289 @@
+//! Recovering staged files left behind by a previous run
+//!
+//! Transfer state lives in memory, so after a crash or restart nothing
+//! knows about the directories already in the temp directory and the
+//! periodic cleanup never removes them. Each transfer with a finished file
+//! writes a small record next to it; on startup, directories are adopted
+//! back from their records or deleted, depending on `ZAP_ORPHANS`.
+
+use std::time::Instant;
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::{AppState, TransferState, TransferStatus};
+
+/// Written inside a transfer's directory once its file is complete
+const RECORD_FILE: &str = ".zap-transfer.json";
+
+/// What to do with transfer directories found on startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum OrphanPolicy {
+    /// Remove them all
+    Delete,
+    /// Serve finished files again until they expire, removing the rest
+    Adopt,
+    /// Leave them alone
+    Keep,
+}
+
+impl OrphanPolicy {
+    /// Read `ZAP_ORPHANS` (`delete`, `adopt` or `keep`), defaulting to adopt
+    pub(super) fn from_env() -> Result<Self> {
+        match std::env::var("ZAP_ORPHANS").as_deref() {
+            Err(_) | Ok("adopt") => Ok(Self::Adopt),
+            Ok("delete") => Ok(Self::Delete),
+            Ok("keep") => Ok(Self::Keep),
+            Ok(other) => bail!(
+                "invalid ZAP_ORPHANS {:?}, expected delete, adopt or keep",
+                other
+            ),
+        }
+    }
+}
+
+/// What a transfer directory holds, enough to serve it again
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct TransferRecord {
+    file_name: String,
+    checksum: Option<String>,
+    short_code: Option<String>,
+    api_key: Option<String>,
+    #[serde(default)]
+    delete_after_download: bool,
+    /// Received for the receive form, which can find it again by its code
+    #[serde(default)]
+    receive: bool,
+    /// Hash of the secret of the browser that started the receive
+    #[serde(default)]
+    owner: Option<String>,
+}
+
+/// Record a transfer's finished file so it can be adopted after a restart
+pub(super) async fn save_record(state: &AppState, transfer_id: &str) {
+    let record = {
+        let transfers = state.transfers.read().await;
+        let Some(transfer) = transfers.get(transfer_id) else {
+            return;
+        };
+        let Some(file_name) = transfer.file_name.clone() else {
+            return;
+        };
+        TransferRecord {
+            file_name,
+            checksum: transfer.checksum.clone(),
+            short_code: transfer.short_code.clone(),
+            api_key: transfer.api_key.clone(),
+            delete_after_download: transfer.delete_after_download,
+            receive: transfer.receive,
+            owner: transfer.owner.clone(),
+        }
+    };
+
+    let path = state.temp_dir.join(transfer_id).join(RECORD_FILE);
+    let result = match serde_json::to_vec(&record) {
+        Ok(json) => fs::write(&path, json).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        warn!("failed to write transfer record {:?}: {}", path, e);
+    }
+}
+
+/// Deal with transfer directories that no tracked transfer owns
+///
+/// Only directories named like transfer ids are touched, so other files in
+/// a shared temp directory are left alone.
+pub(super) async fn recover(state: &AppState, policy: OrphanPolicy) -> Result<()> {
+    if policy == OrphanPolicy::Keep {
+        return Ok(());
+    }
+
+    let mut adopted = 0;
+    let mut removed = 0;
+    let mut dir = fs::read_dir(&state.temp_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let Some(id) = entry.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        if Uuid::parse_str(&id).is_err() || !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        if state.transfers.read().await.contains_key(&id) {
+            continue;
+        }
+
+        if policy == OrphanPolicy::Adopt && adopt(state, &id).await {
+            adopted += 1;
+            continue;
+        }
+
+        match fs::remove_dir_all(entry.path()).await {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("failed to remove orphaned {:?}: {}", entry.path(), e),
+        }
+    }
+
+    if adopted > 0 || removed > 0 {
+        info!(
+            "found orphaned transfers: adopted {}, removed {}",
+            adopted, removed
+        );
+    }
+    Ok(())
+}
+
+/// Track a directory's transfer again if it has a record and its file
+async fn adopt(state: &AppState, id: &str) -> bool {
+    let dir = state.temp_dir.join(id);
+    let Ok(json) = fs::read(dir.join(RECORD_FILE)).await else {
+        return false;
+    };
+    let Ok(record) = serde_json::from_slice::<TransferRecord>(&json) else {
+        return false;
+    };
+
+    // The name comes from disk, so make sure it stays inside the directory
+    let file_path = dir.join(&record.file_name);
+    if file_path.parent() != Some(dir.as_path())
+        || !fs::try_exists(&file_path).await.unwrap_or(false)
+    {
+        return false;
+    }
+
+    // Nothing listens for progress; adopted transfers can only be downloaded
+    let (progress_tx, _) = mpsc::channel(1);
+    let now = Instant::now();
+    state.transfers.write().await.insert(
+        id.to_string(),
+        TransferState {
+            status: TransferStatus::Complete {
+                path: Some(format!("/download/{}", id)),
+                checksum: record.checksum.clone(),
+            },
+            ticket: None,
+            short_code: record.short_code,
+            file_name: Some(record.file_name),
+            label: None,
+            receive: record.receive,
+            owner: record.owner,
+            file_path: Some(file_path),
+            api_key: record.api_key,
+            checksum: record.checksum,
+            expected_checksum: None,
+            upload: None,
+            delete_after_download: record.delete_after_download,
+            handle: None,
+            progress_tx,
+            bytes_per_sec: None,
+            created_at: now,
+            completed_at: Some(now),
+        },
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_round_trip() {
+        let record = TransferRecord {
+            file_name: "photo.jpg".to_string(),
+            checksum: Some("ab".repeat(32)),
+            short_code: Some("abc234".to_string()),
+            api_key: None,
+            delete_after_download: true,
+            receive: true,
+            owner: Some("cd".repeat(32)),
+        };
+        let json = serde_json::to_vec(&record).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<TransferRecord>(&json).unwrap(),
+            record
+        );
+    }
+
+    /// Leave a transfer's directory in `temp_dir`, with its file and a
+    /// record of it if `file_name` is given
+    async fn orphan(temp_dir: &std::path::Path, file_name: Option<&str>) -> String {
+        let id = Uuid::new_v4().to_string();
+        let dir = temp_dir.join(&id);
+        fs::create_dir_all(&dir).await.unwrap();
+        if let Some(file_name) = file_name {
+            fs::write(dir.join(file_name), b"staged").await.unwrap();
+            let record = TransferRecord {
+                file_name: file_name.to_string(),
+                checksum: None,
+                short_code: Some("abc234".to_string()),
+                api_key: None,
+                delete_after_download: false,
+                receive: false,
+                owner: None,
+            };
+            let json = serde_json::to_vec(&record).unwrap();
+            fs::write(dir.join(RECORD_FILE), json).await.unwrap();
+        }
+        id
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let temp_dir = std::env::temp_dir().join(format!("zap-orphans-{}", Uuid::new_v4()));
+        let state = AppState::for_test(temp_dir.clone(), None).await;
+        let exists = |name: &str| std::fs::exists(temp_dir.join(name)).unwrap();
+
+        let finished = orphan(&temp_dir, Some("photo.jpg")).await;
+        let unrecorded = orphan(&temp_dir, None).await;
+        let missing = orphan(&temp_dir, Some("gone.txt")).await;
+        fs::remove_file(temp_dir.join(&missing).join("gone.txt"))
+            .await
+            .unwrap();
+        let escaping = orphan(&temp_dir, Some("../photo.jpg")).await;
+        fs::create_dir(temp_dir.join("not-a-transfer"))
+            .await
+            .unwrap();
+
+        // Keeping them leaves everything as it was
+        recover(&state, OrphanPolicy::Keep).await.unwrap();
+        assert!(state.transfers.read().await.is_empty());
+        assert!(
+            [&finished, &unrecorded, &missing, &escaping]
+                .iter()
+                .all(|id| exists(id))
+        );
+
+        // Only a finished file with its record is served again
+        recover(&state, OrphanPolicy::Adopt).await.unwrap();
+        {
+            let transfers = state.transfers.read().await;
+            assert_eq!(transfers.len(), 1);
+            let adopted = &transfers[&finished];
+            assert!(matches!(adopted.status, TransferStatus::Complete { .. }));
+            assert_eq!(adopted.short_code.as_deref(), Some("abc234"));
+            assert_eq!(
+                adopted.file_path,
+                Some(temp_dir.join(&finished).join("photo.jpg"))
+            );
+        }
+        assert!(exists(&finished));
+        assert!(!exists(&unrecorded));
+        assert!(!exists(&missing));
+        assert!(!exists(&escaping));
+        assert!(exists("not-a-transfer"));
+
+        // Tracked transfers are left alone, and the rest deleted
+        let other = orphan(&temp_dir, Some("notes.txt")).await;
+        recover(&state, OrphanPolicy::Delete).await.unwrap();
+        assert!(exists(&finished));
+        assert!(!exists(&other));
+        assert!(exists("not-a-transfer"));
+
+        fs::remove_dir_all(&temp_dir).await.unwrap();
+    }
+}
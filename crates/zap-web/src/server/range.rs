@@ -0,0 +1,170 @@
+//! Byte ranges, so an interrupted download can pick up where it stopped
+//!
+//! `/download/{id}` answers a single `Range: bytes=...` with `206 Partial
+//! Content`. Staged files keep their transfer id across a restart when
+//! they're adopted back (see [`super::orphans`]), and the validators don't
+//! change either: the `ETag` is the file's BLAKE3 and `Last-Modified` its
+//! modification time. So a browser resuming with `If-Range` gets the rest
+//! of the same file, or the whole file again if it was replaced.
+
+use axum::http::HeaderMap;
+use axum::http::header::{IF_RANGE, RANGE};
+
+/// What part of a file a request asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Requested {
+    /// The whole file, including when a range can't be honoured, such as
+    /// several ranges at once or a stale `If-Range`
+    Full,
+    /// Bytes `start..=end`
+    Partial { start: u64, end: u64 },
+    /// A range entirely past the end of the file
+    Unsatisfiable,
+}
+
+/// The part of a `len` byte file that `headers` ask for, given the file's
+/// current validators
+pub(super) fn requested(
+    headers: &HeaderMap,
+    len: u64,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> Requested {
+    let Some(range) = headers.get(RANGE).and_then(|r| r.to_str().ok()) else {
+        return Requested::Full;
+    };
+
+    // The client's copy has to be of the file as it is now
+    if let Some(validator) = headers.get(IF_RANGE) {
+        let validator = validator.to_str().unwrap_or_default().trim();
+        let current = if validator.starts_with('"') {
+            etag
+        } else {
+            last_modified
+        };
+        if current != Some(validator) {
+            return Requested::Full;
+        }
+    }
+
+    parse(range, len)
+}
+
+/// Parse a `Range` header value for a `len` byte file
+fn parse(range: &str, len: u64) -> Requested {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return Requested::Full;
+    };
+    if spec.contains(',') {
+        return Requested::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Requested::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let (start, end) = if start.is_empty() {
+        // The last `end` bytes
+        let Ok(suffix) = end.parse::<u64>() else {
+            return Requested::Full;
+        };
+        if suffix == 0 {
+            return Requested::Unsatisfiable;
+        }
+        (len.saturating_sub(suffix), len.saturating_sub(1))
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return Requested::Full;
+        };
+        let end = match end {
+            "" => len.saturating_sub(1),
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+                _ => return Requested::Full,
+            },
+        };
+        (start, end)
+    };
+
+    if start >= len {
+        Requested::Unsatisfiable
+    } else {
+        Requested::Partial { start, end }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("bytes=0-99", 1000),
+            Requested::Partial { start: 0, end: 99 }
+        );
+        assert_eq!(
+            parse("bytes=500-", 1000),
+            Requested::Partial {
+                start: 500,
+                end: 999
+            }
+        );
+        assert_eq!(
+            parse("bytes=-100", 1000),
+            Requested::Partial {
+                start: 900,
+                end: 999
+            }
+        );
+        assert_eq!(
+            parse("bytes=900-5000", 1000),
+            Requested::Partial {
+                start: 900,
+                end: 999
+            }
+        );
+        assert_eq!(
+            parse("bytes=-5000", 1000),
+            Requested::Partial { start: 0, end: 999 }
+        );
+        assert_eq!(parse("bytes=1000-", 1000), Requested::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Requested::Unsatisfiable);
+        assert_eq!(parse("bytes=0-1,5-9", 1000), Requested::Full);
+        assert_eq!(parse("bytes=9-5", 1000), Requested::Full);
+        assert_eq!(parse("items=0-5", 1000), Requested::Full);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let headers = |if_range: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RANGE, HeaderValue::from_static("bytes=10-"));
+            headers.insert(IF_RANGE, HeaderValue::from_str(if_range).unwrap());
+            headers
+        };
+        let resumed = Requested::Partial { start: 10, end: 99 };
+
+        let check = |h: &HeaderMap| requested(h, 100, Some("\"abc\""), Some(date));
+        assert_eq!(check(&headers("\"abc\"")), resumed);
+        assert_eq!(check(&headers(date)), resumed);
+        assert_eq!(check(&headers("\"old\"")), Requested::Full);
+        assert_eq!(
+            check(&headers("Mon, 07 Nov 1994 08:49:37 GMT")),
+            Requested::Full
+        );
+
+        // Without a checksum there's no ETag to match
+        assert_eq!(
+            requested(&headers("\"abc\""), 100, None, Some(date)),
+            Requested::Full
+        );
+        assert_eq!(
+            requested(&HeaderMap::new(), 100, None, None),
+            Requested::Full
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Pointing clients at the iroh relay nearest to them
+//!
+//! A deployment with iroh relays in several regions lists them in
+//! `ZAP_IROH_RELAYS` as comma-separated `range=url` pairs, e.g.
+//! `10.0.0.0/8=https://eu.relay.example,0.0.0.0/0=https://us.relay.example`.
+//! A client is sent the relay of the most specific range its address falls
+//! in, going by the first `X-Forwarded-For` entry when the relay sits
+//! behind a proxy. Clients outside every range get no hint and keep their
+//! own relays.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result, bail};
+use axum::http::HeaderMap;
+use reqwest::Url;
+
+/// The iroh relays of a deployment, by the addresses they serve
+#[derive(Debug, Default)]
+pub(super) struct Steering {
+    routes: Vec<Route>,
+}
+
+#[derive(Debug)]
+struct Route {
+    network: IpAddr,
+    prefix: u8,
+    relay: String,
+}
+
+impl Steering {
+    /// Read `ZAP_IROH_RELAYS`; without it nobody is steered
+    pub(super) fn from_env() -> Result<Self> {
+        match std::env::var("ZAP_IROH_RELAYS") {
+            Ok(spec) if !spec.trim().is_empty() => {
+                Self::parse(&spec).context("invalid ZAP_IROH_RELAYS")
+            }
+            _ => Ok(Self::default()),
+        }
+    }
+
+    fn parse(spec: &str) -> Result<Self> {
+        let mut routes = Vec::new();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let Some((range, relay)) = pair.split_once('=') else {
+                bail!("{:?} isn't range=url", pair);
+            };
+            let (network, prefix) = parse_range(range.trim())?;
+            let relay = relay.trim();
+            let url = Url::parse(relay).with_context(|| format!("{:?} isn't a URL", relay))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                bail!("{:?} isn't an http or https URL", relay);
+            }
+            routes.push(Route {
+                network,
+                prefix,
+                relay: relay.to_string(),
+            });
+        }
+        Ok(Self { routes })
+    }
+
+    /// The relay for a client at `ip`, if one of the ranges has it
+    pub(super) fn relay_for(&self, ip: IpAddr) -> Option<&str> {
+        let ip = ip.to_canonical();
+        self.routes
+            .iter()
+            .filter(|route| contains(route.network, route.prefix, ip))
+            .max_by_key(|route| route.prefix)
+            .map(|route| route.relay.as_str())
+    }
+
+    /// The relay for the client that made a request
+    pub(super) fn relay_for_request(&self, headers: &HeaderMap, peer: SocketAddr) -> Option<&str> {
+        if self.routes.is_empty() {
+            return None;
+        }
+        self.relay_for(client_ip(headers, peer))
+    }
+}
+
+/// Parse `10.0.0.0/8`, or a bare address as a range of one
+fn parse_range(range: &str) -> Result<(IpAddr, u8)> {
+    let (addr, prefix) = match range.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (range, None),
+    };
+    let network: IpAddr = addr
+        .parse()
+        .with_context(|| format!("{:?} isn't an IP address", addr))?;
+    let bits = if network.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|p| *p <= bits)
+            .with_context(|| format!("{:?} isn't a prefix length up to {}", prefix, bits))?,
+        None => bits,
+    };
+    Ok((network.to_canonical(), prefix))
+}
+
+fn contains(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    let (network, ip, bits) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => (
+            u128::from(u32::from(network)),
+            u128::from(u32::from(ip)),
+            32,
+        ),
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+        _ => return false,
+    };
+    let shift = bits - u32::from(prefix);
+    shift >= bits || network >> shift == ip >> shift
+}
+
+/// Where a request came from: the first `X-Forwarded-For` address, or the
+/// connection's
+fn client_ip(headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .unwrap_or(peer.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steering() {
+        let steering = Steering::parse(
+            "10.0.0.0/8=https://eu.relay.example, 10.1.0.0/16=https://eu2.relay.example,\
+             2001:db8::/32=https://v6.relay.example,0.0.0.0/0=https://us.relay.example",
+        )
+        .unwrap();
+        let relay = |ip: &str| steering.relay_for(ip.parse().unwrap());
+        assert_eq!(relay("10.2.3.4"), Some("https://eu.relay.example"));
+        assert_eq!(relay("10.1.3.4"), Some("https://eu2.relay.example"));
+        assert_eq!(relay("93.184.216.34"), Some("https://us.relay.example"));
+        assert_eq!(relay("::ffff:10.2.3.4"), Some("https://eu.relay.example"));
+        assert_eq!(relay("2001:db8::1"), Some("https://v6.relay.example"));
+        assert_eq!(relay("2606:2800::1"), None);
+
+        for invalid in [
+            "10.0.0.0/8",
+            "10.0.0.0/33=https://eu.relay.example",
+            "eu=https://eu.relay.example",
+            "10.0.0.0/8=eu.relay.example",
+        ] {
+            assert!(Steering::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(client_ip(&headers, peer), peer.ip());
+        headers.insert("x-forwarded-for", "10.2.3.4, 127.0.0.1".parse().unwrap());
+        assert_eq!(
+            client_ip(&headers, peer),
+            "10.2.3.4".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! The send and receive tasks the relay runs for browsers
+//!
+//! Each runs a node of its own for as long as its transfer does, which can
+//! be long after the page that started it is gone: a send waits for a
+//! receiver that may never come. Tasks are kept by transfer id so they can
+//! be counted, and stopped when their transfer expires.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+use super::accounting::metric;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum TaskKind {
+    Send,
+    Receive,
+}
+
+struct Task {
+    kind: TaskKind,
+    handle: JoinHandle<()>,
+}
+
+/// Running tasks by transfer id
+#[derive(Default)]
+pub(super) struct TaskRegistry {
+    tasks: Mutex<HashMap<String, Task>>,
+}
+
+/// How many tasks are running, by kind
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct ActiveTasks {
+    pub(super) sends: usize,
+    pub(super) receives: usize,
+}
+
+impl TaskRegistry {
+    /// Run `task` for the transfer `id`, stopping any it already had
+    ///
+    /// What the task logs is kept as the transfer's log.
+    pub(super) fn spawn(
+        &self,
+        id: &str,
+        kind: TaskKind,
+        task: impl Future<Output = ()> + Send + 'static,
+    ) {
+        let handle = tokio::spawn(task.instrument(zap_core::logs::span(id)));
+        let mut tasks = self.tasks.lock().unwrap();
+        forget_finished(&mut tasks);
+        if let Some(previous) = tasks.insert(id.to_string(), Task { kind, handle }) {
+            previous.handle.abort();
+        }
+    }
+
+    /// Stop the task of the transfer `id`, returning whether it was still
+    /// running
+    pub(super) fn abort(&self, id: &str) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        forget_finished(&mut tasks);
+        match tasks.remove(id) {
+            Some(task) => {
+                task.handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop the tasks of transfers `stale` picks, returning how many were
+    /// still running
+    pub(super) fn abort_where(&self, stale: impl Fn(&str) -> bool) -> usize {
+        let mut tasks = self.tasks.lock().unwrap();
+        forget_finished(&mut tasks);
+        let mut aborted = 0;
+        tasks.retain(|id, task| {
+            if !stale(id) {
+                return true;
+            }
+            task.handle.abort();
+            aborted += 1;
+            false
+        });
+        aborted
+    }
+
+    pub(super) fn active(&self) -> ActiveTasks {
+        let mut tasks = self.tasks.lock().unwrap();
+        forget_finished(&mut tasks);
+        let mut active = ActiveTasks::default();
+        for task in tasks.values() {
+            match task.kind {
+                TaskKind::Send => active.sends += 1,
+                TaskKind::Receive => active.receives += 1,
+            }
+        }
+        active
+    }
+
+    /// Gauges of the running tasks, in the Prometheus text format
+    pub(super) fn prometheus(&self) -> String {
+        let active = self.active();
+        let mut out = String::new();
+        metric(
+            &mut out,
+            "zap_active_sends",
+            "Sends the relay is running for browsers",
+            "gauge",
+            [("", active.sends as u64)],
+        );
+        metric(
+            &mut out,
+            "zap_active_receives",
+            "Receives the relay is running for browsers",
+            "gauge",
+            [("", active.receives as u64)],
+        );
+        out
+    }
+}
+
+/// Drop the handles of tasks that have ended on their own
+fn forget_finished(tasks: &mut HashMap<String, Task>) {
+    tasks.retain(|_, task| !task.handle.is_finished());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_registry() {
+        let registry = TaskRegistry::default();
+        let counts = |registry: &TaskRegistry| {
+            let active = registry.active();
+            (active.sends, active.receives)
+        };
+        registry.spawn("done", TaskKind::Send, async {});
+        registry.spawn("waiting", TaskKind::Send, std::future::pending());
+        registry.spawn("receiving", TaskKind::Receive, std::future::pending());
+        tokio::task::yield_now().await;
+
+        assert_eq!(counts(&registry), (1, 1));
+        assert!(registry.prometheus().contains("zap_active_sends 1\n"));
+
+        assert_eq!(registry.abort_where(|id| id == "waiting"), 1);
+        assert_eq!(counts(&registry), (0, 1));
+
+        // A transfer's task replaces the one it had
+        registry.spawn("receiving", TaskKind::Send, std::future::pending());
+        assert_eq!(counts(&registry), (1, 0));
+
+        assert!(registry.abort("receiving"));
+        assert!(!registry.abort("receiving"));
+        assert_eq!(counts(&registry), (0, 0));
+    }
+}
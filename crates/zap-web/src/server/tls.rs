@@ -0,0 +1,280 @@
+//! Serving HTTPS directly, with client certificates for the API
+//!
+//! Relays usually sit behind a proxy that handles TLS. One that serves
+//! HTTPS itself reads its certificate chain and key from `ZAP_TLS_CERT`
+//! and `ZAP_TLS_KEY`, both PEM files. A locked-down relay also sets
+//! `ZAP_CLIENT_CA` to the PEM certificates of the CAs it trusts: `/api/*`
+//! then only answers clients presenting a certificate one of them signed,
+//! as `zap --client-cert` does, while pages stay open to browsers.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use axum::Json;
+use axum::Router;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+use tracing::{debug, warn};
+
+/// How the relay serves HTTPS
+pub(super) struct Tls {
+    acceptor: TlsAcceptor,
+    /// Whether `/api/*` needs a client certificate
+    pub(super) client_auth: bool,
+}
+
+/// Set on requests over a connection whose client presented a certificate
+/// from a trusted CA
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ClientCert;
+
+impl Tls {
+    /// Read `ZAP_TLS_CERT`, `ZAP_TLS_KEY` and `ZAP_CLIENT_CA`; without a
+    /// certificate the relay serves plain HTTP
+    pub(super) fn from_env() -> Result<Option<Self>> {
+        let var = |name| {
+            std::env::var_os(name)
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+        };
+        match (
+            var("ZAP_TLS_CERT"),
+            var("ZAP_TLS_KEY"),
+            var("ZAP_CLIENT_CA"),
+        ) {
+            (Some(cert), Some(key), client_ca) => {
+                let read = |path: &Path| {
+                    std::fs::read(path)
+                        .with_context(|| format!("could not read {}", path.display()))
+                };
+                let client_ca = client_ca.as_deref().map(read).transpose()?;
+                Self::from_pem(&read(&cert)?, &read(&key)?, client_ca.as_deref()).map(Some)
+            }
+            (None, None, None) => Ok(None),
+            (_, _, Some(_)) => bail!("ZAP_CLIENT_CA needs ZAP_TLS_CERT and ZAP_TLS_KEY"),
+            _ => bail!("ZAP_TLS_CERT and ZAP_TLS_KEY go together"),
+        }
+    }
+
+    /// Serve with the certificate chain and key in `cert` and `key`,
+    /// checking clients against the CAs in `client_ca`
+    fn from_pem(cert: &[u8], key: &[u8], client_ca: Option<&[u8]>) -> Result<Self> {
+        let chain = read_certs(cert).context("invalid ZAP_TLS_CERT")?;
+        let key = PrivateKeyDer::from_pem_slice(key).context("invalid ZAP_TLS_KEY")?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(pem) => builder.with_client_cert_verifier(client_verifier(pem, provider)?),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(chain, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            client_auth: client_ca.is_some(),
+        })
+    }
+}
+
+fn read_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("no certificates in it");
+    }
+    Ok(certs)
+}
+
+/// Check client certificates against the CAs in `pem`, letting clients
+/// without one connect, since browsers load pages without
+fn client_verifier(
+    pem: &[u8],
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    let (added, _) =
+        roots.add_parsable_certificates(read_certs(pem).context("invalid ZAP_CLIENT_CA")?);
+    if added == 0 {
+        bail!("no usable CA certificates in ZAP_CLIENT_CA");
+    }
+    Ok(
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .allow_unauthenticated()
+            .build()?,
+    )
+}
+
+/// Turn away API calls from clients without a trusted certificate
+pub(super) async fn require_client_cert(request: Request, next: Next) -> Response {
+    let api = request.uri().path().starts_with("/api/");
+    if api && request.extensions().get::<ClientCert>().is_none() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({"error": "This relay needs a client certificate"})),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Serve `app` over TLS on `listener` until `shutdown` completes, then
+/// wait for open connections to finish
+pub(super) async fn serve(
+    listener: TcpListener,
+    tls: Tls,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        let (tcp, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("accepting a connection failed: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let acceptor = tls.acceptor.clone();
+        let app = app.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(tcp).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            // The handshake already checked any certificate against the
+            // CAs, so having one is enough
+            let verified = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .is_some_and(|certs| !certs.is_empty());
+            let service = hyper::service::service_fn(move |mut request: Request<_>| {
+                request
+                    .extensions_mut()
+                    .insert(ConnectInfo::<SocketAddr>(peer));
+                if verified {
+                    request.extensions_mut().insert(ClientCert);
+                }
+                app.clone().call(request)
+            });
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            if let Err(e) = watcher.watch(connection.into_owned()).await {
+                debug!("connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+
+    graceful.shutdown().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair,
+    };
+
+    use super::*;
+
+    fn new_ca() -> CertifiedIssuer<'static, KeyPair> {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+    }
+
+    /// A certificate for `name` signed by `ca`, and its key, as PEM
+    fn leaf(
+        ca: &CertifiedIssuer<'static, KeyPair>,
+        name: &str,
+        usage: ExtendedKeyUsagePurpose,
+    ) -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, ca).unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    #[tokio::test]
+    async fn test_client_certs() {
+        let ca = new_ca();
+        let (cert, key) = leaf(&ca, "relay.test", ExtendedKeyUsagePurpose::ServerAuth);
+        let tls =
+            Tls::from_pem(cert.as_bytes(), key.as_bytes(), Some(ca.pem().as_bytes())).unwrap();
+        assert!(tls.client_auth);
+
+        let app = Router::new()
+            .route("/", get(|| async { "page" }))
+            .route("/api/ping", get(|| async { "pong" }))
+            .layer(axum::middleware::from_fn(require_client_cert));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, tls, app, async {
+            let _ = stopped.await;
+        }));
+
+        let request = |identity: Option<(String, String)>, path: &str| {
+            let mut builder = reqwest::Client::builder()
+                .resolve("relay.test", addr)
+                .add_root_certificate(reqwest::Certificate::from_pem(ca.pem().as_bytes()).unwrap());
+            if let Some((cert, key)) = identity {
+                let pem = format!("{}{}", cert, key);
+                builder = builder.identity(reqwest::Identity::from_pem(pem.as_bytes()).unwrap());
+            }
+            let request = builder
+                .build()
+                .unwrap()
+                .get(format!("https://relay.test:{}{}", addr.port(), path))
+                .send();
+            async move { request.await.map(|response| response.status()) }
+        };
+
+        let client = leaf(&ca, "laptop", ExtendedKeyUsagePurpose::ClientAuth);
+        assert_eq!(
+            request(Some(client), "/api/ping").await.unwrap(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            request(None, "/api/ping").await.unwrap(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(request(None, "/").await.unwrap(), StatusCode::OK);
+
+        // A certificate from some other CA doesn't get a connection at all
+        let stranger = leaf(&new_ca(), "laptop", ExtendedKeyUsagePurpose::ClientAuth);
+        assert!(request(Some(stranger), "/api/ping").await.is_err());
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+//! Sending a file from the browser while it's still being uploaded
+//!
+//! `POST /send` only answers once the whole file is staged, so the
+//! WebSocket, the node and the code all wait for the upload. Instead, the
+//! page registers the file with `POST /send/start`, opens the WebSocket and
+//! then uploads the body with `PUT /send/{id}`. The node starts right away
+//! and streams the staged file to the receiver as it grows, so the upload
+//! and the peer-to-peer transfer overlap.
+//!
+//! A configured scanner needs the whole file, so with one the node still
+//! waits for the upload to finish.
+
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use futures::{Stream, TryStreamExt};
+use serde::Deserialize;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{
+    AppState, Direction, TransferState, TransferStatus, generate_short_code, notify, orphans,
+    send_status_html,
+};
+
+/// How far an upload has got
+#[derive(Debug, Clone, Default)]
+pub(super) struct Upload {
+    /// Size the page announced for the file
+    pub(super) size: u64,
+    /// Bytes written to the staged file so far
+    pub(super) bytes: u64,
+    /// Whether the body is being received yet
+    started: bool,
+    /// How the upload ended, once it has
+    pub(super) finished: Option<Result<(), String>>,
+}
+
+impl Upload {
+    pub(super) fn is_complete(&self) -> bool {
+        matches!(self.finished, Some(Ok(())))
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct StartSendForm {
+    name: String,
+    size: u64,
+    #[serde(default)]
+    delete_after_download: bool,
+}
+
+/// Register a file that's about to be uploaded with `PUT /send/{id}`
+pub(super) async fn start_send(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    axum::Form(form): axum::Form<StartSendForm>,
+) -> Response {
+    let registered = register(
+        &state,
+        &headers,
+        &form.name,
+        form.size,
+        form.delete_after_download,
+    )
+    .await;
+    match registered {
+        Ok((transfer_id, name)) => {
+            let token = state.control_key.token(&transfer_id);
+            Html(send_status_html(&transfer_id, &name, &token)).into_response()
+        }
+        Err(response) => response,
+    }
+}
+
+/// Register a `size` byte file that's about to be staged, returning its
+/// transfer id and the name it's staged under
+pub(super) async fn register(
+    state: &AppState,
+    headers: &HeaderMap,
+    name: &str,
+    size: u64,
+    delete_after_download: bool,
+) -> Result<(String, String), Response> {
+    let api_key = match state.accounting.identify(headers) {
+        Ok(key) => key,
+        Err(status) => {
+            return Err((
+                status,
+                Html(r##"<div class="text-red-400">Invalid API key</div>"##),
+            )
+                .into_response());
+        }
+    };
+    if let Err(status) = state.accounting.check_quota(api_key.as_deref()) {
+        return Err((
+            status,
+            Html(r##"<div class="text-red-400">Monthly quota exceeded</div>"##),
+        )
+            .into_response());
+    }
+    if state
+        .accounting
+        .remaining(api_key.as_deref())
+        .is_some_and(|remaining| size > remaining)
+    {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Html(r##"<div class="text-red-400">File exceeds the remaining monthly quota</div>"##),
+        )
+            .into_response());
+    }
+    if size > super::MAX_FILE_SIZE as u64 {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Html(r##"<div class="text-red-400">File is too large</div>"##),
+        )
+            .into_response());
+    }
+
+    // Only the last component, like a multipart file name
+    let name = FsPath::new(name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "file".to_string());
+
+    let transfer_id = Uuid::new_v4().to_string();
+    let transfer_dir = state.temp_dir.join(&transfer_id);
+    let path = transfer_dir.join(&name);
+    let created = async {
+        fs::create_dir_all(&transfer_dir).await?;
+        File::create(&path).await
+    };
+    if let Err(e) = created.await {
+        return Err(Html(format!(
+            r##"<div class="text-red-400">Error creating directory: {}</div>"##,
+            e
+        ))
+        .into_response());
+    }
+
+    let short_code = generate_short_code();
+    state
+        .accounting
+        .record_transfer(api_key.as_deref(), Some(&short_code));
+
+    let (progress_tx, _) = mpsc::channel(32);
+    state.transfers.write().await.insert(
+        transfer_id.clone(),
+        TransferState {
+            status: TransferStatus::Pending,
+            ticket: None,
+            short_code: Some(short_code),
+            file_name: Some(name.clone()),
+            label: None,
+            receive: false,
+            owner: None,
+            file_path: Some(path),
+            api_key,
+            checksum: None,
+            expected_checksum: None,
+            upload: Some(Arc::new(
+                watch::channel(Upload {
+                    size,
+                    ..Default::default()
+                })
+                .0,
+            )),
+            delete_after_download,
+            handle: None,
+            progress_tx,
+            bytes_per_sec: None,
+            created_at: Instant::now(),
+            completed_at: None,
+        },
+    );
+    Ok((transfer_id, name))
+}
+
+/// Receive the body of a file registered with `POST /send/start` into its
+/// staged file
+pub(super) async fn upload_file(
+    State(state): State<AppState>,
+    Path(transfer_id): Path<String>,
+    body: Body,
+) -> Response {
+    let stream = body.into_data_stream().map_err(std::io::Error::other);
+    stage(&state, &transfer_id, stream).await
+}
+
+/// Write `stream` to the staged file of `transfer_id`, which has to be
+/// waiting for it, answering the way `PUT /send/{id}` does
+pub(super) async fn stage(
+    state: &AppState,
+    transfer_id: &str,
+    stream: impl Stream<Item = std::io::Result<Bytes>> + Unpin,
+) -> Response {
+    let (upload, path, size, api_key, short_code) = {
+        let transfers = state.transfers.read().await;
+        let Some(transfer) = transfers.get(transfer_id) else {
+            return (StatusCode::NOT_FOUND, "Transfer not found").into_response();
+        };
+        let (Some(upload), Some(path)) = (transfer.upload.clone(), transfer.file_path.clone())
+        else {
+            return (StatusCode::CONFLICT, "Transfer isn't waiting for an upload").into_response();
+        };
+        let size = upload.borrow().size;
+        (
+            upload,
+            path,
+            size,
+            transfer.api_key.clone(),
+            transfer.short_code.clone(),
+        )
+    };
+
+    // Only the first request gets to write the file
+    if !upload.send_if_modified(|u| !std::mem::replace(&mut u.started, true)) {
+        return (StatusCode::CONFLICT, "Upload already started").into_response();
+    }
+
+    let result = write_upload(stream, &path, size, &upload, state, transfer_id).await;
+    match result {
+        Ok(hash) => {
+            {
+                let mut transfers = state.transfers.write().await;
+                if let Some(transfer) = transfers.get_mut(transfer_id) {
+                    transfer.checksum = Some(hash.to_hex().to_string());
+                }
+            }
+            state.accounting.record(
+                Direction::Staged,
+                api_key.as_deref(),
+                short_code.as_deref(),
+                size,
+            );
+            upload.send_modify(|u| u.finished = Some(Ok(())));
+            orphans::save_record(state, transfer_id).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            warn!("upload for transfer {} failed: {}", transfer_id, e);
+            upload.send_modify(|u| u.finished = Some(Err(e.to_string())));
+            {
+                let mut transfers = state.transfers.write().await;
+                if let Some(transfer) = transfers.get_mut(transfer_id) {
+                    transfer.file_path = None;
+                    transfer.completed_at = Some(Instant::now());
+                }
+            }
+            let _ = fs::remove_dir_all(state.temp_dir.join(transfer_id)).await;
+            super::update_transfer_status(
+                state,
+                transfer_id,
+                TransferStatus::Error {
+                    message: format!("Upload failed: {}", e),
+                },
+            )
+            .await;
+            let status = if e.kind() == std::io::ErrorKind::FileTooLarge {
+                StatusCode::PAYLOAD_TOO_LARGE
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            (status, e.to_string()).into_response()
+        }
+    }
+}
+
+/// Write the body to `path`, telling readers how much is there after every
+/// chunk, and checking it's exactly `size` bytes
+async fn write_upload(
+    mut stream: impl Stream<Item = std::io::Result<Bytes>> + Unpin,
+    path: &PathBuf,
+    size: u64,
+    upload: &watch::Sender<Upload>,
+    state: &AppState,
+    transfer_id: &str,
+) -> std::io::Result<blake3::Hash> {
+    let mut file = fs::OpenOptions::new().write(true).open(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut total = 0u64;
+    let mut reported = Instant::now();
+
+    while let Some(chunk) = stream.try_next().await? {
+        total += chunk.len() as u64;
+        if total > size {
+            return Err(std::io::ErrorKind::FileTooLarge.into());
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+        // Readers open the file separately, so the data must be written
+        // through before they're told about it
+        file.flush().await?;
+        upload.send_modify(|u| u.bytes = total);
+
+        if reported.elapsed().as_millis() >= 250 || total == size {
+            reported = Instant::now();
+            notify(
+                state,
+                transfer_id,
+                TransferStatus::Uploading {
+                    bytes: total,
+                    total: size,
+                },
+            )
+            .await;
+        }
+    }
+
+    if total != size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("expected {} bytes, got {}", size, total),
+        ));
+    }
+    Ok(hasher.finalize())
+}
+
+/// Read a staged file while it's being uploaded, waiting for more data at
+/// its current end until the upload finishes
+pub(super) async fn growing_reader(
+    path: &FsPath,
+    upload: watch::Receiver<Upload>,
+) -> std::io::Result<impl AsyncRead + Send + Unpin + 'static> {
+    let file = File::open(path).await?;
+    let stream = futures::stream::try_unfold(
+        (file, upload, 0u64),
+        |(mut file, mut upload, read)| async move {
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let n = file.read(&mut buffer).await?;
+                if n > 0 {
+                    buffer.truncate(n);
+                    let chunk = std::io::Cursor::new(buffer);
+                    return Ok(Some((chunk, (file, upload, read + n as u64))));
+                }
+
+                let state = upload.borrow_and_update().clone();
+                match state.finished {
+                    Some(Ok(())) if read >= state.bytes => return Ok(None),
+                    Some(Err(e)) => return Err(std::io::Error::other(e)),
+                    // More was written since the read hit the end
+                    _ if state.bytes > read => continue,
+                    _ => {}
+                }
+                if upload.changed().await.is_err() {
+                    return Err(std::io::Error::other("upload was abandoned"));
+                }
+            }
+        },
+    );
+    Ok(tokio_util::io::StreamReader::new(Box::pin(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_growing_reader() {
+        let dir = std::env::temp_dir().join(format!("zap-upload-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("file");
+        let mut file = File::create(&path).await.unwrap();
+        let (upload, rx) = watch::channel(Upload::default());
+
+        let reader = growing_reader(&path, rx).await.unwrap();
+        let read = tokio::spawn(async move {
+            let mut reader = reader;
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await.map(|_| data)
+        });
+
+        for part in [&b"hello "[..], b"growing ", b"file"] {
+            file.write_all(part).await.unwrap();
+            file.flush().await.unwrap();
+            upload.send_modify(|u| u.bytes += part.len() as u64);
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        upload.send_modify(|u| u.finished = Some(Ok(())));
+
+        let data = read.await.unwrap().unwrap();
+        assert_eq!(data, b"hello growing file");
+
+        // A failed upload fails the reader
+        let (upload, rx) = watch::channel(Upload::default());
+        let mut reader = growing_reader(&path, rx).await.unwrap();
+        upload.send_modify(|u| u.finished = Some(Err("connection reset".into())));
+        let mut data = Vec::new();
+        assert!(reader.read_to_end(&mut data).await.is_err());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
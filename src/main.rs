@@ -1,15 +1,42 @@
 use std::net::SocketAddr;
 
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use tracing_subscriber::EnvFilter;
-
-const DEFAULT_RELAY: &str = "https://zapper.cloud";
+use tracing_subscriber::prelude::*;
 
 #[derive(Parser)]
 #[command(name = "zap")]
 #[command(version, about = "Fast, secure file transfers", long_about = None)]
 struct Cli {
+    /// Settings profile to use, see `zap profile`
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Plain output for screen readers and logs: no colors, symbols or
+    /// progress bars; on by default when TERM=dumb is set
+    #[arg(long, global = true)]
+    plain: bool,
+
+    /// When to color output; `auto` leaves it off in pipes and when
+    /// NO_COLOR is set
+    #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = zap_cli::ColorChoice::Auto)]
+    color: zap_cli::ColorChoice,
+
+    /// Write the version, the outcome and a debug log of the command to
+    /// this file when it's done, to attach to a bug report
+    #[arg(long, global = true, value_name = "PATH")]
+    debug_bundle: Option<std::path::PathBuf>,
+
+    /// PEM certificate to show relays that need one, instead of the
+    /// profile's; goes with --client-key
+    #[arg(long, global = true, value_name = "PATH", requires = "client_key")]
+    client_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key for --client-cert
+    #[arg(long, global = true, value_name = "PATH", requires = "client_cert")]
+    client_key: Option<std::path::PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -18,16 +45,156 @@ struct Cli {
 enum Commands {
     /// Send a file or folder
     Send {
-        /// Path to the file or folder to send (interactive if not provided)
-        path: Option<std::path::PathBuf>,
+        /// Files or folders to send, or `-` for stdin (interactive if not
+        /// provided). Several paths from one folder are sent together
+        paths: Vec<std::path::PathBuf>,
+
+        /// File name to offer when sending from stdin, a URL or a command
+        #[arg(long)]
+        name: Option<String>,
+
+        /// A short description shown to the receiver with the offer, e.g.
+        /// "Q3 report v2", to tell several transfers apart
+        #[arg(long, value_parser = zap_cli::parse_label, conflicts_with = "leave_on_relay")]
+        label: Option<String>,
+
+        /// Send the file at this URL, downloading it as it's sent instead
+        /// of saving it here first
+        #[arg(long, value_name = "URL", conflicts_with_all = ["paths", "queue", "share", "recipients", "exit_after", "leave_on_relay"])]
+        from_url: Option<String>,
+
+        /// Send what this shell command writes, as it's written, e.g.
+        /// 'pg_dump db'. The transfer fails if the command does
+        #[arg(long, value_name = "COMMAND", conflicts_with_all = ["paths", "from_url", "queue", "share", "recipients", "exit_after", "leave_on_relay"])]
+        command: Option<String>,
+
+        /// Also send the paths listed in this file, one per line, or `-` to
+        /// read the list from stdin
+        #[arg(long, value_name = "LIST", conflicts_with_all = ["from_url", "command"])]
+        from_file: Option<std::path::PathBuf>,
+
+        /// Send folders with more than this many files (default 100) or deep
+        /// nesting as a single archive
+        #[arg(long, value_name = "FILES", num_args = 0..=1, default_missing_value = "100")]
+        auto_archive: Option<usize>,
+
+        /// Send each path under its own code, one after another
+        #[arg(long, conflicts_with_all = ["port", "debug_connection"])]
+        queue: bool,
+
+        /// With --queue, how many files to serve at once
+        #[arg(long, default_value_t = 1, requires = "queue")]
+        concurrency: usize,
+
+        /// With --queue, how many files one receiver can get at once
+        #[arg(long, value_name = "N", requires = "queue")]
+        per_peer: Option<usize>,
+
+        /// With --queue, a file to send before the others, which pause
+        /// while it's being sent; can be repeated
+        #[arg(long, value_name = "PATH", requires = "queue")]
+        urgent: Vec<std::path::PathBuf>,
+
+        /// Keep serving the same code to everyone who uses it, until stopped
+        #[arg(long, conflicts_with = "queue")]
+        share: bool,
+
+        /// Give each of this many recipients a code of their own, which
+        /// works once, and show which codes were claimed
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..),
+            conflicts_with_all = ["queue", "share", "exit_after", "code", "port", "debug_connection"])]
+        recipients: Option<u16>,
+
+        /// Stop after the first receiver has everything (the default), after
+        /// --downloads receivers have, or never, like --share
+        #[arg(long, value_enum, conflicts_with_all = ["queue", "share"])]
+        exit_after: Option<zap_cli::ExitAfter>,
+
+        /// With --exit-after all, how many receivers get the files
+        #[arg(long, value_name = "N", required_if_eq("exit_after", "all"))]
+        downloads: Option<u32>,
+
+        /// With --share or --exit-after, list the file in the relay's
+        /// catalog at /catalog for receivers who don't have the code;
+        /// `key` lists it only for those with the same ZAP_API_KEY
+        #[arg(long, value_enum, value_name = "WHO", num_args = 0..=1, default_missing_value = "public",
+            conflicts_with_all = ["queue", "recipients", "leave_on_relay", "require_key", "no_relay", "ticket_only"])]
+        list: Option<zap_cli::Listing>,
+
+        /// Stop once nobody has been connected for this long, e.g. "10m";
+        /// a send nobody came for fails
+        #[arg(long, value_name = "DURATION", value_parser = zap_cli::parse_duration, conflicts_with = "queue")]
+        idle_timeout: Option<std::time::Duration>,
+
+        /// Leave the file on the relay, sealed so only the code opens it,
+        /// for the receiver to pick up after this exits; for files under
+        /// the relay's limit, and deleted once received unless --share
+        #[arg(long = "async", conflicts_with_all = ["queue", "recipients", "exit_after", "idle_timeout", "code", "require_key", "no_relay"])]
+        leave_on_relay: bool,
+
+        /// Stop the code and ticket from working after this long, e.g.
+        /// "2h"; with --async, how long the relay keeps the file, up to the
+        /// relay's own limit
+        #[arg(long, value_name = "DURATION", value_parser = zap_cli::parse_duration, conflicts_with_all = ["queue", "recipients"])]
+        expires: Option<std::time::Duration>,
+
+        /// Send at most this fast to all receivers together, e.g. "10MB",
+        /// shared evenly so a fast receiver can't starve the others
+        #[arg(long, value_name = "RATE", value_parser = zap_cli::parse_rate, conflicts_with_all = ["queue", "leave_on_relay"])]
+        max_rate: Option<u64>,
+
+        /// Send at most this fast to any one receiver, e.g. "2MB"
+        #[arg(long, value_name = "RATE", value_parser = zap_cli::parse_rate, conflicts_with_all = ["queue", "leave_on_relay"])]
+        max_rate_per_receiver: Option<u64>,
+
+        /// Register under this code instead of a generated one, e.g.
+        /// "standup-recording"; the relay needs an API key for it
+        #[arg(long, value_name = "CODE", conflicts_with_all = ["queue", "no_relay"])]
+        code: Option<String>,
+
+        /// Only let receivers in who also have a key, which is added to the
+        /// code and kept from the relay, so the relay can't fetch the files
+        #[arg(long, conflicts_with = "queue")]
+        require_key: bool,
+
+        /// Give up when the receiver rejects the file, instead of waiting
+        /// for it or someone else to try again
+        #[arg(long)]
+        exit_on_reject: bool,
+
+        /// When the file changes while it's being sent, wait for it to
+        /// stop changing and offer the new version, instead of failing
+        #[arg(long, conflicts_with_all = ["queue", "share", "exit_after", "recipients"])]
+        retry_on_change: bool,
+
+        /// When the connection breaks partway, wait for the receiver to
+        /// carry on from what it has with `zap receive --resume`, instead
+        /// of failing
+        #[arg(long, conflicts_with_all = ["queue", "share", "exit_after", "recipients"])]
+        resume: bool,
+
+        /// Run a command once the receiver has everything, e.g.
+        /// "notify-send {name}"; placeholders are {path}, {name}, {size},
+        /// {checksum} and {peer}
+        #[arg(long, value_name = "CMD")]
+        on_sent: Option<String>,
 
         /// Don't use relay for short codes (share full ticket instead)
         #[arg(long)]
         no_relay: bool,
 
-        /// Custom relay server URL
-        #[arg(long, default_value = DEFAULT_RELAY)]
-        relay: String,
+        /// Work without internet: no relays and no code, only a ticket
+        /// with this machine's addresses, for a receiver on the same
+        /// network using --direct. Pair with --port to fix the UDP port
+        #[arg(long, conflicts_with_all = ["code", "relay", "from_url", "queue", "recipients", "leave_on_relay"])]
+        ticket_only: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+
+        #[command(flatten)]
+        net: zap_cli::NetArgs,
     },
 
     /// Receive a file
@@ -35,13 +202,309 @@ enum Commands {
         /// The code or ticket from the sender (interactive if not provided)
         code: Option<String>,
 
+        /// Read the code from a QR code in this image, e.g. a screenshot
+        /// or a photo of the sender's screen
+        #[arg(long, value_name = "IMAGE", conflicts_with = "code")]
+        qr_image: Option<std::path::PathBuf>,
+
+        /// Output directory (defaults to the one set with zap init, or the
+        /// current directory)
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+
+        /// Save under the sender's exact file name, without adding an
+        /// extension detected from the content
+        #[arg(long)]
+        keep_name: bool,
+
+        /// When receiving a folder, pick which of its files to download
+        #[arg(long)]
+        select: bool,
+
+        /// Name the received file from a pattern, e.g.
+        /// "{date}-{sender_short}-{name}"; also {stem}, {ext}, {time},
+        /// {sender} and {size}. Existing files are never overwritten
+        #[arg(long)]
+        output_template: Option<String>,
+
+        /// Run a command on the received file, e.g. "photos-import {path}";
+        /// placeholders are {path}, {name}, {size}, {checksum} and {peer}
+        #[arg(long, value_name = "CMD")]
+        on_complete: Option<String>,
+
+        /// When to fsync what's received: "none", "done" once each file has
+        /// arrived, or a number of megabytes like "64" to also sync along
+        /// the way. Worth it on network filesystems and SD cards
+        #[arg(long, default_value = "none")]
+        durability: zap_cli::Durability,
+
+        /// Don't download a file that's already in the output directory
+        /// with the same contents
+        #[arg(long)]
+        dedup: bool,
+
+        /// Also look for the file anywhere in this folder, copying it from
+        /// there if it's found; implies --dedup
+        #[arg(long, value_name = "DIR")]
+        cache_dir: Option<std::path::PathBuf>,
+
+        /// Get the file's hash tree from the sender first and check every
+        /// 256 KB as it arrives, stopping at the first bad piece. For a
+        /// folder, get every file's hash and check each file instead
+        #[arg(long)]
+        verify_pieces: bool,
+
+        /// Fail unless the file has this hash, passed along by the sender
+        /// some other way: BLAKE3 as zap and b3sum print it, or
+        /// sha256:<hex>. The file is deleted if it doesn't match
+        #[arg(long, value_name = "HASH")]
+        checksum: Option<zap_cli::Checksum>,
+
+        /// Carry on with a receive that broke off, from the .zap-state
+        /// file left next to the partial file, here or on another machine.
+        /// The sender has to be sharing with --share or sending with --resume
+        #[arg(long, value_name = "STATE", conflicts_with_all = ["code", "qr_image"])]
+        resume: Option<std::path::PathBuf>,
+
+        /// Connect straight to the addresses in the sender's ticket, without
+        /// relays or code lookups, e.g. on a LAN without internet
+        #[arg(long, conflicts_with = "relay")]
+        direct: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+
+        #[command(flatten)]
+        net: zap_cli::NetArgs,
+    },
+
+    /// Print a small text file from a sender instead of saving it
+    Cat {
+        /// The code or ticket from the sender
+        code: String,
+
+        /// Refuse files larger than this many bytes
+        #[arg(long, default_value_t = 1024 * 1024)]
+        max_size: u64,
+
+        /// Also save the file to the current directory
+        #[arg(long)]
+        save: bool,
+
+        /// Don't syntax highlight the output
+        #[arg(long)]
+        plain: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Receive a transfer captured with ZAP_CAPTURE again, without the
+    /// network, to reproduce a failure
+    Replay {
+        /// The capture file
+        capture: std::path::PathBuf,
+
         /// Output directory (defaults to current directory)
         #[arg(short, long)]
         output: Option<std::path::PathBuf>,
+    },
+
+    /// Browse a shared folder from your file manager over WebDAV
+    Webdav {
+        /// The code or ticket from a sender running `zap send --share`
+        code: String,
+
+        /// Local port to serve WebDAV on
+        #[arg(long, default_value_t = 4918)]
+        port: u16,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Receive only the new and changed files of a shared folder
+    Sync {
+        /// Local directory to bring up to date
+        dir: std::path::PathBuf,
+
+        /// The code or ticket from a sender running `zap send --share`
+        code: String,
+
+        /// Only show what would be received
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Mount a shared folder as a read-only filesystem, fetching files as
+    /// they're read
+    #[cfg(all(unix, feature = "fuse"))]
+    Mount {
+        /// The code or ticket from a sender running `zap send --share`
+        code: String,
+
+        /// Empty directory to mount on
+        mountpoint: std::path::PathBuf,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Copy files to or from a paired peer running `zap listen`
+    Cp {
+        /// What to copy: a local path, or name:path on a paired peer
+        source: String,
+
+        /// Where to: a local directory, or name:dir on a paired peer
+        dest: String,
+    },
+
+    /// Serve a directory to paired peers for `zap cp`
+    Listen {
+        /// Directory to serve; peers can't reach anything outside it
+        #[arg(default_value = ".")]
+        dir: std::path::PathBuf,
 
-        /// Custom relay server URL
-        #[arg(long, default_value = DEFAULT_RELAY)]
-        relay: String,
+        /// Only let peers copy from the directory, not into it
+        #[arg(long)]
+        read_only: bool,
+
+        /// Sort copies in into inbox/<peer>/<date>/ here, whatever
+        /// directory the peer names
+        #[arg(long, conflicts_with = "read_only")]
+        inbox: bool,
+
+        /// With --inbox, the folders inside each peer's, e.g.
+        /// "{date}/{time}"; placeholders are {date}, {time}, {sender} and
+        /// {sender_short}
+        #[arg(long, value_name = "TEMPLATE", requires = "inbox")]
+        inbox_template: Option<String>,
+
+        /// With --inbox, what to do when a name is already taken
+        #[arg(long, value_enum, default_value_t, requires = "inbox")]
+        on_conflict: zap_cli::Conflict,
+
+        /// With --inbox, the most each peer's folder may hold, e.g. "5GB"
+        #[arg(long, value_name = "SIZE", value_parser = zap_cli::parse_size, requires = "inbox")]
+        quota: Option<u64>,
+    },
+
+    /// Set up this machine: pick a relay and a download folder, and make
+    /// the key other machines pair with
+    Init,
+
+    /// Show this machine's node id and paired peers, or pair with a peer
+    Pair {
+        /// Name to use for the peer, as in name:path
+        #[arg(requires = "id")]
+        name: Option<String>,
+
+        /// The peer's node id, shown by `zap pair` on that machine
+        id: Option<String>,
+
+        /// Forget a paired peer
+        #[arg(long, value_name = "NAME", conflicts_with = "name")]
+        remove: Option<String>,
+    },
+
+    /// Show how much was sent and received each month, or set a monthly
+    /// limit to be warned about
+    Stats {
+        /// How many months to show, latest first
+        #[arg(long, default_value_t = 12)]
+        months: usize,
+
+        /// Warn when a month's transfers go over this, e.g. 50GB
+        #[arg(long, value_name = "SIZE", value_parser = zap_cli::parse_size, conflicts_with = "no_limit")]
+        limit: Option<u64>,
+
+        /// Stop warning about a monthly limit
+        #[arg(long)]
+        no_limit: bool,
+    },
+
+    /// List what was sent, with the receipts receivers signed for it
+    History {
+        /// How many sends to show, latest first
+        #[arg(long, default_value_t = 20)]
+        last: usize,
+
+        /// Print the entries and their receipts as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List, create or switch settings profiles
+    Profile {
+        #[command(subcommand)]
+        action: zap_cli::ProfileAction,
+    },
+
+    /// List, pause, resume or cancel transfers running in other terminals
+    Ctl {
+        #[command(subcommand)]
+        action: zap_cli::CtlAction,
+    },
+
+    /// Watch and control every running transfer in a live dashboard
+    Tui,
+
+    /// Keep a node online for `zap send` and `zap receive` to hand their
+    /// transfers to, so they start faster and keep one node id
+    Daemon,
+
+    /// Forward a TCP port over a zap connection
+    Tunnel {
+        /// The code or ticket of the exposing peer
+        #[arg(conflicts_with = "expose", required_unless_present = "expose")]
+        code: Option<String>,
+
+        /// Local port to expose to the peer
+        #[arg(long)]
+        expose: Option<u16>,
+
+        /// Local port to listen on when connecting to a peer
+        #[arg(long, default_value_t = 8080)]
+        local: u16,
+
+        /// Don't use relay for short codes (share full ticket instead)
+        #[arg(long)]
+        no_relay: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Update zap to the latest release
+    Update {
+        /// Only check whether a newer version is available
+        #[arg(long)]
+        check: bool,
+
+        /// Install without verifying the release signature
+        #[arg(long)]
+        skip_verify: bool,
+
+        /// Custom relay server URL, instead of the profile's or zapper.cloud
+        #[arg(long)]
+        relay: Option<String>,
+    },
+
+    /// Show the version and build details, and the licenses of the
+    /// third-party crates zap is built from
+    About {
+        /// Print the license texts and notices of the third-party crates
+        #[arg(long)]
+        licenses: bool,
     },
 
     /// Start the web server
@@ -54,28 +517,279 @@ enum Commands {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    #[cfg(feature = "otel")]
+    let (otel_layer, _telemetry) = match zap_core::telemetry::from_env("zap") {
+        Some((layer, telemetry)) => (Some(layer), Some(telemetry)),
+        None => (None, None),
+    };
+    #[cfg(not(feature = "otel"))]
+    let otel_layer: Option<tracing_subscriber::layer::Identity> = None;
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
-        )
+    // Filtered per layer, so transfer logs keep debug messages that aren't
+    // printed
+    let filter = || EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(filter()))
+        .with(otel_layer.with_filter(filter()))
+        .with(zap_core::logs::layer())
         .init();
 
-    match cli.command {
+    zap_cli::set_plain(cli.plain);
+    zap_cli::set_color(cli.color);
+    if let Some(profile) = &cli.profile {
+        zap_cli::select_profile(profile)?;
+    }
+    if let (Some(cert), Some(key)) = (cli.client_cert, cli.client_key) {
+        zap_cli::set_client_cert(cert, key);
+    }
+
+    match &cli.debug_bundle {
+        Some(path) => {
+            let name = matches.subcommand_name().unwrap_or_default();
+            zap_cli::with_debug_bundle(path, name, run(cli.command)).await
+        }
+        None => run(cli.command).await,
+    }
+}
+
+async fn run(command: Commands) -> Result<()> {
+    match command {
+        Commands::Send {
+            paths,
+            from_file,
+            name,
+            share,
+            leave_on_relay: true,
+            expires,
+            relay,
+            ..
+        } => {
+            let paths = zap_cli::with_path_list(paths, from_file.as_deref())?;
+            zap_cli::run_send_async(paths, name, share, expires, zap_cli::relay_url(relay)).await?;
+        }
         Commands::Send {
-            path,
+            paths,
+            name,
+            label,
+            from_url,
+            command,
+            from_file,
+            auto_archive,
+            queue,
+            concurrency,
+            per_peer,
+            urgent,
+            share,
+            recipients,
+            exit_after,
+            downloads,
+            list,
+            idle_timeout,
+            expires,
+            max_rate,
+            max_rate_per_receiver,
+            code,
+            require_key,
+            exit_on_reject,
+            retry_on_change,
+            resume,
+            on_sent,
             no_relay,
+            ticket_only,
             relay,
+            net,
+            ..
         } => {
-            zap_cli::run_send(path, no_relay, relay).await?;
+            let mode = match (queue, recipients, share, exit_after) {
+                (true, ..) => zap_cli::SendMode::Queue(zap_cli::QueueOptions {
+                    concurrency,
+                    per_peer,
+                    urgent,
+                }),
+                (false, Some(recipients), ..) => zap_cli::SendMode::Recipients(recipients.into()),
+                (false, None, true, _) | (false, None, false, Some(zap_cli::ExitAfter::Never)) => {
+                    zap_cli::SendMode::Share { downloads: None }
+                }
+                (false, None, false, Some(zap_cli::ExitAfter::All)) => {
+                    zap_cli::SendMode::Share { downloads }
+                }
+                (false, None, false, Some(zap_cli::ExitAfter::First) | None) => {
+                    zap_cli::SendMode::Once
+                }
+            };
+            let args = zap_cli::SendArgs {
+                no_relay: no_relay || ticket_only,
+                relay: zap_cli::relay_url(relay),
+                code,
+                name,
+                label,
+                from_url,
+                command,
+                auto_archive,
+                mode,
+                list,
+                idle_timeout,
+                expires,
+                max_rate,
+                max_rate_per_receiver,
+                require_key,
+                exit_on_reject,
+                retry_on_change,
+                resume,
+                on_sent,
+                net: zap_cli::NetArgs {
+                    offline: ticket_only,
+                    ..net
+                },
+            };
+            zap_cli::run_send(zap_cli::with_path_list(paths, from_file.as_deref())?, args).await?;
         }
         Commands::Receive {
             code,
+            qr_image,
             output,
+            keep_name,
+            select,
+            output_template,
+            on_complete,
+            durability,
+            dedup,
+            cache_dir,
+            verify_pieces,
+            checksum,
+            resume,
+            direct,
             relay,
+            net,
         } => {
-            zap_cli::run_receive(code, output, relay).await?;
+            zap_cli::run_receive(zap_cli::ReceiveArgs {
+                code,
+                qr_image,
+                output,
+                relay: zap_cli::relay_url(relay),
+                keep_name,
+                select,
+                output_template,
+                on_complete,
+                durability,
+                dedup,
+                cache_dir,
+                verify_pieces,
+                expected: checksum,
+                resume,
+                net: zap_cli::NetArgs {
+                    offline: direct,
+                    ..net
+                },
+            })
+            .await?;
+        }
+        Commands::Cat {
+            code,
+            max_size,
+            save,
+            plain,
+            relay,
+        } => {
+            zap_cli::run_cat(code, max_size, save, plain, zap_cli::relay_url(relay)).await?;
+        }
+        Commands::Replay { capture, output } => {
+            zap_cli::run_replay(capture, output).await?;
+        }
+        Commands::Webdav { code, port, relay } => {
+            zap_cli::run_webdav(code, port, zap_cli::relay_url(relay)).await?;
+        }
+        Commands::Sync {
+            dir,
+            code,
+            dry_run,
+            relay,
+        } => {
+            zap_cli::run_sync(dir, code, dry_run, zap_cli::relay_url(relay)).await?;
+        }
+        Commands::Cp { source, dest } => {
+            zap_cli::run_cp(source, dest).await?;
+        }
+        Commands::Listen {
+            dir,
+            read_only,
+            inbox,
+            inbox_template,
+            on_conflict,
+            quota,
+        } => {
+            let inbox = inbox.then_some(zap_cli::InboxOptions {
+                template: inbox_template,
+                conflict: on_conflict,
+                quota,
+            });
+            zap_cli::run_listen(dir, read_only, inbox).await?;
+        }
+        Commands::Init => {
+            zap_cli::run_init().await?;
+        }
+        Commands::Pair { name, id, remove } => {
+            zap_cli::run_pair(name, id, remove).await?;
+        }
+        Commands::Stats {
+            months,
+            limit,
+            no_limit,
+        } => {
+            zap_cli::run_stats(months, limit, no_limit).await?;
+        }
+        Commands::History { last, json } => {
+            zap_cli::run_history(last, json).await?;
+        }
+        Commands::Profile { action } => {
+            zap_cli::run_profile(action).await?;
+        }
+        Commands::Ctl { action } => {
+            zap_cli::run_ctl(action).await?;
+        }
+        Commands::Tui => {
+            zap_cli::run_tui().await?;
+        }
+        Commands::Daemon => {
+            zap_cli::run_daemon().await?;
+        }
+        #[cfg(all(unix, feature = "fuse"))]
+        Commands::Mount {
+            code,
+            mountpoint,
+            relay,
+        } => {
+            zap_cli::run_mount(code, mountpoint, zap_cli::relay_url(relay)).await?;
+        }
+        Commands::Tunnel {
+            code,
+            expose,
+            local,
+            no_relay,
+            relay,
+        } => {
+            zap_cli::run_tunnel(code, expose, local, no_relay, zap_cli::relay_url(relay)).await?;
+        }
+        Commands::Update {
+            check,
+            skip_verify,
+            relay,
+        } => {
+            zap_cli::run_update(zap_cli::relay_url(relay), check, skip_verify).await?;
+        }
+        Commands::About { licenses } => {
+            let info = zap_cli::BuildInfo {
+                version: env!("CARGO_PKG_VERSION"),
+                commit: env!("ZAP_GIT_SHA"),
+                target: env!("ZAP_TARGET"),
+                features: env!("ZAP_FEATURES"),
+                crates: include_str!(concat!(env!("OUT_DIR"), "/crates.txt")),
+                notices: include_str!(concat!(env!("OUT_DIR"), "/notices.txt")),
+            };
+            zap_cli::run_about(info, licenses)?;
         }
         Commands::Serve { addr } => {
             zap_web::run_server(addr).await?;